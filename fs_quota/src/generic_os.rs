@@ -4,9 +4,13 @@
 use std::io;
 use std::path::Path;
 
-use crate::{FqError, FsQuota, Mtab};
+use crate::{FqError, FsQuota, IdType, Mtab};
 
-pub(crate) fn get_quota(_device: impl AsRef<Path>, _uid: u32) -> Result<FsQuota, FqError> {
+pub(crate) fn get_quota(_device: impl AsRef<Path>, _id: u32, _idtype: IdType) -> Result<FsQuota, FqError> {
+    Err(FqError::NoQuota)
+}
+
+pub(crate) fn get_projid(_path: impl AsRef<Path>) -> Result<u32, FqError> {
     Err(FqError::NoQuota)
 }
 
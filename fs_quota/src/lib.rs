@@ -3,7 +3,7 @@
 //!
 //! This crate has support for:
 //!
-//! - the Linux quota system
+//! - the Linux quota system, user and project quotas
 //! - NFS quotas (via SUNRPC).
 //! - `libc::vfsstat` lookups (like `df`).
 //!
@@ -44,13 +44,13 @@ mod quota_nfs;
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "linux")]
-use linux::{get_quota, read_mtab};
+use linux::{get_projid, get_quota, read_mtab};
 
 // Unsupported OS.
 #[cfg(not(target_os = "linux"))]
 mod generic_os;
 #[cfg(not(target_os = "linux"))]
-use generic_os::{get_quota, read_mtab};
+use generic_os::{get_projid, get_quota, read_mtab};
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum FsType {
@@ -60,6 +60,13 @@ pub(crate) enum FsType {
     Other,
 }
 
+// What the id in a quota lookup is.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum IdType {
+    User,
+    Project,
+}
+
 // return filesystem major type.
 fn fstype(tp: &str) -> FsType {
     match tp {
@@ -75,7 +82,7 @@ fn fstype(tp: &str) -> FsType {
 pub struct FsQuota {
     /// number of bytes used.
     pub bytes_used:  u64,
-    /// maximum number of bytes (available - used).
+    /// maximum number of bytes (available - used). `None` if there is no limit.
     pub bytes_limit: Option<u64>,
     /// number of files (inodes) in use.
     pub files_used:  u64,
@@ -112,7 +119,28 @@ impl FsQuota {
             }
         }
 
-        get_quota(&entry.device, id)
+        get_quota(&entry.device, id, IdType::User)
+    }
+
+    /// Get the project quota for project `projid` on the filesystem where `path` is on.
+    ///
+    /// If `projid` is `None`, use the project id of `path` itself (ext4 and xfs).
+    /// Project 0 is "no project", and returns `FqError::NoQuota`, as does a
+    /// filesystem without project ids.
+    pub fn project(path: impl AsRef<Path>, projid: Option<u32>) -> Result<FsQuota, FqError> {
+        let path = path.as_ref();
+        let id = match projid {
+            Some(id) => id,
+            None => get_projid(path).unwrap_or(0),
+        };
+        if id == 0 {
+            return Err(FqError::NoQuota);
+        }
+        let entry = get_mtab_entry(path)?;
+        match fstype(&entry.fstype) {
+            FsType::LinuxExt | FsType::LinuxXfs => get_quota(&entry.device, id, IdType::Project),
+            _ => Err(FqError::NoQuota),
+        }
     }
 
     /// Get used and available disk space of the filesystem indicated by `path`.
@@ -135,7 +163,8 @@ impl FsQuota {
     }
 
     /// Lookup used and available disk space for a `uid`. First check user's quota,
-    /// if quotas are not enabled check the filesystem disk space usage.
+    /// if quotas are not enabled or there is no limit, check the filesystem disk
+    /// space usage.
     ///
     /// This is the equivalent of
     ///
@@ -144,19 +173,29 @@ impl FsQuota {
     /// # let uid = None;
     /// # use fs_quota::*;
     /// FsQuota::user(path, uid)
+    ///     .and_then(|q| if q.bytes_limit.is_none() { Err(FqError::NoQuota) } else { Ok(q) })
     ///     .or_else(|e| if e == FqError::NoQuota { FsQuota::system(path) } else { Err(e) })
     /// # ;
     /// ```
     ///
     pub fn check(path: impl AsRef<Path>, uid: Option<u32>) -> Result<FsQuota, FqError> {
         let path = path.as_ref();
-        FsQuota::user(path, uid).or_else(|e| {
-            if e == FqError::NoQuota {
-                FsQuota::system(path)
-            } else {
-                Err(e)
-            }
-        })
+        or_system(path, FsQuota::user(path, uid))
+    }
+
+    /// Like `check`, but with the project quota of `path` instead of the user's quota.
+    pub fn check_project(path: impl AsRef<Path>) -> Result<FsQuota, FqError> {
+        let path = path.as_ref();
+        or_system(path, FsQuota::project(path, None))
+    }
+}
+
+// If there is no quota, or no limit, fall back to the disk space usage.
+fn or_system(path: &Path, res: Result<FsQuota, FqError>) -> Result<FsQuota, FqError> {
+    match res {
+        Ok(q) if q.bytes_limit.is_some() => Ok(q),
+        Ok(_) | Err(FqError::NoQuota) => FsQuota::system(path),
+        Err(e) => Err(e),
     }
 }
 
@@ -210,31 +249,25 @@ fn get_mtab_entry(path: impl AsRef<Path>) -> Result<Mtab, FqError> {
             // multiple matching entries.. happens on NFS.
 
             // get "realpath" of the path that was passed in.
-            let rp = match realpath(path) {
-                Ok(p) => p,
-                Err(e) => return Err(e.into()),
-            };
+            let rp = realpath(path)?;
 
             // realpath the remaining entries as well..
             let mut v = Vec::new();
             for mut e in ents.into_iter() {
-                match realpath(&e.directory) {
-                    Ok(p) => {
-                        let c = String::from_utf8_lossy(p.as_os_str().as_bytes());
-                        e.directory = c.to_string();
-                        v.push(e);
-                    },
-                    Err(_) => {},
+                if let Ok(p) = realpath(&e.directory) {
+                    let c = String::from_utf8_lossy(p.as_os_str().as_bytes());
+                    e.directory = c.to_string();
+                    v.push(e);
                 }
             }
-            if v.len() == 0 {
+            if v.is_empty() {
                 return Err(FqError::NoQuota);
             }
 
             // find longest match.
             v.sort_by_key(|e| e.directory.clone());
             v.reverse();
-            match v.iter().position(|ref x| rp.starts_with(&x.directory)) {
+            match v.iter().position(|x| rp.starts_with(&x.directory)) {
                 Some(p) => v[p].clone(),
                 None => {
                     return Err(FqError::NoQuota);
@@ -258,19 +291,19 @@ impl From<std::ffi::NulError> for FqError {
 }
 
 fn to_num(e: &FqError) -> u32 {
-    match e {
-        &FqError::PermissionDenied => 1,
-        &FqError::NoQuota => 2,
-        &FqError::IoError(_) => 3,
-        &FqError::Other => 4,
+    match *e {
+        FqError::PermissionDenied => 1,
+        FqError::NoQuota => 2,
+        FqError::IoError(_) => 3,
+        FqError::Other => 4,
     }
 }
 
 impl PartialEq for FqError {
     fn eq(&self, other: &Self) -> bool {
         match self {
-            &FqError::IoError(ref e) => {
-                if let &FqError::IoError(ref o) = other {
+            FqError::IoError(e) => {
+                if let FqError::IoError(o) = other {
                     e.kind() == o.kind()
                 } else {
                     false
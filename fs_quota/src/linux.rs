@@ -10,24 +10,29 @@ use std::os::raw::{c_char, c_int};
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 
-use crate::{FqError, FsQuota, Mtab};
+use crate::{FqError, FsQuota, IdType, Mtab};
 
 // The actual implementation is done in C, and imported here.
 extern "C" {
     fn fs_quota_linux(
         device: *const c_char,
         id: c_int,
-        do_group: c_int,
+        qtype: c_int,
         bytes_used: *mut u64,
         bytes_limit: *mut u64,
         files_used: *mut u64,
         files_limit: *mut u64,
     ) -> c_int;
+    fn fs_quota_linux_projid(path: *const c_char, projid: *mut u32) -> c_int;
 }
 
 // wrapper for the C functions.
-pub(crate) fn get_quota(device: impl AsRef<Path>, uid: u32) -> Result<FsQuota, FqError> {
-    let id = uid as c_int;
+pub(crate) fn get_quota(device: impl AsRef<Path>, id: u32, idtype: IdType) -> Result<FsQuota, FqError> {
+    let qtype = match idtype {
+        IdType::User => 0,
+        IdType::Project => 2,
+    };
+    let id = id as c_int;
     let device = device.as_ref();
 
     let mut bytes_used = 0u64;
@@ -40,7 +45,7 @@ pub(crate) fn get_quota(device: impl AsRef<Path>, uid: u32) -> Result<FsQuota, F
         fs_quota_linux(
             path.as_ptr(),
             id,
-            0,
+            qtype,
             &mut bytes_used as *mut u64,
             &mut bytes_limit as *mut u64,
             &mut files_used as *mut u64,
@@ -53,9 +58,9 @@ pub(crate) fn get_quota(device: impl AsRef<Path>, uid: u32) -> Result<FsQuota, F
         0 => {
             let m = |v| if v == 0xffffffffffffffff { None } else { Some(v) };
             Ok(FsQuota {
-                bytes_used:  bytes_used,
+                bytes_used,
                 bytes_limit: m(bytes_limit),
-                files_used:  files_used,
+                files_used,
                 files_limit: m(files_limit),
             })
         },
@@ -64,6 +69,16 @@ pub(crate) fn get_quota(device: impl AsRef<Path>, uid: u32) -> Result<FsQuota, F
     }
 }
 
+// project id of a file or directory.
+pub(crate) fn get_projid(path: impl AsRef<Path>) -> Result<u32, FqError> {
+    let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
+    let mut projid = 0u32;
+    match unsafe { fs_quota_linux_projid(path.as_ptr(), &mut projid as *mut u32) } {
+        0 => Ok(projid),
+        _ => Err(FqError::IoError(io::Error::last_os_error())),
+    }
+}
+
 // read /etc/mtab.
 pub(crate) fn read_mtab() -> io::Result<Vec<Mtab>> {
     let f = File::open("/etc/mtab")?;
@@ -72,7 +87,7 @@ pub(crate) fn read_mtab() -> io::Result<Vec<Mtab>> {
     for l in reader.lines() {
         let l2 = l?;
         let line = l2.trim();
-        if line.len() == 0 || line.starts_with("#") {
+        if line.is_empty() || line.starts_with("#") {
             continue;
         }
         let words = line.split_whitespace().collect::<Vec<_>>();
@@ -83,15 +98,13 @@ pub(crate) fn read_mtab() -> io::Result<Vec<Mtab>> {
             if !words[0].contains(":") {
                 continue;
             }
-            let mut s = words[0].splitn(2, ':');
-            let host = s.next().unwrap();
-            let path = s.next().unwrap();
+            let (host, path) = words[0].split_once(':').unwrap();
             (Some(host.to_string()), path)
         } else {
             (None, words[2])
         };
         result.push(Mtab {
-            host:      host,
+            host,
             device:    device.to_string(),
             directory: words[1].to_string(),
             fstype:    words[2].to_string(),
@@ -4,18 +4,25 @@
 #include <fcntl.h>
 #include <unistd.h>
 #include <sys/stat.h>
+#include <sys/ioctl.h>
 #include <sys/quota.h>
+#include <linux/fs.h>
 #include <errno.h>
 
+#ifndef PRJQUOTA
+#  define PRJQUOTA 2
+#endif
+
 #ifdef HAVE_STRUCT_DQBLK_CURSPACE
 #  define dqb_curblocks dqb_curspace
 #endif
 
-int fs_quota_linux(char *path, int id, int do_group,
+/* qtype: 0 = user, 1 = group, 2 = project. A limit of UINT64_MAX means "no limit". */
+int fs_quota_linux(char *path, int id, int qtype,
 		   uint64_t *bytes_value_r, uint64_t *bytes_limit_r,
 		   uint64_t *count_value_r, uint64_t *count_limit_r)
 {
-	int type = do_group ? GRPQUOTA : USRQUOTA;
+	int type = qtype == 2 ? PRJQUOTA : qtype == 1 ? GRPQUOTA : USRQUOTA;
 
 	struct dqblk dqblk;
 	if (quotactl(QCMD(Q_GETQUOTA, type), path, id, (caddr_t)&dqblk) < 0) {
@@ -34,11 +41,40 @@ int fs_quota_linux(char *path, int id, int do_group,
 	if (*bytes_limit_r == 0) {
 		*bytes_limit_r = dqblk.dqb_bhardlimit * 1024;
 	}
+	if (*bytes_limit_r == 0) {
+		*bytes_limit_r = UINT64_MAX;
+	}
 	*count_value_r = dqblk.dqb_curinodes;
 	*count_limit_r = dqblk.dqb_isoftlimit;
 	if (*count_limit_r == 0) {
 		*count_limit_r = dqblk.dqb_ihardlimit;
 	}
+	if (*count_limit_r == 0) {
+		*count_limit_r = UINT64_MAX;
+	}
 	return 0;
 }
 
+/* The project id of a file or directory (ext4, xfs). */
+int fs_quota_linux_projid(char *path, uint32_t *projid_r)
+{
+#ifdef FS_IOC_FSGETXATTR
+	struct fsxattr fsx;
+	int fd, rc;
+
+	if ((fd = open(path, O_RDONLY)) < 0) {
+		return -1;
+	}
+	rc = ioctl(fd, FS_IOC_FSGETXATTR, &fsx);
+	close(fd);
+	if (rc < 0) {
+		return -1;
+	}
+	*projid_r = fsx.fsx_projid;
+	return 0;
+#else
+	errno = ENOTSUP;
+	return -1;
+#endif
+}
+
@@ -2,5 +2,6 @@ extern crate cc;
 
 fn main() {
     println!("cargo:rustc-link-lib=pam");
+    println!("cargo:rerun-if-changed=src/pam.c");
     cc::Build::new().file("src/pam.c").compile("rpam"); // outputs `librpam.a`
 }
@@ -1,6 +1,5 @@
 use std::io::{self, Write};
 
-use env_logger;
 use pam_sandboxed::PamAuth;
 
 fn prompt(s: &str) -> io::Result<String> {
@@ -36,8 +35,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 // for that is a dynamic test-mode setting in the library, instead of compile-time.
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use pam_sandboxed::{test_mode, PamAuth, PamError};
-    use tokio;
 
     const TEST_STR: &str = "xyzzy-test-test";
 
@@ -46,7 +46,7 @@ mod tests {
         test_mode(true);
 
         let mut pam = PamAuth::new(None).unwrap();
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
 
         let res = rt.block_on(async {
             let mut pam2 = pam.clone();
@@ -56,7 +56,7 @@ mod tests {
                 return Err(e);
             }
 
-            if let Ok(_) = pam2.auth(TEST_STR, "unknown", "bar", Some(TEST_STR)).await {
+            if pam2.auth(TEST_STR, "unknown", "bar", Some(TEST_STR)).await.is_ok() {
                 eprintln!("auth(unknown) succeeded, should have failed");
                 return Err(PamError::unknown());
             }
@@ -66,12 +66,109 @@ mod tests {
         assert!(res.is_ok());
     }
 
+    #[test]
+    fn test_conv() {
+        test_mode(true);
+
+        let mut pam = PamAuth::new(None).unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let res = pam.auth_conv(TEST_STR, "test", "foo", &["123456"], Some(TEST_STR)).await;
+            assert!(res.is_ok(), "auth_conv(test, 123456) failed: {:?}", res);
+            let res = pam.auth_conv(TEST_STR, "test", "foo", &["654321"], Some(TEST_STR)).await;
+            assert!(res.is_err(), "auth_conv(test, 654321) succeeded, should have failed");
+        });
+    }
+
+    #[test]
+    fn test_session() {
+        test_mode(true);
+
+        let mut pam = PamAuth::new(None).unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async {
+            assert!(pam.account(TEST_STR, "test", Some(TEST_STR)).await.is_ok());
+            assert!(pam.account(TEST_STR, "unknown", Some(TEST_STR)).await.is_err());
+            let session = pam.open_session(TEST_STR, "test", Some(TEST_STR)).await;
+            assert!(session.is_ok(), "open_session(test) failed");
+            drop(session);
+            assert!(pam.open_session(TEST_STR, "unknown", Some(TEST_STR)).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_env() {
+        test_mode(true);
+
+        let mut pam = PamAuth::new(None).unwrap();
+        pam.set_tty(Some("webdav"));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let env = pam.auth_env(TEST_STR, "test", "foo", &[], Some("192.0.2.1")).await.unwrap();
+            let expect = [("TEST_RHOST", "192.0.2.1"), ("TEST_TTY", "webdav")];
+            let expect: Vec<_> = expect.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            assert_eq!(env, expect);
+            let session = pam.open_session(TEST_STR, "test", Some("192.0.2.1")).await.unwrap();
+            assert_eq!(session.env(), &expect[..]);
+        });
+    }
+
+    #[test]
+    fn test_chauthtok() {
+        test_mode(true);
+
+        let mut pam = PamAuth::new(None).unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async {
+            let res = pam.change_password(TEST_STR, "test", "foo", &[], "n3w-p4ss", Some(TEST_STR)).await;
+            assert!(res.is_ok(), "change_password(test) failed: {:?}", res);
+            let err = pam.change_password(TEST_STR, "test", "foo", &[], "weak", None).await.unwrap_err();
+            assert!(err.new_password_refused());
+            let res = pam.change_password(TEST_STR, "unknown", "foo", &[], "n3w-p4ss", None).await;
+            assert!(!res.unwrap_err().new_password_refused());
+        });
+    }
+
+    #[test]
+    fn test_timeout() {
+        test_mode(true);
+
+        let timeout = Some(Duration::from_millis(100));
+        let mut pam = PamAuth::with_options(None, timeout).unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async {
+            assert!(pam.auth(TEST_STR, "sleep", "foo", Some(TEST_STR)).await.is_err());
+            assert!(pam.auth(TEST_STR, "test", "foo", Some(TEST_STR)).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_restart() {
+        test_mode(true);
+
+        let mut pam = PamAuth::new(None).unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async {
+            assert!(pam.ping().await.is_ok());
+            assert!(pam.auth(TEST_STR, "crash", "foo", Some(TEST_STR)).await.is_err());
+            assert!(pam.restarts() >= 1);
+            assert!(pam.auth(TEST_STR, "test", "foo", Some(TEST_STR)).await.is_ok());
+            assert!(pam.ping().await.is_ok());
+        });
+    }
+
     #[test]
     fn test_many() {
         test_mode(true);
 
         let pam = PamAuth::new(None).unwrap();
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
 
         let mut handles = Vec::new();
         rt.block_on(async move {
@@ -10,6 +10,10 @@
 //! channel between the parent (pam-client) and the child (pam-server). All
 //! the Pam work is then done on a threadpool in the child process.
 //!
+//! The child is actually started by a small supervisor process that is
+//! forked off once, early. If the pam-server dies, the supervisor starts
+//! a new one and the requests that were in flight are retried.
+//!
 //! ## WHY.
 //!
 //! Reasons for doing this instead of just calling libpam directly:
@@ -53,7 +57,7 @@ mod pamserver;
 use std::sync::atomic::Ordering;
 
 pub use crate::pam::PamError;
-pub use crate::pamclient::PamAuth;
+pub use crate::pamclient::{PamAuth, PamSession};
 
 // See bin/main.rs, mod tests.
 #[doc(hidden)]
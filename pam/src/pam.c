@@ -1,13 +1,33 @@
 #include <security/pam_appl.h>
 #include <sys/resource.h>
+#include <ctype.h>
 #include <string.h>
 #include <stdlib.h>
 
 struct creds {
     char *user;
     char *password;
+    char **extra;
+    int nextra;
+    int prompts;
 };
 
+/*
+ * The first password prompt gets the password. If extra responses
+ * were supplied (e.g. an OTP), the following password prompts get
+ * those, in order. Without extra responses every password prompt
+ * gets the password, as before.
+ */
+static char *next_secret(struct creds *creds)
+{
+    int n = creds->prompts++;
+    if (n == 0 || creds->nextra == 0)
+        return creds->password;
+    if (n - 1 < creds->nextra)
+        return creds->extra[n - 1];
+    return NULL;
+}
+
 static void add_reply(struct pam_response **reply, int count, char *txt)
 {
     *reply = realloc(*reply, (count + 1) * sizeof(struct pam_response));
@@ -22,6 +42,7 @@ static int c_pam_conv(int num_msg, const struct pam_message **msg,
     struct creds *creds = (struct creds *)appdata;
     int replies = 0;
 
+    char *secret;
     int count;
     for (count = 0; count < num_msg; count++) {
         switch (msg[count]->msg_style) {
@@ -29,7 +50,13 @@ static int c_pam_conv(int num_msg, const struct pam_message **msg,
                 add_reply(&reply, replies++, creds->user);
                 break;
             case PAM_PROMPT_ECHO_OFF:
-                add_reply(&reply, replies++, creds->password);
+                secret = next_secret(creds);
+                if (secret == NULL) {
+                    if (reply != NULL)
+                        free(reply);
+                    return PAM_CONV_ERR;
+                }
+                add_reply(&reply, replies++, secret);
                 break;
             case PAM_TEXT_INFO:
                 break;
@@ -44,11 +71,33 @@ static int c_pam_conv(int num_msg, const struct pam_message **msg,
     return PAM_SUCCESS;
 }
 
-int c_pam_auth(char *service, char *user, char *pass, char *remip)
+/*
+ * Set PAM_RHOST and PAM_TTY, if we have them.
+ */
+static int c_pam_set_items(pam_handle_t *pamh, char *remip, char *tty)
+{
+    int ret = PAM_SUCCESS;
+    if (remip && remip[0])
+        ret = pam_set_item(pamh, PAM_RHOST, remip);
+    if (ret == PAM_SUCCESS && tty && tty[0])
+        ret = pam_set_item(pamh, PAM_TTY, tty);
+    return ret;
+}
+
+/*
+ * With "env" set, the credentials are established (pam_setcred) after
+ * authentication, which is where modules like pam_env and pam_krb5 set
+ * their environment variables, and *env gets the PAM environment.
+ */
+int c_pam_auth(char *service, char *user, char *pass, char **extra, int nextra,
+               char *remip, char *tty, char ***env)
 {
     struct creds creds = {
         user,
         pass,
+        extra,
+        nextra,
+        0,
     };
     struct pam_conv conv = {
         c_pam_conv,
@@ -59,15 +108,215 @@ int c_pam_auth(char *service, char *user, char *pass, char *remip)
     int ret = pam_start(service, user, &conv, &pamh);
     if (ret != PAM_SUCCESS)
             return ret;
-    if (ret == PAM_SUCCESS && remip && remip[0])
-        ret = pam_set_item(pamh, PAM_RHOST, remip);
+    ret = c_pam_set_items(pamh, remip, tty);
     if (ret == PAM_SUCCESS)
         ret = pam_authenticate(pamh, 0);
+    if (ret == PAM_SUCCESS && env) {
+        ret = pam_setcred(pamh, PAM_ESTABLISH_CRED);
+        if (ret == PAM_SUCCESS)
+            *env = pam_getenvlist(pamh);
+    }
     pam_end(pamh, 0);
 
     return ret;
 }
 
+struct newpass {
+    char *user;
+    char *oldpass;
+    char *newpass;
+};
+
+/*
+ * Does a prompt ask for the current password. pam_unix does not when
+ * it runs as root, pam_sss and pam_krb5 do ("Current Password: ").
+ */
+static int old_prompt(const char *msg)
+{
+    char buf[256];
+    size_t i;
+    for (i = 0; msg && msg[i] && i < sizeof(buf) - 1; i++)
+        buf[i] = tolower((unsigned char)msg[i]);
+    buf[i] = 0;
+    return strstr(buf, "current") != NULL || strstr(buf, "old") != NULL;
+}
+
+/*
+ * Conversation for pam_chauthtok. A prompt for the current password
+ * gets the old one, the other password prompts ("New password:",
+ * "Retype new password:") get the new one. Messages, like the reason
+ * pam_pwquality does not like the new password, get an empty answer.
+ */
+static int c_pam_conv_newpass(int num_msg, const struct pam_message **msg,
+                        struct pam_response **resp, void *appdata)
+{
+    struct pam_response *reply = NULL;
+    struct newpass *newpass = (struct newpass *)appdata;
+    char *txt;
+
+    int count;
+    for (count = 0; count < num_msg; count++) {
+        switch (msg[count]->msg_style) {
+            case PAM_PROMPT_ECHO_ON:
+                txt = newpass->user;
+                break;
+            case PAM_PROMPT_ECHO_OFF:
+                txt = old_prompt(msg[count]->msg) ? newpass->oldpass : newpass->newpass;
+                break;
+            case PAM_TEXT_INFO:
+            case PAM_ERROR_MSG:
+                txt = NULL;
+                break;
+            default:
+                if (reply != NULL)
+                    free(reply);
+                return PAM_CONV_ERR;
+        }
+        add_reply(&reply, count, txt);
+    }
+    *resp = reply;
+    return PAM_SUCCESS;
+}
+
+/*
+ * Change the password. As root pam_chauthtok does not ask for the old
+ * password, so that is checked first, with pam_authenticate. Then the
+ * account: a password that has expired (PAM_NEW_AUTHTOK_REQD) is what
+ * this is for, anything else that is wrong with it is not.
+ */
+int c_pam_chauthtok(char *service, char *user, char *pass, char **extra, int nextra,
+                    char *newpass, char *remip, char *tty)
+{
+    struct creds creds = {
+        user,
+        pass,
+        extra,
+        nextra,
+        0,
+    };
+    struct pam_conv conv = {
+        c_pam_conv,
+        &creds,
+    };
+    struct newpass np = {
+        user,
+        pass,
+        newpass,
+    };
+    struct pam_conv conv_newpass = {
+        c_pam_conv_newpass,
+        &np,
+    };
+    int flags = 0;
+
+    pam_handle_t *pamh = NULL;
+    int ret = pam_start(service, user, &conv, &pamh);
+    if (ret != PAM_SUCCESS)
+            return ret;
+    ret = c_pam_set_items(pamh, remip, tty);
+    if (ret == PAM_SUCCESS)
+        ret = pam_authenticate(pamh, 0);
+    if (ret == PAM_SUCCESS) {
+        ret = pam_acct_mgmt(pamh, PAM_SILENT);
+        if (ret == PAM_NEW_AUTHTOK_REQD) {
+            flags = PAM_CHANGE_EXPIRED_AUTHTOK;
+            ret = PAM_SUCCESS;
+        }
+    }
+    if (ret == PAM_SUCCESS)
+        ret = pam_set_item(pamh, PAM_CONV, &conv_newpass);
+    if (ret == PAM_SUCCESS)
+        ret = pam_chauthtok(pamh, flags);
+    pam_end(pamh, ret);
+
+    return ret;
+}
+
+/*
+ * The error of a new password that was not accepted.
+ */
+int c_pam_authtok_err(void)
+{
+    return PAM_AUTHTOK_ERR;
+}
+
+/*
+ * Conversation for account and session management. There is
+ * no password to give, so only informational messages are ok.
+ */
+static int c_pam_conv_none(int num_msg, const struct pam_message **msg,
+                        struct pam_response **resp, void *appdata)
+{
+    int count;
+    (void)appdata;
+    for (count = 0; count < num_msg; count++) {
+        if (msg[count]->msg_style != PAM_TEXT_INFO)
+            return PAM_CONV_ERR;
+    }
+    *resp = NULL;
+    return PAM_SUCCESS;
+}
+
+static const struct pam_conv conv_none = {
+    c_pam_conv_none,
+    NULL,
+};
+
+static int c_pam_start(char *service, char *user, char *remip, char *tty, pam_handle_t **pamh)
+{
+    int ret = pam_start(service, user, &conv_none, pamh);
+    if (ret != PAM_SUCCESS)
+        return ret;
+    ret = c_pam_set_items(*pamh, remip, tty);
+    if (ret != PAM_SUCCESS) {
+        pam_end(*pamh, ret);
+        *pamh = NULL;
+    }
+    return ret;
+}
+
+int c_pam_acct_mgmt(char *service, char *user, char *remip, char *tty)
+{
+    pam_handle_t *pamh = NULL;
+    int ret = c_pam_start(service, user, remip, tty, &pamh);
+    if (ret != PAM_SUCCESS)
+        return ret;
+    ret = pam_acct_mgmt(pamh, PAM_SILENT);
+    pam_end(pamh, ret);
+
+    return ret;
+}
+
+/*
+ * *env gets the PAM environment after the session was opened.
+ */
+int c_pam_open_session(char *service, char *user, char *remip, char *tty,
+                       void **handle, char ***env)
+{
+    pam_handle_t *pamh = NULL;
+    int ret = c_pam_start(service, user, remip, tty, &pamh);
+    if (ret != PAM_SUCCESS)
+        return ret;
+    ret = pam_open_session(pamh, PAM_SILENT);
+    if (ret != PAM_SUCCESS) {
+        pam_end(pamh, ret);
+        return ret;
+    }
+    *handle = pamh;
+    *env = pam_getenvlist(pamh);
+
+    return ret;
+}
+
+int c_pam_close_session(void *handle)
+{
+    pam_handle_t *pamh = (pam_handle_t *)handle;
+    int ret = pam_close_session(pamh, PAM_SILENT);
+    pam_end(pamh, ret);
+
+    return ret;
+}
+
 void c_pam_lower_rlimits()
 {
     struct rlimit rlim;
@@ -8,8 +8,38 @@ extern "C" {
         service: *const c_char,
         user: *const c_char,
         pass: *const c_char,
+        extra: *const *const c_char,
+        nextra: c_int,
         remip: *const c_char,
+        tty: *const c_char,
+        env: *mut *mut *mut c_char,
     ) -> c_int;
+    fn c_pam_chauthtok(
+        service: *const c_char,
+        user: *const c_char,
+        pass: *const c_char,
+        extra: *const *const c_char,
+        nextra: c_int,
+        newpass: *const c_char,
+        remip: *const c_char,
+        tty: *const c_char,
+    ) -> c_int;
+    fn c_pam_authtok_err() -> c_int;
+    fn c_pam_acct_mgmt(
+        service: *const c_char,
+        user: *const c_char,
+        remip: *const c_char,
+        tty: *const c_char,
+    ) -> c_int;
+    fn c_pam_open_session(
+        service: *const c_char,
+        user: *const c_char,
+        remip: *const c_char,
+        tty: *const c_char,
+        handle: *mut *mut c_void,
+        env: *mut *mut *mut c_char,
+    ) -> c_int;
+    fn c_pam_close_session(handle: *mut c_void) -> c_int;
     fn _c_pam_return_value(index: c_int) -> c_int;
     fn pam_strerror(pamh: *const c_void, errnum: c_int) -> *const c_char;
     fn c_pam_lower_rlimits();
@@ -18,6 +48,7 @@ extern "C" {
 pub(crate) const ERR_NUL_BYTE: i32 = 414243;
 pub(crate) const ERR_SEND_TO_SERVER: i32 = 414244;
 pub(crate) const ERR_RECV_FROM_SERVER: i32 = 414245;
+pub(crate) const ERR_TIMEOUT: i32 = 414246;
 
 pub(crate) static TEST_MODE: AtomicUsize = AtomicUsize::new(0);
 
@@ -33,6 +64,12 @@ impl PamError {
     pub fn unknown() -> PamError {
         PamError(13)
     }
+
+    /// Did `change_password()` fail because the new password was not
+    /// accepted (by pam_pwquality, for example), not the old one.
+    pub fn new_password_refused(&self) -> bool {
+        self.0 == unsafe { c_pam_authtok_err() }
+    }
 }
 
 impl std::fmt::Display for PamError {
@@ -41,6 +78,7 @@ impl std::fmt::Display for PamError {
             ERR_NUL_BYTE => write!(f, "embedded 0 byte in string"),
             ERR_SEND_TO_SERVER => write!(f, "error sending request to server"),
             ERR_RECV_FROM_SERVER => write!(f, "error receiving response from server"),
+            ERR_TIMEOUT => write!(f, "timeout waiting for response from server"),
             _ => {
                 let errnum = self.0 as c_int;
                 let nullptr: *const c_void = std::ptr::null();
@@ -67,23 +105,199 @@ impl From<std::ffi::NulError> for PamError {
     }
 }
 
-pub(crate) fn pam_auth(service: &str, user: &str, pass: &str, remip: &str) -> Result<(), PamError> {
+// The "NAME=value" strings of a list from pam_getenvlist, which is freed.
+unsafe fn env_list(list: *mut *mut c_char) -> Vec<String> {
+    let mut env = Vec::new();
+    if list.is_null() {
+        return env;
+    }
+    let mut i = 0;
+    loop {
+        let entry = *list.add(i);
+        if entry.is_null() {
+            break;
+        }
+        env.push(CStr::from_ptr(entry).to_string_lossy().into_owned());
+        libc::free(entry as *mut c_void);
+        i += 1;
+    }
+    libc::free(list as *mut c_void);
+    env
+}
+
+// In test mode, the environment tells what the modules got.
+fn test_env(remip: &str, tty: &str) -> Vec<String> {
+    vec![format!("TEST_RHOST={}", remip), format!("TEST_TTY={}", tty)]
+}
+
+// With "env", the credentials are established, and the PAM environment
+// after that is returned.
+pub(crate) fn pam_auth(
+    service: &str,
+    user: &str,
+    pass: &str,
+    extra: &[String],
+    remip: &str,
+    tty: &str,
+    env: bool,
+) -> Result<Vec<String>, PamError>
+{
     if TEST_MODE.load(Ordering::SeqCst) > 0 {
-        return if user == "test" { Ok(()) } else { Err(PamError(1)) };
+        // in test mode, user "sleep" hangs for a while, user "crash"
+        // kills the server, and any extra responses must be "123456".
+        if user == "sleep" {
+            std::thread::sleep(std::time::Duration::from_secs(2));
+        }
+        if user == "crash" {
+            std::process::exit(1);
+        }
+        let extra_ok = extra.iter().all(|e| e == "123456");
+        return match user == "test" && extra_ok {
+            true if env => Ok(test_env(remip, tty)),
+            true => Ok(Vec::new()),
+            false => Err(PamError(1)),
+        };
     }
 
     let c_service = CString::new(service)?;
     let c_user = CString::new(user)?;
     let c_pass = CString::new(pass)?;
     let c_remip = CString::new(remip)?;
+    let c_tty = CString::new(tty)?;
+    let c_extra = extra
+        .iter()
+        .map(|e| CString::new(e.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let c_extra_ptrs: Vec<*const c_char> = c_extra.iter().map(|e| e.as_ptr()).collect();
+    let mut list: *mut *mut c_char = std::ptr::null_mut();
     let ret = unsafe {
         c_pam_auth(
             c_service.as_ptr(),
             c_user.as_ptr(),
             c_pass.as_ptr(),
+            c_extra_ptrs.as_ptr(),
+            c_extra_ptrs.len() as c_int,
             c_remip.as_ptr(),
+            c_tty.as_ptr(),
+            if env { &mut list } else { std::ptr::null_mut() },
         )
     };
+    let env = unsafe { env_list(list) };
+    match ret {
+        0 => Ok(env),
+        errnum => Err(PamError(errnum)),
+    }
+}
+
+// Authenticate with the old password, check the account, and change it.
+pub(crate) fn pam_chauthtok(
+    service: &str,
+    user: &str,
+    pass: &str,
+    extra: &[String],
+    newpass: &str,
+    remip: &str,
+    tty: &str,
+) -> Result<(), PamError>
+{
+    if TEST_MODE.load(Ordering::SeqCst) > 0 {
+        // in test mode, the new password "weak" is not accepted.
+        return match user {
+            "test" if newpass == "weak" => Err(PamError(unsafe { c_pam_authtok_err() })),
+            "test" => Ok(()),
+            _ => Err(PamError(1)),
+        };
+    }
+
+    let c_service = CString::new(service)?;
+    let c_user = CString::new(user)?;
+    let c_pass = CString::new(pass)?;
+    let c_newpass = CString::new(newpass)?;
+    let c_remip = CString::new(remip)?;
+    let c_tty = CString::new(tty)?;
+    let c_extra = extra
+        .iter()
+        .map(|e| CString::new(e.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let c_extra_ptrs: Vec<*const c_char> = c_extra.iter().map(|e| e.as_ptr()).collect();
+    let ret = unsafe {
+        c_pam_chauthtok(
+            c_service.as_ptr(),
+            c_user.as_ptr(),
+            c_pass.as_ptr(),
+            c_extra_ptrs.as_ptr(),
+            c_extra_ptrs.len() as c_int,
+            c_newpass.as_ptr(),
+            c_remip.as_ptr(),
+            c_tty.as_ptr(),
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        errnum => Err(PamError(errnum)),
+    }
+}
+
+pub(crate) fn pam_acct_mgmt(service: &str, user: &str, remip: &str, tty: &str) -> Result<(), PamError> {
+    if TEST_MODE.load(Ordering::SeqCst) > 0 {
+        return if user == "test" { Ok(()) } else { Err(PamError(1)) };
+    }
+
+    let c_service = CString::new(service)?;
+    let c_user = CString::new(user)?;
+    let c_remip = CString::new(remip)?;
+    let c_tty = CString::new(tty)?;
+    let ret = unsafe {
+        c_pam_acct_mgmt(c_service.as_ptr(), c_user.as_ptr(), c_remip.as_ptr(), c_tty.as_ptr())
+    };
+    match ret {
+        0 => Ok(()),
+        errnum => Err(PamError(errnum)),
+    }
+}
+
+// Returns the pam handle, as an usize so it can be sent between threads,
+// and the PAM environment.
+pub(crate) fn pam_open_session(
+    service: &str,
+    user: &str,
+    remip: &str,
+    tty: &str,
+) -> Result<(usize, Vec<String>), PamError>
+{
+    if TEST_MODE.load(Ordering::SeqCst) > 0 {
+        return if user == "test" { Ok((0, test_env(remip, tty))) } else { Err(PamError(1)) };
+    }
+
+    let c_service = CString::new(service)?;
+    let c_user = CString::new(user)?;
+    let c_remip = CString::new(remip)?;
+    let c_tty = CString::new(tty)?;
+    let mut handle: *mut c_void = std::ptr::null_mut();
+    let mut list: *mut *mut c_char = std::ptr::null_mut();
+    let ret = unsafe {
+        c_pam_open_session(
+            c_service.as_ptr(),
+            c_user.as_ptr(),
+            c_remip.as_ptr(),
+            c_tty.as_ptr(),
+            &mut handle,
+            &mut list,
+        )
+    };
+    let env = unsafe { env_list(list) };
+    match ret {
+        0 => Ok((handle as usize, env)),
+        errnum => Err(PamError(errnum)),
+    }
+}
+
+pub(crate) fn pam_close_session(handle: usize) -> Result<(), PamError> {
+    if TEST_MODE.load(Ordering::SeqCst) > 0 {
+        return Ok(());
+    }
+
+    let ret = unsafe { c_pam_close_session(handle as *mut c_void) };
     match ret {
         0 => Ok(()),
         errnum => Err(PamError(errnum)),
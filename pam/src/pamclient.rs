@@ -6,46 +6,103 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::io;
 use std::os::unix::net::UnixStream as StdUnixStream;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, Once};
+use std::time::Duration;
 
 use futures::channel::{mpsc, oneshot};
-use futures::join;
+use futures::future::{select, Either};
 use futures::{sink::SinkExt, stream::StreamExt};
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::unix::ReadHalf as UnixReadHalf;
-use tokio::net::unix::WriteHalf as UnixWriteHalf;
+use tokio::net::unix::OwnedReadHalf as UnixReadHalf;
+use tokio::net::unix::OwnedWriteHalf as UnixWriteHalf;
 use tokio::net::UnixStream;
 
-use crate::pam::{PamError, ERR_RECV_FROM_SERVER, ERR_SEND_TO_SERVER};
-use crate::pamserver::{PamResponse, PamServer};
+use crate::pam::{PamError, ERR_RECV_FROM_SERVER, ERR_SEND_TO_SERVER, ERR_TIMEOUT};
+use crate::pamserver::{PamResponse, PamServer, Sandbox};
+
+// How often a request is sent to a server before giving up.
+const MAX_TRIES: u32 = 2;
+// Wait this long before starting a new server.
+const RESTART_DELAY_MS: u64 = 250;
+
+// What the server should do.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum PamOp {
+    Auth,
+    // change the password to "newpass".
+    Chauthtok,
+    Account,
+    OpenSession,
+    // close the session that was opened by the request with this id.
+    CloseSession(u64),
+    Ping,
+}
 
 // Request to be sent to the server process.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct PamRequest {
     pub id:      u64,
+    pub op:      PamOp,
     pub user:    String,
     pub pass:    String,
+    pub extra:   Vec<String>,
+    pub newpass: String,
     pub service: String,
     pub remip:   Option<String>,
+    pub tty:     Option<String>,
+    // ask for the PAM environment (and establish credentials for it).
+    pub env:     bool,
+}
+
+impl PamRequest {
+    fn new(op: PamOp, service: &str, user: &str, remip: Option<&str>) -> PamRequest {
+        PamRequest {
+            id: 0,
+            op,
+            user: user.to_string(),
+            pass: String::new(),
+            extra: Vec::new(),
+            newpass: String::new(),
+            service: service.to_string(),
+            remip: remip.map(|s| s.to_string()),
+            tty: None,
+            env: false,
+        }
+    }
 }
 
+// The id of the request and the PAM environment, or an error.
+type PamReply = Result<(u64, Vec<String>), PamError>;
+
 // sent over request channel to PamAuthTask.
 struct PamRequest1 {
     req:       PamRequest,
-    resp_chan: oneshot::Sender<Result<(), PamError>>,
+    resp_chan: oneshot::Sender<PamReply>,
+}
+
+// "NAME=value" strings as pairs.
+fn parse_env(env: Vec<String>) -> Vec<(String, String)> {
+    env.iter()
+        .filter_map(|e| e.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
 }
 
 /// Pam authenticator.
 #[derive(Clone)]
 pub struct PamAuth {
-    inner: Arc<PamAuthInner>,
+    inner:   Arc<PamAuthInner>,
+    timeout: Option<Duration>,
+    tty:     Option<String>,
 }
 
 struct PamAuthInner {
-    once:       Once,
-    serversock: RefCell<Option<StdUnixStream>>,
-    req_chan:   RefCell<Option<mpsc::Sender<PamRequest1>>>,
+    once:     Once,
+    ctlsock:  RefCell<Option<StdUnixStream>>,
+    req_chan: RefCell<Option<mpsc::Sender<PamRequest1>>>,
+    restarts: Arc<AtomicU64>,
 }
 
 // Mutation of PamAuthInner only happens once,
@@ -58,6 +115,9 @@ impl PamAuth {
     /// in the background, and it will contain a new PAM coordination task that
     /// will be lazily spawned the first time auth() is called.
     ///
+    /// The server process is supervised: if it dies, a new one is started
+    /// and requests that were in progress are retried once.
+    ///
     /// Note that it is important to call this very early in main(), before any
     /// threads or runtimes have started.
     ///
@@ -79,19 +139,68 @@ impl PamAuth {
     /// ```
     ///
     pub fn new(num_threads: Option<usize>) -> Result<PamAuth, io::Error> {
-        // spawn the server process.
-        let serversock = PamServer::start(num_threads)?;
+        PamAuth::with_options(num_threads, None)
+    }
+
+    /// Like `new()`, with a timeout for every request.
+    ///
+    /// - `num_threads`: number of threads in the PAM server process (default 8).
+    /// - `timeout`: if the PAM server does not answer in time, for example
+    ///   because a PAM module hangs, the request fails with an error.
+    ///   Note that the thread in the server process stays busy until
+    ///   the PAM module returns.
+    pub fn with_options(
+        num_threads: Option<usize>,
+        timeout: Option<Duration>,
+    ) -> Result<PamAuth, io::Error>
+    {
+        PamAuth::start(num_threads, timeout, None)
+    }
+
+    /// Like `with_options()`, and `sandbox` is called in the PAM server
+    /// process before it serves requests, for example to set up a seccomp
+    /// filter. If it fails, the server process exits.
+    pub fn with_sandbox<F>(
+        num_threads: Option<usize>,
+        timeout: Option<Duration>,
+        sandbox: F,
+    ) -> Result<PamAuth, io::Error>
+    where
+        F: Fn() -> io::Result<()> + Send + 'static,
+    {
+        PamAuth::start(num_threads, timeout, Some(Box::new(sandbox)))
+    }
+
+    fn start(
+        num_threads: Option<usize>,
+        timeout: Option<Duration>,
+        sandbox: Option<Sandbox>,
+    ) -> Result<PamAuth, io::Error>
+    {
+        // spawn the supervisor process, which starts the server process.
+        let ctlsock = PamServer::start(num_threads, sandbox)?;
 
         let inner = PamAuthInner {
-            once:       Once::new(),
-            req_chan:   RefCell::new(None),
-            serversock: RefCell::new(Some(serversock)),
+            once:     Once::new(),
+            req_chan: RefCell::new(None),
+            ctlsock:  RefCell::new(Some(ctlsock)),
+            restarts: Arc::new(AtomicU64::new(0)),
         };
         Ok(PamAuth {
             inner: Arc::new(inner),
+            timeout,
+            tty: None,
         })
     }
 
+    /// Set (or with `None`, unset) PAM_TTY for the requests made with
+    /// this handle, and with the clones of it made after this. Modules
+    /// like pam_access and pam_time match on it; it does not have to be
+    /// a real tty.
+    pub fn set_tty(&mut self, tty: Option<&str>) {
+        self.tty = tty.map(|s| s.to_string());
+    }
+
     /// Authenticate via pam and return the result.
     ///
     /// - `service`: PAM service to use - usually "other".
@@ -106,80 +215,380 @@ impl PamAuth {
         remoteip: Option<&str>,
     ) -> Result<(), PamError>
     {
+        self.auth_conv(service, username, password, &[], remoteip).await
+    }
+
+    /// Authenticate via pam, answering additional password prompts.
+    ///
+    /// Like `auth()`, but for multi-step PAM conversations such as
+    /// pam_oath or pam_google_authenticator asking for a one-time
+    /// password after the password. The first password prompt is
+    /// answered with `password`, the next ones with the entries
+    /// of `extra`, in order. If there are more prompts than answers
+    /// the conversation fails.
+    ///
+    /// With an empty `extra`, this is the same as `auth()`.
+    pub async fn auth_conv(
+        &mut self,
+        service: &str,
+        username: &str,
+        password: &str,
+        extra: &[&str],
+        remoteip: Option<&str>,
+    ) -> Result<(), PamError>
+    {
+        let mut req = PamRequest::new(PamOp::Auth, service, username, remoteip);
+        req.pass = password.to_string();
+        req.extra = extra.iter().map(|s| s.to_string()).collect();
+        self.request(req).await.map(|_| ())
+    }
+
+    /// Authenticate via pam, and return the PAM environment.
+    ///
+    /// Like `auth_conv()`, but after authentication the credentials
+    /// are established (`pam_setcred`), which is where modules like
+    /// pam_env and pam_krb5 set their environment variables. The
+    /// result is the environment after that (`pam_getenvlist`), as
+    /// name and value pairs.
+    pub async fn auth_env(
+        &mut self,
+        service: &str,
+        username: &str,
+        password: &str,
+        extra: &[&str],
+        remoteip: Option<&str>,
+    ) -> Result<Vec<(String, String)>, PamError>
+    {
+        let mut req = PamRequest::new(PamOp::Auth, service, username, remoteip);
+        req.pass = password.to_string();
+        req.extra = extra.iter().map(|s| s.to_string()).collect();
+        req.env = true;
+        self.request(req).await.map(|(_, env)| parse_env(env))
+    }
+
+    /// Change the password via pam (`pam_chauthtok`).
+    ///
+    /// The old `password` (and `extra`, as with `auth_conv()`) is checked
+    /// first, and then the account (`pam_acct_mgmt`); that the password
+    /// expired is fine here, that is what this is for. The password
+    /// prompts of `pam_chauthtok` are answered with `new_password`,
+    /// except one that asks for the current password. If the new one
+    /// is not accepted, the error says so (`new_password_refused()`).
+    pub async fn change_password(
+        &mut self,
+        service: &str,
+        username: &str,
+        password: &str,
+        extra: &[&str],
+        new_password: &str,
+        remoteip: Option<&str>,
+    ) -> Result<(), PamError>
+    {
+        let mut req = PamRequest::new(PamOp::Chauthtok, service, username, remoteip);
+        req.pass = password.to_string();
+        req.extra = extra.iter().map(|s| s.to_string()).collect();
+        req.newpass = new_password.to_string();
+        self.request(req).await.map(|_| ())
+    }
+
+    /// Check the account via pam (`pam_acct_mgmt`).
+    ///
+    /// This is where account expiry, access-time restrictions (pam_time)
+    /// and access lists (pam_access) are enforced. It does not check
+    /// the password, so call it after `auth()` succeeded.
+    pub async fn account(
+        &mut self,
+        service: &str,
+        username: &str,
+        remoteip: Option<&str>,
+    ) -> Result<(), PamError>
+    {
+        let req = PamRequest::new(PamOp::Account, service, username, remoteip);
+        self.request(req).await.map(|_| ())
+    }
+
+    /// Open a pam session (`pam_open_session`).
+    ///
+    /// The session is closed again (`pam_close_session`) when the
+    /// returned `PamSession` is dropped. Note that the session modules
+    /// run in the PAM server process, so modules that change the
+    /// process itself, like pam_limits, have no effect on the caller.
+    /// Modules like pam_mkhomedir or pam_lastlog work fine.
+    pub async fn open_session(
+        &mut self,
+        service: &str,
+        username: &str,
+        remoteip: Option<&str>,
+    ) -> Result<PamSession, PamError>
+    {
+        let req = PamRequest::new(PamOp::OpenSession, service, username, remoteip);
+        let (id, env) = self.request(req).await?;
+        Ok(PamSession {
+            id,
+            service: service.to_string(),
+            env: parse_env(env),
+            req_chan: self.req_chan(),
+        })
+    }
+
+    /// Check that the PAM server process is alive and responding.
+    ///
+    /// Meant for health checks. Use together with a timeout.
+    pub async fn ping(&mut self) -> Result<(), PamError> {
+        let req = PamRequest::new(PamOp::Ping, "", "", None);
+        self.request(req).await.map(|_| ())
+    }
+
+    /// How often the PAM server process was restarted because it died.
+    pub fn restarts(&self) -> u64 {
+        self.inner.restarts.load(Ordering::SeqCst)
+    }
+
+    // Get a handle to the background task, starting it if needed.
+    fn req_chan(&self) -> mpsc::Sender<PamRequest1> {
         // If we haven't started the background task yet, do it now.
         // That also initializes req_chan.
         let inner = &self.inner;
         inner.once.call_once(|| {
             // These should not ever panic on unwrap().
-            let serversock = inner.serversock.borrow_mut().take().unwrap();
+            let ctlsock = inner.ctlsock.borrow_mut().take().unwrap();
+            let restarts = inner.restarts.clone();
             inner
                 .req_chan
-                .replace(Some(PamAuthTask::start(serversock).unwrap()));
+                .replace(Some(PamAuthTask::start(ctlsock, restarts).unwrap()));
         });
+        inner.req_chan.borrow().as_ref().unwrap().clone()
+    }
 
-        // create request to be sent to the server.
-        let req = PamRequest {
-            id:      0,
-            user:    username.to_string(),
-            pass:    password.to_string(),
-            service: service.to_string(),
-            remip:   remoteip.map(|s| s.to_string()),
-        };
+    // Send a request to the server, and wait for the result.
+    // On success, returns the id of the request and the environment.
+    async fn request(&mut self, mut req: PamRequest) -> PamReply {
+        req.tty = self.tty.clone();
 
         // add a one-shot channel for the response.
-        let (tx, rx) = oneshot::channel::<Result<(), PamError>>();
+        let (tx, rx) = oneshot::channel::<PamReply>();
 
         // put it all together and send it.
         let req1 = PamRequest1 {
-            req:       req,
+            req,
             resp_chan: tx,
         };
-        let mut authtask_chan = inner.req_chan.borrow().as_ref().unwrap().clone();
+        let mut authtask_chan = self.req_chan();
         authtask_chan
             .send(req1)
             .await
             .map_err(|_| PamError(ERR_SEND_TO_SERVER))?;
 
         // wait for the response.
-        match rx.await {
+        let res = match self.timeout {
+            Some(timeout) => {
+                tokio::time::timeout(timeout, rx)
+                    .await
+                    .map_err(|_| PamError(ERR_TIMEOUT))?
+            },
+            None => rx.await,
+        };
+        match res {
             Ok(res) => res,
             Err(_) => Err(PamError(ERR_RECV_FROM_SERVER)),
         }
     }
 }
 
+/// An open pam session.
+///
+/// The session is closed when this is dropped.
+pub struct PamSession {
+    id:       u64,
+    service:  String,
+    env:      Vec<(String, String)>,
+    req_chan: mpsc::Sender<PamRequest1>,
+}
+
+impl PamSession {
+    /// The PAM environment after the session was opened.
+    pub fn env(&self) -> &[(String, String)] {
+        &self.env
+    }
+}
+
+impl Drop for PamSession {
+    fn drop(&mut self) {
+        let req = PamRequest::new(PamOp::CloseSession(self.id), &self.service, "", None);
+        let (tx, _) = oneshot::channel::<PamReply>();
+        let req1 = PamRequest1 {
+            req,
+            resp_chan: tx,
+        };
+        // Every sender has a guaranteed slot, so this only
+        // fails if the background task has gone away.
+        if self.req_chan.try_send(req1).is_err() {
+            debug!("PamSession::drop: failed to close session {}", self.id);
+        }
+    }
+}
+
+// Serialize a request, with the 2-byte length header.
+fn encode_request(req: &PamRequest) -> Vec<u8> {
+    let mut data: Vec<u8> = match bincode::serialize(req) {
+        Ok(data) => data,
+        Err(e) => {
+            // this panic can never happen at runtime.
+            panic!("PamClient: serializing data: {:?}", e);
+        },
+    };
+    if data.len() > 65533 {
+        // this panic can never happen at runtime.
+        panic!("PamClient: serialized data > 65533 bytes");
+    }
+    let l1 = ((data.len() >> 8) & 0xff) as u8;
+    let l2 = (data.len() & 0xff) as u8;
+    data.insert(0, l1);
+    data.insert(1, l2);
+    data
+}
+
+// A request that was sent to the server, and is waiting for a response.
+struct Waiter {
+    data:      Vec<u8>,
+    tries:     u32,
+    resp_chan: oneshot::Sender<PamReply>,
+}
+
+// Why handle_request() returned.
+enum Exit {
+    // PamAuth handle was dropped.
+    Done,
+    // Error writing to server.
+    ServerGone,
+}
+
 // Shared data for the PamAuthTask tasks.
 struct PamAuthTask {
     // clients waiting for a response.
-    waiters: Mutex<HashMap<u64, oneshot::Sender<Result<(), PamError>>>>,
+    waiters:  Mutex<HashMap<u64, Waiter>>,
+    // id of the next request.
+    next_id:  AtomicU64,
+    // how often the server was restarted.
+    restarts: Arc<AtomicU64>,
 }
 
 impl PamAuthTask {
-    // Start the server process. Then return a handle to send requests on.
-    fn start(serversock: StdUnixStream) -> io::Result<mpsc::Sender<PamRequest1>> {
-        let mut serversock = UnixStream::from_std(serversock)?;
-
+    // Start the coordination task. Then return a handle to send requests on.
+    fn start(ctlsock: StdUnixStream, restarts: Arc<AtomicU64>) -> io::Result<mpsc::Sender<PamRequest1>> {
         // create a request channel.
         let (req_tx, req_rx) = mpsc::channel::<PamRequest1>(0);
 
         // shared state between request and response task.
         let this = PamAuthTask {
             waiters: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            restarts,
         };
 
         debug!("PamAuthTask: spawning task on runtime");
         tokio::spawn(async move {
-            // split serversock into send/receive halves.
-            let (srx, stx) = serversock.split();
-
-            join!(this.handle_request(req_rx, stx), this.handle_response(srx));
+            this.run(ctlsock, req_rx).await;
         });
 
         Ok(req_tx)
     }
 
-    async fn handle_request(&self, mut req_rx: mpsc::Receiver<PamRequest1>, mut stx: UnixWriteHalf<'_>) {
-        let mut id: u64 = 0;
+    // Get a server from the supervisor and serve requests. If the
+    // server goes away, get a new one and resend the requests that
+    // did not get an answer yet.
+    async fn run(&self, ctlsock: StdUnixStream, mut req_rx: mpsc::Receiver<PamRequest1>) {
+        let mut ctlsock = Some(ctlsock);
+        let mut first = true;
+        loop {
+            if !first {
+                self.restarts.fetch_add(1, Ordering::SeqCst);
+                // don't go into a tight loop if the server keeps dying.
+                tokio::time::sleep(Duration::from_millis(RESTART_DELAY_MS)).await;
+            }
+            first = false;
+
+            // ask the supervisor for a new server. that's blocking, but quick.
+            let ctl = ctlsock.take().unwrap();
+            let res = tokio::task::spawn_blocking(move || {
+                let res = PamServer::connect(&ctl);
+                (ctl, res)
+            })
+            .await;
+            let serversock = match res {
+                Ok((ctl, res)) => {
+                    ctlsock = Some(ctl);
+                    res.and_then(|sock| {
+                        sock.set_nonblocking(true)?;
+                        UnixStream::from_std(sock)
+                    })
+                },
+                Err(e) => Err(io::Error::other(e)),
+            };
+            let serversock = match serversock {
+                Ok(sock) => sock,
+                Err(e) => {
+                    error!("PamClient: FATAL: cannot start server: {}", e);
+                    if ctlsock.is_none() {
+                        self.fail_all();
+                        return;
+                    }
+                    continue;
+                },
+            };
+            let (srx, mut stx) = serversock.into_split();
+
+            // resend what's still waiting for an answer.
+            if !self.resend(&mut stx).await {
+                continue;
+            }
+
+            let req_fut = Box::pin(self.handle_request(&mut req_rx, stx));
+            let resp_fut = Box::pin(self.handle_response(srx));
+            match select(req_fut, resp_fut).await {
+                Either::Left((Exit::Done, _)) => return,
+                _ => error!("PamClient: server gone away, restarting it"),
+            }
+        }
+    }
+
+    // Resend the outstanding requests to a new server. Requests that
+    // were already tried before might be what crashed the server,
+    // so those fail instead.
+    async fn resend(&self, stx: &mut UnixWriteHalf) -> bool {
+        let mut data = Vec::new();
+        {
+            let mut waiters = self.waiters.lock().unwrap();
+            let failed: Vec<u64> = waiters
+                .iter()
+                .filter(|(_, w)| w.tries >= MAX_TRIES)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in failed {
+                if let Some(w) = waiters.remove(&id) {
+                    let _ = w.resp_chan.send(Err(PamError(ERR_RECV_FROM_SERVER)));
+                }
+            }
+            for w in waiters.values_mut() {
+                w.tries += 1;
+                data.extend_from_slice(&w.data);
+            }
+        }
+        if data.is_empty() {
+            return true;
+        }
+        stx.write_all(&data).await.is_ok()
+    }
+
+    // Fail all outstanding requests.
+    fn fail_all(&self) {
+        let mut waiters = self.waiters.lock().unwrap();
+        for (_, w) in waiters.drain() {
+            let _ = w.resp_chan.send(Err(PamError(ERR_RECV_FROM_SERVER)));
+        }
+    }
+
+    async fn handle_request(&self, req_rx: &mut mpsc::Receiver<PamRequest1>, mut stx: UnixWriteHalf) -> Exit {
         loop {
             // receive next request.
             let PamRequest1 { mut req, resp_chan } = match req_rx.next().await {
@@ -188,58 +597,46 @@ impl PamAuthTask {
                     // PamAuth handle was dropped. Ask server to exit.
                     let data = [0u8; 2];
                     let _ = stx.write_all(&data).await;
-                    return;
+                    return Exit::Done;
                 },
             };
 
-            // store the response channel.
-            req.id = id;
-            id += 1;
+            // store the request and the response channel.
+            req.id = self.next_id.fetch_add(1, Ordering::SeqCst);
+            let data = encode_request(&req);
             {
                 let mut waiters = self.waiters.lock().unwrap();
-                waiters.insert(req.id, resp_chan);
+                let waiter = Waiter {
+                    data: data.clone(),
+                    tries: 1,
+                    resp_chan,
+                };
+                waiters.insert(req.id, waiter);
             }
 
-            // serialize data and send.
-            let mut data: Vec<u8> = match bincode::serialize(&req) {
-                Ok(data) => data,
-                Err(e) => {
-                    // this panic can never happen at runtime.
-                    panic!("PamClient: serializing data: {:?}", e);
-                },
-            };
-            if data.len() > 65533 {
-                // this panic can never happen at runtime.
-                panic!("PamClient: serialized data > 65533 bytes");
-            }
-            let l1 = ((data.len() >> 8) & 0xff) as u8;
-            let l2 = (data.len() & 0xff) as u8;
-            data.insert(0, l1);
-            data.insert(1, l2);
+            // and send.
             if let Err(e) = stx.write_all(&data).await {
                 // this can happen if the server has gone away.
-                // in which case, handle_response() will exit as well.
-                error!("PamClient: FATAL: writing data to server: {:?}", e);
-                return;
+                error!("PamClient: writing data to server: {:?}", e);
+                return Exit::ServerGone;
             }
         }
     }
 
-    async fn handle_response(&self, mut srx: UnixReadHalf<'_>) {
+    async fn handle_response(&self, mut srx: UnixReadHalf) {
         loop {
             // read size header.
             let mut buf = [0u8; 2];
-            if let Err(_) = srx.read_exact(&mut buf).await {
-                error!("PamClient: FATAL: short read, server gone away?!");
+            if srx.read_exact(&mut buf).await.is_err() {
+                error!("PamClient: short read, server gone away?!");
                 return;
             }
             let sz = ((buf[0] as usize) << 8) + (buf[1] as usize);
 
             // read response data.
-            let mut data = Vec::with_capacity(sz);
-            data.resize(sz, 0u8);
-            if let Err(_) = srx.read_exact(&mut data[..]).await {
-                error!("PamClient: FATAL: short read, server gone away?!");
+            let mut data = vec![0; sz];
+            if srx.read_exact(&mut data[..]).await.is_err() {
+                error!("PamClient: short read, server gone away?!");
                 return;
             }
 
@@ -253,12 +650,13 @@ impl PamAuthTask {
             };
 
             // and send response to waiting requester.
-            let resp_chan = {
+            let waiter = {
                 let mut waiters = self.waiters.lock().unwrap();
                 waiters.remove(&resp.id)
             };
-            if let Some(resp_chan) = resp_chan {
-                let _ = resp_chan.send(resp.result);
+            if let Some(waiter) = waiter {
+                let id = resp.id;
+                let _ = waiter.resp_chan.send(resp.result.map(|env| (id, env)));
             }
         }
     }
@@ -1,69 +1,124 @@
 // Server part - the code here is fork()ed off and lives in its own
 // process. We communicate with it through a unix stream socket.
 //
+// That process is started by a supervisor process, so that it
+// can be restarted if it dies.
+//
 // This is all old-fashioned blocking and thread-based code.
 //
+use std::collections::HashMap;
 use std::io::{self, Read, Write};
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::UnixStream as StdUnixStream;
 use std::sync::{Arc, Mutex};
 
 use bincode::{deserialize, serialize};
-use libc;
 
-use crate::pam::{pam_auth, pam_lower_rlimits, PamError};
-use crate::pamclient::PamRequest;
+use crate::pam::{
+    pam_acct_mgmt, pam_auth, pam_chauthtok, pam_close_session, pam_lower_rlimits, pam_open_session, PamError,
+};
+use crate::pamclient::{PamOp, PamRequest};
+
+// Open sessions: request id -> pam handle.
+type Sessions = Arc<Mutex<HashMap<u64, usize>>>;
+
+// Called in a new server process, before it serves requests.
+pub(crate) type Sandbox = Box<dyn Fn() -> io::Result<()> + Send>;
 
 // Response back from the server process.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct PamResponse {
     pub id:     u64,
-    pub result: Result<(), PamError>,
+    // the PAM environment, "NAME=value".
+    pub result: Result<Vec<String>, PamError>,
 }
 
 // server side.
 pub(crate) struct PamServer {
     rx_socket: StdUnixStream,
     tx_socket: Arc<Mutex<StdUnixStream>>,
+    sessions:  Sessions,
 }
 
 impl PamServer {
-    // fork and start the server, return the stream socket for communication.
-    pub(crate) fn start(num_threads: Option<usize>) -> Result<StdUnixStream, io::Error> {
+    // fork and start the supervisor, return the control socket.
+    //
+    // The supervisor is a small single-threaded process that forks
+    // a new server every time it is asked to, and passes the socket
+    // for that server back over the control socket. This way a new
+    // server can be started if the old one died, without forking
+    // the (by then multi-threaded) main process.
+    pub(crate) fn start(
+        num_threads: Option<usize>,
+        sandbox: Option<Sandbox>,
+    ) -> Result<StdUnixStream, io::Error>
+    {
         // Create a unix socketpair for communication.
         let (sock1, sock2) = StdUnixStream::pair()?;
-        let sock3 = sock2.try_clone()?;
 
         let handle = std::thread::spawn(move || {
-            // fork server.
+            // fork supervisor.
             let pid = unsafe { libc::fork() };
             if pid < 0 {
                 return Err(io::Error::last_os_error());
             }
             if pid == 0 {
                 // first, close all filedescriptors (well, all..)
-                for fdno in 3..8192 {
-                    if fdno != sock2.as_raw_fd() && fdno != sock3.as_raw_fd() {
-                        unsafe {
-                            libc::close(fdno);
-                        }
-                    }
-                }
-                let mut server = PamServer {
-                    rx_socket: sock2,
-                    tx_socket: Arc::new(Mutex::new(sock3)),
-                };
+                close_fds(&[sock2.as_raw_fd()]);
                 pam_lower_rlimits();
-                trace!("PamServer: child: starting server");
-                server.serve(num_threads.unwrap_or(8));
-                drop(server);
-                std::process::exit(0);
+                trace!("PamServer: supervisor: started");
+                supervise(sock2, num_threads.unwrap_or(8), sandbox);
             }
             Ok(())
         });
         handle.join().unwrap()?;
 
-        trace!("PamServer: parent: started server");
+        trace!("PamServer: parent: started supervisor");
+        Ok(sock1)
+    }
+
+    // ask the supervisor for a new server, return the stream socket for communication.
+    pub(crate) fn connect(ctlsock: &StdUnixStream) -> Result<StdUnixStream, io::Error> {
+        (&*ctlsock).write_all(&[1u8])?;
+        let fd = recv_fd(ctlsock)?;
+        Ok(unsafe { StdUnixStream::from_raw_fd(fd) })
+    }
+
+    // fork a server, return the stream socket for communication.
+    fn fork_server(
+        num_threads: usize,
+        ctlsock: &StdUnixStream,
+        sandbox: Option<&Sandbox>,
+    ) -> Result<StdUnixStream, io::Error>
+    {
+        // Create a unix socketpair for communication.
+        let (sock1, sock2) = StdUnixStream::pair()?;
+        let sock3 = sock2.try_clone()?;
+
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if pid == 0 {
+            unsafe {
+                libc::close(ctlsock.as_raw_fd());
+                libc::close(sock1.as_raw_fd());
+            }
+            if let Some(Err(e)) = sandbox.map(|sandbox| sandbox()) {
+                error!("PamServer: child: sandbox: {}", e);
+                std::process::exit(1);
+            }
+            let mut server = PamServer {
+                rx_socket: sock2,
+                tx_socket: Arc::new(Mutex::new(sock3)),
+                sessions:  Arc::new(Mutex::new(HashMap::new())),
+            };
+            trace!("PamServer: child: starting server");
+            server.serve(num_threads);
+            drop(server);
+            std::process::exit(0);
+        }
+        trace!("PamServer: supervisor: started server, pid {}", pid);
         Ok(sock1)
     }
 
@@ -93,8 +148,7 @@ impl PamServer {
             }
 
             // read request data.
-            let mut data = Vec::with_capacity(sz);
-            data.resize(sz, 0u8);
+            let mut data = vec![0; sz];
             let res = self.rx_socket.read_exact(&mut data);
             if let Err(e) = res {
                 panic!("PamServer::serve: read socket: {}", e);
@@ -112,8 +166,9 @@ impl PamServer {
 
             // run request on pool.
             let sock = self.tx_socket.clone();
+            let sessions = self.sessions.clone();
             pool.execute(move || {
-                if let Err(e) = pam_process(req, sock) {
+                if let Err(e) = pam_process(req, sock, sessions) {
                     panic!("PamServer::pam_process: error: {}", e);
                 }
             });
@@ -128,7 +183,7 @@ impl PamServer {
                     );
                 }
                 i += 1;
-                i = i % 400;
+                i %= 400;
                 std::thread::sleep(std::time::Duration::from_millis(5));
             }
         }
@@ -140,20 +195,43 @@ impl PamServer {
 }
 
 // Process one request. This is run on the threadpool.
-fn pam_process(req: PamRequest, sock: Arc<Mutex<StdUnixStream>>) -> Result<(), io::Error> {
+fn pam_process(
+    req: PamRequest,
+    sock: Arc<Mutex<StdUnixStream>>,
+    sessions: Sessions,
+) -> Result<(), io::Error>
+{
     trace!("PamServer::pam_process: starting with request {:?}", req);
 
-    // authenticate.
-    let remip = req.remip.as_ref().map(|s| s.as_str()).unwrap_or("");
-    let res = PamResponse {
-        id:     req.id,
-        result: pam_auth(&req.service, &req.user, &req.pass, remip),
+    // authenticate, or do one of the other operations.
+    let remip = req.remip.as_deref().unwrap_or("");
+    let tty = req.tty.as_deref().unwrap_or("");
+    let result = match req.op {
+        PamOp::Auth => pam_auth(&req.service, &req.user, &req.pass, &req.extra, remip, tty, req.env),
+        PamOp::Chauthtok => {
+            let (service, user, pass) = (&req.service, &req.user, &req.pass);
+            pam_chauthtok(service, user, pass, &req.extra, &req.newpass, remip, tty).map(|_| Vec::new())
+        },
+        PamOp::Account => pam_acct_mgmt(&req.service, &req.user, remip, tty).map(|_| Vec::new()),
+        PamOp::OpenSession => pam_open_session(&req.service, &req.user, remip, tty).map(|(handle, env)| {
+            sessions.lock().unwrap().insert(req.id, handle);
+            env
+        }),
+        PamOp::CloseSession(id) => {
+            let handle = sessions.lock().unwrap().remove(&id);
+            match handle {
+                Some(handle) => pam_close_session(handle).map(|_| Vec::new()),
+                None => Err(PamError::unknown()),
+            }
+        },
+        PamOp::Ping => Ok(Vec::new()),
     };
+    let res = PamResponse { id: req.id, result };
 
     // and send back result.
     trace!("PamServer::pam_process: returning response {:?}", res);
     let mut response: Vec<u8> = serialize(&res)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("error serializing response: {}", e)))?;
+        .map_err(|e| io::Error::other(format!("error serializing response: {}", e)))?;
     let l1 = ((response.len() >> 8) & 0xff) as u8;
     let l2 = (response.len() & 0xff) as u8;
     response.insert(0, l1);
@@ -167,3 +245,119 @@ fn pam_process(req: PamRequest, sock: Arc<Mutex<StdUnixStream>>) -> Result<(), i
         Ok(..) => Ok(()),
     }
 }
+
+// Close all filedescriptors from 3 upwards, except the ones in `keep`.
+fn close_fds(keep: &[RawFd]) {
+    for fdno in 3..8192 {
+        if !keep.contains(&fdno) {
+            unsafe {
+                libc::close(fdno);
+            }
+        }
+    }
+}
+
+// The supervisor process. Every byte read from the control socket
+// is a request for a new server. Exits when the parent goes away.
+fn supervise(ctlsock: StdUnixStream, num_threads: usize, sandbox: Option<Sandbox>) -> ! {
+    loop {
+        let mut buf = [0u8; 1];
+        match (&ctlsock).read(&mut buf) {
+            Ok(0) => break,
+            Ok(_) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                debug!("PamServer::supervise: read control socket: {}", e);
+                break;
+            },
+        }
+
+        // reap servers that have exited.
+        while unsafe { libc::waitpid(-1, std::ptr::null_mut(), libc::WNOHANG) } > 0 {}
+
+        // start a new one and pass its socket to the parent. if that
+        // fails, send a message without a socket, which is an error.
+        let res = match PamServer::fork_server(num_threads, &ctlsock, sandbox.as_ref()) {
+            Ok(sock) => send_fd(&ctlsock, Some(sock.as_raw_fd())),
+            Err(e) => {
+                debug!("PamServer::supervise: starting server: {}", e);
+                send_fd(&ctlsock, None)
+            },
+        };
+        if let Err(e) = res {
+            debug!("PamServer::supervise: write control socket: {}", e);
+            break;
+        }
+    }
+    trace!("PamServer::supervise: exit.");
+    std::process::exit(0);
+}
+
+// Room for one filedescriptor in a control message, aligned for cmsghdr.
+#[repr(C)]
+union CmsgBuf {
+    _align: libc::cmsghdr,
+    buf:    [u8; 64],
+}
+
+// Send a filedescriptor over a unix socket.
+fn send_fd(sock: &StdUnixStream, fd: Option<RawFd>) -> io::Result<()> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len:  1,
+    };
+    let mut cbuf = CmsgBuf { buf: [0u8; 64] };
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if let Some(fd) = fd {
+            msg.msg_control = cbuf.buf.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+        }
+        if libc::sendmsg(sock.as_raw_fd(), &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Receive a filedescriptor from a unix socket.
+fn recv_fd(sock: &StdUnixStream) -> io::Result<RawFd> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len:  1,
+    };
+    let mut cbuf = CmsgBuf { buf: [0u8; 64] };
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cbuf.buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = std::mem::size_of::<CmsgBuf>() as _;
+        let n = loop {
+            let n = libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+            if n >= 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                break n;
+            }
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null() || (*cmsg).cmsg_level != libc::SOL_SOCKET || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            return Err(io::Error::other("supervisor failed to start server"));
+        }
+        Ok(std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd))
+    }
+}
@@ -0,0 +1,288 @@
+//
+// Access log.
+//
+// One line per request, written when the response body is done (or the
+// client went away), so that the duration and the number of bytes are
+// those of the whole transfer. By default each line is a JSON object:
+//
+// {"bytes":1234,"duration":0.012,"method":"GET","path":"/files/a.txt","protocol":"HTTP/1.1",
+//  "referer":null,"remote_addr":"192.0.2.1","status":200,"time":"2021-06-01T12:00:00Z",
+//  "user":"bob","user_agent":"curl/7.68.0"}
+//
+// Or the Apache common or combined log format:
+//
+// 192.0.2.1 - bob [01/Jun/2021:12:00:00 +0000] "GET /files/a.txt HTTP/1.1" 200 1234 "-" "curl/7.68.0"
+//
+// The log file is reopened on SIGUSR1, for logrotate.
+//
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use hyper::body::{Bytes, HttpBody};
+use lazy_static::lazy_static;
+use serde_json::json;
+
+use crate::config::AccessFormat;
+
+enum Target {
+    Stdout,
+    Stderr,
+    File(String, File),
+}
+
+lazy_static! {
+    static ref ACCESSLOG: Mutex<Option<(Target, AccessFormat)>> = Mutex::new(None);
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn open_file(path: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+/// Open the log. Must be called before dropping privileges.
+pub fn open(path: &str, format: AccessFormat) -> io::Result<()> {
+    let target = match path {
+        "stdout" => Target::Stdout,
+        "stderr" => Target::Stderr,
+        _ => Target::File(path.to_string(), open_file(path)?),
+    };
+    *ACCESSLOG.lock().unwrap() = Some((target, format));
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Close and reopen the log file. This happens with the privileges the
+/// server runs with, so they need write access to the directory. If it
+/// fails, the old file is kept.
+pub fn reopen() -> io::Result<()> {
+    let mut accesslog = ACCESSLOG.lock().unwrap();
+    if let Some((Target::File(ref path, ref mut file), _)) = *accesslog {
+        *file = open_file(path)?;
+    }
+    Ok(())
+}
+
+/// Is there an access log.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Escape a field of the common log format, like Apache does.
+fn escape(s: &str, space: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            ' ' if space => out.push_str("\\x20"),
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The client address and user of a request. It is stored in the request
+/// extensions, and filled in while the request is handled.
+#[derive(Clone)]
+pub struct Client(Arc<Mutex<(IpAddr, Option<String>)>>);
+
+impl Client {
+    /// The real client address, when behind a trusted proxy.
+    pub fn set_addr(&self, addr: IpAddr) {
+        self.0.lock().unwrap().0 = addr;
+    }
+
+    /// The authenticated user.
+    pub fn set_user(&self, user: &str) {
+        self.0.lock().unwrap().1 = Some(user.to_string());
+    }
+}
+
+/// A request that will be logged.
+pub struct Entry {
+    client:     Client,
+    time:       time::Tm,
+    start:      Instant,
+    method:     String,
+    path:       String,
+    protocol:   String,
+    referer:    Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Entry {
+    /// Start an entry for this request, if there is an access log. The
+    /// Client is added to the request extensions.
+    pub fn new<B>(req: &mut http::Request<B>, addr: IpAddr) -> Option<Entry> {
+        if !enabled() {
+            return None;
+        }
+        let client = Client(Arc::new(Mutex::new((addr, None))));
+        req.extensions_mut().insert(client.clone());
+        let header = |name: http::header::HeaderName| {
+            req.headers()
+                .get(name)
+                .map(|h| String::from_utf8_lossy(h.as_bytes()).into_owned())
+        };
+        Some(Entry {
+            client,
+            time: time::now_utc(),
+            start: Instant::now(),
+            method: req.method().to_string(),
+            path: req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/").to_string(),
+            protocol: format!("{:?}", req.version()),
+            referer: header(http::header::REFERER),
+            user_agent: header(http::header::USER_AGENT),
+        })
+    }
+
+    fn line(&self, format: AccessFormat, status: u16, bytes: u64) -> String {
+        let (addr, user) = self.client.0.lock().unwrap().clone();
+        if format != AccessFormat::Json {
+            let quoted = |s: &Option<String>| s.as_deref().map(|s| escape(s, false)).unwrap_or("-".into());
+            let mut line = format!(
+                "{} - {} [{}] \"{} {} {}\" {} {}",
+                addr,
+                user.as_deref().map(|u| escape(u, true)).unwrap_or("-".into()),
+                self.time.strftime("%d/%b/%Y:%H:%M:%S +0000").unwrap(),
+                escape(&self.method, false),
+                escape(&self.path, false),
+                self.protocol,
+                status,
+                if bytes > 0 { bytes.to_string() } else { "-".to_string() },
+            );
+            if format == AccessFormat::Combined {
+                line.push_str(&format!(" \"{}\" \"{}\"", quoted(&self.referer), quoted(&self.user_agent)));
+            }
+            return line;
+        }
+        let line = json!({
+            "time": self.time.rfc3339().to_string(),
+            "remote_addr": addr.to_string(),
+            "user": user,
+            "method": self.method,
+            "path": self.path,
+            "protocol": self.protocol,
+            "status": status,
+            "bytes": bytes,
+            "duration": self.start.elapsed().as_secs_f64(),
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+        });
+        line.to_string()
+    }
+
+    fn log(&self, status: u16, bytes: u64) {
+        let mut accesslog = ACCESSLOG.lock().unwrap();
+        let (target, format) = match accesslog.as_mut() {
+            Some(log) => log,
+            None => return,
+        };
+        let line = format!("{}\n", self.line(*format, status, bytes));
+        let _ = match target {
+            Target::Stdout => io::stdout().write_all(line.as_bytes()),
+            Target::Stderr => io::stderr().write_all(line.as_bytes()),
+            Target::File(_, file) => file.write_all(line.as_bytes()),
+        };
+    }
+}
+
+/// The response body. It counts the bytes that were sent, and writes
+/// the log entry when it is dropped.
+pub struct Body {
+    inner: hyper::Body,
+    entry: Option<(Entry, u16)>,
+    bytes: u64,
+}
+
+/// Wrap the body of a response.
+pub fn response(
+    resp: http::Response<hyper::Body>,
+    entry: Option<Entry>,
+) -> http::Response<Body>
+{
+    let status = resp.status().as_u16();
+    resp.map(|inner| {
+        Body {
+            inner,
+            entry: entry.map(|e| (e, status)),
+            bytes: 0,
+        }
+    })
+}
+
+impl Drop for Body {
+    fn drop(&mut self) {
+        if let Some((ref entry, status)) = self.entry {
+            entry.log(status, self.bytes);
+        }
+    }
+}
+
+impl HttpBody for Body {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        let res = Pin::new(&mut self.inner).poll_data(cx);
+        if let Poll::Ready(Some(Ok(ref data))) = res {
+            self.bytes += data.len() as u64;
+        }
+        res
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<Result<Option<http::HeaderMap>, hyper::Error>>
+    {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_combined() {
+        let entry = Entry {
+            client:     Client(Arc::new(Mutex::new(("192.0.2.1".parse().unwrap(), Some("bo b".into()))))),
+            time:       time::at_utc(time::Timespec::new(1622548800, 0)),
+            start:      Instant::now(),
+            method:     "GET".into(),
+            path:       "/a\"b".into(),
+            protocol:   "HTTP/1.1".into(),
+            referer:    None,
+            user_agent: Some("curl/7.68.0".into()),
+        };
+        assert_eq!(
+            entry.line(AccessFormat::Combined, 200, 1234),
+            concat!(
+                "192.0.2.1 - bo\\x20b [01/Jun/2021:12:00:00 +0000] ",
+                "\"GET /a\\\"b HTTP/1.1\" 200 1234 \"-\" \"curl/7.68.0\""
+            )
+        );
+        assert!(entry.line(AccessFormat::Common, 404, 0).ends_with("HTTP/1.1\" 404 -"));
+    }
+}
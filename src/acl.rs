@@ -0,0 +1,289 @@
+//
+// Access control lists.
+//
+// The [[location.acl]] rules are checked in order. The first rule that
+// matches the path and the user, and that allows or denies the permission,
+// decides. Then, if acl-files is set, the .webdav-acl files are checked,
+// from the directory of the file up to the directory of the location. The
+// first file with a line for the user decides. Otherwise acl-default
+// (default: deny) applies. Requests that act on a whole tree need the
+// permission on everything below the path as well.
+//
+// A .webdav-acl file has lines with a username, "@group" or "*", followed
+// by a comma separated list of permissions, or "-" for none:
+//
+//     alice      read,write,delete,lock
+//     @staff     read
+//     *          -
+//
+use std::path::Path;
+
+use webdav_handler::DavMethod;
+
+use crate::config::{AclDefault, AclPerm, AclRule, Location};
+
+pub const ACL_FILE: &str = ".webdav-acl";
+
+/// Who is asking: the authenticated user, and the unix groups.
+pub struct Principal<'a> {
+    pub user: Option<&'a str>,
+    pub gids: &'a [u32],
+}
+
+/// Are ACLs enabled on this location.
+pub fn enabled(location: &Location) -> bool {
+    !location.acl.is_empty() || location.acl_files
+}
+
+// The permission a method needs on the request path.
+fn method_perm(method: DavMethod) -> AclPerm {
+    match method {
+        DavMethod::Get | DavMethod::Head | DavMethod::Options | DavMethod::PropFind => AclPerm::Read,
+        DavMethod::Copy => AclPerm::Read,
+        DavMethod::Delete | DavMethod::Move => AclPerm::Delete,
+        DavMethod::Lock | DavMethod::Unlock => AclPerm::Lock,
+        DavMethod::Put | DavMethod::Patch | DavMethod::PropPatch | DavMethod::MkCol => AclPerm::Write,
+    }
+}
+
+// Is "path" equal to, or below, "rule_path".
+fn path_matches(rule_path: &str, path: &str) -> bool {
+    let rule_path = rule_path.trim_end_matches('/');
+    path == rule_path || (path.starts_with(rule_path) && path[rule_path.len()..].starts_with('/'))
+}
+
+fn rule_decides(rule: &AclRule, path: &str, who: &Principal, perm: AclPerm) -> Option<bool> {
+    if !path_matches(&rule.path, path) {
+        return None;
+    }
+    let user = rule.users.iter().any(|u| u == "*" || Some(u.as_str()) == who.user);
+    let group = rule.gids.iter().any(|g| who.gids.contains(g));
+    if !user && !group {
+        return None;
+    }
+    if rule.deny.iter().flatten().any(|p| *p == perm) {
+        return Some(false);
+    }
+    if rule.allow.iter().flatten().any(|p| *p == perm) {
+        return Some(true);
+    }
+    None
+}
+
+// Check the contents of a .webdav-acl file. None if there's no line for us.
+fn file_decides<F>(data: &str, who: &Principal, perm: AclPerm, gid: F) -> Option<bool>
+where F: Fn(&str) -> Option<u32> {
+    for line in data.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let name = fields.next().unwrap_or_default();
+        let perms = fields.next().unwrap_or("-");
+        let matches = match name.strip_prefix('@') {
+            Some(group) => gid(group).map(|g| who.gids.contains(&g)).unwrap_or(false),
+            None => name == "*" || Some(name) == who.user,
+        };
+        if matches {
+            return Some(perms.split(',').any(|p| p.trim().parse::<AclPerm>().ok() == Some(perm)));
+        }
+    }
+    None
+}
+
+fn group_gid(name: &str) -> Option<u32> {
+    nix::unistd::Group::from_name(name)
+        .ok()
+        .flatten()
+        .map(|g| g.gid.as_raw())
+}
+
+// Check one path (relative to the location). Reads files, so it blocks.
+fn allowed(location: &Location, dir: &str, path: &str, who: &Principal, perm: AclPerm) -> bool {
+    let base = Path::new(dir);
+    allowed_at(location, base, &base.join(path.trim_start_matches('/')), path, who, perm)
+}
+
+// The same, with the path on disk too ("full"): the names below a
+// directory do not have to be UTF-8.
+fn allowed_at(
+    location: &Location,
+    base: &Path,
+    full: &Path,
+    path: &str,
+    who: &Principal,
+    perm: AclPerm,
+) -> bool
+{
+    for rule in &location.acl {
+        if let Some(allow) = rule_decides(rule, path, who, perm) {
+            return allow;
+        }
+    }
+    if location.acl_files {
+        let mut cur = Some(full);
+        while let Some(d) = cur.filter(|d| d.starts_with(base)) {
+            if let Ok(data) = std::fs::read_to_string(d.join(ACL_FILE)) {
+                if let Some(allow) = file_decides(&data, who, perm, group_gid) {
+                    return allow;
+                }
+            }
+            cur = d.parent();
+        }
+    }
+    location.acl_default == Some(AclDefault::Allow)
+}
+
+// Is "perm" allowed on everything below "path" as well. The answer can
+// only change at the path of a rule, or at a directory with a .webdav-acl
+// file, so those are checked; symlinks are not followed.
+fn allowed_below(location: &Location, dir: &str, path: &str, who: &Principal, perm: AclPerm) -> bool {
+    let path = path.trim_end_matches('/');
+    for rule in &location.acl {
+        let below = rule.path.trim_end_matches('/');
+        if below != path && path_matches(path, below) && !allowed(location, dir, below, who, perm) {
+            return false;
+        }
+    }
+    if !location.acl_files {
+        return true;
+    }
+    let base = Path::new(dir);
+    let mut dirs = vec![(base.join(path.trim_start_matches('/')), path.to_string())];
+    while let Some((full, rel)) = dirs.pop() {
+        let entries = match std::fs::read_dir(&full) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            if !entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                continue;
+            }
+            let (full, rel) = (entry.path(), format!("{}/{}", rel, entry.file_name().to_string_lossy()));
+            if full.join(ACL_FILE).exists() && !allowed_at(location, base, &full, &rel, who, perm) {
+                return false;
+            }
+            dirs.push((full, rel));
+        }
+    }
+    true
+}
+
+/// Check a request. `path` and `dest` (the Destination: of COPY and
+/// MOVE) are relative to the location. With `recursive` (DELETE, MOVE,
+/// and COPY, PROPFIND, LOCK and SEARCH with Depth: infinity) the request
+/// needs the permission on everything below them too. The .webdav-acl
+/// files themselves cannot be accessed at all.
+pub fn check(
+    location: &Location,
+    dir: &str,
+    method: DavMethod,
+    path: &str,
+    dest: Option<&str>,
+    recursive: bool,
+    who: &Principal,
+) -> bool
+{
+    let paths = std::iter::once(path).chain(dest);
+    if location.acl_files && paths.flat_map(|p| p.split('/')).any(|c| c == ACL_FILE) {
+        return false;
+    }
+    let ok = |path: &str, perm: AclPerm| {
+        allowed(location, dir, path, who, perm) &&
+            (!recursive || allowed_below(location, dir, path, who, perm))
+    };
+    tokio::task::block_in_place(|| {
+        if !ok(path, method_perm(method)) {
+            return false;
+        }
+        match (method, dest) {
+            (DavMethod::Copy, Some(dest)) | (DavMethod::Move, Some(dest)) => ok(dest, AclPerm::Write),
+            _ => true,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(path: &str, users: &[&str], allow: &[AclPerm], deny: &[AclPerm]) -> AclRule {
+        AclRule {
+            path:   path.to_string(),
+            users:  users.iter().map(|u| u.to_string()).collect(),
+            groups: Vec::new(),
+            allow:  Some(allow.to_vec()),
+            deny:   Some(deny.to_vec()),
+            gids:   vec![100],
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let alice = Principal { user: Some("alice"), gids: &[] };
+        let bob = Principal { user: Some("bob"), gids: &[100] };
+        let anon = Principal { user: None, gids: &[] };
+
+        let r = rule("/private", &["alice"], &[AclPerm::Read, AclPerm::Write], &[]);
+        assert_eq!(rule_decides(&r, "/private/x", &alice, AclPerm::Write), Some(true));
+        assert_eq!(rule_decides(&r, "/private", &alice, AclPerm::Read), Some(true));
+        assert_eq!(rule_decides(&r, "/privateer", &alice, AclPerm::Read), None);
+        assert_eq!(rule_decides(&r, "/private/x", &alice, AclPerm::Delete), None);
+        // bob matches through group 100.
+        assert_eq!(rule_decides(&r, "/private/x", &bob, AclPerm::Read), Some(true));
+        assert_eq!(rule_decides(&r, "/private/x", &anon, AclPerm::Read), None);
+
+        let r = rule("/", &["*"], &[AclPerm::Read], &[AclPerm::Read]);
+        assert_eq!(rule_decides(&r, "/a", &anon, AclPerm::Read), Some(false));
+    }
+
+    #[test]
+    fn test_file() {
+        let data = "# comment\nalice read,write\n@staff lock\n* -\n";
+        let gid = |g: &str| if g == "staff" { Some(50) } else { None };
+        let alice = Principal { user: Some("alice"), gids: &[50] };
+        let bob = Principal { user: Some("bob"), gids: &[50] };
+        let carol = Principal { user: Some("carol"), gids: &[] };
+        assert_eq!(file_decides(data, &alice, AclPerm::Write, gid), Some(true));
+        assert_eq!(file_decides(data, &alice, AclPerm::Lock, gid), Some(false));
+        assert_eq!(file_decides(data, &bob, AclPerm::Lock, gid), Some(true));
+        assert_eq!(file_decides(data, &carol, AclPerm::Read, gid), Some(false));
+        assert_eq!(file_decides("alice read\n", &carol, AclPerm::Read, gid), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_recursive() {
+        let dir = std::env::temp_dir().join(format!("acl-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("data/private")).unwrap();
+        std::fs::create_dir_all(dir.join("data/public")).unwrap();
+        let toml = format!(
+            "[server]\n[[location]]\nroute = [ \"/*path\" ]\nhandler = \"filesystem\"\ndirectory = \"{}\"\n\
+             acl-default = \"allow\"\n\
+             [[location.acl]]\npath = \"/data/private\"\nusers = [ \"*\" ]\ndeny = [ \"read\" ]\n",
+            dir.display()
+        );
+        let config = crate::config::from_value(toml::from_str(&toml).unwrap()).unwrap();
+        let mut location = config.location[0].clone();
+        let d = dir.to_str().unwrap();
+        let bob = Principal { user: Some("bob"), gids: &[] };
+
+        // A deny rule below the source.
+        assert!(check(&location, d, DavMethod::Copy, "/data", Some("/copy"), false, &bob));
+        assert!(!check(&location, d, DavMethod::Copy, "/data", Some("/copy"), true, &bob));
+        assert!(check(&location, d, DavMethod::Copy, "/data/public", Some("/copy"), true, &bob));
+        assert!(!check(&location, d, DavMethod::PropFind, "/", None, true, &bob));
+        assert!(check(&location, d, DavMethod::Delete, "/data", None, true, &bob));
+
+        // A .webdav-acl file below the source.
+        location.acl.clear();
+        location.acl_files = true;
+        std::fs::write(dir.join("data/public/.webdav-acl"), "bob read\n").unwrap();
+        assert!(!check(&location, d, DavMethod::Delete, "/data", None, true, &bob));
+        assert!(!check(&location, d, DavMethod::Move, "/data", Some("/moved"), true, &bob));
+        assert!(check(&location, d, DavMethod::Delete, "/data/private", None, true, &bob));
+        // .. and below the destination.
+        assert!(!check(&location, d, DavMethod::Copy, "/data/private", Some("/data"), true, &bob));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
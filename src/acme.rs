@@ -0,0 +1,565 @@
+//
+// ACME (RFC 8555) client, for automatic certificates from
+// Let's Encrypt or another ACME CA.
+//
+// The certificate and its key are kept in the state directory, and
+// renewed in the background when they are about to expire. Both the
+// TLS-ALPN-01 challenge (answered on the TLS listeners, which must
+// include port 443) and the HTTP-01 challenge (answered on the
+// plaintext listeners, which must include port 80) are supported.
+//
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnector;
+use rcgen::{CertificateParams, CustomExtension, DistinguishedName};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, ClientHello, PrivateKey, ResolvesServerCert};
+
+use crate::config::{self, AcmeChallenge};
+
+const LETSENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+// ALPN protocol for the TLS-ALPN-01 challenge.
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+// URL path prefix for the HTTP-01 challenge.
+pub const HTTP01_PREFIX: &str = "/.well-known/acme-challenge/";
+// How long to wait before trying again after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+// How often to check if the certificate needs to be renewed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+type HttpClient = hyper::Client<HttpsConnector<HttpConnector>, hyper::Body>;
+
+fn acme_error(msg: impl Into<String>) -> io::Error {
+    io::Error::other(msg.into())
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn pem_encode(label: &str, der: &[u8]) -> String {
+    let b64 = base64::encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for chunk in b64.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(chunk));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+// Write a file atomically, readable only by us.
+fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+fn certified_key(chain_pem: &[u8], key_pem: &[u8]) -> io::Result<CertifiedKey> {
+    let certs = pemfile::certs(&mut &chain_pem[..]).map_err(|_| acme_error("invalid certificate"))?;
+    if certs.is_empty() {
+        return Err(acme_error("no certificates found"));
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut &key_pem[..]).map_err(|_| acme_error("invalid key"))?;
+    let key = keys.pop().ok_or_else(|| acme_error("no private key found"))?;
+    let key = sign::any_supported_type(&key).map_err(|_| acme_error("unsupported private key"))?;
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+// Expiry time (unix seconds) and dns names of a certificate.
+fn cert_info(cert: &Certificate) -> Option<(i64, Vec<String>)> {
+    use x509_parser::extensions::GeneralName;
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let not_after = cert.validity().not_after.timestamp();
+    let names = match cert.subject_alternative_name().ok()? {
+        Some(san) => {
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|n| {
+                    match n {
+                        GeneralName::DNSName(s) => Some(s.to_string()),
+                        _ => None,
+                    }
+                })
+                .collect()
+        },
+        None => Vec::new(),
+    };
+    Some((not_after, names))
+}
+
+// The public part of the account key.
+fn jwk(key: &EcdsaKeyPair) -> Value {
+    // uncompressed point: 0x04 || x || y.
+    let pubkey = key.public_key().as_ref();
+    json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": b64(&pubkey[1..33]),
+        "y": b64(&pubkey[33..65]),
+    })
+}
+
+// RFC 7638 JWK thumbprint.
+fn thumbprint(key: &EcdsaKeyPair) -> String {
+    // serde_json sorts the keys, which is what RFC 7638 wants.
+    let jwk = serde_json::to_string(&jwk(key)).unwrap();
+    b64(&Sha256::digest(jwk.as_bytes()))
+}
+
+/// Certificate resolver for the TLS listeners. Serves the current
+/// certificate, or a challenge certificate for TLS-ALPN-01.
+#[derive(Default)]
+pub struct CertResolver {
+    cert:       RwLock<Option<CertifiedKey>>,
+    challenges: RwLock<HashMap<String, CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, hello: ClientHello) -> Option<CertifiedKey> {
+        let acme = hello.alpn().map(|a| a.contains(&ACME_TLS_ALPN)).unwrap_or(false);
+        if acme {
+            let name: &str = hello.server_name()?.into();
+            return self.challenges.read().unwrap().get(name).cloned();
+        }
+        self.cert.read().unwrap().clone()
+    }
+}
+
+pub struct Acme {
+    cfg:         config::Acme,
+    state_dir:   PathBuf,
+    resolver:    Arc<CertResolver>,
+    http_tokens: RwLock<HashMap<String, String>>,
+}
+
+impl Acme {
+    /// Load the certificate from the state directory, if we have one.
+    pub fn new(cfg: &config::Acme) -> Arc<Acme> {
+        let state_dir = PathBuf::from(&cfg.state_dir);
+        let acme = Acme {
+            cfg: cfg.clone(),
+            state_dir,
+            resolver: Arc::new(CertResolver::default()),
+            http_tokens: RwLock::new(HashMap::new()),
+        };
+        let chain = fs::read(acme.state_dir.join("cert.pem"));
+        let key = fs::read(acme.state_dir.join("key.pem"));
+        if let (Ok(chain), Ok(key)) = (chain, key) {
+            match certified_key(&chain, &key) {
+                Ok(ck) => *acme.resolver.cert.write().unwrap() = Some(ck),
+                Err(e) => warn!("acme: {:?}: {}", acme.state_dir, e),
+            }
+        }
+        Arc::new(acme)
+    }
+
+    pub fn resolver(&self) -> Arc<CertResolver> {
+        self.resolver.clone()
+    }
+
+    /// Key authorization for a HTTP-01 challenge token.
+    pub fn http01(&self, token: &str) -> Option<String> {
+        self.http_tokens.read().unwrap().get(token).cloned()
+    }
+
+    fn challenge_type(&self) -> AcmeChallenge {
+        self.cfg.challenge.unwrap_or(AcmeChallenge::TlsAlpn01)
+    }
+
+    // Do we need a (new) certificate?
+    fn needs_renewal(&self) -> bool {
+        let cert = self.resolver.cert.read().unwrap();
+        let (not_after, names) = match cert.as_ref().and_then(|ck| cert_info(ck.cert.first()?)) {
+            Some(info) => info,
+            None => return true,
+        };
+        if !self.cfg.domains.iter().all(|d| names.contains(d)) {
+            return true;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let renew = self.cfg.renew_days.unwrap_or(30) * 86400;
+        not_after - (now as i64) < renew as i64
+    }
+
+    /// Background task that obtains and renews the certificate.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            let mut wait = CHECK_INTERVAL;
+            if self.needs_renewal() {
+                info!("acme: requesting certificate for {}", self.cfg.domains.join(", "));
+                match self.obtain().await {
+                    Ok(()) => info!("acme: new certificate installed"),
+                    Err(e) => {
+                        error!("acme: failed to obtain certificate: {}", e);
+                        wait = RETRY_INTERVAL;
+                    },
+                }
+                self.http_tokens.write().unwrap().clear();
+                self.resolver.challenges.write().unwrap().clear();
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // Load or create the account key. This also creates the state directory,
+    // which is done here because by now we're running as [server] uid.
+    fn account_key(&self) -> io::Result<EcdsaKeyPair> {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.state_dir)
+            .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", self.state_dir, e)))?;
+        let path = self.state_dir.join("account.key");
+        let der = match fs::read(&path) {
+            Ok(pem) => {
+                let mut keys = pemfile::pkcs8_private_keys(&mut &pem[..])
+                    .map_err(|_| acme_error(format!("{:?}: invalid key", path)))?;
+                keys.pop()
+                    .ok_or_else(|| acme_error(format!("{:?}: no key found", path)))?
+                    .0
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let rng = SystemRandom::new();
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| acme_error("cannot generate account key"))?;
+                write_file(&path, pem_encode("PRIVATE KEY", pkcs8.as_ref()).as_bytes())?;
+                pkcs8.as_ref().to_vec()
+            },
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{:?}: {}", path, e))),
+        };
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &der)
+            .map_err(|_| acme_error(format!("{:?}: invalid key", path)))
+    }
+
+    // Run one complete order.
+    async fn obtain(&self) -> io::Result<()> {
+        let directory = self.cfg.directory.as_deref().unwrap_or(LETSENCRYPT);
+        let mut client = Client::new(directory, self.account_key()?).await?;
+        client.account(&self.cfg.contact).await?;
+
+        // new order.
+        let identifiers: Vec<Value> = self
+            .cfg
+            .domains
+            .iter()
+            .map(|d| json!({ "type": "dns", "value": d }))
+            .collect();
+        let (order_url, order) = client
+            .post(&client.dir.new_order.clone(), Some(json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = order_url.ok_or_else(|| acme_error("newOrder: no Location"))?;
+
+        // complete the authorizations.
+        let authz_urls = order["authorizations"].as_array().cloned().unwrap_or_default();
+        for url in authz_urls.iter().filter_map(|u| u.as_str()) {
+            self.authorize(&mut client, url).await?;
+        }
+
+        // finalize with a CSR for a new key.
+        let mut params = CertificateParams::new(self.cfg.domains.clone());
+        params.distinguished_name = DistinguishedName::new();
+        let cert = rcgen::Certificate::from_params(params).map_err(|e| acme_error(e.to_string()))?;
+        let csr = cert.serialize_request_der().map_err(|e| acme_error(e.to_string()))?;
+        let finalize = order["finalize"]
+            .as_str()
+            .ok_or_else(|| acme_error("order: no finalize url"))?;
+        client.post(finalize, Some(json!({ "csr": b64(&csr) }))).await?;
+        let order = client.poll(&order_url, "order").await?;
+        let cert_url = order["certificate"]
+            .as_str()
+            .ok_or_else(|| acme_error("order: no certificate url"))?;
+        let chain = client.post_raw(cert_url, None).await?.1;
+
+        // install and store.
+        let key_pem = cert.serialize_private_key_pem();
+        let ck = certified_key(&chain, key_pem.as_bytes())?;
+        write_file(&self.state_dir.join("key.pem"), key_pem.as_bytes())?;
+        write_file(&self.state_dir.join("cert.pem"), &chain)?;
+        *self.resolver.cert.write().unwrap() = Some(ck);
+        Ok(())
+    }
+
+    // Complete one authorization.
+    async fn authorize(&self, client: &mut Client, url: &str) -> io::Result<()> {
+        let (_, authz) = client.post(url, None).await?;
+        if authz["status"] == "valid" {
+            return Ok(());
+        }
+        let domain = authz["identifier"]["value"]
+            .as_str()
+            .ok_or_else(|| acme_error("authorization: no identifier"))?;
+        let ctype = match self.challenge_type() {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        };
+        let challenge = authz["challenges"]
+            .as_array()
+            .and_then(|c| c.iter().find(|c| c["type"] == ctype))
+            .ok_or_else(|| acme_error(format!("{}: no {} challenge offered", domain, ctype)))?;
+        let token = challenge["token"]
+            .as_str()
+            .ok_or_else(|| acme_error("challenge: no token"))?;
+        let chall_url = challenge["url"]
+            .as_str()
+            .ok_or_else(|| acme_error("challenge: no url"))?;
+        let key_auth = format!("{}.{}", token, client.thumbprint());
+
+        // set up the response.
+        match self.challenge_type() {
+            AcmeChallenge::Http01 => {
+                let mut tokens = self.http_tokens.write().unwrap();
+                tokens.insert(token.to_string(), key_auth);
+            },
+            AcmeChallenge::TlsAlpn01 => {
+                let digest = Sha256::digest(key_auth.as_bytes());
+                let mut params = CertificateParams::new(vec![domain.to_string()]);
+                params.custom_extensions = vec![CustomExtension::new_acme_identifier(&digest)];
+                let cert = rcgen::Certificate::from_params(params).map_err(|e| acme_error(e.to_string()))?;
+                let der = cert.serialize_der().map_err(|e| acme_error(e.to_string()))?;
+                let key = PrivateKey(cert.serialize_private_key_der());
+                let key = sign::any_supported_type(&key).map_err(|_| acme_error("challenge key"))?;
+                let ck = CertifiedKey::new(vec![Certificate(der)], Arc::new(key));
+                self.resolver.challenges.write().unwrap().insert(domain.to_string(), ck);
+            },
+        }
+
+        // tell the server we're ready, and wait for the result.
+        client.post(chall_url, Some(json!({}))).await?;
+        client.poll(url, "authorization").await?;
+        Ok(())
+    }
+}
+
+struct Directory {
+    new_nonce:   String,
+    new_account: String,
+    new_order:   String,
+}
+
+// An ACME client session.
+struct Client {
+    http:  HttpClient,
+    key:   EcdsaKeyPair,
+    rng:   SystemRandom,
+    dir:   Directory,
+    nonce: Option<String>,
+    kid:   Option<String>,
+}
+
+impl Client {
+    async fn new(directory: &str, key: EcdsaKeyPair) -> io::Result<Client> {
+        let https = hyper_rustls::HttpsConnector::with_native_roots();
+        let http = hyper::Client::builder().build::<_, hyper::Body>(https);
+        let req = http::Request::get(directory).body(hyper::Body::empty()).unwrap();
+        let (_, _, body) = Client::send(&http, req).await?;
+        let dir: Value =
+            serde_json::from_slice(&body).map_err(|e| acme_error(format!("{}: {}", directory, e)))?;
+        let get = |k: &str| {
+            dir[k]
+                .as_str()
+                .map(|s| s.to_string())
+                .ok_or_else(|| acme_error(format!("{}: no {} in directory", directory, k)))
+        };
+        let dir = Directory {
+            new_nonce:   get("newNonce")?,
+            new_account: get("newAccount")?,
+            new_order:   get("newOrder")?,
+        };
+        Ok(Client {
+            http,
+            key,
+            rng: SystemRandom::new(),
+            dir,
+            nonce: None,
+            kid: None,
+        })
+    }
+
+    // Send a request, with a timeout.
+    async fn send(
+        http: &HttpClient,
+        req: http::Request<hyper::Body>,
+    ) -> io::Result<(http::StatusCode, http::HeaderMap, hyper::body::Bytes)>
+    {
+        let uri = req.uri().to_string();
+        let fetch = async {
+            let resp = http.request(req).await.map_err(io::Error::other)?;
+            let (parts, body) = resp.into_parts();
+            let body = hyper::body::to_bytes(body).await.map_err(io::Error::other)?;
+            Ok((parts.status, parts.headers, body))
+        };
+        match tokio::time::timeout(Duration::from_secs(30), fetch).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("{}: timeout", uri))),
+        }
+    }
+
+    fn jwk(&self) -> Value {
+        jwk(&self.key)
+    }
+
+    fn thumbprint(&self) -> String {
+        thumbprint(&self.key)
+    }
+
+    async fn nonce(&mut self) -> io::Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let req = http::Request::head(&self.dir.new_nonce)
+            .body(hyper::Body::empty())
+            .unwrap();
+        let (_, headers, _) = Client::send(&self.http, req).await?;
+        headers
+            .get("replay-nonce")
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string())
+            .ok_or_else(|| acme_error("newNonce: no Replay-Nonce"))
+    }
+
+    // Signed POST. No payload means POST-as-GET.
+    // Returns the Location header and the raw body.
+    async fn post_raw(
+        &mut self,
+        url: &str,
+        payload: Option<Value>,
+    ) -> io::Result<(Option<String>, hyper::body::Bytes)>
+    {
+        let payload = payload.map(|p| b64(p.to_string().as_bytes())).unwrap_or_default();
+        for _ in 0..3 {
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": self.nonce().await?,
+                "url": url,
+            });
+            match self.kid {
+                Some(ref kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk(),
+            }
+            let protected = b64(protected.to_string().as_bytes());
+            let signing_input = format!("{}.{}", protected, payload);
+            let sig = self
+                .key
+                .sign(&self.rng, signing_input.as_bytes())
+                .map_err(|_| acme_error("signing failed"))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": b64(sig.as_ref()),
+            });
+            let req = http::Request::post(url)
+                .header("content-type", "application/jose+json")
+                .body(hyper::Body::from(body.to_string()))
+                .unwrap();
+            let (status, headers, body) = Client::send(&self.http, req).await?;
+            self.nonce = headers
+                .get("replay-nonce")
+                .and_then(|v| v.to_str().ok())
+                .map(|s| s.to_string());
+            if status.is_success() {
+                let location = headers
+                    .get("location")
+                    .and_then(|v| v.to_str().ok())
+                    .map(|s| s.to_string());
+                return Ok((location, body));
+            }
+            let problem: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                continue;
+            }
+            let detail = problem["detail"].as_str().unwrap_or("");
+            return Err(acme_error(format!("{}: {} {}", url, status, detail)));
+        }
+        Err(acme_error(format!("{}: too many bad nonces", url)))
+    }
+
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> io::Result<(Option<String>, Value)> {
+        let (location, body) = self.post_raw(url, payload).await?;
+        let value = serde_json::from_slice(&body).map_err(|e| acme_error(format!("{}: {}", url, e)))?;
+        Ok((location, value))
+    }
+
+    // Find or create the account.
+    async fn account(&mut self, contact: &[String]) -> io::Result<()> {
+        let url = self.dir.new_account.clone();
+        let payload = json!({
+            "termsOfServiceAgreed": true,
+            "contact": contact,
+        });
+        let (location, _) = self.post(&url, Some(payload)).await?;
+        self.kid = Some(location.ok_or_else(|| acme_error("newAccount: no Location"))?);
+        Ok(())
+    }
+
+    // Wait for an order or authorization to become valid.
+    async fn poll(&mut self, url: &str, what: &str) -> io::Result<Value> {
+        for _ in 0..30 {
+            let (_, value) = self.post(url, None).await?;
+            match value["status"].as_str() {
+                Some("valid") => return Ok(value),
+                Some("pending") | Some("processing") | Some("ready") => {},
+                status => {
+                    let err = &value["error"]["detail"];
+                    return Err(acme_error(format!("{}: status {:?} {}", what, status, err)));
+                },
+            }
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+        Err(acme_error(format!("{}: timeout waiting for status valid", what)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_account_key() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let pem = pem_encode("PRIVATE KEY", pkcs8.as_ref());
+        let der = pemfile::pkcs8_private_keys(&mut pem.as_bytes()).unwrap().pop().unwrap();
+        assert_eq!(der.0, pkcs8.as_ref());
+
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &der.0).unwrap();
+        let jwk = serde_json::to_string(&jwk(&key)).unwrap();
+        assert!(jwk.starts_with(r#"{"crv":"P-256","kty":"EC","x":""#));
+        assert_eq!(thumbprint(&key).len(), 43);
+    }
+
+    #[test]
+    fn test_cert_info() {
+        let cert = rcgen::generate_simple_self_signed(vec!["dav.example.com".to_string()]).unwrap();
+        let der = Certificate(cert.serialize_der().unwrap());
+        let (not_after, names) = cert_info(&der).unwrap();
+        assert_eq!(names, vec!["dav.example.com".to_string()]);
+        assert!(not_after > 0);
+
+        let ck = certified_key(
+            cert.serialize_pem().unwrap().as_bytes(),
+            cert.serialize_private_key_pem().as_bytes(),
+        );
+        assert!(ck.is_ok());
+    }
+}
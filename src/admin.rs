@@ -0,0 +1,380 @@
+//
+// The [admin] listener. It serves the Prometheus metrics on /metrics,
+// and the health checks on /healthz and /readyz.
+//
+// There is no authentication, so it should only listen on localhost
+// or on a management network; or set allow-from.
+//
+// The admin API is on a listener of its own (api-listen), and needs the
+// api-token as a bearer token. It answers with JSON:
+//
+// GET    /api/sessions           the open connections, and their user
+// GET    /api/locks              the locks held, in all lock databases
+// DELETE /api/locks/<token>      release a lock, whoever holds it
+// GET    /api/usage              requests and bytes per user
+// GET    /api/storage            bytes, files and directories in the directories with a quota
+// DELETE /api/auth-cache/<user>  forget the cached logins of a user
+// GET    /api/setuid-pools       the threads and calls of the setuid pools
+//
+// GET    /api/app-passwords[/<user>]     the app passwords (not the passwords)
+// POST   /api/app-passwords/<user>       a new one, from {"name", "read-only", "paths"}
+// DELETE /api/app-passwords/<user>/<id>  revoke one
+//
+// GET    /api/shares[/<user>]    the share links (not the passwords)
+// DELETE /api/shares/<id>        remove one
+//
+// GET    /api/routes[/<name>]    the locations added at runtime
+// PUT    /api/routes/<name>      add or replace one, from the keys of a [[location]]
+// DELETE /api/routes/<name>      remove one
+//
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use headers::{authorization::Bearer, Authorization, HeaderMapExt};
+use http::{Method, Response, StatusCode};
+use hyper::Body;
+use serde_json::{json, Value};
+
+use crate::auth::Auth;
+use crate::config::Config;
+use crate::server::Server;
+
+pub async fn handle(
+    req: http::Request<Body>,
+    config: Arc<Config>,
+    auth: Auth,
+    peer: IpAddr,
+) -> Result<Response<Body>, std::convert::Infallible>
+{
+    if !allowed(&config, peer) {
+        return Ok(Response::builder().status(StatusCode::FORBIDDEN).body(Body::empty()).unwrap());
+    }
+    let path = req.uri().path();
+    let resp = match (req.method(), path) {
+        (&Method::GET, "/metrics") => {
+            Response::builder()
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .body(Body::from(crate::metrics::render()))
+        },
+        (&Method::GET, _) | (&Method::HEAD, _) if crate::health::is_health_path(path) => {
+            let (status, body) = crate::health::check(path, &config, &auth).await;
+            Response::builder()
+                .status(status)
+                .header("Content-Type", "text/plain")
+                .body(Body::from(body))
+        },
+        (_, "/metrics") => {
+            Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::empty())
+        },
+        _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()),
+    };
+    Ok(resp.unwrap())
+}
+
+// allow-from and deny-from of [admin], by the address of the connection.
+fn allowed(config: &Config, peer: IpAddr) -> bool {
+    crate::cidr::allowed(&config.admin.allow_from, &config.admin.deny_from, peer)
+}
+
+fn unix_time(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn json_response(status: StatusCode, value: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(format!("{}\n", value)))
+        .unwrap()
+}
+
+fn error(status: StatusCode) -> Response<Body> {
+    let reason = status.canonical_reason().unwrap_or("error");
+    json_response(status, json!({ "error": reason }))
+}
+
+/// The admin API.
+pub async fn api(
+    req: http::Request<Body>,
+    server: Server,
+    peer: IpAddr,
+) -> Result<Response<Body>, std::convert::Infallible>
+{
+    let config = server.live().0;
+    if !allowed(&config, peer) {
+        return Ok(error(StatusCode::FORBIDDEN));
+    }
+
+    // Check the bearer token.
+    let token = config.admin.api_token.as_deref().unwrap_or("");
+    let authorized = match req.headers().typed_get::<Authorization<Bearer>>() {
+        Some(Authorization(bearer)) if !token.is_empty() => {
+            crate::htpasswd::consteq(bearer.token().as_bytes(), token.as_bytes())
+        },
+        _ => false,
+    };
+    if !authorized {
+        let mut resp = error(StatusCode::UNAUTHORIZED);
+        resp.headers_mut().insert("WWW-Authenticate", "Bearer realm=\"admin\"".parse().unwrap());
+        return Ok(resp);
+    }
+
+    let (parts, body) = req.into_parts();
+    let path = parts.uri.path();
+    let segs: Vec<_> = path.trim_start_matches('/').split('/').collect();
+    let decode = |s: &str| percent_encoding::percent_decode_str(s).decode_utf8_lossy().into_owned();
+    let arg = segs.get(2).map(|s| decode(s));
+    let resp = match (&parts.method, segs.as_slice()) {
+        (&Method::GET, ["api", "sessions"]) => {
+            let sessions: Vec<_> = crate::limits::sessions()
+                .iter()
+                .map(|s| {
+                    json!({
+                        "id": s.id,
+                        "remote_addr": s.ip.to_string(),
+                        "user": s.user,
+                        "connected_at": unix_time(s.since),
+                    })
+                })
+                .collect();
+            json_response(StatusCode::OK, json!(sessions))
+        },
+        (&Method::GET, ["api", "locks"]) => json_response(StatusCode::OK, locks()),
+        (&Method::DELETE, ["api", "locks", _]) => {
+            match release(arg.as_deref().unwrap_or_default()) {
+                true => json_response(StatusCode::OK, json!({ "released": true })),
+                false => error(StatusCode::NOT_FOUND),
+            }
+        },
+        (&Method::GET, ["api", "usage"]) => {
+            let mut users = serde_json::Map::new();
+            for (user, c) in crate::usage::users() {
+                let counters = json!({
+                    "requests": c.requests.load(Ordering::Relaxed),
+                    "bytes_read": c.bytes_read.load(Ordering::Relaxed),
+                    "bytes_written": c.bytes_written.load(Ordering::Relaxed),
+                    "last_request": c.last_request.load(Ordering::Relaxed),
+                });
+                users.insert(user, counters);
+            }
+            json_response(StatusCode::OK, Value::Object(users))
+        },
+        (&Method::GET, ["api", "storage"]) => {
+            let roots: Vec<_> = crate::softquota::roots()
+                .into_iter()
+                .map(|(dir, stats, scanned)| {
+                    let scanned = scanned.and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+                    json!({
+                        "directory": dir.to_string_lossy(),
+                        "bytes": stats.bytes,
+                        "files": stats.files,
+                        "directories": stats.dirs,
+                        "scanned": scanned.map(|d| d.as_secs()),
+                    })
+                })
+                .collect();
+            json_response(StatusCode::OK, json!(roots))
+        },
+        (&Method::DELETE, ["api", "user-cache"]) => {
+            let removed = crate::cache::cached::flush_accounts();
+            info!("admin: account cache flushed ({} entries)", removed);
+            json_response(StatusCode::OK, json!({ "removed": removed }))
+        },
+        (&Method::DELETE, ["api", "auth-cache", _]) => {
+            let user = arg.unwrap_or_default();
+            let removed = crate::cache::cached::invalidate(&user);
+            info!("admin: auth cache of {} invalidated ({} entries)", user, removed);
+            json_response(StatusCode::OK, json!({ "removed": removed }))
+        },
+        (&Method::GET, ["api", "setuid-pools"]) => {
+            let pools: Vec<_> = crate::suidpool::stats()
+                .iter()
+                .map(|p| {
+                    json!({
+                        "uid": p.uid,
+                        "threads": p.threads,
+                        "idle": p.idle,
+                        "queued": p.queued,
+                        "calls": p.jobs,
+                    })
+                })
+                .collect();
+            json_response(StatusCode::OK, json!(pools))
+        },
+        (&Method::GET, ["api", "app-passwords"]) | (&Method::GET, ["api", "app-passwords", _]) => {
+            match crate::apppass::list(arg.as_deref()) {
+                Ok(list) => json_response(StatusCode::OK, json!(list)),
+                Err(_) => error(StatusCode::NOT_FOUND),
+            }
+        },
+        (&Method::POST, ["api", "app-passwords", _]) => {
+            #[derive(serde::Deserialize)]
+            struct New {
+                name:      String,
+                #[serde(rename = "read-only", default)]
+                read_only: bool,
+                #[serde(default)]
+                paths:     Vec<String>,
+            }
+            let user = arg.unwrap_or_default();
+            let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+            match serde_json::from_slice::<New>(&body) {
+                Ok(new) => {
+                    match crate::apppass::create(&user, &new.name, new.read_only, new.paths) {
+                        Ok((id, password)) => {
+                            info!("admin: app password {} ({}) created for {}", id, new.name, user);
+                            json_response(StatusCode::CREATED, json!({ "id": id, "password": password }))
+                        },
+                        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                            json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() }))
+                        },
+                        Err(e) => {
+                            error!("admin: app password for {}: {}", user, e);
+                            error(StatusCode::INTERNAL_SERVER_ERROR)
+                        },
+                    }
+                },
+                Err(e) => json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() })),
+            }
+        },
+        (&Method::DELETE, ["api", "app-passwords", _, id]) => {
+            let user = arg.unwrap_or_default();
+            match crate::apppass::revoke(&user, &decode(id)) {
+                Ok(()) => {
+                    info!("admin: app password {} of {} revoked", decode(id), user);
+                    json_response(StatusCode::OK, json!({ "revoked": true }))
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => error(StatusCode::NOT_FOUND),
+                Err(e) => {
+                    error!("admin: app password {} of {}: {}", decode(id), user, e);
+                    error(StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        },
+        (&Method::GET, ["api", "shares"]) | (&Method::GET, ["api", "shares", _]) => {
+            match crate::share::list(arg.as_deref()) {
+                Ok(list) => json_response(StatusCode::OK, json!(list)),
+                Err(_) => error(StatusCode::NOT_FOUND),
+            }
+        },
+        (&Method::DELETE, ["api", "shares", _]) => {
+            let id = arg.unwrap_or_default();
+            match crate::share::revoke(None, &id) {
+                Ok(()) => {
+                    info!("admin: share {} removed", id);
+                    json_response(StatusCode::OK, json!({ "removed": true }))
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => error(StatusCode::NOT_FOUND),
+                Err(e) => {
+                    error!("admin: share {}: {}", id, e);
+                    error(StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        },
+        (&Method::GET, ["api", "routes"]) | (&Method::GET, ["api", "routes", _]) => {
+            let file = match config.admin.routes_file.as_deref() {
+                Some(file) => file,
+                None => return Ok(error(StatusCode::NOT_FOUND)),
+            };
+            match crate::routefile::read(file) {
+                Ok(mut routes) => {
+                    match arg {
+                        Some(name) => {
+                            match routes.remove(&name) {
+                                Some(route) => json_response(StatusCode::OK, json!(route)),
+                                None => error(StatusCode::NOT_FOUND),
+                            }
+                        },
+                        None => json_response(StatusCode::OK, json!(routes)),
+                    }
+                },
+                Err(e) => {
+                    error!("admin: routes: {}", e);
+                    error(StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        },
+        (&Method::PUT, ["api", "routes", _]) | (&Method::DELETE, ["api", "routes", _]) => {
+            let name = arg.unwrap_or_default();
+            let location = match parts.method {
+                Method::PUT => {
+                    let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+                    let value = serde_json::from_slice::<Value>(&body).map_err(|e| e.to_string());
+                    match value.and_then(|v| toml::Value::try_from(v).map_err(|e| e.to_string())) {
+                        Ok(location) => Some(location),
+                        Err(e) => return Ok(json_response(StatusCode::BAD_REQUEST, json!({ "error": e }))),
+                    }
+                },
+                _ => None,
+            };
+            let removed = location.is_none();
+            match crate::routefile::update(&server, &name, location) {
+                Ok(_) if removed => json_response(StatusCode::OK, json!({ "removed": true })),
+                Ok(true) => json_response(StatusCode::OK, json!({ "updated": true })),
+                Ok(false) => json_response(StatusCode::CREATED, json!({ "added": true })),
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                    json_response(StatusCode::BAD_REQUEST, json!({ "error": e.to_string() }))
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    json_response(StatusCode::NOT_FOUND, json!({ "error": e.to_string() }))
+                },
+                Err(e) => {
+                    error!("admin: routes: {}: {}", name, e);
+                    error(StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        },
+        (_, ["api", "routes"]) | (_, ["api", "routes", ..]) => error(StatusCode::METHOD_NOT_ALLOWED),
+        (_, ["api", "app-passwords"]) | (_, ["api", "app-passwords", ..]) => {
+            error(StatusCode::METHOD_NOT_ALLOWED)
+        },
+        (_, ["api", "sessions"]) | (_, ["api", "locks"]) | (_, ["api", "locks", _]) => {
+            error(StatusCode::METHOD_NOT_ALLOWED)
+        },
+        (_, ["api", "usage"]) | (_, ["api", "auth-cache", _]) | (_, ["api", "setuid-pools"]) => {
+            error(StatusCode::METHOD_NOT_ALLOWED)
+        },
+        _ => error(StatusCode::NOT_FOUND),
+    };
+    Ok(resp)
+}
+
+#[cfg(feature = "sqlite")]
+fn locks() -> Value {
+    let locks: Vec<_> = crate::lockdb::list()
+        .iter()
+        .map(|l| {
+            json!({
+                "token": l.token,
+                "lock_db": l.db,
+                "root": l.root,
+                "path": l.path,
+                "principal": l.principal,
+                "shared": l.shared,
+                "deep": l.deep,
+                "timeout_at": l.timeout_at.map(unix_time),
+            })
+        })
+        .collect();
+    json!(locks)
+}
+
+#[cfg(feature = "sqlite")]
+fn release(token: &str) -> bool {
+    crate::lockdb::release(token)
+}
+
+// Without lock databases no locks are kept.
+#[cfg(not(feature = "sqlite"))]
+fn locks() -> Value {
+    json!([])
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn release(_token: &str) -> bool {
+    false
+}
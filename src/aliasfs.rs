@@ -0,0 +1,253 @@
+//
+// Alias filesystem: mount other directories inside a location.
+//
+// Every alias is a single path component below the root, like "/shared".
+// Requests below it go to the filesystem of the alias, everything else to
+// the main filesystem. A listing of the root shows the aliases as
+// directories, hiding anything with the same name in the main filesystem.
+//
+use std::future::Future;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use futures::future::{self, FutureExt};
+use futures::StreamExt;
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+#[derive(Clone)]
+pub struct AliasFs {
+    fs:      Box<dyn DavFileSystem>,
+    aliases: Vec<(String, Box<dyn DavFileSystem>)>,
+}
+
+impl AliasFs {
+    pub fn new(fs: Box<dyn DavFileSystem>, aliases: Vec<(String, Box<dyn DavFileSystem>)>) -> Box<AliasFs> {
+        Box::new(AliasFs { fs, aliases })
+    }
+
+    // The alias that "path" is in, if any.
+    fn alias_index(&self, path: &DavPath) -> Option<usize> {
+        let first = path.as_bytes().get(1..)?.split(|&c| c == b'/').next()?;
+        self.aliases.iter().position(|(name, _)| name.as_bytes() == first)
+    }
+
+    // Is this the root of an alias, i.e. "/shared" or "/shared/".
+    fn is_alias_root(&self, path: &DavPath) -> bool {
+        let mut segs = path.as_bytes()[1..].splitn(2, |&c| c == b'/');
+        let rest = segs.nth(1).unwrap_or_default();
+        self.alias_index(path).is_some() && rest.is_empty()
+    }
+
+    // Find the filesystem for "path", and the path within that filesystem.
+    fn resolve(&self, path: &DavPath) -> FsResult<(Option<usize>, &dyn DavFileSystem, DavPath)> {
+        match self.alias_index(path) {
+            Some(idx) => {
+                // strip the first component off the URL encoded path.
+                let url = path.as_url_string();
+                let rest = match url[1..].find('/') {
+                    Some(pos) => &url[pos + 1..],
+                    None => "/",
+                };
+                let path = DavPath::new(rest).map_err(|_| FsError::GeneralFailure)?;
+                Ok((Some(idx), &*self.aliases[idx].1, path))
+            },
+            None => Ok((None, &*self.fs, path.clone())),
+        }
+    }
+}
+
+impl DavFileSystem for AliasFs {
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let (_, fs, path) = self.resolve(path)?;
+            fs.metadata(&path).await
+        }
+        .boxed()
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let (_, fs, path) = self.resolve(path)?;
+            fs.symlink_metadata(&path).await
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            let (idx, fs, rpath) = self.resolve(path)?;
+            let strm = fs.read_dir(&rpath, meta).await?;
+            if idx.is_some() || path.as_bytes() != b"/" {
+                return Ok(strm);
+            }
+
+            // root of the main filesystem: add the aliases.
+            let names: Vec<Vec<u8>> = self.aliases.iter().map(|(n, _)| n.as_bytes().to_vec()).collect();
+            let strm = strm.filter(move |e| future::ready(!names.contains(&e.name())));
+            let mut entries = Vec::new();
+            let root = DavPath::new("/").unwrap();
+            for (name, fs) in &self.aliases {
+                entries.push(Box::new(AliasDirEntry {
+                    name: name.clone(),
+                    meta: fs.metadata(&root).await,
+                }) as Box<dyn DavDirEntry>);
+            }
+            let strm = strm.chain(futures::stream::iter(entries));
+            Ok(Box::pin(strm) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let (_, fs, path) = self.resolve(path)?;
+            fs.open(&path, options).await
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let (_, fs, path) = self.resolve(path)?;
+            fs.create_dir(&path).await
+        }
+        .boxed()
+    }
+
+    // the root of an alias cannot be removed, renamed or copied.
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            if self.is_alias_root(path) {
+                return Err(FsError::Forbidden);
+            }
+            let (_, fs, path) = self.resolve(path)?;
+            fs.remove_dir(&path).await
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let (_, fs, path) = self.resolve(path)?;
+            fs.remove_file(&path).await
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            if self.is_alias_root(from) || self.is_alias_root(to) {
+                return Err(FsError::Forbidden);
+            }
+            let (from_idx, fs, from) = self.resolve(from)?;
+            let (to_idx, _, to) = self.resolve(to)?;
+            if from_idx != to_idx {
+                return Err(FsError::IsRemote);
+            }
+            fs.rename(&from, &to).await
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            if self.is_alias_root(to) {
+                return Err(FsError::Forbidden);
+            }
+            let (from_idx, fs, from) = self.resolve(from)?;
+            let (to_idx, _, to) = self.resolve(to)?;
+            if from_idx != to_idx {
+                return Err(FsError::IsRemote);
+            }
+            fs.copy(&from, &to).await
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            let (_, fs, path) = self.resolve(path)?;
+            fs.set_accessed(&path, tm).await
+        }
+        .boxed()
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            let (_, fs, path) = self.resolve(path)?;
+            fs.set_modified(&path, tm).await
+        }
+        .boxed()
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        async move {
+            match self.resolve(path) {
+                Ok((_, fs, path)) => fs.have_props(&path).await,
+                Err(_) => false,
+            }
+        }
+        .boxed()
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        async move {
+            let (_, fs, path) = self.resolve(path)?;
+            fs.patch_props(&path, patch).await
+        }
+        .boxed()
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        async move {
+            let (_, fs, path) = self.resolve(path)?;
+            fs.get_props(&path, do_content).await
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        async move {
+            let (_, fs, path) = self.resolve(path)?;
+            fs.get_prop(&path, prop).await
+        }
+        .boxed()
+    }
+
+    // quota of the main filesystem.
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[derive(Debug)]
+struct AliasDirEntry {
+    meta: FsResult<Box<dyn DavMetaData>>,
+    name: String,
+}
+
+impl DavDirEntry for AliasDirEntry {
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        Box::pin(future::ready(self.meta.clone()))
+    }
+
+    fn name(&self) -> Vec<u8> {
+        self.name.as_bytes().to_vec()
+    }
+
+    fn is_dir(&self) -> FsFuture<'_, bool> {
+        Box::pin(future::ready(Ok(true)))
+    }
+}
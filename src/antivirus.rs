@@ -0,0 +1,631 @@
+//
+// Virus scanning of uploads ([antivirus]), with clamd or an ICAP server.
+//
+// In sync mode the body of a PUT is streamed to the scanner while it is
+// written, and the verdict is known when the file is closed. An infected
+// file is moved to the quarantine directory (or removed), and the PUT
+// fails with the configured status. A PUT that does not write the file
+// from start to end (Content-Range) is read back and scanned at close.
+//
+// In async mode the PUT succeeds, and the file is scanned afterwards in
+// the background; infected files are moved to quarantine or removed.
+//
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future::FutureExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::config::{self, ScanMode, ScanOnError};
+
+const CHUNK: usize = 65536;
+const MAX_REPLY: usize = 65536;
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Stream for T {}
+
+/// The verdict of a scan.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Clean,
+    Infected(String),
+}
+
+/// Why an upload was rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejected {
+    Infected(String),
+    // the scanner could not be reached, and on-error = "reject".
+    Failed,
+}
+
+async fn timed<T>(timeout: Duration, fut: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    match tokio::time::timeout(timeout, fut).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timeout")),
+    }
+}
+
+// A connection to clamd or an ICAP server, that the file is sent to.
+struct Scanner {
+    stream:  Box<dyn Stream>,
+    icap:    bool,
+    timeout: Duration,
+}
+
+impl Scanner {
+    // "path" is only used in the ICAP request.
+    async fn connect(av: &config::Antivirus, path: &DavPath) -> io::Result<Scanner> {
+        let timeout = Duration::from_secs(av.timeout.unwrap_or(30));
+        let (addr, start) = match (&av.clamd, &av.icap) {
+            (Some(clamd), _) => {
+                let addr = clamd.strip_prefix("unix:").unwrap_or(clamd).to_string();
+                (addr, b"zINSTREAM\0".to_vec())
+            },
+            (None, Some(url)) => {
+                // validate() checked that this is an icap:// url.
+                let uri: http::Uri = url.parse().map_err(io::Error::other)?;
+                let host = uri.host().unwrap_or_default();
+                let addr = format!("{}:{}", host, uri.port_u16().unwrap_or(1344));
+                (addr, icap_request(url, host, &path.with_prefix().as_url_string()))
+            },
+            (None, None) => return Err(io::Error::other("no scanner configured")),
+        };
+        let stream: Box<dyn Stream> = if addr.starts_with('/') {
+            Box::new(timed(timeout, UnixStream::connect(&addr)).await?)
+        } else {
+            Box::new(timed(timeout, TcpStream::connect(&addr)).await?)
+        };
+        let mut scanner = Scanner {
+            stream,
+            icap: av.icap.is_some(),
+            timeout,
+        };
+        scanner.send(&start).await?;
+        Ok(scanner)
+    }
+
+    async fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        timed(self.timeout, self.stream.write_all(data)).await
+    }
+
+    // Send a part of the file, as clamd or HTTP chunks.
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        for chunk in data.chunks(CHUNK) {
+            if self.icap {
+                self.send(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+                self.send(chunk).await?;
+                self.send(b"\r\n").await?;
+            } else {
+                self.send(&(chunk.len() as u32).to_be_bytes()).await?;
+                self.send(chunk).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // All of the file was sent, get the verdict.
+    async fn finish(mut self) -> io::Result<Verdict> {
+        let end: &[u8] = if self.icap { b"0\r\n\r\n" } else { &[0, 0, 0, 0] };
+        self.send(end).await?;
+        let icap = self.icap;
+        let reply = timed(self.timeout, read_reply(&mut self.stream, icap)).await?;
+        let reply = String::from_utf8_lossy(&reply);
+        if icap {
+            icap_verdict(&reply)
+        } else {
+            clamd_verdict(&reply)
+        }
+    }
+}
+
+// Read the reply: up to the NUL of clamd, or the end of the ICAP headers.
+async fn read_reply(stream: &mut Box<dyn Stream>, icap: bool) -> io::Result<Vec<u8>> {
+    let mut reply = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let done = match icap {
+            true => reply.windows(4).any(|w| w == b"\r\n\r\n"),
+            false => reply.contains(&0),
+        };
+        if done || reply.len() > MAX_REPLY {
+            return Ok(reply);
+        }
+        match stream.read(&mut buf).await? {
+            0 if reply.is_empty() => return Err(io::Error::other("connection closed")),
+            0 => return Ok(reply),
+            n => reply.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+fn icap_request(url: &str, host: &str, path: &str) -> Vec<u8> {
+    let http = format!("PUT {} HTTP/1.1\r\nHost: webdav\r\n\r\n", path);
+    let icap = format!(
+        "REQMOD {} ICAP/1.0\r\nHost: {}\r\nAllow: 204\r\nEncapsulated: req-hdr=0, req-body={}\r\n\r\n",
+        url,
+        host,
+        http.len()
+    );
+    (icap + &http).into_bytes()
+}
+
+// "stream: OK", "stream: Eicar-Signature FOUND" or "... ERROR".
+fn clamd_verdict(reply: &str) -> io::Result<Verdict> {
+    let reply = reply.trim_end_matches(|c: char| c == '\0' || c.is_whitespace());
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(name) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(name.to_string()))
+    } else {
+        Err(io::Error::other(format!("clamd: {}", reply)))
+    }
+}
+
+// 204 is clean. With 200 or 403 the server blocked the request, the name
+// is in X-Infection-Found ("...; Threat=NAME;") or X-Virus-ID.
+fn icap_verdict(reply: &str) -> io::Result<Verdict> {
+    let mut lines = reply.lines();
+    let status = lines.next().unwrap_or_default();
+    let code = status.split_whitespace().nth(1).unwrap_or_default();
+    match code {
+        "204" => return Ok(Verdict::Clean),
+        "200" | "403" => {},
+        _ => return Err(io::Error::other(format!("icap: {}", status))),
+    }
+    let mut name = None;
+    for line in lines.take_while(|l| !l.is_empty()) {
+        let (key, value) = match line.find(':') {
+            Some(idx) => (line[..idx].trim(), line[idx + 1..].trim()),
+            None => continue,
+        };
+        if key.eq_ignore_ascii_case("X-Infection-Found") {
+            let threat = value.split(';').map(str::trim).find_map(|p| p.strip_prefix("Threat="));
+            name = threat.map(|t| t.to_string()).or(name);
+        } else if key.eq_ignore_ascii_case("X-Virus-ID") && name.is_none() {
+            name = Some(value.to_string());
+        }
+    }
+    Ok(Verdict::Infected(name.unwrap_or_else(|| "unknown".to_string())))
+}
+
+/// The scanning of the uploads of one request.
+#[derive(Debug)]
+pub struct Scan {
+    av:       config::Antivirus,
+    user:     Option<String>,
+    rejected: Mutex<Option<Rejected>>,
+}
+
+impl Scan {
+    pub fn new(av: &config::Antivirus, user: Option<&str>) -> Arc<Scan> {
+        Arc::new(Scan {
+            av:       av.clone(),
+            user:     user.map(|u| u.to_string()),
+            rejected: Mutex::new(None),
+        })
+    }
+
+    /// Was an upload rejected.
+    pub fn rejected(&self) -> Option<Rejected> {
+        self.rejected.lock().unwrap().clone()
+    }
+
+    /// The status for a rejected upload, and the body of the response.
+    pub fn response(&self, rejected: &Rejected) -> (StatusCode, String) {
+        match rejected {
+            Rejected::Infected(name) => {
+                let status = self.av.status.and_then(|s| StatusCode::from_u16(s).ok());
+                (status.unwrap_or(StatusCode::FORBIDDEN), format!("virus found: {}\n", name))
+            },
+            Rejected::Failed => (StatusCode::SERVICE_UNAVAILABLE, "virus scan failed\n".to_string()),
+        }
+    }
+
+    fn too_large(&self, size: u64) -> bool {
+        self.av.max_size.map(|m| size > m.0).unwrap_or(false)
+    }
+
+    fn who(&self) -> &str {
+        self.user.as_deref().unwrap_or("-")
+    }
+}
+
+fn read_options() -> OpenOptions {
+    OpenOptions {
+        read: true,
+        ..OpenOptions::default()
+    }
+}
+
+// Read the file through the filesystem, and scan it.
+async fn scan_file(fs: &dyn DavFileSystem, path: &DavPath, scan: &Scan) -> io::Result<Verdict> {
+    let name = path.with_prefix().as_url_string();
+    let size = fs.metadata(path).await.map_err(io::Error::from)?.len();
+    if scan.too_large(size) {
+        debug!("antivirus: {}: {} bytes, not scanned", name, size);
+        return Ok(Verdict::Clean);
+    }
+    let mut file = fs.open(path, read_options()).await.map_err(io::Error::from)?;
+    let mut scanner = Scanner::connect(&scan.av, path).await?;
+    loop {
+        let data = file.read_bytes(CHUNK).await.map_err(io::Error::from)?;
+        if data.is_empty() {
+            break;
+        }
+        scanner.write(&data).await?;
+    }
+    scanner.finish().await
+}
+
+// Copy an infected file to the quarantine directory, then remove it.
+async fn quarantine(fs: &dyn DavFileSystem, path: &DavPath, scan: &Scan) {
+    let name = path.with_prefix().as_url_string();
+    if let Some(ref dir) = scan.av.quarantine {
+        match copy_out(fs, path, dir, scan.who()).await {
+            Ok(dest) => info!("antivirus: {}: moved to {}", name, dest),
+            Err(e) => error!("antivirus: {}: quarantine {}: {}", name, dir, e),
+        }
+    }
+    if let Err(e) = fs.remove_file(path).await {
+        error!("antivirus: {}: remove: {:?}", name, e);
+    }
+}
+
+// The copy is named "<unix time>-<user>-<filename>".
+async fn copy_out(fs: &dyn DavFileSystem, path: &DavPath, dir: &str, user: &str) -> io::Result<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let pathbuf = path.as_pathbuf();
+    let filename = pathbuf.file_name().map(|f| f.to_string_lossy()).unwrap_or_default();
+    let dest = format!("{}/{}-{}-{}", dir.trim_end_matches('/'), now, user.replace('/', "_"), filename);
+    let mut out = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&dest)
+        .await?;
+    let mut file = fs.open(path, read_options()).await.map_err(io::Error::from)?;
+    loop {
+        let data = file.read_bytes(CHUNK).await.map_err(io::Error::from)?;
+        if data.is_empty() {
+            break;
+        }
+        out.write_all(&data).await?;
+    }
+    out.sync_all().await?;
+    Ok(dest)
+}
+
+// Scan after the upload, in the background.
+async fn scan_later(fs: Box<dyn DavFileSystem>, path: DavPath, scan: Arc<Scan>) {
+    let name = path.with_prefix().as_url_string();
+    match scan_file(&*fs, &path, &scan).await {
+        Ok(Verdict::Clean) => debug!("antivirus: {}: clean", name),
+        Ok(Verdict::Infected(virus)) => {
+            warn!("antivirus: {}: {} found, uploaded by {}", name, virus, scan.who());
+            quarantine(&*fs, &path, &scan).await;
+        },
+        Err(e) => warn!("antivirus: {}: {}, not scanned", name, e),
+    }
+}
+
+/// A filesystem that scans the files that are written.
+#[derive(Clone)]
+pub struct ScanFs {
+    fs:   Box<dyn DavFileSystem>,
+    scan: Arc<Scan>,
+}
+
+impl ScanFs {
+    pub fn new(fs: Box<dyn DavFileSystem>, scan: Arc<Scan>) -> Box<ScanFs> {
+        Box::new(ScanFs { fs, scan })
+    }
+}
+
+impl DavFileSystem for ScanFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let file = self.fs.open(path, options).await?;
+            if !options.write {
+                return Ok(file);
+            }
+            let state = match options.append {
+                true => State::ReadBack,
+                false => State::Tee,
+            };
+            Ok(Box::new(ScanFile {
+                file,
+                fs: self.fs.clone(),
+                path: path.clone(),
+                scan: self.scan.clone(),
+                scanner: None,
+                state,
+                pos: 0,
+                written: false,
+            }) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_dir(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_file(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.rename(from, to)
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.copy(from, to)
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+    ) -> std::pin::Pin<Box<dyn Future<Output = bool> + Send + 'a>>
+    {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        self.fs.patch_props(path, patch)
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+// How the file is scanned when it is closed.
+#[derive(Debug)]
+enum State {
+    // the writes so far were sent to the scanner.
+    Tee,
+    // not written from start to end, read it back.
+    ReadBack,
+    // larger than max-size.
+    Skip,
+    // the scanner could not be reached.
+    Failed(String),
+}
+
+struct ScanFile {
+    file:    Box<dyn DavFile>,
+    fs:      Box<dyn DavFileSystem>,
+    path:    DavPath,
+    scan:    Arc<Scan>,
+    scanner: Option<Scanner>,
+    state:   State,
+    pos:     u64,
+    written: bool,
+}
+
+impl std::fmt::Debug for ScanFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScanFile")
+            .field("path", &self.path)
+            .field("state", &self.state)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+impl ScanFile {
+    // Send what was written to the scanner, in sync mode.
+    async fn tee(&mut self, data: &[u8]) {
+        self.written = true;
+        let start = self.pos;
+        self.pos += data.len() as u64;
+        if self.scan.av.mode == Some(ScanMode::Async) || data.is_empty() {
+            return;
+        }
+        if !matches!(self.state, State::Tee) {
+            return;
+        }
+        if self.scan.too_large(self.pos) {
+            self.scanner = None;
+            self.state = State::Skip;
+            return;
+        }
+        if self.scanner.is_none() {
+            if start != 0 {
+                self.state = State::ReadBack;
+                return;
+            }
+            match Scanner::connect(&self.scan.av, &self.path).await {
+                Ok(scanner) => self.scanner = Some(scanner),
+                Err(e) => {
+                    self.state = State::Failed(e.to_string());
+                    return;
+                },
+            }
+        }
+        if let Err(e) = self.scanner.as_mut().unwrap().write(data).await {
+            self.scanner = None;
+            self.state = State::Failed(e.to_string());
+        }
+    }
+
+    // The verdict, in sync mode.
+    async fn verdict(&mut self) -> io::Result<Verdict> {
+        let state = std::mem::replace(&mut self.state, State::Tee);
+        match state {
+            State::Tee => {
+                match self.scanner.take() {
+                    Some(scanner) => scanner.finish().await,
+                    None => Ok(Verdict::Clean),
+                }
+            },
+            State::ReadBack => scan_file(&*self.fs, &self.path, &self.scan).await,
+            State::Skip => {
+                debug!("antivirus: {}: larger than max-size, not scanned", self.name());
+                Ok(Verdict::Clean)
+            },
+            State::Failed(e) => Err(io::Error::other(e)),
+        }
+    }
+
+    fn name(&self) -> String {
+        self.path.with_prefix().as_url_string()
+    }
+
+    fn reject(&self, rejected: Rejected) {
+        *self.scan.rejected.lock().unwrap() = Some(rejected);
+    }
+}
+
+impl DavFile for ScanFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.file.metadata()
+    }
+
+    fn write_buf<'a>(&'a mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        async move {
+            let data = buf.copy_to_bytes(buf.remaining());
+            self.file.write_bytes(data.clone()).await?;
+            self.tee(&data).await;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        async move {
+            self.file.write_bytes(buf.clone()).await?;
+            self.tee(&buf).await;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        async move {
+            let data = self.file.read_bytes(count).await?;
+            self.pos += data.len() as u64;
+            Ok(data)
+        }
+        .boxed()
+    }
+
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> FsFuture<'a, u64> {
+        async move {
+            let pos = self.file.seek(pos).await?;
+            if pos != self.pos && matches!(self.state, State::Tee) {
+                self.scanner = None;
+                self.state = State::ReadBack;
+            }
+            self.pos = pos;
+            Ok(pos)
+        }
+        .boxed()
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        async move {
+            self.file.flush().await?;
+            if !std::mem::replace(&mut self.written, false) {
+                return Ok(());
+            }
+            if self.scan.av.mode == Some(ScanMode::Async) {
+                tokio::spawn(scan_later(self.fs.clone(), self.path.clone(), self.scan.clone()));
+                return Ok(());
+            }
+            match self.verdict().await {
+                Ok(Verdict::Clean) => Ok(()),
+                Ok(Verdict::Infected(virus)) => {
+                    warn!("antivirus: {}: {} found, uploaded by {}", self.name(), virus, self.scan.who());
+                    quarantine(&*self.fs, &self.path, &self.scan).await;
+                    self.reject(Rejected::Infected(virus));
+                    Err(FsError::Forbidden)
+                },
+                Err(e) if self.scan.av.on_error == Some(ScanOnError::Allow) => {
+                    warn!("antivirus: {}: {}, not scanned", self.name(), e);
+                    Ok(())
+                },
+                Err(e) => {
+                    warn!("antivirus: {}: {}, upload rejected", self.name(), e);
+                    if let Err(e) = self.fs.remove_file(&self.path).await {
+                        error!("antivirus: {}: remove: {:?}", self.name(), e);
+                    }
+                    self.reject(Rejected::Failed);
+                    Err(FsError::GeneralFailure)
+                },
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verdict() {
+        assert_eq!(clamd_verdict("stream: OK\0").unwrap(), Verdict::Clean);
+        assert_eq!(
+            clamd_verdict("stream: Eicar-Signature FOUND\0").unwrap(),
+            Verdict::Infected("Eicar-Signature".to_string())
+        );
+        assert!(clamd_verdict("INSTREAM size limit exceeded. ERROR\0").is_err());
+
+        assert_eq!(icap_verdict("ICAP/1.0 204 No Content\r\n\r\n").unwrap(), Verdict::Clean);
+        let reply = "ICAP/1.0 200 OK\r\nX-Infection-Found: Type=0; Resolution=2; Threat=EICAR;\r\n\r\n";
+        assert_eq!(icap_verdict(reply).unwrap(), Verdict::Infected("EICAR".to_string()));
+        assert!(icap_verdict("ICAP/1.0 500 Server Error\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//
+// Appends, for backup clients that write archives that keep growing
+// (append = true). A PUT with "X-Append: true" adds its body at the end
+// of the file, or makes the file; a PUT with a Content-Range writes at
+// that offset, and past the end of the file extends it. Both wait for
+// the appends and ranges to the same file before them, so that two of
+// them never interleave. The answer has the size of the file after it
+// in X-Append-Offset.
+//
+// An append is done by the handler as a PUT with a Content-Range from
+// the end of the file, so it needs a Content-Length. What arrived of a
+// body that was cut off is kept, as with any range; a HEAD tells where
+// to go on from. Without append, a PUT with X-Append gets "501 Not
+// Implemented" instead of replacing the file.
+//
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use headers::HeaderMapExt;
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavFileSystem, FsError, OpenOptions};
+
+use crate::{report, uploadlimit};
+
+type FileLock = Arc<tokio::sync::Mutex<()>>;
+
+lazy_static::lazy_static! {
+    static ref FILES: Mutex<HashMap<String, FileLock>> = Mutex::new(HashMap::new());
+}
+
+/// The turn of a request to write to a file. The next one goes when
+/// this is dropped.
+pub struct Turn {
+    key:      String,
+    lock:     FileLock,
+    guard:    Option<tokio::sync::OwnedMutexGuard<()>>,
+    /// The size of the file after the write.
+    pub size: u64,
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut files = FILES.lock().unwrap();
+        // Nobody waits: only the map and this one have it.
+        if Arc::strong_count(&self.lock) == 2 {
+            files.remove(&self.key);
+        }
+    }
+}
+
+async fn turn(key: &str) -> Turn {
+    let lock = FILES.lock().unwrap().entry(key.to_string()).or_default().clone();
+    let guard = lock.clone().lock_owned().await;
+    Turn {
+        key: key.to_string(),
+        lock,
+        guard: Some(guard),
+        size: 0,
+    }
+}
+
+/// A PUT with "X-Append: true".
+pub fn is_append(headers: &http::HeaderMap) -> bool {
+    let value = headers.get("X-Append").and_then(|v| v.to_str().ok());
+    value.map(|v| v.trim().eq_ignore_ascii_case("true")).unwrap_or(false)
+}
+
+/// Get a PUT ready for the handler, on a location with append. For an
+/// append or a range this waits for the turn of the file, which is
+/// "key", and an append gets its Content-Range. None for a PUT of the
+/// whole file. An answer right away is an error, or an append of nothing.
+pub async fn prepare(
+    req: &mut http::Request<hyper::Body>,
+    fs: &dyn DavFileSystem,
+    path: &DavPath,
+    key: &str,
+    max_size: Option<u64>,
+) -> Result<Option<Turn>, http::Response<String>>
+{
+    let range = match req.headers().typed_try_get::<headers::ContentRange>() {
+        Ok(range) => range.and_then(|r| r.bytes_range()),
+        Err(_) => return Err(report::error(StatusCode::BAD_REQUEST)),
+    };
+    let append = is_append(req.headers());
+    if !append && range.is_none() {
+        return Ok(None);
+    }
+    if append && range.is_some() {
+        return Err(report::error(StatusCode::BAD_REQUEST));
+    }
+    let len = match (append, uploadlimit::content_length(req)) {
+        (true, None) => return Err(report::error(StatusCode::LENGTH_REQUIRED)),
+        (_, len) => len.unwrap_or(0),
+    };
+
+    let mut turn = turn(key).await;
+    let size = match fs.metadata(path).await {
+        Ok(meta) if meta.is_dir() => return Err(report::error(StatusCode::METHOD_NOT_ALLOWED)),
+        Ok(meta) => meta.len(),
+        Err(FsError::NotFound) => 0,
+        Err(e) => return Err(report::error(report::status(e))),
+    };
+    let end = match range {
+        Some((_, last)) => last + 1,
+        None => size + len,
+    };
+    turn.size = size.max(end);
+    if max_size.map(|max| turn.size > max).unwrap_or(false) {
+        debug!("append: {}: {} bytes is larger than max-file-size", path, turn.size);
+        return Err(report::error(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+    if !append {
+        return Ok(Some(turn));
+    }
+
+    if len == 0 {
+        let options = OpenOptions {
+            write: true,
+            append: true,
+            create: true,
+            ..OpenOptions::default()
+        };
+        let mut file = fs.open(path, options).await.map_err(|e| report::error(report::status(e)))?;
+        file.flush().await.map_err(|e| report::error(report::status(e)))?;
+        let mut resp = report::error(StatusCode::NO_CONTENT);
+        resp.headers_mut().insert("X-Append-Offset", size.into());
+        return Err(resp);
+    }
+    let range = format!("bytes {}-{}/*", size, end - 1);
+    req.headers_mut().insert(http::header::CONTENT_RANGE, range.parse().unwrap());
+    Ok(Some(turn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_append() {
+        let mut headers = http::HeaderMap::new();
+        assert!(!is_append(&headers));
+        headers.insert("X-Append", "True".parse().unwrap());
+        assert!(is_append(&headers));
+
+        let first = turn("/a").await;
+        let waiting = tokio::spawn(async { turn("/a").await.key.clone() });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        assert!(FILES.lock().unwrap().contains_key("/a"));
+        drop(first);
+        assert_eq!(waiting.await.unwrap(), "/a");
+        assert!(!FILES.lock().unwrap().contains_key("/a"));
+    }
+}
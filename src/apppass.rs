@@ -0,0 +1,204 @@
+//
+// App passwords: long random passwords, one per device, that log in like
+// the real password with Basic authentication. They can be revoked one
+// by one, and limited to reading, or to some paths.
+//
+// They are kept in the [app-passwords] file, as a SHA-256 hash (they are
+// random, so that is enough), and managed with the admin API.
+//
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::session::now;
+use crate::statefile;
+
+lazy_static::lazy_static! {
+    static ref STORE: Mutex<Option<Store>> = Mutex::new(None);
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AppPassword {
+    pub id:        String,
+    pub user:      String,
+    pub name:      String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash:      String,
+    pub created:   u64,
+    #[serde(rename = "read-only", default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub paths:     Vec<String>,
+}
+
+impl AppPassword {
+    /// May this password be used for this path.
+    pub fn allows(&self, path: &[u8]) -> bool {
+        self.paths.is_empty() ||
+            self.paths.iter().any(|p| {
+                let p = p.trim_end_matches('/').as_bytes();
+                path.starts_with(p) && (path.len() == p.len() || path[p.len()] == b'/')
+            })
+    }
+}
+
+struct Store {
+    file:      String,
+    passwords: Vec<Arc<AppPassword>>,
+}
+
+fn hash(password: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, password.as_bytes());
+    digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// 25 characters, in groups of 5: about 125 bits.
+fn generate() -> String {
+    let chars: Vec<char> = statefile::token(25).chars().collect();
+    chars.chunks(5).map(|c| c.iter().collect::<String>()).collect::<Vec<_>>().join("-")
+}
+
+fn read(file: &str) -> io::Result<Vec<Arc<AppPassword>>> {
+    let data = match std::fs::read(file) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", file, e))),
+    };
+    let passwords: Vec<AppPassword> = serde_json::from_slice(&data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", file, e)))?;
+    Ok(passwords.into_iter().map(Arc::new).collect())
+}
+
+fn write(file: &str, passwords: &[Arc<AppPassword>]) -> io::Result<()> {
+    let passwords: Vec<&AppPassword> = passwords.iter().map(|p| &**p).collect();
+    let data = serde_json::to_vec_pretty(&passwords).map_err(io::Error::other)?;
+    statefile::write(file, &data)
+}
+
+/// (Re)read the file. Without one, app passwords are off.
+pub fn load(file: Option<&str>) -> io::Result<()> {
+    let store = match file {
+        Some(file) => {
+            Some(Store {
+                file:      file.to_string(),
+                passwords: read(file)?,
+            })
+        },
+        None => None,
+    };
+    *STORE.lock().unwrap() = store;
+    Ok(())
+}
+
+/// The app password of this user, if it is one.
+pub fn lookup(user: &str, password: &str) -> Option<Arc<AppPassword>> {
+    let store = STORE.lock().unwrap();
+    let mut passwords = store.as_ref()?.passwords.iter();
+    let hash = hash(password);
+    passwords
+        .find(|p| crate::htpasswd::consteq(p.hash.as_bytes(), hash.as_bytes()) && p.user == user)
+        .cloned()
+}
+
+/// The app passwords of a user (or of everyone), without the hashes.
+pub fn list(user: Option<&str>) -> io::Result<Vec<AppPassword>> {
+    let store = STORE.lock().unwrap();
+    let store = store.as_ref().ok_or_else(|| io::Error::other("no [app-passwords] file"))?;
+    let passwords = store.passwords.iter().filter(|p| user.map(|u| u == p.user).unwrap_or(true));
+    Ok(passwords
+        .map(|p| {
+            AppPassword {
+                hash: String::new(),
+                ..(**p).clone()
+            }
+        })
+        .collect())
+}
+
+/// A new app password. Returns its id, and the password (which is not
+/// stored, it can only be shown now).
+pub fn create(user: &str, name: &str, read_only: bool, paths: Vec<String>) -> io::Result<(String, String)> {
+    if paths.iter().any(|p| !p.starts_with('/')) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "paths must start with /"));
+    }
+    let mut store = STORE.lock().unwrap();
+    let store = store.as_mut().ok_or_else(|| io::Error::other("no [app-passwords] file"))?;
+    let password = generate();
+    // 5 characters is short enough to clash now and then.
+    let id = loop {
+        let id = generate()[..5].to_string();
+        if !store.passwords.iter().any(|p| p.id == id) {
+            break id;
+        }
+    };
+    let mut passwords = store.passwords.clone();
+    passwords.push(Arc::new(AppPassword {
+        id: id.clone(),
+        user: user.to_string(),
+        name: name.to_string(),
+        hash: hash(&password),
+        created: now(),
+        read_only,
+        paths,
+    }));
+    write(&store.file, &passwords)?;
+    store.passwords = passwords;
+    Ok((id, password))
+}
+
+/// Revoke an app password of a user.
+pub fn revoke(user: &str, id: &str) -> io::Result<()> {
+    let mut store = STORE.lock().unwrap();
+    let store = store.as_mut().ok_or_else(|| io::Error::other("no [app-passwords] file"))?;
+    let mut passwords = store.passwords.clone();
+    let len = passwords.len();
+    passwords.retain(|p| !(p.user == user && p.id == id));
+    if passwords.len() == len {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: {}: not found", user, id)));
+    }
+    write(&store.file, &passwords)?;
+    store.passwords = passwords;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apppass() {
+        let pw = generate();
+        assert_eq!(pw.len(), 29);
+        assert!(pw.split('-').all(|g| g.len() == 5 && g.bytes().all(|b| statefile::ALPHABET.contains(&b))));
+        assert_ne!(generate(), pw);
+
+        let app = AppPassword {
+            paths: vec!["/dav/photos/".to_string()],
+            ..AppPassword::default()
+        };
+        assert!(app.allows(b"/dav/photos"));
+        assert!(app.allows(b"/dav/photos/2024/a.jpg"));
+        assert!(!app.allows(b"/dav/photosx"));
+        assert!(!app.allows(b"/dav"));
+        assert!(AppPassword::default().allows(b"/"));
+
+        let dir = std::env::temp_dir().join(format!("apppass-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("app-passwords.json");
+        load(Some(file.to_str().unwrap())).unwrap();
+        let (id, password) = create("alice", "phone", true, Vec::new()).unwrap();
+        assert!(create("alice", "x", false, vec!["rel".to_string()]).is_err());
+        assert_eq!(lookup("alice", &password).unwrap().name, "phone");
+        assert!(lookup("bob", &password).is_none());
+        assert!(lookup("alice", "wrong").is_none());
+
+        load(Some(file.to_str().unwrap())).unwrap();
+        let list = list(Some("alice")).unwrap();
+        assert_eq!((list.len(), list[0].read_only, list[0].hash.as_str()), (1, true, ""));
+        revoke("alice", &id).unwrap();
+        assert!(revoke("alice", &id).is_err());
+        assert!(lookup("alice", &password).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
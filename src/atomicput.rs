@@ -0,0 +1,420 @@
+//
+// Atomic uploads (atomic-put) and fsync.
+//
+// A PUT of a whole file is written to a temporary file next to it, and
+// renamed over it when all of the body is there (with If-None-Match: *
+// only if there still is no file by then). Until then, others see
+// the old file; an upload that is cut off leaves nothing behind (but for
+// a crash: the ".webdav-put.*" files, that the janitor removes). Those
+// files are not listed. With preserve-mode the new file gets the mode and
+// group of the old one.
+//
+// With fsync, a file that was written is synced before it is closed:
+// "data" its contents, "dir" also the directory it is in, after the
+// rename, so that a new name survives a crash too.
+//
+use std::future::Future;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use futures::future::{BoxFuture, FutureExt};
+use futures::StreamExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::casefs::{join, segments};
+use crate::config::Fsync;
+use crate::trashfs;
+use crate::userfs::UserFs;
+
+const TMP_PREFIX: &[u8] = b".webdav-put.";
+
+// Bytes of the name in the name of the temporary file.
+const TMP_NAME_MAX: usize = 128;
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// At most "max" bytes of a name, not in the middle of a UTF-8 character.
+fn name_prefix(name: &[u8], max: usize) -> &[u8] {
+    if name.len() <= max {
+        return name;
+    }
+    let mut end = max;
+    while end > 0 && (name[end] & 0xc0) == 0x80 {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+#[derive(Clone)]
+pub struct AtomicPutFs {
+    fs:       Box<UserFs>,
+    atomic:   bool,
+    fsync:    Fsync,
+    preserve: bool,
+    // Content-Length of the PUT.
+    expected: Option<u64>,
+}
+
+impl AtomicPutFs {
+    pub fn new(
+        fs: Box<UserFs>,
+        atomic: bool,
+        fsync: Option<Fsync>,
+        preserve: bool,
+        expected: Option<u64>,
+    ) -> Box<AtomicPutFs>
+    {
+        Box::new(AtomicPutFs {
+            fs,
+            atomic,
+            fsync: fsync.unwrap_or(Fsync::None),
+            preserve,
+            expected,
+        })
+    }
+
+    // A new name for the temporary file, in the same directory. Of the
+    // name only the start is in it, so that it fits in NAME_MAX as well.
+    fn tmp_path(path: &DavPath) -> FsResult<DavPath> {
+        let mut names: Vec<Vec<u8>> = segments(path).into_iter().map(|n| n.to_vec()).collect();
+        let name = names.pop().ok_or(FsError::Forbidden)?;
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let mut tmp = TMP_PREFIX.to_vec();
+        tmp.extend_from_slice(name_prefix(&name, TMP_NAME_MAX));
+        tmp.extend_from_slice(format!(".{}.{}", std::process::id(), n).as_bytes());
+        names.push(tmp);
+        join(&names, false)
+    }
+
+    // Write to a temporary file, or to the file itself.
+    async fn open_tmp(&self, path: &DavPath, options: OpenOptions) -> FsResult<Option<DavPath>> {
+        if !self.atomic || !options.write || !options.truncate || options.append {
+            return Ok(None);
+        }
+        match self.fs.symlink_metadata(path).await {
+            // not over a symlink, but through it.
+            Ok(meta) if meta.is_symlink() => return Ok(None),
+            Ok(meta) if meta.is_dir() => return Err(FsError::Forbidden),
+            Ok(_) if options.create_new => return Err(FsError::Exists),
+            Err(FsError::NotFound) if !options.create && !options.create_new => return Err(FsError::NotFound),
+            _ => {},
+        }
+        Ok(Some(Self::tmp_path(path)?))
+    }
+}
+
+struct AtomicPutFile {
+    file:       Box<dyn DavFile>,
+    fs:         Box<UserFs>,
+    path:       DavPath,
+    tmp:        Option<DavPath>,
+    fsync:      Fsync,
+    expected:   Option<u64>,
+    create_new: bool,
+    pos:        u64,
+    written:    u64,
+    done:       bool,
+}
+
+impl std::fmt::Debug for AtomicPutFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AtomicPutFile")
+            .field("path", &self.path)
+            .field("tmp", &self.tmp)
+            .field("written", &self.written)
+            .finish()
+    }
+}
+
+impl AtomicPutFile {
+    fn wrote(&mut self, len: usize) {
+        self.pos += len as u64;
+        self.written = self.written.max(self.pos);
+    }
+
+    // Sync, and move the temporary file into place.
+    async fn commit(&mut self) -> FsResult<()> {
+        let tmp = match self.tmp {
+            Some(ref tmp) => tmp.clone(),
+            None => {
+                match self.fsync {
+                    Fsync::None => return Ok(()),
+                    _ => return self.fs.sync(&self.path, true).await,
+                }
+            },
+        };
+        if let Some(expected) = self.expected {
+            if self.written != expected {
+                debug!("atomic-put: {}: {} of {} bytes", self.path.as_url_string(), self.written, expected);
+                return Err(FsError::GeneralFailure);
+            }
+        }
+        if self.fsync != Fsync::None {
+            self.fs.sync(&tmp, true).await?;
+        }
+        match self.create_new {
+            // If-None-Match: *, also against a file made since open.
+            true => self.fs.rename_noreplace(&tmp, &self.path).await?,
+            false => self.fs.rename(&tmp, &self.path).await?,
+        }
+        self.tmp = None;
+        if self.fsync == Fsync::Dir {
+            let mut names: Vec<Vec<u8>> = segments(&self.path).into_iter().map(|n| n.to_vec()).collect();
+            names.pop();
+            self.fs.sync(&join(&names, true)?, false).await?;
+        }
+        Ok(())
+    }
+}
+
+// An upload that did not get to the end.
+impl Drop for AtomicPutFile {
+    fn drop(&mut self) {
+        if let Some(tmp) = self.tmp.take() {
+            let fs = self.fs.clone();
+            tokio::spawn(async move {
+                if let Err(e) = fs.remove_file(&tmp).await {
+                    warn!("atomic-put: remove {}: {:?}", tmp.as_url_string(), e);
+                }
+            });
+        }
+    }
+}
+
+impl DavFile for AtomicPutFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.file.metadata()
+    }
+
+    fn write_buf<'a>(&'a mut self, buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        async move {
+            let len = buf.remaining();
+            self.file.write_buf(buf).await?;
+            self.wrote(len);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        async move {
+            let len = buf.len();
+            self.file.write_bytes(buf).await?;
+            self.wrote(len);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        async move {
+            let data = self.file.read_bytes(count).await?;
+            self.pos += data.len() as u64;
+            Ok(data)
+        }
+        .boxed()
+    }
+
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> FsFuture<'a, u64> {
+        async move {
+            self.pos = self.file.seek(pos).await?;
+            Ok(self.pos)
+        }
+        .boxed()
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        async move {
+            self.file.flush().await?;
+            if std::mem::replace(&mut self.done, true) {
+                return Ok(());
+            }
+            self.commit().await
+        }
+        .boxed()
+    }
+}
+
+impl DavFileSystem for AtomicPutFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let write = options.write || options.append;
+            if !write || (!self.atomic && self.fsync == Fsync::None) {
+                return self.fs.open(path, options).await;
+            }
+            let tmp = self.open_tmp(path, options).await?;
+            let file = match tmp {
+                Some(ref tmp) => {
+                    let oo = OpenOptions {
+                        create:     true,
+                        create_new: true,
+                        ..options
+                    };
+                    let file = self.fs.open(tmp, oo).await?;
+                    if self.preserve && self.fs.symlink_metadata(path).await.is_ok() {
+                        if let Err(e) = self.fs.copy_mode(path, tmp).await {
+                            warn!("atomic-put: {}: preserve-mode: {:?}", path.as_url_string(), e);
+                        }
+                    }
+                    file
+                },
+                None => self.fs.open(path, options).await?,
+            };
+            Ok(Box::new(AtomicPutFile {
+                file,
+                fs: self.fs.clone(),
+                path: path.clone(),
+                expected: self.expected.filter(|_| tmp.is_some()),
+                tmp,
+                fsync: self.fsync,
+                create_new: options.create_new,
+                pos: 0,
+                written: 0,
+                done: false,
+            }) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            let strm = self.fs.read_dir(path, meta).await?;
+            let strm = strm.filter(|entry| futures::future::ready(!entry.name().starts_with(TMP_PREFIX)));
+            Ok(Box::pin(strm) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_dir(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_file(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.rename(from, to)
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.copy(from, to)
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        self.fs.patch_props(path, patch)
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+/// Remove the temporary files of uploads that were cut off by a crash:
+/// those from before "cutoff", in "dir" and below. The number removed.
+pub fn remove_stale(fs: &dyn DavFileSystem, dir: DavPath, cutoff: SystemTime) -> BoxFuture<'_, usize> {
+    async move {
+        let mut entries = match fs.read_dir(&dir, ReadDirMeta::DataSymlink).await {
+            Ok(entries) => entries,
+            Err(_) => return 0,
+        };
+        let mut stale = Vec::new();
+        let mut dirs = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let name = entry.name();
+            match entry.metadata().await {
+                Ok(meta) if meta.is_dir() => dirs.push(name),
+                Ok(meta) if name.starts_with(TMP_PREFIX) && meta.modified().is_ok_and(|m| m < cutoff) => {
+                    stale.push(name)
+                },
+                _ => {},
+            }
+        }
+        let mut removed = 0;
+        for name in stale {
+            let path = match trashfs::join(&dir, &name) {
+                Ok(path) => path,
+                Err(_) => continue,
+            };
+            match fs.remove_file(&path).await {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("atomic-put: remove {}: {:?}", path.as_url_string(), e),
+            }
+        }
+        for name in dirs {
+            if let Ok(path) = trashfs::join(&dir, &name) {
+                removed += remove_stale(fs, path, cutoff).await;
+            }
+        }
+        removed
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_atomicput() {
+        let path = DavPath::new("/dir/caf%C3%A9.txt").unwrap();
+        let tmp = AtomicPutFs::tmp_path(&path).unwrap();
+        let name = segments(&tmp)[1].to_vec();
+        assert_eq!(segments(&tmp)[0], b"dir");
+        assert!(name.starts_with(b".webdav-put.caf\xc3\xa9.txt."));
+        assert_ne!(AtomicPutFs::tmp_path(&path).unwrap().as_bytes(), tmp.as_bytes());
+        assert!(AtomicPutFs::tmp_path(&DavPath::new("/").unwrap()).is_err());
+
+        let long = DavPath::new(&format!("/{}", "%C3%A9".repeat(127))).unwrap();
+        let name = segments(&AtomicPutFs::tmp_path(&long).unwrap())[0].to_vec();
+        assert!(name.len() <= 255 && std::str::from_utf8(&name).is_ok());
+        assert!(name.starts_with(&[TMP_PREFIX, "é".repeat(64).as_bytes(), b"."].concat()));
+    }
+}
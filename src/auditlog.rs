@@ -0,0 +1,255 @@
+//
+// Audit log.
+//
+// One line per request that changes something (PUT, PATCH, DELETE,
+// MKCOL, COPY, MOVE, PROPPATCH, LOCK, UNLOCK), with the user, the client
+// address, the path and destination, the bytes received and the status.
+// Also when it failed. It is separate from the access log:
+//
+// {"bytes":1234,"destination":null,"method":"PUT","path":"/files/a.txt",
+//  "remote_addr":"192.0.2.1","status":201,"time":"2021-06-01T12:00:00Z","user":"bob"}
+//
+// With audit-chain, every line also has the hash of the line before it
+// ("prev"), and its own "hash": the SHA256 of the line without the
+// "hash". A line that was changed or removed later breaks the chain,
+// which --verify-audit-log shows. The chain goes on in a new file after
+// logrotate, and at a restart, from the last line of the file.
+//
+// The log file is reopened on SIGUSR1, for logrotate.
+//
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::stream::StreamExt;
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+const METHODS: &[&str] = &["PUT", "PATCH", "DELETE", "MKCOL", "COPY", "MOVE", "PROPPATCH", "LOCK", "UNLOCK"];
+
+// The "prev" of the first line.
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+struct Log {
+    path: String,
+    file: File,
+    // the hash of the last line, with audit-chain.
+    last: Option<String>,
+}
+
+lazy_static! {
+    static ref AUDITLOG: Mutex<Option<Log>> = Mutex::new(None);
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn open_file(path: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .read(true)
+        .open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+// The hash in the last line of the file, to go on with.
+fn last_hash(file: &mut File) -> io::Result<Option<String>> {
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(len.saturating_sub(65536)))?;
+    let mut tail = String::new();
+    file.read_to_string(&mut tail)?;
+    let line = match tail.lines().rev().find(|l| !l.trim().is_empty()) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let value: Value = serde_json::from_str(line).unwrap_or(Value::Null);
+    Ok(value.get("hash").and_then(Value::as_str).map(|s| s.to_string()))
+}
+
+/// Open the log. Must be called before dropping privileges.
+pub fn open(path: &str, chain: bool) -> io::Result<()> {
+    let mut file = open_file(path)?;
+    let last = match chain {
+        true => {
+            let err = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path, e));
+            let last = last_hash(&mut file).map_err(err)?;
+            Some(last.unwrap_or_else(|| GENESIS.to_string()))
+        },
+        false => None,
+    };
+    *AUDITLOG.lock().unwrap() = Some(Log {
+        path: path.to_string(),
+        file,
+        last,
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Close and reopen the log file. If that fails, the old file is kept.
+pub fn reopen() -> io::Result<()> {
+    if let Some(ref mut log) = *AUDITLOG.lock().unwrap() {
+        log.file = open_file(&log.path)?;
+    }
+    Ok(())
+}
+
+/// A request that will be in the audit log.
+pub struct Entry {
+    time:        time::Tm,
+    addr:        IpAddr,
+    method:      String,
+    path:        String,
+    destination: Option<String>,
+    received:    Option<Arc<AtomicU64>>,
+}
+
+impl Entry {
+    /// Start an entry, if there is an audit log and the method changes
+    /// something. The body of a PUT or PATCH is counted.
+    pub fn new(req: &mut http::Request<hyper::Body>, addr: IpAddr) -> Option<Entry> {
+        if !ENABLED.load(Ordering::Relaxed) || !METHODS.contains(&req.method().as_str()) {
+            return None;
+        }
+        let received = match *req.method() {
+            http::Method::PUT | http::Method::PATCH => {
+                let received = Arc::new(AtomicU64::new(0));
+                let count = received.clone();
+                let body = std::mem::take(req.body_mut()).inspect(move |data| {
+                    if let Ok(data) = data {
+                        count.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    }
+                });
+                *req.body_mut() = hyper::Body::wrap_stream(body);
+                Some(received)
+            },
+            _ => None,
+        };
+        let destination = req.headers().get("Destination");
+        Some(Entry {
+            time: time::now_utc(),
+            addr,
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            destination: destination.map(|d| String::from_utf8_lossy(d.as_bytes()).into_owned()),
+            received,
+        })
+    }
+
+    // The line, and its hash if it is chained to "prev".
+    fn line(&self, status: u16, user: Option<&str>, prev: Option<&str>) -> (String, Option<String>) {
+        let mut record = json!({
+            "time": self.time.rfc3339().to_string(),
+            "remote_addr": self.addr.to_string(),
+            "user": user,
+            "method": self.method,
+            "path": self.path,
+            "destination": self.destination,
+            "bytes": self.received.as_ref().map(|r| r.load(Ordering::Relaxed)),
+            "status": status,
+        });
+        let prev = match prev {
+            Some(prev) => prev,
+            None => return (record.to_string(), None),
+        };
+        record["prev"] = prev.into();
+        let hash = format!("{:x}", Sha256::digest(record.to_string().as_bytes()));
+        record["hash"] = hash.clone().into();
+        (record.to_string(), Some(hash))
+    }
+
+    /// Write the entry, with the status of the response.
+    pub fn log(&self, status: u16, user: Option<&str>) {
+        let mut auditlog = AUDITLOG.lock().unwrap();
+        let log = match auditlog.as_mut() {
+            Some(log) => log,
+            None => return,
+        };
+        let (line, hash) = self.line(status, user, log.last.as_deref());
+        match log.file.write_all(format!("{}\n", line).as_bytes()) {
+            Ok(()) => {
+                if hash.is_some() {
+                    log.last = hash;
+                }
+            },
+            Err(e) => error!("audit log: {}: {}", log.path, e),
+        }
+    }
+}
+
+// Check the chain in the lines. Returns the number of lines.
+fn verify_lines(lines: impl BufRead) -> Result<u64, String> {
+    let mut last: Option<String> = None;
+    let mut count = 0;
+    for (idx, line) in lines.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let lineno = idx + 1;
+        let mut record: Value = serde_json::from_str(&line).map_err(|e| format!("line {}: {}", lineno, e))?;
+        let hash = match record.as_object_mut().and_then(|r| r.remove("hash")) {
+            Some(Value::String(hash)) => hash,
+            _ => return Err(format!("line {}: no hash", lineno)),
+        };
+        if format!("{:x}", Sha256::digest(record.to_string().as_bytes())) != hash {
+            return Err(format!("line {}: the hash does not match, it was changed", lineno));
+        }
+        let prev = record.get("prev").and_then(Value::as_str).unwrap_or("");
+        if last.as_deref().map(|last| last != prev).unwrap_or(false) {
+            return Err(format!("line {}: not chained to the line before it", lineno));
+        }
+        last = Some(hash);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Check the hash chain of an audit log file. Returns the number of lines.
+pub fn verify(path: &str) -> io::Result<u64> {
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    verify_lines(io::BufReader::new(file))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auditlog() {
+        let entry = Entry {
+            time:        time::at_utc(time::Timespec::new(1622548800, 0)),
+            addr:        "192.0.2.1".parse().unwrap(),
+            method:      "PUT".into(),
+            path:        "/files/a.txt".into(),
+            destination: None,
+            received:    Some(Arc::new(AtomicU64::new(1234))),
+        };
+        let (line, hash) = entry.line(201, Some("bob"), None);
+        assert!(hash.is_none());
+        assert_eq!(
+            line,
+            concat!(
+                "{\"bytes\":1234,\"destination\":null,\"method\":\"PUT\",\"path\":\"/files/a.txt\",",
+                "\"remote_addr\":\"192.0.2.1\",\"status\":201,",
+                "\"time\":\"2021-06-01T12:00:00Z\",\"user\":\"bob\"}"
+            )
+        );
+
+        let (first, hash) = entry.line(201, Some("bob"), Some(GENESIS));
+        let (second, _) = entry.line(403, None, hash.as_deref());
+        let log = format!("{}\n{}\n", first, second);
+        assert_eq!(verify_lines(log.as_bytes()), Ok(2));
+
+        let changed = log.replacen("\"status\":403", "\"status\":204", 1);
+        assert!(verify_lines(changed.as_bytes()).unwrap_err().starts_with("line 2: the hash"));
+        let (other, _) = entry.line(204, None, hash.as_deref());
+        let removed = format!("{}\n{}\n", second, other);
+        assert!(verify_lines(removed.as_bytes()).unwrap_err().starts_with("line 2: not chained"));
+        assert_eq!(verify_lines(format!("{}\n", line).as_bytes()), Err("line 1: no hash".to_string()));
+    }
+}
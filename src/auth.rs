@@ -1,66 +1,489 @@
+use std::collections::HashMap;
 use std::io;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::config::{AuthType, Config, Location};
+use crate::config::{AuthScheme, AuthType, Config, Location};
+#[cfg(feature = "pam")]
+use crate::config::{PamSession, Seccomp};
 
-use headers::{authorization::Basic, Authorization, HeaderMapExt};
+use headers::{authorization::Bearer, Authorization, HeaderMapExt};
 use http::status::StatusCode;
 
 type HttpRequest = http::Request<hyper::Body>;
 
+type PamEnv = Arc<HashMap<String, String>>;
+
+lazy_static::lazy_static! {
+    static ref PAM_ENV: Mutex<HashMap<String, PamEnv>> = Mutex::new(HashMap::new());
+}
+
 #[derive(Clone)]
 pub struct Auth {
-    config:   Arc<Config>,
+    config:    Arc<Config>,
     #[cfg(feature = "pam")]
-    pam_auth: pam_sandboxed::PamAuth,
+    pam_auth:  pam_sandboxed::PamAuth,
+    jwt_auth:  HashMap<String, crate::jwt::JwtAuth>,
+    oidc_auth: HashMap<String, crate::oidc::OidcAuth>,
+    throttle: Arc<crate::throttle::Throttle>,
+}
+
+/// The username from a verified TLS client certificate. Set as a request extension.
+#[derive(Clone, Debug)]
+pub struct ClientCertUser(pub String);
+
+/// A request the server makes to itself for a logged in user (a COPY or
+/// MOVE to another location). Set as a request extension. Good for the
+/// locations with the same auth-type and realm.
+#[derive(Clone, Debug)]
+pub struct Subrequest {
+    pub user:  String,
+    pub scope: String,
+}
+
+// Split the Authorization: header into scheme and parameters.
+fn auth_scheme(req: &HttpRequest) -> Option<(&str, &str)> {
+    let hdr = req.headers().get(http::header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, params) = hdr.split_once(' ')?;
+    Some((scheme, params.trim()))
+}
+
+// The username and password in the credentials of "Basic". They are
+// UTF-8 (RFC 7617, that is what charset="UTF-8" in the challenge asks
+// for), but older clients send ISO-8859-1; that is decoded as such.
+fn decode_basic(credentials: &str) -> Option<(String, String)> {
+    let bytes = base64::decode(credentials.trim()).ok()?;
+    let decoded = match String::from_utf8(bytes) {
+        Ok(decoded) => decoded,
+        Err(e) => e.into_bytes().into_iter().map(char::from).collect(),
+    };
+    let (user, pass) = decoded.split_once(':')?;
+    Some((user.to_string(), pass.to_string()))
+}
+
+/// The username and password from an "Authorization: Basic" header.
+pub fn basic_credentials(req: &HttpRequest) -> Option<(String, String)> {
+    auth_scheme(req)
+        .filter(|(s, _)| s.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, c)| decode_basic(c))
+}
+
+// Does the request carry credentials that we know how to check.
+pub fn has_credentials(req: &HttpRequest) -> bool {
+    if req.extensions().get::<ClientCertUser>().is_some() || crate::oidc::has_session(req) {
+        return true;
+    }
+    if req.extensions().get::<Subrequest>().is_some() {
+        return true;
+    }
+    if crate::session::has_cookie(req, crate::session::COOKIE) {
+        return true;
+    }
+    match auth_scheme(req) {
+        Some((scheme, _)) => {
+            let scheme = scheme.to_ascii_lowercase();
+            #[cfg(feature = "kerberos")]
+            if scheme == "negotiate" {
+                return true;
+            }
+            scheme == "basic" || scheme == "bearer" || scheme == "digest"
+        },
+        None => false,
+    }
+}
+
+/// The app password the request logs in with, if it does.
+pub fn app_password(req: &HttpRequest) -> Option<Arc<crate::apppass::AppPassword>> {
+    let (user, pass) = basic_credentials(req)?;
+    crate::apppass::lookup(&user, &pass)
+}
+
+// Get the token from an "Authorization: Negotiate" header.
+#[cfg(feature = "kerberos")]
+fn negotiate_token(req: &HttpRequest) -> Option<&str> {
+    auth_scheme(req).filter(|(s, _)| s.eq_ignore_ascii_case("negotiate")).map(|(_, t)| t)
+}
+
+// The username the client is trying to login as, if we can tell.
+fn request_user(req: &HttpRequest) -> String {
+    if let Some((user, _)) = basic_credentials(req) {
+        return user;
+    }
+    digest_params(req)
+        .and_then(|mut p| p.remove("username"))
+        .unwrap_or_default()
+}
+
+/// The password from an "Authorization: Basic" header.
+pub fn basic_password(req: &HttpRequest) -> Option<String> {
+    basic_credentials(req).map(|(_, pass)| pass)
+}
+
+// Get the parameters from an "Authorization: Digest" header.
+fn digest_params(req: &HttpRequest) -> Option<HashMap<String, String>> {
+    auth_scheme(req)
+        .filter(|(s, _)| s.eq_ignore_ascii_case("digest"))
+        .map(|(_, p)| crate::digest::parse(p))
+}
+
+/// The PAM environment of a user ([pam] environment), from when the
+/// password was last verified, and the session of the last request.
+pub fn pam_env(user: &str) -> Option<PamEnv> {
+    PAM_ENV.lock().unwrap().get(user).cloned()
+}
+
+#[cfg(feature = "pam")]
+fn set_pam_env(user: &str, env: &[(String, String)]) {
+    if env.is_empty() {
+        return;
+    }
+    let mut users = PAM_ENV.lock().unwrap();
+    let mut vars = users.get(user).map(|e| (**e).clone()).unwrap_or_default();
+    vars.extend(env.iter().cloned());
+    users.insert(user.to_string(), Arc::new(vars));
+}
+
+// The remote IP address for PAM_RHOST. Behind trusted-proxies that is
+// the client already.
+#[cfg(feature = "pam")]
+fn pam_rhost(remote_ip: SocketAddr) -> Option<String> {
+    Some(remote_ip.ip().to_string())
+}
+
+// Basic auth has no room for a second round-trip, so a one-time password
+// is sent appended to the password. Split it off again, either at a
+// fixed length or at the last separator. None if it is missing.
+#[cfg(feature = "pam")]
+fn split_otp<'a>(cfg: &crate::config::Pam, pass: &'a str) -> Option<(&'a str, Option<&'a str>)> {
+    if let Some(len) = cfg.otp_length {
+        let idx = pass.char_indices().rev().nth(len.checked_sub(1)?)?.0;
+        return Some((&pass[..idx], Some(&pass[idx..])));
+    }
+    if let Some(ref sep) = cfg.otp_separator {
+        let (pass, otp) = pass.rsplit_once(sep.as_str())?;
+        return Some((pass, Some(otp)));
+    }
+    Some((pass, None))
 }
 
 impl Auth {
     pub fn new(config: Arc<Config>) -> io::Result<Auth> {
         // initialize pam.
         #[cfg(feature = "pam")]
-        let pam_auth = {
-            // set cache timeouts.
-            if let Some(timeout) = config.pam.cache_timeout {
-                crate::cache::cached::set_pamcache_timeout(timeout);
+        let mut pam_auth = {
+            let timeout = match config.pam.timeout.unwrap_or(30) {
+                0 => None,
+                t => Some(std::time::Duration::from_secs(t)),
+            };
+            let sandbox = &config.sandbox;
+            match sandbox.pam_seccomp.or(sandbox.seccomp).unwrap_or(Seccomp::Off) {
+                Seccomp::Off => pam_sandboxed::PamAuth::with_options(config.pam.threads, timeout)?,
+                mode => {
+                    let sandbox = move || crate::seccomp::apply(mode, true);
+                    pam_sandboxed::PamAuth::with_sandbox(config.pam.threads, timeout, sandbox)?
+                },
             }
-            pam_sandboxed::PamAuth::new(config.pam.threads.clone())?
         };
+        #[cfg(feature = "pam")]
+        pam_auth.set_tty(config.pam.tty.as_deref());
+
+        // kerberos keytab. Not changed on reload, the environment
+        // must not be modified once there are other threads.
+        #[cfg(feature = "kerberos")]
+        if let Some(ref keytab) = config.kerberos.keytab {
+            std::env::set_var("KRB5_KTNAME", keytab);
+        }
+
+        // open the user database while we still can.
+        #[cfg(feature = "sqlite")]
+        if config.uses_sqlite() {
+            crate::userdb::open(config.sqlite.path())?;
+        }
 
+        let (jwt_auth, oidc_auth) = Auth::init(&config)?;
+        let throttle = Arc::new(crate::throttle::Throttle::new(&config.throttle));
+
+        Ok(Auth {
+            #[cfg(feature = "pam")]
+            pam_auth,
+            jwt_auth,
+            oidc_auth,
+            throttle,
+            config,
+        })
+    }
+
+    /// A new Auth for a reloaded config. The PAM helper processes (which
+    /// cannot be restarted without root privileges) and the login throttle
+    /// are kept.
+    pub fn reload(&self, config: Arc<Config>) -> io::Result<Auth> {
+        let (jwt_auth, oidc_auth) = Auth::init(&config)?;
+        #[cfg(feature = "pam")]
+        let mut pam_auth = self.pam_auth.clone();
+        #[cfg(feature = "pam")]
+        pam_auth.set_tty(config.pam.tty.as_deref());
         Ok(Auth {
             #[cfg(feature = "pam")]
             pam_auth,
+            jwt_auth,
+            oidc_auth,
+            throttle: self.throttle.clone(),
             config,
         })
     }
 
+    /// Check that the PAM helper process answers, if PAM is used.
+    pub async fn pam_ping(&self, timeout: Duration) -> Result<(), String> {
+        #[cfg(feature = "pam")]
+        if self.config.uses_pam() {
+            let mut pam_auth = self.pam_auth.clone();
+            return match tokio::time::timeout(timeout, pam_auth.ping()).await {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err("timeout".to_string()),
+            };
+        }
+        let _ = timeout;
+        Ok(())
+    }
+
+    // Settings that can change on reload: caches, app passwords, JWT validators
+    // and OIDC providers.
+    #[allow(clippy::type_complexity)]
+    fn init(
+        config: &Config,
+    ) -> io::Result<(HashMap<String, crate::jwt::JwtAuth>, HashMap<String, crate::oidc::OidcAuth>)>
+    {
+        // set cache timeouts. [pam] cache-timeout is the old name.
+        let timeout = config.accounts.auth_cache_timeout.or(config.pam.cache_timeout);
+        if let Some(timeout) = timeout {
+            crate::cache::cached::set_authcache_timeout(timeout);
+        }
+        if let Some(size) = config.accounts.auth_cache_size {
+            crate::cache::cached::set_authcache_size(size);
+        }
+
+        crate::apppass::load(config.apppass.file.as_deref())?;
+        crate::share::load(&config.shares)?;
+
+        // initialize the JWT validators.
+        let mut jwt_auth = HashMap::new();
+        for (name, jwt) in &config.jwt {
+            let ja = crate::jwt::JwtAuth::new(jwt)
+                .map_err(|e| io::Error::new(e.kind(), format!("[jwt.{}]: {}", name, e)))?;
+            jwt_auth.insert(name.to_string(), ja);
+        }
+        let mut oidc_auth = HashMap::new();
+        for (name, oidc) in &config.oidc {
+            let oa = crate::oidc::OidcAuth::new(name, oidc)
+                .map_err(|e| io::Error::new(e.kind(), format!("[oidc.{}]: {}", name, e)))?;
+            oidc_auth.insert(name.to_string(), oa);
+        }
+        Ok((jwt_auth, oidc_auth))
+    }
+
+    fn auth_type<'a>(&'a self, location: Option<&'a Location>) -> Option<&'a AuthType> {
+        location
+            .and_then(|location| location.accounts.auth_type.as_ref())
+            .or(self.config.accounts.auth_type.as_ref())
+    }
+
+    fn realm<'a>(&'a self, location: Option<&'a Location>) -> &'a str {
+        location
+            .and_then(|location| location.accounts.realm.as_ref())
+            .or(self.config.accounts.realm.as_ref())
+            .map(|s| s.as_str())
+            .unwrap_or("Webdav Server")
+    }
+
+    /// The authentication scheme that a location uses.
+    pub fn scheme(&self, location: &Location) -> AuthScheme {
+        match self.auth_type(Some(location)) {
+            Some(AuthType::Jwt(_)) | Some(AuthType::Oidc(_)) => AuthScheme::Bearer,
+            #[cfg(feature = "kerberos")]
+            Some(AuthType::Kerberos) => AuthScheme::Negotiate,
+            Some(AuthType::HtDigest(_)) => AuthScheme::Digest,
+            _ => AuthScheme::Basic,
+        }
+    }
+
+    // value for the WWW-Authenticate: header.
+    pub fn www_authenticate(&self, location: Option<&Location>, req: Option<&HttpRequest>) -> String {
+        let realm = self.realm(location);
+        match self.auth_type(location) {
+            Some(AuthType::Jwt(_)) | Some(AuthType::Oidc(_)) => format!("Bearer realm=\"{}\"", realm),
+            #[cfg(feature = "kerberos")]
+            Some(AuthType::Kerberos) => "Negotiate".to_string(),
+            Some(AuthType::HtDigest(section)) => {
+                // If the client used a nonce that has expired, tell it so,
+                // so that it retries with a new one without asking the user.
+                let lifetime = self.nonce_lifetime(section);
+                let stale = req
+                    .and_then(digest_params)
+                    .and_then(|p| p.get("nonce").map(|n| crate::digest::check_nonce(n, lifetime)))
+                    .map(|n| n == crate::digest::Nonce::Stale)
+                    .unwrap_or(false);
+                crate::digest::challenge(realm, stale)
+            },
+            _ => format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm),
+        }
+    }
+
+    fn nonce_lifetime(&self, section: &str) -> u64 {
+        self.config
+            .htdigest
+            .get(section)
+            .and_then(|h| h.nonce_timeout)
+            .unwrap_or(300)
+    }
+
     // authenticate user.
     pub async fn auth<'a>(
         &'a self,
         req: &'a HttpRequest,
         location: &Location,
-        _remote_ip: SocketAddr,
+        remote_ip: SocketAddr,
+    ) -> Result<String, StatusCode>
+    {
+        // If there were too many failed logins, don't even try.
+        let ip = remote_ip.ip();
+        let user = request_user(req);
+        if !self.throttle.check(ip, &user) {
+            debug!("auth: too many failed logins for {} from {}", user, ip);
+            crate::authlog::throttled(ip, &user);
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        // The server itself, for a user that already logged in.
+        if let Some(sub) = req.extensions().get::<Subrequest>() {
+            if sub.scope == self.session_scope(location) {
+                return Ok(sub.user.clone());
+            }
+        }
+
+        // A session cookie from an earlier login?
+        if let Some(user) = self.session_user(req, location, ip) {
+            return Ok(user);
+        }
+
+        let res = self.auth_inner(req, location, remote_ip).await;
+        match res {
+            Ok(ref user) => {
+                self.throttle.success(ip, user);
+                self.set_session_cookie(req, location, user, ip);
+            },
+            Err(StatusCode::UNAUTHORIZED) if has_credentials(req) => {
+                self.throttle.failure(ip, &user);
+                crate::authlog::failure(ip, &user);
+            },
+            Err(_) => {},
+        }
+        res
+    }
+
+    // If session cookies are on: their timeout, and if they are bound to the address.
+    fn session_cookies(&self, location: &Location) -> Option<(u64, bool)> {
+        let accounts = &self.config.accounts;
+        let timeout = location.accounts.session_timeout.or(accounts.session_timeout);
+        let bind_ip = location.accounts.session_bind_ip.or(accounts.session_bind_ip);
+        timeout.filter(|&t| t > 0).map(|t| (t, bind_ip.unwrap_or(true)))
+    }
+
+    // A session cookie is good for locations with the same auth-type and realm.
+    pub(crate) fn session_scope(&self, location: &Location) -> String {
+        format!("{:?} {}", self.auth_type(Some(location)), self.realm(Some(location)))
+    }
+
+    // The user of a valid session cookie. If there is a username in the
+    // Authorization: header too, it must be the same.
+    fn session_user(&self, req: &HttpRequest, location: &Location, ip: std::net::IpAddr) -> Option<String> {
+        self.session_cookies(location)?;
+        let user = crate::session::check(req, &self.session_scope(location), ip)?;
+        let other = request_user(req);
+        if !other.is_empty() && other != user {
+            return None;
+        }
+        Some(user)
+    }
+
+    // After a login with a password, set a session cookie. Not for app
+    // passwords, the cookie would not have their limits.
+    fn set_session_cookie(&self, req: &HttpRequest, location: &Location, user: &str, ip: std::net::IpAddr) {
+        let (timeout, bind_ip) = match self.session_cookies(location) {
+            Some(settings) => settings,
+            None => return,
+        };
+        let scheme = auth_scheme(req).map(|(s, _)| s.to_ascii_lowercase());
+        let password = matches!(scheme.as_deref(), Some("basic") | Some("digest"));
+        if !password || req.extensions().get::<ClientCertUser>().is_some() || app_password(req).is_some() {
+            return;
+        }
+        if let Some(set_cookie) = req.extensions().get::<crate::session::SetCookie>() {
+            let scope = self.session_scope(location);
+            let value = crate::session::issue(user, &scope, Some(ip).filter(|_| bind_ip), timeout);
+            set_cookie.set(value, timeout);
+        }
+    }
+
+    async fn auth_inner<'a>(
+        &'a self,
+        req: &'a HttpRequest,
+        location: &Location,
+        remote_ip: SocketAddr,
     ) -> Result<String, StatusCode>
     {
+        // a client certificate overrides everything else.
+        if let Some(ClientCertUser(user)) = req.extensions().get::<ClientCertUser>() {
+            return Ok(user.to_string());
+        }
+
+        // so does an app password, whatever the auth type.
+        if let Some(app) = app_password(req) {
+            debug!("auth: {} logged in with app password {} ({})", app.user, app.id, app.name);
+            return Ok(app.user.clone());
+        }
+
+        // match the auth type.
+        let auth_type = self.auth_type(Some(location));
+
+        // bearer tokens are handled separately.
+        if let Some(AuthType::Jwt(jwt)) = auth_type {
+            return self.auth_jwt(req, jwt.as_str()).await;
+        }
+        if let Some(AuthType::Oidc(oidc)) = auth_type {
+            return self.auth_oidc(req, oidc.as_str()).await;
+        }
+        #[cfg(feature = "kerberos")]
+        if let Some(AuthType::Kerberos) = auth_type {
+            return self.auth_kerberos(req).await;
+        }
+        if let Some(AuthType::HtDigest(ht)) = auth_type {
+            return self.auth_htdigest(req, location, ht.as_str()).await;
+        }
+
         // we must have a login/pass
-        let basic = match req.headers().typed_get::<Authorization<Basic>>() {
-            Some(Authorization(basic)) => basic,
+        let (user, pass) = match basic_credentials(req) {
+            Some(credentials) => credentials,
             _ => return Err(StatusCode::UNAUTHORIZED),
         };
-        let user = basic.username();
-        let pass = basic.password();
+        let (user, pass) = (user.as_str(), pass.as_str());
 
-        // match the auth type.
-        let auth_type = location
-            .accounts
-            .auth_type
-            .as_ref()
-            .or(self.config.accounts.auth_type.as_ref());
         match auth_type {
             #[cfg(feature = "pam")]
-            Some(&AuthType::Pam) => self.auth_pam(req, user, pass, _remote_ip).await,
-            Some(&AuthType::HtPasswd(ref ht)) => self.auth_htpasswd(user, pass, ht.as_str()).await,
+            Some(&AuthType::Pam) => self.auth_pam(user, pass, remote_ip).await,
+            Some(AuthType::HtPasswd(ht)) => self.auth_htpasswd(user, pass, ht.as_str()).await,
+            Some(AuthType::Ldap(ldap)) => self.auth_ldap(user, pass, ldap.as_str()).await,
+            Some(AuthType::Sql(sql)) => self.auth_sql(user, pass, sql.as_str()).await,
+            Some(AuthType::Exec) => self.auth_exec(user, pass, remote_ip.ip()).await,
+            #[cfg(feature = "sqlite")]
+            Some(AuthType::Sqlite) => self.auth_sqlite(user, pass).await,
+            // checked in config::check.
+            #[cfg(not(feature = "sqlite"))]
+            Some(AuthType::Sqlite) => unreachable!(),
+            Some(AuthType::Jwt(_)) | Some(AuthType::Oidc(_)) | Some(AuthType::HtDigest(_)) => unreachable!(),
+            #[cfg(feature = "kerberos")]
+            Some(AuthType::Kerberos) => unreachable!(),
             None => {
                 debug!("need authentication, but auth-type is not set");
                 Err(StatusCode::UNAUTHORIZED)
@@ -68,38 +491,87 @@ impl Auth {
         }
     }
 
+    /// Open a PAM session for the duration of a request, if
+    /// `[pam] session = "request"` is set and the location uses PAM.
+    #[cfg(feature = "pam")]
+    pub async fn pam_session(
+        &self,
+        location: &Location,
+        user: &str,
+        remote_ip: SocketAddr,
+    ) -> Result<Option<pam_sandboxed::PamSession>, StatusCode>
+    {
+        match (self.auth_type(Some(location)), &self.config.pam.session) {
+            (Some(AuthType::Pam), Some(PamSession::Request)) => {},
+            _ => return Ok(None),
+        }
+        let service = self.config.pam.service.as_str();
+        let ip_string = pam_rhost(remote_ip);
+        let mut pam_auth = self.pam_auth.clone();
+        match pam_auth.open_session(service, user, ip_string.as_deref()).await {
+            Ok(session) => {
+                if self.config.pam.environment == Some(true) {
+                    set_pam_env(user, session.env());
+                }
+                Ok(Some(session))
+            },
+            Err(e) => {
+                debug!("pam_session({}): open session for {}: {}", service, user, e);
+                Err(StatusCode::FORBIDDEN)
+            },
+        }
+    }
+
     // authenticate user using PAM.
     #[cfg(feature = "pam")]
     async fn auth_pam<'a>(
         &'a self,
-        req: &'a HttpRequest,
         user: &'a str,
         pass: &'a str,
         remote_ip: SocketAddr,
     ) -> Result<String, StatusCode>
     {
-        // stringify the remote IP address.
-        let ip = remote_ip.ip();
-        let ip_string = if ip.is_loopback() {
-            // if it's loopback, take the value from the x-forwarded-for
-            // header, if present.
-            req.headers()
-                .get("x-forwarded-for")
-                .and_then(|s| s.to_str().ok())
-                .and_then(|s| s.split(',').next())
-                .map(|s| s.trim().to_owned())
-        } else {
-            Some(match ip {
-                std::net::IpAddr::V4(ip) => ip.to_string(),
-                std::net::IpAddr::V6(ip) => ip.to_string(),
-            })
-        };
-        let ip_ref = ip_string.as_ref().map(|s| s.as_str());
+        let ip_string = pam_rhost(remote_ip);
+        let ip_ref = ip_string.as_deref();
 
         // authenticate.
         let service = self.config.pam.service.as_str();
-        let pam_auth = self.pam_auth.clone();
-        match crate::cache::cached::pam_auth(pam_auth, service, user, pass, ip_ref).await {
+        let mut pam_auth = self.pam_auth.clone();
+        let backend = format!("pam.{}", service);
+        let (password, otp) = match split_otp(&self.config.pam, pass) {
+            Some(v) => v,
+            None => {
+                debug!("auth_pam({}): no one-time password for {}", service, user);
+                return Err(StatusCode::UNAUTHORIZED);
+            },
+        };
+        let extra: Vec<&str> = otp.into_iter().collect();
+        let pam = &self.config.pam;
+        let environment = pam.environment.unwrap_or(false);
+        let check = async move {
+            let mut env = match environment {
+                true => pam_auth.auth_env(service, user, password, &extra, ip_ref).await?,
+                false => {
+                    pam_auth.auth_conv(service, user, password, &extra, ip_ref).await?;
+                    Vec::new()
+                },
+            };
+            if pam.account.unwrap_or(false) {
+                pam_auth.account(service, user, ip_ref).await?;
+            }
+            if let Some(PamSession::Login) = pam.session {
+                // opened and closed right away.
+                let session = pam_auth.open_session(service, user, ip_ref).await?;
+                env.extend(session.env().iter().filter(|_| environment).cloned());
+            }
+            if !env.is_empty() {
+                let vars: Vec<_> = env.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                debug!("auth_pam({}): environment of {}: {}", service, user, vars.join(" "));
+                set_pam_env(user, &env);
+            }
+            Ok::<_, pam_sandboxed::PamError>(())
+        };
+        match crate::cache::cached::auth(&backend, user, pass, ip_ref, check).await {
             Ok(_) => Ok(user.to_string()),
             Err(_) => {
                 debug!(
@@ -111,6 +583,93 @@ impl Auth {
         }
     }
 
+    /// Change the PAM password of a user ([pam] change-password). The old
+    /// password counts for the login throttle. 403 if it is wrong or the
+    /// account may not log in, 400 if PAM does not take the new one.
+    #[cfg(feature = "pam")]
+    pub async fn change_password(
+        &self,
+        user: &str,
+        pass: &str,
+        new_pass: &str,
+        remote_ip: SocketAddr,
+    ) -> Result<(), StatusCode>
+    {
+        let ip = remote_ip.ip();
+        if !self.throttle.check(ip, user) {
+            debug!("change_password: too many failed logins for {} from {}", user, ip);
+            crate::authlog::throttled(ip, user);
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        let ip_string = pam_rhost(remote_ip);
+
+        let service = self.config.pam.service.as_str();
+        let mut pam_auth = self.pam_auth.clone();
+        let res = match split_otp(&self.config.pam, pass) {
+            Some((password, otp)) => {
+                let extra: Vec<&str> = otp.into_iter().collect();
+                let ip_ref = ip_string.as_deref();
+                pam_auth.change_password(service, user, password, &extra, new_pass, ip_ref).await
+            },
+            None => Err(pam_sandboxed::PamError::unknown()),
+        };
+        match res {
+            Ok(()) => {
+                info!("change_password({}): password of {} changed", service, user);
+                self.throttle.success(ip, user);
+                crate::cache::cached::invalidate(user);
+                crate::session::password_changed(user);
+                Ok(())
+            },
+            Err(e) if e.new_password_refused() => {
+                debug!("change_password({}): new password of {} refused", service, user);
+                Err(StatusCode::BAD_REQUEST)
+            },
+            Err(e) => {
+                debug!("change_password({}): {} ({:?}): {}", service, user, ip_string, e);
+                self.throttle.failure(ip, user);
+                crate::authlog::failure(ip, user);
+                Err(StatusCode::FORBIDDEN)
+            },
+        }
+    }
+
+    /// Check the password of a share link, in Basic authentication with
+    /// any name. For the throttle and the auth log, it is a login of
+    /// "share:<id>".
+    pub fn share_password(
+        &self,
+        req: &HttpRequest,
+        id: &str,
+        hash: &str,
+        remote_ip: SocketAddr,
+    ) -> Result<(), StatusCode>
+    {
+        let pass = match basic_credentials(req) {
+            Some((_, pass)) => pass,
+            None => return Err(StatusCode::UNAUTHORIZED),
+        };
+        let ip = remote_ip.ip();
+        let user = format!("share:{}", id);
+        if !self.throttle.check(ip, &user) {
+            debug!("share_password: too many failed logins for {} from {}", user, ip);
+            crate::authlog::throttled(ip, &user);
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        match tokio::task::block_in_place(|| crate::htpasswd::verify(&pass, hash)) {
+            true => {
+                self.throttle.success(ip, &user);
+                Ok(())
+            },
+            false => {
+                debug!("share_password: {}: wrong password from {}", user, ip);
+                self.throttle.failure(ip, &user);
+                crate::authlog::failure(ip, &user);
+                Err(StatusCode::UNAUTHORIZED)
+            },
+        }
+    }
+
     // authenticate user using htpasswd.
     async fn auth_htpasswd<'a>(
         &'a self,
@@ -126,31 +685,344 @@ impl Auth {
         };
 
         // Read the file and split it into a bunch of lines.
-        tokio::task::block_in_place(move || {
-            let data = match std::fs::read_to_string(file) {
-                Ok(data) => data,
-                Err(e) => {
-                    debug!("{}: {}", file, e);
-                    return Err(StatusCode::UNAUTHORIZED);
-                },
-            };
-            let lines = data
-                .split('\n')
-                .map(|s| s.trim())
-                .filter(|s| !s.starts_with("#") && !s.is_empty());
-
-            // Check each line for a match.
-            for line in lines {
-                let mut fields = line.split(':');
-                if let (Some(htuser), Some(htpass)) = (fields.next(), fields.next()) {
-                    if htuser == user && pwhash::unix::verify(pass, htpass) {
-                        return Ok(user.to_string());
+        let passwords = &self.config.passwords;
+        let check = async move {
+            tokio::task::block_in_place(move || {
+                let data = match std::fs::read_to_string(file) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        debug!("{}: {}", file, e);
+                        return Err(StatusCode::UNAUTHORIZED);
+                    },
+                };
+                let lines = data
+                    .split('\n')
+                    .map(|s| s.trim())
+                    .filter(|s| !s.starts_with("#") && !s.is_empty());
+
+                // Check each line for a match.
+                for line in lines {
+                    let mut fields = line.split(':');
+                    if let (Some(htuser), Some(htpass)) = (fields.next(), fields.next()) {
+                        if htuser == user && crate::htpasswd::verify(pass, htpass) {
+                            if let Some(hash) = crate::passhash::rehash(passwords, pass, htpass) {
+                                match hash.and_then(|hash| crate::htpasswd::update(file, user, &hash)) {
+                                    Ok(()) => info!("{}: {}: password rehashed", file, user),
+                                    Err(e) => warn!("{}: {}: rehash: {}", file, user, e),
+                                }
+                            }
+                            return Ok(());
+                        }
                     }
                 }
-            }
 
-            debug!("auth_htpasswd: authentication for {} failed", user);
-            Err(StatusCode::UNAUTHORIZED)
-        })
+                debug!("auth_htpasswd: authentication for {} failed", user);
+                Err(StatusCode::UNAUTHORIZED)
+            })
+        };
+        let backend = format!("htpasswd.{}", section);
+        crate::cache::cached::auth(&backend, user, pass, None, check).await?;
+        Ok(user.to_string())
+    }
+
+    // authenticate user using LDAP.
+    async fn auth_ldap<'a>(
+        &'a self,
+        user: &'a str,
+        pass: &'a str,
+        section: &'a str,
+    ) -> Result<String, StatusCode>
+    {
+        // Get the ldap.WHATEVER section from the config file.
+        let ldap = match self.config.ldap.get(section) {
+            Some(ldap) => ldap,
+            None => return Err(StatusCode::UNAUTHORIZED),
+        };
+
+        let backend = format!("ldap.{}", section);
+        let check = crate::ldap::auth(ldap, user, pass);
+        match crate::cache::cached::auth(&backend, user, pass, None, check).await {
+            Ok(_) => Ok(user.to_string()),
+            Err(e) => {
+                debug!("auth_ldap({}): authentication for {} failed: {}", section, user, e);
+                Err(StatusCode::UNAUTHORIZED)
+            },
+        }
+    }
+
+    // authenticate user using a SQL database.
+    async fn auth_sql<'a>(
+        &'a self,
+        user: &'a str,
+        pass: &'a str,
+        section: &'a str,
+    ) -> Result<String, StatusCode>
+    {
+        let sql = match self.config.sql.get(section) {
+            Some(sql) => sql,
+            None => return Err(StatusCode::UNAUTHORIZED),
+        };
+
+        let backend = format!("sql.{}", section);
+        let check = crate::sql::auth(sql, &self.config.passwords, user, pass);
+        match crate::cache::cached::auth(&backend, user, pass, None, check).await {
+            Ok(_) => Ok(user.to_string()),
+            Err(e) => {
+                debug!("auth_sql({}): authentication for {} failed: {}", section, user, e);
+                Err(StatusCode::UNAUTHORIZED)
+            },
+        }
+    }
+
+    // authenticate user by running a command.
+    async fn auth_exec<'a>(
+        &'a self,
+        user: &'a str,
+        pass: &'a str,
+        ip: std::net::IpAddr,
+    ) -> Result<String, StatusCode>
+    {
+        let exec = self.config.exec.as_ref().ok_or(StatusCode::UNAUTHORIZED)?;
+        let ip = ip.to_string();
+        let check = crate::execauth::auth(exec, user, pass, Some(&ip));
+        match crate::cache::cached::auth("exec", user, pass, Some(&ip), check).await {
+            Ok(_) => Ok(crate::execauth::user(user)),
+            Err(e) => {
+                debug!("auth_exec: authentication for {} failed: {}", user, e);
+                Err(StatusCode::UNAUTHORIZED)
+            },
+        }
+    }
+
+    // authenticate user using the built-in user database.
+    #[cfg(feature = "sqlite")]
+    async fn auth_sqlite<'a>(&'a self, user: &'a str, pass: &'a str) -> Result<String, StatusCode> {
+        let path = self.config.sqlite.path();
+        let check = async move {
+            let db = crate::userdb::open(path)?;
+            tokio::task::block_in_place(|| db.auth(user, pass, &self.config.passwords))
+        };
+        match crate::cache::cached::auth("sqlite", user, pass, None, check).await {
+            Ok(_) => Ok(user.to_string()),
+            Err(e) => {
+                debug!("auth_sqlite({}): authentication for {} failed: {}", path, user, e);
+                Err(StatusCode::UNAUTHORIZED)
+            },
+        }
+    }
+
+    // authenticate user using a bearer token.
+    async fn auth_jwt<'a>(&'a self, req: &'a HttpRequest, section: &'a str) -> Result<String, StatusCode> {
+        let bearer = match req.headers().typed_get::<Authorization<Bearer>>() {
+            Some(Authorization(bearer)) => bearer,
+            _ => return Err(StatusCode::UNAUTHORIZED),
+        };
+        let jwt_auth = match self.jwt_auth.get(section) {
+            Some(jwt_auth) => jwt_auth,
+            None => return Err(StatusCode::UNAUTHORIZED),
+        };
+        match jwt_auth.auth(bearer.token()).await {
+            Ok(user) => Ok(user),
+            Err(e) => {
+                debug!("auth_jwt({}): token rejected: {}", section, e);
+                Err(StatusCode::UNAUTHORIZED)
+            },
+        }
+    }
+
+    // authenticate user with an OpenID Connect session cookie, or a bearer token.
+    async fn auth_oidc<'a>(&'a self, req: &'a HttpRequest, section: &'a str) -> Result<String, StatusCode> {
+        let oidc_auth = match self.oidc_auth.get(section) {
+            Some(oidc_auth) => oidc_auth,
+            None => return Err(StatusCode::UNAUTHORIZED),
+        };
+        if let Some(user) = oidc_auth.session(req) {
+            return Ok(user);
+        }
+        let bearer = match req.headers().typed_get::<Authorization<Bearer>>() {
+            Some(Authorization(bearer)) => bearer,
+            _ => return Err(StatusCode::UNAUTHORIZED),
+        };
+        match oidc_auth.bearer(bearer.token()).await {
+            Ok(user) => Ok(user),
+            Err(e) => {
+                debug!("auth_oidc({}): token rejected: {}", section, e);
+                Err(StatusCode::UNAUTHORIZED)
+            },
+        }
+    }
+
+    /// For a browser that is not logged in at an "oidc" location: where
+    /// to redirect it to log in, and the cookie to set.
+    pub async fn login_redirect(&self, location: &Location, req: &HttpRequest) -> Option<(String, String)> {
+        let section = match self.auth_type(Some(location)) {
+            Some(AuthType::Oidc(section)) => section,
+            _ => return None,
+        };
+        let get = matches!(*req.method(), http::Method::GET | http::Method::HEAD);
+        let accept = req.headers().get(http::header::ACCEPT).and_then(|h| h.to_str().ok());
+        if !get || !accept.unwrap_or("").contains("text/html") || auth_scheme(req).is_some() {
+            return None;
+        }
+        let url = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        match self.oidc_auth.get(section)?.login(url).await {
+            Ok(redirect) => Some(redirect),
+            Err(e) => {
+                debug!("login_redirect({}): {}", section, e);
+                None
+            },
+        }
+    }
+
+    /// If this is the redirect-uri of an [oidc] section, finish the login:
+    /// the path to go on to, and the cookies to set.
+    pub async fn oidc_callback(
+        &self,
+        req: &HttpRequest,
+    ) -> Option<Result<(String, Vec<String>), StatusCode>>
+    {
+        if req.method() != http::Method::GET {
+            return None;
+        }
+        let mut sections = self.oidc_auth.iter();
+        let (section, oidc_auth) = sections.find(|(_, o)| o.callback_path() == req.uri().path())?;
+        Some(oidc_auth.callback(req).await.map_err(|e| {
+            debug!("oidc_callback({}): login failed: {}", section, e);
+            StatusCode::FORBIDDEN
+        }))
+    }
+
+    // authenticate user using kerberos.
+    #[cfg(feature = "kerberos")]
+    async fn auth_kerberos<'a>(&'a self, req: &'a HttpRequest) -> Result<String, StatusCode> {
+        let token = match negotiate_token(req).and_then(|t| base64::decode(t).ok()) {
+            Some(token) => token,
+            None => return Err(StatusCode::UNAUTHORIZED),
+        };
+        let cfg = &self.config.kerberos;
+        match tokio::task::block_in_place(move || crate::kerberos::accept(cfg, &token)) {
+            Ok(user) => Ok(user),
+            Err(e) => {
+                debug!("auth_kerberos: authentication failed: {}", e);
+                Err(StatusCode::UNAUTHORIZED)
+            },
+        }
+    }
+
+    // authenticate user using digest authentication.
+    async fn auth_htdigest<'a>(
+        &'a self,
+        req: &'a HttpRequest,
+        location: &'a Location,
+        section: &'a str,
+    ) -> Result<String, StatusCode>
+    {
+        use crate::digest::{self, Nonce};
+
+        let params = digest_params(req).ok_or(StatusCode::UNAUTHORIZED)?;
+        let file = match self.config.htdigest.get(section) {
+            Some(section) => section.htdigest.as_str(),
+            None => return Err(StatusCode::UNAUTHORIZED),
+        };
+        let get = |k: &str| params.get(k).map(|s| s.as_str()).unwrap_or("");
+        let user = get("username");
+        let realm = self.realm(Some(location));
+
+        // Basic sanity checks.
+        if get("realm") != realm {
+            debug!("auth_htdigest: {}: realm mismatch", user);
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        if !get("algorithm").is_empty() && !get("algorithm").eq_ignore_ascii_case("md5") {
+            debug!("auth_htdigest: {}: unsupported algorithm {}", user, get("algorithm"));
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        let uri = req.uri();
+        let req_uri = uri.path_and_query().map(|p| p.as_str()).unwrap_or(uri.path());
+        if get("uri") != req_uri {
+            debug!("auth_htdigest: {}: uri mismatch", user);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        if digest::check_nonce(get("nonce"), self.nonce_lifetime(section)) != Nonce::Valid {
+            debug!("auth_htdigest: {}: invalid or stale nonce", user);
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+
+        // Look up the user, and check the response.
+        let ha1 = tokio::task::block_in_place(|| digest::lookup_ha1(file, user, realm));
+        let ha1 = match ha1 {
+            Ok(Some(ha1)) => ha1,
+            Ok(None) => {
+                debug!("auth_htdigest: {}: unknown user", user);
+                return Err(StatusCode::UNAUTHORIZED);
+            },
+            Err(e) => {
+                debug!("{}: {}", file, e);
+                return Err(StatusCode::UNAUTHORIZED);
+            },
+        };
+        match digest::response(&ha1, req.method().as_str(), &params) {
+            Some(resp) if crate::htpasswd::consteq(resp.as_bytes(), get("response").as_bytes()) => {
+                Ok(user.to_string())
+            },
+            _ => {
+                debug!("auth_htdigest: authentication for {} failed", user);
+                Err(StatusCode::UNAUTHORIZED)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth() {
+        // "bob:pass", "jürgen:pässwörd" in UTF-8 and in ISO-8859-1.
+        assert_eq!(decode_basic("Ym9iOnBhc3M="), Some(("bob".into(), "pass".into())));
+        let utf8 = base64::encode("jürgen:pässwörd");
+        assert_eq!(decode_basic(&utf8), Some(("jürgen".into(), "pässwörd".into())));
+        let latin1 = base64::encode(b"j\xfcrgen:p\xe4ssw\xf6rd");
+        assert_eq!(decode_basic(&latin1), Some(("jürgen".into(), "pässwörd".into())));
+        assert_eq!(decode_basic(&base64::encode("bob:a:b")), Some(("bob".into(), "a:b".into())));
+        assert_eq!(decode_basic(&base64::encode("bob")), None);
+        assert_eq!(decode_basic("not base64!"), None);
+    }
+
+    // X-Forwarded-For from a peer that is not in trusted-proxies is not
+    // the client address; so it does not get around the throttle.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_throttle_forwarded() {
+        let dir = std::env::temp_dir().join(format!("auth-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let htpasswd = dir.join("htpasswd");
+        std::fs::write(&htpasswd, "bob:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n").unwrap();
+        std::fs::write(dir.join("a.txt"), "a\n").unwrap();
+        let toml = format!(
+            "[server]\n[accounts]\nauth-type = \"htpasswd.main\"\n[htpasswd.main]\nhtpasswd = {:?}\n\
+             [throttle]\nmax-failures = 3\nbackoff = 60\n\
+             [[location]]\nroute = [ \"/*path\" ]\nhandler = \"filesystem\"\ndirectory = {:?}\n\
+             auth = \"true\"\n",
+            htpasswd.to_str().unwrap(),
+            dir.to_str().unwrap()
+        );
+        let server = crate::builder::Builder::from_toml(&toml).build().unwrap();
+        let login = |pass: &str, n: u32| {
+            http::Request::get("/a.txt")
+                .header("Authorization", format!("Basic {}", base64::encode(format!("bob:{}", pass))))
+                .header("X-Forwarded-For", format!("192.0.2.{}", n))
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+        let peer: SocketAddr = "127.0.0.1:4711".parse().unwrap();
+        for n in 0..3 {
+            assert_eq!(server.route(login("wrong", n), peer).await.unwrap().status(), 401);
+        }
+        assert_eq!(server.route(login("wrong", 3), peer).await.unwrap().status(), 429);
+        assert_eq!(server.route(login("secret", 4), peer).await.unwrap().status(), 429);
+        let other: SocketAddr = "127.0.0.2:4711".parse().unwrap();
+        assert_eq!(server.route(login("secret", 0), other).await.unwrap().status(), 200);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,96 @@
+//
+// Authentication failure log.
+//
+// A separate log of failed logins, one line per failure, in a fixed
+// format that is easy to match with fail2ban or crowdsec:
+//
+// 2021-06-01T12:00:00Z webdav-server[1234]: authentication failure; rhost=192.0.2.1 user=bob
+// 2021-06-01T12:00:01Z webdav-server[1234]: too many authentication failures; rhost=192.0.2.1 user=bob
+//
+// The log file is reopened on SIGUSR1, for logrotate.
+//
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+enum Target {
+    Stderr,
+    File(String, File),
+}
+
+lazy_static! {
+    static ref AUTHLOG: Mutex<Option<Target>> = Mutex::new(None);
+}
+
+fn open_file(path: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+/// Open the log. Must be called before dropping privileges.
+pub fn open(path: &str) -> io::Result<()> {
+    let target = match path {
+        "stderr" => Target::Stderr,
+        _ => Target::File(path.to_string(), open_file(path)?),
+    };
+    *AUTHLOG.lock().unwrap() = Some(target);
+    Ok(())
+}
+
+/// Close and reopen the log file. If that fails, the old file is kept.
+pub fn reopen() -> io::Result<()> {
+    if let Some(Target::File(ref path, ref mut file)) = *AUTHLOG.lock().unwrap() {
+        *file = open_file(path)?;
+    }
+    Ok(())
+}
+
+// Usernames come straight from the client, so make sure they
+// cannot break up the line or add fake fields.
+fn sanitize(user: &str) -> String {
+    let user: String = user
+        .chars()
+        .map(|c| if c.is_whitespace() || c.is_control() { '_' } else { c })
+        .collect();
+    if user.is_empty() {
+        return "-".to_string();
+    }
+    user
+}
+
+fn log(msg: &str, ip: IpAddr, user: &str) {
+    let mut authlog = AUTHLOG.lock().unwrap();
+    let target = match authlog.as_mut() {
+        Some(target) => target,
+        None => return,
+    };
+    let line = format!(
+        "{} {}[{}]: {}; rhost={} user={}\n",
+        time::now_utc().rfc3339(),
+        crate::PROGNAME,
+        std::process::id(),
+        msg,
+        ip,
+        sanitize(user)
+    );
+    let _ = match target {
+        Target::Stderr => io::stderr().write_all(line.as_bytes()),
+        Target::File(_, file) => file.write_all(line.as_bytes()),
+    };
+}
+
+/// Log an authentication failure.
+pub fn failure(ip: IpAddr, user: &str) {
+    log("authentication failure", ip, user);
+}
+
+/// Log a login attempt that was refused because of too many failures.
+pub fn throttled(ip: IpAddr, user: &str) {
+    log("too many authentication failures", ip, user);
+}
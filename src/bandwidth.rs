@@ -0,0 +1,259 @@
+//
+// Bandwidth limits: upload-rate and download-rate.
+//
+// A RateFs slows down reading and writing the files in it. The rate is a
+// token bucket that is shared by all requests of a user (or of a client
+// address, if the user is not authenticated), so that opening more
+// connections does not get anyone more bandwidth.
+//
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::future::FutureExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+lazy_static::lazy_static! {
+    static ref BUCKETS: Mutex<HashMap<(Direction, String, u64), Weak<Bucket>>> = Mutex::new(HashMap::new());
+}
+
+/// A token bucket. It holds at most one second worth of bytes.
+#[derive(Debug)]
+pub struct Bucket {
+    rate:  u64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        Bucket {
+            rate,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    /// Take "n" bytes out of the bucket. Returns how long to wait
+    /// before they may be sent.
+    fn take(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (ref mut tokens, ref mut last) = *state;
+        let now = Instant::now();
+        let rate = self.rate as f64;
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+        *last = now;
+        *tokens -= n as f64;
+        if *tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-*tokens / rate)
+        }
+    }
+
+    async fn wait(&self, n: usize) {
+        let delay = self.take(n);
+        if delay > Duration::from_secs(0) {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// The bucket of "who" (a username or an address) for this direction
+/// and rate. Buckets that are not in use by any request are dropped.
+pub fn bucket(direction: Direction, who: &str, rate: u64) -> Arc<Bucket> {
+    let mut buckets = BUCKETS.lock().unwrap();
+    let key = (direction, who.to_string(), rate);
+    if let Some(bucket) = buckets.get(&key).and_then(Weak::upgrade) {
+        return bucket;
+    }
+    buckets.retain(|_, b| b.strong_count() > 0);
+    let bucket = Arc::new(Bucket::new(rate));
+    buckets.insert(key, Arc::downgrade(&bucket));
+    bucket
+}
+
+/// A filesystem with rate limited file contents.
+#[derive(Clone)]
+pub struct RateFs {
+    fs:       Box<dyn DavFileSystem>,
+    upload:   Option<Arc<Bucket>>,
+    download: Option<Arc<Bucket>>,
+}
+
+impl RateFs {
+    pub fn new(
+        fs: Box<dyn DavFileSystem>,
+        upload: Option<Arc<Bucket>>,
+        download: Option<Arc<Bucket>>,
+    ) -> Box<RateFs>
+    {
+        Box::new(RateFs { fs, upload, download })
+    }
+}
+
+impl DavFileSystem for RateFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let file = self.fs.open(path, options).await?;
+            Ok(Box::new(RateFile {
+                file,
+                upload: self.upload.clone(),
+                download: self.download.clone(),
+            }) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_dir(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_file(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.rename(from, to)
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.copy(from, to)
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        self.fs.patch_props(path, patch)
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[derive(Debug)]
+struct RateFile {
+    file:     Box<dyn DavFile>,
+    upload:   Option<Arc<Bucket>>,
+    download: Option<Arc<Bucket>>,
+}
+
+impl DavFile for RateFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.file.metadata()
+    }
+
+    fn write_buf<'a>(&'a mut self, buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        async move {
+            if let Some(ref bucket) = self.upload {
+                bucket.wait(buf.remaining()).await;
+            }
+            self.file.write_buf(buf).await
+        }
+        .boxed()
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        async move {
+            if let Some(ref bucket) = self.upload {
+                bucket.wait(buf.len()).await;
+            }
+            self.file.write_bytes(buf).await
+        }
+        .boxed()
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        async move {
+            let data = self.file.read_bytes(count).await?;
+            if let Some(ref bucket) = self.download {
+                bucket.wait(data.len()).await;
+            }
+            Ok(data)
+        }
+        .boxed()
+    }
+
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> FsFuture<'a, u64> {
+        self.file.seek(pos)
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let tb = Bucket::new(1000);
+        assert_eq!(tb.take(1000), Duration::from_secs(0));
+        let delay = tb.take(500);
+        assert!(delay > Duration::from_millis(450) && delay <= Duration::from_millis(500));
+        // the debt adds up.
+        assert!(tb.take(500) > Duration::from_millis(950));
+
+        let a = bucket(Direction::Upload, "alice", 1000);
+        let b = bucket(Direction::Upload, "alice", 1000);
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &bucket(Direction::Download, "alice", 1000)));
+    }
+}
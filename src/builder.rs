@@ -0,0 +1,196 @@
+//
+// A Server for a program that embeds it, configured with the settings of
+// the config file: read from a file or a string, or set one by one.
+//
+// The listeners, TLS, ACME, the admin listener, and dropping privileges
+// are up to the program; the rest is as in the binary.
+//
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use toml::value::{Table, Value};
+
+use crate::middleware::{Middleware, Registry};
+use crate::server::Server;
+use crate::{accesslog, auditlog, auth, authlog, config};
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// The table of a section, "htpasswd.main" is [htpasswd.main].
+fn section<'a>(table: &'a mut Table, name: &str) -> io::Result<&'a mut Table> {
+    let mut table = table;
+    for name in name.split('.') {
+        table = table
+            .entry(name.to_string())
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| invalid_data(format!("[{}]: not a section", name)))?;
+    }
+    Ok(table)
+}
+
+/// Builds a [`Server`].
+///
+/// ```no_run
+/// # fn build() -> std::io::Result<webdav_server::Server> {
+/// use webdav_server::{Builder, LocationBuilder};
+///
+/// Builder::from_file("/etc/webdav-server.toml")
+///     .set("server", "identification", "files")
+///     .location(LocationBuilder::new("/public/*path", "filesystem", "/srv/public").set("auth", "false"))
+///     .build()
+/// # }
+/// ```
+pub struct Builder {
+    name:       String,
+    table:      io::Result<Table>,
+    middleware: Registry,
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+impl Builder {
+    /// An empty configuration: no locations, and the defaults for the rest.
+    pub fn new() -> Builder {
+        // [server] is the one section that must be there.
+        let mut table = Table::new();
+        table.insert("server".to_string(), Value::Table(Table::new()));
+        Builder {
+            name:       "config".to_string(),
+            table:      Ok(table),
+            middleware: Registry::default(),
+        }
+    }
+
+    /// The configuration in a file (and its includes), as the binary reads it.
+    pub fn from_file(path: impl AsRef<Path>) -> Builder {
+        let table = config::read_toml(&path).and_then(|value| {
+            match value {
+                Value::Table(table) => Ok(table),
+                _ => Err(invalid_data("not a table".to_string())),
+            }
+        });
+        Builder {
+            name: path.as_ref().display().to_string(),
+            table,
+            middleware: Registry::default(),
+        }
+    }
+
+    /// The configuration in a string, in the format of the config file.
+    pub fn from_toml(toml: &str) -> Builder {
+        Builder {
+            name:       "config".to_string(),
+            table:      toml::from_str(toml).map_err(|e| invalid_data(e.to_string())),
+            middleware: Registry::default(),
+        }
+    }
+
+    /// Set a key in a section ("server", "accounts", "htpasswd.main", ...).
+    pub fn set(mut self, section: &str, key: &str, value: impl Into<Value>) -> Builder {
+        if let Ok(ref mut table) = self.table {
+            match self::section(table, section) {
+                Ok(table) => {
+                    table.insert(key.to_string(), value.into());
+                },
+                Err(e) => self.table = Err(e),
+            }
+        }
+        self
+    }
+
+    /// Add a location, after the ones that are there.
+    pub fn location(mut self, location: LocationBuilder) -> Builder {
+        if let Ok(ref mut table) = self.table {
+            let locations = table
+                .entry("location".to_string())
+                .or_insert_with(|| Value::Array(Vec::new()));
+            match locations.as_array_mut() {
+                Some(locations) => locations.push(Value::Table(location.table)),
+                None => self.table = Err(invalid_data("location: not an array".to_string())),
+            }
+        }
+        self
+    }
+
+    /// Register a middleware under a name. The names in `middleware = [ .. ]`
+    /// in the configuration select and order them.
+    pub fn middleware(mut self, name: &str, middleware: impl Middleware + 'static) -> Builder {
+        self.middleware.register(name, Arc::new(middleware));
+        self
+    }
+
+    /// Check the configuration, and build the server. The access log, the
+    /// audit log and the log of failed logins are opened here, if they are
+    /// set.
+    pub fn build(self) -> io::Result<Server> {
+        let name = self.name;
+        let err = |e: String| invalid_data(format!("{}: {}", name, e));
+        let mut config = config::from_value(Value::Table(self.table?)).map_err(|e| err(e.to_string()))?;
+        config::validate(&config).map_err(err)?;
+        config::build_routes(&name, &mut config)?;
+        let config = Arc::new(config);
+
+        let auth = auth::Auth::new(config.clone())?;
+        if let Some(ref path) = config.log.auth_failures {
+            authlog::open(path)?;
+        }
+        if let Some(ref path) = config.log.access {
+            accesslog::open(path, config.log.access_format.unwrap_or(config::AccessFormat::Json))?;
+        }
+        if let Some(ref path) = config.log.audit {
+            auditlog::open(path, config.log.audit_chain)?;
+        }
+        Server::new(config, auth, None).with_middleware(self.middleware).map_err(|e| err(e.to_string()))
+    }
+}
+
+/// A `[[location]]`, for [`Builder::location`].
+pub struct LocationBuilder {
+    table: Table,
+}
+
+impl LocationBuilder {
+    /// A location with a route, and the handler and directory for it.
+    pub fn new(route: &str, handler: &str, directory: &str) -> LocationBuilder {
+        LocationBuilder { table: Table::new() }
+            .set("route", vec![route])
+            .set("handler", handler)
+            .set("directory", directory)
+    }
+
+    /// Set a key of the location ("auth", "methods", "setuid", ...).
+    pub fn set(mut self, key: &str, value: impl Into<Value>) -> LocationBuilder {
+        self.table.insert(key.to_string(), value.into());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let builder = Builder::new()
+            .set("htpasswd.main", "htpasswd", "/etc/htpasswd")
+            .set("server", "identification", "files")
+            .location(LocationBuilder::new("/dav/*path", "filesystem", "/srv").set("auth", "false"));
+        let table = builder.table.unwrap();
+        assert_eq!(table["htpasswd"]["main"]["htpasswd"].as_str(), Some("/etc/htpasswd"));
+        assert_eq!(table["location"][0]["route"][0].as_str(), Some("/dav/*path"));
+        let config = config::from_value(Value::Table(table)).unwrap();
+        assert_eq!(config.location[0].directory, "/srv");
+        assert_eq!(config.server.identification.as_deref(), Some("files"));
+
+        let builder = Builder::new().set("server", "x", 1).set("server.x", "y", 2);
+        assert!(builder.table.is_err());
+    }
+}
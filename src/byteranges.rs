@@ -0,0 +1,232 @@
+//
+// GET with more than one range in the Range header, answered with a
+// multipart/byteranges body (RFC 7233). Ranges that overlap or touch are
+// merged first; if that leaves one range, the handler does it as usual.
+//
+// With If-Match, If-None-Match, If-Modified-Since, If-Unmodified-Since
+// or If, the request is left to the handler as well.
+//
+use std::io::{self, SeekFrom};
+
+use futures::TryStreamExt;
+use headers::HeaderMapExt;
+use http::StatusCode;
+use hyper::body::Bytes;
+use ring::rand::SecureRandom;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavFile, DavFileSystem, FsResult, OpenOptions};
+
+const CHUNK: u64 = 65536;
+const MAX_RANGES: usize = 64;
+
+/// Is there more than one range in the Range header.
+pub fn is_multi(headers: &http::HeaderMap) -> bool {
+    headers
+        .get(http::header::RANGE)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.contains(','))
+        .unwrap_or(false)
+}
+
+// The ranges in "bytes=0-99,200-" as (start, end), end included, sorted
+// and merged. Ranges past the end of the file are left out. None if the
+// header is not valid, and should be ignored.
+fn parse(value: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+    let (unit, specs) = value.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (first, last) = spec.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        let range = match (first.is_empty(), last.is_empty()) {
+            (true, true) => return None,
+            (true, false) => {
+                let n = last.parse::<u64>().ok()?;
+                (len.saturating_sub(n), len.saturating_sub(1))
+            },
+            (false, _) => {
+                let start = first.parse::<u64>().ok()?;
+                let end = match last.is_empty() {
+                    true => u64::MAX,
+                    false => last.parse::<u64>().ok()?,
+                };
+                if end < start {
+                    return None;
+                }
+                (start, end.min(len.saturating_sub(1)))
+            },
+        };
+        if range.0 < len && range.0 <= range.1 {
+            ranges.push(range);
+        }
+    }
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    Some(merged)
+}
+
+// The body: the part headers, and the ranges of the file.
+enum Segment {
+    Text(Bytes),
+    Data(u64, u64),
+}
+
+fn boundary() -> String {
+    let mut b = [0u8; 12];
+    ring::rand::SystemRandom::new().fill(&mut b).unwrap();
+    b.iter().map(|c| format!("{:02x}", c)).collect()
+}
+
+/// Answer a GET or HEAD with more than one range. If it is not one
+/// (anymore, after checking If-Range and merging the ranges), the request
+/// is given back, and the Range header might be changed. `content_type`
+/// is that of the file, for the parts.
+pub async fn handle(
+    mut req: http::Request<hyper::Body>,
+    fs: Box<dyn DavFileSystem>,
+    path: &DavPath,
+    content_type: &str,
+) -> Result<http::Response<hyper::Body>, http::Request<hyper::Body>>
+{
+    let conditional = ["If-Match", "If-None-Match", "If-Modified-Since", "If-Unmodified-Since", "If"];
+    if conditional.iter().any(|h| req.headers().contains_key(*h)) {
+        return Err(req);
+    }
+    let file = fs.open(path, OpenOptions { read: true, ..OpenOptions::default() }).await;
+    let (file, meta) = match file {
+        Ok(mut file) => {
+            match file.metadata().await {
+                Ok(meta) if meta.is_file() => (file, meta),
+                _ => return Err(req),
+            }
+        },
+        Err(_) => return Err(req),
+    };
+    let len = meta.len();
+    let etag = meta.etag().and_then(|t| format!("\"{}\"", t).parse::<headers::ETag>().ok());
+    let modified = meta.modified().ok().map(headers::LastModified::from);
+
+    // Whole file if If-Range does not match, or the header is invalid.
+    let if_range = req.headers().typed_get::<headers::IfRange>();
+    let unchanged = if_range.map(|h| !h.is_modified(etag.as_ref(), modified.as_ref())).unwrap_or(true);
+    let value = req.headers().get(http::header::RANGE).and_then(|h| h.to_str().ok());
+    let ranges = match value.and_then(|v| parse(v, len)) {
+        Some(ranges) if unchanged && ranges.len() <= MAX_RANGES => ranges,
+        _ => {
+            req.headers_mut().remove(http::header::RANGE);
+            return Err(req);
+        },
+    };
+    match ranges.len() {
+        0 => {
+            let mut resp = http::Response::new(hyper::Body::empty());
+            *resp.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            let range = format!("bytes */{}", len);
+            resp.headers_mut().insert("Content-Range", range.parse().unwrap());
+            return Ok(resp);
+        },
+        1 => {
+            let range = format!("bytes={}-{}", ranges[0].0, ranges[0].1);
+            req.headers_mut().insert(http::header::RANGE, range.parse().unwrap());
+            return Err(req);
+        },
+        _ => {},
+    }
+
+    let boundary = boundary();
+    let mut segments = Vec::new();
+    for (n, &(start, end)) in ranges.iter().enumerate() {
+        let text = format!(
+            "{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            if n == 0 { "" } else { "\r\n" },
+            boundary,
+            content_type,
+            start,
+            end,
+            len
+        );
+        segments.push(Segment::Text(text.into()));
+        segments.push(Segment::Data(start, end - start + 1));
+    }
+    segments.push(Segment::Text(format!("\r\n--{}--\r\n", boundary).into()));
+    let length: u64 = segments
+        .iter()
+        .map(|s| {
+            match s {
+                Segment::Text(t) => t.len() as u64,
+                Segment::Data(_, count) => *count,
+            }
+        })
+        .sum();
+
+    let mut resp = http::Response::new(hyper::Body::empty());
+    *resp.status_mut() = StatusCode::PARTIAL_CONTENT;
+    let headers = resp.headers_mut();
+    let ctype = format!("multipart/byteranges; boundary={}", boundary);
+    headers.insert("Content-Type", ctype.parse().unwrap());
+    headers.insert("Content-Length", length.into());
+    headers.insert("Accept-Ranges", "bytes".parse().unwrap());
+    if let Some(etag) = etag {
+        headers.typed_insert(etag);
+    }
+    if let Some(modified) = modified {
+        headers.typed_insert(modified);
+    }
+    if req.method() == http::Method::HEAD {
+        return Ok(resp);
+    }
+    *resp.body_mut() = body(file, segments);
+    Ok(resp)
+}
+
+fn body(file: Box<dyn DavFile>, segments: Vec<Segment>) -> hyper::Body {
+    // The state: the file, the segments, and what is left of a range.
+    let state = (file, segments.into_iter(), 0u64);
+    let stream = futures::stream::try_unfold(state, |(mut file, mut segments, mut left)| {
+        async move {
+            while left == 0 {
+                match segments.next() {
+                    Some(Segment::Text(text)) => return Ok(Some((text, (file, segments, 0)))),
+                    Some(Segment::Data(start, count)) => {
+                        file.seek(SeekFrom::Start(start)).await?;
+                        left = count;
+                    },
+                    None => return FsResult::Ok(None),
+                }
+            }
+            let buf = file.read_bytes(left.min(CHUNK) as usize).await?;
+            if buf.is_empty() {
+                // The file got shorter.
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            left -= buf.len() as u64;
+            Ok(Some((buf, (file, segments, left))))
+        }
+    });
+    hyper::Body::wrap_stream(stream.map_err(io::Error::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("bytes=0-2,5-6,-2", 16), Some(vec![(0, 2), (5, 6), (14, 15)]));
+        assert_eq!(parse("bytes=0-2, 1-6, 8-", 10), Some(vec![(0, 6), (8, 9)]));
+        assert_eq!(parse("bytes=0-2,3-4", 10), Some(vec![(0, 4)]));
+        assert_eq!(parse("bytes=-20,50-60", 10), Some(vec![(0, 9)]));
+        assert_eq!(parse("bytes=20-30,40-", 10), Some(vec![]));
+        assert_eq!(parse("bytes=5-2,7-8", 10), None);
+        assert_eq!(parse("items=0-1,3-4", 10), None);
+        assert_eq!(parse("bytes=a-1", 10), None);
+    }
+}
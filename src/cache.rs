@@ -44,6 +44,14 @@ impl<K: Hash + Eq + Clone, V> Cache<K, V> {
         self
     }
 
+    /// Change the limits of a cache that is already in use.
+    pub fn set_limits(&self, maxage: Duration, maxsize: usize) {
+        let mut m = self.intern.lock().unwrap();
+        m.maxage = maxage;
+        m.maxsize = maxsize;
+        self.expire(&mut m);
+    }
+
     fn expire(&self, m: &mut Intern<K, V>) {
         let mut n = m.fifo.len();
         if m.maxsize > 0 && n >= m.maxsize {
@@ -60,8 +68,8 @@ impl<K: Hash + Eq + Clone, V> Cache<K, V> {
             }
         }
         for x in n..m.fifo.len() {
-            let &(_, ref key) = m.fifo.get(x).unwrap();
-            m.map.remove(&key);
+            let (_, key) = m.fifo.get(x).unwrap();
+            m.map.remove(key);
         }
         m.fifo.truncate(n);
     }
@@ -77,10 +85,10 @@ impl<K: Hash + Eq + Clone, V> Cache<K, V> {
     }
 
     // see https://doc.rust-lang.org/book/first-edition/borrow-and-asref.html
-    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<Arc<V>>
+    pub fn get<Q>(&self, key: &Q) -> Option<Arc<V>>
     where
         K: Borrow<Q>,
-        Q: Hash + Eq,
+        Q: Hash + Eq + ?Sized,
     {
         let mut m = self.intern.lock().unwrap();
         self.expire(&mut *m);
@@ -89,97 +97,242 @@ impl<K: Hash + Eq + Clone, V> Cache<K, V> {
         }
         None
     }
+
+    /// Remove the entries for which "f" is true. Returns how many.
+    pub fn remove_if<F: Fn(&K, &V) -> bool>(&self, f: F) -> usize {
+        let mut m = self.intern.lock().unwrap();
+        let before = m.map.len();
+        m.map.retain(|k, v| !f(k, v));
+        let Intern { ref map, ref mut fifo, .. } = *m;
+        fifo.retain(|(_, k)| map.contains_key(k));
+        before - m.map.len()
+    }
 }
 
 pub(crate) mod cached {
     //
-    // Cached versions of Unix account lookup and Pam auth.
+    // Cached versions of Unix account lookup and password authentication.
     //
+    use std::future::Future;
     use std::io;
+    use std::sync::atomic::{AtomicU64, Ordering};
     use std::sync::{Arc, Mutex};
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
+
+    use sha2::{Digest, Sha256};
+    use tracing::Instrument;
 
     use crate::cache;
     use crate::unixuser::{self, User};
     use lazy_static::lazy_static;
 
     struct Timeouts {
-        pwcache:  Duration,
-        pamcache: Duration,
+        pwcache:       Duration,
+        pwcache_neg:   Duration,
+        pwcache_max:   usize,
+        authcache:     Duration,
+        authcache_max: usize,
     }
 
     lazy_static! {
         static ref TIMEOUTS: Mutex<Timeouts> = Mutex::new(Timeouts {
-            pwcache:  Duration::new(120, 0),
-            pamcache: Duration::new(120, 0),
+            pwcache:       Duration::new(120, 0),
+            pwcache_neg:   Duration::new(30, 0),
+            pwcache_max:   4096,
+            authcache:     Duration::new(120, 0),
+            authcache_max: 1024,
         });
         static ref PWCACHE: cache::Cache<String, unixuser::User> = new_pwcache();
-        static ref PAMCACHE: cache::Cache<u64, String> = new_pamcache();
+        // the users that do not exist.
+        static ref NEGCACHE: cache::Cache<String, ()> = new_negcache();
+        static ref AUTHCACHE: cache::Cache<[u8; 32], String> = new_authcache();
     }
 
+    static AUTHCACHE_HITS: AtomicU64 = AtomicU64::new(0);
+    static AUTHCACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+    static PWCACHE_HITS: AtomicU64 = AtomicU64::new(0);
+    static PWCACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
     fn new_pwcache() -> cache::Cache<String, unixuser::User> {
         let timeouts = TIMEOUTS.lock().unwrap();
-        cache::Cache::new().maxage(timeouts.pwcache)
+        cache::Cache::new()
+            .maxage(timeouts.pwcache)
+            .maxsize(timeouts.pwcache_max)
+    }
+
+    fn new_negcache() -> cache::Cache<String, ()> {
+        let timeouts = TIMEOUTS.lock().unwrap();
+        cache::Cache::new()
+            .maxage(timeouts.pwcache_neg)
+            .maxsize(timeouts.pwcache_max)
     }
 
-    fn new_pamcache() -> cache::Cache<u64, String> {
+    fn new_authcache() -> cache::Cache<[u8; 32], String> {
         let timeouts = TIMEOUTS.lock().unwrap();
-        cache::Cache::new().maxage(timeouts.pamcache)
+        cache::Cache::new()
+            .maxage(timeouts.authcache)
+            .maxsize(timeouts.authcache_max)
     }
 
-    pub(crate) fn set_pwcache_timeout(secs: usize) {
+    /// The [unix] cache settings. Also for a cache that is in use, after a reload.
+    pub(crate) fn set_pwcache(unix: &crate::config::Unix) {
         let mut timeouts = TIMEOUTS.lock().unwrap();
-        timeouts.pwcache = Duration::new(secs as u64, 0);
+        if let Some(secs) = unix.cache_timeout {
+            timeouts.pwcache = Duration::new(secs as u64, 0);
+        }
+        if let Some(secs) = unix.negative_cache_timeout {
+            timeouts.pwcache_neg = Duration::new(secs as u64, 0);
+        }
+        if let Some(size) = unix.cache_size {
+            timeouts.pwcache_max = size;
+        }
+        let (pos, neg, max) = (timeouts.pwcache, timeouts.pwcache_neg, timeouts.pwcache_max);
+        drop(timeouts);
+        PWCACHE.set_limits(pos, max);
+        NEGCACHE.set_limits(neg, max);
+    }
+
+    pub(crate) fn set_authcache_timeout(secs: usize) {
+        let mut timeouts = TIMEOUTS.lock().unwrap();
+        timeouts.authcache = Duration::new(secs as u64, 0);
     }
 
-    #[cfg(feature = "pam")]
-    pub(crate) fn set_pamcache_timeout(secs: usize) {
+    pub(crate) fn set_authcache_size(size: usize) {
         let mut timeouts = TIMEOUTS.lock().unwrap();
-        timeouts.pamcache = Duration::new(secs as u64, 0);
+        timeouts.authcache_max = size;
+    }
+
+    /// Authentication cache hits and misses.
+    pub fn authcache_stats() -> (u64, u64) {
+        (
+            AUTHCACHE_HITS.load(Ordering::Relaxed),
+            AUTHCACHE_MISSES.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Unix user cache hits and misses.
+    pub fn pwcache_stats() -> (u64, u64) {
+        (
+            PWCACHE_HITS.load(Ordering::Relaxed),
+            PWCACHE_MISSES.load(Ordering::Relaxed),
+        )
+    }
+
+    // The cache key is a hash of everything that went into the authentication
+    // request, so that we never keep passwords in memory.
+    fn authcache_key(backend: &str, user: &str, pass: &str, remip: Option<&str>) -> [u8; 32] {
+        let mut h = Sha256::new();
+        for field in &[backend, user, pass, remip.unwrap_or("")] {
+            h.update((field.len() as u64).to_le_bytes());
+            h.update(field.as_bytes());
+        }
+        h.finalize().into()
     }
 
-    #[cfg(feature = "pam")]
-    pub async fn pam_auth<'a>(
-        pam_auth: pam_sandboxed::PamAuth,
-        service: &'a str,
+    /// Run a password check, with the result cached on success.
+    ///
+    /// `backend` should be unique for the auth-type and its settings.
+    pub async fn auth<'a, F, E>(
+        backend: &'a str,
         user: &'a str,
         pass: &'a str,
         remip: Option<&'a str>,
-    ) -> Result<(), pam_sandboxed::PamError>
+        check: F,
+    ) -> Result<(), E>
+    where
+        F: Future<Output = Result<(), E>>,
     {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-
-        let mut s = DefaultHasher::new();
-        service.hash(&mut s);
-        user.hash(&mut s);
-        pass.hash(&mut s);
-        remip.as_ref().hash(&mut s);
-        let key = s.finish();
+        let enabled = {
+            let timeouts = TIMEOUTS.lock().unwrap();
+            timeouts.authcache.as_secs() > 0 && timeouts.authcache_max > 0
+        };
+        let span = tracing::info_span!("auth.check", backend);
+        if !enabled {
+            let start = Instant::now();
+            let res = check.instrument(span).await;
+            crate::metrics::auth(backend, start.elapsed());
+            return res;
+        }
 
-        if let Some(cache_user) = PAMCACHE.get(&key) {
+        let key = authcache_key(backend, user, pass, remip);
+        if let Some(cache_user) = AUTHCACHE.get(&key) {
             if user == cache_user.as_str() {
+                AUTHCACHE_HITS.fetch_add(1, Ordering::Relaxed);
                 return Ok(());
             }
         }
+        let misses = AUTHCACHE_MISSES.fetch_add(1, Ordering::Relaxed) + 1;
+        let hits = AUTHCACHE_HITS.load(Ordering::Relaxed);
+        debug!(
+            "auth cache: miss for {} ({} hits, {} misses, hit rate {:.1}%)",
+            user,
+            hits,
+            misses,
+            (hits as f64 * 100.0) / ((hits + misses) as f64)
+        );
 
-        let mut pam_auth = pam_auth;
-        match pam_auth.auth(&service, &user, &pass, remip).await {
-            Err(e) => Err(e),
-            Ok(()) => {
-                PAMCACHE.insert(key, user.to_owned());
-                Ok(())
-            },
-        }
+        let start = Instant::now();
+        let res = check.instrument(span).await;
+        crate::metrics::auth(backend, start.elapsed());
+        res?;
+        AUTHCACHE.insert(key, user.to_owned());
+        Ok(())
+    }
+
+    /// Forget the cached logins and the account of "user", so that the
+    /// next request checks the password again. Returns how many logins.
+    pub fn invalidate(user: &str) -> usize {
+        let sql = format!("\0{}", user);
+        PWCACHE.remove_if(|name, _| name == user || name.ends_with(&sql));
+        NEGCACHE.remove_if(|name, _| name == user);
+        AUTHCACHE.remove_if(|_, cache_user| cache_user == user)
+    }
+
+    /// Forget all cached accounts, and the users that were not found.
+    pub fn flush_accounts() -> usize {
+        PWCACHE.remove_if(|_, _| true) + NEGCACHE.remove_if(|_, _| true)
+    }
+
+    // a timeout of 0 means no caching, not no expiry.
+    fn enabled() -> (bool, bool) {
+        let timeouts = TIMEOUTS.lock().unwrap();
+        let max = timeouts.pwcache_max > 0;
+        (max && !timeouts.pwcache.is_zero(), max && !timeouts.pwcache_neg.is_zero())
     }
 
     pub async fn unixuser(username: &str, with_groups: bool) -> Result<Arc<User>, io::Error> {
+        let (positive, negative) = enabled();
         if let Some(pwd) = PWCACHE.get(username) {
+            PWCACHE_HITS.fetch_add(1, Ordering::Relaxed);
             return Ok(pwd);
         }
+        if NEGCACHE.get(username).is_some() {
+            PWCACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        PWCACHE_MISSES.fetch_add(1, Ordering::Relaxed);
         match User::by_name_async(username, with_groups).await {
+            // only "not found" is cached, not a directory server that is down.
+            Err(e) if negative && e.raw_os_error() == Some(libc::ENOENT) => {
+                NEGCACHE.insert(username.to_owned(), ());
+                Err(e)
+            },
             Err(e) => Err(e),
-            Ok(pwd) => Ok(PWCACHE.insert(username.to_owned(), pwd)),
+            Ok(pwd) if positive => Ok(PWCACHE.insert(username.to_owned(), pwd)),
+            Ok(pwd) => Ok(Arc::new(pwd)),
+        }
+    }
+
+    /// An account from the [sql.<section>] database. Cached with the Unix
+    /// accounts, as "<section>\0<user>".
+    pub async fn sqluser(section: &str, cfg: &crate::config::Sql, username: &str) -> io::Result<Arc<User>> {
+        let key = format!("{}\0{}", section, username);
+        if let Some(pwd) = PWCACHE.get(&key) {
+            PWCACHE_HITS.fetch_add(1, Ordering::Relaxed);
+            return Ok(pwd);
         }
+        PWCACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+        let pwd = crate::sql::account(cfg, username).await?;
+        Ok(PWCACHE.insert(key, pwd))
     }
 }
@@ -0,0 +1,81 @@
+//
+// Caching by browsers, proxies and CDNs: the Cache-Control and Expires
+// headers of a location (cache-control, expires), on the responses to a
+// GET or HEAD of a file, the 304s too.
+//
+// The handler answers If-None-Match and If-Modified-Since with a 304 for
+// every backend, but it compares ETags strongly. If-None-Match is meant
+// to use the weak comparison, so the W/ is taken off the ETags in the
+// request first: then the weak ETags of compressed responses, and those
+// that a proxy made weak, match as well.
+//
+use std::time::{Duration, SystemTime};
+
+use headers::HeaderMapExt;
+use http::StatusCode;
+
+use crate::config::Location;
+
+/// If-None-Match uses the weak comparison. The handler compares
+/// strongly, so it gets the ETags without the W/.
+pub fn if_none_match(headers: &mut http::HeaderMap) {
+    let value = headers.get(http::header::IF_NONE_MATCH).and_then(|h| h.to_str().ok());
+    let tags = match value {
+        Some(v) if v.contains("W/") => v.split(',').map(|t| t.trim().trim_start_matches("W/")),
+        _ => return,
+    };
+    let tags = tags.collect::<Vec<_>>();
+    if let Ok(value) = tags.join(", ").parse() {
+        headers.insert(http::header::IF_NONE_MATCH, value);
+    }
+}
+
+/// Add the Cache-Control and Expires headers of the location to the
+/// response to a GET or HEAD of a file. With only expires, Cache-Control
+/// gets the same max-age. Headers that are already there are kept.
+pub fn response(location: &Location, status: StatusCode, headers: &mut http::HeaderMap) {
+    let cached = matches!(status, StatusCode::OK | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED);
+    if !cached {
+        return;
+    }
+    let max_age = location.expires.map(|secs| format!("max-age={}", secs));
+    let value = location.cache_control.clone().or(max_age);
+    if let Some(value) = value.and_then(|v| v.parse().ok()) {
+        if !headers.contains_key(http::header::CACHE_CONTROL) {
+            headers.insert(http::header::CACHE_CONTROL, value);
+        }
+    }
+    if let Some(secs) = location.expires {
+        if !headers.contains_key(http::header::EXPIRES) {
+            headers.typed_insert(headers::Expires::from(SystemTime::now() + Duration::from_secs(secs)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cachecontrol() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(http::header::IF_NONE_MATCH, "W/\"1-2\", \"3-4\"".parse().unwrap());
+        if_none_match(&mut headers);
+        assert_eq!(headers.get(http::header::IF_NONE_MATCH).unwrap(), "\"1-2\", \"3-4\"");
+
+        let toml = "[server]\n[[location]]\nhandler = \"mem\"\ndirectory = \"x\"\nexpires = 60\n";
+        let config = crate::config::from_value(toml::from_str(toml).unwrap()).unwrap();
+        let mut location = config.location[0].clone();
+        let mut headers = http::HeaderMap::new();
+        response(&location, StatusCode::NOT_FOUND, &mut headers);
+        assert!(headers.is_empty());
+        response(&location, StatusCode::NOT_MODIFIED, &mut headers);
+        assert_eq!(headers.get(http::header::CACHE_CONTROL).unwrap(), "max-age=60");
+        assert!(headers.typed_get::<headers::Expires>().is_some());
+
+        location.cache_control = Some("public, max-age=3600".to_string());
+        let mut headers = http::HeaderMap::new();
+        response(&location, StatusCode::OK, &mut headers);
+        assert_eq!(headers.get(http::header::CACHE_CONTROL).unwrap(), "public, max-age=3600");
+    }
+}
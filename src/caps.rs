@@ -0,0 +1,250 @@
+//
+// Dropping capabilities ([sandbox] capabilities and no-new-privs).
+//
+// The server keeps the saved uid root to switch the uid of a thread to
+// a user, and so all capabilities of root as well. With a list of
+// capabilities only those are kept: the others are dropped from the
+// permitted, inheritable, ambient and bounding sets before the uid is
+// switched. A thread that goes back to uid 0 then only gets those.
+//
+// Capabilities (and no-new-privs) belong to a thread, not to the process,
+// and the threads of the runtime are already running. So, as the C
+// library does for setuid(), each thread gets a signal and drops them
+// itself. /proc/self/task/*/status shows if they all did.
+//
+use std::io;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering};
+
+use crate::config::Capability;
+
+// The numbers of <linux/capability.h>.
+fn number(cap: Capability) -> u32 {
+    match cap {
+        Capability::Chown => 0,
+        Capability::DacOverride => 1,
+        Capability::DacReadSearch => 2,
+        Capability::Fowner => 3,
+        Capability::Setgid => 6,
+        Capability::Setuid => 7,
+        Capability::NetBindService => 10,
+        Capability::SysResource => 24,
+    }
+}
+
+fn mask(caps: &[Capability]) -> u64 {
+    caps.iter().fold(0, |m, &cap| m | 1 << number(cap))
+}
+
+#[cfg(target_os = "linux")]
+const CAP_SETPCAP: u32 = 8;
+
+// What a thread has: CapPrm and CapBnd, and NoNewPrivs.
+#[derive(Debug, Default, PartialEq)]
+struct Status {
+    permitted:    u64,
+    bounding:     u64,
+    no_new_privs: bool,
+}
+
+fn parse_status(text: &str) -> Status {
+    let mut status = Status::default();
+    for line in text.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key, value.trim()),
+            None => continue,
+        };
+        match key {
+            "CapPrm" => status.permitted = u64::from_str_radix(value, 16).unwrap_or(u64::MAX),
+            "CapBnd" => status.bounding = u64::from_str_radix(value, 16).unwrap_or(u64::MAX),
+            "NoNewPrivs" => status.no_new_privs = value == "1",
+            _ => {},
+        }
+    }
+    status
+}
+
+#[cfg(target_os = "linux")]
+mod thread {
+    use super::*;
+
+    #[repr(C)]
+    struct Header {
+        version: u32,
+        pid:     libc::c_int,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Data {
+        effective:   u32,
+        permitted:   u32,
+        inheritable: u32,
+    }
+
+    const VERSION_3: u32 = 0x2008_0522;
+
+    pub static KEEP: AtomicU64 = AtomicU64::new(0);
+    pub static NO_NEW_PRIVS: AtomicBool = AtomicBool::new(false);
+    pub static BOUNDING: AtomicBool = AtomicBool::new(false);
+    pub static LAST_CAP: AtomicU32 = AtomicU32::new(40);
+    pub static ERRNO: AtomicI32 = AtomicI32::new(0);
+
+    fn errno() -> i32 {
+        io::Error::last_os_error().raw_os_error().unwrap_or(libc::EINVAL)
+    }
+
+    // Drop them in this thread. Only system calls, it runs in a signal handler.
+    pub fn drop_caps() -> Result<(), i32> {
+        let keep = KEEP.load(Ordering::Acquire);
+        unsafe {
+            let nnp = NO_NEW_PRIVS.load(Ordering::Relaxed);
+            if nnp && libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(errno());
+            }
+            // EINVAL: a kernel without ambient capabilities.
+            if libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) != 0
+                && errno() != libc::EINVAL
+            {
+                return Err(errno());
+            }
+            let mut header = Header { version: VERSION_3, pid: 0 };
+            let mut data = [Data::default(); 2];
+            if libc::syscall(libc::SYS_capget, &mut header as *mut Header, data.as_mut_ptr()) != 0 {
+                return Err(errno());
+            }
+            if BOUNDING.load(Ordering::Relaxed) && data[0].effective & (1 << CAP_SETPCAP) != 0 {
+                for cap in 0..=LAST_CAP.load(Ordering::Relaxed) {
+                    if keep & (1 << cap) == 0
+                        && libc::prctl(libc::PR_CAPBSET_READ, cap as libc::c_ulong, 0, 0, 0) == 1
+                        && libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) != 0
+                    {
+                        return Err(errno());
+                    }
+                }
+            }
+            for (i, d) in data.iter_mut().enumerate() {
+                let keep = (keep >> (32 * i)) as u32;
+                d.effective &= keep;
+                d.permitted &= keep;
+                d.inheritable = 0;
+            }
+            if libc::syscall(libc::SYS_capset, &mut header as *mut Header, data.as_ptr()) != 0 {
+                return Err(errno());
+            }
+        }
+        Ok(())
+    }
+
+    pub extern "C" fn handler(_sig: libc::c_int) {
+        if let Err(e) = drop_caps() {
+            ERRNO.store(e, Ordering::Relaxed);
+        }
+    }
+}
+
+// The threads that still have more than they should.
+#[cfg(target_os = "linux")]
+fn pending(keep: u64, bounding: bool, no_new_privs: bool) -> io::Result<Vec<libc::pid_t>> {
+    let mut tids = Vec::new();
+    for entry in std::fs::read_dir("/proc/self/task")? {
+        let entry = entry?;
+        let tid = match entry.file_name().to_str().and_then(|t| t.parse().ok()) {
+            Some(tid) => tid,
+            None => continue,
+        };
+        // a thread that exited in the meantime is not pending.
+        let status = match std::fs::read_to_string(entry.path().join("status")) {
+            Ok(text) => parse_status(&text),
+            Err(_) => continue,
+        };
+        if status.permitted & !keep != 0
+            || (bounding && status.bounding & !keep != 0)
+            || (no_new_privs && !status.no_new_privs)
+        {
+            tids.push(tid);
+        }
+    }
+    Ok(tids)
+}
+
+/// Keep only these capabilities, in all threads, and set no-new-privs.
+#[cfg(target_os = "linux")]
+pub fn apply(caps: Option<&[Capability]>, no_new_privs: bool) -> io::Result<()> {
+    use self::thread::*;
+
+    // without a list, all capabilities are kept.
+    let keep = caps.map(mask).unwrap_or(u64::MAX);
+    if caps.is_none() && !no_new_privs {
+        return Ok(());
+    }
+    if let Ok(last) = std::fs::read_to_string("/proc/sys/kernel/cap_last_cap") {
+        LAST_CAP.store(last.trim().parse().unwrap_or(40), Ordering::Relaxed);
+    }
+    KEEP.store(keep, Ordering::Release);
+    NO_NEW_PRIVS.store(no_new_privs, Ordering::Relaxed);
+
+    // this thread first: without CAP_SETPCAP the bounding set stays.
+    let status = parse_status(&std::fs::read_to_string("/proc/thread-self/status")?);
+    let bounding = caps.is_some() && status.bounding & !keep != 0;
+    BOUNDING.store(bounding, Ordering::Relaxed);
+    thread::drop_caps().map_err(io::Error::from_raw_os_error)?;
+    let status = parse_status(&std::fs::read_to_string("/proc/thread-self/status")?);
+    let bounding = bounding && status.bounding & !keep == 0;
+    BOUNDING.store(bounding, Ordering::Relaxed);
+
+    let sig = libc::SIGRTMIN() + 4;
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = thread::handler as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(sig, &action, std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let pid = std::process::id() as libc::pid_t;
+    let mut tids = Vec::new();
+    for _ in 0..200 {
+        tids = pending(keep, bounding, no_new_privs)?;
+        let errno = ERRNO.load(Ordering::Relaxed);
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+        if tids.is_empty() {
+            break;
+        }
+        for &tid in &tids {
+            unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, sig) };
+        }
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    match tids.len() {
+        0 => Ok(()),
+        n => Err(io::Error::other(format!("capabilities: {} threads did not drop them", n))),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(caps: Option<&[Capability]>, no_new_privs: bool) -> io::Result<()> {
+    match caps.is_none() && !no_new_privs {
+        true => Ok(()),
+        false => Err(io::Error::other("capabilities and no-new-privs: only on Linux")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caps() {
+        assert_eq!(mask(&[Capability::Setuid, Capability::Setgid]), 0xc0);
+        assert_eq!(mask(&[Capability::DacReadSearch, Capability::SysResource]), 1 << 2 | 1 << 24);
+        let text = "Name:\tworker\nCapInh:\t0000000000000000\nCapPrm:\t00000000000000c4\n\
+                    CapEff:\t0000000000000000\nCapBnd:\t000001ffffffffff\nNoNewPrivs:\t1\n";
+        let status = parse_status(text);
+        assert_eq!(status, Status { permitted: 0xc4, bounding: 0x1ff_ffff_ffff, no_new_privs: true });
+        assert_eq!(parse_status("CapPrm:\tzz\n").permitted, u64::MAX);
+    }
+}
@@ -0,0 +1,299 @@
+//
+// Case-insensitive names, for the filesystems that do not look them up
+// themselves (mem, s3), and no names that differ only in case.
+//
+// With lookup, a path that does not exist as it is written is looked up
+// name by name, ignoring case: GET /Docs/Report.DOCX finds
+// docs/report.docx. With unique names, a new file or directory (PUT,
+// MKCOL, the destination of COPY and MOVE) can not get a name that is
+// already in its directory in another case: a sync client on Windows or
+// macOS would see one of them, or make "Docs (2)". Renaming a file to the
+// same name in another case is fine, but not with lookups: then it is
+// the file itself.
+//
+use std::future::Future;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use futures::future::FutureExt;
+use futures::StreamExt;
+use http::StatusCode;
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+fn fold(name: &[u8]) -> String {
+    String::from_utf8_lossy(name).to_lowercase()
+}
+
+/// The same path, but not in the same case. With lookups that is a
+/// COPY or MOVE onto itself, which the handler would not see: a MOVE of
+/// a directory would remove it first, as the destination.
+pub fn differs_in_case(a: &str, b: &str) -> bool {
+    let (a, b) = (a.trim_end_matches('/'), b.trim_end_matches('/'));
+    a != b && a.to_lowercase() == b.to_lowercase()
+}
+
+// The names in a path.
+pub(crate) fn segments(path: &DavPath) -> Vec<&[u8]> {
+    path.as_bytes().split(|&c| c == b'/').filter(|s| !s.is_empty()).collect()
+}
+
+// A path from its names.
+pub(crate) fn join(names: &[Vec<u8>], collection: bool) -> FsResult<DavPath> {
+    let mut url = String::new();
+    for name in names {
+        url.push('/');
+        url.push_str(&percent_encode(name, NON_ALPHANUMERIC).to_string());
+    }
+    if collection || url.is_empty() {
+        url.push('/');
+    }
+    DavPath::new(&url).map_err(|_| FsError::GeneralFailure)
+}
+
+#[derive(Clone)]
+pub struct CaseFs {
+    fs:     Box<dyn DavFileSystem>,
+    lookup: bool,
+    unique: bool,
+}
+
+impl CaseFs {
+    pub fn new(fs: Box<dyn DavFileSystem>, lookup: bool, unique: bool) -> Box<CaseFs> {
+        Box::new(CaseFs { fs, lookup, unique })
+    }
+
+    // The name in a directory in another case, if there is one.
+    async fn find(&self, dir: &DavPath, name: &[u8]) -> Option<Vec<u8>> {
+        let mut entries = self.fs.read_dir(dir, ReadDirMeta::None).await.ok()?;
+        let folded = fold(name);
+        while let Some(entry) = entries.next().await {
+            let entry = entry.name();
+            if entry != name && fold(&entry) == folded {
+                return Some(entry);
+            }
+        }
+        None
+    }
+
+    // The path as it is in the filesystem. After the first name that is
+    // not found, the rest is as it was.
+    async fn resolve(&self, path: &DavPath) -> FsResult<DavPath> {
+        if !self.lookup || self.fs.symlink_metadata(path).await.is_ok() {
+            return Ok(path.clone());
+        }
+        let mut names: Vec<Vec<u8>> = Vec::new();
+        let mut found = true;
+        for name in segments(path) {
+            if found {
+                let dir = join(&names, true)?;
+                let mut here = names.clone();
+                here.push(name.to_vec());
+                if self.fs.symlink_metadata(&join(&here, false)?).await.is_err() {
+                    match self.find(&dir, name).await {
+                        Some(other) => {
+                            names.push(other);
+                            continue;
+                        },
+                        None => found = false,
+                    }
+                }
+            }
+            names.push(name.to_vec());
+        }
+        join(&names, path.is_collection())
+    }
+
+    // A new name may not be in its directory in another case. `from` is
+    // the name that is renamed, which may.
+    async fn check_new(&self, path: &DavPath, from: Option<&DavPath>) -> FsResult<()> {
+        if !self.unique || self.fs.symlink_metadata(path).await.is_ok() {
+            return Ok(());
+        }
+        let mut names: Vec<Vec<u8>> = segments(path).into_iter().map(|s| s.to_vec()).collect();
+        let name = match names.pop() {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let dir = join(&names, true)?;
+        let other = match self.find(&dir, &name).await {
+            Some(other) => other,
+            None => return Ok(()),
+        };
+        names.push(other);
+        let other = join(&names, false)?;
+        match from {
+            Some(from) if from.as_rel_ospath() == other.as_rel_ospath() => Ok(()),
+            _ => {
+                debug!("casefs: {}: is there as {}", path.as_url_string(), other.as_url_string());
+                Err(FsError::Forbidden)
+            },
+        }
+    }
+}
+
+impl DavFileSystem for CaseFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let path = self.resolve(path).await?;
+            if options.create || options.create_new {
+                self.check_new(&path, None).await?;
+            }
+            self.fs.open(&path, options).await
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.read_dir(&path, meta).await
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.metadata(&path).await
+        }
+        .boxed()
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.symlink_metadata(&path).await
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.check_new(&path, None).await?;
+            self.fs.create_dir(&path).await
+        }
+        .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.remove_dir(&path).await
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.remove_file(&path).await
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let from = self.resolve(from).await?;
+            let to = self.resolve(to).await?;
+            self.check_new(&to, Some(&from)).await?;
+            self.fs.rename(&from, &to).await
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let from = self.resolve(from).await?;
+            let to = self.resolve(to).await?;
+            self.check_new(&to, None).await?;
+            self.fs.copy(&from, &to).await
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.set_accessed(&path, tm).await
+        }
+        .boxed()
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.set_modified(&path, tm).await
+        }
+        .boxed()
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        async move {
+            match self.resolve(path).await {
+                Ok(path) => self.fs.have_props(&path).await,
+                Err(_) => false,
+            }
+        }
+        .boxed()
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.patch_props(&path, patch).await
+        }
+        .boxed()
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.get_props(&path, do_content).await
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.get_prop(&path, prop).await
+        }
+        .boxed()
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_casefs() {
+        let path = DavPath::new("/Docs/Report%20A.DOCX").unwrap();
+        assert_eq!(segments(&path), vec![&b"Docs"[..], &b"Report A.DOCX"[..]]);
+        let names = vec![b"docs".to_vec(), b"report a.docx".to_vec()];
+        assert_eq!(join(&names, false).unwrap().as_url_string(), "/docs/report%20a.docx");
+        assert_eq!(join(&names[..1], true).unwrap().as_url_string(), "/docs/");
+        assert_eq!(join(&[], false).unwrap().as_url_string(), "/");
+        assert_eq!(fold("Ärger.TXT".as_bytes()), "ärger.txt");
+        assert!(differs_in_case("/docs/a.txt", "/Docs/A.txt"));
+        assert!(!differs_in_case("/docs/", "/docs"));
+        assert!(!differs_in_case("/docs/a.txt", "/docs/b.txt"));
+    }
+}
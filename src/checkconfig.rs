@@ -0,0 +1,186 @@
+//
+// --check-config: look for problems that config::validate cannot see,
+// like missing directories, unreadable files and unknown users.
+//
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use nix::unistd::{Gid, Group, Uid, User};
+
+use crate::config::{Config, Handler};
+
+// Can uid / gid read (4), write (2) or search (1) a file with this metadata.
+fn access(meta: &fs::Metadata, uid: u32, gid: u32, want: u32) -> bool {
+    if uid == 0 {
+        return true;
+    }
+    let mode = meta.mode();
+    let bits = if meta.uid() == uid {
+        mode >> 6
+    } else if meta.gid() == gid {
+        mode >> 3
+    } else {
+        mode
+    };
+    bits & want == want
+}
+
+// Is the PAM service configured.
+#[cfg(feature = "pam")]
+fn pam_service_exists(service: &str) -> bool {
+    use std::path::Path;
+    let dirs = ["/etc/pam.d", "/usr/lib/pam.d", "/lib/pam.d"];
+    dirs.iter().any(|d| Path::new(d).join(service).exists()) || Path::new("/etc/pam.conf").exists()
+}
+
+/// Check the config against the system. Returns a list of problems.
+pub fn check(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    // the uid / gid that the server runs as.
+    let uid = config.server.uid.unwrap_or_else(|| Uid::current().as_raw());
+    let gid = config.server.gid.unwrap_or_else(|| Gid::current().as_raw());
+    if let Some(uid) = config.server.uid {
+        if !matches!(User::from_uid(Uid::from_raw(uid)), Ok(Some(_))) {
+            problems.push(format!("[server]: uid {}: no such user", uid));
+        }
+    }
+    if let Some(gid) = config.server.gid {
+        if !matches!(Group::from_gid(Gid::from_raw(gid)), Ok(Some(_))) {
+            problems.push(format!("[server]: gid {}: no such group", gid));
+        }
+    }
+    if let Some(ref group) = config.server.unix_socket_group {
+        if group.parse::<u32>().is_err() && !matches!(Group::from_name(group), Ok(Some(_))) {
+            problems.push(format!("[server]: unix_socket_group {}: no such group", group));
+        }
+    }
+
+    // files that are read after dropping privileges.
+    let mut files = Vec::new();
+    let server = &config.server;
+    for (name, file) in [("tls_cert", &server.tls_cert), ("tls_key", &server.tls_key)].iter() {
+        if let Some(file) = file {
+            files.push((format!("[server]: {}", name), file.as_str()));
+        }
+    }
+    for (idx, vhost) in config.vhost.iter().enumerate() {
+        for file in vhost.tls_cert.iter().chain(vhost.tls_key.iter()) {
+            files.push((format!("[[vhost]][{}]", idx), file.as_str()));
+        }
+    }
+    for (name, ht) in &config.htpasswd {
+        files.push((format!("[htpasswd.{}]", name), ht.htpasswd.as_str()));
+    }
+    for (name, ht) in &config.htdigest {
+        files.push((format!("[htdigest.{}]", name), ht.htdigest.as_str()));
+    }
+    for (name, jwt) in &config.jwt {
+        if let Some(ref file) = jwt.key_file {
+            files.push((format!("[jwt.{}]", name), file.as_str()));
+        }
+    }
+    let before = problems.len();
+    for (section, file) in files {
+        match fs::metadata(file) {
+            Ok(meta) if !access(&meta, uid, gid, 4) => {
+                problems.push(format!("{}: {}: not readable by uid {}", section, file, uid));
+            },
+            Ok(_) => {},
+            Err(e) => problems.push(format!("{}: {}: {}", section, file, e)),
+        }
+    }
+
+    // load the certificates, if the files are there.
+    if config.has_tls_listener() && config.acme.is_none() && problems.len() == before {
+        let res = crate::tls::Certs::load(config).and_then(|c| crate::tls::tls_config(config, &c, None));
+        if let Err(e) = res {
+            problems.push(format!("[server]: tls: {}", e));
+        }
+    }
+
+    // with rehash, htpasswd files are replaced by a new file next to them.
+    if config.passwords.rehash {
+        for (name, ht) in &config.htpasswd {
+            let dir = Path::new(&ht.htpasswd).parent().unwrap_or_else(|| Path::new("."));
+            if fs::metadata(dir).map(|m| !access(&m, uid, gid, 3)).unwrap_or(false) {
+                let dir = dir.display();
+                problems.push(format!("[htpasswd.{}]: rehash: {}: not writable by uid {}", name, dir, uid));
+            }
+        }
+    }
+
+    // the quarantine directory is written by the server, not the user.
+    if let Some(dir) = config.antivirus.as_ref().and_then(|av| av.quarantine.as_ref()) {
+        match fs::metadata(dir) {
+            Ok(meta) if !meta.is_dir() => {
+                problems.push(format!("[antivirus]: quarantine {}: not a directory", dir));
+            },
+            Ok(meta) if !access(&meta, uid, gid, 3) => {
+                problems.push(format!("[antivirus]: quarantine {}: not writable by uid {}", dir, uid));
+            },
+            Ok(_) => {},
+            Err(e) => problems.push(format!("[antivirus]: quarantine {}: {}", dir, e)),
+        }
+    }
+
+    // directories. "~" is per user, and with setuid the user needs access, not us.
+    for (section, location) in config.locations() {
+        if let Some(ref guest) = location.guest {
+            if !matches!(User::from_name(guest), Ok(Some(_))) {
+                problems.push(format!("{}: guest {}: no such user", section, guest));
+            }
+        }
+        if let Some(ref skeleton) = location.skeleton {
+            if !fs::metadata(skeleton).map(|m| m.is_dir()).unwrap_or(false) {
+                problems.push(format!("{}: skeleton {}: not a directory", section, skeleton));
+            }
+        }
+        // the spool files are written by the server.
+        if let Some(ref dir) = location.spool_dir {
+            match fs::metadata(dir) {
+                Ok(meta) if !meta.is_dir() => {
+                    problems.push(format!("{}: spool-dir {}: not a directory", section, dir));
+                },
+                Ok(meta) if !access(&meta, uid, gid, 3) => {
+                    problems.push(format!("{}: spool-dir {}: not writable by uid {}", section, dir, uid));
+                },
+                Ok(_) => {},
+                Err(e) => problems.push(format!("{}: spool-dir {}: {}", section, dir, e)),
+            }
+        }
+        // for s3 and mem, the directory is not on disk.
+        if matches!(location.handler, Handler::S3 | Handler::Mem) {
+            continue;
+        }
+        let aliases = location.alias.iter().map(|a| (false, a.directory.as_str()));
+        let base = location.overlay_base.iter().map(|b| (false, b.as_str()));
+        let templates = location.template.iter().filter_map(|t| t.directory.as_deref()).map(|d| (true, d));
+        let dirs = std::iter::once((true, location.directory.as_str())).chain(templates);
+        for (main, dir) in dirs.chain(aliases).chain(base) {
+            if dir.starts_with('~') || dir.contains("$user") || dir.contains("${pam:") {
+                continue;
+            }
+            match fs::metadata(dir) {
+                Ok(meta) if !meta.is_dir() => problems.push(format!("{}: {}: not a directory", section, dir)),
+                Ok(meta) if !location.setuid && location.guest.is_none() && !access(&meta, uid, gid, 5) => {
+                    problems.push(format!("{}: {}: not accessible by uid {}", section, dir, uid));
+                },
+                Ok(_) => {},
+                Err(_) if main && location.create_directory => {},
+                Err(e) => problems.push(format!("{}: {}: {}", section, dir, e)),
+            }
+        }
+    }
+
+    // PAM.
+    #[cfg(feature = "pam")]
+    {
+        if config.uses_pam() && !pam_service_exists(&config.pam.service) {
+            problems.push(format!("[pam]: service {}: not found in /etc/pam.d", config.pam.service));
+        }
+    }
+
+    problems
+}
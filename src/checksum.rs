@@ -0,0 +1,361 @@
+//
+// Checksums of file contents, as properties, on a location with
+// checksums = [ ... ].
+//
+// A ChecksumFs answers PROPFIND of oc:checksums (the ownCloud property,
+// "SHA1:<hex> MD5:<hex>") and of the properties sha1, sha256, md5 and
+// adler32 in the namespace below. They are only computed when they are
+// asked for by name, not for allprop. If the filesystem below stores dead
+// properties, the result is kept there, with the mtime and size of the
+// file; it is used until one of them changes.
+//
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::FutureExt;
+use http::StatusCode;
+use md5::{Digest, Md5};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::config::ChecksumType;
+use crate::report;
+
+pub const NS: &str = "urn:webdav-server-rs:checksum";
+pub const OC_NS: &str = "http://owncloud.org/ns";
+
+const CHUNK: usize = 65536;
+
+/// A checksum that is being computed.
+pub enum Checksum {
+    Ring(ring::digest::Context),
+    Md5(Md5),
+    Adler32(u32, u32),
+}
+
+impl Checksum {
+    /// By name, as in OC-Checksum: SHA1, SHA256, MD5 or ADLER32.
+    pub fn new(algorithm: &str) -> Option<Checksum> {
+        let checksum = match algorithm.to_ascii_uppercase().as_str() {
+            "SHA1" => Checksum::Ring(ring::digest::Context::new(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY)),
+            "SHA256" => Checksum::Ring(ring::digest::Context::new(&ring::digest::SHA256)),
+            "MD5" => Checksum::Md5(Md5::new()),
+            "ADLER32" => Checksum::Adler32(1, 0),
+            _ => return None,
+        };
+        Some(checksum)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Checksum::Ring(ctx) => ctx.update(data),
+            Checksum::Md5(ctx) => ctx.update(data),
+            Checksum::Adler32(a, b) => {
+                for &c in data {
+                    *a = (*a + c as u32) % 65521;
+                    *b = (*b + *a) % 65521;
+                }
+            },
+        }
+    }
+
+    /// The checksum in hex.
+    pub fn finish(self) -> String {
+        match self {
+            Checksum::Ring(ctx) => ctx.finish().as_ref().iter().map(|b| format!("{:02x}", b)).collect(),
+            Checksum::Md5(ctx) => format!("{:x}", ctx.finalize()),
+            Checksum::Adler32(a, b) => format!("{:08x}", (b << 16) | a),
+        }
+    }
+}
+
+/// The name of a checksum type, as in OC-Checksum.
+pub fn name(t: ChecksumType) -> &'static str {
+    match t {
+        ChecksumType::Sha1 => "SHA1",
+        ChecksumType::Sha256 => "SHA256",
+        ChecksumType::Md5 => "MD5",
+        ChecksumType::Adler32 => "ADLER32",
+    }
+}
+
+// The checksums as "SHA1:<hex> MD5:<hex>".
+fn format(sums: &[(ChecksumType, String)]) -> String {
+    let sums = sums.iter().map(|(t, sum)| format!("{}:{}", name(*t), sum));
+    sums.collect::<Vec<_>>().join(" ")
+}
+
+fn prop(ns: &str, name: &str, xml: Option<String>) -> DavProp {
+    DavProp {
+        name:      name.to_string(),
+        prefix:    None,
+        namespace: Some(ns.to_string()),
+        xml:       xml.map(String::into_bytes),
+    }
+}
+
+/// A filesystem with checksum properties.
+#[derive(Clone)]
+pub struct ChecksumFs {
+    fs:    Box<dyn DavFileSystem>,
+    types: Vec<ChecksumType>,
+}
+
+impl ChecksumFs {
+    pub fn new(fs: Box<dyn DavFileSystem>, types: &[ChecksumType]) -> Box<ChecksumFs> {
+        Box::new(ChecksumFs {
+            fs,
+            types: types.to_vec(),
+        })
+    }
+
+    // The mtime and size of a file, that the cached checksums are for.
+    async fn stamp(&self, path: &DavPath) -> FsResult<String> {
+        let meta = self.fs.metadata(path).await?;
+        if !meta.is_file() {
+            return Err(FsError::NotFound);
+        }
+        let mtime = meta.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok(format!("{}.{:09}-{}", mtime.as_secs(), mtime.subsec_nanos(), meta.len()))
+    }
+
+    // The checksums in the store, by name, if they are of this version of
+    // the file. There can be more than ours.
+    async fn stored(&self, path: &DavPath, stamp: &str) -> Vec<(String, String)> {
+        if !self.fs.have_props(path).await {
+            return Vec::new();
+        }
+        let xml = match self.fs.get_prop(path, prop(NS, "cache", None)).await {
+            Ok(xml) => xml,
+            Err(_) => return Vec::new(),
+        };
+        let text = xmltree::Element::parse(&xml[..]).ok().and_then(|e| e.get_text().map(|t| t.to_string()));
+        let text = text.unwrap_or_default();
+        let mut words = text.split_whitespace();
+        if words.next() != Some(stamp) {
+            return Vec::new();
+        }
+        words
+            .filter_map(|w| w.split_once(':'))
+            .map(|(n, sum)| (n.to_string(), sum.to_string()))
+            .collect()
+    }
+
+    // Our checksums, if they are all in what was stored.
+    fn cached(&self, stored: &[(String, String)]) -> Option<Vec<(ChecksumType, String)>> {
+        self.types
+            .iter()
+            .map(|&t| {
+                let sum = stored.iter().find(|(n, _)| n == name(t))?;
+                Some((t, sum.1.clone()))
+            })
+            .collect()
+    }
+
+    /// The checksums of a file, from the store if they are there.
+    pub async fn checksums(&self, path: &DavPath) -> FsResult<Vec<(ChecksumType, String)>> {
+        let stamp = self.stamp(path).await?;
+        let stored = self.stored(path, &stamp).await;
+        if let Some(sums) = self.cached(&stored) {
+            return Ok(sums);
+        }
+        let mut checksums = self
+            .types
+            .iter()
+            .map(|&t| (t, Checksum::new(name(t)).unwrap()))
+            .collect::<Vec<_>>();
+        let options = OpenOptions {
+            read: true,
+            ..OpenOptions::default()
+        };
+        let mut file = self.fs.open(path, options).await?;
+        loop {
+            let data = file.read_bytes(CHUNK).await?;
+            if data.is_empty() {
+                break;
+            }
+            checksums.iter_mut().for_each(|(_, c)| c.update(&data));
+        }
+        let sums = checksums
+            .into_iter()
+            .map(|(t, c)| (t, c.finish()))
+            .collect::<Vec<_>>();
+
+        // Keep them, if the filesystem can, with the others that are there.
+        if self.fs.have_props(path).await {
+            let mut value = format!("{} {}", stamp, format(&sums));
+            for (n, sum) in stored.iter().filter(|(n, _)| !sums.iter().any(|(t, _)| name(*t) == n)) {
+                value.push_str(&format!(" {}:{}", n, sum));
+            }
+            let xml = report::prop_xml(NS, "cache", &report::escape(&value));
+            let _ = self.fs.patch_props(path, vec![(true, prop(NS, "cache", Some(xml)))]).await;
+        }
+        Ok(sums)
+    }
+
+    /// The value for an OC-Checksum header, if it is known without
+    /// reading the file.
+    pub async fn header(&self, path: &DavPath) -> Option<String> {
+        let stamp = self.stamp(path).await.ok()?;
+        let sums = self.cached(&self.stored(path, &stamp).await)?;
+        sums.first().map(std::slice::from_ref).map(format)
+    }
+
+    // The xml of one of our properties.
+    async fn prop_xml(&self, path: &DavPath, prop: &DavProp) -> FsResult<Vec<u8>> {
+        let ns = prop.namespace.as_deref().unwrap_or_default();
+        if ns == OC_NS {
+            let sums = self.checksums(path).await?;
+            let value = report::escape(&format(&sums));
+            let xml = format!(
+                "<oc:checksums xmlns:oc=\"{}\"><oc:checksum>{}</oc:checksum></oc:checksums>",
+                OC_NS, value
+            );
+            return Ok(xml.into_bytes());
+        }
+        let t = self.types.iter().find(|&&t| name(t).eq_ignore_ascii_case(&prop.name));
+        if t.is_none() {
+            return Err(FsError::NotFound);
+        }
+        let sums = self.checksums(path).await?;
+        let sum = sums.into_iter().find(|s| Some(&s.0) == t).ok_or(FsError::NotFound)?;
+        Ok(report::prop_xml(NS, &prop.name, &sum.1).into_bytes())
+    }
+}
+
+// Is this one of ours.
+fn is_ours(prop: &DavProp) -> bool {
+    match prop.namespace.as_deref() {
+        Some(NS) => true,
+        Some(OC_NS) => prop.name == "checksums",
+        _ => false,
+    }
+}
+
+impl DavFileSystem for ChecksumFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        self.fs.open(path, options)
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_dir(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_file(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.rename(from, to)
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.copy(from, to)
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, _path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        Box::pin(futures::future::ready(true))
+    }
+
+    // Our properties are live, and cannot be set.
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        if !patch.iter().any(|(_, p)| is_ours(p)) {
+            return self.fs.patch_props(path, patch);
+        }
+        let result = patch
+            .into_iter()
+            .map(|(_, p)| {
+                let status = match is_ours(&p) {
+                    true => StatusCode::FORBIDDEN,
+                    false => StatusCode::FAILED_DEPENDENCY,
+                };
+                (status, DavProp { xml: None, ..p })
+            })
+            .collect();
+        Box::pin(futures::future::ready(Ok(result)))
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        async move {
+            if !self.fs.have_props(path).await {
+                return Ok(Vec::new());
+            }
+            let props = self.fs.get_props(path, do_content).await?;
+            Ok(props.into_iter().filter(|p| !is_ours(p)).collect())
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        async move {
+            if is_ours(&prop) {
+                return self.prop_xml(path, &prop).await;
+            }
+            match self.fs.have_props(path).await {
+                true => self.fs.get_prop(path, prop).await,
+                false => Err(FsError::NotFound),
+            }
+        }
+        .boxed()
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        let digest = |algorithm: &str| {
+            let mut checksum = Checksum::new(algorithm).unwrap();
+            checksum.update(b"Wiki");
+            checksum.update(b"pedia");
+            checksum.finish()
+        };
+        assert_eq!(digest("adler32"), "11e60398");
+        assert_eq!(digest("MD5"), "9c677286866aad38f8e9b660f5411814");
+        assert_eq!(digest("SHA1"), "664add438097fbd4307f814de8e62a10f8905588");
+        assert!(Checksum::new("CRC32").is_none());
+        let sums = [(ChecksumType::Sha1, "ab".to_string()), (ChecksumType::Md5, "cd".to_string())];
+        assert_eq!(format(&sums), "SHA1:ab MD5:cd");
+    }
+}
@@ -0,0 +1,137 @@
+//
+// IP address ranges ("192.0.2.0/24", "2001:db8::/32", or a single address).
+//
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr:   IpAddr,
+    prefix: u8,
+}
+
+/// Map IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) to IPv4.
+pub fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            match v6.octets() {
+                [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => IpAddr::from([a, b, c, d]),
+                _ => ip,
+            }
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn to_bits(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u32::from(v4) as u128,
+        IpAddr::V6(v6) => u128::from(v6),
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        let bits = match (self.addr, ip) {
+            (IpAddr::V4(_), IpAddr::V4(_)) => 32,
+            (IpAddr::V6(_), IpAddr::V6(_)) => 128,
+            _ => return false,
+        };
+        if self.prefix == 0 {
+            return true;
+        }
+        let shift = bits - self.prefix as u32;
+        to_bits(self.addr) >> shift == to_bits(ip) >> shift
+    }
+}
+
+/// An address is allowed if it is in "allow" (or "allow" is empty), and
+/// not in "deny".
+pub fn allowed(allow: &[Cidr], deny: &[Cidr], ip: IpAddr) -> bool {
+    (allow.is_empty() || allow.iter().any(|c| c.contains(ip))) && !deny.iter().any(|c| c.contains(ip))
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map(canonical)
+            .map_err(|_| format!("{}: invalid address", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => {
+                match p.parse::<u8>() {
+                    Ok(p) if p <= max => p,
+                    _ => return Err(format!("{}: invalid prefix length", s)),
+                }
+            },
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D>(deserializer: D) -> Result<Cidr, D::Error>
+    where D: Deserializer<'de> {
+        let s = String::deserialize(deserializer)?;
+        s.parse::<Cidr>().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_contains() {
+        let net: Cidr = "192.0.2.0/24".parse().unwrap();
+        assert!(net.contains(ip("192.0.2.77")));
+        assert!(net.contains(ip("::ffff:192.0.2.1")));
+        assert!(!net.contains(ip("192.0.3.1")));
+        assert!(!net.contains(ip("2001:db8::1")));
+
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(ip("2001:db8:1::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+
+        let net: Cidr = "127.0.0.1".parse().unwrap();
+        assert!(net.contains(ip("127.0.0.1")));
+        assert!(!net.contains(ip("127.0.0.2")));
+
+        let net: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(net.contains(ip("203.0.113.1")));
+
+        assert!("192.0.2.0/33".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+
+        let lan: Vec<Cidr> = vec!["192.168.0.0/16".parse().unwrap(), "::1".parse().unwrap()];
+        let guest: Vec<Cidr> = vec!["192.168.99.0/24".parse().unwrap()];
+        assert!(allowed(&[], &[], ip("203.0.113.1")));
+        assert!(allowed(&lan, &[], ip("::1")));
+        assert!(!allowed(&lan, &[], ip("203.0.113.1")));
+        assert!(allowed(&lan, &guest, ip("192.168.1.1")));
+        assert!(!allowed(&lan, &guest, ip("192.168.99.1")));
+        assert!(!allowed(&[], &guest, ip("::ffff:192.168.99.1")));
+    }
+}
@@ -0,0 +1,204 @@
+//
+// Compression of responses, on a location with compress = true.
+//
+// GET responses and the XML of PROPFIND and REPORT are sent with
+// Content-Encoding: gzip if the client accepts it, the content type is
+// one of compress-types, and they are not smaller than compress-min-size.
+// Partial content (206) is not compressed. The ETag becomes a weak one,
+// since the bytes that are sent are not those of the file; the W/ of an
+// If-None-Match is taken off in cachecontrol.rs.
+//
+// There is no brotli or zstd encoder. Those are not negotiated: if gzip is
+// not accepted (Accept-Encoding: br, zstd), the response is sent as it is.
+//
+use futures::stream;
+use hyper::body::{Bytes, HttpBody};
+
+use crate::config::Location;
+use crate::gzip::Gzip;
+
+const MIN_SIZE: u64 = 1024;
+const TYPES: &[&str] = &[
+    "text/*",
+    "application/xml",
+    "application/json",
+    "application/javascript",
+    "application/xhtml+xml",
+    "image/svg+xml",
+];
+
+/// Does the client accept gzip, by the Accept-Encoding header.
+pub fn accepts_gzip(headers: &http::HeaderMap) -> bool {
+    let mut gzip = None;
+    let mut star = None;
+    for value in headers.get_all(http::header::ACCEPT_ENCODING) {
+        for item in value.to_str().unwrap_or_default().split(',') {
+            let mut parts = item.split(';').map(str::trim);
+            let coding = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let q = parts
+                .filter_map(|p| p.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match coding.as_str() {
+                "gzip" | "x-gzip" => gzip = Some(q > 0.0),
+                "*" => star = Some(q > 0.0),
+                _ => {},
+            }
+        }
+    }
+    gzip.or(star).unwrap_or(false)
+}
+
+// Is this content type in the list. "text/*" is all of text.
+fn type_matches(types: &[&str], content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    types.iter().any(|t| {
+        match t.strip_suffix("/*") {
+            Some(major) => mime.split('/').next() == Some(major),
+            None => mime == t.to_ascii_lowercase(),
+        }
+    })
+}
+
+// Should this response be compressed, leaving out what the client accepts.
+fn wanted(location: &Location, method: &http::Method, resp: &http::Response<hyper::Body>) -> bool {
+    let status = resp.status().as_u16();
+    let ok = match method.as_str() {
+        "GET" | "HEAD" => status == 200,
+        "PROPFIND" | "REPORT" => status == 207,
+        _ => false,
+    };
+    let headers = resp.headers();
+    if !ok || headers.contains_key(http::header::CONTENT_ENCODING) {
+        return false;
+    }
+    let no_transform = headers
+        .get_all(http::header::CACHE_CONTROL)
+        .iter()
+        .any(|v| v.to_str().unwrap_or_default().to_ascii_lowercase().contains("no-transform"));
+    if no_transform {
+        return false;
+    }
+    let length = headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<u64>().ok());
+    let min = location.compress_min.map(|s| s.0).unwrap_or(MIN_SIZE);
+    if length.map(|len| len < min).unwrap_or(false) {
+        return false;
+    }
+    let content_type = headers.get(http::header::CONTENT_TYPE).and_then(|h| h.to_str().ok());
+    let types = match location.compress_types {
+        Some(ref types) => types.iter().map(String::as_str).collect::<Vec<_>>(),
+        None => TYPES.to_vec(),
+    };
+    content_type.map(|t| type_matches(&types, t)).unwrap_or(false)
+}
+
+/// Compress the response, if it should be, and the client accepts it.
+pub fn response(
+    location: &Location,
+    method: &http::Method,
+    accepts: bool,
+    mut resp: http::Response<hyper::Body>,
+) -> http::Response<hyper::Body>
+{
+    if !wanted(location, method, &resp) {
+        return resp;
+    }
+    let headers = resp.headers_mut();
+    headers.append(http::header::VARY, "Accept-Encoding".parse().unwrap());
+    if !accepts {
+        return resp;
+    }
+    headers.insert(http::header::CONTENT_ENCODING, "gzip".parse().unwrap());
+    headers.remove(http::header::CONTENT_LENGTH);
+    let etag = headers.get(http::header::ETAG).and_then(|h| h.to_str().ok());
+    if let Some(weak) = etag.filter(|e| e.starts_with('"')).map(|e| format!("W/{}", e)) {
+        headers.insert(http::header::ETAG, weak.parse().unwrap());
+    }
+    if method == http::Method::HEAD {
+        return resp;
+    }
+    resp.map(gzip)
+}
+
+fn gzip(body: hyper::Body) -> hyper::Body {
+    let state = Some((body, Gzip::new()));
+    let stream = stream::unfold(state, |state| {
+        async move {
+            let (mut body, mut gz) = state?;
+            loop {
+                match body.data().await {
+                    Some(Ok(data)) => {
+                        let out = gz.write(&data);
+                        if !out.is_empty() {
+                            return Some((Ok(Bytes::from(out)), Some((body, gz))));
+                        }
+                    },
+                    Some(Err(e)) => return Some((Err(e), None)),
+                    None => return Some((Ok(Bytes::from(gz.finish())), None)),
+                }
+            }
+        }
+    });
+    hyper::Body::wrap_stream(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiation() {
+        let accept = |value: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert(http::header::ACCEPT_ENCODING, value.parse().unwrap());
+            accepts_gzip(&headers)
+        };
+        assert!(accept("gzip, deflate, br"));
+        assert!(accept("br;q=1.0, gzip;q=0.8, *;q=0.1"));
+        assert!(!accept("gzip;q=0, *"));
+        assert!(accept("*"));
+        assert!(!accept("br, zstd"));
+        assert!(!accepts_gzip(&http::HeaderMap::new()));
+
+        assert!(type_matches(TYPES, "text/html; charset=utf-8"));
+        assert!(type_matches(TYPES, "application/xml; charset=utf-8"));
+        assert!(!type_matches(TYPES, "image/png"));
+        assert!(!type_matches(TYPES, "textual/x"));
+    }
+
+    // Clients that do not accept gzip get the file as it is.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fallback() {
+        let dir = std::env::temp_dir().join(format!("compress-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = "Hello, world.\n".repeat(200);
+        std::fs::write(dir.join("a.txt"), &text).unwrap();
+        let toml = format!(
+            "[server]\n[[location]]\nroute = [ \"/*path\" ]\nhandler = \"filesystem\"\ndirectory = {:?}\n\
+             compress = true\n",
+            dir.to_str().unwrap()
+        );
+        let server = crate::builder::Builder::from_toml(&toml).build().unwrap();
+        let peer: std::net::SocketAddr = "127.0.0.1:4711".parse().unwrap();
+        let get = |accept: &str| {
+            http::Request::get("/a.txt")
+                .header("Accept-Encoding", accept)
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+
+        let resp = server.route(get("br, zstd"), peer).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers().get("content-encoding").is_none());
+        assert_eq!(resp.headers().get("vary").unwrap(), "Accept-Encoding");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, text.as_bytes());
+
+        let resp = server.route(get("br, zstd, gzip;q=0.5"), peer).await.unwrap();
+        assert_eq!(resp.headers().get("content-encoding").unwrap(), "gzip");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,68 +1,255 @@
 use std::collections::HashMap;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::Path;
-use std::process::exit;
+use std::sync::Arc;
 use std::{fs, io};
 
 use enum_from_str::ParseEnumVariantError;
 use enum_from_str_derive::FromStr;
 use serde::{Deserialize, Deserializer};
-use toml;
-use webdav_handler::DavMethodSet;
+use webdav_handler::{DavMethod, DavMethodSet};
 
+use crate::cidr::Cidr;
+use crate::errorpage;
+use crate::geoip;
+use crate::hidefs;
+use crate::namefs;
+use crate::mimetypes;
 use crate::router::Router;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Config {
-    pub server:   Server,
+    pub server:      Server,
+    #[serde(default)]
+    pub runtime:     Runtime,
+    #[serde(default)]
+    pub accounts:    Accounts,
+    #[serde(default)]
+    pub pam:         Pam,
+    #[serde(default)]
+    pub htpasswd:    HashMap<String, HtPasswd>,
+    #[serde(default)]
+    pub htdigest:    HashMap<String, HtDigest>,
+    #[serde(default)]
+    pub ldap:        HashMap<String, Ldap>,
+    #[serde(default)]
+    pub sql:         HashMap<String, Sql>,
+    #[serde(default)]
+    pub sqlite:      Sqlite,
+    #[serde(rename = "password-hash", default)]
+    pub passwords:   PasswordHash,
+    #[serde(default)]
+    pub exec:        Option<Exec>,
+    #[serde(rename = "app-passwords", default)]
+    pub apppass:     AppPasswords,
+    #[serde(default)]
+    pub shares:      Shares,
+    #[serde(default)]
+    pub jwt:         HashMap<String, Jwt>,
+    #[serde(default)]
+    pub oidc:        HashMap<String, Oidc>,
+    #[serde(default)]
+    pub s3:          HashMap<String, S3>,
+    #[serde(default)]
+    pub webhook:     HashMap<String, Webhook>,
+    #[serde(default)]
+    pub antivirus:   Option<Antivirus>,
+    #[serde(default)]
+    pub maintenance: Option<Maintenance>,
+    #[serde(default)]
+    pub jobs:        Option<Jobs>,
+    #[cfg(feature = "kerberos")]
+    #[serde(default)]
+    pub kerberos:    Kerberos,
+    #[serde(default)]
+    pub acme:        Option<Acme>,
+    #[serde(default)]
+    pub throttle:    Throttle,
+    #[serde(default)]
+    pub limits:      Limits,
+    #[serde(default)]
+    pub geoip:       GeoIp,
+    #[serde(default)]
+    pub admin:       Admin,
+    #[serde(default)]
+    pub tracing:     Tracing,
+    #[serde(default)]
+    pub log:         Log,
+    #[serde(default)]
+    pub errors:      Errors,
+    #[serde(rename = "mime-types", default)]
+    pub mime:        MimeTypes,
+    #[serde(default)]
+    pub locks:       Locks,
+    #[serde(default)]
+    pub cors:        Cors,
+    #[serde(default)]
+    pub unix:        Unix,
+    #[serde(default)]
+    pub sandbox:     Sandbox,
+    #[serde(default)]
+    pub listen:      Vec<Listen>,
     #[serde(default)]
-    pub accounts: Accounts,
+    pub vhost:       Vec<Vhost>,
     #[serde(default)]
-    pub pam:      Pam,
+    pub location:    Vec<Location>,
+    #[serde(skip)]
+    pub router:      Router<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Listen {
+    pub address:        OneOrMany<ListenAddr>,
+    #[serde(default)]
+    pub tls:            bool,
+    #[serde(rename = "proxy-protocol", default)]
+    pub proxy_protocol: Option<bool>,
+    #[serde(rename = "auth-schemes", deserialize_with = "deserialize_vec_enum", default)]
+    pub auth_schemes:   Option<Vec<AuthScheme>>,
+    #[serde(rename = "read-only", default)]
+    pub read_only:      bool,
+    #[serde(rename = "redirect-https", default)]
+    pub redirect_https: bool,
+    #[serde(rename = "https-port", default)]
+    pub https_port:     Option<u16>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Vhost {
     #[serde(default)]
-    pub htpasswd: HashMap<String, HtPasswd>,
+    pub hostname: Vec<String>,
+    #[serde(rename = "tls-cert", alias = "tls_cert", default)]
+    pub tls_cert: Option<String>,
+    #[serde(rename = "tls-key", alias = "tls_key", default)]
+    pub tls_key:  Option<String>,
     #[serde(default)]
-    pub unix:     Unix,
+    pub realm:    Option<String>,
     #[serde(default)]
     pub location: Vec<Location>,
     #[serde(skip)]
     pub router:   Router<usize>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Server {
     #[serde(default)]
-    pub listen:         OneOrManyAddr,
+    pub listen:              OneOrMany<ListenAddr>,
+    #[serde(default)]
+    pub tls_listen:          OneOrManyAddr,
+    #[serde(default)]
+    pub quic_listen:         OneOrManyAddr,
+    #[serde(default)]
+    pub tls_key:             Option<String>,
+    #[serde(default)]
+    pub tls_cert:            Option<String>,
+    #[serde(default)]
+    pub tls_client_ca:       Option<String>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub tls_client_auth:     Option<TlsClientAuth>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub tls_client_user:     Option<TlsClientUser>,
+    #[serde(default)]
+    pub unix_socket_mode:    Option<String>,
     #[serde(default)]
-    pub tls_listen:     OneOrManyAddr,
+    pub unix_socket_group:   Option<String>,
+    #[serde(default, alias = "trusted-proxies")]
+    pub trusted_proxies:     Vec<Cidr>,
     #[serde(default)]
-    pub tls_key:        Option<String>,
+    pub proxy_protocol:      Option<bool>,
     #[serde(default)]
-    pub tls_cert:       Option<String>,
+    pub http2:               Option<bool>,
+    #[serde(default)]
+    pub h2c:                 Option<bool>,
+    #[serde(default)]
+    pub sendfile:            Option<bool>,
+    #[serde(default, alias = "drain-timeout")]
+    pub drain_timeout:       Option<u64>,
+    #[serde(default, alias = "header-read-timeout")]
+    pub header_read_timeout: Option<u64>,
+    #[serde(default, alias = "idle-timeout")]
+    pub idle_timeout:        Option<u64>,
+    #[serde(default, alias = "request-timeout")]
+    pub request_timeout:     Option<u64>,
+    #[serde(default, alias = "transfer-timeout")]
+    pub transfer_timeout:    Option<u64>,
+    #[serde(default, alias = "read-only", alias = "readonly")]
+    pub read_only:           Option<bool>,
+    #[serde(default, alias = "max-request-body")]
+    pub max_request_body:    Option<Size>,
+    #[serde(default)]
+    pub hsts:                Option<String>,
+    #[serde(default, alias = "tls-headers")]
+    pub tls_headers:         HashMap<String, String>,
     //#[serde(deserialize_with = "deserialize_user", default)]
-    pub uid:            Option<u32>,
+    pub uid:                 Option<u32>,
     //#[serde(deserialize_with = "deserialize_group", default)]
-    pub gid:            Option<u32>,
+    pub gid:                 Option<u32>,
+    #[serde(default)]
+    pub identification:      Option<String>,
     #[serde(default)]
-    pub identification: Option<String>,
+    pub middleware:          Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Acme {
+    pub domains:    Vec<String>,
+    #[serde(default)]
+    pub contact:    Vec<String>,
+    pub directory:  Option<String>,
+    #[serde(rename = "state-dir")]
+    pub state_dir:  String,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub challenge:  Option<AcmeChallenge>,
+    #[serde(rename = "renew-days")]
+    pub renew_days: Option<u64>,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
 pub struct Accounts {
     #[serde(rename = "auth-type", deserialize_with = "deserialize_authtype", default)]
-    pub auth_type: Option<AuthType>,
-    #[serde(rename = "acct-type", deserialize_with = "deserialize_opt_enum", default)]
-    pub acct_type: Option<AcctType>,
+    pub auth_type:           Option<AuthType>,
+    #[serde(rename = "acct-type", deserialize_with = "deserialize_accttype", default)]
+    pub acct_type:           Option<AcctType>,
     #[serde(default)]
-    pub realm:     Option<String>,
+    pub realm:               Option<String>,
+    #[serde(rename = "auth-cache-timeout", default)]
+    pub auth_cache_timeout:  Option<usize>,
+    #[serde(rename = "auth-cache-size", default)]
+    pub auth_cache_size:     Option<usize>,
+    #[serde(rename = "read-only-users", default)]
+    pub read_only_users:     Vec<String>,
+    #[serde(rename = "max-file-size", default)]
+    pub max_file_size:       Option<Size>,
+    #[serde(rename = "max-file-size-users", default)]
+    pub max_file_size_users: HashMap<String, Size>,
+    #[serde(rename = "upload-rate", default)]
+    pub upload_rate:         Option<Rate>,
+    #[serde(rename = "download-rate", default)]
+    pub download_rate:       Option<Rate>,
+    #[serde(rename = "session-cookie-timeout", default)]
+    pub session_timeout:     Option<u64>,
+    #[serde(rename = "session-cookie-bind-ip", default)]
+    pub session_bind_ip:     Option<bool>,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
 pub struct Pam {
-    pub service:       String,
+    pub service:         String,
     #[serde(rename = "cache-timeout")]
-    pub cache_timeout: Option<usize>,
-    pub threads:       Option<usize>,
+    pub cache_timeout:   Option<usize>,
+    pub threads:         Option<usize>,
+    pub timeout:         Option<u64>,
+    #[serde(rename = "otp-length")]
+    pub otp_length:      Option<usize>,
+    #[serde(rename = "otp-separator")]
+    pub otp_separator:   Option<String>,
+    pub account:         Option<bool>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub session:         Option<PamSession>,
+    pub tty:             Option<String>,
+    pub environment:     Option<bool>,
+    #[serde(rename = "change-password")]
+    pub change_password: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -71,267 +258,2593 @@ pub struct HtPasswd {
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
-pub struct Unix {
-    #[serde(rename = "cache-timeout")]
-    pub cache_timeout: Option<usize>,
-    #[serde(rename = "min-uid", default)]
-    pub min_uid:       Option<u32>,
-    #[serde(rename = "supplementary-groups", default)]
-    pub aux_groups:    bool,
+pub struct HtDigest {
+    pub htdigest:      String,
+    #[serde(rename = "nonce-timeout", default)]
+    pub nonce_timeout: Option<u64>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
-pub struct Location {
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Ldap {
+    pub url:                  String,
     #[serde(default)]
-    pub route:            Vec<String>,
-    #[serde(deserialize_with = "deserialize_methodset", default)]
-    pub methods:          Option<DavMethodSet>,
-    #[serde(deserialize_with = "deserialize_opt_enum", default)]
-    pub auth:             Option<Auth>,
-    #[serde(default, flatten)]
-    pub accounts:         Accounts,
-    #[serde(deserialize_with = "deserialize_enum")]
-    pub handler:          Handler,
+    pub starttls:             bool,
+    #[serde(rename = "tls-verify", default = "default_true")]
+    pub tls_verify:           bool,
+    #[serde(rename = "bind-dn", default)]
+    pub bind_dn:              Option<String>,
+    #[serde(rename = "search-base", default)]
+    pub search_base:          Option<String>,
+    #[serde(rename = "search-filter", default)]
+    pub search_filter:        Option<String>,
+    #[serde(rename = "search-bind-dn", default)]
+    pub search_bind_dn:       Option<String>,
+    #[serde(rename = "search-bind-password", default)]
+    pub search_bind_password: Option<String>,
+    #[serde(rename = "group-base", default)]
+    pub group_base:           Option<String>,
+    #[serde(rename = "group-filter", default)]
+    pub group_filter:         Option<String>,
     #[serde(default)]
-    pub setuid:           bool,
-    pub directory:        String,
-    #[serde(default, alias = "hide-symlinks")]
-    pub hide_symlinks:    Option<bool>,
+    pub timeout:              Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Sql {
+    pub url:     String,
     #[serde(default)]
-    pub indexfile:        Option<String>,
+    pub socket:  Option<String>,
+    pub query:   String,
     #[serde(default)]
-    pub autoindex:        bool,
-    #[serde(
-        rename = "case-insensitive",
-        deserialize_with = "deserialize_opt_enum",
-        default
-    )]
-    pub case_insensitive: Option<CaseInsensitive>,
-    #[serde(deserialize_with = "deserialize_opt_enum", default)]
-    pub on_notfound:      Option<OnNotfound>,
+    pub update:  Option<String>,
+    #[serde(rename = "min-uid", default)]
+    pub min_uid: Option<u32>,
+    #[serde(default)]
+    pub timeout: Option<u64>,
 }
 
-#[derive(FromStr, Debug, Clone, Copy)]
-pub enum Handler {
-    #[from_str = "virtroot"]
-    Virtroot,
-    #[from_str = "filesystem"]
-    Filesystem,
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Sqlite {
+    #[serde(default)]
+    pub path: Option<String>,
 }
 
-#[derive(FromStr, Debug, Clone, Copy)]
-pub enum Auth {
-    #[from_str = "false"]
-    False,
-    #[from_str = "true"]
-    True,
-    #[from_str = "opportunistic"]
-    Opportunistic,
-    #[from_str = "write"]
-    Write,
+impl Sqlite {
+    /// The user database of auth-type "sqlite".
+    pub fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or("/var/lib/webdav-server/users.db")
+    }
 }
 
-#[derive(Debug, Clone)]
-pub enum AuthType {
-    #[cfg(feature = "pam")]
-    Pam,
-    HtPasswd(String),
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PasswordHash {
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub scheme:        Option<HashScheme>,
+    #[serde(rename = "argon2-memory", default)]
+    pub argon2_memory: Option<u32>,
+    #[serde(rename = "argon2-time", default)]
+    pub argon2_time:   Option<u32>,
+    #[serde(rename = "argon2-lanes", default)]
+    pub argon2_lanes:  Option<u32>,
+    #[serde(rename = "scrypt-log-n", default)]
+    pub scrypt_log_n:  Option<u8>,
+    #[serde(rename = "scrypt-r", default)]
+    pub scrypt_r:      Option<u32>,
+    #[serde(rename = "scrypt-p", default)]
+    pub scrypt_p:      Option<u32>,
+    #[serde(rename = "bcrypt-cost", default)]
+    pub bcrypt_cost:   Option<u32>,
+    #[serde(default)]
+    pub rehash:        bool,
 }
 
-#[derive(FromStr, Debug, Clone, Copy)]
-pub enum AcctType {
-    #[from_str = "unix"]
-    Unix,
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum HashScheme {
+    #[from_str = "argon2id"]
+    Argon2id,
+    #[from_str = "scrypt"]
+    Scrypt,
+    #[from_str = "bcrypt"]
+    Bcrypt,
 }
 
-#[derive(FromStr, Debug, Clone, Copy)]
-pub enum CaseInsensitive {
-    #[from_str = "true"]
-    True,
-    #[from_str = "ms"]
-    Ms,
-    #[from_str = "false"]
-    False,
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AppPasswords {
+    #[serde(default)]
+    pub file: Option<String>,
 }
 
-#[derive(FromStr, Debug, Clone, Copy)]
-pub enum OnNotfound {
-    #[from_str = "continue"]
-    Continue,
-    #[from_str = "return"]
-    Return,
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Shares {
+    #[serde(default)]
+    pub file:     Option<String>,
+    #[serde(default)]
+    pub prefix:   Option<String>,
+    #[serde(rename = "max-days", default)]
+    pub max_days: Option<u64>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
-#[serde(untagged)]
-pub enum OneOrManyAddr {
-    One(SocketAddr),
-    Many(Vec<SocketAddr>),
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Maintenance {
+    #[serde(rename = "locks-interval", default)]
+    pub locks_interval:    Option<u64>,
+    #[serde(rename = "trash-interval", default)]
+    pub trash_interval:    Option<u64>,
+    #[serde(rename = "versions-interval", default)]
+    pub versions_interval: Option<u64>,
+    #[serde(rename = "compact-interval", default)]
+    pub compact_interval:  Option<u64>,
+    #[serde(rename = "uploads-interval", default)]
+    pub uploads_interval:  Option<u64>,
+    #[serde(rename = "uploads-max-age", default)]
+    pub uploads_max_age:   Option<u64>,
 }
 
-impl OneOrManyAddr {
-    pub fn is_empty(&self) -> bool {
-        match self {
-            OneOrManyAddr::One(_) => false,
-            OneOrManyAddr::Many(v) => v.is_empty(),
-        }
-    }
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Jobs {
+    #[serde(default)]
+    pub prefix:      Option<String>,
+    #[serde(default)]
+    pub keep:        Option<u64>,
+    #[serde(rename = "max-running", default)]
+    pub max_running: Option<usize>,
 }
 
-impl Default for OneOrManyAddr {
-    fn default() -> Self {
-        OneOrManyAddr::Many(Vec::new())
-    }
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Exec {
+    pub command: Vec<String>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub input:   Option<ExecInput>,
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    #[serde(rename = "min-uid", default)]
+    pub min_uid: Option<u32>,
 }
 
-impl ToSocketAddrs for OneOrManyAddr {
-    type Iter = std::vec::IntoIter<SocketAddr>;
-    fn to_socket_addrs(&self) -> io::Result<std::vec::IntoIter<SocketAddr>> {
-        let i = match self {
-            OneOrManyAddr::Many(ref v) => v.to_owned(),
-            OneOrManyAddr::One(ref s) => vec![*s],
-        };
-        Ok(i.into_iter())
-    }
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum ExecInput {
+    #[from_str = "stdin"]
+    Stdin,
+    #[from_str = "env"]
+    Env,
 }
 
-// keep this here for now, we might implement a enum{(u32, String} later for
-// usernames and groupnames.
-#[allow(unused)]
-pub fn deserialize_user<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
-where D: Deserializer<'de> {
-    let s = String::deserialize(deserializer)?;
-    s.parse::<u32>()
-        .map(|v| Some(v))
-        .map_err(serde::de::Error::custom)
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Jwt {
+    #[serde(rename = "jwks-url", default)]
+    pub jwks_url:     Option<String>,
+    #[serde(rename = "jwks-refresh", default)]
+    pub jwks_refresh: Option<u64>,
+    #[serde(default)]
+    pub key:          Option<String>,
+    #[serde(rename = "key-file", default)]
+    pub key_file:     Option<String>,
+    #[serde(default)]
+    pub algorithms:   Option<Vec<String>>,
+    #[serde(default)]
+    pub issuer:       Option<String>,
+    #[serde(default)]
+    pub audience:     Option<String>,
+    #[serde(default)]
+    pub claim:        Option<String>,
 }
 
-#[allow(unused)]
-pub fn deserialize_group<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
-where D: Deserializer<'de> {
-    let s = String::deserialize(deserializer)?;
-    s.parse::<u32>()
-        .map(|v| Some(v))
-        .map_err(serde::de::Error::custom)
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Oidc {
+    pub issuer:          String,
+    #[serde(rename = "client-id")]
+    pub client_id:       String,
+    #[serde(rename = "client-secret", default)]
+    pub client_secret:   Option<String>,
+    #[serde(rename = "redirect-uri")]
+    pub redirect_uri:    String,
+    #[serde(default)]
+    pub scopes:          Option<Vec<String>>,
+    #[serde(default)]
+    pub claim:           Option<String>,
+    #[serde(default)]
+    pub algorithms:      Option<Vec<String>>,
+    #[serde(rename = "session-timeout", default)]
+    pub session_timeout: Option<u64>,
+    #[serde(rename = "cookie-secret", default)]
+    pub cookie_secret:   Option<String>,
 }
 
-pub fn deserialize_methodset<'de, D>(deserializer: D) -> Result<Option<DavMethodSet>, D::Error>
-where D: Deserializer<'de> {
-    let m = Vec::<String>::deserialize(deserializer)?;
-    DavMethodSet::from_vec(m)
-        .map(|v| Some(v))
-        .map_err(serde::de::Error::custom)
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct S3 {
+    pub endpoint:   String,
+    #[serde(default = "default_s3_region")]
+    pub region:     String,
+    pub bucket:     String,
+    #[serde(rename = "access-key")]
+    pub access_key: String,
+    #[serde(rename = "secret-key")]
+    pub secret_key: String,
+    #[serde(rename = "path-style", default)]
+    pub path_style: bool,
+    #[serde(rename = "part-size", default)]
+    pub part_size:  Option<usize>,
 }
 
-pub fn deserialize_authtype<'de, D>(deserializer: D) -> Result<Option<AuthType>, D::Error>
-where D: Deserializer<'de> {
-    let s = String::deserialize(deserializer)?;
-    if s.starts_with("htpasswd.") {
-        return Ok(Some(AuthType::HtPasswd(s[9..].to_string())));
-    }
-    #[cfg(feature = "pam")]
-    if &s == "pam" {
-        return Ok(Some(AuthType::Pam));
-    }
-    if s == "" {
-        return Ok(None);
-    }
-    Err(serde::de::Error::custom("unknown auth-type"))
+#[derive(Deserialize, Debug, Clone)]
+pub struct Webhook {
+    pub url:     String,
+    #[serde(deserialize_with = "deserialize_vec_enum", default)]
+    pub events:  Option<Vec<WebhookEvent>>,
+    #[serde(default)]
+    pub secret:  Option<String>,
+    #[serde(default)]
+    pub retries: Option<u32>,
+    #[serde(default)]
+    pub timeout: Option<u64>,
 }
 
-pub fn deserialize_opt_enum<'de, D, E>(deserializer: D) -> Result<Option<E>, D::Error>
-where
-    D: Deserializer<'de>,
-    E: std::str::FromStr,
-    E::Err: std::fmt::Display,
-{
-    String::deserialize(deserializer)?
-        .as_str()
-        .parse::<E>()
-        .map(|e| Some(e))
-        .map_err(serde::de::Error::custom)
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum WebhookEvent {
+    #[from_str = "put"]
+    Put,
+    #[from_str = "delete"]
+    Delete,
+    #[from_str = "move"]
+    Move,
+    #[from_str = "copy"]
+    Copy,
+    #[from_str = "mkcol"]
+    Mkcol,
 }
 
-pub fn deserialize_enum<'de, D, E>(deserializer: D) -> Result<E, D::Error>
-where
-    D: Deserializer<'de>,
-    E: std::str::FromStr,
-    E::Err: std::fmt::Display,
-{
-    String::deserialize(deserializer)?
-        .as_str()
-        .parse::<E>()
-        .map_err(serde::de::Error::custom)
+#[derive(Deserialize, Debug, Clone)]
+pub struct Antivirus {
+    #[serde(default)]
+    pub clamd:      Option<String>,
+    #[serde(default)]
+    pub icap:       Option<String>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub mode:       Option<ScanMode>,
+    #[serde(default)]
+    pub quarantine: Option<String>,
+    #[serde(default)]
+    pub status:     Option<u16>,
+    #[serde(rename = "on-error", deserialize_with = "deserialize_opt_enum", default)]
+    pub on_error:   Option<ScanOnError>,
+    #[serde(rename = "max-size", default)]
+    pub max_size:   Option<Size>,
+    #[serde(default)]
+    pub timeout:    Option<u64>,
 }
 
-// Read the TOML config into a config::Config struct.
-pub fn read(toml_file: impl AsRef<Path>) -> io::Result<Config> {
-    let buffer = fs::read_to_string(&toml_file)?;
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum ScanMode {
+    #[from_str = "sync"]
+    Sync,
+    #[from_str = "async"]
+    Async,
+}
 
-    // initial parse.
-    let config: Config = match toml::from_str(&buffer) {
-        Ok(v) => Ok(v),
-        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
-    }?;
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum ScanOnError {
+    #[from_str = "reject"]
+    Reject,
+    #[from_str = "allow"]
+    Allow,
+}
 
-    Ok(config)
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
 }
 
-pub fn build_routes(cfg: &str, config: &mut Config) -> io::Result<()> {
-    let mut builder = Router::builder();
-    for (idx, location) in config.location.iter().enumerate() {
-        for r in &location.route {
-            if let Err(e) = builder.add(r, location.methods.clone(), idx) {
-                let msg = format!("{}: [[location]][{}]: route {}: {}", cfg, idx, r, e);
-                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
-            }
-        }
-    }
-    config.router = builder.build();
-    Ok(())
+#[cfg(feature = "kerberos")]
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Kerberos {
+    #[serde(default)]
+    pub keytab:      Option<String>,
+    #[serde(default)]
+    pub principal:   Option<String>,
+    #[serde(default)]
+    pub realms:      Vec<String>,
+    #[serde(rename = "strip-realm", default)]
+    pub strip_realm: Option<bool>,
 }
 
-pub fn check(cfg: &str, config: &Config) {
-    #[cfg(feature = "pam")]
-    if let Some(AuthType::Pam) = config.accounts.auth_type {
-        if config.pam.service == "" {
-            eprintln!("{}: missing section [pam]", cfg);
-            exit(1);
-        }
-    }
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Runtime {
+    #[serde(rename = "worker-threads", default)]
+    pub worker_threads:   Option<usize>,
+    #[serde(rename = "max-blocking-threads", default)]
+    pub max_blocking:     Option<usize>,
+    #[serde(rename = "metadata-threads", default)]
+    pub metadata_threads: Option<usize>,
+    #[serde(rename = "listing-fanout", default)]
+    pub listing_fanout:   Option<usize>,
+    #[serde(rename = "setuid-pool", default)]
+    pub setuid_pool:      Option<usize>,
+    #[serde(rename = "setuid-pool-idle", default)]
+    pub setuid_pool_idle: Option<u64>,
+}
 
-    if config.server.listen.is_empty() && config.server.tls_listen.is_empty() {
-        eprintln!("{}: [server]: at least one of listen or tls_listen must be set", cfg);
-        exit(1);
-    }
-    if !config.server.tls_listen.is_empty() {
-        if config.server.tls_cert.is_none() {
-            eprintln!("{}: [server]: tls_cert not set", cfg);
-            exit(1);
-        }
-        if config.server.tls_key.is_none() {
-            eprintln!("{}: [server]: tls_key not set", cfg);
-            exit(1);
-        }
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Errors {
+    #[serde(default)]
+    pub enabled:   Option<bool>,
+    #[serde(default)]
+    pub directory: Option<String>,
+    // the pages in the directory, by file name, read in build_routes.
+    #[serde(skip)]
+    pub pages:     HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MimeTypes {
+    #[serde(default)]
+    pub file:       Option<String>,
+    #[serde(default)]
+    pub types:      HashMap<String, String>,
+    #[serde(default)]
+    pub default:    Option<String>,
+    // the types in the file, read in build_routes.
+    #[serde(skip)]
+    pub file_types: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Throttle {
+    #[serde(default)]
+    pub enabled:         Option<bool>,
+    #[serde(rename = "max-failures", default)]
+    pub max_failures:    Option<u32>,
+    #[serde(rename = "max-failures-ip", default)]
+    pub max_failures_ip: Option<u32>,
+    #[serde(default)]
+    pub backoff:         Option<u64>,
+    #[serde(rename = "max-lockout", default)]
+    pub max_lockout:     Option<u64>,
+    #[serde(default)]
+    pub forget:          Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct GeoIp {
+    #[serde(default)]
+    pub database: Option<String>,
+    // the database, read in build_routes.
+    #[serde(skip)]
+    pub reader:   Option<Arc<geoip::Reader>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    #[serde(rename = "connections-per-ip", default)]
+    pub connections_per_ip:   Option<usize>,
+    #[serde(rename = "connections-per-user", default)]
+    pub connections_per_user: Option<usize>,
+    #[serde(rename = "requests-per-ip", default)]
+    pub requests_per_ip:      Option<usize>,
+    #[serde(rename = "requests-per-user", default)]
+    pub requests_per_user:    Option<usize>,
+    #[serde(rename = "request-rate", default)]
+    pub request_rate:         Option<f64>,
+    #[serde(rename = "request-burst", default)]
+    pub request_burst:        Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Admin {
+    #[serde(default)]
+    pub listen:           OneOrManyAddr,
+    #[serde(rename = "health-on-listen", default)]
+    pub health_on_listen: bool,
+    #[serde(rename = "api-listen", default)]
+    pub api_listen:       OneOrManyAddr,
+    #[serde(rename = "api-token", default)]
+    pub api_token:        Option<String>,
+    #[serde(rename = "routes-file", default)]
+    pub routes_file:      Option<String>,
+    #[serde(rename = "allow-from", default)]
+    pub allow_from:       Vec<Cidr>,
+    #[serde(rename = "deny-from", default)]
+    pub deny_from:        Vec<Cidr>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Tracing {
+    #[serde(rename = "otlp-endpoint", default)]
+    pub otlp_endpoint: Option<String>,
+    #[serde(rename = "service-name", default)]
+    pub service_name:  Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Log {
+    #[serde(rename = "auth-failures", default)]
+    pub auth_failures:   Option<String>,
+    #[serde(default)]
+    pub access:          Option<String>,
+    #[serde(rename = "access-format", deserialize_with = "deserialize_opt_enum", default)]
+    pub access_format:   Option<AccessFormat>,
+    #[serde(default)]
+    pub audit:           Option<String>,
+    #[serde(rename = "audit-chain", default)]
+    pub audit_chain:     bool,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub target:          Option<LogTarget>,
+    #[serde(rename = "syslog-facility", default)]
+    pub syslog_facility: Option<String>,
+    #[serde(rename = "syslog-ident", default)]
+    pub syslog_ident:    Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Locks {
+    #[serde(default)]
+    pub shared:          Option<bool>,
+    #[serde(rename = "max-timeout", default)]
+    pub max_timeout:     Option<u64>,
+    #[serde(rename = "default-timeout", default)]
+    pub default_timeout: Option<u64>,
+    #[serde(default)]
+    pub anonymous:       Option<bool>,
+    #[serde(rename = "require-lock", default)]
+    pub require_lock:    Vec<String>,
+    // the require-lock routes, built in build_routes.
+    #[serde(skip)]
+    pub require_router:  Router<()>,
+}
+
+impl Locks {
+    /// Is anything set that changes the default locking behaviour.
+    pub fn is_set(&self) -> bool {
+        self.shared.is_some() || self.max_timeout.is_some() || self.default_timeout.is_some()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Cors {
+    #[serde(default)]
+    pub origins:        Vec<String>,
+    #[serde(default)]
+    pub methods:        Option<Vec<String>>,
+    #[serde(default)]
+    pub headers:        Option<Vec<String>>,
+    #[serde(rename = "expose-headers", default)]
+    pub expose_headers: Option<Vec<String>>,
+    #[serde(default)]
+    pub credentials:    bool,
+    #[serde(rename = "max-age", default)]
+    pub max_age:        Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Unix {
+    #[serde(rename = "cache-timeout")]
+    pub cache_timeout:          Option<usize>,
+    #[serde(rename = "negative-cache-timeout", default)]
+    pub negative_cache_timeout: Option<usize>,
+    #[serde(rename = "cache-size", default)]
+    pub cache_size:             Option<usize>,
+    #[serde(rename = "min-uid", default)]
+    pub min_uid:                Option<u32>,
+    #[serde(rename = "supplementary-groups", default)]
+    pub aux_groups:             bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Sandbox {
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub seccomp:      Option<Seccomp>,
+    #[serde(rename = "pam-seccomp", deserialize_with = "deserialize_opt_enum", default)]
+    pub pam_seccomp:  Option<Seccomp>,
+    #[serde(deserialize_with = "deserialize_vec_enum", default)]
+    pub capabilities: Option<Vec<Capability>>,
+    #[serde(rename = "no-new-privs", default)]
+    pub no_new_privs: bool,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum Capability {
+    #[from_str = "chown"]
+    Chown,
+    #[from_str = "dac-override"]
+    DacOverride,
+    #[from_str = "dac-read-search"]
+    DacReadSearch,
+    #[from_str = "fowner"]
+    Fowner,
+    #[from_str = "setgid"]
+    Setgid,
+    #[from_str = "setuid"]
+    Setuid,
+    #[from_str = "net-bind-service"]
+    NetBindService,
+    #[from_str = "sys-resource"]
+    SysResource,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum Seccomp {
+    #[from_str = "off"]
+    Off,
+    #[from_str = "audit"]
+    Audit,
+    #[from_str = "deny"]
+    Deny,
+    #[from_str = "kill"]
+    Kill,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Location {
+    #[serde(default)]
+    pub route:            Vec<String>,
+    #[serde(deserialize_with = "deserialize_methodset", default)]
+    pub methods:          Option<DavMethodSet>,
+    #[serde(rename = "dav-class", default)]
+    pub dav_class:        Option<Vec<u32>>,
+    #[serde(rename = "allow-from", default)]
+    pub allow_from:       Vec<Cidr>,
+    #[serde(rename = "deny-from", default)]
+    pub deny_from:        Vec<Cidr>,
+    #[serde(rename = "allow-countries", default)]
+    pub allow_countries:  Vec<String>,
+    #[serde(rename = "deny-countries", default)]
+    pub deny_countries:   Vec<String>,
+    #[serde(rename = "request-rate", default)]
+    pub request_rate:     Option<f64>,
+    #[serde(rename = "request-burst", default)]
+    pub request_burst:    Option<u32>,
+    #[serde(rename = "propfind-infinity", deserialize_with = "deserialize_opt_enum", default)]
+    pub infinity:         Option<PropfindInfinity>,
+    #[serde(rename = "propfind-max-depth", default)]
+    pub max_depth:        Option<u32>,
+    #[serde(rename = "propfind-max-resources", default)]
+    pub max_resources:    Option<u64>,
+    #[serde(default)]
+    pub middleware:       Option<Vec<String>>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub auth:             Option<Auth>,
+    #[serde(rename = "require-tls", default)]
+    pub require_tls:      bool,
+    #[serde(default, flatten)]
+    pub accounts:         Accounts,
+    #[serde(deserialize_with = "deserialize_enum")]
+    pub handler:          Handler,
+    #[serde(default)]
+    pub setuid:           bool,
+    pub directory:        String,
+    #[serde(default, alias = "hide-symlinks")]
+    pub hide_symlinks:    Option<bool>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub symlinks:         Option<Symlinks>,
+    #[serde(default)]
+    pub confine:          bool,
+    #[serde(rename = "file-mode", default)]
+    pub file_mode:        Option<String>,
+    #[serde(rename = "dir-mode", default)]
+    pub dir_mode:         Option<String>,
+    #[serde(default)]
+    pub umask:            Option<String>,
+    #[serde(rename = "file-group", default)]
+    pub file_group:       Option<String>,
+    #[serde(rename = "preserve-mode", default)]
+    pub preserve_mode:    bool,
+    #[serde(rename = "atomic-put", default)]
+    pub atomic_put:       bool,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub fsync:            Option<Fsync>,
+    #[serde(default)]
+    pub indexfile:        Option<String>,
+    #[serde(default)]
+    pub autoindex:        Option<bool>,
+    #[serde(rename = "autoindex-template", default)]
+    pub autoindex_tmpl:   Option<String>,
+    #[serde(default)]
+    pub webui:            bool,
+    #[serde(rename = "mime-types", default)]
+    pub mime_types:       HashMap<String, String>,
+    #[serde(rename = "default-type", default)]
+    pub default_type:     Option<String>,
+    #[serde(default)]
+    pub hide:             Vec<String>,
+    #[serde(default)]
+    pub deny:             Vec<String>,
+    #[serde(
+        rename = "listing-order",
+        deserialize_with = "deserialize_opt_enum",
+        default
+    )]
+    pub listing_order:    Option<ListingOrder>,
+    #[serde(rename = "directories-first", default)]
+    pub dirs_first:       Option<bool>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub finder:           Option<Finder>,
+    #[serde(
+        rename = "case-insensitive",
+        deserialize_with = "deserialize_opt_enum",
+        default
+    )]
+    pub case_insensitive: Option<CaseInsensitive>,
+    #[serde(
+        rename = "case-collisions",
+        deserialize_with = "deserialize_opt_enum",
+        default
+    )]
+    pub case_collisions:  Option<CaseCollisions>,
+    #[serde(
+        rename = "non-utf8-names",
+        deserialize_with = "deserialize_opt_enum",
+        default
+    )]
+    pub non_utf8_names:   Option<NonUtf8Names>,
+    #[serde(rename = "legacy-charset", default)]
+    pub legacy_charset:   Option<String>,
+    #[serde(default)]
+    pub windows:          bool,
+    #[serde(rename = "ms-author-via", default)]
+    pub ms_author_via:    Option<bool>,
+    #[serde(rename = "win32-props", default)]
+    pub win32_props:      Option<bool>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub on_notfound:      Option<OnNotfound>,
+    #[serde(rename = "read-only", alias = "readonly", default)]
+    pub read_only:        bool,
+    #[serde(default)]
+    pub acl:              Vec<AclRule>,
+    #[serde(default)]
+    pub template:         Vec<Template>,
+    #[serde(rename = "acl-files", default)]
+    pub acl_files:        bool,
+    #[serde(rename = "acl-default", deserialize_with = "deserialize_opt_enum", default)]
+    pub acl_default:      Option<AclDefault>,
+    #[serde(default)]
+    pub guest:            Option<String>,
+    #[serde(rename = "guest-methods", deserialize_with = "deserialize_methodset", default)]
+    pub guest_methods:    Option<DavMethodSet>,
+    #[serde(rename = "create-directory", default)]
+    pub create_directory: bool,
+    #[serde(rename = "create-mode", default)]
+    pub create_mode:      Option<String>,
+    #[serde(rename = "create-owner", default)]
+    pub create_owner:     Option<u32>,
+    #[serde(rename = "create-group", default)]
+    pub create_group:     Option<u32>,
+    #[serde(default)]
+    pub skeleton:         Option<String>,
+    #[serde(default)]
+    pub alias:            Vec<Alias>,
+    #[serde(default)]
+    pub s3:               Option<String>,
+    #[serde(default)]
+    pub webhooks:         Vec<String>,
+    #[serde(default)]
+    pub antivirus:        bool,
+    #[serde(rename = "trash-dir", default)]
+    pub trash_dir:        Option<String>,
+    #[serde(rename = "trash-retention", default)]
+    pub trash_retention:  Option<u64>,
+    #[serde(rename = "trash-visible", default)]
+    pub trash_visible:    bool,
+    #[serde(rename = "versions-dir", default)]
+    pub versions_dir:     Option<String>,
+    #[serde(rename = "versions-max", default)]
+    pub versions_max:     Option<usize>,
+    #[serde(rename = "versions-days", default)]
+    pub versions_days:    Option<u64>,
+    #[serde(rename = "versions-visible", default)]
+    pub versions_visible: bool,
+    #[serde(default)]
+    pub worm:             bool,
+    #[serde(rename = "worm-retention", default)]
+    pub worm_retention:   Option<u64>,
+    #[serde(default)]
+    pub deltav:           bool,
+    #[serde(default)]
+    pub search:           bool,
+    #[serde(rename = "fulltext-index", default)]
+    pub fulltext_index:   Option<String>,
+    #[serde(rename = "fulltext-max-size", default)]
+    pub fulltext_max:     Option<Size>,
+    #[serde(rename = "oc-chunking", default)]
+    pub oc_chunking:      bool,
+    #[serde(default)]
+    pub tus:              bool,
+    #[serde(default)]
+    pub append:           bool,
+    #[serde(default)]
+    pub compress:         bool,
+    #[serde(rename = "compress-min-size", default)]
+    pub compress_min:     Option<Size>,
+    #[serde(rename = "compress-types", default)]
+    pub compress_types:   Option<Vec<String>>,
+    #[serde(rename = "cache-control", default)]
+    pub cache_control:    Option<String>,
+    #[serde(default)]
+    pub expires:          Option<u64>,
+    #[serde(default)]
+    pub spool:            bool,
+    #[serde(rename = "spool-dir", default)]
+    pub spool_dir:        Option<String>,
+    #[serde(rename = "spool-memory", default)]
+    pub spool_memory:     Option<Size>,
+    #[serde(rename = "spool-retries", default)]
+    pub spool_retries:    Option<u32>,
+    #[serde(rename = "overlay-base", default)]
+    pub overlay_base:     Option<String>,
+    #[serde(rename = "mem-size", default)]
+    pub mem_size:         Option<u64>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub encrypt:          Option<Encrypt>,
+    #[serde(rename = "encrypt-key", default)]
+    pub encrypt_key:      Option<String>,
+    #[serde(rename = "encrypt-names", default)]
+    pub encrypt_names:    bool,
+    #[serde(rename = "dead-props", default)]
+    pub dead_props:       Option<String>,
+    #[serde(rename = "dead-props-max-count", default)]
+    pub props_max_count:  Option<u64>,
+    #[serde(rename = "dead-props-max-size", default)]
+    pub props_max_size:   Option<Size>,
+    #[serde(rename = "dead-props-user-max-count", default)]
+    pub props_user_count: Option<u64>,
+    #[serde(rename = "dead-props-user-max-size", default)]
+    pub props_user_size:  Option<Size>,
+    #[serde(rename = "xattr-props", default)]
+    pub xattr_props:      bool,
+    #[serde(deserialize_with = "deserialize_vec_enum", default)]
+    pub checksums:        Option<Vec<ChecksumType>>,
+    #[serde(deserialize_with = "deserialize_opt_enum", default)]
+    pub etag:             Option<EtagScheme>,
+    #[serde(rename = "lock-db", default)]
+    pub lock_db:          Option<String>,
+    #[serde(rename = "sync-db", default)]
+    pub sync_db:          Option<String>,
+    #[serde(rename = "sync-retention", default)]
+    pub sync_retention:   Option<u64>,
+    #[serde(deserialize_with = "deserialize_quota", default)]
+    pub quota:            Option<Quota>,
+    #[serde(default)]
+    pub watch:            bool,
+    #[serde(default)]
+    pub shares:           bool,
+    // the contents of encrypt-key, read in build_routes.
+    #[serde(skip)]
+    pub master_key:       Option<MasterKey>,
+    // the contents of autoindex-template, read in build_routes.
+    #[serde(skip)]
+    pub autoindex_html:   Option<String>,
+    // hide and deny, compiled in build_routes.
+    #[serde(skip)]
+    pub hide_rules:       Option<Arc<hidefs::Rules>>,
+    // file-group, resolved in build_routes.
+    #[serde(skip)]
+    pub file_gid:         Option<u32>,
+    // legacy-charset, opened in build_routes.
+    #[serde(skip)]
+    pub charset:          Option<Arc<namefs::Charset>>,
+    // the name in the routes-file, if it was added with the admin API.
+    #[serde(skip)]
+    pub runtime_name:     Option<String>,
+}
+
+#[derive(FromStr, Debug, Clone, Copy)]
+pub enum Encrypt {
+    #[from_str = "master"]
+    Master,
+    #[from_str = "password"]
+    Password,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum ChecksumType {
+    #[from_str = "sha1"]
+    Sha1,
+    #[from_str = "sha256"]
+    Sha256,
+    #[from_str = "md5"]
+    Md5,
+    #[from_str = "adler32"]
+    Adler32,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum EtagScheme {
+    #[from_str = "inode"]
+    Inode,
+    #[from_str = "mtime-size"]
+    MtimeSize,
+    #[from_str = "content"]
+    Content,
+}
+
+// A key, that does not show up in debug output.
+#[derive(Clone)]
+pub struct MasterKey(pub Vec<u8>);
+
+impl std::fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Alias {
+    pub path:      String,
+    pub directory: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AclRule {
+    #[serde(default = "default_acl_path")]
+    pub path:   String,
+    #[serde(default)]
+    pub users:  Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(deserialize_with = "deserialize_vec_enum", default)]
+    pub allow:  Option<Vec<AclPerm>>,
+    #[serde(deserialize_with = "deserialize_vec_enum", default)]
+    pub deny:   Option<Vec<AclPerm>>,
+    // the groups, resolved to gids in build_routes.
+    #[serde(skip)]
+    pub gids:   Vec<u32>,
+}
+
+/// Settings of a location for some users, or the members of some groups.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Template {
+    #[serde(default)]
+    pub users:       Vec<String>,
+    #[serde(default)]
+    pub groups:      Vec<String>,
+    #[serde(default)]
+    pub directory:   Option<String>,
+    #[serde(rename = "read-only", alias = "readonly", default)]
+    pub read_only:   Option<bool>,
+    #[serde(rename = "require-tls", default)]
+    pub require_tls: bool,
+    // the groups, resolved to gids in build_routes.
+    #[serde(skip)]
+    pub gids:        Vec<u32>,
+}
+
+fn default_acl_path() -> String {
+    "/".to_string()
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum AclPerm {
+    #[from_str = "read"]
+    Read,
+    #[from_str = "write"]
+    Write,
+    #[from_str = "delete"]
+    Delete,
+    #[from_str = "lock"]
+    Lock,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum AclDefault {
+    #[from_str = "allow"]
+    Allow,
+    #[from_str = "deny"]
+    Deny,
+}
+
+#[derive(FromStr, Debug, Clone, Copy)]
+pub enum Handler {
+    #[from_str = "virtroot"]
+    Virtroot,
+    #[from_str = "filesystem"]
+    Filesystem,
+    #[from_str = "s3"]
+    S3,
+    #[from_str = "mem"]
+    Mem,
+    #[from_str = "caldav"]
+    Caldav,
+    #[from_str = "carddav"]
+    Carddav,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quota {
+    User,
+    Project,
+    Filesystem,
+    None,
+    // a limit in bytes, kept by the server.
+    Limit(u64),
+}
+
+#[derive(FromStr, Debug, Clone, Copy)]
+pub enum Auth {
+    #[from_str = "false"]
+    False,
+    #[from_str = "true"]
+    True,
+    #[from_str = "opportunistic"]
+    Opportunistic,
+    #[from_str = "write"]
+    Write,
+}
+
+#[derive(Debug, Clone)]
+pub enum AuthType {
+    #[cfg(feature = "pam")]
+    Pam,
+    HtPasswd(String),
+    HtDigest(String),
+    Ldap(String),
+    Sql(String),
+    Sqlite,
+    Exec,
+    Jwt(String),
+    Oidc(String),
+    #[cfg(feature = "kerberos")]
+    Kerberos,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum AuthScheme {
+    #[from_str = "basic"]
+    Basic,
+    #[from_str = "digest"]
+    Digest,
+    #[from_str = "bearer"]
+    Bearer,
+    #[from_str = "negotiate"]
+    Negotiate,
+    #[from_str = "client-cert"]
+    ClientCert,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum AcmeChallenge {
+    #[from_str = "http-01"]
+    Http01,
+    #[from_str = "tls-alpn-01"]
+    TlsAlpn01,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum LogTarget {
+    #[from_str = "stderr"]
+    Stderr,
+    #[from_str = "syslog"]
+    Syslog,
+    #[from_str = "journald"]
+    Journald,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum AccessFormat {
+    #[from_str = "json"]
+    Json,
+    #[from_str = "common"]
+    Common,
+    #[from_str = "combined"]
+    Combined,
+}
+
+#[derive(FromStr, Debug, Clone, Copy)]
+pub enum PamSession {
+    #[from_str = "none"]
+    None,
+    #[from_str = "login"]
+    Login,
+    #[from_str = "request"]
+    Request,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum TlsClientAuth {
+    #[from_str = "required"]
+    Required,
+    #[from_str = "optional"]
+    Optional,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum TlsClientUser {
+    #[from_str = "cn"]
+    Cn,
+    #[from_str = "email"]
+    Email,
+    #[from_str = "dns"]
+    Dns,
+}
+
+#[derive(Debug, Clone)]
+pub enum AcctType {
+    Unix,
+    Sql(String),
+    Exec,
+}
+
+#[derive(FromStr, Debug, Clone, Copy)]
+pub enum CaseInsensitive {
+    #[from_str = "true"]
+    True,
+    #[from_str = "ms"]
+    Ms,
+    #[from_str = "ms-macos"]
+    MsMacos,
+    #[from_str = "false"]
+    False,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum Fsync {
+    #[from_str = "none"]
+    None,
+    #[from_str = "data"]
+    Data,
+    #[from_str = "dir"]
+    Dir,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum PropfindInfinity {
+    #[from_str = "deny"]
+    Deny,
+    #[from_str = "allow"]
+    Allow,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum NonUtf8Names {
+    #[from_str = "raw"]
+    Raw,
+    #[from_str = "skip"]
+    Skip,
+    #[from_str = "percent"]
+    Percent,
+    #[from_str = "transcode"]
+    Transcode,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum CaseCollisions {
+    #[from_str = "allow"]
+    Allow,
+    #[from_str = "deny"]
+    Deny,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum Symlinks {
+    #[from_str = "deny"]
+    Deny,
+    #[from_str = "follow-within-root"]
+    FollowWithinRoot,
+    #[from_str = "follow"]
+    Follow,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum ListingOrder {
+    #[from_str = "bytes"]
+    Bytes,
+    #[from_str = "case-insensitive"]
+    CaseInsensitive,
+    #[from_str = "natural"]
+    Natural,
+}
+
+#[derive(FromStr, Debug, Clone, Copy, PartialEq)]
+pub enum Finder {
+    #[from_str = "off"]
+    Off,
+    #[from_str = "discard"]
+    Discard,
+    #[from_str = "xattr"]
+    Xattr,
+}
+
+#[derive(FromStr, Debug, Clone, Copy)]
+pub enum OnNotfound {
+    #[from_str = "continue"]
+    Continue,
+    #[from_str = "return"]
+    Return,
+}
+
+impl Config {
+    /// Find the [[vhost]] for a hostname. The hostname must be lowercase.
+    pub fn vhost(&self, host: &str) -> Option<&Vhost> {
+        self.vhost.iter().find(|v| v.hostname.iter().any(|h| hostname_matches(h, host)))
+    }
+
+    /// All locations, with the name of the section they are in.
+    pub fn locations(&self) -> impl Iterator<Item = (String, &Location)> {
+        let top = self
+            .location
+            .iter()
+            .enumerate()
+            .map(|(idx, l)| (format!("[[location]][{}]", idx), l));
+        let vhosts = self.vhost.iter().enumerate().flat_map(|(vidx, v)| {
+            v.location
+                .iter()
+                .enumerate()
+                .map(move |(idx, l)| (format!("[[vhost]][{}]: [[location]][{}]", vidx, idx), l))
+        });
+        top.chain(vhosts)
+    }
+
+    /// Is there a TLS listener, in [server] or a [[listen]] block.
+    pub fn has_tls_listener(&self) -> bool {
+        !self.server.tls_listen.is_empty() || self.listen.iter().any(|l| l.tls)
+    }
+
+    /// Is there a plaintext listener, in [server] or a [[listen]] block.
+    pub fn has_plain_listener(&self) -> bool {
+        !self.server.listen.is_empty() || self.listen.iter().any(|l| !l.tls)
+    }
+
+    /// Does any location use setuid, run guests as another user, or
+    /// create directories for other users.
+    pub fn any_setuid(&self) -> bool {
+        self.locations()
+            .any(|(_, l)| l.setuid || l.guest.is_some() || l.create_directory)
+    }
+
+    /// Is PAM used for authentication, by default or in any location.
+    #[cfg(feature = "pam")]
+    pub fn uses_pam(&self) -> bool {
+        std::iter::once(&self.accounts.auth_type)
+            .chain(self.locations().map(|(_, l)| &l.accounts.auth_type))
+            .any(|a| matches!(a, Some(AuthType::Pam)))
+    }
+
+    /// Is the built-in user database used, by default or in any location.
+    pub fn uses_sqlite(&self) -> bool {
+        std::iter::once(&self.accounts.auth_type)
+            .chain(self.locations().map(|(_, l)| &l.accounts.auth_type))
+            .any(|a| matches!(a, Some(AuthType::Sqlite)))
+    }
+}
+
+// "*.example.com" matches "a.example.com", but not "example.com" or "a.b.example.com".
+pub fn hostname_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => {
+            match host.split_once('.') {
+                Some((label, rest)) => !label.is_empty() && rest.eq_ignore_ascii_case(domain),
+                None => false,
+            }
+        },
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+pub type OneOrManyAddr = OneOrMany<SocketAddr>;
+
+impl<T: Clone> OneOrMany<T> {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            OneOrMany::One(_) => false,
+            OneOrMany::Many(v) => v.is_empty(),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<T> {
+        match self {
+            OneOrMany::Many(ref v) => v.to_owned(),
+            OneOrMany::One(ref s) => vec![s.clone()],
+        }
+    }
+}
+
+impl<T> Default for OneOrMany<T> {
+    fn default() -> Self {
+        OneOrMany::Many(Vec::new())
+    }
+}
+
+impl ToSocketAddrs for OneOrManyAddr {
+    type Iter = std::vec::IntoIter<SocketAddr>;
+    fn to_socket_addrs(&self) -> io::Result<std::vec::IntoIter<SocketAddr>> {
+        Ok(self.to_vec().into_iter())
+    }
+}
+
+/// A TCP address, or "unix:/path/to/socket".
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(String),
+}
+
+impl<'de> Deserialize<'de> for ListenAddr {
+    fn deserialize<D>(deserializer: D) -> Result<ListenAddr, D::Error>
+    where D: Deserializer<'de> {
+        let s = String::deserialize(deserializer)?;
+        if let Some(path) = s.strip_prefix("unix:") {
+            if !path.starts_with('/') {
+                return Err(serde::de::Error::custom("unix socket path must be absolute"));
+            }
+            return Ok(ListenAddr::Unix(path.to_string()));
+        }
+        s.parse::<SocketAddr>()
+            .map(ListenAddr::Tcp)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+// A number, or a string with a unit.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumOrStr {
+    Num(u64),
+    Str(String),
+}
+
+/// A size in bytes: a number, or a string like "10G".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Size(pub u64);
+
+impl<'de> Deserialize<'de> for Size {
+    fn deserialize<D>(deserializer: D) -> Result<Size, D::Error>
+    where D: Deserializer<'de> {
+        match NumOrStr::deserialize(deserializer)? {
+            NumOrStr::Num(n) => Ok(Size(n)),
+            NumOrStr::Str(s) => {
+                parse_size(&s)
+                    .map(Size)
+                    .ok_or_else(|| serde::de::Error::custom("invalid size"))
+            },
+        }
+    }
+}
+
+/// A rate in bytes per second: a number, or a string like "10MB/s".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate(pub u64);
+
+impl<'de> Deserialize<'de> for Rate {
+    fn deserialize<D>(deserializer: D) -> Result<Rate, D::Error>
+    where D: Deserializer<'de> {
+        let rate = match NumOrStr::deserialize(deserializer)? {
+            NumOrStr::Num(n) => Some(n),
+            NumOrStr::Str(s) => parse_rate(&s),
+        };
+        match rate {
+            Some(0) | None => Err(serde::de::Error::custom("invalid rate")),
+            Some(n) => Ok(Rate(n)),
+        }
+    }
+}
+
+// keep this here for now, we might implement a enum{(u32, String} later for
+// usernames and groupnames.
+#[allow(unused)]
+pub fn deserialize_user<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+    s.parse::<u32>()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+#[allow(unused)]
+pub fn deserialize_group<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+    s.parse::<u32>()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+// Methods, or sets of them, and "-name" for those that are left out.
+// With only those, it is all methods but them.
+pub fn deserialize_methodset<'de, D>(deserializer: D) -> Result<Option<DavMethodSet>, D::Error>
+where D: Deserializer<'de> {
+    let m = Vec::<String>::deserialize(deserializer)?;
+    let (minus, plus): (Vec<_>, Vec<_>) = m.iter().partition(|m| m.starts_with('-'));
+    let mut set = match plus.is_empty() && !minus.is_empty() {
+        true => DavMethodSet::all(),
+        false => DavMethodSet::from_vec(plus).map_err(serde::de::Error::custom)?,
+    };
+    let minus = minus.iter().map(|m| &m[1..]).collect::<Vec<_>>();
+    let minus = DavMethodSet::from_vec(minus).map_err(serde::de::Error::custom)?;
+    for &method in DAV_METHODS.iter().filter(|&&m| minus.contains(m)) {
+        set.remove(method);
+    }
+    Ok(Some(set))
+}
+
+const DAV_METHODS: [DavMethod; 13] = [
+    DavMethod::Head,
+    DavMethod::Get,
+    DavMethod::Put,
+    DavMethod::Patch,
+    DavMethod::Options,
+    DavMethod::PropFind,
+    DavMethod::PropPatch,
+    DavMethod::MkCol,
+    DavMethod::Copy,
+    DavMethod::Move,
+    DavMethod::Delete,
+    DavMethod::Lock,
+    DavMethod::Unlock,
+];
+
+pub fn deserialize_authtype<'de, D>(deserializer: D) -> Result<Option<AuthType>, D::Error>
+where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+    if let Some(section) = s.strip_prefix("htpasswd.") {
+        return Ok(Some(AuthType::HtPasswd(section.to_string())));
+    }
+    if let Some(section) = s.strip_prefix("htdigest.") {
+        return Ok(Some(AuthType::HtDigest(section.to_string())));
+    }
+    if let Some(section) = s.strip_prefix("ldap.") {
+        return Ok(Some(AuthType::Ldap(section.to_string())));
+    }
+    if let Some(section) = s.strip_prefix("sql.") {
+        return Ok(Some(AuthType::Sql(section.to_string())));
+    }
+    if let Some(section) = s.strip_prefix("jwt.") {
+        return Ok(Some(AuthType::Jwt(section.to_string())));
+    }
+    if let Some(section) = s.strip_prefix("oidc.") {
+        return Ok(Some(AuthType::Oidc(section.to_string())));
+    }
+    if &s == "sqlite" {
+        return Ok(Some(AuthType::Sqlite));
+    }
+    if &s == "exec" {
+        return Ok(Some(AuthType::Exec));
+    }
+    #[cfg(feature = "pam")]
+    if &s == "pam" {
+        return Ok(Some(AuthType::Pam));
+    }
+    #[cfg(feature = "kerberos")]
+    if &s == "kerberos" {
+        return Ok(Some(AuthType::Kerberos));
+    }
+    if s.is_empty() {
+        return Ok(None);
+    }
+    Err(serde::de::Error::custom("unknown auth-type"))
+}
+
+pub fn deserialize_accttype<'de, D>(deserializer: D) -> Result<Option<AcctType>, D::Error>
+where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+    if let Some(section) = s.strip_prefix("sql.") {
+        return Ok(Some(AcctType::Sql(section.to_string())));
+    }
+    match s.as_str() {
+        "unix" => Ok(Some(AcctType::Unix)),
+        "exec" => Ok(Some(AcctType::Exec)),
+        "" => Ok(None),
+        _ => Err(serde::de::Error::custom("unknown acct-type")),
+    }
+}
+
+pub fn deserialize_quota<'de, D>(deserializer: D) -> Result<Option<Quota>, D::Error>
+where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+    let quota = match s.as_str() {
+        "user" => Quota::User,
+        "project" => Quota::Project,
+        "filesystem" => Quota::Filesystem,
+        "none" => Quota::None,
+        _ => {
+            let size = parse_size(&s).ok_or_else(|| serde::de::Error::custom("invalid quota"))?;
+            Quota::Limit(size)
+        },
+    };
+    Ok(Some(quota))
+}
+
+/// Parse a size: a number of bytes, or a number with a K, M, G or T suffix.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (num, mult) = match s.char_indices().last()? {
+        (i, 'k') | (i, 'K') => (&s[..i], 1u64 << 10),
+        (i, 'm') | (i, 'M') => (&s[..i], 1 << 20),
+        (i, 'g') | (i, 'G') => (&s[..i], 1 << 30),
+        (i, 't') | (i, 'T') => (&s[..i], 1 << 40),
+        _ => (s, 1),
+    };
+    num.trim().parse::<u64>().ok()?.checked_mul(mult)
+}
+
+/// Parse a rate: a size, optionally followed by "B" or "iB" and "/s".
+pub fn parse_rate(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let s = s.strip_suffix("/s").unwrap_or(s);
+    let s = s.strip_suffix('B').unwrap_or(s);
+    let s = s.strip_suffix('i').filter(|s| !s.ends_with(char::is_numeric)).unwrap_or(s);
+    parse_size(s)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+pub fn deserialize_opt_enum<'de, D, E>(deserializer: D) -> Result<Option<E>, D::Error>
+where
+    D: Deserializer<'de>,
+    E: std::str::FromStr,
+    E::Err: std::fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .as_str()
+        .parse::<E>()
+        .map(|e| Some(e))
+        .map_err(serde::de::Error::custom)
+}
+
+pub fn deserialize_vec_enum<'de, D, E>(deserializer: D) -> Result<Option<Vec<E>>, D::Error>
+where
+    D: Deserializer<'de>,
+    E: std::str::FromStr,
+    E::Err: std::fmt::Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse::<E>().map_err(serde::de::Error::custom))
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+pub fn deserialize_enum<'de, D, E>(deserializer: D) -> Result<E, D::Error>
+where
+    D: Deserializer<'de>,
+    E: std::str::FromStr,
+    E::Err: std::fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .as_str()
+        .parse::<E>()
+        .map_err(serde::de::Error::custom)
+}
+
+// Read the TOML config into a config::Config struct.
+pub fn read(toml_file: impl AsRef<Path>) -> io::Result<Config> {
+    from_value(read_toml(toml_file)?)
+}
+
+/// Read the config file and its includes, with the environment variables
+/// expanded, but not yet as a Config.
+pub fn read_toml(toml_file: impl AsRef<Path>) -> io::Result<toml::Value> {
+    let mut value = read_value(toml_file.as_ref(), 0)?;
+    expand_env(&mut value)?;
+    Ok(value)
+}
+
+pub fn from_value(value: toml::Value) -> io::Result<Config> {
+    value.try_into().map_err(|e| invalid_data(e.to_string()))
+}
+
+// Maximum nesting of include files.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Read a TOML file, and the files it includes.
+fn read_value(path: &Path, depth: usize) -> io::Result<toml::Value> {
+    // errors in included files mention the file name.
+    let name = |e: String| {
+        match depth {
+            0 => e,
+            _ => format!("{}: {}", path.display(), e),
+        }
+    };
+    let buffer = fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), name(e.to_string())))?;
+    let mut value: toml::Value = toml::from_str(&buffer).map_err(|e| invalid_data(name(e.to_string())))?;
+
+    let include = match value.as_table_mut().and_then(|t| t.remove("include")) {
+        Some(include) => include,
+        None => return Ok(value),
+    };
+    let patterns = match include {
+        toml::Value::String(s) => vec![s],
+        toml::Value::Array(a) => a.into_iter().filter_map(|v| v.as_str().map(String::from)).collect(),
+        _ => return Err(invalid_data(name("include: expected a string or a list".into()))),
+    };
+    if depth >= MAX_INCLUDE_DEPTH {
+        return Err(invalid_data(name("include: nested too deeply".into())));
+    }
+
+    // relative to the directory of this file.
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    for pattern in patterns {
+        let pattern = dir.join(pattern);
+        let pattern = pattern.to_string_lossy();
+        let paths = glob::glob(&pattern).map_err(|e| invalid_data(name(format!("include: {}", e))))?;
+        let mut found = false;
+        for path in paths {
+            let path = path.map_err(|e| io::Error::new(e.error().kind(), name(e.to_string())))?;
+            merge(&mut value, read_value(&path, depth + 1)?);
+            found = true;
+        }
+        // a pattern may match nothing, a plain file name must exist.
+        if !found && !pattern.contains(&['*', '?', '['][..]) {
+            let msg = name(format!("include: {}: file not found", pattern));
+            return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+        }
+    }
+    Ok(value)
+}
+
+// Merge an included file. Tables are merged, arrays of tables
+// (like [[location]]) are appended to, other values are replaced.
+fn merge(base: &mut toml::Value, other: toml::Value) {
+    use toml::Value;
+    match (base, other) {
+        (Value::Table(base), Value::Table(other)) => {
+            for (key, value) in other {
+                match base.get_mut(&key) {
+                    Some(b) => merge(b, value),
+                    None => {
+                        base.insert(key, value);
+                    },
+                }
+            }
+        },
+        (Value::Array(base), Value::Array(other))
+            if base.iter().chain(other.iter()).all(|v| v.is_table()) =>
+        {
+            base.extend(other);
+        },
+        (base, other) => *base = other,
+    }
+}
+
+// Expand ${VAR} in all strings.
+fn expand_env(value: &mut toml::Value) -> io::Result<()> {
+    use toml::Value;
+    match value {
+        Value::String(s) => *s = expand_vars(s, |v| std::env::var(v).ok()).map_err(invalid_data)?,
+        Value::Array(a) => a.iter_mut().try_for_each(expand_env)?,
+        Value::Table(t) => t.iter_mut().try_for_each(|(_, v)| expand_env(v))?,
+        _ => {},
+    }
+    Ok(())
+}
+
+// Replace ${VAR} and ${VAR:-default}. "$${" is a literal "${". ${pam:NAME}
+// is left alone, it is expanded per user, from the PAM environment.
+fn expand_vars(s: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(idx) = rest.find("${") {
+        if rest[..idx].ends_with('$') {
+            out.push_str(&rest[..idx]);
+            rest = &rest[idx + 2..];
+            out.push('{');
+            continue;
+        }
+        out.push_str(&rest[..idx]);
+        let end = match rest[idx..].find('}') {
+            Some(end) => idx + end,
+            None => return Err(format!("{}: unterminated ${{", s)),
+        };
+        let var = &rest[idx + 2..end];
+        if var.starts_with("pam:") {
+            out.push_str(&rest[idx..=end]);
+            rest = &rest[end + 1..];
+            continue;
+        }
+        let (name, default) = match var.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (var, None),
+        };
+        match lookup(name).or_else(|| default.map(String::from)) {
+            Some(value) => out.push_str(&value),
+            None => return Err(format!("${{{}}}: environment variable not set", name)),
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn build_router(cfg: &str, section: &str, locations: &[Location]) -> io::Result<Router<usize>> {
+    let mut builder = Router::builder();
+    for (idx, location) in locations.iter().enumerate() {
+        for r in &location.route {
+            if let Err(e) = builder.add(r, location.methods, idx) {
+                let msg = format!("{}: {}[[location]][{}]: route {}: {}", cfg, section, idx, r, e);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+        }
+    }
+    Ok(builder.build())
+}
+
+pub fn build_routes(cfg: &str, config: &mut Config) -> io::Result<()> {
+    config.router = build_router(cfg, "", &config.location)?;
+    let mut builder = Router::builder();
+    let methods = DavMethodSet::from_vec(vec!["PUT", "DELETE"]).ok();
+    for r in &config.locks.require_lock {
+        if let Err(e) = builder.add(r, methods, ()) {
+            let msg = format!("{}: [locks]: require-lock {}: {}", cfg, r, e);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+    }
+    config.locks.require_router = builder.build();
+    resolve_acl_groups(cfg, "", &mut config.location)?;
+    resolve_file_groups(cfg, "", &mut config.location)?;
+    read_master_keys(cfg, "", &mut config.location)?;
+    read_templates(cfg, "", &mut config.location)?;
+    compile_hide_rules(cfg, "", &mut config.location)?;
+    open_charsets(cfg, "", &mut config.location)?;
+    if let Some(ref dir) = config.errors.directory {
+        config.errors.pages = errorpage::read_dir(dir).map_err(|e| {
+            let msg = format!("{}: [errors]: directory {}: {}", cfg, dir, e);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })?;
+    }
+    if let Some(ref file) = config.mime.file {
+        config.mime.file_types = mimetypes::read_file(file).map_err(|e| {
+            let msg = format!("{}: [mime-types]: file {}: {}", cfg, file, e);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })?;
+    }
+    if let Some(ref file) = config.geoip.database {
+        let reader = geoip::Reader::open(file).map_err(|e| {
+            let msg = format!("{}: [geoip]: database {}: {}", cfg, file, e);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })?;
+        config.geoip.reader = Some(Arc::new(reader));
+    }
+    mimetypes::normalize(&mut config.mime.types);
+    config.location.iter_mut().for_each(|l| mimetypes::normalize(&mut l.mime_types));
+    for (idx, vhost) in config.vhost.iter_mut().enumerate() {
+        let section = format!("[[vhost]][{}]: ", idx);
+        for location in vhost.location.iter_mut().filter(|l| l.accounts.realm.is_none()) {
+            location.accounts.realm = vhost.realm.clone();
+        }
+        vhost.router = build_router(cfg, &section, &vhost.location)?;
+        resolve_acl_groups(cfg, &section, &mut vhost.location)?;
+        resolve_file_groups(cfg, &section, &mut vhost.location)?;
+        read_master_keys(cfg, &section, &mut vhost.location)?;
+        read_templates(cfg, &section, &mut vhost.location)?;
+        compile_hide_rules(cfg, &section, &mut vhost.location)?;
+        open_charsets(cfg, &section, &mut vhost.location)?;
+        vhost.location.iter_mut().for_each(|l| mimetypes::normalize(&mut l.mime_types));
+    }
+    Ok(())
+}
+
+// The gids of groups, or the first one that is unknown.
+fn group_gids(groups: &[String]) -> Result<Vec<u32>, &String> {
+    let gid = |group| nix::unistd::Group::from_name(group).ok().flatten().map(|g| g.gid.as_raw());
+    groups.iter().map(|group| gid(group).ok_or(group)).collect()
+}
+
+// Look up the gids of the groups in the ACL rules and the templates.
+fn resolve_acl_groups(cfg: &str, section: &str, locations: &mut [Location]) -> io::Result<()> {
+    for (idx, location) in locations.iter_mut().enumerate() {
+        let unknown = |key: &str, group: &String| {
+            let msg = format!("{}: {}[[location]][{}]: {}: unknown group {}", cfg, section, idx, key, group);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        };
+        for rule in location.acl.iter_mut() {
+            rule.gids = group_gids(&rule.groups).map_err(|g| unknown("acl", g))?;
+        }
+        for template in location.template.iter_mut() {
+            template.gids = group_gids(&template.groups).map_err(|g| unknown("template", g))?;
+        }
+    }
+    Ok(())
+}
+
+// The file-group of new files, a name or a number.
+fn resolve_file_groups(cfg: &str, section: &str, locations: &mut [Location]) -> io::Result<()> {
+    for (idx, location) in locations.iter_mut().enumerate() {
+        let group = match location.file_group {
+            Some(ref group) => group,
+            None => continue,
+        };
+        let gid = match group.parse::<u32>() {
+            Ok(gid) => Some(gid),
+            Err(_) => nix::unistd::Group::from_name(group).ok().flatten().map(|g| g.gid.as_raw()),
+        };
+        if gid.is_none() {
+            let msg = format!(
+                "{}: {}[[location]][{}]: file-group: unknown group {}",
+                cfg, section, idx, group
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        location.file_gid = gid;
+    }
+    Ok(())
+}
+
+// Read the encrypt-key files: 32 bytes, as 64 hex digits.
+fn read_master_keys(cfg: &str, section: &str, locations: &mut [Location]) -> io::Result<()> {
+    for (idx, location) in locations.iter_mut().enumerate() {
+        let file = match location.encrypt_key {
+            Some(ref file) => file,
+            None => continue,
+        };
+        let error = |msg: String| {
+            let msg = format!("{}: {}[[location]][{}]: encrypt-key {}: {}", cfg, section, idx, file, msg);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        };
+        let data = std::fs::read_to_string(file).map_err(|e| error(e.to_string()))?;
+        let hex = data.trim();
+        let key = (0..hex.len())
+            .step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .filter(|k| k.len() == 32)
+            .ok_or_else(|| error("must be 64 hex digits".to_string()))?;
+        location.master_key = Some(MasterKey(key));
+    }
+    Ok(())
+}
+
+// Read the autoindex-template files.
+fn read_templates(cfg: &str, section: &str, locations: &mut [Location]) -> io::Result<()> {
+    for (idx, location) in locations.iter_mut().enumerate() {
+        if let Some(ref file) = location.autoindex_tmpl {
+            let html = std::fs::read_to_string(file).map_err(|e| {
+                let msg = format!(
+                    "{}: {}[[location]][{}]: autoindex-template {}: {}",
+                    cfg, section, idx, file, e
+                );
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            })?;
+            location.autoindex_html = Some(html);
+        }
+    }
+    Ok(())
+}
+
+// Compile the hide and deny patterns.
+fn compile_hide_rules(cfg: &str, section: &str, locations: &mut [Location]) -> io::Result<()> {
+    for (idx, location) in locations.iter_mut().enumerate() {
+        if location.hide.is_empty() && location.deny.is_empty() {
+            continue;
+        }
+        let case_sensitive = matches!(location.case_insensitive, None | Some(CaseInsensitive::False));
+        let rules = hidefs::Rules::new(&location.hide, &location.deny, case_sensitive).map_err(|e| {
+            let msg = format!("{}: {}[[location]][{}]: {}", cfg, section, idx, e);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })?;
+        location.hide_rules = Some(Arc::new(rules));
+    }
+    Ok(())
+}
+
+// Open the legacy-charset conversions, now: iconv may not work in a chroot.
+fn open_charsets(cfg: &str, section: &str, locations: &mut [Location]) -> io::Result<()> {
+    for (idx, location) in locations.iter_mut().enumerate() {
+        if let Some(ref name) = location.legacy_charset {
+            let charset = namefs::Charset::open(name).map_err(|e| {
+                let msg = format!("{}: {}[[location]][{}]: {}", cfg, section, idx, e);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            })?;
+            location.charset = Some(Arc::new(charset));
+        }
+    }
+    Ok(())
+}
+
+/// Settings that need a restart to change are copied from the running
+/// config into a newly read one. Returns the sections that had changes.
+pub fn keep_restart_only(old: &Config, new: &mut Config) -> Vec<&'static str> {
+    let mut skipped = Vec::new();
+
+    // in [server], only trusted_proxies, identification, read_only and the
+    // headers for https can change.
+    let mut server = old.server.clone();
+    server.trusted_proxies = new.server.trusted_proxies.clone();
+    server.identification = new.server.identification.clone();
+    server.read_only = new.server.read_only;
+    server.hsts = new.server.hsts.clone();
+    server.tls_headers = new.server.tls_headers.clone();
+    if server != new.server {
+        skipped.push("[server]");
+    }
+    new.server = server;
+
+    if new.listen != old.listen {
+        skipped.push("[[listen]]");
+        new.listen = old.listen.clone();
+    }
+    if new.acme != old.acme {
+        skipped.push("[acme]");
+        new.acme = old.acme.clone();
+    }
+    if new.runtime != old.runtime {
+        skipped.push("[runtime]");
+        new.runtime = old.runtime.clone();
+    }
+    if new.sandbox != old.sandbox {
+        skipped.push("[sandbox]");
+        new.sandbox = old.sandbox.clone();
+    }
+    if new.throttle != old.throttle {
+        skipped.push("[throttle]");
+        new.throttle = old.throttle.clone();
+    }
+    if new.log != old.log {
+        skipped.push("[log]");
+        new.log = old.log.clone();
+    }
+    if new.pam.threads != old.pam.threads || new.pam.timeout != old.pam.timeout {
+        skipped.push("[pam] threads / timeout");
+        new.pam.threads = old.pam.threads;
+        new.pam.timeout = old.pam.timeout;
+    }
+    #[cfg(feature = "kerberos")]
+    if new.kerberos.keytab != old.kerberos.keytab {
+        skipped.push("[kerberos] keytab");
+        new.kerberos.keytab = old.kerberos.keytab.clone();
+    }
+
+    // certificates are loaded once, the running ones stay in use.
+    let certs = |c: &Config| {
+        c.vhost
+            .iter()
+            .filter(|v| v.tls_cert.is_some())
+            .map(|v| (v.hostname.clone(), v.tls_cert.clone(), v.tls_key.clone()))
+            .collect::<Vec<_>>()
+    };
+    if certs(new) != certs(old) {
+        skipped.push("[[vhost]] tls-cert / tls-key");
+    }
+    skipped
+}
+
+/// The binary needs something to listen on (a server that is embedded
+/// does not).
+pub fn validate_listeners(config: &Config) -> Result<(), String> {
+    let activated = std::env::var_os("LISTEN_FDS").is_some();
+    if !config.has_plain_listener() && !config.has_tls_listener() && !activated {
+        return Err("[server]: listen or tls_listen must be set, or a [[listen]] block".into());
+    }
+    Ok(())
+}
+
+// request-rate and request-burst.
+fn check_rate(section: &str, rate: Option<f64>, burst: Option<u32>) -> Result<(), String> {
+    match rate {
+        Some(rate) if !(rate > 0.0 && rate.is_finite()) => {
+            Err(format!("{}: request-rate: must be more than 0", section))
+        },
+        None if burst.is_some() => Err(format!("{}: request-burst: only used with request-rate", section)),
+        _ if burst == Some(0) => Err(format!("{}: request-burst: cannot be 0", section)),
+        _ => Ok(()),
+    }
+}
+
+/// Check the config for missing or conflicting settings.
+pub fn validate(config: &Config) -> Result<(), String> {
+    #[cfg(feature = "pam")]
+    if let Some(AuthType::Pam) = config.accounts.auth_type {
+        if config.pam.service.is_empty() {
+            return Err("missing section [pam]".into());
+        }
+    }
+    if config.pam.otp_length.is_some() && config.pam.otp_separator.is_some() {
+        return Err("[pam]: set only one of otp-length and otp-separator".into());
+    }
+    if config.pam.otp_length == Some(0) {
+        return Err("[pam]: otp-length cannot be 0".into());
+    }
+    if config.pam.otp_separator.as_deref() == Some("") {
+        return Err("[pam]: otp-separator cannot be empty".into());
+    }
+    for (section, location) in config.locations() {
+        let templates = location.template.iter().filter_map(|t| t.directory.as_deref());
+        let mut dirs = std::iter::once(location.directory.as_str()).chain(templates);
+        if dirs.any(|d| d.contains("${pam:")) && config.pam.environment != Some(true) {
+            return Err(format!("{}: directory: ${{pam:...}} needs [pam] environment = true", section));
+        }
+    }
+    if let Some(ref url) = config.pam.change_password {
+        if !url.starts_with('/') {
+            return Err(format!("[pam]: change-password {}: must start with /", url));
+        }
+    }
+    let limits = &config.limits;
+    let limits = [
+        limits.connections_per_ip,
+        limits.connections_per_user,
+        limits.requests_per_ip,
+        limits.requests_per_user,
+    ];
+    if limits.contains(&Some(0)) {
+        return Err("[limits]: limits cannot be 0".into());
+    }
+    check_rate("[limits]", config.limits.request_rate, config.limits.request_burst)?;
+    if let Some(ref prefix) = config.shares.prefix {
+        if !prefix.starts_with('/') || prefix.ends_with('/') {
+            return Err(format!("[shares]: prefix {}: must start, and not end, with /", prefix));
+        }
+    }
+    if let Some(ref name) = config.log.syslog_facility {
+        if crate::logger::facility(name).is_none() {
+            return Err(format!("[log]: syslog-facility {}: unknown facility", name));
+        }
+    }
+    if !config.admin.api_listen.is_empty() && config.admin.api_token.as_deref().unwrap_or("").is_empty() {
+        return Err("[admin]: api-listen needs an api-token".into());
+    }
+    for (name, webhook) in &config.webhook {
+        let ok = webhook.url.parse::<http::Uri>().ok().and_then(|u| u.scheme_str().map(|s| s.to_string()));
+        if !matches!(ok.as_deref(), Some("http") | Some("https")) {
+            return Err(format!("[webhook.{}]: url {}: not a http(s) url", name, webhook.url));
+        }
+    }
+    if let Some(ref av) = config.antivirus {
+        if av.clamd.is_some() == av.icap.is_some() {
+            return Err("[antivirus]: set one of clamd and icap".into());
+        }
+        if let Some(ref url) = av.icap {
+            let ok = url.parse::<http::Uri>().ok();
+            let ok = ok.filter(|u| u.scheme_str() == Some("icap") && u.host().is_some());
+            if ok.is_none() {
+                return Err(format!("[antivirus]: icap {}: not an icap:// url", url));
+            }
+        }
+        if let Some(status) = av.status {
+            if !(400..=599).contains(&status) {
+                return Err(format!("[antivirus]: status {}: not a 4xx or 5xx status", status));
+            }
+        }
+    }
+    if let Some(ref endpoint) = config.tracing.otlp_endpoint {
+        let ok = endpoint.parse::<http::Uri>().ok().and_then(|u| u.scheme_str().map(|s| s.to_string()));
+        if !matches!(ok.as_deref(), Some("http") | Some("https")) {
+            return Err(format!("[tracing]: otlp-endpoint {}: not a http(s) url", endpoint));
+        }
+    }
+    if let Some(ref caps) = config.sandbox.capabilities {
+        let switch = caps.contains(&Capability::Setuid) && caps.contains(&Capability::Setgid);
+        if config.any_setuid() && !switch {
+            return Err("[sandbox]: capabilities: setuid locations need setuid and setgid".into());
+        }
+    }
+    if config.locks.max_timeout == Some(0) || config.locks.default_timeout == Some(0) {
+        return Err("[locks]: max-timeout and default-timeout cannot be 0".into());
+    }
+    if let (Some(max), Some(default)) = (config.locks.max_timeout, config.locks.default_timeout) {
+        if default > max {
+            return Err("[locks]: default-timeout cannot be larger than max-timeout".into());
+        }
+    }
+
+    let auth_types = std::iter::once(("[accounts]".to_string(), &config.accounts.auth_type))
+        .chain(config.locations().map(|(section, l)| (section, &l.accounts.auth_type)));
+    for (section, auth_type) in auth_types {
+        match auth_type {
+            Some(AuthType::Ldap(name)) => {
+                match config.ldap.get(name) {
+                    None => {
+                        return Err(format!("{}: auth-type: missing section [ldap.{}]", section, name));
+                    },
+                    Some(ldap) => {
+                        if ldap.bind_dn.is_none() && ldap.search_base.is_none() {
+                            return Err(format!("[ldap.{}]: one of bind-dn or search-base must be set", name));
+                        }
+                    },
+                }
+            },
+            Some(AuthType::Jwt(name)) => {
+                match config.jwt.get(name) {
+                    None => {
+                        return Err(format!("{}: auth-type: missing section [jwt.{}]", section, name));
+                    },
+                    Some(jwt) => {
+                        let keys = [jwt.key.is_some(), jwt.key_file.is_some(), jwt.jwks_url.is_some()];
+                        if keys.iter().filter(|k| **k).count() != 1 {
+                            return Err(format!(
+                                "[jwt.{}]: exactly one of key, key-file or jwks-url must be set",
+                                name
+                            ));
+                        }
+                    },
+                }
+            },
+            Some(AuthType::Oidc(name)) if !config.oidc.contains_key(name) => {
+                return Err(format!("{}: auth-type: missing section [oidc.{}]", section, name));
+            },
+            Some(AuthType::HtDigest(name)) if !config.htdigest.contains_key(name) => {
+                return Err(format!("{}: auth-type: missing section [htdigest.{}]", section, name));
+            },
+            Some(AuthType::Sql(name)) if !config.sql.contains_key(name) => {
+                return Err(format!("{}: auth-type: missing section [sql.{}]", section, name));
+            },
+            Some(AuthType::Sqlite) if cfg!(not(feature = "sqlite")) => {
+                return Err(format!("{}: auth-type sqlite: not built with the sqlite feature", section));
+            },
+            Some(AuthType::Exec) if config.exec.is_none() => {
+                return Err(format!("{}: auth-type: missing section [exec]", section));
+            },
+            _ => {},
+        }
+    }
+    let acct_types = std::iter::once(("[accounts]".to_string(), &config.accounts.acct_type))
+        .chain(config.locations().map(|(section, l)| (section, &l.accounts.acct_type)));
+    for (section, acct_type) in acct_types {
+        if let Some(AcctType::Sql(name)) = acct_type {
+            if !config.sql.contains_key(name) {
+                return Err(format!("{}: acct-type: missing section [sql.{}]", section, name));
+            }
+        }
+        if let Some(AcctType::Exec) = acct_type {
+            if config.exec.is_none() {
+                return Err(format!("{}: acct-type: missing section [exec]", section));
+            }
+        }
+    }
+    for (name, oidc) in &config.oidc {
+        for (key, value) in [("issuer", &oidc.issuer), ("redirect-uri", &oidc.redirect_uri)] {
+            match url::Url::parse(value) {
+                Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {},
+                _ => return Err(format!("[oidc.{}]: {}: {}: not a http(s) url", name, key, value)),
+            }
+        }
+    }
+    if let Some(ref exec) = config.exec {
+        if exec.command.is_empty() || !exec.command[0].starts_with('/') {
+            return Err("[exec]: command: must be a list, starting with an absolute path".into());
+        }
+        // the server's filter does not allow execve.
+        if config.sandbox.seccomp.unwrap_or(Seccomp::Off) != Seccomp::Off {
+            return Err("[exec]: does not work with [sandbox] seccomp".into());
+        }
+    }
+    for (name, sql) in &config.sql {
+        if let Err(e) = crate::sql::Url::parse(&sql.url) {
+            return Err(format!("[sql.{}]: url: {}", name, e));
+        }
+        if !sql.query.contains("{user}") {
+            return Err(format!("[sql.{}]: query: must have {{user}} in it", name));
+        }
+        let update = sql.update.as_deref().unwrap_or("{user}{password}");
+        if !update.contains("{user}") || !update.contains("{password}") {
+            return Err(format!("[sql.{}]: update: must have {{user}} and {{password}} in it", name));
+        }
+    }
+    if let Some(prefix) = config.jobs.as_ref().and_then(|j| j.prefix.as_ref()) {
+        if !prefix.starts_with('/') || !prefix.ends_with('/') || prefix.len() < 3 {
+            return Err(format!("[jobs]: prefix: {}: must start and end with a /", prefix));
+        }
+    }
+    if let Err(e) = crate::passhash::check(&config.passwords) {
+        return Err(format!("[password-hash]: {}", e));
+    }
+
+    if let Some(ref mode) = config.server.unix_socket_mode {
+        if u32::from_str_radix(mode, 8).map(|m| m > 0o777).unwrap_or(true) {
+            return Err(format!("[server]: unix_socket_mode: invalid mode {}", mode));
+        }
+    }
+    if let Some(ref acme) = config.acme {
+        if acme.domains.is_empty() {
+            return Err("[acme]: domains not set".into());
+        }
+        if acme.state_dir.is_empty() {
+            return Err("[acme]: state-dir not set".into());
+        }
+        if !config.has_tls_listener() {
+            return Err("[acme]: [server] tls_listen not set".into());
+        }
+        if config.server.tls_cert.is_some() || config.server.tls_key.is_some() {
+            return Err("[acme]: cannot be used together with [server] tls_cert / tls_key".into());
+        }
+        if acme.challenge == Some(AcmeChallenge::Http01) && !config.has_plain_listener() {
+            return Err("[acme]: http-01 challenge needs a [server] listen address (port 80)".into());
+        }
+    } else if config.has_tls_listener() {
+        // with per-vhost certificates, the default certificate is optional.
+        let vhost_certs = config.vhost.iter().any(|v| v.tls_cert.is_some());
+        if config.server.tls_cert.is_none() && (!vhost_certs || config.server.tls_key.is_some()) {
+            return Err("[server]: tls_cert not set".into());
+        }
+        if config.server.tls_key.is_none() && (!vhost_certs || config.server.tls_cert.is_some()) {
+            return Err("[server]: tls_key not set".into());
+        }
+    }
+    #[cfg(not(feature = "quic"))]
+    if !config.server.quic_listen.is_empty() {
+        return Err("[server]: quic_listen: not built with the quic feature".into());
+    }
+    if !config.server.quic_listen.is_empty() {
+        if config.server.tls_cert.is_none() || config.server.tls_key.is_none() {
+            return Err("[server]: quic_listen needs tls_cert and tls_key".into());
+        }
+        if config.server.tls_client_ca.is_some() {
+            return Err("[server]: quic_listen cannot be used with tls_client_ca".into());
+        }
+    }
+    if config.server.tls_client_ca.is_none() &&
+        (config.server.tls_client_auth.is_some() || config.server.tls_client_user.is_some())
+    {
+        return Err("[server]: tls_client_ca not set".into());
+    }
+
+    let hsts = config.server.hsts.iter().map(|v| ("Strict-Transport-Security", v.as_str()));
+    for (name, value) in config.server.tls_headers.iter().map(|(n, v)| (n.as_str(), v.as_str())).chain(hsts) {
+        if http::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(format!("[server]: tls-headers: {}: invalid header name", name));
+        }
+        if http::header::HeaderValue::from_str(value).is_err() {
+            return Err(format!("[server]: {}: {}: invalid header value", name, value));
+        }
+    }
+
+    for (idx, listen) in config.listen.iter().enumerate() {
+        if listen.address.is_empty() {
+            return Err(format!("[[listen]][{}]: address not set", idx));
+        }
+        let unix = listen.address.to_vec().iter().any(|a| matches!(a, ListenAddr::Unix(_)));
+        if listen.tls && unix {
+            return Err(format!("[[listen]][{}]: no TLS on unix sockets", idx));
+        }
+        let client_cert = listen.auth_schemes.iter().flatten().any(|s| *s == AuthScheme::ClientCert);
+        if client_cert && (!listen.tls || config.server.tls_client_ca.is_none()) {
+            return Err(format!("[[listen]][{}]: client-cert needs tls and [server] tls_client_ca", idx));
+        }
+        if listen.redirect_https && listen.tls {
+            return Err(format!("[[listen]][{}]: redirect-https: not on a tls listener", idx));
+        }
+        if listen.https_port.is_some() && !listen.redirect_https {
+            return Err(format!("[[listen]][{}]: https-port: redirect-https is not set", idx));
+        }
+    }
+
+    for (name, s3) in &config.s3 {
+        if s3.part_size.map(|p| p < 5).unwrap_or(false) {
+            return Err(format!("[s3.{}]: part-size: the minimum is 5 (MiB)", name));
+        }
+        if s3.endpoint.parse::<http::Uri>().map(|u| u.host().is_none()).unwrap_or(true) {
+            return Err(format!("[s3.{}]: endpoint: invalid URL {}", name, s3.endpoint));
+        }
+    }
+
+    for (idx, vhost) in config.vhost.iter().enumerate() {
+        if vhost.hostname.is_empty() {
+            return Err(format!("[[vhost]][{}]: hostname not set", idx));
+        }
+        if vhost.tls_cert.is_some() != vhost.tls_key.is_some() {
+            return Err(format!("[[vhost]][{}]: set both tls-cert and tls-key, or neither", idx));
+        }
+        if vhost.tls_cert.is_some() && !config.has_tls_listener() {
+            return Err(format!("[[vhost]][{}]: tls-cert set, but [server] tls_listen is not", idx));
+        }
     }
 
-    for (idx, location) in config.location.iter().enumerate() {
+    for (section, location) in config.locations() {
+        for (idx, alias) in location.alias.iter().enumerate() {
+            let name = alias.path.strip_prefix('/').unwrap_or_default().trim_end_matches('/');
+            if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                let msg = "path must be one directory, like \"/shared\"";
+                return Err(format!("{}: alias[{}]: {}", section, idx, msg));
+            }
+            if !matches!(location.handler, Handler::Filesystem) {
+                let msg = "only supported with handler = \"filesystem\"";
+                return Err(format!("{}: alias[{}]: {}", section, idx, msg));
+            }
+        }
+        if matches!(location.handler, Handler::S3) {
+            if cfg!(not(feature = "s3")) {
+                return Err(format!("{}: handler = \"s3\": not built with the s3 feature", section));
+            }
+            match location.s3 {
+                Some(ref name) if config.s3.contains_key(name) => {},
+                Some(ref name) => return Err(format!("{}: s3: [s3.{}] not found", section, name)),
+                None => return Err(format!("{}: handler = \"s3\", but s3 is not set", section)),
+            }
+            if location.setuid || location.acl_files || location.create_directory {
+                let msg = "setuid, acl-files and create-directory cannot be used with s3";
+                return Err(format!("{}: {}", section, msg));
+            }
+        }
+        for name in &location.webhooks {
+            if !config.webhook.contains_key(name) {
+                return Err(format!("{}: webhooks: [webhook.{}] not found", section, name));
+            }
+        }
+        if let Some(ref classes) = location.dav_class {
+            if !classes.contains(&1) || classes.iter().any(|c| !(1..=3).contains(c)) {
+                return Err(format!("{}: dav-class: must have 1, and only 1, 2 and 3", section));
+            }
+        }
+        if location.antivirus && config.antivirus.is_none() {
+            return Err(format!("{}: antivirus: section [antivirus] not found", section));
+        }
+        for (idx, template) in location.template.iter().enumerate() {
+            if template.users.is_empty() && template.groups.is_empty() {
+                return Err(format!("{}: template[{}]: set users or groups", section, idx));
+            }
+            if template.directory.is_none() && template.read_only.is_none() && !template.require_tls {
+                let msg = "set directory, read-only or require-tls";
+                return Err(format!("{}: template[{}]: {}", section, idx, msg));
+            }
+        }
+        if let Some(ref value) = location.cache_control {
+            if http::header::HeaderValue::from_str(value).is_err() {
+                return Err(format!("{}: cache-control: {}: invalid header value", section, value));
+            }
+        }
+        if location.shares && config.shares.file.is_none() {
+            return Err(format!("{}: shares: no file in section [shares]", section));
+        }
+        let dirs = [("trash-dir", &location.trash_dir), ("versions-dir", &location.versions_dir)];
+        for (name, dir) in dirs.iter() {
+            match dir.as_deref().map(|t| t.trim_matches('/')) {
+                Some("") | Some(".") | Some("..") => {
+                    return Err(format!("{}: {}: invalid name", section, name));
+                },
+                Some(dir) if dir.contains('/') => {
+                    return Err(format!("{}: {}: must be a directory in the root", section, name));
+                },
+                Some(_) if matches!(location.handler, Handler::Virtroot) => {
+                    let msg = "cannot be used with handler = \"virtroot\"";
+                    return Err(format!("{}: {}: {}", section, name, msg));
+                },
+                _ => {},
+            }
+        }
+        if location.worm_retention.is_some() && !location.worm {
+            return Err(format!("{}: worm-retention: worm is not set", section));
+        }
+        if location.trash_dir.is_none() && (location.trash_retention.is_some() || location.trash_visible) {
+            return Err(format!("{}: trash-retention, trash-visible: trash-dir is not set", section));
+        }
+        let versions = location.versions_max.is_some() || location.versions_days.is_some();
+        if location.versions_dir.is_none() && (versions || location.versions_visible) {
+            let msg = "versions-max, versions-days, versions-visible: versions-dir is not set";
+            return Err(format!("{}: {}", section, msg));
+        }
+        if location.deltav && location.versions_dir.is_none() {
+            return Err(format!("{}: deltav: versions-dir is not set", section));
+        }
+        if location.versions_max == Some(0) {
+            return Err(format!("{}: versions-max cannot be 0", section));
+        }
+        if location.trash_dir.is_some() && location.trash_dir == location.versions_dir {
+            return Err(format!("{}: trash-dir and versions-dir must be different", section));
+        }
+        if location.spool && matches!(location.handler, Handler::Virtroot | Handler::Mem) {
+            return Err(format!("{}: spool: cannot be used with handler = \"virtroot\" or \"mem\"", section));
+        }
+        let spool_opts = location.spool_dir.is_some() || location.spool_memory.is_some();
+        if !location.spool && (spool_opts || location.spool_retries.is_some()) {
+            return Err(format!("{}: spool-dir, spool-memory, spool-retries: spool is not set", section));
+        }
+        if location.overlay_base.is_some() && !matches!(location.handler, Handler::Filesystem) {
+            return Err(format!("{}: overlay-base: only used with handler = \"filesystem\"", section));
+        }
+        if let Some(encrypt) = location.encrypt {
+            if matches!(encrypt, Encrypt::Master) && location.encrypt_key.is_none() {
+                return Err(format!("{}: encrypt = \"master\": encrypt-key is not set", section));
+            }
+            if matches!(encrypt, Encrypt::Password) && !matches!(location.auth, Some(Auth::True)) {
+                return Err(format!("{}: encrypt = \"password\": needs auth = true", section));
+            }
+            if matches!(location.handler, Handler::Virtroot) {
+                return Err(format!("{}: encrypt: cannot be used with handler = \"virtroot\"", section));
+            }
+            if location.acl_files || location.skeleton.is_some() || location.overlay_base.is_some() {
+                let msg = "encrypt: acl-files, skeleton and overlay-base need unencrypted files";
+                return Err(format!("{}: {}", section, msg));
+            }
+            if location.encrypt_names && !location.alias.is_empty() {
+                return Err(format!("{}: encrypt-names: cannot be used with alias", section));
+            }
+        } else if location.encrypt_key.is_some() || location.encrypt_names {
+            return Err(format!("{}: encrypt-key, encrypt-names: encrypt is not set", section));
+        }
+        if location.dead_props.is_some() {
+            if cfg!(not(feature = "sqlite")) {
+                return Err(format!("{}: dead-props: not built with the sqlite feature", section));
+            }
+            if matches!(location.handler, Handler::Virtroot | Handler::Mem) {
+                let msg = "dead-props: cannot be used with handler = \"virtroot\" or \"mem\"";
+                return Err(format!("{}: {}", section, msg));
+            }
+        } else if location.props_max_count.is_some() ||
+            location.props_max_size.is_some() ||
+            location.props_user_count.is_some() ||
+            location.props_user_size.is_some()
+        {
+            let msg = "dead-props-max-*, dead-props-user-max-*: dead-props is not set";
+            return Err(format!("{}: {}", section, msg));
+        }
+        if let Some(quota) = location.quota {
+            let os_quota = matches!(quota, Quota::User | Quota::Project | Quota::Filesystem);
+            if os_quota && cfg!(not(feature = "quota")) {
+                return Err(format!("{}: quota: not built with the quota feature", section));
+            }
+            let fs = matches!(location.handler, Handler::Filesystem | Handler::Caldav | Handler::Carddav);
+            if quota != Quota::None && !fs {
+                let msg = "quota: only used with handler = \"filesystem\", \"caldav\" or \"carddav\"";
+                return Err(format!("{}: {}", section, msg));
+            }
+        }
+        if location.confine {
+            if !matches!(location.handler, Handler::Filesystem | Handler::Caldav | Handler::Carddav) {
+                let msg = "confine: only used with handler = \"filesystem\", \"caldav\" or \"carddav\"";
+                return Err(format!("{}: {}", section, msg));
+            }
+            if !matches!(location.case_insensitive, None | Some(CaseInsensitive::False)) {
+                return Err(format!("{}: confine: cannot be used with case-insensitive", section));
+            }
+        }
+        let fsync = !matches!(location.fsync, None | Some(Fsync::None));
+        let local = matches!(location.handler, Handler::Filesystem | Handler::Caldav | Handler::Carddav);
+        if (location.atomic_put || fsync) && !local {
+            let msg = "only used with handler = \"filesystem\", \"caldav\" or \"carddav\"";
+            return Err(format!("{}: atomic-put, fsync: {}", section, msg));
+        }
+        let modes = location.file_mode.is_some() || location.dir_mode.is_some() || location.umask.is_some();
+        if (modes || location.file_group.is_some() || location.preserve_mode) && !local {
+            let msg = "only used with handler = \"filesystem\", \"caldav\" or \"carddav\"";
+            let names = "file-mode, dir-mode, umask, file-group, preserve-mode";
+            return Err(format!("{}: {}: {}", section, names, msg));
+        }
+        check_rate(&section, location.request_rate, location.request_burst)?;
+        let countries = location.allow_countries.iter().chain(location.deny_countries.iter());
+        for country in countries.clone() {
+            let iso = country.len() == 2 && country.bytes().all(|b| b.is_ascii_alphabetic());
+            if !iso && country != "unknown" {
+                return Err(format!("{}: {}: not a country code", section, country));
+            }
+        }
+        if countries.count() > 0 && config.geoip.database.is_none() {
+            return Err(format!("{}: allow-countries, deny-countries: need [geoip] database", section));
+        }
+        let limits = location.max_depth.is_some() || location.max_resources.is_some();
+        if limits && location.infinity != Some(PropfindInfinity::Allow) {
+            let names = "propfind-max-depth, propfind-max-resources";
+            return Err(format!("{}: {}: only used with propfind-infinity = \"allow\"", section, names));
+        }
+        if location.max_depth == Some(0) || location.max_resources == Some(0) {
+            let names = "propfind-max-depth, propfind-max-resources";
+            return Err(format!("{}: {}: must be at least 1", section, names));
+        }
+        let transcode = location.non_utf8_names == Some(NonUtf8Names::Transcode);
+        if transcode != location.legacy_charset.is_some() {
+            let msg = "non-utf8-names = \"transcode\" and legacy-charset go together";
+            return Err(format!("{}: {}", section, msg));
+        }
+        let watched = matches!(location.quota, Some(Quota::Limit(_))) ||
+            location.sync_db.is_some() ||
+            location.fulltext_index.is_some();
+        if location.watch && !watched {
+            let msg = "watch: only used with quota = \"<size>\", sync-db or fulltext-index";
+            return Err(format!("{}: {}", section, msg));
+        }
+        if location.watch && matches!(location.handler, Handler::S3) {
+            return Err(format!("{}: watch: cannot be used with handler = \"s3\"", section));
+        }
+        if location.lock_db.is_some() && cfg!(not(feature = "sqlite")) {
+            return Err(format!("{}: lock-db: not built with the sqlite feature", section));
+        }
+        if location.sync_db.is_some() && cfg!(not(feature = "sqlite")) {
+            return Err(format!("{}: sync-db: not built with the sqlite feature", section));
+        }
+        if location.sync_retention.is_some() && location.sync_db.is_none() {
+            return Err(format!("{}: sync-retention: sync-db is not set", section));
+        }
+        if location.fulltext_index.is_some() {
+            if cfg!(not(feature = "sqlite")) {
+                return Err(format!("{}: fulltext-index: not built with the sqlite feature", section));
+            }
+            if !location.search {
+                return Err(format!("{}: fulltext-index: search is not set", section));
+            }
+        } else if location.fulltext_max.is_some() {
+            return Err(format!("{}: fulltext-max-size: fulltext-index is not set", section));
+        }
+        if matches!(location.handler, Handler::Mem) {
+            if location.setuid || location.acl_files || location.create_directory {
+                let msg = "setuid, acl-files and create-directory cannot be used with mem";
+                return Err(format!("{}: {}", section, msg));
+            }
+        } else if location.mem_size.is_some() {
+            return Err(format!("{}: mem-size: only used with handler = \"mem\"", section));
+        }
+        for (idx, rule) in location.acl.iter().enumerate() {
+            if !rule.path.starts_with('/') {
+                return Err(format!("{}: acl[{}]: path must start with /", section, idx));
+            }
+            if rule.users.is_empty() && rule.groups.is_empty() {
+                return Err(format!("{}: acl[{}]: set users or groups", section, idx));
+            }
+        }
+        if location.guest.is_some() {
+            if !crate::suid::has_thread_switch_ugid() {
+                return Err(format!("{}: guest: uid switching not supported on this OS", section));
+            }
+            if config.server.uid.is_none() || config.server.gid.is_none() {
+                return Err("[server]: missing uid and/or gid".into());
+            }
+            if matches!(location.auth, Some(Auth::True)) {
+                return Err(format!("{}: guest: never used with auth = true", section));
+            }
+        }
+        if location.create_directory {
+            if !crate::suid::has_thread_switch_ugid() {
+                return Err(format!("{}: create-directory: uid switching not supported on this OS", section));
+            }
+            if config.server.uid.is_none() || config.server.gid.is_none() {
+                return Err("[server]: missing uid and/or gid".into());
+            }
+        }
+        if let Some(ref mode) = location.create_mode {
+            if u32::from_str_radix(mode, 8).map(|m| m > 0o7777).unwrap_or(true) {
+                return Err(format!("{}: create-mode: invalid mode {}", section, mode));
+            }
+        }
+        let modes = [
+            ("file-mode", &location.file_mode),
+            ("dir-mode", &location.dir_mode),
+            ("umask", &location.umask),
+        ];
+        for (name, mode) in modes.iter() {
+            if let Some(mode) = mode {
+                if u32::from_str_radix(mode, 8).map(|m| m > 0o7777).unwrap_or(true) {
+                    return Err(format!("{}: {}: invalid mode {}", section, name, mode));
+                }
+            }
+        }
         if location.setuid {
             if !crate::suid::has_thread_switch_ugid() {
-                eprintln!(
-                    "{}: [[location]][{}]: setuid: uid switching not supported on this OS",
-                    cfg, idx
-                );
-                exit(1);
+                return Err(format!("{}: setuid: uid switching not supported on this OS", section));
             }
             if config.server.uid.is_none() || config.server.gid.is_none() {
-                eprintln!("{}: [server]: missing uid and/or gid", cfg);
-                exit(1);
+                return Err("[server]: missing uid and/or gid".into());
             }
             if config.accounts.acct_type.is_none() && location.accounts.acct_type.is_none() {
-                eprintln!("{}: [[location]][{}]: setuid: no acct-type set", cfg, idx);
-                exit(1);
+                return Err(format!("{}: setuid: no acct-type set", section));
             }
         }
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_vars() {
+        let lookup = |v: &str| {
+            match v {
+                "PASS" => Some("secret".to_string()),
+                "EMPTY" => Some(String::new()),
+                _ => None,
+            }
+        };
+        assert_eq!(expand_vars("plain", lookup).unwrap(), "plain");
+        assert_eq!(expand_vars("pw=${PASS}!", lookup).unwrap(), "pw=secret!");
+        assert_eq!(expand_vars("${PASS}${PASS}", lookup).unwrap(), "secretsecret");
+        assert_eq!(expand_vars("x${EMPTY}y", lookup).unwrap(), "xy");
+        assert_eq!(expand_vars("${NOPE:-dflt}", lookup).unwrap(), "dflt");
+        assert_eq!(expand_vars("${PASS:-dflt}", lookup).unwrap(), "secret");
+        assert_eq!(expand_vars("$${PASS}", lookup).unwrap(), "${PASS}");
+        assert_eq!(expand_vars("cost $5", lookup).unwrap(), "cost $5");
+        assert!(expand_vars("${NOPE}", lookup).is_err());
+        assert!(expand_vars("${PASS", lookup).is_err());
+        assert_eq!(expand_vars("/srv/${pam:GROUP}/${PASS}", lookup).unwrap(), "/srv/${pam:GROUP}/secret");
+
+        let file = std::env::temp_dir().join(format!("webdav-server-pamenv-{}.toml", std::process::id()));
+        let toml = "[server]\n[pam]\nservice = \"other\"\nenvironment = true\n\
+                    [[location]]\nhandler = \"filesystem\"\ndirectory = \"/srv/${pam:GROUP}/$user\"\n";
+        fs::write(&file, toml).unwrap();
+        let config = read(&file);
+        fs::remove_file(&file).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.location[0].directory, "/srv/${pam:GROUP}/$user");
+        assert!(validate(&config).is_ok());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Some(1024));
+        assert_eq!(parse_size("10G"), Some(10 << 30));
+        assert_eq!(parse_size("500 m"), Some(500 << 20));
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("1.5G"), None);
+        assert_eq!(parse_size("99999999T"), None);
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("10MB/s"), Some(10 << 20));
+        assert_eq!(parse_rate("512 KiB/s"), Some(512 << 10));
+        assert_eq!(parse_rate("1G"), Some(1 << 30));
+        assert_eq!(parse_rate("100B/s"), Some(100));
+        assert_eq!(parse_rate("10Mb/s"), None);
+    }
+
+    #[test]
+    fn test_size() {
+        let accounts: Accounts = toml::from_str(
+            r#"
+            max-file-size = 1024
+            [max-file-size-users]
+            alice = "1G"
+        "#,
+        )
+        .unwrap();
+        assert_eq!(accounts.max_file_size, Some(Size(1024)));
+        assert_eq!(accounts.max_file_size_users["alice"], Size(1 << 30));
+        assert!(toml::from_str::<Accounts>("max-file-size = \"lots\"").is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut base: toml::Value = toml::from_str(
+            r#"
+            [server]
+            listen = [ "0.0.0.0:80" ]
+            uid = 33
+            [[location]]
+            route = [ "/a" ]
+            "#,
+        )
+        .unwrap();
+        let other: toml::Value = toml::from_str(
+            r#"
+            [server]
+            listen = [ "0.0.0.0:8080" ]
+            [[location]]
+            route = [ "/b" ]
+            "#,
+        )
+        .unwrap();
+        merge(&mut base, other);
+        assert_eq!(base["server"]["listen"].as_array().unwrap().len(), 1);
+        assert_eq!(base["server"]["listen"][0].as_str(), Some("0.0.0.0:8080"));
+        assert_eq!(base["server"]["uid"].as_integer(), Some(33));
+        assert_eq!(base["location"].as_array().unwrap().len(), 2);
+        assert_eq!(base["location"][1]["route"][0].as_str(), Some("/b"));
+    }
+
+    #[test]
+    fn test_template() {
+        let toml = r#"
+            [server]
+            [[location]]
+            handler = "filesystem"
+            directory = "/srv/projects"
+            read-only = true
+            [[location.template]]
+            groups = [ "root" ]
+            read-only = false
+            [[location.template]]
+            users = [ "alice" ]
+            directory = "/srv/alice"
+            [[location.template]]
+            users = [ "bob" ]
+            require-tls = true
+        "#;
+        let mut config = from_value(toml::from_str(toml).unwrap()).unwrap();
+        validate(&config).unwrap();
+        resolve_acl_groups("test", "", &mut config.location).unwrap();
+        let templates = &config.location[0].template;
+        assert_eq!((templates[0].gids.as_slice(), templates[0].read_only), (&[0][..], Some(false)));
+        assert_eq!(templates[1].directory.as_deref(), Some("/srv/alice"));
+        assert!(templates[2].require_tls && !templates[1].require_tls);
+
+        config.location[0].template[0].groups.push("no-such-group-here".to_string());
+        assert!(resolve_acl_groups("test", "", &mut config.location).is_err());
+        config.location[0].template[1].users.clear();
+        assert!(validate(&config).is_err());
+    }
 }
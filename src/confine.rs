@@ -0,0 +1,393 @@
+//
+// File operations that cannot leave the directory of a location
+// (confine = true).
+//
+// Paths are resolved by the kernel with openat2() and RESOLVE_IN_ROOT:
+// "..", and symlinks, absolute or not, are resolved as if the directory
+// were the root, as in a chroot. So a path with ".." from a bug in one
+// of the layers above, or a symlink that points elsewhere, cannot reach
+// a file outside of it. Calls that do not follow the last name of a path
+// (mkdir, unlink, rename, lstat, the xattrs) are made in the directory
+// it is in, opened that way, as /proc/self/fd/N/name.
+//
+// This needs Linux 5.6 or later.
+//
+use std::ffi::{CString, OsString};
+use std::fs::{File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future::FutureExt;
+use hyper::body::{Buf, Bytes};
+use webdav_handler::fs::*;
+
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+const RESOLVE_IN_ROOT: u64 = 0x10;
+
+#[repr(C)]
+struct OpenHow {
+    flags:   u64,
+    mode:    u64,
+    resolve: u64,
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+// Open a path below the root, resolved in the root.
+fn openat2(root: &Path, rel: &Path, flags: libc::c_int, mode: u32) -> io::Result<File> {
+    let croot = cstring(root)?;
+    let rootfd = unsafe { libc::open(croot.as_ptr(), libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC) };
+    if rootfd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let rootfd = unsafe { File::from_raw_fd(rootfd) };
+    if rel.as_os_str().is_empty() {
+        return Ok(rootfd);
+    }
+    let crel = cstring(rel)?;
+    let how = OpenHow {
+        flags:   (flags | libc::O_CLOEXEC) as u64,
+        // the kernel wants no mode when no file is created.
+        mode:    if flags & libc::O_CREAT != 0 { mode as u64 } else { 0 },
+        resolve: RESOLVE_IN_ROOT | RESOLVE_NO_MAGICLINKS,
+    };
+    let size = std::mem::size_of::<OpenHow>();
+    let fd = unsafe {
+        libc::syscall(libc::SYS_openat2, rootfd.as_raw_fd(), crel.as_ptr(), &how as *const OpenHow, size)
+    };
+    match fd {
+        fd if fd >= 0 => Ok(unsafe { File::from_raw_fd(fd as libc::c_int) }),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The directory that a path is in, opened in the root, and the name in
+/// it. Keep it while the path is used.
+pub struct Parent {
+    dir:  File,
+    name: OsString,
+}
+
+impl Parent {
+    pub fn new(root: &Path, rel: &Path) -> io::Result<Parent> {
+        let (dir, name) = match (rel.parent(), rel.file_name()) {
+            (Some(dir), Some(name)) => (dir, name.to_os_string()),
+            _ => (Path::new(""), OsString::from(".")),
+        };
+        let dir = openat2(root, dir, libc::O_PATH | libc::O_DIRECTORY, 0)?;
+        Ok(Parent { dir, name })
+    }
+
+    /// The path, through the directory that was opened.
+    pub fn path(&self) -> PathBuf {
+        let mut path = PathBuf::from(format!("/proc/self/fd/{}", self.dir.as_raw_fd()));
+        path.push(&self.name);
+        path
+    }
+}
+
+/// Open a file, resolved in the root.
+pub fn open(root: &Path, rel: &Path, options: &OpenOptions, mode: u32) -> io::Result<File> {
+    let mut flags = match (options.read, options.write || options.append) {
+        (true, true) => libc::O_RDWR,
+        (false, true) => libc::O_WRONLY,
+        _ => libc::O_RDONLY,
+    };
+    if options.append {
+        flags |= libc::O_APPEND;
+    }
+    if options.truncate {
+        flags |= libc::O_TRUNC;
+    }
+    if options.create_new {
+        flags |= libc::O_CREAT | libc::O_EXCL;
+    } else if options.create {
+        flags |= libc::O_CREAT;
+    }
+    openat2(root, rel, flags, mode)
+}
+
+/// The metadata of a path, of the symlink itself if `follow` is false.
+pub fn metadata(root: &Path, rel: &Path, follow: bool) -> io::Result<Metadata> {
+    match follow {
+        true => openat2(root, rel, libc::O_PATH, 0)?.metadata(),
+        false => std::fs::symlink_metadata(Parent::new(root, rel)?.path()),
+    }
+}
+
+/// The entries of a directory: name, is it a symlink, and the metadata,
+/// of what a symlink points to if `follow` is set (if it points anywhere).
+/// They are read as the iterator goes.
+pub struct ReadDir {
+    root:   PathBuf,
+    rel:    PathBuf,
+    follow: bool,
+    iter:   std::fs::ReadDir,
+}
+
+impl Iterator for ReadDir {
+    type Item = io::Result<(OsString, bool, Metadata)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.iter.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            let link = meta.file_type().is_symlink();
+            let meta = match link && self.follow {
+                true => metadata(&self.root, &self.rel.join(entry.file_name()), true).unwrap_or(meta),
+                false => meta,
+            };
+            return Some(Ok((entry.file_name(), link, meta)));
+        }
+    }
+}
+
+pub fn read_dir(root: &Path, rel: &Path, follow: bool) -> io::Result<ReadDir> {
+    let dir = openat2(root, rel, libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+    let path = PathBuf::from(format!("/proc/self/fd/{}", dir.as_raw_fd()));
+    Ok(ReadDir {
+        root: root.to_path_buf(),
+        rel: rel.to_path_buf(),
+        follow,
+        iter: std::fs::read_dir(path)?,
+    })
+}
+
+pub fn create_dir(root: &Path, rel: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    std::fs::DirBuilder::new().mode(mode).create(Parent::new(root, rel)?.path())
+}
+
+pub fn remove_dir(root: &Path, rel: &Path) -> io::Result<()> {
+    std::fs::remove_dir(Parent::new(root, rel)?.path())
+}
+
+pub fn remove_file(root: &Path, rel: &Path) -> io::Result<()> {
+    std::fs::remove_file(Parent::new(root, rel)?.path())
+}
+
+/// Rename, and like LocalFs a directory onto a file.
+pub fn rename(root: &Path, from: &Path, to: &Path) -> io::Result<()> {
+    let (from, to) = (Parent::new(root, from)?, Parent::new(root, to)?);
+    match std::fs::rename(from.path(), to.path()) {
+        Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) && from.path().is_dir() => {
+            let _ = std::fs::remove_file(to.path());
+            std::fs::rename(from.path(), to.path())
+        },
+        res => res,
+    }
+}
+
+/// Rename, but not over a file that is there (EEXIST then).
+pub fn rename_noreplace(root: &Path, from: &Path, to: &Path) -> io::Result<()> {
+    let (from, to) = (Parent::new(root, from)?, Parent::new(root, to)?);
+    renameat2_noreplace(&from.path(), &to.path())
+}
+
+/// renameat2 with RENAME_NOREPLACE, for paths that are not confined.
+pub fn renameat2_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    let cfrom = CString::new(from.as_os_str().as_bytes())?;
+    let cto = CString::new(to.as_os_str().as_bytes())?;
+    let (cwd, flags) = (libc::AT_FDCWD, libc::RENAME_NOREPLACE);
+    match unsafe { libc::syscall(libc::SYS_renameat2, cwd, cfrom.as_ptr(), cwd, cto.as_ptr(), flags) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Copy the contents and the permissions of a file.
+pub fn copy(root: &Path, from: &Path, to: &Path) -> io::Result<u64> {
+    let mut src = openat2(root, from, libc::O_RDONLY, 0)?;
+    let perms = src.metadata()?.permissions();
+    let mode = perms.mode() & 0o7777;
+    let mut dst = openat2(root, to, libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC, mode)?;
+    let n = io::copy(&mut src, &mut dst)?;
+    dst.set_permissions(perms)?;
+    Ok(n)
+}
+
+/// A file that was opened confined.
+#[derive(Debug)]
+pub struct ConfinedFile(Option<File>);
+
+impl ConfinedFile {
+    pub fn new(file: File) -> Box<ConfinedFile> {
+        Box::new(ConfinedFile(Some(file)))
+    }
+
+    // A call on the file, on a blocking thread.
+    async fn call<F, T>(&mut self, func: F) -> FsResult<T>
+    where F: FnOnce(&mut File) -> io::Result<T> {
+        let mut file = self.0.take().ok_or(FsError::GeneralFailure)?;
+        let res = tokio::task::block_in_place(|| func(&mut file));
+        self.0 = Some(file);
+        res.map_err(|e| e.into())
+    }
+}
+
+impl DavFile for ConfinedFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let meta = self.call(|file| file.metadata()).await?;
+            Ok(ConfinedMeta::new(meta) as Box<dyn DavMetaData>)
+        }
+        .boxed()
+    }
+
+    fn write_buf<'a>(&'a mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        async move {
+            self.call(move |file| {
+                while buf.has_remaining() {
+                    let n = file.write(buf.chunk())?;
+                    buf.advance(n);
+                }
+                Ok(())
+            })
+            .await
+        }
+        .boxed()
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        async move { self.call(move |file| file.write_all(&buf)).await }.boxed()
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        async move {
+            self.call(move |file| {
+                let mut buf = vec![0u8; count];
+                let n = file.read(&mut buf)?;
+                buf.truncate(n);
+                Ok(Bytes::from(buf))
+            })
+            .await
+        }
+        .boxed()
+    }
+
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> FsFuture<'a, u64> {
+        async move { self.call(move |file| file.seek(pos)).await }.boxed()
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        async move { self.call(|file| file.flush()).await }.boxed()
+    }
+}
+
+/// Metadata, the same as that of LocalFs (the ETag too).
+#[derive(Debug, Clone)]
+pub struct ConfinedMeta(Metadata);
+
+impl ConfinedMeta {
+    pub fn new(meta: Metadata) -> Box<ConfinedMeta> {
+        Box::new(ConfinedMeta(meta))
+    }
+}
+
+impl DavMetaData for ConfinedMeta {
+    fn len(&self) -> u64 {
+        self.0.len()
+    }
+
+    fn created(&self) -> FsResult<SystemTime> {
+        self.0.created().map_err(|e| e.into())
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        self.0.modified().map_err(|e| e.into())
+    }
+
+    fn accessed(&self) -> FsResult<SystemTime> {
+        self.0.accessed().map_err(|e| e.into())
+    }
+
+    fn status_changed(&self) -> FsResult<SystemTime> {
+        Ok(UNIX_EPOCH + Duration::new(self.0.ctime() as u64, 0))
+    }
+
+    fn is_dir(&self) -> bool {
+        self.0.is_dir()
+    }
+
+    fn is_file(&self) -> bool {
+        self.0.is_file()
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.0.file_type().is_symlink()
+    }
+
+    fn executable(&self) -> FsResult<bool> {
+        match self.0.is_file() {
+            true => Ok((self.0.permissions().mode() & 0o100) > 0),
+            false => Err(FsError::NotImplemented),
+        }
+    }
+
+    fn etag(&self) -> Option<String> {
+        let t = self.0.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        let t = t.as_secs() * 1000000 + t.subsec_nanos() as u64 / 1000;
+        match self.is_file() {
+            true => Some(format!("{:x}-{:x}-{:x}", self.0.ino(), self.0.len(), t)),
+            false => Some(format!("{:x}-{:x}", self.0.ino(), t)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_confine() {
+        let dir = std::env::temp_dir().join(format!("confine-test-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(dir.join("secret"), "outside").unwrap();
+        std::fs::write(root.join("secret"), "inside").unwrap();
+        symlink("../secret", root.join("sub/up")).unwrap();
+        symlink("/secret", root.join("absolute")).unwrap();
+        symlink(dir.join("secret"), root.join("outside")).unwrap();
+
+        let read = |rel: &str| {
+            let mut s = String::new();
+            let options = OpenOptions { read: true, ..OpenOptions::default() };
+            open(&root, Path::new(rel), &options, 0).and_then(|mut f| f.read_to_string(&mut s)).map(|_| s)
+        };
+        // every way out ends up in the root.
+        assert_eq!(read("secret").unwrap(), "inside");
+        assert_eq!(read("sub/up").unwrap(), "inside");
+        assert_eq!(read("absolute").unwrap(), "inside");
+        assert_eq!(read("../secret").unwrap(), "inside");
+        assert_eq!(read("sub/../../../secret").unwrap(), "inside");
+        assert_eq!(read("outside").unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        assert!(metadata(&root, Path::new("sub/up"), false).unwrap().file_type().is_symlink());
+        assert_eq!(metadata(&root, Path::new("absolute"), true).unwrap().len(), 6);
+        create_dir(&root, Path::new("sub/new"), 0o755).unwrap();
+        rename(&root, Path::new("sub/new"), Path::new("moved")).unwrap();
+        copy(&root, Path::new("secret"), Path::new("moved/copy")).unwrap();
+        let entries = read_dir(&root, Path::new(""), true).unwrap();
+        let mut names: Vec<_> = entries.map(|e| e.unwrap().0).collect();
+        names.sort();
+        assert_eq!(names, vec!["absolute", "moved", "outside", "secret", "sub"]);
+        remove_file(&root, Path::new("moved/copy")).unwrap();
+        remove_dir(&root, Path::new("moved")).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("secret")).unwrap(), "outside");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//
+// Cross-origin requests from browsers ([cors]).
+//
+// A request with an Origin that is in `origins` gets the
+// Access-Control-Allow-* headers in its response, and a preflight
+// (OPTIONS with Access-Control-Request-Method) is answered here, before
+// authentication: browsers do not send credentials with it.
+//
+use http::header::HeaderValue;
+use http::{HeaderMap, Method, StatusCode};
+
+use crate::config::Cors;
+
+const METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS", "PROPFIND", "PROPPATCH", "MKCOL", "COPY",
+    "MOVE", "LOCK", "UNLOCK", "REPORT", "SEARCH",
+];
+const HEADERS: &[&str] = &[
+    "Authorization", "Content-Type", "Depth", "Destination", "Overwrite", "If", "If-Match", "If-None-Match",
+    "If-Modified-Since", "Lock-Token", "Timeout", "Range", "Content-Range", "X-Requested-With",
+    "X-Append",
+];
+const EXPOSE: &[&str] = &[
+    "DAV", "ETag", "Last-Modified", "Content-Length", "Content-Range", "Content-Type", "Lock-Token",
+    "Location", "X-Append-Offset",
+];
+
+fn list(items: &Option<Vec<String>>, default: &[&str]) -> String {
+    match items {
+        Some(items) => items.join(", "),
+        None => default.join(", "),
+    }
+}
+
+// The origin is allowed: "*", the same, or "https://*.example.com".
+fn allowed(cfg: &Cors, origin: &str) -> bool {
+    cfg.origins.iter().any(|o| {
+        if o == "*" || o.eq_ignore_ascii_case(origin) {
+            return true;
+        }
+        match o.split_once("://*.") {
+            Some((scheme, domain)) => {
+                let origin = origin.to_ascii_lowercase();
+                let host = origin.strip_prefix(&format!("{}://", scheme.to_ascii_lowercase()));
+                host.and_then(|h| h.strip_suffix(&domain.to_ascii_lowercase()))
+                    .map(|h| h.ends_with('.') && h.len() > 1)
+                    .unwrap_or(false)
+            },
+            None => false,
+        }
+    })
+}
+
+fn origin<'a>(cfg: &Cors, origin: Option<&'a HeaderValue>) -> Option<&'a HeaderValue> {
+    let value = origin?;
+    match allowed(cfg, value.to_str().ok()?) {
+        true => Some(value),
+        false => None,
+    }
+}
+
+/// The answer to a preflight from an allowed origin.
+pub fn preflight(
+    cfg: &Cors,
+    method: &Method,
+    headers: &HeaderMap,
+    builder: http::response::Builder,
+) -> Option<http::Response<hyper::Body>>
+{
+    if method != Method::OPTIONS || origin(cfg, headers.get("origin")).is_none() {
+        return None;
+    }
+    let wanted = headers.get("access-control-request-method")?.to_str().ok()?;
+    let methods = list(&cfg.methods, METHODS);
+    if !methods.split(", ").any(|m| m.eq_ignore_ascii_case(wanted)) {
+        debug!("cors: preflight for {}: not allowed", wanted);
+        return None;
+    }
+    let mut builder = builder
+        .status(StatusCode::NO_CONTENT)
+        .header("Access-Control-Allow-Methods", methods)
+        .header("Access-Control-Allow-Headers", list(&cfg.headers, HEADERS));
+    if let Some(max_age) = cfg.max_age {
+        builder = builder.header("Access-Control-Max-Age", max_age.to_string());
+    }
+    Some(builder.body(hyper::Body::empty()).unwrap())
+}
+
+/// Add the headers for an allowed origin (of the request) to a response.
+pub fn headers(cfg: &Cors, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+    let origin = match self::origin(cfg, origin) {
+        Some(origin) => origin.clone(),
+        None => return,
+    };
+    let any = cfg.origins.iter().any(|o| o == "*") && !cfg.credentials;
+    match any {
+        true => headers.insert("Access-Control-Allow-Origin", HeaderValue::from_static("*")),
+        false => {
+            headers.append("Vary", HeaderValue::from_static("Origin"));
+            headers.insert("Access-Control-Allow-Origin", origin)
+        },
+    };
+    if cfg.credentials {
+        headers.insert("Access-Control-Allow-Credentials", HeaderValue::from_static("true"));
+    }
+    if let Ok(expose) = HeaderValue::from_str(&list(&cfg.expose_headers, EXPOSE)) {
+        headers.insert("Access-Control-Expose-Headers", expose);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cors() {
+        let toml = r#"origins = [ "https://office.example.com", "https://*.example.org" ]"#;
+        let cfg: Cors = toml::from_str(toml).unwrap();
+        assert!(allowed(&cfg, "https://office.example.com"));
+        assert!(allowed(&cfg, "https://a.example.org"));
+        assert!(!allowed(&cfg, "https://example.org"));
+        assert!(!allowed(&cfg, "http://a.example.org"));
+        assert!(!allowed(&cfg, "https://evil-office.example.com"));
+
+        let mut req = HeaderMap::new();
+        req.insert("origin", "https://a.example.org".parse().unwrap());
+        req.insert("access-control-request-method", "PROPFIND".parse().unwrap());
+        let resp = preflight(&cfg, &Method::OPTIONS, &req, http::Response::builder()).unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(resp.headers()["access-control-allow-methods"].to_str().unwrap().contains("PROPFIND"));
+        req.insert("access-control-request-method", "TRACE".parse().unwrap());
+        assert!(preflight(&cfg, &Method::OPTIONS, &req, http::Response::builder()).is_none());
+
+        let mut headers = HeaderMap::new();
+        super::headers(&cfg, req.get("origin"), &mut headers);
+        assert_eq!(headers["access-control-allow-origin"], "https://a.example.org");
+        assert_eq!(headers["vary"], "Origin");
+    }
+}
@@ -0,0 +1,492 @@
+//
+// COPY and MOVE to a Destination: in another location (from a home
+// directory to a shared one, from the filesystem to S3). They can not be
+// done by one filesystem, so they are done with requests to the server
+// itself, as the same user: PROPFIND to walk the tree, GET and PUT for
+// files, streamed, MKCOL for collections, and PROPPATCH for the dead
+// properties. Every one of those is checked as if the client made it.
+//
+// An error below the top is reported in a 207 Multi-Status, and the rest
+// is copied. A MOVE deletes the source only after all of it was copied,
+// so a MOVE that is cut off or fails halfway leaves the source complete.
+// In the background (jobs.rs), what was copied is counted.
+//
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use http::{HeaderMap, StatusCode};
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::DavMethod;
+use xmltree::{Element, EmitterConfig, XMLNode};
+
+use crate::auth::{ClientCertUser, Subrequest};
+use crate::jobs::Progress;
+use crate::report::{self, Multistatus};
+use crate::server::Server;
+
+type HttpRequest = http::Request<hyper::Body>;
+type HttpResponse = http::Response<hyper::Body>;
+
+// headers of the client's request that the requests of the copy get.
+const HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "host",
+    "user-agent",
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-real-ip",
+];
+
+// properties in these namespaces are live, they are not copied.
+const LIVE_NAMESPACES: &[&str] = &[
+    "DAV:",
+    "http://apache.org/dav/props/",
+    "http://owncloud.org/ns",
+    "urn:webdav-server-rs:checksum",
+    "urn:webdav-server-rs:sync:",
+];
+
+const ALLPROP: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                       <D:propfind xmlns:D=\"DAV:\"><D:allprop/></D:propfind>\n";
+
+const MAX_XML: usize = 16 * 1024 * 1024;
+
+/// The Destination: of a request, if it is on this server but not in
+/// the location with this prefix.
+pub fn destination(req: &HttpRequest, prefix: &str) -> Option<DavPath> {
+    let dest = req.headers().get("destination")?.to_str().ok()?.parse::<http::Uri>().ok()?;
+    if let Some(authority) = dest.authority() {
+        let host = req.headers().get("host").and_then(|h| h.to_str().ok());
+        if !host.map(|h| h.eq_ignore_ascii_case(authority.as_str())).unwrap_or(false) {
+            return None;
+        }
+    }
+    let dest = DavPath::from_uri(&dest).ok()?;
+    match dest.as_bytes().starts_with(prefix.as_bytes()) {
+        true => None,
+        false => Some(dest),
+    }
+}
+
+// A resource, from a PROPFIND.
+struct Entry {
+    path:   Vec<u8>,
+    is_dir: bool,
+    // the dead properties, as XML.
+    props:  Vec<String>,
+}
+
+fn trim_slash(path: &[u8]) -> &[u8] {
+    match path {
+        [rest @ .., b'/'] => rest,
+        _ => path,
+    }
+}
+
+// A path (%XX decoded), as the path of a URL.
+fn encode(path: &[u8]) -> String {
+    let names = path.split(|&c| c == b'/').map(|n| percent_encode(n, NON_ALPHANUMERIC).to_string());
+    names.collect::<Vec<_>>().join("/")
+}
+
+fn element_xml(elem: &Element) -> Option<String> {
+    let mut out = Vec::new();
+    let config = EmitterConfig::new().write_document_declaration(false);
+    elem.write_with_config(&mut out, config).ok()?;
+    String::from_utf8(out).ok()
+}
+
+// The responses in a PROPFIND multistatus.
+fn parse_multistatus(xml: &[u8]) -> Option<Vec<Entry>> {
+    let root = Element::parse(xml).ok()?;
+    let mut entries = Vec::new();
+    let resps = root.children.iter().filter_map(XMLNode::as_element);
+    for resp in resps.filter(|e| report::is_dav(e, "response")) {
+        let href = resp.get_child("href")?.get_text()?;
+        let href = match href.trim().parse::<http::Uri>() {
+            Ok(uri) => uri.path().to_string(),
+            Err(_) => href.trim().to_string(),
+        };
+        let path = DavPath::new(&href).ok()?.as_bytes().to_vec();
+        let mut entry = Entry {
+            path:   trim_slash(&path).to_vec(),
+            is_dir: false,
+            props:  Vec::new(),
+        };
+        let propstats = resp.children.iter().filter_map(XMLNode::as_element);
+        for propstat in propstats.filter(|e| report::is_dav(e, "propstat")) {
+            let ok = propstat.get_child("status").and_then(|s| s.get_text());
+            if !ok.map(|s| s.contains(" 200")).unwrap_or(false) {
+                continue;
+            }
+            let props = propstat.get_child("prop").map(|p| p.children.iter()).into_iter().flatten();
+            for prop in props.filter_map(XMLNode::as_element) {
+                if report::is_dav(prop, "resourcetype") {
+                    entry.is_dir = prop.get_child("collection").is_some();
+                }
+                let ns = prop.namespace.as_deref().unwrap_or_default();
+                if !LIVE_NAMESPACES.contains(&ns) {
+                    entry.props.extend(element_xml(prop));
+                }
+            }
+        }
+        entries.push(entry);
+    }
+    Some(entries)
+}
+
+struct Copy<'a> {
+    server:    &'a Server,
+    remote_ip: SocketAddr,
+    headers:   HeaderMap,
+    cert:      Option<ClientCertUser>,
+    sub:       Option<Subrequest>,
+    errors:    Option<Multistatus>,
+    progress:  Option<Arc<Progress>>,
+}
+
+impl<'a> Copy<'a> {
+    async fn request(
+        &self,
+        method: &str,
+        path: &[u8],
+        headers: &[(&str, String)],
+        body: hyper::Body,
+    ) -> Result<HttpResponse, StatusCode>
+    {
+        let mut req = http::Request::builder().method(method).uri(encode(path));
+        for (name, value) in self.headers.iter() {
+            req = req.header(name, value);
+        }
+        for (name, value) in headers {
+            req = req.header(*name, value.as_str());
+        }
+        if let Some(ref cert) = self.cert {
+            req = req.extension(cert.clone());
+        }
+        if let Some(ref sub) = self.sub {
+            req = req.extension(sub.clone());
+        }
+        let req = req.body(body).map_err(|_| StatusCode::BAD_REQUEST)?;
+        self.server.subrequest(req, self.remote_ip).await.map_err(|e| {
+            debug!("crossroute: {} {}: {}", method, String::from_utf8_lossy(path), e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+    }
+
+    async fn propfind(&self, path: &[u8], depth: &str) -> Result<Vec<Entry>, StatusCode> {
+        let headers = [
+            ("depth", depth.to_string()),
+            ("content-type", "application/xml".to_string()),
+        ];
+        let resp = self.request("PROPFIND", path, &headers, ALLPROP.into()).await?;
+        if resp.status() != StatusCode::MULTI_STATUS {
+            return Err(resp.status());
+        }
+        let body = read_body(resp).await?;
+        parse_multistatus(&body).ok_or(StatusCode::BAD_GATEWAY)
+    }
+
+    // Is something there, and is it a collection.
+    async fn exists(&self, path: &[u8]) -> Result<Option<Entry>, StatusCode> {
+        match self.propfind(path, "0").await {
+            Ok(entries) => Ok(entries.into_iter().next()),
+            Err(StatusCode::NOT_FOUND) => Ok(None),
+            Err(status) => Err(status),
+        }
+    }
+
+    // A request that must succeed: the path and status if it did not.
+    async fn must(
+        &self,
+        method: &str,
+        path: &[u8],
+        headers: &[(&str, String)],
+        body: hyper::Body,
+    ) -> Result<(), (Vec<u8>, StatusCode)>
+    {
+        match self.request(method, path, headers, body).await {
+            Ok(resp) if resp.status().is_success() && resp.status() != StatusCode::MULTI_STATUS => Ok(()),
+            Ok(resp) => Err((path.to_vec(), resp.status())),
+            Err(status) => Err((path.to_vec(), status)),
+        }
+    }
+
+    // GET, and the body of that as the body of a PUT.
+    async fn copy_file(&self, from: &[u8], to: &[u8]) -> Result<(), (Vec<u8>, StatusCode)> {
+        let resp = self.request("GET", from, &[], hyper::Body::empty()).await;
+        let resp = match resp {
+            Ok(resp) if resp.status() == StatusCode::OK => resp,
+            Ok(resp) => return Err((from.to_vec(), resp.status())),
+            Err(status) => return Err((from.to_vec(), status)),
+        };
+        let mut headers = Vec::new();
+        for name in ["content-length", "content-type"].iter() {
+            if let Some(value) = resp.headers().get(*name).and_then(|v| v.to_str().ok()) {
+                headers.push((*name, value.to_string()));
+            }
+        }
+        let len = headers.first().filter(|(n, _)| *n == "content-length").and_then(|(_, v)| v.parse().ok());
+        self.must("PUT", to, &headers, resp.into_body()).await?;
+        if let Some(ref progress) = self.progress {
+            progress.add(len.unwrap_or(0));
+        }
+        Ok(())
+    }
+
+    // The dead properties. Not a reason to fail the copy.
+    async fn copy_props(&self, entry: &Entry, to: &[u8]) {
+        if entry.props.is_empty() {
+            return;
+        }
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <D:propertyupdate xmlns:D=\"DAV:\"><D:set><D:prop>{}</D:prop></D:set></D:propertyupdate>\n",
+            entry.props.join("")
+        );
+        let headers = [("content-type", "application/xml".to_string())];
+        let res = self.request("PROPPATCH", to, &headers, body.into()).await;
+        let failed = match res {
+            Ok(resp) if resp.status() == StatusCode::MULTI_STATUS => {
+                let body = read_body(resp).await.unwrap_or_default();
+                String::from_utf8_lossy(&body).split("<D:status>").skip(1).any(|s| !s.contains(" 200"))
+            },
+            Ok(resp) => !resp.status().is_success(),
+            Err(_) => true,
+        };
+        if failed {
+            debug!("crossroute: {}: not all properties copied", String::from_utf8_lossy(to));
+        }
+    }
+
+    fn error(&mut self, path: &[u8], status: StatusCode) {
+        debug!("crossroute: {}: {}", String::from_utf8_lossy(path), status);
+        let errors = self.errors.get_or_insert_with(Multistatus::new);
+        errors.status(&encode(path), status);
+    }
+
+    // Copy a resource, and with depth what is below it. An error at the
+    // top is returned, the others are collected.
+    async fn copy_tree(&mut self, top: Entry, to: Vec<u8>, depth: bool) -> Result<(), StatusCode> {
+        let mut todo = vec![(top, to)];
+        let mut first = true;
+        while let Some((entry, to)) = todo.pop() {
+            let res = match entry.is_dir {
+                true => {
+                    let res = self.must("MKCOL", &to, &[], hyper::Body::empty()).await;
+                    if let (Ok(()), Some(progress)) = (&res, &self.progress) {
+                        progress.add(0);
+                    }
+                    res
+                },
+                false => self.copy_file(&entry.path, &to).await,
+            };
+            if let Err((path, status)) = res {
+                if first {
+                    return Err(status);
+                }
+                self.error(&path, status);
+                continue;
+            }
+            first = false;
+            self.copy_props(&entry, &to).await;
+            if !entry.is_dir || !depth {
+                continue;
+            }
+            let members = match self.propfind(&entry.path, "1").await {
+                Ok(members) => members,
+                Err(status) => {
+                    self.error(&entry.path, status);
+                    continue;
+                },
+            };
+            for member in members.into_iter().filter(|m| m.path != entry.path) {
+                let name = match member.path.strip_prefix(&entry.path[..]) {
+                    Some(name) if name.starts_with(b"/") => name.to_vec(),
+                    _ => continue,
+                };
+                let mut dest = to.clone();
+                dest.extend_from_slice(&name);
+                todo.push((member, dest));
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn read_body(resp: HttpResponse) -> Result<hyper::body::Bytes, StatusCode> {
+    match hyper::body::to_bytes(resp.into_body()).await {
+        Ok(body) if body.len() <= MAX_XML => Ok(body),
+        _ => Err(StatusCode::BAD_GATEWAY),
+    }
+}
+
+/// What copy_move needs of a request, to do it in the background.
+pub fn head(req: &HttpRequest) -> HttpRequest {
+    let mut head = http::Request::new(hyper::Body::empty());
+    *head.method_mut() = req.method().clone();
+    *head.uri_mut() = req.uri().clone();
+    *head.headers_mut() = req.headers().clone();
+    if let Some(cert) = req.extensions().get::<ClientCertUser>() {
+        head.extensions_mut().insert(cert.clone());
+    }
+    head
+}
+
+/// COPY or MOVE the resource at path (with the prefix) to dest.
+pub async fn copy_move(
+    server: &Server,
+    req: &HttpRequest,
+    method: DavMethod,
+    path: &[u8],
+    dest: DavPath,
+    sub: Option<Subrequest>,
+    remote_ip: SocketAddr,
+) -> http::Response<String>
+{
+    let (from, to) = (trim_slash(path).to_vec(), trim_slash(dest.as_bytes()).to_vec());
+    if from.is_empty() || to.is_empty() || to.starts_with(&from) || from.starts_with(&to) {
+        return report::error(StatusCode::FORBIDDEN);
+    }
+    let depth = match req.headers().get("depth").and_then(|d| d.to_str().ok()) {
+        None => true,
+        Some(d) if d.eq_ignore_ascii_case("infinity") => true,
+        Some("0") if method == DavMethod::Copy => false,
+        Some(_) => return report::error(StatusCode::BAD_REQUEST),
+    };
+    let overwrite = req.headers().get("overwrite").map(|o| o.as_bytes() != b"F").unwrap_or(true);
+
+    let mut headers = HeaderMap::new();
+    for name in HEADERS {
+        for value in req.headers().get_all(*name) {
+            headers.append(*name, value.clone());
+        }
+    }
+    let mut copy = Copy {
+        server,
+        remote_ip,
+        headers,
+        cert: req.extensions().get::<ClientCertUser>().cloned(),
+        sub,
+        errors: None,
+        progress: req.extensions().get::<Arc<Progress>>().cloned(),
+    };
+
+    let entry = match copy.exists(&from).await {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return report::error(StatusCode::NOT_FOUND),
+        Err(status) => return report::error(status),
+    };
+    let existed = match copy.exists(&to).await {
+        Ok(existed) => existed.is_some(),
+        Err(status) => return report::error(status),
+    };
+    if existed {
+        if !overwrite {
+            return report::error(StatusCode::PRECONDITION_FAILED);
+        }
+        if let Err((_, status)) = copy.must("DELETE", &to, &[], hyper::Body::empty()).await {
+            return report::error(status);
+        }
+    }
+    if let Err(status) = copy.copy_tree(entry, to, depth).await {
+        return report::error(status);
+    }
+    if let Some(errors) = copy.errors.take() {
+        return errors.finish("");
+    }
+
+    if method == DavMethod::Move {
+        let resp = copy.request("DELETE", &from, &[], hyper::Body::empty()).await;
+        match resp {
+            Ok(resp) if resp.status() == StatusCode::MULTI_STATUS => {
+                let body = read_body(resp).await.unwrap_or_default();
+                let body = String::from_utf8_lossy(&body).into_owned();
+                return report::response(StatusCode::MULTI_STATUS, body);
+            },
+            Ok(resp) if !resp.status().is_success() => return report::error(resp.status()),
+            Err(status) => return report::error(status),
+            Ok(_) => {},
+        }
+    }
+    match existed {
+        true => report::error(StatusCode::NO_CONTENT),
+        false => report::error(StatusCode::CREATED),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crossroute() {
+        assert_eq!(encode(b"/home/a b/caf\xc3\xa9"), "/home/a%20b/caf%C3%A9");
+        let xml = br#"<?xml version="1.0"?>
+            <D:multistatus xmlns:D="DAV:" xmlns:X="urn:x">
+              <D:response><D:href>/home/dir/</D:href>
+                <D:propstat><D:prop><D:resourcetype><D:collection/></D:resourcetype>
+                  <X:color>red</X:color></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>
+              </D:response>
+              <D:response><D:href>http://host/home/dir/a%20b.txt</D:href>
+                <D:propstat><D:prop><D:resourcetype/><D:getcontentlength>3</D:getcontentlength></D:prop>
+                  <D:status>HTTP/1.1 200 OK</D:status></D:propstat>
+                <D:propstat><D:prop><X:gone/></D:prop><D:status>HTTP/1.1 404 Not Found</D:status></D:propstat>
+              </D:response>
+            </D:multistatus>"#;
+        let entries = parse_multistatus(xml).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].path.as_slice(), entries[0].is_dir), (&b"/home/dir"[..], true));
+        assert_eq!(entries[0].props.len(), 1);
+        assert!(entries[0].props[0].contains("red</X:color>"));
+        assert_eq!((entries[1].path.as_slice(), entries[1].is_dir), (&b"/home/dir/a b.txt"[..], false));
+        assert!(entries[1].props.is_empty());
+
+        let req = |dest: &str| {
+            http::Request::builder()
+                .header("host", "example.com")
+                .header("destination", dest)
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+        assert!(destination(&req("/shared/x"), "/home").is_some());
+        assert!(destination(&req("http://example.com/shared/x"), "/home").is_some());
+        assert!(destination(&req("http://other.com/shared/x"), "/home").is_none());
+        assert!(destination(&req("/home/y"), "/home").is_none());
+    }
+
+    // A COPY out of a read-only location is fine, into it is not.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_copy_read_only() {
+        let dir = std::env::temp_dir().join(format!("crossroute-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("ro")).unwrap();
+        std::fs::create_dir_all(dir.join("rw")).unwrap();
+        std::fs::write(dir.join("ro/a.txt"), "a\n").unwrap();
+        let toml = format!(
+            "[server]\n\
+             [[location]]\nroute = [ \"/ro/*path\" ]\nhandler = \"filesystem\"\ndirectory = {:?}\n\
+             methods = [ \"webdav-rw\" ]\nread-only = true\n\
+             [[location]]\nroute = [ \"/rw/*path\" ]\nhandler = \"filesystem\"\ndirectory = {:?}\n\
+             methods = [ \"webdav-rw\" ]\n",
+            dir.join("ro").to_str().unwrap(),
+            dir.join("rw").to_str().unwrap()
+        );
+        let server = crate::builder::Builder::from_toml(&toml).build().unwrap();
+        let peer: std::net::SocketAddr = "127.0.0.1:4711".parse().unwrap();
+        let copy = |dest: &str| {
+            http::Request::builder()
+                .method("COPY")
+                .uri("/ro/a.txt")
+                .header("destination", dest)
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+        assert_eq!(server.route(copy("/ro/b.txt"), peer).await.unwrap().status(), 403);
+        assert_eq!(server.route(copy("/rw/b.txt"), peer).await.unwrap().status(), 201);
+        assert_eq!(std::fs::read(dir.join("rw/b.txt")).unwrap(), b"a\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,705 @@
+//
+// Encryption at rest.
+//
+// File contents are encrypted with AES-256-GCM, in chunks of 64 KiB, so
+// that a range can be read without decrypting the whole file. A file
+// starts with a header: "WDE1" and a random 16 byte file id. Every chunk
+// is stored as a random nonce, the ciphertext, and the tag. The file id and
+// the chunk number are authenticated with it, so chunks cannot be moved
+// around, or to another file.
+//
+// Names can be encrypted too, deterministically: the nonce is a HMAC of
+// the name, so the same name always encrypts to the same base64 string.
+//
+// The key is derived from a master key per user, or from the password
+// of the user. In the last case, the files are lost when the password is.
+//
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::num::NonZeroU32;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use futures::future::FutureExt;
+use futures::StreamExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac, pbkdf2};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+const MAGIC: &[u8] = b"WDE1";
+const HEADER_LEN: u64 = 20;
+const CHUNK: usize = 65536;
+const OVERHEAD: usize = NONCE_LEN + 16;
+const CT_CHUNK: u64 = (CHUNK + OVERHEAD) as u64;
+const PBKDF2_ROUNDS: u32 = 100_000;
+// the longest name most filesystems allow.
+const NAME_MAX: usize = 255;
+
+lazy_static::lazy_static! {
+    // keys derived from passwords, by sha256(salt, user, password).
+    static ref PASSWORD_KEYS: Mutex<HashMap<Vec<u8>, Arc<Keys>>> = Mutex::new(HashMap::new());
+}
+
+/// The keys of one user.
+pub struct Keys {
+    content: LessSafeKey,
+    name:    LessSafeKey,
+    name_iv: hmac::Key,
+}
+
+impl Keys {
+    fn new(key: &[u8]) -> Keys {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+        let subkey = |what: &[u8]| hmac::sign(&key, what);
+        let aead_key = |what: &[u8]| {
+            let unbound = UnboundKey::new(&AES_256_GCM, subkey(what).as_ref()).unwrap();
+            LessSafeKey::new(unbound)
+        };
+        Keys {
+            content: aead_key(b"content"),
+            name:    aead_key(b"name"),
+            name_iv: hmac::Key::new(hmac::HMAC_SHA256, subkey(b"name-iv").as_ref()),
+        }
+    }
+
+    /// Keys derived from the master key, for `user`.
+    pub fn from_master(master: &[u8], user: Option<&str>) -> Arc<Keys> {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, master);
+        let user = format!("user:{}", user.unwrap_or(""));
+        Arc::new(Keys::new(hmac::sign(&key, user.as_bytes()).as_ref()))
+    }
+
+    /// Keys derived from the password of `user`, with the master key (if
+    /// any) as part of the salt. That's slow on purpose, so they are cached.
+    pub fn from_password(master: Option<&[u8]>, user: &str, password: &str) -> Arc<Keys> {
+        let mut salt = master.unwrap_or_default().to_vec();
+        salt.extend_from_slice(b"webdav-server:");
+        salt.extend_from_slice(user.as_bytes());
+
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        for part in &[&salt[..], b"\0", password.as_bytes()] {
+            ctx.update(part);
+        }
+        let id = ctx.finish().as_ref().to_vec();
+        if let Some(keys) = PASSWORD_KEYS.lock().unwrap().get(&id) {
+            return keys.clone();
+        }
+
+        let mut key = [0u8; 32];
+        let rounds = NonZeroU32::new(PBKDF2_ROUNDS).unwrap();
+        tokio::task::block_in_place(|| {
+            pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, rounds, &salt, password.as_bytes(), &mut key)
+        });
+        let keys = Arc::new(Keys::new(&key));
+        let mut cache = PASSWORD_KEYS.lock().unwrap();
+        if cache.len() >= 10000 {
+            cache.clear();
+        }
+        cache.insert(id, keys.clone());
+        keys
+    }
+
+    // Deterministic encryption of a name, base64 encoded.
+    fn encrypt_name(&self, name: &[u8]) -> FsResult<String> {
+        let iv = hmac::sign(&self.name_iv, name);
+        let nonce_bytes = &iv.as_ref()[..NONCE_LEN];
+        let nonce = Nonce::try_assume_unique_for_key(nonce_bytes).unwrap();
+        let mut data = name.to_vec();
+        self.name
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut data)
+            .map_err(|_| FsError::GeneralFailure)?;
+        let mut out = nonce_bytes.to_vec();
+        out.extend_from_slice(&data);
+        let name = base64::encode_config(&out, base64::URL_SAFE_NO_PAD);
+        if name.len() > NAME_MAX {
+            return Err(FsError::PathTooLong);
+        }
+        Ok(name)
+    }
+
+    fn decrypt_name(&self, name: &[u8]) -> Option<Vec<u8>> {
+        let data = base64::decode_config(name, base64::URL_SAFE_NO_PAD).ok()?;
+        if data.len() < OVERHEAD {
+            return None;
+        }
+        let (nonce_bytes, data) = data.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce_bytes).ok()?;
+        let mut data = data.to_vec();
+        let name = self.name.open_in_place(nonce, Aad::empty(), &mut data).ok()?;
+        let iv = hmac::sign(&self.name_iv, name);
+        if &iv.as_ref()[..NONCE_LEN] != nonce_bytes {
+            return None;
+        }
+        Some(name.to_vec())
+    }
+
+    fn chunk_aad(id: &[u8; 16], idx: u64) -> [u8; 24] {
+        let mut aad = [0u8; 24];
+        aad[..16].copy_from_slice(id);
+        aad[16..].copy_from_slice(&idx.to_be_bytes());
+        aad
+    }
+
+    fn encrypt_chunk(&self, id: &[u8; 16], idx: u64, data: &[u8]) -> FsResult<Vec<u8>> {
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce_bytes)
+            .map_err(|_| FsError::GeneralFailure)?;
+        let nonce = Nonce::assume_unique_for_key(nonce_bytes);
+        let mut out = nonce_bytes.to_vec();
+        out.extend_from_slice(data);
+        let aad = Aad::from(Keys::chunk_aad(id, idx));
+        let tag = self
+            .content
+            .seal_in_place_separate_tag(nonce, aad, &mut out[NONCE_LEN..])
+            .map_err(|_| FsError::GeneralFailure)?;
+        out.extend_from_slice(tag.as_ref());
+        Ok(out)
+    }
+
+    fn decrypt_chunk(&self, id: &[u8; 16], idx: u64, mut data: Vec<u8>) -> FsResult<Vec<u8>> {
+        if data.len() < OVERHEAD {
+            return Err(FsError::GeneralFailure);
+        }
+        let nonce = Nonce::try_assume_unique_for_key(&data[..NONCE_LEN]).unwrap();
+        let aad = Aad::from(Keys::chunk_aad(id, idx));
+        let len = self
+            .content
+            .open_in_place(nonce, aad, &mut data[NONCE_LEN..])
+            .map_err(|_| FsError::GeneralFailure)?
+            .len();
+        data.drain(..NONCE_LEN);
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+/// Size of the contents of an encrypted file of `len` bytes.
+fn plain_len(len: u64) -> u64 {
+    if len <= HEADER_LEN {
+        return 0;
+    }
+    let len = len - HEADER_LEN;
+    (len / CT_CHUNK) * CHUNK as u64 + (len % CT_CHUNK).saturating_sub(OVERHEAD as u64)
+}
+
+#[derive(Clone)]
+pub struct CryptFs {
+    fs:    Box<dyn DavFileSystem>,
+    keys:  Arc<Keys>,
+    names: bool,
+}
+
+impl CryptFs {
+    pub fn new(fs: Box<dyn DavFileSystem>, keys: Arc<Keys>, names: bool) -> Box<CryptFs> {
+        Box::new(CryptFs { fs, keys, names })
+    }
+
+    // The path on disk.
+    fn path(&self, path: &DavPath) -> FsResult<DavPath> {
+        if !self.names {
+            return Ok(path.clone());
+        }
+        let mut disk = String::new();
+        for seg in path.as_bytes().split(|&c| c == b'/').filter(|s| !s.is_empty()) {
+            disk.push('/');
+            disk.push_str(&self.keys.encrypt_name(seg)?);
+        }
+        if disk.is_empty() || path.is_collection() {
+            disk.push('/');
+        }
+        DavPath::new(&disk).map_err(|_| FsError::GeneralFailure)
+    }
+}
+
+impl DavFileSystem for CryptFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let path = self.path(path)?;
+            // we need to read when writing, and do the appending ourselves.
+            let mut oo = options;
+            oo.read = true;
+            oo.append = false;
+            let file = self.fs.open(&path, oo).await?;
+            let file = CryptFile::new(file, self.keys.clone(), options.append).await?;
+            Ok(Box::new(file) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            let strm = self.fs.read_dir(&self.path(path)?, meta).await?;
+            let keys = self.keys.clone();
+            let names = self.names;
+            let strm = strm.filter_map(move |entry| {
+                let name = match names {
+                    true => keys.decrypt_name(&entry.name()),
+                    false => Some(entry.name()),
+                };
+                // names that do not decrypt are not ours.
+                let entry = name.map(|name| Box::new(CryptDirEntry { entry, name }) as Box<dyn DavDirEntry>);
+                futures::future::ready(entry)
+            });
+            Ok(Box::pin(strm) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move { Ok(CryptMeta::wrap(self.fs.metadata(&self.path(path)?).await?)) }.boxed()
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move { Ok(CryptMeta::wrap(self.fs.symlink_metadata(&self.path(path)?).await?)) }.boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move { self.fs.create_dir(&self.path(path)?).await }.boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move { self.fs.remove_dir(&self.path(path)?).await }.boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move { self.fs.remove_file(&self.path(path)?).await }.boxed()
+    }
+
+    // the file id moves along, so the chunks still decrypt.
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move { self.fs.rename(&self.path(from)?, &self.path(to)?).await }.boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move { self.fs.copy(&self.path(from)?, &self.path(to)?).await }.boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move { self.fs.set_accessed(&self.path(path)?, tm).await }.boxed()
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move { self.fs.set_modified(&self.path(path)?, tm).await }.boxed()
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        async move {
+            match self.path(path) {
+                Ok(path) => self.fs.have_props(&path).await,
+                Err(_) => false,
+            }
+        }
+        .boxed()
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        async move { self.fs.patch_props(&self.path(path)?, patch).await }.boxed()
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        async move { self.fs.get_props(&self.path(path)?, do_content).await }.boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        async move { self.fs.get_prop(&self.path(path)?, prop).await }.boxed()
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+// Metadata, with the size of the contents instead of the file on disk.
+#[derive(Debug, Clone)]
+struct CryptMeta {
+    meta: Box<dyn DavMetaData>,
+    len:  u64,
+}
+
+impl CryptMeta {
+    fn wrap(meta: Box<dyn DavMetaData>) -> Box<dyn DavMetaData> {
+        let len = if meta.is_file() { plain_len(meta.len()) } else { meta.len() };
+        Box::new(CryptMeta { meta, len })
+    }
+}
+
+impl DavMetaData for CryptMeta {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        self.meta.modified()
+    }
+
+    fn is_dir(&self) -> bool {
+        self.meta.is_dir()
+    }
+
+    fn etag(&self) -> Option<String> {
+        self.meta.etag()
+    }
+
+    fn is_file(&self) -> bool {
+        self.meta.is_file()
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.meta.is_symlink()
+    }
+
+    fn accessed(&self) -> FsResult<SystemTime> {
+        self.meta.accessed()
+    }
+
+    fn created(&self) -> FsResult<SystemTime> {
+        self.meta.created()
+    }
+
+    fn status_changed(&self) -> FsResult<SystemTime> {
+        self.meta.status_changed()
+    }
+
+    fn executable(&self) -> FsResult<bool> {
+        self.meta.executable()
+    }
+}
+
+struct CryptDirEntry {
+    entry: Box<dyn DavDirEntry>,
+    name:  Vec<u8>,
+}
+
+impl DavDirEntry for CryptDirEntry {
+    fn name(&self) -> Vec<u8> {
+        self.name.clone()
+    }
+
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        async move { Ok(CryptMeta::wrap(self.entry.metadata().await?)) }.boxed()
+    }
+
+    fn is_dir(&self) -> FsFuture<'_, bool> {
+        self.entry.is_dir()
+    }
+
+    fn is_file(&self) -> FsFuture<'_, bool> {
+        self.entry.is_file()
+    }
+
+    fn is_symlink(&self) -> FsFuture<'_, bool> {
+        self.entry.is_symlink()
+    }
+}
+
+// The chunk that's being read or written.
+struct Chunk {
+    idx:   u64,
+    data:  Vec<u8>,
+    dirty: bool,
+}
+
+struct CryptFile {
+    file:   Box<dyn DavFile>,
+    keys:   Arc<Keys>,
+    // None for a new file, until the header is written.
+    id:     Option<[u8; 16]>,
+    size:   u64,
+    pos:    u64,
+    chunk:  Option<Chunk>,
+    append: bool,
+}
+
+impl fmt::Debug for CryptFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CryptFile")
+            .field("file", &self.file)
+            .field("size", &self.size)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+// Read up to "count" bytes, less only at the end of the file.
+async fn read_full(file: &mut Box<dyn DavFile>, count: usize) -> FsResult<Vec<u8>> {
+    let mut buf = Vec::with_capacity(count);
+    while buf.len() < count {
+        let data = file.read_bytes(count - buf.len()).await?;
+        if data.is_empty() {
+            break;
+        }
+        buf.extend_from_slice(&data);
+    }
+    Ok(buf)
+}
+
+impl CryptFile {
+    async fn new(mut file: Box<dyn DavFile>, keys: Arc<Keys>, append: bool) -> FsResult<CryptFile> {
+        let len = file.metadata().await?.len();
+        let id = match len {
+            0 => None,
+            _ => {
+                let header = read_full(&mut file, HEADER_LEN as usize).await?;
+                if header.len() as u64 != HEADER_LEN || &header[..4] != MAGIC {
+                    debug!("CryptFile::new: not an encrypted file");
+                    return Err(FsError::GeneralFailure);
+                }
+                let mut id = [0u8; 16];
+                id.copy_from_slice(&header[4..]);
+                Some(id)
+            },
+        };
+        Ok(CryptFile {
+            file,
+            keys,
+            id,
+            size: plain_len(len),
+            pos: 0,
+            chunk: None,
+            append,
+        })
+    }
+
+    // Encrypt the current chunk, and write it.
+    async fn write_chunk(&mut self) -> FsResult<()> {
+        let chunk = match self.chunk {
+            Some(ref mut chunk) if chunk.dirty => chunk,
+            _ => return Ok(()),
+        };
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                let mut id = [0u8; 16];
+                SystemRandom::new().fill(&mut id).map_err(|_| FsError::GeneralFailure)?;
+                let mut header = MAGIC.to_vec();
+                header.extend_from_slice(&id);
+                self.file.seek(SeekFrom::Start(0)).await?;
+                self.file.write_bytes(Bytes::from(header)).await?;
+                self.id = Some(id);
+                id
+            },
+        };
+        let data = self.keys.encrypt_chunk(&id, chunk.idx, &chunk.data)?;
+        self.file.seek(SeekFrom::Start(HEADER_LEN + chunk.idx * CT_CHUNK)).await?;
+        self.file.write_bytes(Bytes::from(data)).await?;
+        chunk.dirty = false;
+        Ok(())
+    }
+
+    // Make chunk "idx" the current chunk.
+    async fn load_chunk(&mut self, idx: u64) -> FsResult<&mut Chunk> {
+        if self.chunk.as_ref().map(|c| c.idx) != Some(idx) {
+            self.write_chunk().await?;
+            let data = match self.id {
+                Some(ref id) if idx * (CHUNK as u64) < self.size => {
+                    self.file.seek(SeekFrom::Start(HEADER_LEN + idx * CT_CHUNK)).await?;
+                    let data = read_full(&mut self.file, CT_CHUNK as usize).await?;
+                    self.keys.decrypt_chunk(id, idx, data)?
+                },
+                _ => Vec::new(),
+            };
+            self.chunk = Some(Chunk {
+                idx,
+                data,
+                dirty: false,
+            });
+        }
+        Ok(self.chunk.as_mut().unwrap())
+    }
+
+    async fn write_data(&mut self, mut buf: &[u8]) -> FsResult<()> {
+        while !buf.is_empty() {
+            let off = (self.pos % CHUNK as u64) as usize;
+            let chunk = self.load_chunk(self.pos / CHUNK as u64).await?;
+            let n = std::cmp::min(buf.len(), CHUNK - off);
+            if chunk.data.len() < off + n {
+                chunk.data.resize(off + n, 0);
+            }
+            chunk.data[off..off + n].copy_from_slice(&buf[..n]);
+            chunk.dirty = true;
+            let full = chunk.data.len() == CHUNK;
+            self.pos += n as u64;
+            self.size = std::cmp::max(self.size, self.pos);
+            buf = &buf[n..];
+            if full {
+                self.write_chunk().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> FsResult<()> {
+        if self.append {
+            self.pos = self.size;
+        }
+        // after a seek past the end, fill the hole.
+        if self.pos > self.size {
+            let end = self.pos;
+            self.pos = self.size;
+            let zeroes = vec![0u8; CHUNK];
+            while self.pos < end {
+                let n = std::cmp::min(end - self.pos, CHUNK as u64) as usize;
+                self.write_data(&zeroes[..n]).await?;
+            }
+        }
+        self.write_data(buf).await
+    }
+}
+
+impl DavFile for CryptFile {
+    fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        async move {
+            let meta = self.file.metadata().await?;
+            Ok(Box::new(CryptMeta { meta, len: self.size }) as Box<dyn DavMetaData>)
+        }
+        .boxed()
+    }
+
+    fn write_buf(&mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
+        async move {
+            while buf.has_remaining() {
+                let len = buf.chunk().len();
+                self.write(buf.chunk()).await?;
+                buf.advance(len);
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<'_, ()> {
+        async move { self.write(&buf).await }.boxed()
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, Bytes> {
+        async move {
+            let mut buf = Vec::new();
+            while buf.len() < count && self.pos < self.size {
+                let off = (self.pos % CHUNK as u64) as usize;
+                let chunk = self.load_chunk(self.pos / CHUNK as u64).await?;
+                if off >= chunk.data.len() {
+                    break;
+                }
+                let n = std::cmp::min(count - buf.len(), chunk.data.len() - off);
+                buf.extend_from_slice(&chunk.data[off..off + n]);
+                self.pos += n as u64;
+            }
+            Ok(Bytes::from(buf))
+        }
+        .boxed()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        let pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+            SeekFrom::End(d) => self.size.checked_add_signed(d),
+        };
+        let res = match pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            },
+            None => Err(FsError::GeneralFailure),
+        };
+        Box::pin(futures::future::ready(res))
+    }
+
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        async move {
+            self.write_chunk().await?;
+            self.file.flush().await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webdav_handler::memfs::MemFs;
+
+    fn path(p: &str) -> DavPath {
+        DavPath::new(p).unwrap()
+    }
+
+    #[test]
+    fn test_names() {
+        let keys = Keys::from_master(&[7u8; 32], Some("alice"));
+        let enc = keys.encrypt_name(b"hello.txt").unwrap();
+        assert_eq!(enc, keys.encrypt_name(b"hello.txt").unwrap());
+        assert_eq!(keys.decrypt_name(enc.as_bytes()).unwrap(), b"hello.txt");
+        let other = Keys::from_master(&[7u8; 32], Some("bob"));
+        assert!(other.decrypt_name(enc.as_bytes()).is_none());
+    }
+
+    #[test]
+    fn test_plain_len() {
+        assert_eq!(plain_len(0), 0);
+        assert_eq!(plain_len(HEADER_LEN + OVERHEAD as u64 + 10), 10);
+        assert_eq!(plain_len(HEADER_LEN + CT_CHUNK * 2 + OVERHEAD as u64 + 1), CHUNK as u64 * 2 + 1);
+    }
+
+    #[tokio::test]
+    async fn test_contents() {
+        let mem = MemFs::new();
+        let fs = CryptFs::new(mem.clone(), Keys::from_master(&[1u8; 32], None), true);
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let oo = OpenOptions {
+            write: true,
+            create: true,
+            truncate: true,
+            ..OpenOptions::default()
+        };
+        let mut file = fs.open(&path("/f"), oo).await.unwrap();
+        file.write_bytes(Bytes::from(data.clone())).await.unwrap();
+        file.flush().await.unwrap();
+
+        // overwrite a range that spans two chunks.
+        let oo = OpenOptions {
+            write: true,
+            ..OpenOptions::default()
+        };
+        let mut file = fs.open(&path("/f"), oo).await.unwrap();
+        file.seek(SeekFrom::Start(65530)).await.unwrap();
+        file.write_bytes(Bytes::from_static(b"0123456789")).await.unwrap();
+        file.flush().await.unwrap();
+        let mut expect = data.clone();
+        expect[65530..65540].copy_from_slice(b"0123456789");
+
+        assert_eq!(fs.metadata(&path("/f")).await.unwrap().len(), 200_000);
+        let oo = OpenOptions {
+            read: true,
+            ..OpenOptions::default()
+        };
+        let mut file = fs.open(&path("/f"), oo).await.unwrap();
+        file.seek(SeekFrom::Start(65000)).await.unwrap();
+        let got = file.read_bytes(1000).await.unwrap();
+        assert_eq!(&got[..], &expect[65000..66000]);
+        file.seek(SeekFrom::Start(0)).await.unwrap();
+        assert_eq!(&file.read_bytes(300_000).await.unwrap()[..], &expect[..]);
+
+        // nothing readable on disk.
+        let mut names = mem.read_dir(&path("/"), ReadDirMeta::None).await.unwrap();
+        let name = names.next().await.unwrap().name();
+        assert_ne!(name, b"f");
+    }
+}
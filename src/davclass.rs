@@ -0,0 +1,77 @@
+//
+// The DAV compliance classes of a location (dav-class = [ 1, 2, 3 ]).
+//
+// Class 2 is locking: without it LOCK and UNLOCK are not allowed. And a
+// location that does not allow LOCK does not say that it has class 2.
+// The DAV header of an OPTIONS response has the classes, extended-mkcol
+// if MKCOL is allowed (RFC 5689), and sabredav-partialupdate if PATCH is
+// allowed; the Allow header only has the methods of the location.
+//
+use webdav_handler::{DavMethod, DavMethodSet};
+
+use crate::config::Location;
+
+/// The methods of a location, without locking if it is not class 2.
+pub fn methods(location: &Location, mut methods: DavMethodSet) -> DavMethodSet {
+    if location.dav_class.as_ref().map(|c| !c.contains(&2)).unwrap_or(false) {
+        methods.remove(DavMethod::Lock);
+        methods.remove(DavMethod::Unlock);
+    }
+    methods
+}
+
+fn dav_header(location: &Location, methods: DavMethodSet) -> String {
+    let classes = location.dav_class.clone().unwrap_or_else(|| vec![1, 2, 3]);
+    let mut dav = Vec::new();
+    for class in [1, 2, 3].iter().filter(|c| classes.contains(c)) {
+        if *class != 2 || methods.contains(DavMethod::Lock) {
+            dav.push(class.to_string());
+        }
+    }
+    if methods.contains(DavMethod::MkCol) {
+        dav.push("extended-mkcol".to_string());
+    }
+    if methods.contains(DavMethod::Patch) {
+        dav.push("sabredav-partialupdate".to_string());
+    }
+    dav.join(",")
+}
+
+/// Put the classes in the DAV header of an OPTIONS response.
+pub fn options(location: &Location, methods: DavMethodSet, headers: &mut http::HeaderMap) {
+    if !headers.contains_key("DAV") {
+        return;
+    }
+    if let Ok(value) = dav_header(location, methods).parse() {
+        headers.insert("DAV", value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_davclass() {
+        let location = |extra: &str| {
+            let toml = format!("route = [ \"/\" ]\ndirectory = \"/\"\nhandler = \"filesystem\"\n{}", extra);
+            toml::from_str::<Location>(&toml).unwrap()
+        };
+        let rw = DavMethodSet::WEBDAV_RW;
+        assert_eq!(dav_header(&location(""), rw), "1,2,3,extended-mkcol,sabredav-partialupdate");
+        assert_eq!(dav_header(&location(""), DavMethodSet::WEBDAV_RO), "1,3");
+
+        let l = location("dav-class = [ 1, 3 ]");
+        assert!(!methods(&l, rw).contains(DavMethod::Lock));
+        assert!(methods(&l, rw).contains(DavMethod::Put));
+        assert_eq!(dav_header(&l, methods(&l, rw)), "1,3,extended-mkcol,sabredav-partialupdate");
+
+        let l = location("methods = [ \"webdav-rw\", \"-proppatch\", \"-lock\", \"-unlock\" ]");
+        let m = l.methods.unwrap();
+        assert!(m.contains(DavMethod::Put) && !m.contains(DavMethod::PropPatch));
+        assert!(!m.contains(DavMethod::Lock));
+        let l = location("methods = [ \"-patch\" ]");
+        let m = l.methods.unwrap();
+        assert!(m.contains(DavMethod::Lock) && !m.contains(DavMethod::Patch));
+    }
+}
@@ -0,0 +1,643 @@
+//
+// Dead property storage.
+//
+// Properties set with PROPPATCH are kept in a database, for filesystems
+// that cannot store them themselves. They are keyed by the directory of
+// the location ("root") and the path below it. For local filesystems the
+// inode is stored as well: if a file was replaced behind our back, its
+// old properties are dropped.
+//
+// The number of properties and the size of their XML can be limited per
+// resource, and per user over all of the database (the user that set a
+// property owns it). A PROPPATCH that would go over a limit fails with
+// "507 Insufficient Storage"; one that removes properties always works.
+//
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use futures::future::FutureExt;
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+lazy_static::lazy_static! {
+    static ref STORES: Mutex<HashMap<String, Arc<dyn PropStore>>> = Mutex::new(HashMap::new());
+}
+
+/// A dead property, with the inode of the file it was set on.
+pub struct StoredProp {
+    pub inode: Option<u64>,
+    pub owner: Option<String>,
+    pub prop:  DavProp,
+}
+
+/// Limits on the number of properties and their size, per resource and
+/// per user.
+#[derive(Debug, Default, Clone)]
+pub struct Limits {
+    pub count:      Option<u64>,
+    pub size:       Option<u64>,
+    pub user_count: Option<u64>,
+    pub user_size:  Option<u64>,
+}
+
+/// Where the properties are kept.
+pub trait PropStore: Send + Sync {
+    /// All properties of a path.
+    fn load(&self, root: &str, path: &[u8]) -> io::Result<Vec<StoredProp>>;
+    /// Set (true) or remove (false) properties.
+    fn patch(
+        &self,
+        root: &str,
+        path: &[u8],
+        inode: Option<u64>,
+        owner: Option<&str>,
+        patch: &[(bool, DavProp)],
+    ) -> io::Result<()>;
+    /// The number of properties of a user, and their size.
+    fn usage(&self, owner: &str) -> io::Result<(u64, u64)>;
+    /// Remove the properties of a path, and of everything below it.
+    fn remove(&self, root: &str, path: &[u8]) -> io::Result<()>;
+    /// Move the properties of a path, and of everything below it.
+    fn rename(&self, root: &str, from: &[u8], to: &[u8]) -> io::Result<()>;
+    /// Copy the properties of one path.
+    fn copy(&self, root: &str, from: &[u8], to: &[u8]) -> io::Result<()>;
+    /// Give the space of removed properties back to the filesystem.
+    fn compact(&self) -> io::Result<()>;
+}
+
+/// The store in database file "path", opened on first use.
+pub fn store(path: &str) -> io::Result<Arc<dyn PropStore>> {
+    let mut stores = STORES.lock().unwrap();
+    if let Some(store) = stores.get(path) {
+        return Ok(store.clone());
+    }
+    let store = Arc::new(SqliteStore::open(path)?) as Arc<dyn PropStore>;
+    stores.insert(path.to_string(), store.clone());
+    Ok(store)
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+// The path without a trailing slash. "/" stays "/".
+fn key(path: &DavPath) -> Vec<u8> {
+    let p = path.as_bytes();
+    match p.len() {
+        0 | 1 => b"/".to_vec(),
+        n if p[n - 1] == b'/' => p[..n - 1].to_vec(),
+        _ => p.to_vec(),
+    }
+}
+
+// Is "path" equal to, or below, "dir".
+fn is_below(dir: &[u8], path: &[u8]) -> bool {
+    path == dir || dir == b"/" || (path.starts_with(dir) && path[dir.len()] == b'/')
+}
+
+// The size of a property: the length of its XML.
+fn size(prop: &DavProp) -> u64 {
+    prop.xml.as_ref().map(|x| x.len() as u64).unwrap_or(0)
+}
+
+// What a patch does to the properties of a resource: how many there are
+// and their size, before and after, and the change for the owner.
+#[derive(Debug, Default, PartialEq)]
+struct Change {
+    before: (u64, u64),
+    after:  (u64, u64),
+    owner:  (i64, i64),
+}
+
+fn change(current: &[StoredProp], patch: &[(bool, DavProp)], owner: Option<&str>) -> Change {
+    let key = |p: &DavProp| (p.namespace.clone(), p.name.clone());
+    let mut props: HashMap<_, _> = current
+        .iter()
+        .map(|p| (key(&p.prop), (size(&p.prop), p.owner.as_deref())))
+        .collect();
+    let total = |props: &HashMap<_, (u64, _)>| (props.len() as u64, props.values().map(|v| v.0).sum());
+    let mut change = Change {
+        before: total(&props),
+        ..Change::default()
+    };
+    for (set, prop) in patch {
+        if let Some((len, old)) = props.remove(&key(prop)) {
+            if owner.is_some() && old == owner {
+                change.owner.0 -= 1;
+                change.owner.1 -= len as i64;
+            }
+        }
+        if *set {
+            let len = size(prop);
+            props.insert(key(prop), (len, owner));
+            if owner.is_some() {
+                change.owner.0 += 1;
+                change.owner.1 += len as i64;
+            }
+        }
+    }
+    change.after = total(&props);
+    change
+}
+
+impl Limits {
+    // Does the change go over a limit. Only a change that adds counts,
+    // so that properties can always be removed. "usage" is what the owner
+    // has now.
+    fn exceeded<F>(&self, change: &Change, usage: F) -> FsResult<bool>
+    where F: FnOnce() -> FsResult<(u64, u64)> {
+        let over = |limit: Option<u64>, before: u64, after: u64| {
+            limit.map(|limit| after > before && after > limit).unwrap_or(false)
+        };
+        let (before, after) = (change.before, change.after);
+        if over(self.count, before.0, after.0) || over(self.size, before.1, after.1) {
+            return Ok(true);
+        }
+        let (count, bytes) = change.owner;
+        if (self.user_count.is_none() && self.user_size.is_none()) || (count <= 0 && bytes <= 0) {
+            return Ok(false);
+        }
+        let (now_count, now_bytes) = usage()?;
+        let after = |now: u64, delta: i64| (now as i64 + delta).max(0) as u64;
+        Ok(over(self.user_count, now_count, after(now_count, count)) ||
+            over(self.user_size, now_bytes, after(now_bytes, bytes)))
+    }
+}
+
+pub struct SqliteStore {
+    db: Mutex<rusqlite::Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> io::Result<SqliteStore> {
+        let db = rusqlite::Connection::open(path).map_err(sql_error)?;
+        db.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS props (
+                 root   TEXT NOT NULL,
+                 path   BLOB NOT NULL,
+                 ns     TEXT NOT NULL,
+                 name   TEXT NOT NULL,
+                 prefix TEXT,
+                 xml    BLOB,
+                 inode  INTEGER,
+                 owner  TEXT,
+                 PRIMARY KEY (root, path, ns, name)
+             );",
+        )
+        .map_err(sql_error)?;
+        // databases from before the owner was kept.
+        if db.prepare("SELECT owner FROM props LIMIT 0").is_err() {
+            db.execute_batch("ALTER TABLE props ADD COLUMN owner TEXT;").map_err(sql_error)?;
+        }
+        db.execute_batch("CREATE INDEX IF NOT EXISTS props_owner ON props (owner);")
+            .map_err(sql_error)?;
+        Ok(SqliteStore { db: Mutex::new(db) })
+    }
+
+    // The paths with properties, at and below "dir".
+    fn paths_below(db: &rusqlite::Connection, root: &str, dir: &[u8]) -> rusqlite::Result<Vec<Vec<u8>>> {
+        let mut stmt = db.prepare_cached("SELECT DISTINCT path FROM props WHERE root = ?1")?;
+        let rows = stmt.query_map(rusqlite::params![root], |row| row.get::<_, Vec<u8>>(0))?;
+        let mut paths = Vec::new();
+        for path in rows {
+            let path = path?;
+            if is_below(dir, &path) {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+}
+
+impl PropStore for SqliteStore {
+    fn load(&self, root: &str, path: &[u8]) -> io::Result<Vec<StoredProp>> {
+        let db = self.db.lock().unwrap();
+        let mut stmt = db
+            .prepare_cached(
+                "SELECT ns, name, prefix, xml, inode, owner FROM props WHERE root = ?1 AND path = ?2",
+            )
+            .map_err(sql_error)?;
+        let rows = stmt
+            .query_map(rusqlite::params![root, path], |row| {
+                let ns: String = row.get(0)?;
+                Ok(StoredProp {
+                    prop:  DavProp {
+                        namespace: if ns.is_empty() { None } else { Some(ns) },
+                        name:      row.get(1)?,
+                        prefix:    row.get(2)?,
+                        xml:       row.get(3)?,
+                    },
+                    inode: row.get::<_, Option<i64>>(4)?.map(|i| i as u64),
+                    owner: row.get(5)?,
+                })
+            })
+            .map_err(sql_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(sql_error)
+    }
+
+    fn patch(
+        &self,
+        root: &str,
+        path: &[u8],
+        inode: Option<u64>,
+        owner: Option<&str>,
+        patch: &[(bool, DavProp)],
+    ) -> io::Result<()>
+    {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction().map_err(sql_error)?;
+        for (set, prop) in patch {
+            let ns = prop.namespace.as_deref().unwrap_or("");
+            if *set {
+                tx.execute(
+                    "INSERT OR REPLACE INTO props (root, path, ns, name, prefix, xml, inode, owner)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    rusqlite::params![
+                        root,
+                        path,
+                        ns,
+                        prop.name,
+                        prop.prefix,
+                        prop.xml,
+                        inode.map(|i| i as i64),
+                        owner
+                    ],
+                )
+            } else {
+                tx.execute(
+                    "DELETE FROM props WHERE root = ?1 AND path = ?2 AND ns = ?3 AND name = ?4",
+                    rusqlite::params![root, path, ns, prop.name],
+                )
+            }
+            .map_err(sql_error)?;
+        }
+        tx.execute(
+            "UPDATE props SET inode = ?3 WHERE root = ?1 AND path = ?2",
+            rusqlite::params![root, path, inode.map(|i| i as i64)],
+        )
+        .map_err(sql_error)?;
+        tx.commit().map_err(sql_error)
+    }
+
+    fn usage(&self, owner: &str) -> io::Result<(u64, u64)> {
+        let db = self.db.lock().unwrap();
+        let mut stmt = db
+            .prepare_cached("SELECT COUNT(*), TOTAL(LENGTH(xml)) FROM props WHERE owner = ?1")
+            .map_err(sql_error)?;
+        stmt.query_row(rusqlite::params![owner], |row| {
+            Ok((row.get::<_, i64>(0)? as u64, row.get::<_, f64>(1)? as u64))
+        })
+        .map_err(sql_error)
+    }
+
+    fn remove(&self, root: &str, path: &[u8]) -> io::Result<()> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction().map_err(sql_error)?;
+        for p in SqliteStore::paths_below(&tx, root, path).map_err(sql_error)? {
+            tx.execute("DELETE FROM props WHERE root = ?1 AND path = ?2", rusqlite::params![root, p])
+                .map_err(sql_error)?;
+        }
+        tx.commit().map_err(sql_error)
+    }
+
+    fn rename(&self, root: &str, from: &[u8], to: &[u8]) -> io::Result<()> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction().map_err(sql_error)?;
+        // whatever was at the destination is gone.
+        for p in SqliteStore::paths_below(&tx, root, to).map_err(sql_error)? {
+            tx.execute("DELETE FROM props WHERE root = ?1 AND path = ?2", rusqlite::params![root, p])
+                .map_err(sql_error)?;
+        }
+        for p in SqliteStore::paths_below(&tx, root, from).map_err(sql_error)? {
+            let mut newpath = to.to_vec();
+            newpath.extend_from_slice(&p[from.len()..]);
+            tx.execute(
+                "UPDATE props SET path = ?3 WHERE root = ?1 AND path = ?2",
+                rusqlite::params![root, p, newpath],
+            )
+            .map_err(sql_error)?;
+        }
+        tx.commit().map_err(sql_error)
+    }
+
+    fn copy(&self, root: &str, from: &[u8], to: &[u8]) -> io::Result<()> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction().map_err(sql_error)?;
+        tx.execute("DELETE FROM props WHERE root = ?1 AND path = ?2", rusqlite::params![root, to])
+            .map_err(sql_error)?;
+        // the copy is a new file, so it gets its inode when it is next patched.
+        tx.execute(
+            "INSERT INTO props (root, path, ns, name, prefix, xml, inode, owner)
+             SELECT root, ?3, ns, name, prefix, xml, NULL, owner FROM props WHERE root = ?1 AND path = ?2",
+            rusqlite::params![root, from, to],
+        )
+        .map_err(sql_error)?;
+        tx.commit().map_err(sql_error)
+    }
+
+    fn compact(&self) -> io::Result<()> {
+        let db = self.db.lock().unwrap();
+        db.execute_batch("PRAGMA wal_checkpoint(TRUNCATE); VACUUM;").map_err(sql_error)
+    }
+}
+
+/// A filesystem, with its dead properties in a PropStore.
+#[derive(Clone)]
+pub struct PropFs {
+    fs:        Box<dyn DavFileSystem>,
+    store:     Arc<dyn PropStore>,
+    root:      String,
+    use_inode: bool,
+    limits:    Limits,
+    owner:     Option<String>,
+}
+
+impl PropFs {
+    /// With `use_inode`, the filesystem is a local one (see `inode`).
+    /// Properties that are set are owned by `owner`.
+    pub fn new(
+        fs: Box<dyn DavFileSystem>,
+        store: Arc<dyn PropStore>,
+        root: &str,
+        use_inode: bool,
+        limits: Limits,
+        owner: Option<&str>,
+    ) -> Box<PropFs>
+    {
+        Box::new(PropFs {
+            fs,
+            store,
+            root: root.to_string(),
+            use_inode,
+            limits,
+            owner: owner.map(|o| o.to_string()),
+        })
+    }
+
+    // LocalFs etags start with the inode number, in hex.
+    async fn inode(&self, path: &DavPath) -> FsResult<Option<u64>> {
+        let meta = self.fs.metadata(path).await?;
+        if !self.use_inode {
+            return Ok(None);
+        }
+        let etag = meta.etag().unwrap_or_default();
+        Ok(etag.split('-').next().and_then(|i| u64::from_str_radix(i, 16).ok()))
+    }
+
+    fn blocking<T, F>(&self, what: &str, f: F) -> FsResult<T>
+    where F: FnOnce(&dyn PropStore) -> io::Result<T> {
+        tokio::task::block_in_place(|| f(&*self.store)).map_err(|e| {
+            error!("dead properties: {}: {}", what, e);
+            FsError::GeneralFailure
+        })
+    }
+
+    // The properties of a path, minus those of a previous file with the same name.
+    async fn stored(&self, path: &DavPath) -> FsResult<Vec<StoredProp>> {
+        let inode = self.inode(path).await?;
+        let props = self.blocking("load", |s| s.load(&self.root, &key(path)))?;
+        if props.iter().any(|p| p.inode.is_some() && p.inode != inode) {
+            self.blocking("remove", |s| s.remove(&self.root, &key(path)))?;
+            return Ok(Vec::new());
+        }
+        Ok(props)
+    }
+
+    async fn load(&self, path: &DavPath) -> FsResult<Vec<DavProp>> {
+        Ok(self.stored(path).await?.into_iter().map(|p| p.prop).collect())
+    }
+
+    // Update the store after a change in the filesystem. The change has
+    // happened already, so a failure is only logged.
+    fn update<F>(&self, what: &str, f: F)
+    where F: FnOnce(&dyn PropStore) -> io::Result<()> {
+        let _ = self.blocking(what, f);
+    }
+}
+
+impl DavFileSystem for PropFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        self.fs.open(path, options)
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.remove_dir(path).await?;
+            self.update("remove", |s| s.remove(&self.root, &key(path)));
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.remove_file(path).await?;
+            self.update("remove", |s| s.remove(&self.root, &key(path)));
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.rename(from, to).await?;
+            self.update("rename", |s| s.rename(&self.root, &key(from), &key(to)));
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.copy(from, to).await?;
+            self.update("copy", |s| s.copy(&self.root, &key(from), &key(to)));
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, _path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        Box::pin(futures::future::ready(true))
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        async move {
+            // drop the properties of an older file first.
+            let current = self.stored(path).await?;
+            let owner = self.owner.as_deref();
+            let change = change(&current, &patch, owner);
+            let usage = || self.blocking("usage", |s| s.usage(owner.unwrap_or_default()));
+            if self.limits.exceeded(&change, usage)? {
+                debug!("dead properties: {}: over the limit", path);
+                return Err(FsError::InsufficientStorage);
+            }
+            let inode = self.inode(path).await?;
+            self.blocking("patch", |s| s.patch(&self.root, &key(path), inode, owner, &patch))?;
+            let result = patch
+                .into_iter()
+                .map(|(_, p)| {
+                    let prop = DavProp { xml: None, ..p };
+                    (StatusCode::OK, prop)
+                })
+                .collect();
+            Ok(result)
+        }
+        .boxed()
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        async move {
+            let mut props = self.load(path).await?;
+            if !do_content {
+                props.iter_mut().for_each(|p| p.xml = None);
+            }
+            Ok(props)
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        async move {
+            let props = self.load(path).await?;
+            props
+                .into_iter()
+                .find(|p| p.name == prop.name && p.namespace == prop.namespace)
+                .and_then(|p| p.xml)
+                .ok_or(FsError::NotFound)
+        }
+        .boxed()
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prop(name: &str, xml: &str) -> DavProp {
+        DavProp {
+            name:      name.to_string(),
+            prefix:    Some("x".to_string()),
+            namespace: Some("urn:x".to_string()),
+            xml:       Some(xml.as_bytes().to_vec()),
+        }
+    }
+
+    fn names(store: &SqliteStore, path: &[u8]) -> Vec<String> {
+        let mut names: Vec<_> = store.load("r", path).unwrap().into_iter().map(|p| p.prop.name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_store() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        store.patch("r", b"/d/a", Some(1), Some("bob"), &[(true, prop("one", "<x:one>1</x:one>"))]).unwrap();
+        store.patch("r", b"/d", None, Some("bob"), &[(true, prop("two", "<x:two/>"))]).unwrap();
+        store.patch("r", b"/dd", None, None, &[(true, prop("three", "<x:three/>"))]).unwrap();
+        assert_eq!(names(&store, b"/d/a"), vec!["one"]);
+        assert_eq!(store.load("r", b"/d/a").unwrap()[0].inode, Some(1));
+        assert!(store.load("other", b"/d/a").unwrap().is_empty());
+        assert_eq!(store.usage("bob").unwrap(), (2, 24));
+
+        store.rename("r", b"/d", b"/e").unwrap();
+        assert_eq!(names(&store, b"/e/a"), vec!["one"]);
+        assert_eq!(names(&store, b"/e"), vec!["two"]);
+        assert_eq!(names(&store, b"/dd"), vec!["three"]);
+
+        store.copy("r", b"/e/a", b"/f").unwrap();
+        assert_eq!(store.load("r", b"/f").unwrap()[0].inode, None);
+        store.patch("r", b"/f", None, None, &[(false, prop("one", ""))]).unwrap();
+        assert!(names(&store, b"/f").is_empty());
+
+        store.remove("r", b"/e").unwrap();
+        assert!(names(&store, b"/e/a").is_empty());
+        assert_eq!(names(&store, b"/dd"), vec!["three"]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let stored = |name: &str, xml: &str, owner: Option<&str>| {
+            StoredProp {
+                inode: None,
+                owner: owner.map(|o| o.to_string()),
+                prop:  prop(name, xml),
+            }
+        };
+        let current = vec![stored("one", "<x:one>1</x:one>", Some("bob")), stored("two", "<x:two/>", None)];
+        let patch = vec![(true, prop("one", "<x:one>11</x:one>")), (true, prop("three", "<x:three/>"))];
+        let c = change(&current, &patch, Some("bob"));
+        assert_eq!(c, Change {
+            before: (2, 24),
+            after:  (3, 35),
+            owner:  (1, 11),
+        });
+        let c = change(&current, &[(false, prop("two", ""))], Some("bob"));
+        assert_eq!(c.after, (1, 16));
+        assert_eq!(c.owner, (0, 0));
+
+        let usage = || Ok((10, 100));
+        let limits = |count, size, user_count, user_size| {
+            Limits {
+                count,
+                size,
+                user_count,
+                user_size,
+            }
+        };
+        let grow = change(&current, &patch, Some("bob"));
+        let shrink = change(&current, &[(false, prop("one", ""))], Some("bob"));
+        assert!(!Limits::default().exceeded(&grow, usage).unwrap());
+        assert!(limits(Some(2), None, None, None).exceeded(&grow, usage).unwrap());
+        assert!(!limits(Some(3), Some(35), None, None).exceeded(&grow, usage).unwrap());
+        assert!(limits(None, Some(34), None, None).exceeded(&grow, usage).unwrap());
+        assert!(!limits(Some(0), Some(0), None, None).exceeded(&shrink, usage).unwrap());
+        assert!(limits(None, None, Some(10), None).exceeded(&grow, usage).unwrap());
+        assert!(!limits(None, None, Some(11), Some(111)).exceeded(&grow, usage).unwrap());
+        assert!(limits(None, None, None, Some(110)).exceeded(&grow, usage).unwrap());
+        assert!(!limits(None, None, Some(0), Some(0)).exceeded(&shrink, || panic!()).unwrap());
+    }
+}
@@ -0,0 +1,156 @@
+//
+// DeltaV (RFC 3253), a subset on top of the file versions (versions-dir).
+//
+// Every file is under version control, and auto-versioned: a PUT keeps
+// the old contents as a version. Supported are:
+//
+// - VERSION-CONTROL: nothing to do, files are always under version control.
+// - CHECKOUT, UNCHECKOUT: nothing to do.
+// - CHECKIN: keep the current contents of the file as a version.
+// - REPORT DAV:version-tree: the versions of a file, with their properties.
+//
+// The versions are at their path in the versions directory, where they
+// can be fetched with GET, even if the directory is hidden.
+//
+use std::time::UNIX_EPOCH;
+
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::DavMethod;
+use xmltree::Element;
+
+use crate::report::{error, escape, status, wanted, Multistatus};
+use crate::versionfs::{Version, VersionFs};
+
+// The live properties of a version.
+const PROPS: &[&str] = &[
+    "version-name",
+    "creationdate",
+    "getcontentlength",
+    "getlastmodified",
+    "predecessor-set",
+    "successor-set",
+    "resourcetype",
+];
+
+const ALLOW: &str = "VERSION-CONTROL,REPORT,CHECKOUT,CHECKIN,UNCHECKOUT";
+
+/// For routing and permissions, a DeltaV method is taken as the WebDAV
+/// method it is like: REPORT as PROPFIND, the others as PROPPATCH.
+pub fn dav_method(method: &http::Method) -> Option<DavMethod> {
+    match method.as_str() {
+        "REPORT" => Some(DavMethod::PropFind),
+        "VERSION-CONTROL" | "CHECKOUT" | "CHECKIN" | "UNCHECKOUT" => Some(DavMethod::PropPatch),
+        _ => None,
+    }
+}
+
+/// Add version-control to the DAV header of an OPTIONS response, and the
+/// methods to the Allow header.
+pub fn options(headers: &mut http::HeaderMap) {
+    for (name, add) in [("DAV", "version-control"), ("Allow", ALLOW)].iter() {
+        let value = match headers.get(*name).and_then(|v| v.to_str().ok()) {
+            Some(v) => format!("{},{}", v, add),
+            None => continue,
+        };
+        if let Ok(value) = value.parse() {
+            headers.insert(*name, value);
+        }
+    }
+}
+
+/// Handle a DeltaV request, other than REPORT. "path" is the path of the
+/// request, and "prefix" the prefix of the location.
+pub async fn handle(
+    method: &http::Method,
+    fs: &VersionFs,
+    path: &DavPath,
+    prefix: &str,
+) -> http::Response<String>
+{
+    match method.as_str() {
+        "VERSION-CONTROL" | "CHECKOUT" | "UNCHECKOUT" => {
+            match fs.is_file(path).await {
+                Ok(true) => error(StatusCode::OK),
+                Ok(false) => error(StatusCode::FORBIDDEN),
+                Err(e) => error(status(e)),
+            }
+        },
+        "CHECKIN" => {
+            match fs.checkin(path).await {
+                Ok(version) => {
+                    let mut resp = error(StatusCode::CREATED);
+                    let href = format!("{}{}", prefix, version.as_url_string());
+                    if let Ok(href) = href.parse() {
+                        resp.headers_mut().insert("Location", href);
+                    }
+                    resp
+                },
+                Err(e) => error(status(e)),
+            }
+        },
+        _ => error(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+/// The DAV:version-tree report.
+pub async fn version_tree(
+    root: &Element,
+    fs: &VersionFs,
+    path: &DavPath,
+    prefix: &str,
+) -> http::Response<String>
+{
+    let versions = match fs.is_file(path).await {
+        Ok(true) => fs.versions(path).await,
+        Ok(false) => Ok(Vec::new()),
+        Err(e) => Err(e),
+    };
+    let versions = match versions {
+        Ok(v) => v,
+        Err(e) => return error(status(e)),
+    };
+
+    let wanted = wanted(root, PROPS);
+    let href = |v: &Version| format!("{}{}", prefix, v.path.as_url_string());
+
+    let mut multi = Multistatus::new();
+    for (idx, version) in versions.iter().enumerate() {
+        multi.props(&href(version), &wanted, |ns, name| {
+            match ns {
+                "DAV:" => prop_value(name, &versions, idx, &href),
+                _ => None,
+            }
+        });
+    }
+    multi.finish("")
+}
+
+// The value of a live property of versions[idx], as XML.
+fn prop_value(
+    name: &str,
+    versions: &[Version],
+    idx: usize,
+    href: &dyn Fn(&Version) -> String,
+) -> Option<String>
+{
+    let version = &versions[idx];
+    let set = |v: Option<&Version>| {
+        v.map(|v| format!("<D:href>{}</D:href>", escape(&href(v)))).unwrap_or_default()
+    };
+    let value = match name {
+        "version-name" => escape(&version.name),
+        "creationdate" => time::at_utc(time::Timespec::new(version.time, 0)).rfc3339().to_string(),
+        "getcontentlength" => version.meta.len().to_string(),
+        "getlastmodified" => {
+            let modified = version.meta.modified().ok()?;
+            let secs = modified.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+            time::at_utc(time::Timespec::new(secs, 0)).rfc822().to_string()
+        },
+        "predecessor-set" => set(idx.checked_sub(1).and_then(|i| versions.get(i))),
+        "successor-set" => set(versions.get(idx + 1)),
+        "resourcetype" => String::new(),
+        _ => return None,
+    };
+    Some(value)
+}
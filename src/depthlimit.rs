@@ -0,0 +1,264 @@
+//
+// PROPFIND with Depth: infinity (propfind-infinity = "allow"), and the
+// limits on it: how deep it goes below the collection of the request
+// (propfind-max-depth), and how many resources it shows at most
+// (propfind-max-resources).
+//
+// The walk is done by the handler; here the directories it reads are
+// counted and cut off. The response is still streamed. When something
+// was left out, there is a response for the collection of the request
+// at the end with "507 Insufficient Storage", so that a client knows it
+// did not get all of it, and can go on with Depth: 1.
+//
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use futures::future::{self, FutureExt};
+use futures::stream::{self, StreamExt};
+use http::StatusCode;
+use hyper::body::{Bytes, HttpBody};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::casefs::segments;
+
+const END: &[u8] = b"</D:multistatus>";
+
+/// Is this a PROPFIND with Depth: infinity (the default).
+pub fn is_infinity(headers: &http::HeaderMap) -> bool {
+    match headers.get("Depth").map(|d| d.to_str().map(str::trim)) {
+        None => true,
+        Some(Ok(d)) => d.eq_ignore_ascii_case("infinity"),
+        Some(Err(_)) => false,
+    }
+}
+
+#[derive(Clone)]
+pub struct DepthFs {
+    fs:        Box<dyn DavFileSystem>,
+    // the segments in the path of the request.
+    base:      usize,
+    max_depth: Option<u32>,
+    max_res:   Option<u64>,
+    count:     Arc<AtomicU64>,
+    truncated: Arc<AtomicBool>,
+}
+
+impl DepthFs {
+    pub fn new(
+        fs: Box<dyn DavFileSystem>,
+        path: &DavPath,
+        max_depth: Option<u32>,
+        max_res: Option<u64>,
+    ) -> Box<DepthFs>
+    {
+        Box::new(DepthFs {
+            fs,
+            base: segments(path).len(),
+            max_depth,
+            max_res,
+            count: Arc::new(AtomicU64::new(0)),
+            truncated: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Set when not all of the tree was shown.
+    pub fn truncated(&self) -> Arc<AtomicBool> {
+        self.truncated.clone()
+    }
+
+    // The members of a directory at this depth below the request are
+    // not shown.
+    fn too_deep(&self, path: &DavPath) -> bool {
+        let depth = segments(path).len().saturating_sub(self.base) as u64;
+        self.max_depth.map(|max| depth >= max as u64).unwrap_or(false)
+    }
+}
+
+impl DavFileSystem for DepthFs {
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            let mut strm = self.fs.read_dir(path, meta).await?;
+            if self.too_deep(path) {
+                if strm.next().await.is_some() {
+                    self.truncated.store(true, Ordering::SeqCst);
+                }
+                return Ok(Box::pin(stream::empty()) as FsStream<Box<dyn DavDirEntry>>);
+            }
+            let max = match self.max_res {
+                Some(max) => max,
+                None => return Ok(strm),
+            };
+            let count = self.count.clone();
+            let truncated = self.truncated.clone();
+            let strm = strm.take_while(move |_| {
+                let ok = count.fetch_add(1, Ordering::SeqCst) < max;
+                if !ok {
+                    truncated.store(true, Ordering::SeqCst);
+                }
+                future::ready(ok)
+            });
+            Ok(Box::pin(strm) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        self.fs.open(path, options)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_dir(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_file(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.rename(from, to)
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.copy(from, to)
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        self.fs.patch_props(path, patch)
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+// The 507 response, before the end of the multistatus.
+fn insert_507(data: &[u8], href: &str) -> Option<Vec<u8>> {
+    let end = data.windows(END.len()).rposition(|w| w == END)?;
+    let resp = format!(
+        "<D:response><D:href>{}</D:href>\
+         <D:status>HTTP/1.1 507 Insufficient Storage</D:status>\
+         <D:error><D:number-of-matches-within-limits/></D:error>\
+         <D:responsedescription>Too many resources, use a smaller Depth</D:responsedescription>\
+         </D:response>",
+        crate::report::escape(href)
+    );
+    let mut out = data[..end].to_vec();
+    out.extend_from_slice(resp.as_bytes());
+    out.extend_from_slice(&data[end..]);
+    Some(out)
+}
+
+/// The multistatus of the handler, with a 507 at the end if it was cut off.
+pub fn response(
+    resp: http::Response<hyper::Body>,
+    truncated: Arc<AtomicBool>,
+    href: String,
+) -> http::Response<hyper::Body>
+{
+    if resp.status() != StatusCode::MULTI_STATUS {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    parts.headers.remove(http::header::CONTENT_LENGTH);
+    // the last chunk is held back until the end.
+    let state = Some((body, None::<Bytes>, truncated, href));
+    let strm = stream::unfold(state, |state| {
+        async move {
+            let (mut body, mut last, truncated, href) = state?;
+            loop {
+                match body.data().await {
+                    Some(Ok(data)) => {
+                        if let Some(prev) = last.replace(data) {
+                            return Some((Ok(prev), Some((body, last, truncated, href))));
+                        }
+                    },
+                    Some(Err(e)) => return Some((Err(e), None)),
+                    None => {
+                        let last = last.unwrap_or_default();
+                        let out = match truncated.load(Ordering::SeqCst) {
+                            true => insert_507(&last, &href).map(Bytes::from).unwrap_or(last),
+                            false => last,
+                        };
+                        return Some((Ok(out), None));
+                    },
+                }
+            }
+        }
+    });
+    http::Response::from_parts(parts, hyper::Body::wrap_stream(strm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_depthlimit() {
+        let body = b"<D:multistatus xmlns:D=\"DAV:\"><D:response/></D:multistatus>\n";
+        let out = String::from_utf8(insert_507(body, "/a&b/").unwrap()).unwrap();
+        let start = "<D:multistatus xmlns:D=\"DAV:\"><D:response/><D:response><D:href>/a&amp;b/</D:href>";
+        assert!(out.starts_with(start));
+        assert!(out.contains("507 Insufficient Storage"));
+        assert!(out.ends_with("</D:response></D:multistatus>\n"));
+        assert!(insert_507(b"<D:response/>", "/").is_none());
+
+        let mut headers = http::HeaderMap::new();
+        assert!(is_infinity(&headers));
+        headers.insert("Depth", "Infinity".parse().unwrap());
+        assert!(is_infinity(&headers));
+        headers.insert("Depth", "1".parse().unwrap());
+        assert!(!is_infinity(&headers));
+
+        let fs = webdav_handler::memfs::MemFs::new();
+        let dfs = DepthFs::new(fs, &DavPath::new("/a/").unwrap(), Some(2), None);
+        assert!(!dfs.too_deep(&DavPath::new("/a/").unwrap()));
+        assert!(!dfs.too_deep(&DavPath::new("/a/b/").unwrap()));
+        assert!(dfs.too_deep(&DavPath::new("/a/b/c/").unwrap()));
+    }
+}
@@ -0,0 +1,204 @@
+//
+// HTTP Digest authentication (RFC 7616, MD5 only, qop=auth).
+//
+// Nonces are stateless: a timestamp plus a MAC over that timestamp,
+// made with a secret that is generated at startup. Nonce counts are
+// not tracked, so a captured request can be replayed while the nonce
+// is still valid.
+//
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use md5::{Digest, Md5};
+
+lazy_static! {
+    static ref SECRET: [u8; 16] = {
+        let mut buf = [0u8; 16];
+        if let Err(e) = std::fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut buf)) {
+            panic!("digest: cannot read /dev/urandom: {}", e);
+        }
+        buf
+    };
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Nonce {
+    Valid,
+    Stale,
+    Invalid,
+}
+
+fn md5_hex(data: &str) -> String {
+    format!("{:x}", Md5::digest(data.as_bytes()))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn nonce_mac(ts: &str) -> String {
+    let mut ctx = Md5::new();
+    ctx.update(ts.as_bytes());
+    ctx.update(b":");
+    ctx.update(&SECRET[..]);
+    format!("{:x}", ctx.finalize())
+}
+
+/// Generate a new nonce.
+pub fn new_nonce() -> String {
+    let ts = format!("{:x}", now());
+    let mac = nonce_mac(&ts);
+    format!("{}.{}", ts, mac)
+}
+
+/// Check if a nonce is one of ours, and not too old.
+pub fn check_nonce(nonce: &str, lifetime: u64) -> Nonce {
+    let (ts, mac) = match nonce.split_once('.') {
+        Some(v) => v,
+        None => return Nonce::Invalid,
+    };
+    if !crate::htpasswd::consteq(nonce_mac(ts).as_bytes(), mac.as_bytes()) {
+        return Nonce::Invalid;
+    }
+    match u64::from_str_radix(ts, 16) {
+        Ok(ts) if now().saturating_sub(ts) <= lifetime => Nonce::Valid,
+        Ok(_) => Nonce::Stale,
+        Err(_) => Nonce::Invalid,
+    }
+}
+
+/// The value for the WWW-Authenticate header.
+pub fn challenge(realm: &str, stale: bool) -> String {
+    format!(
+        "Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"{}",
+        realm,
+        new_nonce(),
+        if stale { ", stale=true" } else { "" }
+    )
+}
+
+/// Parse the parameters of an "Authorization: Digest" header.
+pub fn parse(params: &str) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    let mut chars = params.chars().peekable();
+    loop {
+        // skip whitespace and commas.
+        while chars.peek().map(|c| c.is_whitespace() || *c == ',').unwrap_or(false) {
+            chars.next();
+        }
+        // key.
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c == ',' || c.is_whitespace() {
+                break;
+            }
+            key.push(c);
+            chars.next();
+        }
+        if key.is_empty() {
+            break;
+        }
+        while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            chars.next();
+        }
+        if chars.peek() != Some(&'=') {
+            map.insert(key.to_ascii_lowercase(), String::new());
+            continue;
+        }
+        chars.next();
+        while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            chars.next();
+        }
+        // value, either a token or a quoted-string.
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' || c.is_whitespace() {
+                    break;
+                }
+                value.push(c);
+                chars.next();
+            }
+        }
+        map.insert(key.to_ascii_lowercase(), value);
+    }
+    map
+}
+
+/// Calculate the expected response.
+pub fn response(ha1: &str, method: &str, params: &HashMap<String, String>) -> Option<String> {
+    let get = |k: &str| params.get(k).map(|s| s.as_str());
+    let ha2 = md5_hex(&format!("{}:{}", method, get("uri")?));
+    let nonce = get("nonce")?;
+    match get("qop") {
+        Some("auth") => {
+            let nc = get("nc")?;
+            let cnonce = get("cnonce")?;
+            Some(md5_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc, cnonce, ha2)))
+        },
+        Some(_) => None,
+        None => Some(md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2))),
+    }
+}
+
+/// Find the HA1 hash for user / realm in a htdigest file.
+pub fn lookup_ha1(file: &str, user: &str, realm: &str) -> io::Result<Option<String>> {
+    let data = std::fs::read_to_string(file)?;
+    let lines = data
+        .split('\n')
+        .map(|s| s.trim())
+        .filter(|s| !s.starts_with('#') && !s.is_empty());
+    for line in lines {
+        let mut fields = line.splitn(3, ':');
+        if let (Some(u), Some(r), Some(ha1)) = (fields.next(), fields.next(), fields.next()) {
+            if u == user && r == realm {
+                return Ok(Some(ha1.to_ascii_lowercase()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example from RFC 2617, section 3.5.
+    #[test]
+    fn test_response() {
+        let hdr = r#"username="Mufasa",
+                 realm="testrealm@host.com",
+                 nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093",
+                 uri="/dir/index.html",
+                 qop=auth,
+                 nc=00000001,
+                 cnonce="0a4f113b",
+                 response="6629fae49393a05397450978507c4ef1",
+                 opaque="5ccc069c403ebaf9f0171e9517f40e41""#;
+        let params = parse(hdr);
+        assert_eq!(params["username"], "Mufasa");
+        assert_eq!(params["qop"], "auth");
+        let ha1 = md5_hex("Mufasa:testrealm@host.com:Circle Of Life");
+        assert_eq!(response(&ha1, "GET", &params).unwrap(), params["response"]);
+    }
+
+    #[test]
+    fn test_nonce() {
+        let nonce = new_nonce();
+        assert_eq!(check_nonce(&nonce, 60), Nonce::Valid);
+        assert_eq!(check_nonce(&format!("{}0", nonce), 60), Nonce::Invalid);
+        let ts = "1000";
+        assert_eq!(check_nonce(&format!("{}.{}", ts, nonce_mac(ts)), 60), Nonce::Stale);
+    }
+}
@@ -0,0 +1,193 @@
+//
+// Error pages, for clients that ask for HTML or JSON.
+//
+// A browser sends "Accept: text/html", an API client may send
+// "Accept: application/json". They get a page instead of the bare error,
+// from the [errors] directory if it has one: "404.html", else "4xx.html",
+// else "error.html" (and the same with .json), with {{status}},
+// {{reason}} and {{path}} in it. WebDAV clients do not ask for either, and
+// get the response as it was.
+//
+use std::collections::HashMap;
+use std::io;
+
+use http::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use http::{HeaderMap, StatusCode};
+
+use crate::config::Errors;
+use crate::report::escape;
+
+const HTML: &str = r#"<!DOCTYPE html>
+<html><head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{{status}} {{reason}}</title>
+<style>
+body { font-family: sans-serif; margin: 3em auto; max-width: 40em; color: #222; }
+h1 { font-weight: normal; }
+</style>
+</head><body>
+<h1>{{status}} {{reason}}</h1>
+<p>{{path}}</p>
+</body></html>
+"#;
+
+const JSON: &str = r#"{"status":{{status}},"error":"{{reason}}","path":"{{path}}"}
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Html,
+    Json,
+}
+
+impl Format {
+    fn ext(self) -> &'static str {
+        match self {
+            Format::Html => "html",
+            Format::Json => "json",
+        }
+    }
+}
+
+/// What the client wants an error page in, if anything.
+pub fn format(headers: &HeaderMap) -> Option<Format> {
+    let accept = headers.get(http::header::ACCEPT)?.to_str().ok()?;
+    let mut best = None;
+    let mut best_q = 0.0;
+    for item in accept.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let format = match parts.next().unwrap_or("") {
+            "text/html" | "application/xhtml+xml" => Format::Html,
+            "application/json" => Format::Json,
+            _ => continue,
+        };
+        let q = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if q > best_q {
+            best = Some(format);
+            best_q = q;
+        }
+    }
+    best
+}
+
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// The page for this status, from the directory or built in.
+fn page(errors: &Errors, status: StatusCode, format: Format, path: &str) -> String {
+    let ext = format.ext();
+    let names = [
+        format!("{}.{}", status.as_u16(), ext),
+        format!("{}xx.{}", status.as_u16() / 100, ext),
+        format!("error.{}", ext),
+    ];
+    let template = names.iter().find_map(|n| errors.pages.get(n)).map(|s| s.as_str());
+    let template = template.unwrap_or(match format {
+        Format::Html => HTML,
+        Format::Json => JSON,
+    });
+    let esc = match format {
+        Format::Html => escape,
+        Format::Json => escape_json,
+    };
+    let path = percent_encoding::percent_decode_str(path).decode_utf8_lossy();
+    template
+        .replace("{{status}}", &status.as_u16().to_string())
+        .replace("{{reason}}", &esc(status.canonical_reason().unwrap_or("")))
+        .replace("{{path}}", &esc(&path))
+}
+
+// On if there is a directory, unless it is turned off.
+fn enabled(errors: &Errors) -> bool {
+    errors.enabled.unwrap_or(errors.directory.is_some())
+}
+
+/// Replace the body of an error response with a page, if the client asked
+/// for one. The headers stay, apart from the ones about the body.
+pub fn response(
+    errors: &Errors,
+    format: Option<Format>,
+    head: bool,
+    path: &str,
+    resp: http::Response<hyper::Body>,
+) -> http::Response<hyper::Body>
+{
+    let status = resp.status();
+    let format = match format {
+        Some(format) if enabled(errors) && (status.is_client_error() || status.is_server_error()) => format,
+        _ => return resp,
+    };
+    let (mut parts, _) = resp.into_parts();
+    parts.headers.remove(CONTENT_ENCODING);
+    parts.headers.remove(CONTENT_LENGTH);
+    let ctype = match format {
+        Format::Html => "text/html; charset=utf-8",
+        Format::Json => "application/json",
+    };
+    parts.headers.insert(CONTENT_TYPE, ctype.parse().unwrap());
+    let body = match head {
+        true => hyper::Body::empty(),
+        false => hyper::Body::from(page(errors, status, format, path)),
+    };
+    http::Response::from_parts(parts, body)
+}
+
+/// Read the pages in the directory: *.html and *.json.
+pub fn read_dir(dir: &str) -> io::Result<HashMap<String, String>> {
+    let mut pages = HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) if name.ends_with(".html") || name.ends_with(".json") => name,
+            _ => continue,
+        };
+        let page = std::fs::read_to_string(entry.path())?;
+        pages.insert(name, page);
+    }
+    Ok(pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errorpage() {
+        let accept = |s: &str| {
+            let mut h = HeaderMap::new();
+            h.insert(http::header::ACCEPT, s.parse().unwrap());
+            format(&h)
+        };
+        assert_eq!(format(&HeaderMap::new()), None);
+        assert_eq!(accept("*/*"), None);
+        assert_eq!(accept("text/html,application/xhtml+xml,*/*;q=0.8"), Some(Format::Html));
+        assert_eq!(accept("application/json"), Some(Format::Json));
+        assert_eq!(accept("text/html;q=0.5, application/json"), Some(Format::Json));
+        assert_eq!(accept("text/html;q=0"), None);
+
+        let mut errors = Errors::default();
+        errors.pages.insert("4xx.html".to_string(), "{{status}} {{path}}".to_string());
+        let page = |status, format| page(&errors, status, format, "/a%20<b>");
+        assert_eq!(page(StatusCode::NOT_FOUND, Format::Html), "404 /a &lt;b&gt;");
+        assert!(page(StatusCode::BAD_GATEWAY, Format::Html).contains("<h1>502 Bad Gateway</h1>"));
+        assert_eq!(
+            page(StatusCode::FORBIDDEN, Format::Json),
+            "{\"status\":403,\"error\":\"Forbidden\",\"path\":\"/a <b>\"}\n"
+        );
+        assert_eq!(escape_json("a\"\\\n"), "a\\\"\\\\\\u000a");
+    }
+}
@@ -0,0 +1,332 @@
+//
+// The ETag scheme of a location, etag = "inode", "mtime-size" or "content".
+//
+// "inode" is what the filesystem gives; for a local one that is the
+// inode, the size and the mtime. "mtime-size" leaves out the inode, which
+// is not stable on some network filesystems. "content" is a hash of the
+// contents, so a file has the same ETag on every server, and after it was
+// copied. It is kept in memory, and with the dead properties if the
+// location has them; the first time a file is listed, it is read.
+//
+// Directories always get the mtime-size ETag, in the last two cases.
+//
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::FutureExt;
+use futures::StreamExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::checksum::ChecksumFs;
+use crate::config::{ChecksumType, EtagScheme};
+use crate::trashfs;
+
+// Content hashes in memory, at most this many.
+const MAX_CACHED: usize = 100_000;
+// Hex digits of the SHA-256 in the ETag.
+const HASH_LEN: usize = 32;
+
+lazy_static::lazy_static! {
+    // by root and path: the ETag of the filesystem below, and the hash.
+    static ref CACHE: Mutex<HashMap<String, (String, String)>> = Mutex::new(HashMap::new());
+}
+
+// Size and mtime in microseconds, in hex, as the handler does it.
+fn mtime_size(meta: &dyn DavMetaData) -> Option<String> {
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    let micros = mtime.as_micros() as u64;
+    match meta.is_file() && meta.len() > 0 {
+        true => Some(format!("{:x}-{:x}", meta.len(), micros)),
+        false => Some(format!("{:x}", micros)),
+    }
+}
+
+/// A filesystem with another kind of ETag.
+#[derive(Clone)]
+pub struct EtagFs {
+    fs:     Box<dyn DavFileSystem>,
+    scheme: EtagScheme,
+    root:   String,
+    sums:   Box<ChecksumFs>,
+}
+
+impl EtagFs {
+    /// `root` makes the paths unique in the cache, like the key of the
+    /// dead properties.
+    pub fn new(fs: Box<dyn DavFileSystem>, scheme: EtagScheme, root: &str) -> Box<EtagFs> {
+        let sums = ChecksumFs::new(fs.clone(), &[ChecksumType::Sha256]);
+        Box::new(EtagFs {
+            fs,
+            scheme,
+            root: root.to_string(),
+            sums,
+        })
+    }
+
+    async fn wrap(&self, path: &DavPath, meta: Box<dyn DavMetaData>) -> Box<dyn DavMetaData> {
+        let etag = match self.scheme {
+            EtagScheme::Inode => meta.etag(),
+            EtagScheme::Content if meta.is_file() => match self.content(path, &*meta).await {
+                Some(etag) => Some(etag),
+                None => mtime_size(&*meta),
+            },
+            _ => mtime_size(&*meta),
+        };
+        Box::new(EtagMeta { meta, etag })
+    }
+
+    // The hash of the contents, from the cache if the file did not change.
+    async fn content(&self, path: &DavPath, meta: &dyn DavMetaData) -> Option<String> {
+        let stamp = meta.etag()?;
+        let key = format!("{}:{}", self.root, path.as_url_string());
+        if let Some((s, hash)) = CACHE.lock().unwrap().get(&key) {
+            if *s == stamp {
+                return Some(hash.clone());
+            }
+        }
+        let sums = self.sums.checksums(path).await.ok()?;
+        let hash = sums.first()?.1.get(..HASH_LEN)?.to_string();
+        let mut cache = CACHE.lock().unwrap();
+        if cache.len() >= MAX_CACHED {
+            cache.clear();
+        }
+        cache.insert(key, (stamp, hash.clone()));
+        Some(hash)
+    }
+}
+
+impl DavFileSystem for EtagFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let file = self.fs.open(path, options).await?;
+            let file = EtagFile {
+                file,
+                fs: self.clone(),
+                path: path.clone(),
+            };
+            Ok(Box::new(file) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            let strm = self.fs.read_dir(path, meta).await?;
+            let fs = self.clone();
+            let dir = path.clone();
+            let strm = strm.filter_map(move |entry| {
+                let entry = trashfs::join(&dir, &entry.name()).ok().map(|path| {
+                    let fs = fs.clone();
+                    Box::new(EtagDirEntry { entry, fs, path }) as Box<dyn DavDirEntry>
+                });
+                futures::future::ready(entry)
+            });
+            Ok(Box::pin(strm) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let meta = self.fs.metadata(path).await?;
+            Ok(self.wrap(path, meta).await)
+        }
+        .boxed()
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let meta = self.fs.symlink_metadata(path).await?;
+            Ok(self.wrap(path, meta).await)
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_dir(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_file(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.rename(from, to)
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.copy(from, to)
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        self.fs.patch_props(path, patch)
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct EtagMeta {
+    meta: Box<dyn DavMetaData>,
+    etag: Option<String>,
+}
+
+impl DavMetaData for EtagMeta {
+    fn len(&self) -> u64 {
+        self.meta.len()
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        self.meta.modified()
+    }
+
+    fn is_dir(&self) -> bool {
+        self.meta.is_dir()
+    }
+
+    fn etag(&self) -> Option<String> {
+        self.etag.clone()
+    }
+
+    fn is_file(&self) -> bool {
+        self.meta.is_file()
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.meta.is_symlink()
+    }
+
+    fn accessed(&self) -> FsResult<SystemTime> {
+        self.meta.accessed()
+    }
+
+    fn created(&self) -> FsResult<SystemTime> {
+        self.meta.created()
+    }
+
+    fn status_changed(&self) -> FsResult<SystemTime> {
+        self.meta.status_changed()
+    }
+
+    fn executable(&self) -> FsResult<bool> {
+        self.meta.executable()
+    }
+}
+
+struct EtagDirEntry {
+    entry: Box<dyn DavDirEntry>,
+    fs:    EtagFs,
+    path:  DavPath,
+}
+
+impl DavDirEntry for EtagDirEntry {
+    fn name(&self) -> Vec<u8> {
+        self.entry.name()
+    }
+
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        async move {
+            let meta = self.entry.metadata().await?;
+            Ok(self.fs.wrap(&self.path, meta).await)
+        }
+        .boxed()
+    }
+
+    fn is_dir(&self) -> FsFuture<'_, bool> {
+        self.entry.is_dir()
+    }
+
+    fn is_file(&self) -> FsFuture<'_, bool> {
+        self.entry.is_file()
+    }
+
+    fn is_symlink(&self) -> FsFuture<'_, bool> {
+        self.entry.is_symlink()
+    }
+}
+
+struct EtagFile {
+    file: Box<dyn DavFile>,
+    fs:   EtagFs,
+    path: DavPath,
+}
+
+impl fmt::Debug for EtagFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EtagFile").field("file", &self.file).field("path", &self.path).finish()
+    }
+}
+
+impl DavFile for EtagFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let meta = self.file.metadata().await?;
+            Ok(self.fs.wrap(&self.path, meta).await)
+        }
+        .boxed()
+    }
+
+    fn write_buf<'a>(&'a mut self, buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        self.file.write_buf(buf)
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        self.file.write_bytes(buf)
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        self.file.read_bytes(count)
+    }
+
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> FsFuture<'a, u64> {
+        self.file.seek(pos)
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        self.file.flush()
+    }
+}
@@ -0,0 +1,185 @@
+//
+// Authentication by an external command (auth-type and acct-type "exec").
+//
+// The command gets the username and the password on stdin, one per line
+// (or in WEBDAV_USER and WEBDAV_PASSWORD, with input = "env"), and the
+// address of the client in WEBDAV_REMOTE_ADDR. Exit status 0 means yes,
+// anything else no. On stdout it may print "key=value" lines:
+//
+// - user: log in as this user instead.
+// - uid, gid, groups, home: the account, for acct-type "exec".
+//
+// It runs as the server's user, without the saved root uid, in an empty
+// environment, and is killed after the timeout.
+//
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+
+use crate::config::{Exec, ExecInput};
+use crate::unixuser::User;
+
+lazy_static::lazy_static! {
+    // what the command said the last time a user logged in.
+    static ref LOGINS: Mutex<HashMap<String, Login>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Login {
+    pub user:    Option<String>,
+    pub account: Option<Arc<User>>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// The "key=value" lines of the output.
+fn parse_output(name: &str, text: &str, min_uid: u32) -> io::Result<Login> {
+    let mut values = HashMap::new();
+    for line in text.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        match line.split_once('=') {
+            Some((key, value)) => values.insert(key.trim(), value.trim()),
+            None => return Err(invalid(format!("output: {:?}: not key=value", line))),
+        };
+    }
+    let user = values.get("user").map(|u| u.to_string());
+    if let Some(ref u) = user {
+        if u.is_empty() || u.contains(|c: char| c == '/' || c == ':' || c.is_control()) {
+            return Err(invalid(format!("output: user={:?}: invalid", u)));
+        }
+    }
+    let num = |key: &str| {
+        values.get(key).map(|v| v.parse::<u32>().map_err(|_| invalid(format!("output: {}={}", key, v))))
+    };
+    let account = match (num("uid").transpose()?, num("gid").transpose()?) {
+        (Some(uid), Some(gid)) => {
+            if uid < min_uid {
+                let msg = format!("uid {} too low (<{})", uid, min_uid);
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+            }
+            let groups = values.get("groups").copied().unwrap_or("");
+            let groups = groups.split(|c: char| c == ',' || c.is_whitespace());
+            Some(Arc::new(User {
+                name: user.clone().unwrap_or_else(|| name.to_string()),
+                passwd: "*".to_string(),
+                gecos: String::new(),
+                uid,
+                gid,
+                groups: groups.filter_map(|g| g.parse().ok()).filter(|&g| g != gid).collect(),
+                dir: PathBuf::from(values.get("home").copied().unwrap_or("")),
+                shell: PathBuf::new(),
+            }))
+        },
+        (None, None) => None,
+        _ => return Err(invalid("output: uid and gid go together".to_string())),
+    };
+    Ok(Login { user, account })
+}
+
+async fn run(cfg: &Exec, user: &str, pass: &str, remote_ip: Option<&str>) -> io::Result<String> {
+    let input = cfg.input.unwrap_or(ExecInput::Stdin);
+    if input == ExecInput::Stdin && (user.contains('\n') || pass.contains('\n')) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "newline in username or password"));
+    }
+    let mut cmd = Command::new(&cfg.command[0]);
+    cmd.args(&cfg.command[1..])
+        .env_clear()
+        .env("PATH", "/usr/local/bin:/usr/bin:/bin")
+        .env("WEBDAV_USER", user)
+        .env("WEBDAV_REMOTE_ADDR", remote_ip.unwrap_or(""))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true);
+    if input == ExecInput::Env {
+        cmd.env("WEBDAV_PASSWORD", pass);
+    }
+    // the thread may have a saved uid of root; the command must not.
+    unsafe {
+        cmd.pre_exec(|| {
+            let (uid, gid) = (libc::geteuid(), libc::getegid());
+            if libc::syscall(libc::SYS_setresgid, gid, gid, gid) != 0
+                || libc::syscall(libc::SYS_setresuid, uid, uid, uid) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = cmd.spawn()?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    let io = async move {
+        if input == ExecInput::Stdin {
+            // it may exit without reading it.
+            let _ = stdin.write_all(format!("{}\n{}\n", user, pass).as_bytes()).await;
+        }
+        drop(stdin);
+        let mut out = Vec::new();
+        (&mut stdout).take(65536).read_to_end(&mut out).await?;
+        let status = child.wait().await?;
+        Ok::<_, io::Error>((status, out))
+    };
+    let timeout = Duration::from_secs(cfg.timeout.unwrap_or(10));
+    let (status, out) = match tokio::time::timeout(timeout, io).await {
+        Ok(res) => res?,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "timeout")),
+    };
+    if !status.success() {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("denied ({})", status)));
+    }
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// Run the command for a login, and remember what it said.
+pub async fn auth(cfg: &Exec, user: &str, pass: &str, remote_ip: Option<&str>) -> io::Result<()> {
+    let out = run(cfg, user, pass, remote_ip).await?;
+    let login = parse_output(user, &out, cfg.min_uid.unwrap_or(1))?;
+    LOGINS.lock().unwrap().insert(user.to_string(), login);
+    Ok(())
+}
+
+/// The user to log in as, after a successful auth().
+pub fn user(name: &str) -> String {
+    let logins = LOGINS.lock().unwrap();
+    logins.get(name).and_then(|l| l.user.clone()).unwrap_or_else(|| name.to_string())
+}
+
+/// The account of a user (the name after mapping), if the command gave one.
+pub fn account(name: &str) -> Option<Arc<User>> {
+    let logins = LOGINS.lock().unwrap();
+    let mut accounts = logins.iter().filter(|(login, l)| l.user.as_deref().unwrap_or(login) == name);
+    accounts.find_map(|(_, l)| l.account.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_execauth() {
+        let login = parse_output("alice", "", 1000).unwrap();
+        assert!(login.user.is_none() && login.account.is_none());
+
+        let out = "user=alice.smith\nuid=1001\ngid=100\ngroups=100,27\nhome = /srv/dav/alice\n";
+        let login = parse_output("alice", out, 1000).unwrap();
+        assert_eq!(login.user.as_deref(), Some("alice.smith"));
+        let account = login.account.unwrap();
+        assert_eq!((account.name.as_str(), account.uid, account.gid), ("alice.smith", 1001, 100));
+        assert_eq!(account.groups, vec![27]);
+        assert_eq!(account.dir, PathBuf::from("/srv/dav/alice"));
+
+        assert!(parse_output("alice", "uid=1001\n", 1000).is_err());
+        assert!(parse_output("alice", "uid=10\ngid=10\n", 1000).is_err());
+        assert!(parse_output("alice", "user=../root\n", 1000).is_err());
+        assert!(parse_output("alice", "welcome!\n", 1000).is_err());
+    }
+}
@@ -0,0 +1,503 @@
+//
+// Finder mode: the "._name" (AppleDouble) and ".DS_Store" files that
+// macOS writes next to everything it copies are kept out of the share.
+//
+// finder = "discard" keeps them in memory, for as long as the server
+// runs, so that Finder can read back what it wrote; nobody else sees
+// them. finder = "xattr" stores them in an extended attribute of the file
+// they belong to ("user.webdav.appledouble"), or of the directory for a
+// .DS_Store ("user.webdav.ds_store"). That way they are renamed with the
+// file, and copied with it. What does not fit in an attribute (ext4
+// allows about 4K for all of them) is kept in memory, as with discard.
+//
+// Files like that which are already on disk are not listed, and can not
+// be reached anymore.
+//
+use std::ffi::CStr;
+use std::fmt;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use futures::future::{self, FutureExt};
+use futures::StreamExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::trashfs;
+use crate::userfs::UserFs;
+
+/// The names that Finder writes.
+pub fn is_apple_name(name: &[u8]) -> bool {
+    name == b".DS_Store" || (name.starts_with(b"._") && name.len() > 2)
+}
+
+const APPLEDOUBLE: &[u8] = b"user.webdav.appledouble\0";
+const DS_STORE: &[u8] = b"user.webdav.ds_store\0";
+
+fn attr(name: &'static [u8]) -> &'static CStr {
+    CStr::from_bytes_with_nul(name).unwrap()
+}
+
+// A ._name or .DS_Store: the file or directory it is about, and the
+// attribute it goes in.
+struct Apple {
+    target: DavPath,
+    attr:   &'static CStr,
+}
+
+fn apple(path: &DavPath) -> Option<Apple> {
+    let url = path.as_url_string();
+    let (dir, name) = url.rsplit_once('/')?;
+    let (target, name) = match name {
+        ".DS_Store" => (format!("{}/", dir), DS_STORE),
+        name if name.len() > 2 && name.starts_with("._") => (format!("{}/{}", dir, &name[2..]), APPLEDOUBLE),
+        _ => return None,
+    };
+    Some(Apple {
+        target: DavPath::new(&target).ok()?,
+        attr:   attr(name),
+    })
+}
+
+#[derive(Clone)]
+pub struct FinderFs {
+    fs:      Box<dyn DavFileSystem>,
+    // where they go if not in an xattr.
+    store:   Box<dyn DavFileSystem>,
+    xattr:   Option<Box<UserFs>>,
+    listing: bool,
+}
+
+impl FinderFs {
+    /// With `xattr` the files go in extended attributes, if they can.
+    /// With `listing` they are left out of the directories that are read.
+    pub fn new(
+        fs: Box<dyn DavFileSystem>,
+        store: Box<dyn DavFileSystem>,
+        xattr: Option<Box<UserFs>>,
+        listing: bool,
+    ) -> Box<FinderFs>
+    {
+        Box::new(FinderFs {
+            fs,
+            store,
+            xattr,
+            listing,
+        })
+    }
+
+    async fn get_xattr(&self, apple: &Apple) -> Option<Vec<u8>> {
+        let xattr = self.xattr.as_ref()?;
+        xattr.get_xattr(&apple.target, apple.attr).await.ok().flatten()
+    }
+
+    // The contents, from the attribute or the store.
+    async fn read(&self, path: &DavPath, apple: &Apple) -> FsResult<Vec<u8>> {
+        if let Some(data) = self.get_xattr(apple).await {
+            return Ok(data);
+        }
+        let mut file = self.store.open(path, OpenOptions { read: true, ..OpenOptions::default() }).await?;
+        let mut data = Vec::new();
+        loop {
+            let buf = file.read_bytes(65536).await?;
+            if buf.is_empty() {
+                return Ok(data);
+            }
+            data.extend_from_slice(&buf);
+        }
+    }
+
+    // Save the contents in the attribute, or else in the store.
+    async fn write(&self, path: &DavPath, apple: &Apple, data: Vec<u8>) -> FsResult<()> {
+        if let Some(ref xattr) = self.xattr {
+            match xattr.set_xattr(&apple.target, apple.attr, data.clone()).await {
+                Ok(()) => {
+                    let _ = self.store.remove_file(path).await;
+                    return Ok(());
+                },
+                Err(e) => debug!("finder: {}: xattr: {:?}", apple.target.as_url_string(), e),
+            }
+        }
+        self.create_parent(path).await?;
+        let oo = OpenOptions {
+            write: true,
+            create: true,
+            truncate: true,
+            ..OpenOptions::default()
+        };
+        let mut file = self.store.open(path, oo).await?;
+        file.write_bytes(Bytes::from(data)).await?;
+        file.flush().await
+    }
+
+    async fn remove(&self, path: &DavPath, apple: &Apple) -> FsResult<()> {
+        let mut found = false;
+        if let Some(ref xattr) = self.xattr {
+            found = xattr.remove_xattr(&apple.target, apple.attr).await.unwrap_or(false);
+        }
+        match self.store.remove_file(path).await {
+            Ok(()) => Ok(()),
+            Err(_) if found => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // The store only has the directories that something was put in.
+    async fn create_parent(&self, path: &DavPath) -> FsResult<()> {
+        let url = path.as_url_string();
+        let parent = &url[..url.rfind('/').unwrap_or(0)];
+        match parent {
+            "" => Ok(()),
+            parent => {
+                let parent = DavPath::new(parent).map_err(|_| FsError::GeneralFailure)?;
+                trashfs::create_dirs(&*self.store, &parent).await
+            },
+        }
+    }
+
+    async fn apple_metadata(&self, path: &DavPath, apple: &Apple) -> FsResult<Box<dyn DavMetaData>> {
+        match self.get_xattr(apple).await {
+            Some(data) => {
+                let modified = match self.fs.metadata(&apple.target).await {
+                    Ok(meta) => meta.modified().unwrap_or_else(|_| SystemTime::now()),
+                    Err(_) => SystemTime::now(),
+                };
+                let meta = BlobMeta {
+                    len: data.len() as u64,
+                    modified,
+                };
+                Ok(Box::new(meta) as Box<dyn DavMetaData>)
+            },
+            None => self.store.metadata(path).await,
+        }
+    }
+
+    async fn apple_open(
+        &self,
+        path: &DavPath,
+        apple: Apple,
+        options: OpenOptions,
+    ) -> FsResult<Box<dyn DavFile>>
+    {
+        let create = options.create || options.create_new;
+        if self.xattr.is_none() {
+            if create {
+                self.create_parent(path).await?;
+            }
+            return self.store.open(path, options).await;
+        }
+        let data = match self.read(path, &apple).await {
+            Ok(_) if options.create_new => return Err(FsError::Exists),
+            Ok(data) => Some(data),
+            Err(FsError::NotFound) if create => None,
+            Err(e) => return Err(e),
+        };
+        let data = if options.truncate { None } else { data };
+        let file = BlobFile {
+            fs: self.clone(),
+            path: path.clone(),
+            apple,
+            data: data.unwrap_or_default(),
+            pos: 0,
+            append: options.append,
+            dirty: options.truncate || create,
+            modified: SystemTime::now(),
+        };
+        Ok(Box::new(file) as Box<dyn DavFile>)
+    }
+}
+
+impl DavFileSystem for FinderFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        match apple(path) {
+            Some(apple) => self.apple_open(path, apple, options).boxed(),
+            None => self.fs.open(path, options),
+        }
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            let strm = self.fs.read_dir(path, meta).await?;
+            if !self.listing {
+                return Ok(strm);
+            }
+            let strm = strm.filter(|e| future::ready(!is_apple_name(&e.name())));
+            Ok(Box::pin(strm) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            match apple(path) {
+                Some(apple) => self.apple_metadata(path, &apple).await,
+                None => self.fs.metadata(path).await,
+            }
+        }
+        .boxed()
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            match apple(path) {
+                Some(apple) => self.apple_metadata(path, &apple).await,
+                None => self.fs.symlink_metadata(path).await,
+            }
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_dir(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            match apple(path) {
+                Some(apple) => self.remove(path, &apple).await,
+                None => self.fs.remove_file(path).await,
+            }
+        }
+        .boxed()
+    }
+
+    // A rename keeps the attributes. Finder renames the ._name file
+    // after the file, and that gets a 404, as it has already moved.
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            match (apple(from), apple(to)) {
+                (None, None) => self.fs.rename(from, to).await,
+                (Some(src), Some(dst)) => {
+                    let data = self.read(from, &src).await?;
+                    self.write(to, &dst, data).await?;
+                    self.remove(from, &src).await
+                },
+                _ => Err(FsError::Forbidden),
+            }
+        }
+        .boxed()
+    }
+
+    // Copying a file does not copy its attributes; that is done here.
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            match (apple(from), apple(to)) {
+                (None, None) => {
+                    self.fs.copy(from, to).await?;
+                    if let Some(ref xattr) = self.xattr {
+                        if let Ok(Some(data)) = xattr.get_xattr(from, attr(APPLEDOUBLE)).await {
+                            let _ = xattr.set_xattr(to, attr(APPLEDOUBLE), data).await;
+                        }
+                    }
+                    Ok(())
+                },
+                (Some(src), Some(dst)) => {
+                    let data = self.read(from, &src).await?;
+                    self.write(to, &dst, data).await
+                },
+                _ => Err(FsError::Forbidden),
+            }
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        match apple(path) {
+            Some(_) => Box::pin(future::ready(Ok(()))),
+            None => self.fs.set_accessed(path, tm),
+        }
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        match apple(path) {
+            Some(_) => Box::pin(future::ready(Ok(()))),
+            None => self.fs.set_modified(path, tm),
+        }
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        match apple(path) {
+            Some(_) => self.store.have_props(path),
+            None => self.fs.have_props(path),
+        }
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        match apple(path) {
+            Some(_) => self.store.patch_props(path, patch),
+            None => self.fs.patch_props(path, patch),
+        }
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        match apple(path) {
+            Some(_) => self.store.get_props(path, do_content),
+            None => self.fs.get_props(path, do_content),
+        }
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        match apple(path) {
+            Some(_) => self.store.get_prop(path, prop),
+            None => self.fs.get_prop(path, prop),
+        }
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct BlobMeta {
+    len:      u64,
+    modified: SystemTime,
+}
+
+impl DavMetaData for BlobMeta {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        Ok(self.modified)
+    }
+
+    fn is_dir(&self) -> bool {
+        false
+    }
+}
+
+// A ._name or .DS_Store in memory, while it is open. What is written
+// goes to the attribute (or the store) on flush.
+struct BlobFile {
+    fs:       FinderFs,
+    path:     DavPath,
+    apple:    Apple,
+    data:     Vec<u8>,
+    pos:      usize,
+    append:   bool,
+    dirty:    bool,
+    modified: SystemTime,
+}
+
+impl fmt::Debug for BlobFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BlobFile").field("path", &self.path).field("len", &self.data.len()).finish()
+    }
+}
+
+impl BlobFile {
+    fn put(&mut self, buf: &[u8]) {
+        if self.append {
+            self.pos = self.data.len();
+        }
+        let end = self.pos + buf.len();
+        if end > self.data.len() {
+            self.data.resize(end, 0);
+        }
+        self.data[self.pos..end].copy_from_slice(buf);
+        self.pos = end;
+        self.dirty = true;
+        self.modified = SystemTime::now();
+    }
+}
+
+impl DavFile for BlobFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        let meta = BlobMeta {
+            len:      self.data.len() as u64,
+            modified: self.modified,
+        };
+        Box::pin(future::ready(Ok(Box::new(meta) as Box<dyn DavMetaData>)))
+    }
+
+    fn write_buf<'a>(&'a mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        while buf.has_remaining() {
+            let chunk = buf.chunk().to_vec();
+            self.put(&chunk);
+            buf.advance(chunk.len());
+        }
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        self.put(&buf);
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        let start = self.pos.min(self.data.len());
+        let end = (start + count).min(self.data.len());
+        self.pos = end;
+        Box::pin(future::ready(Ok(Bytes::copy_from_slice(&self.data[start..end]))))
+    }
+
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> FsFuture<'a, u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n as i64),
+            SeekFrom::Current(n) => (self.pos as i64).checked_add(n),
+            SeekFrom::End(n) => (self.data.len() as i64).checked_add(n),
+        };
+        let res = match pos {
+            Some(n) if n >= 0 => {
+                self.pos = n as usize;
+                Ok(n as u64)
+            },
+            _ => Err(FsError::GeneralFailure),
+        };
+        Box::pin(future::ready(res))
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        async move {
+            if self.dirty {
+                self.fs.write(&self.path, &self.apple, self.data.clone()).await?;
+                self.dirty = false;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finder() {
+        assert!(is_apple_name(b"._a.txt"));
+        assert!(is_apple_name(b".DS_Store"));
+        assert!(!is_apple_name(b"._"));
+        assert!(!is_apple_name(b".gitignore"));
+
+        let a = apple(&DavPath::new("/dir/._a%20b.txt").unwrap()).unwrap();
+        assert_eq!(a.target.as_url_string(), "/dir/a%20b.txt");
+        assert_eq!(a.attr.to_bytes(), b"user.webdav.appledouble");
+        let a = apple(&DavPath::new("/dir/.DS_Store").unwrap()).unwrap();
+        assert_eq!(a.target.as_url_string(), "/dir/");
+        assert_eq!(a.attr.to_bytes(), b"user.webdav.ds_store");
+        let a = apple(&DavPath::new("/.DS_Store").unwrap()).unwrap();
+        assert_eq!(a.target.as_url_string(), "/");
+        assert!(apple(&DavPath::new("/dir/a.txt").unwrap()).is_none());
+    }
+}
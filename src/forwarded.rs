@@ -0,0 +1,123 @@
+//
+// Client address from Forwarded / X-Forwarded-For, when behind a trusted proxy.
+//
+// The addresses in the headers are checked from right to left. Every
+// address that is a trusted proxy is skipped, the first one that is not
+// is the client. Forwarded (RFC 7239) is used if present, otherwise
+// X-Forwarded-For.
+//
+use std::net::{IpAddr, SocketAddr};
+
+use http::HeaderMap;
+
+use crate::cidr::{self, Cidr};
+
+// Parse one address, with optional port: "192.0.2.1", "192.0.2.1:80",
+// "2001:db8::1", "[2001:db8::1]:80".
+fn parse_addr(s: &str) -> Option<SocketAddr> {
+    let s = s.trim().trim_matches('"');
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let s = s.trim_start_matches('[').trim_end_matches(']');
+    s.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+// The for= values of the Forwarded headers, left to right.
+fn forwarded_for(headers: &HeaderMap) -> Vec<String> {
+    let mut addrs = Vec::new();
+    for value in headers.get_all("forwarded") {
+        let value = match value.to_str() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        for element in value.split(',') {
+            let found = element.split(';').find_map(|pair| {
+                let (key, val) = pair.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("for") {
+                    Some(val.trim().to_string())
+                } else {
+                    None
+                }
+            });
+            // a hop without for= is unknown.
+            addrs.push(found.unwrap_or_default());
+        }
+    }
+    addrs
+}
+
+// The X-Forwarded-For addresses, left to right.
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().to_string())
+        .collect()
+}
+
+/// The address of the client. If the peer is not a trusted proxy,
+/// that is the peer address.
+pub fn client_addr(trusted: &[Cidr], headers: &HeaderMap, peer: SocketAddr) -> SocketAddr {
+    let mut addr = SocketAddr::new(cidr::canonical(peer.ip()), peer.port());
+    let is_trusted = |addr: &SocketAddr| trusted.iter().any(|net| net.contains(addr.ip()));
+    if !is_trusted(&addr) {
+        return addr;
+    }
+    let mut hops = forwarded_for(headers);
+    if hops.is_empty() {
+        hops = x_forwarded_for(headers);
+    }
+    while is_trusted(&addr) {
+        match hops.pop().as_deref().and_then(parse_addr) {
+            Some(a) => addr = SocketAddr::new(cidr::canonical(a.ip()), a.port()),
+            // no more hops, or "unknown" / garbage: stop at the last proxy.
+            None => break,
+        }
+    }
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(hdrs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in hdrs {
+            map.append(*name, value.parse().unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let trusted: Vec<Cidr> = vec!["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap()];
+        let proxy: SocketAddr = "127.0.0.1:5555".parse().unwrap();
+
+        // untrusted peer: headers are ignored.
+        let peer: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let h = headers(&[("x-forwarded-for", "198.51.100.1")]);
+        assert_eq!(client_addr(&trusted, &h, peer), peer);
+
+        // spoofed entries on the left are ignored.
+        let h = headers(&[("x-forwarded-for", "198.51.100.1, 192.0.2.9"), ("x-forwarded-for", "10.1.1.1")]);
+        assert_eq!(client_addr(&trusted, &h, proxy), "192.0.2.9:0".parse().unwrap());
+
+        // Forwarded wins over X-Forwarded-For.
+        let h = headers(&[
+            ("forwarded", r#"for=192.0.2.60;proto=http, for="[2001:db8:cafe::17]:4711""#),
+            ("x-forwarded-for", "198.51.100.1"),
+        ]);
+        assert_eq!(client_addr(&trusted, &h, proxy), "[2001:db8:cafe::17]:4711".parse().unwrap());
+
+        // "unknown" stops the search.
+        let h = headers(&[("forwarded", "for=unknown")]);
+        assert_eq!(client_addr(&trusted, &h, proxy), proxy);
+
+        // no headers.
+        assert_eq!(client_addr(&trusted, &HeaderMap::new(), proxy), proxy);
+    }
+}
@@ -0,0 +1,662 @@
+//
+// Full-text index of file contents, for DAV:contains in SEARCH.
+//
+// The text is kept in an SQLite FTS5 table, keyed by the directory of the
+// location ("root") and the path below it. Files are indexed in the
+// background: after they were written through the server, and once per
+// root when it is first used, to catch up with changes made outside the
+// server (and with watch = true, when inotify sees them). Removes and
+// renames update the index right away.
+//
+// Text is taken from plain text files (text/* by the extension, and
+// common source and config files), and from HTML and XML with the markup
+// removed. Files that look binary, or are larger than the maximum size,
+// are not indexed.
+//
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::FutureExt;
+use futures::StreamExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use tokio::sync::mpsc;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::trashfs::join;
+
+lazy_static::lazy_static! {
+    static ref INDEXES: Mutex<HashMap<String, Index>> = Mutex::new(HashMap::new());
+}
+
+// Extensions of text files that mime_guess does not call text/*.
+const TEXT: &[&str] = &[
+    "json", "yaml", "yml", "toml", "ini", "conf", "cfg", "log", "md", "markdown", "rst", "tex", "sh", "py",
+    "rs", "go", "js", "ts", "java", "c", "h", "cpp", "hpp", "rb", "pl", "php", "sql",
+];
+const MARKUP: &[&str] = &["html", "htm", "xhtml", "xml", "svg"];
+
+/// The index in database file "path", opened on first use.
+pub fn open(path: &str) -> io::Result<Index> {
+    let mut indexes = INDEXES.lock().unwrap();
+    if let Some(index) = indexes.get(path) {
+        return Ok(index.clone());
+    }
+    let index = Index::open(path)?;
+    indexes.insert(path.to_string(), index.clone());
+    Ok(index)
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn read_options() -> OpenOptions {
+    OpenOptions {
+        read: true,
+        ..OpenOptions::default()
+    }
+}
+
+fn unix_time(t: FsResult<SystemTime>) -> i64 {
+    t.ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// The path without a trailing slash. "/" stays "/".
+fn key(path: &DavPath) -> Vec<u8> {
+    let p = path.as_bytes();
+    match p.len() {
+        0 | 1 => b"/".to_vec(),
+        n if p[n - 1] == b'/' => p[..n - 1].to_vec(),
+        _ => p.to_vec(),
+    }
+}
+
+// The prefix of the paths below "key", and its length.
+fn below(key: &[u8]) -> (Vec<u8>, i64) {
+    let mut dir = key.to_vec();
+    if dir != b"/" {
+        dir.push(b'/');
+    }
+    let len = dir.len() as i64;
+    (dir, len)
+}
+
+// Words, as an FTS5 query that matches all of them.
+fn fts_query(words: &str) -> String {
+    let words = words.split_whitespace().map(|w| format!("\"{}\"", w.replace('"', "\"\"")));
+    words.collect::<Vec<_>>().join(" ")
+}
+
+// How to get the text out of a file, by its name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Text,
+    Markup,
+}
+
+fn kind(name: &str) -> Option<Kind> {
+    let ext = name.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase())?;
+    if MARKUP.contains(&ext.as_str()) {
+        return Some(Kind::Markup);
+    }
+    let text = mime_guess::from_ext(&ext).first().map(|m| m.type_() == "text").unwrap_or(false);
+    match text || TEXT.contains(&ext.as_str()) {
+        true => Some(Kind::Text),
+        false => None,
+    }
+}
+
+// The text of HTML or XML: the markup is removed, and the common entities
+// are decoded.
+fn strip_markup(s: &str) -> String {
+    let mut text = String::with_capacity(s.len());
+    let mut in_tag = false;
+    for c in s.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            },
+            c if !in_tag => text.push(c),
+            _ => {},
+        }
+    }
+    let entities = [("&lt;", "<"), ("&gt;", ">"), ("&quot;", "\""), ("&apos;", "'"), ("&nbsp;", " ")];
+    let mut text = entities.iter().fold(text, |t, (e, c)| t.replace(e, c));
+    text = text.replace("&amp;", "&");
+    text
+}
+
+fn extract(kind: Kind, data: &[u8]) -> Option<String> {
+    if data[..data.len().min(1024)].contains(&0) {
+        return None;
+    }
+    let text = String::from_utf8_lossy(data);
+    match kind {
+        Kind::Text => Some(text.into_owned()),
+        Kind::Markup => Some(strip_markup(&text)),
+    }
+}
+
+fn blocking<T, F>(name: &str, f: F) -> FsResult<T>
+where F: FnOnce() -> io::Result<T> {
+    tokio::task::block_in_place(f).map_err(|e| {
+        error!("fulltext: {}: {}", name, e);
+        FsError::GeneralFailure
+    })
+}
+
+// Work for the indexer.
+enum Job {
+    // (re)index a file, if it changed since it was indexed.
+    File(Box<dyn DavFileSystem>, String, DavPath),
+    // index every file of a root, and drop files that are gone.
+    Crawl(Box<dyn DavFileSystem>, String),
+}
+
+#[derive(Clone)]
+pub struct Index(Arc<Inner>);
+
+struct Inner {
+    db:      Mutex<rusqlite::Connection>,
+    // the indexer, started on first use.
+    queue:   Mutex<Option<mpsc::UnboundedSender<(Job, u64)>>>,
+    // the roots that were crawled since startup.
+    crawled: Mutex<HashSet<String>>,
+}
+
+impl Index {
+    /// Open the database, and create the tables if needed.
+    pub fn open(path: &str) -> io::Result<Index> {
+        let db = rusqlite::Connection::open(path).map_err(sql_error)?;
+        db.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS docs (
+                 id    INTEGER PRIMARY KEY,
+                 root  TEXT NOT NULL,
+                 path  BLOB NOT NULL,
+                 mtime INTEGER NOT NULL,
+                 size  INTEGER NOT NULL,
+                 UNIQUE (root, path)
+             );
+             CREATE VIRTUAL TABLE IF NOT EXISTS fulltext USING fts5(body);",
+        )
+        .map_err(sql_error)?;
+        Ok(Index(Arc::new(Inner {
+            db:      Mutex::new(db),
+            queue:   Mutex::new(None),
+            crawled: Mutex::new(HashSet::new()),
+        })))
+    }
+
+    /// The paths of the files in "root" that contain all the words.
+    pub fn lookup(&self, root: &str, words: &str) -> io::Result<HashSet<Vec<u8>>> {
+        let query = fts_query(words);
+        if query.is_empty() {
+            return Ok(HashSet::new());
+        }
+        let db = self.0.db.lock().unwrap();
+        let mut stmt = db
+            .prepare_cached(
+                "SELECT docs.path FROM fulltext JOIN docs ON docs.id = fulltext.rowid
+                 WHERE fulltext MATCH ?1 AND docs.root = ?2",
+            )
+            .map_err(sql_error)?;
+        let rows = stmt
+            .query_map(rusqlite::params![query, root], |row| row.get::<_, Vec<u8>>(0))
+            .map_err(sql_error)?;
+        rows.collect::<rusqlite::Result<HashSet<_>>>().map_err(sql_error)
+    }
+
+    // The mtime and size of a file when it was indexed.
+    fn indexed(&self, root: &str, path: &[u8]) -> io::Result<Option<(i64, u64)>> {
+        let db = self.0.db.lock().unwrap();
+        let res = db.query_row(
+            "SELECT mtime, size FROM docs WHERE root = ?1 AND path = ?2",
+            rusqlite::params![root, path],
+            |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)),
+        );
+        match res {
+            Ok(r) => Ok(Some(r)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(sql_error(e)),
+        }
+    }
+
+    // Store the text of a file. Without text, the file is still recorded,
+    // so that it is not read again until it changes.
+    fn store(&self, root: &str, path: &[u8], mtime: i64, size: u64, text: Option<&str>) -> io::Result<()> {
+        let mut db = self.0.db.lock().unwrap();
+        let tx = db.transaction().map_err(sql_error)?;
+        tx.execute(
+            "INSERT INTO docs (root, path, mtime, size) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (root, path) DO UPDATE SET mtime = excluded.mtime, size = excluded.size",
+            rusqlite::params![root, path, mtime, size as i64],
+        )
+        .map_err(sql_error)?;
+        let id: i64 = tx
+            .query_row(
+                "SELECT id FROM docs WHERE root = ?1 AND path = ?2",
+                rusqlite::params![root, path],
+                |row| row.get(0),
+            )
+            .map_err(sql_error)?;
+        tx.execute("DELETE FROM fulltext WHERE rowid = ?1", [id]).map_err(sql_error)?;
+        if let Some(text) = text {
+            tx.execute("INSERT INTO fulltext (rowid, body) VALUES (?1, ?2)", rusqlite::params![id, text])
+                .map_err(sql_error)?;
+        }
+        tx.commit().map_err(sql_error)
+    }
+
+    /// Remove a path, and everything below it.
+    pub fn remove(&self, root: &str, path: &[u8]) -> io::Result<()> {
+        let (dir, len) = below(path);
+        let mut db = self.0.db.lock().unwrap();
+        let tx = db.transaction().map_err(sql_error)?;
+        let cond = "root = ?1 AND (path = ?2 OR substr(path, 1, ?3) = ?4)";
+        tx.execute(
+            &format!("DELETE FROM fulltext WHERE rowid IN (SELECT id FROM docs WHERE {})", cond),
+            rusqlite::params![root, path, len, dir],
+        )
+        .map_err(sql_error)?;
+        tx.execute(&format!("DELETE FROM docs WHERE {}", cond), rusqlite::params![root, path, len, dir])
+            .map_err(sql_error)?;
+        tx.commit().map_err(sql_error)
+    }
+
+    /// Move a path, and everything below it.
+    pub fn rename(&self, root: &str, from: &[u8], to: &[u8]) -> io::Result<()> {
+        self.remove(root, to)?;
+        let (dir, len) = below(from);
+        let (to_dir, _) = below(to);
+        let db = self.0.db.lock().unwrap();
+        db.execute(
+            "UPDATE docs
+             SET path = CASE WHEN path = ?2 THEN ?5 ELSE CAST(?6 || substr(path, ?3 + 1) AS BLOB) END
+             WHERE root = ?1 AND (path = ?2 OR substr(path, 1, ?3) = ?4)",
+            rusqlite::params![root, from, len, dir, to, to_dir],
+        )
+        .map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn send(&self, job: Job, max_size: u64) {
+        let mut queue = self.0.queue.lock().unwrap();
+        if queue.is_none() {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(indexer(self.clone(), rx));
+            *queue = Some(tx);
+        }
+        let _ = queue.as_ref().unwrap().send((job, max_size));
+    }
+
+    /// Lookups in "root", for SEARCH.
+    pub fn searcher(&self, root: &str) -> crate::search::Contains {
+        let (index, root) = (self.clone(), root.to_string());
+        Box::new(move |words| {
+            tokio::task::block_in_place(|| index.lookup(&root, words)).map_err(|e| {
+                error!("fulltext: lookup: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })
+        })
+    }
+
+    // (Re)index one file.
+    async fn index_file(
+        &self,
+        fs: &dyn DavFileSystem,
+        root: &str,
+        path: &DavPath,
+        max_size: u64,
+    ) -> FsResult<()>
+    {
+        let meta = fs.metadata(path).await?;
+        if !meta.is_file() {
+            return Ok(());
+        }
+        let name = String::from_utf8_lossy(&key(path)).into_owned();
+        let (mtime, size) = (unix_time(meta.modified()), meta.len());
+        if blocking(&name, || self.indexed(root, &key(path)))? == Some((mtime, size)) {
+            return Ok(());
+        }
+        let kind = match kind(&name) {
+            Some(kind) if size <= max_size => kind,
+            _ => return blocking(&name, || self.store(root, &key(path), mtime, size, None)),
+        };
+        let mut file = fs.open(path, read_options()).await?;
+        let mut data = Vec::new();
+        loop {
+            let buf = file.read_bytes(65536).await?;
+            if buf.is_empty() || data.len() as u64 > max_size {
+                break;
+            }
+            data.extend_from_slice(&buf);
+        }
+        let text = extract(kind, &data);
+        blocking(&name, || self.store(root, &key(path), mtime, size, text.as_deref()))
+    }
+
+    // Index every file below "root", and drop those that are gone.
+    async fn crawl(&self, fs: &dyn DavFileSystem, root: &str, max_size: u64) -> FsResult<()> {
+        let mut seen = HashSet::new();
+        let mut dirs = vec![DavPath::new("/").map_err(|_| FsError::GeneralFailure)?];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match fs.read_dir(&dir, ReadDirMeta::Data).await {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            while let Some(entry) = entries.next().await {
+                let path = join(&dir, &entry.name())?;
+                match entry.metadata().await {
+                    Ok(meta) if meta.is_dir() => dirs.push(path),
+                    Ok(meta) if meta.is_file() => {
+                        if let Err(e) = self.index_file(fs, root, &path, max_size).await {
+                            debug!("fulltext: {}: {:?}", path.as_url_string(), e);
+                        }
+                        seen.insert(key(&path));
+                    },
+                    _ => {},
+                }
+            }
+        }
+        let gone = tokio::task::block_in_place(|| -> io::Result<Vec<Vec<u8>>> {
+            let db = self.0.db.lock().unwrap();
+            let mut stmt = db.prepare("SELECT path FROM docs WHERE root = ?1").map_err(sql_error)?;
+            let rows = stmt.query_map([root], |row| row.get::<_, Vec<u8>>(0)).map_err(sql_error)?;
+            let paths = rows.collect::<rusqlite::Result<Vec<_>>>().map_err(sql_error)?;
+            Ok(paths.into_iter().filter(|p| !seen.contains(p)).collect())
+        });
+        for path in gone.unwrap_or_default() {
+            let _ = tokio::task::block_in_place(|| self.remove(root, &path));
+        }
+        Ok(())
+    }
+}
+
+// The indexer: one at a time, in the background.
+async fn indexer(index: Index, mut rx: mpsc::UnboundedReceiver<(Job, u64)>) {
+    while let Some((job, max_size)) = rx.recv().await {
+        match job {
+            Job::File(fs, root, path) => {
+                if let Err(e) = index.index_file(&*fs, &root, &path, max_size).await {
+                    debug!("fulltext: {}: {:?}", path.as_url_string(), e);
+                }
+            },
+            Job::Crawl(fs, root) => {
+                info!("fulltext: indexing {}", root);
+                if let Err(e) = index.crawl(&*fs, &root, max_size).await {
+                    warn!("fulltext: {}: {:?}", root, e);
+                }
+            },
+        }
+    }
+}
+
+/// A filesystem that keeps the index up to date.
+#[derive(Clone)]
+pub struct IndexFs {
+    fs:       Box<dyn DavFileSystem>,
+    index:    Index,
+    root:     String,
+    max_size: u64,
+}
+
+impl IndexFs {
+    /// Files larger than "max_size" are not indexed. The first time a
+    /// root is seen, all of it is (re)indexed in the background.
+    pub fn new(fs: Box<dyn DavFileSystem>, index: Index, root: &str, max_size: u64) -> Box<IndexFs> {
+        if index.0.crawled.lock().unwrap().insert(root.to_string()) {
+            index.send(Job::Crawl(fs.clone(), root.to_string()), max_size);
+        }
+        Box::new(IndexFs {
+            fs,
+            index,
+            root: root.to_string(),
+            max_size,
+        })
+    }
+
+    /// Also index the changes made outside the server, in directory "dir"
+    /// (see inotify.rs).
+    pub fn watch(&self, dir: &std::path::Path) {
+        let (fs, index, root) = (self.fs.clone(), self.index.clone(), self.root.clone());
+        let max_size = self.max_size;
+        let hook = move |change: crate::inotify::Change| {
+            let path = match change {
+                crate::inotify::Change::Path(path, false) => path,
+                crate::inotify::Change::Path(path, true) => {
+                    if let Err(e) = index.remove(&root, path) {
+                        error!("fulltext: remove: {}", e);
+                    }
+                    return;
+                },
+                crate::inotify::Change::Lost => {
+                    return index.send(Job::Crawl(fs.clone(), root.clone()), max_size);
+                },
+            };
+            if let Ok(path) = crate::syncdb::to_path(path) {
+                index.send(Job::File(fs.clone(), root.clone(), path), max_size);
+            }
+        };
+        tokio::task::block_in_place(|| crate::inotify::watch(dir, "fulltext-index", Box::new(hook)));
+    }
+
+    // Update the index after a change. The change has happened already,
+    // so a failure is only logged.
+    fn update<F>(&self, what: &str, f: F)
+    where F: FnOnce(&Index) -> io::Result<()> {
+        if let Err(e) = tokio::task::block_in_place(|| f(&self.index)) {
+            error!("fulltext: {}: {}", what, e);
+        }
+    }
+
+    fn reindex(&self, path: &DavPath) {
+        let job = Job::File(self.fs.clone(), self.root.clone(), path.clone());
+        self.index.send(job, self.max_size);
+    }
+}
+
+impl std::fmt::Debug for IndexFs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IndexFs").field("root", &self.root).finish()
+    }
+}
+
+// Reindexes a file after it was written to.
+#[derive(Debug)]
+struct IndexFile {
+    file:    Box<dyn DavFile>,
+    fs:      IndexFs,
+    path:    DavPath,
+    written: bool,
+}
+
+impl DavFile for IndexFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.file.metadata()
+    }
+
+    fn write_buf<'a>(&'a mut self, buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        self.written = true;
+        self.file.write_buf(buf)
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        self.written = true;
+        self.file.write_bytes(buf)
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        self.file.read_bytes(count)
+    }
+
+    fn seek<'a>(&'a mut self, pos: std::io::SeekFrom) -> FsFuture<'a, u64> {
+        self.file.seek(pos)
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        async move {
+            self.file.flush().await?;
+            if std::mem::replace(&mut self.written, false) {
+                self.fs.reindex(&self.path);
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+impl DavFileSystem for IndexFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let write = options.write || options.append || options.create || options.create_new;
+            let file = self.fs.open(path, options).await?;
+            if !write {
+                return Ok(file);
+            }
+            Ok(Box::new(IndexFile {
+                file,
+                fs: self.clone(),
+                path: path.clone(),
+                written: false,
+            }) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.remove_dir(path).await?;
+            self.update("remove", |i| i.remove(&self.root, &key(path)));
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.remove_file(path).await?;
+            self.update("remove", |i| i.remove(&self.root, &key(path)));
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.rename(from, to).await?;
+            self.update("rename", |i| i.rename(&self.root, &key(from), &key(to)));
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.copy(from, to).await?;
+            self.reindex(to);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        self.fs.patch_props(path, patch)
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        assert_eq!(kind("notes.TXT"), Some(Kind::Text));
+        assert_eq!(kind("main.rs"), Some(Kind::Text));
+        assert_eq!(kind("index.html"), Some(Kind::Markup));
+        assert_eq!(kind("photo.jpg"), None);
+        assert_eq!(kind("README"), None);
+        let text = extract(Kind::Markup, b"<p>fish &amp; <b>chips</b></p>").unwrap();
+        assert_eq!(text.split_whitespace().collect::<Vec<_>>(), ["fish", "&", "chips"]);
+        assert_eq!(extract(Kind::Text, b"a\0b"), None);
+        assert_eq!(fts_query("fish \"chips\""), "\"fish\" \"\"\"chips\"\"\"");
+    }
+
+    #[test]
+    fn test_index() {
+        let index = Index::open(":memory:").unwrap();
+        index.store("r", b"/a/x.txt", 1, 10, Some("the quick brown fox")).unwrap();
+        index.store("r", b"/b.txt", 1, 10, Some("a lazy dog")).unwrap();
+        let found = index.lookup("r", "quick fox").unwrap();
+        assert_eq!(found.into_iter().collect::<Vec<_>>(), vec![b"/a/x.txt".to_vec()]);
+        assert!(index.lookup("r", "quick dog").unwrap().is_empty());
+        assert!(index.lookup("other", "fox").unwrap().is_empty());
+
+        index.rename("r", b"/a", b"/c").unwrap();
+        let found = index.lookup("r", "fox").unwrap();
+        assert_eq!(found.into_iter().collect::<Vec<_>>(), vec![b"/c/x.txt".to_vec()]);
+        index.remove("r", b"/c").unwrap();
+        assert!(index.lookup("r", "fox").unwrap().is_empty());
+    }
+}
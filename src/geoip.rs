@@ -0,0 +1,399 @@
+//
+// The country of a client address, from a MaxMind DB file (GeoLite2 or
+// GeoIP2 Country or City, or anything else in that format with a
+// "country" / "iso_code"), for allow-countries and deny-countries.
+//
+// The file is read into memory when the config is read (again on a
+// reload, so a newer file is picked up with SIGHUP). Only what a lookup
+// needs is decoded: the search tree, and the record that it ends at.
+//
+// An address that is not in the file (private ranges, loopback, or just
+// unknown) has the country "unknown".
+//
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+
+const MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+// A corrupt file should not loop forever.
+const MAX_NESTING: u32 = 32;
+
+/// A value from the data section.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Uint(u128),
+    Int(i32),
+    Double(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(map) => map.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u128> {
+        match self {
+            Value::Uint(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+// Decodes values in a section; pointers are from its start.
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&self, pos: usize, len: usize) -> Result<&'a [u8], String> {
+        let end = pos.checked_add(len).ok_or("position out of range")?;
+        self.buf.get(pos..end).ok_or_else(|| "unexpected end of data".to_string())
+    }
+
+    fn uint(&self, pos: usize, len: usize) -> Result<u128, String> {
+        if len > 16 {
+            return Err(format!("integer of {} bytes", len));
+        }
+        Ok(self.bytes(pos, len)?.iter().fold(0u128, |n, b| n << 8 | *b as u128))
+    }
+
+    // The value at "pos", and the position after it.
+    fn decode(&self, pos: usize, depth: u32) -> Result<(Value, usize), String> {
+        if depth > MAX_NESTING {
+            return Err("values nested too deep".to_string());
+        }
+        let ctrl = self.bytes(pos, 1)?[0];
+        let mut pos = pos + 1;
+        let mut kind = ctrl >> 5;
+
+        // A pointer has a size of its own.
+        if kind == 1 {
+            let v = (ctrl & 0x07) as usize;
+            let (ptr, len) = match (ctrl >> 3) & 0x03 {
+                0 => (v << 8 | self.uint(pos, 1)? as usize, 1),
+                1 => ((v << 16 | self.uint(pos, 2)? as usize) + 2048, 2),
+                2 => ((v << 24 | self.uint(pos, 3)? as usize) + 526336, 3),
+                _ => (self.uint(pos, 4)? as usize, 4),
+            };
+            let (value, _) = self.decode(ptr, depth + 1)?;
+            return Ok((value, pos + len));
+        }
+        if kind == 0 {
+            kind = 7 + self.bytes(pos, 1)?[0];
+            pos += 1;
+        }
+        let size = match ctrl & 0x1f {
+            29 => {
+                pos += 1;
+                29 + self.uint(pos - 1, 1)? as usize
+            },
+            30 => {
+                pos += 2;
+                285 + self.uint(pos - 2, 2)? as usize
+            },
+            31 => {
+                pos += 3;
+                65821 + self.uint(pos - 3, 3)? as usize
+            },
+            n => n as usize,
+        };
+
+        let value = match kind {
+            2 => {
+                let s = std::str::from_utf8(self.bytes(pos, size)?).map_err(|_| "invalid UTF-8")?;
+                pos += size;
+                Value::Str(s.to_string())
+            },
+            3 | 15 => {
+                let b = self.bytes(pos, size)?;
+                pos += size;
+                match size {
+                    8 => Value::Double(f64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])),
+                    4 => Value::Double(f32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f64),
+                    _ => return Err(format!("float of {} bytes", size)),
+                }
+            },
+            4 => {
+                let b = self.bytes(pos, size)?;
+                pos += size;
+                Value::Bytes(b.to_vec())
+            },
+            5 | 6 | 9 | 10 => {
+                let n = self.uint(pos, size)?;
+                pos += size;
+                Value::Uint(n)
+            },
+            8 => {
+                if size > 4 {
+                    return Err(format!("int32 of {} bytes", size));
+                }
+                let n = self.uint(pos, size)? as u32;
+                pos += size;
+                Value::Int(n as i32)
+            },
+            7 => {
+                let mut map = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (key, next) = self.decode(pos, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    match key {
+                        Value::Str(key) => map.push((key, value)),
+                        _ => return Err("map key is not a string".to_string()),
+                    }
+                    pos = next;
+                }
+                Value::Map(map)
+            },
+            11 => {
+                let mut array = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (value, next) = self.decode(pos, depth + 1)?;
+                    array.push(value);
+                    pos = next;
+                }
+                Value::Array(array)
+            },
+            14 => Value::Bool(size != 0),
+            _ => return Err(format!("unknown data type {}", kind)),
+        };
+        Ok((value, pos))
+    }
+}
+
+/// A MaxMind DB file, in memory.
+pub struct Reader {
+    data:        Vec<u8>,
+    node_count:  usize,
+    record_size: usize,
+    ip_version:  u16,
+    // where the data section starts, and the metadata.
+    data_start:  usize,
+    meta_start:  usize,
+    // the node of ::0.0.0.0, where IPv4 addresses start in an IPv6 tree.
+    ipv4_start:  usize,
+    db_type:     String,
+}
+
+impl fmt::Debug for Reader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Reader")
+            .field("db_type", &self.db_type)
+            .field("ip_version", &self.ip_version)
+            .field("node_count", &self.node_count)
+            .finish()
+    }
+}
+
+impl Reader {
+    pub fn open(path: &str) -> io::Result<Reader> {
+        let data = std::fs::read(path)?;
+        Reader::from_bytes(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Reader, String> {
+        let marker = data
+            .windows(MARKER.len())
+            .rposition(|w| w == MARKER)
+            .ok_or("not a MaxMind DB file")?;
+        let meta = Decoder { buf: &data[marker + MARKER.len()..] };
+        let (meta, _) = meta.decode(0, 0).map_err(|e| format!("metadata: {}", e))?;
+        let field = |name: &str| {
+            meta.get(name)
+                .and_then(Value::as_uint)
+                .ok_or_else(|| format!("metadata: no {}", name))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")? as u16;
+        if !matches!(record_size, 24 | 28 | 32) {
+            return Err(format!("record size {} is not supported", record_size));
+        }
+        if !matches!(ip_version, 4 | 6) {
+            return Err(format!("ip version {} is not supported", ip_version));
+        }
+        let data_start = node_count.checked_mul(record_size / 4).and_then(|n| n.checked_add(16));
+        let data_start = data_start.ok_or("node count out of range")?;
+        if data_start > marker {
+            return Err("search tree is larger than the file".to_string());
+        }
+        let db_type = meta.get("database_type").and_then(Value::as_str).unwrap_or("").to_string();
+        let mut reader = Reader {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+            meta_start: marker,
+            ipv4_start: 0,
+            db_type,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = reader.record(node, 0)?;
+            }
+            reader.ipv4_start = node;
+        }
+        Ok(reader)
+    }
+
+    // The left (0) or right (1) record of a node.
+    fn record(&self, node: usize, bit: u8) -> Result<usize, String> {
+        let size = self.record_size / 4;
+        let b = self
+            .data
+            .get(node * size..node * size + size)
+            .ok_or("node outside of the search tree")?;
+        let be = |b: &[u8]| b.iter().fold(0usize, |n, b| n << 8 | *b as usize);
+        Ok(match (self.record_size, bit) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => ((b[3] & 0xf0) as usize) << 20 | be(&b[0..3]),
+            (28, _) => ((b[3] & 0x0f) as usize) << 24 | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            (_, _) => be(&b[4..8]),
+        })
+    }
+
+    /// The record for an address, if there is one.
+    pub fn lookup(&self, ip: IpAddr) -> Result<Option<Value>, String> {
+        let (bits, count, mut node) = match crate::cidr::canonical(ip) {
+            IpAddr::V4(v4) if self.ip_version == 4 => ((u32::from(v4) as u128) << 96, 32, 0),
+            IpAddr::V4(v4) => ((u32::from(v4) as u128) << 96, 32, self.ipv4_start),
+            IpAddr::V6(_) if self.ip_version == 4 => return Ok(None),
+            IpAddr::V6(v6) => (u128::from(v6), 128, 0),
+        };
+        for i in 0..count {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (bits >> (127 - i)) as u8 & 1)?;
+        }
+        if node == self.node_count {
+            return Ok(None);
+        }
+        if node < self.node_count {
+            return Err("search tree too deep".to_string());
+        }
+        let offset = match node.checked_sub(self.node_count + 16) {
+            Some(offset) => offset,
+            None => return Err("record points into the separator".to_string()),
+        };
+        let data = Decoder { buf: &self.data[self.data_start..self.meta_start] };
+        data.decode(offset, 0).map(|(value, _)| Some(value))
+    }
+
+    /// The ISO 3166 country code of an address.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let value = match self.lookup(ip) {
+            Ok(value) => value?,
+            Err(e) => {
+                debug!("geoip: {}: {}", ip, e);
+                return None;
+            },
+        };
+        let country = value.get("country").or_else(|| value.get("registered_country"))?;
+        country.get("iso_code").and_then(Value::as_str).map(|s| s.to_string())
+    }
+}
+
+/// A country (None is "unknown") is allowed if it is in "allow" (or "allow"
+/// is empty), and not in "deny".
+pub fn allowed(allow: &[String], deny: &[String], country: Option<&str>) -> bool {
+    let country = country.unwrap_or("unknown");
+    let matches = |list: &[String]| list.iter().any(|c| c.eq_ignore_ascii_case(country));
+    (allow.is_empty() || matches(allow)) && !matches(deny)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut v = vec![0x40 | s.len() as u8];
+        v.extend_from_slice(s.as_bytes());
+        v
+    }
+
+    // IPv4 only, 24 bit records, and just 192.0.2.0/24 in it.
+    fn database() -> Vec<u8> {
+        let prefix: u32 = 0xc0000200;
+        let node_count = 24u32;
+        // {"iso_code": "NL"} at 0, {"country": <pointer to 0>} after it.
+        let mut data = vec![0xe1];
+        data.extend(string("iso_code"));
+        data.extend(string("NL"));
+        let outer = data.len() as u32;
+        data.push(0xe1);
+        data.extend(string("country"));
+        data.extend([0x20, 0x00]);
+
+        let mut db = Vec::new();
+        for node in 0..node_count {
+            let bit = (prefix >> (31 - node)) & 1;
+            let next = if node == node_count - 1 { node_count + 16 + outer } else { node + 1 };
+            let (left, right) = if bit == 0 { (next, node_count) } else { (node_count, next) };
+            db.extend(&left.to_be_bytes()[1..]);
+            db.extend(&right.to_be_bytes()[1..]);
+        }
+        db.extend([0u8; 16]);
+        db.extend(data);
+        db.extend(MARKER);
+        db.push(0xe3);
+        db.extend(string("node_count"));
+        db.extend([0xc1, node_count as u8]);
+        db.extend(string("record_size"));
+        db.extend([0xa1, 24]);
+        db.extend(string("ip_version"));
+        db.extend([0xa1, 4]);
+        db
+    }
+
+    #[test]
+    fn test_geoip() {
+        let reader = Reader::from_bytes(database()).unwrap();
+        assert_eq!(reader.country("192.0.2.77".parse().unwrap()).as_deref(), Some("NL"));
+        assert_eq!(reader.country("::ffff:192.0.2.1".parse().unwrap()).as_deref(), Some("NL"));
+        assert_eq!(reader.country("192.0.3.1".parse().unwrap()), None);
+        assert_eq!(reader.country("2001:db8::1".parse().unwrap()), None);
+        assert!(Reader::from_bytes(b"not a database".to_vec()).is_err());
+
+        // a record that points between the tree and the data.
+        let mut corrupt = database();
+        corrupt[23 * 6..23 * 6 + 3].copy_from_slice(&[0, 0, 24 + 5]);
+        assert!(reader.lookup("192.0.2.1".parse().unwrap()).is_ok());
+        assert!(Reader::from_bytes(corrupt).unwrap().lookup("192.0.2.1".parse().unwrap()).is_err());
+        let mut huge = database();
+        let at = huge.len() - 27;
+        huge.splice(at - 2..at, [0x08, 0x02, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(Reader::from_bytes(huge).err().as_deref(), Some("node count out of range"));
+
+        let eu = vec!["NL".to_string(), "be".to_string()];
+        let unknown = vec!["unknown".to_string()];
+        assert!(allowed(&eu, &[], Some("BE")));
+        assert!(!allowed(&eu, &[], Some("US")));
+        assert!(!allowed(&eu, &[], None));
+        assert!(allowed(&[], &[], None));
+        assert!(!allowed(&[], &unknown, None));
+        assert!(allowed(&[], &unknown, Some("US")));
+    }
+}
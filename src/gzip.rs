@@ -0,0 +1,293 @@
+//
+// A streaming gzip encoder (RFC 1951, RFC 1952).
+//
+// Every chunk of input becomes one deflate block with the fixed Huffman
+// codes. Matches are found with hash chains over a 32K window that
+// spans the chunks. That does not compress as well as zlib does, but it
+// is fast, and for text it is most of the gain. A chunk that does not
+// get smaller is sent as stored blocks.
+//
+const WINDOW: usize = 32768;
+const HASH_BITS: usize = 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// How many earlier positions to try, for a match.
+const MAX_CHAIN: usize = 48;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195,
+    227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073,
+    4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+lazy_static::lazy_static! {
+    static ref CRC_TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        for (n, entry) in table.iter_mut().enumerate() {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+            }
+            *entry = c;
+        }
+        table
+    };
+}
+
+/// Update a CRC-32 with some data. Start with 0.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut c = !crc;
+    for &b in data {
+        c = CRC_TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+// Huffman codes are sent most significant bit first, everything else
+// least significant bit first.
+fn reverse(code: u32, len: u32) -> u32 {
+    code.reverse_bits() >> (32 - len)
+}
+
+// The fixed code of a literal/length symbol: (code, length).
+fn fixed_code(sym: usize) -> (u32, u32) {
+    let (code, len) = match sym {
+        0..=143 => (0x30 + sym as u32, 8),
+        144..=255 => (0x190 + (sym as u32 - 144), 9),
+        256..=279 => (sym as u32 - 256, 7),
+        _ => (0xc0 + (sym as u32 - 280), 8),
+    };
+    (reverse(code, len), len)
+}
+
+struct BitWriter {
+    out:   Vec<u8>,
+    bits:  u64,
+    nbits: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, len: u32) {
+        self.bits |= (value as u64) << self.nbits;
+        self.nbits += len;
+        while self.nbits >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.nbits > 0 {
+            self.out.push(self.bits as u8);
+            self.bits = 0;
+            self.nbits = 0;
+        }
+    }
+}
+
+/// A gzip stream. Data goes in with `write`, which returns what can be
+/// sent so far; `finish` returns the rest.
+pub struct Gzip {
+    writer: BitWriter,
+    // the window: the last 32K of earlier input, and the current input.
+    window: Vec<u8>,
+    // the position of window[0] in the input.
+    base:   usize,
+    head:   Vec<usize>,
+    prev:   Vec<usize>,
+    crc:    u32,
+    size:   u32,
+}
+
+impl Default for Gzip {
+    fn default() -> Gzip {
+        Gzip::new()
+    }
+}
+
+impl Gzip {
+    pub fn new() -> Gzip {
+        let mut writer = BitWriter {
+            out:   Vec::new(),
+            bits:  0,
+            nbits: 0,
+        };
+        // magic, deflate, no flags, no mtime, no extra flags, unix.
+        writer.out.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3]);
+        Gzip {
+            writer,
+            window: Vec::new(),
+            base: 0,
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            prev: vec![usize::MAX; WINDOW],
+            crc: 0,
+            size: 0,
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let w = &self.window[pos - self.base..];
+        let h = (w[0] as usize) << 10 ^ (w[1] as usize) << 5 ^ w[2] as usize;
+        h.wrapping_mul(2654435761) >> 7 & ((1 << HASH_BITS) - 1)
+    }
+
+    fn insert(&mut self, pos: usize) {
+        let h = self.hash(pos);
+        self.prev[pos % WINDOW] = self.head[h];
+        self.head[h] = pos;
+    }
+
+    // The longest earlier match at "pos": (length, distance).
+    fn find(&self, pos: usize, end: usize) -> (usize, usize) {
+        let max = (end - pos).min(MAX_MATCH);
+        let data = &self.window;
+        let cur = pos - self.base;
+        let (mut best, mut dist) = (0, 0);
+        let mut candidate = self.head[self.hash(pos)];
+        for _ in 0..MAX_CHAIN {
+            let valid = candidate != usize::MAX && candidate >= self.base;
+            if !valid || candidate >= pos || pos - candidate > WINDOW {
+                break;
+            }
+            let c = candidate - self.base;
+            if data[c + best] == data[cur + best] {
+                let len = (0..max).take_while(|&i| data[c + i] == data[cur + i]).count();
+                if len > best {
+                    best = len;
+                    dist = pos - candidate;
+                    if len == max {
+                        break;
+                    }
+                }
+            }
+            candidate = self.prev[candidate % WINDOW];
+        }
+        (best, dist)
+    }
+
+    fn literal(&mut self, b: u8) {
+        let (code, len) = fixed_code(b as usize);
+        self.writer.put(code, len);
+    }
+
+    fn matched(&mut self, length: usize, distance: usize) {
+        let l = LENGTH_BASE.iter().rposition(|&b| b as usize <= length).unwrap();
+        let (code, len) = fixed_code(257 + l);
+        self.writer.put(code, len);
+        self.writer.put((length - LENGTH_BASE[l] as usize) as u32, LENGTH_EXTRA[l] as u32);
+        let d = DIST_BASE.iter().rposition(|&b| b as usize <= distance).unwrap();
+        self.writer.put(reverse(d as u32, 5), 5);
+        self.writer.put((distance - DIST_BASE[d] as usize) as u32, DIST_EXTRA[d] as u32);
+    }
+
+    /// Compress some data.
+    pub fn write(&mut self, data: &[u8]) -> Vec<u8> {
+        if data.is_empty() {
+            return std::mem::take(&mut self.writer.out);
+        }
+        self.crc = crc32(self.crc, data);
+        self.size = self.size.wrapping_add(data.len() as u32);
+
+        // Keep 32K of history.
+        if self.window.len() > WINDOW {
+            let drop = self.window.len() - WINDOW;
+            self.window.drain(..drop);
+            self.base += drop;
+        }
+        let start = self.base + self.window.len();
+        self.window.extend_from_slice(data);
+        let end = self.base + self.window.len();
+
+        // A block with the fixed codes, not the last one.
+        let (out, bits, nbits) = (self.writer.out.len(), self.writer.bits, self.writer.nbits);
+        self.writer.put(0b010, 3);
+        let mut pos = start;
+        while pos < end {
+            let (length, distance) = match end - pos >= MIN_MATCH {
+                true => self.find(pos, end),
+                false => (0, 0),
+            };
+            if length >= MIN_MATCH {
+                self.matched(length, distance);
+                for p in pos..(pos + length).min(end - MIN_MATCH + 1) {
+                    self.insert(p);
+                }
+                pos += length;
+            } else {
+                self.literal(self.window[pos - self.base]);
+                if end - pos >= MIN_MATCH {
+                    self.insert(pos);
+                }
+                pos += 1;
+            }
+        }
+        let (code, len) = fixed_code(256);
+        self.writer.put(code, len);
+
+        // Stored blocks instead, if that is smaller.
+        if self.writer.out.len() - out > data.len() + data.len() / 65535 * 5 + 5 {
+            self.writer.out.truncate(out);
+            self.writer.bits = bits;
+            self.writer.nbits = nbits;
+            for block in data.chunks(65535) {
+                self.writer.put(0b000, 3);
+                self.writer.align();
+                let len = block.len() as u16;
+                self.writer.out.extend_from_slice(&len.to_le_bytes());
+                self.writer.out.extend_from_slice(&(!len).to_le_bytes());
+                self.writer.out.extend_from_slice(block);
+            }
+        }
+        std::mem::take(&mut self.writer.out)
+    }
+
+    /// The end of the stream.
+    pub fn finish(mut self) -> Vec<u8> {
+        // An empty last block.
+        self.writer.put(0b011, 3);
+        let (code, len) = fixed_code(256);
+        self.writer.put(code, len);
+        self.writer.align();
+        let mut out = self.writer.out;
+        out.extend_from_slice(&self.crc.to_le_bytes());
+        out.extend_from_slice(&self.size.to_le_bytes());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gzip() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf43926);
+
+        let mut gz = Gzip::new();
+        let text = b"<D:response><D:href>/a</D:href></D:response>\n".repeat(100);
+        let mut out = gz.write(&text[..1000]);
+        out.extend(gz.write(&text[1000..]));
+        out.extend(gz.finish());
+        assert_eq!(&out[..3], &[0x1f, 0x8b, 8]);
+        assert!(out.len() < text.len() / 10);
+        let n = out.len();
+        assert_eq!(&out[n - 8..n - 4], &crc32(0, &text).to_le_bytes());
+        assert_eq!(&out[n - 4..], &(text.len() as u32).to_le_bytes());
+        // "a" as a fixed block, then the empty last block.
+        let mut gz = Gzip::new();
+        let mut out = gz.write(b"a");
+        out.extend(gz.finish());
+        assert_eq!(&out[10..14], &[0x4a, 0x04, 0x0c, 0x00]);
+    }
+}
@@ -0,0 +1,154 @@
+//
+// Health checks, for load balancers and container orchestrators.
+//
+// /healthz: the server is alive, and the PAM helper process answers.
+// /readyz: also, all listeners are up, and the storage of the locations
+// can be reached: the directories exist, and the S3 buckets can be listed.
+// Once the server is shutting down, it is not ready anymore.
+//
+// The status is 200 or 503, and the body has a line for every check.
+//
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use futures::future::{BoxFuture, FutureExt};
+use http::StatusCode;
+
+use crate::auth::Auth;
+use crate::config::{Config, Handler};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+type Check = BoxFuture<'static, Result<(), String>>;
+
+/// All listeners are up (or, false, the server is shutting down).
+pub fn set_listening(up: bool) {
+    LISTENING.store(up, Ordering::SeqCst);
+}
+
+/// Is this the path of a health check.
+pub fn is_health_path(path: &str) -> bool {
+    path == "/healthz" || path == "/readyz"
+}
+
+/// Run the checks for /healthz or /readyz. Returns the status and body.
+pub async fn check(path: &str, config: &Config, auth: &Auth) -> (StatusCode, String) {
+    let mut results = vec![("pam".to_string(), auth.pam_ping(TIMEOUT).await)];
+    if path == "/readyz" {
+        let listening = match LISTENING.load(Ordering::SeqCst) {
+            true => Ok(()),
+            false => Err("not listening".to_string()),
+        };
+        results.push(("listeners".to_string(), listening));
+        let checks = storage(config)
+            .into_iter()
+            .map(|(name, check)| async move { (name, check.await) });
+        results.extend(futures::future::join_all(checks).await);
+    }
+
+    let ok = results.iter().all(|(_, res)| res.is_ok());
+    let mut body = String::new();
+    for (name, res) in &results {
+        let _ = match res {
+            Ok(()) => writeln!(body, "ok {}", name),
+            Err(e) => writeln!(body, "fail {}: {}", name, e),
+        };
+    }
+    body.push_str(if ok { "ok\n" } else { "fail\n" });
+    let status = if ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, body)
+}
+
+// The part of a location directory that is the same for all users.
+// "/home/$user/www" is checked as "/home/", and so is "/home/${pam:X}".
+// Home directories are skipped.
+fn fixed_dir(dir: &str) -> Option<&str> {
+    if dir.starts_with('~') {
+        return None;
+    }
+    match dir.find("$user").into_iter().chain(dir.find("${pam:")).min() {
+        Some(idx) => dir[..idx].rfind('/').map(|end| &dir[..=end]),
+        None => Some(dir),
+    }
+}
+
+// A check for every directory and S3 bucket that is used.
+fn storage(config: &Config) -> Vec<(String, Check)> {
+    let mut dirs = BTreeSet::new();
+    let mut buckets = BTreeSet::new();
+    for (_, location) in config.locations() {
+        match location.handler {
+            Handler::Filesystem | Handler::Caldav | Handler::Carddav | Handler::Virtroot => {
+                if let Some(dir) = fixed_dir(&location.directory) {
+                    dirs.insert(dir.to_string());
+                }
+            },
+            Handler::S3 => {
+                buckets.insert(location.s3.clone().unwrap_or_default());
+            },
+            Handler::Mem => {},
+        }
+    }
+
+    let mut checks = Vec::new();
+    for dir in dirs {
+        checks.push((format!("directory {}", dir), directory(dir)));
+    }
+    for name in buckets {
+        checks.push((format!("s3 {}", name), bucket(config, &name)));
+    }
+    checks
+}
+
+fn directory(dir: String) -> Check {
+    async move {
+        let meta = tokio::task::spawn_blocking(move || std::fs::metadata(dir));
+        match tokio::time::timeout(TIMEOUT, meta).await {
+            Ok(Ok(Ok(meta))) if meta.is_dir() => Ok(()),
+            Ok(Ok(Ok(_))) => Err("not a directory".to_string()),
+            Ok(Ok(Err(e))) => Err(e.to_string()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("timeout".to_string()),
+        }
+    }
+    .boxed()
+}
+
+#[cfg(feature = "s3")]
+fn bucket(config: &Config, name: &str) -> Check {
+    let client = config.s3.get(name).map(|cfg| crate::s3::S3Client::shared(name, cfg));
+    async move {
+        let client = client.ok_or_else(|| "not configured".to_string())?;
+        match tokio::time::timeout(TIMEOUT, client.list("", false, 1, None)).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(format!("{:?}", e)),
+            Err(_) => Err("timeout".to_string()),
+        }
+    }
+    .boxed()
+}
+
+// validate() does not allow s3 locations without the feature.
+#[cfg(not(feature = "s3"))]
+fn bucket(_config: &Config, _name: &str) -> Check {
+    async { Err("not supported".to_string()) }.boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_dir() {
+        assert_eq!(fixed_dir("/srv/dav"), Some("/srv/dav"));
+        assert_eq!(fixed_dir("/home/$user/www"), Some("/home/"));
+        assert_eq!(fixed_dir("/srv/u-$user"), Some("/srv/"));
+        assert_eq!(fixed_dir("/srv/${pam:GROUP}/$user"), Some("/srv/"));
+        assert_eq!(fixed_dir("~/www"), None);
+        assert_eq!(fixed_dir("$user"), None);
+    }
+}
@@ -0,0 +1,320 @@
+//
+// Hidden and denied files, by glob patterns (hide = [ ".*", "Thumbs.db" ],
+// deny = [ ".git", "secret/**" ]).
+//
+// A pattern without a slash is matched against the name of a file, at
+// any depth; one with a slash (also at the start) against its path from
+// the root of the location. "dir/**" is the directory and everything in it, and so is
+// "dir" when it is denied.
+//
+// Hidden files are left out of listings, but can be accessed by name.
+// Denied files are left out too, and every access to them, or to
+// anything in them, is refused as if they did not exist. A DELETE,
+// COPY or MOVE of a directory takes the files in it along, hidden or not.
+//
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use futures::future::{self, FutureExt};
+use futures::StreamExt;
+use glob::{MatchOptions, Pattern};
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: Pattern,
+    // matched against the path, not the name.
+    path:    bool,
+}
+
+impl Rule {
+    fn new(s: &str) -> Result<Rule, glob::PatternError> {
+        let path = s.trim_end_matches('/').contains('/');
+        let s = s.trim_start_matches('/');
+        let s = s.strip_suffix("/**").unwrap_or(s).trim_end_matches('/');
+        Ok(Rule {
+            pattern: Pattern::new(s)?,
+            path,
+        })
+    }
+}
+
+/// The hide and deny rules of a location.
+#[derive(Debug, Clone)]
+pub struct Rules {
+    hide:    Vec<Rule>,
+    deny:    Vec<Rule>,
+    options: MatchOptions,
+}
+
+impl Rules {
+    pub fn new(hide: &[String], deny: &[String], case_sensitive: bool) -> Result<Rules, String> {
+        let compile = |what: &str, patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|p| Rule::new(p).map_err(|e| format!("{} {}: {}", what, p, e)))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Rules {
+            hide:    compile("hide", hide)?,
+            deny:    compile("deny", deny)?,
+            options: MatchOptions {
+                case_sensitive,
+                require_literal_separator: true,
+                require_literal_leading_dot: false,
+            },
+        })
+    }
+
+    fn matches(&self, rules: &[Rule], path: &str, name: &str) -> bool {
+        rules.iter().any(|r| {
+            let s = if r.path { path } else { name };
+            r.pattern.matches_with(s, self.options)
+        })
+    }
+
+    // The path, or a directory it is in, is denied.
+    fn denied(&self, path: &DavPath) -> bool {
+        if self.deny.is_empty() {
+            return false;
+        }
+        let path = String::from_utf8_lossy(path.as_bytes());
+        let path = path.trim_matches('/');
+        let mut end = 0;
+        for name in path.split('/') {
+            end += name.len();
+            if self.matches(&self.deny, &path[..end], name) {
+                return true;
+            }
+            end += 1;
+        }
+        false
+    }
+
+    // A name in a directory is not listed.
+    fn hidden(&self, dir: &DavPath, name: &[u8]) -> bool {
+        let name = String::from_utf8_lossy(name);
+        let dir = String::from_utf8_lossy(dir.as_bytes());
+        let path = format!("{}/{}", dir.trim_matches('/'), name);
+        let path = path.trim_start_matches('/');
+        self.matches(&self.hide, path, &name) || self.matches(&self.deny, path, &name)
+    }
+}
+
+#[derive(Clone)]
+pub struct HideFs {
+    fs:      Box<dyn DavFileSystem>,
+    rules:   Arc<Rules>,
+    listing: bool,
+}
+
+impl HideFs {
+    /// With `listing`, read_dir leaves out what is hidden; that is for
+    /// the methods that list a directory, not those that walk it.
+    pub fn new(fs: Box<dyn DavFileSystem>, rules: Arc<Rules>, listing: bool) -> Box<HideFs> {
+        Box::new(HideFs { fs, rules, listing })
+    }
+
+    fn check(&self, path: &DavPath) -> FsResult<()> {
+        match self.rules.denied(path) {
+            true => Err(FsError::NotFound),
+            false => Ok(()),
+        }
+    }
+
+    fn check_write(&self, path: &DavPath) -> FsResult<()> {
+        match self.rules.denied(path) {
+            true => Err(FsError::Forbidden),
+            false => Ok(()),
+        }
+    }
+}
+
+impl DavFileSystem for HideFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            if options.create || options.create_new {
+                self.check_write(path)?;
+            }
+            self.check(path)?;
+            self.fs.open(path, options).await
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            self.check(path)?;
+            let strm = self.fs.read_dir(path, meta).await?;
+            if !self.listing {
+                return Ok(strm);
+            }
+            let rules = self.rules.clone();
+            let dir = path.clone();
+            let strm = strm.filter(move |e| future::ready(!rules.hidden(&dir, &e.name())));
+            Ok(Box::pin(strm) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            self.check(path)?;
+            self.fs.metadata(path).await
+        }
+        .boxed()
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            self.check(path)?;
+            self.fs.symlink_metadata(path).await
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check_write(path)?;
+            self.fs.create_dir(path).await
+        }
+        .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check(path)?;
+            self.fs.remove_dir(path).await
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check(path)?;
+            self.fs.remove_file(path).await
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check(from)?;
+            self.check_write(to)?;
+            self.fs.rename(from, to).await
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check(from)?;
+            self.check_write(to)?;
+            self.fs.copy(from, to).await
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            self.check(path)?;
+            self.fs.set_accessed(path, tm).await
+        }
+        .boxed()
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            self.check(path)?;
+            self.fs.set_modified(path, tm).await
+        }
+        .boxed()
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        match self.rules.denied(path) {
+            true => Box::pin(future::ready(false)),
+            false => self.fs.have_props(path),
+        }
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        async move {
+            self.check(path)?;
+            self.fs.patch_props(path, patch).await
+        }
+        .boxed()
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        async move {
+            self.check(path)?;
+            self.fs.get_props(path, do_content).await
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        async move {
+            self.check(path)?;
+            self.fs.get_prop(path, prop).await
+        }
+        .boxed()
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hidefs() {
+        let list = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let rules = Rules::new(&list(&[".*", "Thumbs.db"]), &list(&[".git/**", "/private/*.key"]), true);
+        let rules = rules.unwrap();
+        let path = |s: &str| DavPath::new(s).unwrap();
+
+        assert!(rules.denied(&path("/.git")));
+        assert!(rules.denied(&path("/.git/")));
+        assert!(rules.denied(&path("/.git/objects/ab")));
+        assert!(!rules.denied(&path("/src/.git")));
+        assert!(rules.denied(&path("/private/a.key")));
+        assert!(!rules.denied(&path("/private/a.txt")));
+        assert!(!rules.denied(&path("/.hidden")));
+        let rules2 = Rules::new(&[], &list(&[".svn", "**/CVS/**"]), true).unwrap();
+        assert!(rules2.denied(&path("/a/.svn/entries")));
+        assert!(rules2.denied(&path("/CVS/Root")));
+        assert!(rules2.denied(&path("/a/b/CVS")));
+        let rules2 = Rules::new(&[], &list(&["/top"]), true).unwrap();
+        assert!(rules2.denied(&path("/top/x")));
+        assert!(!rules2.denied(&path("/a/top")));
+
+        assert!(rules.hidden(&path("/"), b".hidden"));
+        assert!(rules.hidden(&path("/a/b/"), b"Thumbs.db"));
+        assert!(!rules.hidden(&path("/a/b/"), b"thumbs.db"));
+        assert!(rules.hidden(&path("/"), b".git"));
+        assert!(!rules.hidden(&path("/a/"), b"b.txt"));
+
+        let rules = Rules::new(&list(&["thumbs.db"]), &[], false).unwrap();
+        assert!(rules.hidden(&path("/"), b"Thumbs.DB"));
+        assert!(Rules::new(&list(&["a/***"]), &[], true).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+//
+// Apache htpasswd password verification.
+//
+// Apart from the usual crypt(3) formats (bcrypt, md5-crypt, sha256/512-crypt,
+// des) that pwhash handles, Apache uses two formats of its own:
+//
+// - $apr1$: md5-crypt with a different magic string
+// - {SHA}:  base64 encoded unsalted SHA-1.
+//
+// And the argon2id and scrypt hashes of passhash.rs. With rehash, the
+// line of a user is replaced by update().
+//
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::sync::Mutex;
+
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+lazy_static::lazy_static! {
+    static ref UPDATE: Mutex<()> = Mutex::new(());
+}
+
+const CRYPT_B64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+// Compare two byte strings in constant time.
+pub(crate) fn consteq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn to64(out: &mut String, mut v: u32, n: usize) {
+    for _ in 0..n {
+        out.push(CRYPT_B64[(v & 0x3f) as usize] as char);
+        v >>= 6;
+    }
+}
+
+// The md5-crypt algorithm, with "$apr1$" as the magic.
+fn apr1_crypt(pw: &[u8], salt: &[u8]) -> String {
+    const MAGIC: &[u8] = b"$apr1$";
+
+    let mut alt = Md5::new();
+    alt.update(pw);
+    alt.update(salt);
+    alt.update(pw);
+    let alt = alt.finalize();
+
+    let mut ctx = Md5::new();
+    ctx.update(pw);
+    ctx.update(MAGIC);
+    ctx.update(salt);
+    for chunk in pw.chunks(16) {
+        ctx.update(&alt[..chunk.len()]);
+    }
+    let mut i = pw.len();
+    while i > 0 {
+        if i & 1 != 0 {
+            ctx.update([0u8]);
+        } else {
+            ctx.update(&pw[..1]);
+        }
+        i >>= 1;
+    }
+    let mut fin = ctx.finalize();
+
+    for i in 0..1000 {
+        let mut ctx = Md5::new();
+        if i & 1 != 0 {
+            ctx.update(pw);
+        } else {
+            ctx.update(fin);
+        }
+        if i % 3 != 0 {
+            ctx.update(salt);
+        }
+        if i % 7 != 0 {
+            ctx.update(pw);
+        }
+        if i & 1 != 0 {
+            ctx.update(fin);
+        } else {
+            ctx.update(pw);
+        }
+        fin = ctx.finalize();
+    }
+
+    let mut out = String::with_capacity(37);
+    out.push_str("$apr1$");
+    out.push_str(&String::from_utf8_lossy(salt));
+    out.push('$');
+    for &(a, b, c) in &[(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        let v = (fin[a] as u32) << 16 | (fin[b] as u32) << 8 | fin[c] as u32;
+        to64(&mut out, v, 4);
+    }
+    to64(&mut out, fin[11] as u32, 2);
+    out
+}
+
+/// Verify a password against a hash from a htpasswd file.
+pub fn verify(pass: &str, hash: &str) -> bool {
+    if let Some(rest) = hash.strip_prefix("$apr1$") {
+        let salt = rest.split('$').next().unwrap_or("");
+        let salt = &salt.as_bytes()[..std::cmp::min(salt.len(), 8)];
+        return consteq(apr1_crypt(pass.as_bytes(), salt).as_bytes(), hash.as_bytes());
+    }
+    if let Some(b64) = hash.strip_prefix("{SHA}") {
+        let digest = Sha1::digest(pass.as_bytes());
+        return consteq(base64::encode(digest).as_bytes(), b64.as_bytes());
+    }
+    if let Some(ok) = crate::passhash::verify(pass, hash) {
+        return ok;
+    }
+    pwhash::unix::verify(pass, hash)
+}
+
+/// Replace the hash of a user in a htpasswd file. The new file is written
+/// next to it, with the same owner and mode, and renamed over it.
+pub fn update(file: &str, user: &str, hash: &str) -> io::Result<()> {
+    let _guard = UPDATE.lock().unwrap();
+    let meta = std::fs::metadata(file)?;
+    let data = std::fs::read_to_string(file)?;
+    let mut found = false;
+    let lines: Vec<String> = data
+        .split('\n')
+        .map(|line| {
+            let mut fields = line.trim().splitn(3, ':');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(u), Some(_), rest) if u == user && !found => {
+                    found = true;
+                    let rest = rest.map(|r| format!(":{}", r)).unwrap_or_default();
+                    format!("{}:{}{}", user, hash, rest)
+                },
+                _ => line.to_string(),
+            }
+        })
+        .collect();
+    if !found {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: no such user", user)));
+    }
+
+    let tmp = format!("{}.{}.tmp", file, std::process::id());
+    let res = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(meta.mode() & 0o7777)
+        .open(&tmp)
+        .and_then(|mut f| {
+            let _ = std::os::unix::fs::fchown(&f, Some(meta.uid()), Some(meta.gid()));
+            f.write_all(lines.join("\n").as_bytes())?;
+            f.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmp, file));
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{update, verify};
+
+    #[test]
+    fn test_apr1() {
+        let hash = "$apr1$abcdefgh$h9FWgUz3n9YxylKLlR5SQ/";
+        assert!(verify("secret", hash));
+        assert!(!verify("Secret", hash));
+    }
+
+    #[test]
+    fn test_sha() {
+        let hash = "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=";
+        assert!(verify("secret", hash));
+        assert!(!verify("secret2", hash));
+    }
+
+    #[test]
+    fn test_update() {
+        let file = std::env::temp_dir().join(format!("htpasswd-test-{}", std::process::id()));
+        let file = file.to_str().unwrap();
+        std::fs::write(file, "# users\nalice:old:Alice\nbob:old\n").unwrap();
+        update(file, "bob", "new").unwrap();
+        update(file, "alice", "new").unwrap();
+        assert!(update(file, "carol", "new").is_err());
+        assert_eq!(std::fs::read_to_string(file).unwrap(), "# users\nalice:new:Alice\nbob:new\n");
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_crypt() {
+        let hash = "$2y$05$bvIG6Nmid91Mu9RcmmWZfO5HJIMCT8riNW0hEp8f6/FuA2/mHZFpe";
+        assert!(verify("password", hash));
+        assert!(!verify("wrong", hash));
+    }
+}
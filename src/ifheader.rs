@@ -0,0 +1,262 @@
+//
+// The If: header (RFC 4918, section 10.4).
+//
+// The handler does not read a tagged list with a path instead of an
+// absolute URL ("</files/a.doc> (<opaquelocktoken:...>)", which Office
+// sends), loses the tag for the lists after the first one, and counts a
+// lock token as a match when the resource is not locked at all. So the
+// header is evaluated here: the lists are ORed, the conditions in a list
+// (lock tokens and entity tags, with Not) ANDed, each against the
+// resource of its tag. If none of the lists holds, the request fails with
+// "412 Precondition Failed".
+//
+// If it holds, the handler gets an If: header that it sees as true, with
+// the same lock tokens in it, so that they still count as submitted.
+//
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::DavFileSystem;
+use webdav_handler::ls::DavLockSystem;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Token(String),
+    ETag(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub not:  bool,
+    pub item: Item,
+}
+
+/// A list of conditions, for the resource in the tag (or the request).
+#[derive(Debug, Clone, PartialEq)]
+pub struct List {
+    pub resource:   Option<String>,
+    pub conditions: Vec<Condition>,
+}
+
+// ( [Not] <token> [Not] [etag] ... ), after the "(".
+fn parse_list(s: &str) -> Option<(Vec<Condition>, &str)> {
+    let mut conditions = Vec::new();
+    let mut not = false;
+    let mut rest = s;
+    loop {
+        rest = rest.trim_start();
+        if let Some(r) = rest.strip_prefix(')') {
+            if conditions.is_empty() || not {
+                return None;
+            }
+            return Some((conditions, r));
+        }
+        if rest.get(..3).map(|w| w.eq_ignore_ascii_case("not")).unwrap_or(false) {
+            if not {
+                return None;
+            }
+            not = true;
+            rest = &rest[3..];
+            continue;
+        }
+        let (item, r) = match rest.as_bytes().first() {
+            Some(b'<') => {
+                let end = rest.find('>')?;
+                (Item::Token(rest[1..end].trim().to_string()), &rest[end + 1..])
+            },
+            Some(b'[') => {
+                let end = rest.find(']')?;
+                (Item::ETag(rest[1..end].trim().to_string()), &rest[end + 1..])
+            },
+            _ => return None,
+        };
+        conditions.push(Condition { not, item });
+        not = false;
+        rest = r;
+    }
+}
+
+/// Parse an If: header. The lists after a tag are all for that resource.
+pub fn parse(hdr: &str) -> Option<Vec<List>> {
+    let mut lists = Vec::new();
+    let mut resource = None;
+    let mut rest = hdr.trim_start();
+    while let Some(&c) = rest.as_bytes().first() {
+        match c {
+            b'<' => {
+                // all lists are tagged, or none are.
+                if !lists.is_empty() && resource.is_none() {
+                    return None;
+                }
+                let end = rest.find('>')?;
+                resource = Some(rest[1..end].trim().to_string());
+                rest = rest[end + 1..].trim_start();
+                if !rest.starts_with('(') {
+                    return None;
+                }
+            },
+            b'(' => {
+                let (conditions, r) = parse_list(&rest[1..])?;
+                lists.push(List {
+                    resource: resource.clone(),
+                    conditions,
+                });
+                rest = r.trim_start();
+            },
+            _ => return None,
+        }
+    }
+    if lists.is_empty() {
+        return None;
+    }
+    Some(lists)
+}
+
+/// The lists in the If: header(s) of a request. None if there is none,
+/// Some(None) if it is not valid.
+pub fn lists(headers: &http::HeaderMap) -> Option<Option<Vec<List>>> {
+    let values: Option<Vec<_>> = headers.get_all("If").into_iter().map(|v| v.to_str().ok()).collect();
+    match values {
+        Some(ref v) if v.is_empty() => None,
+        Some(v) => Some(parse(&v.join(" "))),
+        None => Some(None),
+    }
+}
+
+// The path of a resource tag, an absolute URL or a path, in the location.
+fn resolve(url: &str, prefix: &str) -> Option<DavPath> {
+    let uri: http::Uri = url.parse().ok()?;
+    let mut path = DavPath::new(uri.path()).ok()?;
+    path.set_prefix(prefix).ok()?;
+    Some(path)
+}
+
+// Entity tags are compared without the W/, as compress.rs makes them weak.
+fn same_etag(etag: &str, tag: &str) -> bool {
+    tag.trim_start_matches("W/") == format!("\"{}\"", etag)
+}
+
+/// Does the If: header hold for a request for "path". Without a lock
+/// database ("ls" is None) the locks are fake, and every lock token
+/// matches.
+pub async fn evaluate(
+    lists: &[List],
+    path: &DavPath,
+    prefix: &str,
+    fs: &dyn DavFileSystem,
+    ls: Option<&dyn DavLockSystem>,
+) -> bool
+{
+    for list in lists {
+        let resource = match list.resource {
+            Some(ref url) => resolve(url, prefix),
+            None => Some(path.clone()),
+        };
+        let mut holds = true;
+        for cond in &list.conditions {
+            let matches = match (resource.as_ref(), &cond.item) {
+                (None, _) => false,
+                (Some(_), Item::Token(token)) if token.starts_with("DAV:") => false,
+                (Some(p), Item::Token(token)) => {
+                    match ls {
+                        Some(ls) => ls.discover(p).iter().any(|l| &l.token == token),
+                        None => true,
+                    }
+                },
+                (Some(p), Item::ETag(tag)) => {
+                    match fs.metadata(p).await {
+                        Ok(meta) => meta.etag().map(|e| same_etag(&e, tag)).unwrap_or(false),
+                        Err(_) => false,
+                    }
+                },
+            };
+            if matches == cond.not {
+                holds = false;
+                break;
+            }
+        }
+        if holds {
+            return true;
+        }
+    }
+    false
+}
+
+/// The If: header for the handler: one list that is always true, and a
+/// list for every lock token.
+pub fn rewrite(lists: &[List]) -> String {
+    let mut hdr = "(Not [\"webdav-server-rs:if\"])".to_string();
+    let mut tokens: Vec<&str> = Vec::new();
+    for cond in lists.iter().flat_map(|l| l.conditions.iter()) {
+        match cond.item {
+            Item::Token(ref t) if !t.starts_with("DAV:") && !tokens.contains(&t.as_str()) => {
+                tokens.push(t);
+                hdr.push_str(&format!(" (<{}>)", t));
+            },
+            _ => {},
+        }
+    }
+    hdr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use webdav_handler::{memfs::MemFs, memls::MemLs};
+
+    fn token(not: bool, t: &str) -> Condition {
+        Condition {
+            not,
+            item: Item::Token(t.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ifheader() {
+        let lists = parse("</a/b> (<urn:uuid:1> [\"x\"]) (Not <DAV:no-lock>)").unwrap();
+        assert_eq!(lists.len(), 2);
+        assert_eq!(lists[1].resource.as_deref(), Some("/a/b"));
+        assert_eq!(lists[1].conditions, vec![token(true, "DAV:no-lock")]);
+        assert_eq!(lists[0].conditions[1].item, Item::ETag("\"x\"".to_string()));
+        let lists = parse("(<urn:uuid:1>)(NOT<urn:uuid:2>)").unwrap();
+        assert_eq!(lists[1].conditions, vec![token(true, "urn:uuid:2")]);
+        assert!(lists.iter().all(|l| l.resource.is_none()));
+        for bad in &["", "()", "(Not)", "<http://h/a>", "(<urn:uuid:1>) </a> (<urn:uuid:2>)", "(<x>", "x"] {
+            assert!(parse(bad).is_none(), "{}", bad);
+        }
+
+        let mut headers = http::HeaderMap::new();
+        assert!(super::lists(&headers).is_none());
+        headers.append("If", "(<urn:uuid:1>)".parse().unwrap());
+        headers.append("If", "(<urn:uuid:2>)".parse().unwrap());
+        assert_eq!(super::lists(&headers).unwrap().unwrap().len(), 2);
+
+        let fs = MemFs::new();
+        let ls = MemLs::new();
+        let path = |p: &str| DavPath::new(p).unwrap();
+        fs.create_dir(&path("/d/")).await.unwrap();
+        let lock = ls.lock(&path("/d/"), None, None, Some(Duration::from_secs(60)), false, true).unwrap();
+        let etag = fs.metadata(&path("/d/")).await.unwrap().etag().unwrap();
+        let eval = |hdr: String, ls: Option<&'static MemLs>| {
+            let fs = fs.clone();
+            async move {
+                let lists = parse(&hdr).unwrap();
+                let ls = ls.map(|ls| ls as &dyn DavLockSystem);
+                evaluate(&lists, &path("/d/"), "", &*fs, ls).await
+            }
+        };
+        let ls: &'static MemLs = Box::leak(ls);
+        let tok = &lock.token;
+        assert!(eval(format!("(<{}>)", tok), Some(ls)).await);
+        assert!(!eval("(<urn:uuid:0>)".into(), Some(ls)).await);
+        assert!(eval("(<urn:uuid:0>)".into(), None).await);
+        assert!(eval(format!("(<urn:uuid:0>) (<{}> [\"{}\"])", tok, etag), Some(ls)).await);
+        assert!(!eval(format!("(<{}> [\"{}x\"])", tok, etag), Some(ls)).await);
+        assert!(eval(format!("</d/x> (<{}>) (Not [\"{}\"])", tok, etag), Some(ls)).await);
+        assert!(!eval(format!("</e/> (<{}>)", tok), Some(ls)).await);
+        assert!(eval("(Not <DAV:no-lock>)".into(), Some(ls)).await);
+        assert!(eval(format!("(Not <urn:uuid:0> [W/\"{}\"])", etag), Some(ls)).await);
+
+        let lists = parse(&format!("</d/> (<{}>) (Not <DAV:no-lock> <{}>)", tok, tok)).unwrap();
+        assert_eq!(rewrite(&lists), format!("(Not [\"webdav-server-rs:if\"]) (<{}>)", tok));
+    }
+}
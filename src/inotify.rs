@@ -0,0 +1,284 @@
+//
+// Watch directories with inotify, for changes made outside of the server.
+//
+// The total of a quota = "<size>" location is kept in memory, and only
+// rescanned once an hour. With watch = true every directory below it gets
+// an inotify watch, and a change (by rsync, samba, a shell) marks the
+// total as stale. Stale totals are rescanned in the background after a
+// few seconds, so that quota-used-bytes in a PROPFIND and the quota
+// itself follow what is on disk.
+//
+// The sync-db journal and the fulltext-index only see the changes made
+// through the server. With watch = true they add a hook (see watch()),
+// and get the paths that changed, after the same few seconds. Changes made
+// through the server are then seen twice; that does no harm. If the kernel
+// drops events (the queue overflows) the hooks are told that anything may
+// have changed.
+//
+// The rest of a PROPFIND needs no invalidation: the content ETags and the
+// checksums are kept with the mtime and size they are for and checked
+// against them, and names (case-insensitive too) are looked up on disk
+// every time.
+//
+// A watch is added for new directories as they appear. If the kernel
+// runs out of watches (fs.inotify.max_user_watches) the rest of the tree
+// is not watched, and the hourly rescan still picks up the changes.
+//
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Changes within this time are rescanned together.
+const SETTLE: Duration = Duration::from_secs(2);
+
+const MASK: u32 = libc::IN_CREATE |
+    libc::IN_DELETE |
+    libc::IN_MODIFY |
+    libc::IN_CLOSE_WRITE |
+    libc::IN_MOVED_FROM |
+    libc::IN_MOVED_TO |
+    libc::IN_DELETE_SELF |
+    libc::IN_ONLYDIR;
+
+struct Watcher {
+    fd:      libc::c_int,
+    // watch descriptor -> (root, directory).
+    watches: HashMap<libc::c_int, (PathBuf, PathBuf)>,
+}
+
+/// A change below a root.
+pub enum Change<'a> {
+    /// The path (relative to the root, with a leading "/") and whether it
+    /// was removed.
+    Path(&'a [u8], bool),
+    /// Events were lost, anything may have changed.
+    Lost,
+}
+
+pub type Hook = Box<dyn Fn(Change) + Send + Sync>;
+
+// Changed paths, as (path, removed).
+type Changes = Vec<(Vec<u8>, bool)>;
+
+lazy_static::lazy_static! {
+    static ref WATCHER: Mutex<Option<Watcher>> = Mutex::new(None);
+    static ref STALE: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+    // root -> name -> hook.
+    static ref HOOKS: Mutex<HashMap<PathBuf, HashMap<&'static str, Hook>>> = Mutex::new(HashMap::new());
+    // root -> the changes since the hooks were last called, in order.
+    static ref CHANGES: Mutex<HashMap<PathBuf, Changes>> = Mutex::new(HashMap::new());
+    static ref LOST: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+static FULL: AtomicBool = AtomicBool::new(false);
+
+// Start the inotify thread, if it is not running yet.
+fn init(watcher: &mut Option<Watcher>) -> io::Result<&mut Watcher> {
+    if watcher.is_none() {
+        // SAFETY: no pointers are passed.
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        std::thread::Builder::new()
+            .name("inotify".to_string())
+            .spawn(move || read_events(fd))?;
+        *watcher = Some(Watcher {
+            fd,
+            watches: HashMap::new(),
+        });
+    }
+    Ok(watcher.as_mut().unwrap())
+}
+
+impl Watcher {
+    // Watch one directory of the tree at "root".
+    fn add(&mut self, root: &Path, dir: &Path) {
+        if FULL.load(Ordering::Relaxed) {
+            return;
+        }
+        let cpath = match CString::new(dir.as_os_str().as_bytes()) {
+            Ok(p) => p,
+            Err(_) => return,
+        };
+        // SAFETY: cpath is a valid C string.
+        let wd = unsafe { libc::inotify_add_watch(self.fd, cpath.as_ptr(), MASK) };
+        if wd < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENOSPC) {
+                warn!("inotify: {:?}: out of inotify watches, not watching the rest", dir);
+                FULL.store(true, Ordering::Relaxed);
+            } else {
+                debug!("inotify: {:?}: {}", dir, e);
+            }
+            return;
+        }
+        self.watches.insert(wd, (root.to_path_buf(), dir.to_path_buf()));
+    }
+}
+
+/// Watch directory "dir", part of the tree at "root". Called for every
+/// directory while the tree is scanned.
+pub fn add(root: &Path, dir: &Path) {
+    let mut watcher = WATCHER.lock().unwrap();
+    match init(&mut watcher) {
+        Ok(watcher) => watcher.add(root, dir),
+        Err(e) => warn!("inotify: inotify: {}", e),
+    }
+}
+
+/// Call "hook" with the changes below "root". The first hook of a root
+/// watches its tree; a hook with the same name is only added once. Reads
+/// the whole tree, so it blocks.
+pub fn watch(root: &Path, name: &'static str, hook: Hook) {
+    let mut hooks = HOOKS.lock().unwrap();
+    let watched = hooks.contains_key(root);
+    let root_hooks = hooks.entry(root.to_path_buf()).or_default();
+    if root_hooks.contains_key(name) {
+        return;
+    }
+    root_hooks.insert(name, hook);
+    drop(hooks);
+    if !watched {
+        add_tree(root, root.to_path_buf());
+    }
+}
+
+// Watch a new directory, and everything below it. Returns everything
+// that is in it.
+fn add_tree(root: &Path, dir: PathBuf) -> Vec<PathBuf> {
+    let mut dirs = vec![dir];
+    let mut found = Vec::new();
+    while let Some(dir) = dirs.pop() {
+        add(root, &dir);
+        if let Ok(entries) = std::fs::read_dir(&dir) {
+            for entry in entries.flatten() {
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    dirs.push(entry.path());
+                }
+                found.push(entry.path());
+            }
+        }
+    }
+    found
+}
+
+// Remember a change, if a hook wants it.
+fn changed(root: &Path, path: &Path, deleted: bool) {
+    if !HOOKS.lock().unwrap().contains_key(root) {
+        return;
+    }
+    let rel = match path.strip_prefix(root) {
+        Ok(rel) if !rel.as_os_str().is_empty() => rel,
+        _ => return,
+    };
+    let mut key = b"/".to_vec();
+    key.extend_from_slice(rel.as_os_str().as_bytes());
+    let mut changes = CHANGES.lock().unwrap();
+    let changes = changes.entry(root.to_path_buf()).or_default();
+    if changes.last() != Some(&(key.clone(), deleted)) {
+        changes.push((key, deleted));
+    }
+}
+
+fn read_events(fd: libc::c_int) {
+    let header = std::mem::size_of::<libc::inotify_event>();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        // SAFETY: buf is valid for buf.len() bytes.
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            error!("inotify: inotify read: {}", e);
+            return;
+        }
+        let mut off = 0;
+        while off + header <= n as usize {
+            // SAFETY: the kernel wrote a whole event at this offset.
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(buf[off..].as_ptr() as *const libc::inotify_event) };
+            let name = &buf[off + header..off + header + event.len as usize];
+            let name = &name[..name.iter().position(|&c| c == 0).unwrap_or(name.len())];
+            off += header + event.len as usize;
+            handle_event(event.wd, event.mask, name);
+        }
+    }
+}
+
+fn handle_event(wd: libc::c_int, mask: u32, name: &[u8]) {
+    if mask & libc::IN_Q_OVERFLOW != 0 {
+        debug!("inotify: event queue overflow, all totals are stale");
+        let watcher = WATCHER.lock().unwrap();
+        let roots = watcher.iter().flat_map(|w| w.watches.values().map(|(r, _)| r.clone()));
+        STALE.lock().unwrap().extend(roots);
+        LOST.lock().unwrap().extend(HOOKS.lock().unwrap().keys().cloned());
+        return;
+    }
+    let (root, dir) = {
+        let mut watcher = WATCHER.lock().unwrap();
+        let watches = match watcher.as_mut() {
+            Some(w) => &mut w.watches,
+            None => return,
+        };
+        if mask & libc::IN_IGNORED != 0 {
+            watches.remove(&wd);
+            return;
+        }
+        match watches.get(&wd) {
+            Some(w) => w.clone(),
+            None => return,
+        }
+    };
+    // the directory itself is reported by its parent.
+    if mask & libc::IN_DELETE_SELF == 0 {
+        let path = dir.join(std::ffi::OsString::from_vec(name.to_vec()));
+        changed(&root, &path, mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0);
+        if mask & libc::IN_ISDIR != 0 && mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+            for path in add_tree(&root, path) {
+                changed(&root, &path, false);
+            }
+        }
+    }
+    STALE.lock().unwrap().insert(root);
+}
+
+// Call the hooks with the changes.
+fn call_hooks() {
+    let lost = std::mem::take(&mut *LOST.lock().unwrap());
+    let changes = std::mem::take(&mut *CHANGES.lock().unwrap());
+    let hooks = HOOKS.lock().unwrap();
+    for root in &lost {
+        for hook in hooks.get(root).iter().flat_map(|h| h.values()) {
+            hook(Change::Lost);
+        }
+    }
+    for (root, changes) in changes.iter().filter(|(root, _)| !lost.contains(*root)) {
+        for hook in hooks.get(root).iter().flat_map(|h| h.values()) {
+            for (path, deleted) in changes {
+                hook(Change::Path(path, *deleted));
+            }
+        }
+    }
+}
+
+/// Rescan the totals that are stale, and call the hooks. Runs for as long
+/// as the server runs.
+pub async fn run() {
+    loop {
+        tokio::time::sleep(SETTLE).await;
+        let stale = std::mem::take(&mut *STALE.lock().unwrap());
+        for root in stale {
+            debug!("inotify: {:?} changed", root);
+            crate::softquota::rescan(&root);
+        }
+        tokio::task::block_in_place(call_hooks);
+    }
+}
@@ -0,0 +1,204 @@
+//
+// The janitor: maintenance in the background, with a [maintenance]
+// section. Every task has its own interval in seconds, 0 is off:
+//
+// - locks:    expired locks are removed from the lock databases
+// - trash:    trash older than trash-retention is removed
+// - versions: versions over versions-max or versions-days are removed
+// - compact:  the lock and property databases are vacuumed
+// - uploads:  what is left of uploads that never finished, older than
+//             uploads-max-age: atomic-put files, tus and oc-chunking
+//
+// The janitor works as the uid of the server, on the locations with a
+// directory on local disk that is the same for everyone: not those with
+// "~", "$user" or "${pam:...}" in it, or with setuid. Their trash and
+// versions are still pruned when they are written to. The config is read
+// again on every round, so a reload changes the intervals too.
+//
+#[cfg(feature = "sqlite")]
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use webdav_handler::davpath::DavPath;
+
+use crate::config::{Config, Handler, Location, Maintenance, Symlinks};
+use crate::server::Server;
+use crate::userfs::UserFs;
+use crate::versionfs::VersionFs;
+use crate::{atomicput, ocupload, trashfs, tus};
+
+const ROUND: Duration = Duration::from_secs(60);
+const UPLOADS_MAX_AGE: u64 = 86400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Task {
+    Locks,
+    Trash,
+    Versions,
+    Compact,
+    Uploads,
+}
+
+const TASKS: [Task; 5] = [Task::Locks, Task::Trash, Task::Versions, Task::Compact, Task::Uploads];
+
+impl Task {
+    // The interval, or None if the task is off.
+    fn interval(self, m: &Maintenance) -> Option<Duration> {
+        let (secs, default) = match self {
+            Task::Locks => (m.locks_interval, 300),
+            Task::Trash => (m.trash_interval, 3600),
+            Task::Versions => (m.versions_interval, 86400),
+            Task::Compact => (m.compact_interval, 7 * 86400),
+            Task::Uploads => (m.uploads_interval, 3600),
+        };
+        match secs.unwrap_or(default) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+}
+
+/// Do the tasks of [maintenance], if there is one, when they are due.
+/// Runs for as long as the server runs.
+pub async fn run(server: Server) {
+    let start = Instant::now();
+    let mut last = HashMap::new();
+    loop {
+        tokio::time::sleep(ROUND).await;
+        let (config, _) = server.live();
+        let maintenance = match config.maintenance {
+            Some(ref m) => m,
+            None => continue,
+        };
+        for task in TASKS {
+            let due = match task.interval(maintenance) {
+                Some(interval) => last.get(&task).unwrap_or(&start).elapsed() >= interval,
+                None => false,
+            };
+            if due {
+                debug!("maintenance: {:?}", task);
+                do_task(task, &config, maintenance).await;
+                last.insert(task, Instant::now());
+            }
+        }
+    }
+}
+
+// The files of a location, if the janitor can get at them.
+fn local_fs(location: &Location) -> Option<Box<UserFs>> {
+    let dir = location.directory.as_str();
+    let local = matches!(location.handler, Handler::Filesystem | Handler::Caldav | Handler::Carddav);
+    let per_user = dir.starts_with('~') || dir.contains("$user") || dir.contains("${pam:");
+    if !local || location.setuid || per_user {
+        return None;
+    }
+    let mut fs = UserFs::new(dir, None, true, false, false);
+    fs.set_symlinks(location.symlinks.unwrap_or(Symlinks::Follow));
+    fs.set_confine(location.confine);
+    Some(fs)
+}
+
+// The database files of the locations, each once.
+#[cfg(feature = "sqlite")]
+fn databases<'a>(config: &'a Config, db: fn(&'a Location) -> Option<&'a String>) -> BTreeSet<&'a str> {
+    config.locations().filter_map(|(_, l)| db(l)).map(String::as_str).collect()
+}
+
+async fn do_task(task: Task, config: &Config, maintenance: &Maintenance) {
+    match task {
+        Task::Locks => {
+            #[cfg(feature = "sqlite")]
+            for path in databases(config, |l| l.lock_db.as_ref()) {
+                match crate::lockdb::open(path) {
+                    Ok(db) => {
+                        let expired = tokio::task::block_in_place(|| db.expire());
+                        if expired > 0 {
+                            info!("maintenance: {}: {} expired locks removed", path, expired);
+                        }
+                    },
+                    Err(e) => warn!("maintenance: {}: {}", path, e),
+                }
+            }
+        },
+        Task::Compact => {
+            #[cfg(feature = "sqlite")]
+            {
+                let compact = |path: &str, res: std::io::Result<()>| {
+                    match res {
+                        Ok(()) => info!("maintenance: {}: compacted", path),
+                        Err(e) => warn!("maintenance: {}: compact: {}", path, e),
+                    }
+                };
+                use tokio::task::block_in_place;
+                for path in databases(config, |l| l.lock_db.as_ref()) {
+                    compact(path, crate::lockdb::open(path).and_then(|db| block_in_place(|| db.compact())));
+                }
+                for path in databases(config, |l| l.dead_props.as_ref()) {
+                    compact(path, crate::deadprops::store(path).and_then(|s| block_in_place(|| s.compact())));
+                }
+            }
+        },
+        Task::Trash | Task::Versions | Task::Uploads => {
+            for (section, location) in config.locations() {
+                if let Some(fs) = local_fs(location) {
+                    do_location(task, &section, location, fs, maintenance).await;
+                }
+            }
+        },
+    }
+}
+
+async fn do_location(task: Task, section: &str, location: &Location, fs: Box<UserFs>, m: &Maintenance) {
+    match task {
+        Task::Trash => {
+            let trash = location.trash_dir.as_deref();
+            if let (Some(name), Some(retention)) = (trash, trashfs::retention(location.trash_retention)) {
+                let removed = trashfs::remove_old(&*fs, name, retention).await;
+                if removed > 0 {
+                    info!("maintenance: {}: {} removed from the trash", section, removed);
+                }
+            }
+        },
+        Task::Versions => {
+            if let Some(ref name) = location.versions_dir {
+                let (max, days) = (location.versions_max, location.versions_days);
+                let vfs = VersionFs::new(fs, name, false, false, max, days);
+                if let Err(e) = vfs.prune_all().await {
+                    warn!("maintenance: {}: versions: {:?}", section, e);
+                }
+            }
+        },
+        Task::Uploads => {
+            let max_age = Duration::from_secs(m.uploads_max_age.unwrap_or(UPLOADS_MAX_AGE));
+            let cutoff = SystemTime::now() - max_age;
+            let mut removed = 0;
+            if location.atomic_put {
+                removed += atomicput::remove_stale(&*fs, DavPath::new("/").unwrap(), cutoff).await;
+            }
+            if location.tus {
+                removed += tus::expire(&*fs, cutoff).await;
+            }
+            if location.oc_chunking {
+                removed += ocupload::expire(&*fs, cutoff).await;
+            }
+            if removed > 0 {
+                info!("maintenance: {}: {} unfinished uploads removed", section, removed);
+            }
+        },
+        Task::Locks | Task::Compact => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval() {
+        let m = Maintenance { trash_interval: Some(0), compact_interval: Some(60), ..Default::default() };
+        assert_eq!(Task::Locks.interval(&m), Some(Duration::from_secs(300)));
+        assert_eq!(Task::Trash.interval(&m), None);
+        assert_eq!(Task::Compact.interval(&m), Some(Duration::from_secs(60)));
+    }
+}
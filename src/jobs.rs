@@ -0,0 +1,283 @@
+//
+// COPY and MOVE in the background, for trees that take longer than a
+// client or a reverse proxy waits. With a [jobs] section, a COPY or MOVE
+// with "Prefer: respond-async" (RFC 7240) is answered right away with
+// "202 Accepted", and a Location: under the prefix where a GET shows how
+// far it is:
+//
+//   {"id": "...", "method": "COPY", "source": "/a", "destination": "/b",
+//    "state": "running", "resources": 1234, "bytes": 987654, "started": ...}
+//
+// When it is done, "state" is "done", "status" is the status the request
+// would have had, and "body" the multistatus of a 207. Resources and
+// bytes are counted for copies to another location; a copy in one
+// location is done by the filesystem. A DELETE cancels a running job.
+//
+// The URL has a random id, and that is all that is needed to see or
+// cancel the job. Finished jobs are kept for "keep" seconds.
+//
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http::StatusCode;
+use ring::rand::{SecureRandom, SystemRandom};
+use webdav_handler::DavMethod;
+
+use crate::config;
+
+const PREFIX: &str = "/.jobs/";
+const KEEP: u64 = 3600;
+const MAX_RUNNING: usize = 4;
+
+lazy_static::lazy_static! {
+    static ref JOBS: Mutex<HashMap<String, Entry>> = Mutex::new(HashMap::new());
+}
+
+/// How far a copy is.
+#[derive(Debug, Default)]
+pub struct Progress {
+    resources: AtomicU64,
+    bytes:     AtomicU64,
+}
+
+impl Progress {
+    /// One more resource copied, of "bytes" bytes.
+    pub fn add(&self, bytes: u64) {
+        self.resources.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+struct Entry {
+    user:        Option<String>,
+    method:      &'static str,
+    source:      String,
+    destination: String,
+    started:     SystemTime,
+    progress:    Arc<Progress>,
+    finished:    Option<(SystemTime, StatusCode, String)>,
+    task:        Option<tokio::task::AbortHandle>,
+}
+
+/// A COPY or MOVE that is to be done in the background.
+pub struct Job {
+    entry: Entry,
+    cfg:   config::Jobs,
+}
+
+fn unix_time(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// The prefix of the status URLs.
+pub fn prefix(cfg: &Option<config::Jobs>) -> Option<&str> {
+    Some(cfg.as_ref()?.prefix.as_deref().unwrap_or(PREFIX))
+}
+
+// Prefer: respond-async.
+fn respond_async(headers: &http::HeaderMap) -> bool {
+    let values = headers.get_all("Prefer").into_iter().filter_map(|v| v.to_str().ok());
+    let mut prefs = values.flat_map(|v| v.split(',')).map(|p| p.split(';').next().unwrap_or("").trim());
+    prefs.any(|p| p.eq_ignore_ascii_case("respond-async"))
+}
+
+/// A job, if there is a [jobs] section and the client asked for one.
+pub fn job(
+    cfg: &config::Config,
+    method: DavMethod,
+    headers: &http::HeaderMap,
+    user: Option<&str>,
+    source: &[u8],
+) -> Option<Job>
+{
+    let method = match method {
+        DavMethod::Copy => "COPY",
+        DavMethod::Move => "MOVE",
+        _ => return None,
+    };
+    let cfg = cfg.jobs.as_ref().filter(|_| respond_async(headers))?;
+    let destination = headers.get("destination").and_then(|d| d.to_str().ok()).unwrap_or("");
+    let entry = Entry {
+        user: user.map(str::to_string),
+        method,
+        source: String::from_utf8_lossy(source).into_owned(),
+        destination: destination.to_string(),
+        started: SystemTime::now(),
+        progress: Arc::new(Progress::default()),
+        finished: None,
+        task: None,
+    };
+    Some(Job { entry, cfg: cfg.clone() })
+}
+
+// Forget the jobs that finished more than "keep" seconds ago.
+fn expire(jobs: &mut HashMap<String, Entry>, keep: u64) {
+    let cutoff = SystemTime::now() - Duration::from_secs(keep);
+    jobs.retain(|_, e| e.finished.as_ref().map(|f| f.0 > cutoff).unwrap_or(true));
+}
+
+impl Job {
+    /// The progress, for the copy to count.
+    pub fn progress(&self) -> Arc<Progress> {
+        self.entry.progress.clone()
+    }
+
+    /// Run the request in the background, and answer 202 with the URL of
+    /// the job. 429 if the user already has max-running jobs.
+    pub fn start<F>(self, run: F) -> Result<http::Response<String>, StatusCode>
+    where F: Future<Output = http::Response<String>> + Send + 'static {
+        let mut jobs = JOBS.lock().unwrap();
+        expire(&mut jobs, self.cfg.keep.unwrap_or(KEEP));
+        let user = self.entry.user.clone();
+        let running = jobs.values().filter(|e| e.finished.is_none() && e.user == user).count();
+        if running >= self.cfg.max_running.unwrap_or(MAX_RUNNING) {
+            debug!("jobs: {:?}: already {} running", user, running);
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        let mut id = [0u8; 16];
+        SystemRandom::new()
+            .fill(&mut id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+        info!("jobs: {}: {} {} -> {}", id, self.entry.method, self.entry.source, self.entry.destination);
+        let job_id = id.clone();
+        let task = tokio::spawn(async move {
+            let (parts, body) = run.await.into_parts();
+            info!("jobs: {}: done, {}", job_id, parts.status);
+            if let Some(entry) = JOBS.lock().unwrap().get_mut(&job_id) {
+                entry.finished = Some((SystemTime::now(), parts.status, body));
+                entry.task = None;
+            }
+        });
+        let mut entry = self.entry;
+        entry.task = Some(task.abort_handle());
+        jobs.insert(id.clone(), entry);
+
+        let prefix = self.cfg.prefix.as_deref().unwrap_or(PREFIX);
+        let resp = http::Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .header("Location", format!("{}{}", prefix, id))
+            .header("Preference-Applied", "respond-async")
+            .header("Content-Type", "application/json")
+            .body(format!("{{\"id\":\"{}\"}}\n", id))
+            .unwrap();
+        Ok(resp)
+    }
+}
+
+/// A request for a status URL: GET or HEAD for the state of the job,
+/// DELETE to cancel it.
+pub fn handle(cfg: &config::Jobs, method: &http::Method, path: &str) -> http::Response<String> {
+    let prefix = cfg.prefix.as_deref().unwrap_or(PREFIX);
+    let id = path.strip_prefix(prefix).unwrap_or("");
+    let mut jobs = JOBS.lock().unwrap();
+    expire(&mut jobs, cfg.keep.unwrap_or(KEEP));
+    let entry = match jobs.get(id) {
+        Some(entry) => entry,
+        None => return text(StatusCode::NOT_FOUND),
+    };
+    match *method {
+        http::Method::GET | http::Method::HEAD => {
+            let mut value = serde_json::json!({
+                "id":          id,
+                "method":      entry.method,
+                "source":      entry.source,
+                "destination": entry.destination,
+                "state":       if entry.finished.is_some() { "done" } else { "running" },
+                "resources":   entry.progress.resources.load(Ordering::Relaxed),
+                "bytes":       entry.progress.bytes.load(Ordering::Relaxed),
+                "started":     unix_time(entry.started),
+            });
+            if let Some((finished, status, ref body)) = entry.finished {
+                value["finished"] = unix_time(finished).into();
+                value["status"] = status.as_u16().into();
+                if status == StatusCode::MULTI_STATUS {
+                    value["body"] = body.as_str().into();
+                }
+            }
+            http::Response::builder()
+                .header("Content-Type", "application/json")
+                .header("Cache-Control", "no-store")
+                .body(format!("{}\n", value))
+                .unwrap()
+        },
+        http::Method::DELETE => {
+            if let Some(ref task) = entry.task {
+                task.abort();
+                info!("jobs: {}: cancelled", id);
+            }
+            jobs.remove(id);
+            text(StatusCode::NO_CONTENT)
+        },
+        _ => {
+            let mut resp = text(StatusCode::METHOD_NOT_ALLOWED);
+            resp.headers_mut().insert("Allow", "GET, HEAD, DELETE".parse().unwrap());
+            resp
+        },
+    }
+}
+
+fn text(status: StatusCode) -> http::Response<String> {
+    let body = match status {
+        StatusCode::NO_CONTENT => String::new(),
+        _ => format!("{}\n", status),
+    };
+    http::Response::builder().status(status).body(body).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_jobs() {
+        let toml = "[server]\n[jobs]\nmax-running = 1\n";
+        let config = crate::config::from_value(toml::from_str(toml).unwrap()).unwrap();
+        let mut headers = http::HeaderMap::new();
+        headers.insert("destination", "/b".parse().unwrap());
+        assert!(job(&config, DavMethod::Copy, &headers, Some("alice"), b"/a").is_none());
+        headers.insert("prefer", "wait=10, respond-async".parse().unwrap());
+        assert!(job(&config, DavMethod::Get, &headers, Some("alice"), b"/a").is_none());
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let job1 = job(&config, DavMethod::Copy, &headers, Some("alice"), b"/a").unwrap();
+        let progress = job1.progress();
+        let resp = job1
+            .start(async move {
+                progress.add(100);
+                let _ = rx.await;
+                http::Response::builder().status(StatusCode::CREATED).body(String::new()).unwrap()
+            })
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let url = resp.headers().get("location").unwrap().to_str().unwrap().to_string();
+        assert!(url.starts_with("/.jobs/"));
+
+        // one running job per user.
+        let job2 = job(&config, DavMethod::Move, &headers, Some("alice"), b"/a").unwrap();
+        let ready = async { http::Response::new(String::new()) };
+        assert_eq!(job2.start(ready).unwrap_err(), StatusCode::TOO_MANY_REQUESTS);
+
+        let cfg = config.jobs.as_ref().unwrap();
+        tokio::task::yield_now().await;
+        let status = |url: &str| {
+            let resp = handle(cfg, &http::Method::GET, url);
+            serde_json::from_str::<serde_json::Value>(resp.body()).unwrap()
+        };
+        let value = status(&url);
+        assert_eq!((value["state"].as_str(), value["bytes"].as_u64()), (Some("running"), Some(100)));
+        tx.send(()).unwrap();
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        let value = status(&url);
+        assert_eq!((value["state"].as_str(), value["status"].as_u64()), (Some("done"), Some(201)));
+
+        assert_eq!(handle(cfg, &http::Method::DELETE, &url).status(), StatusCode::NO_CONTENT);
+        assert_eq!(handle(cfg, &http::Method::GET, &url).status(), StatusCode::NOT_FOUND);
+    }
+}
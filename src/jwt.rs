@@ -0,0 +1,172 @@
+//
+// Bearer token (JWT) authentication.
+//
+// Tokens are validated either against a static key from the config
+// (a HMAC secret or a PEM public key) or against the keys published
+// at a JWKS url. The JWKS is fetched when needed and cached.
+//
+use std::collections::HashMap;
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use tokio::sync::Mutex;
+
+use crate::config;
+
+// Do not refetch the JWKS more often than this when we see an unknown key id.
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(60);
+
+struct JwksCache {
+    fetched: Instant,
+    keys:    JwkSet,
+}
+
+#[derive(Clone)]
+pub struct JwtAuth {
+    cfg:        config::Jwt,
+    algorithms: Vec<Algorithm>,
+    key:        Option<Arc<DecodingKey>>,
+    jwks:       Arc<Mutex<Option<JwksCache>>>,
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn auth_error(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, msg.into())
+}
+
+impl JwtAuth {
+    pub fn new(cfg: &config::Jwt) -> io::Result<JwtAuth> {
+        // Static key?
+        let key = if let Some(ref secret) = cfg.key {
+            Some(DecodingKey::from_secret(secret.as_bytes()))
+        } else if let Some(ref file) = cfg.key_file {
+            let pem = std::fs::read(file).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file, e)))?;
+            let key = DecodingKey::from_rsa_pem(&pem)
+                .or_else(|_| DecodingKey::from_ec_pem(&pem))
+                .or_else(|_| DecodingKey::from_ed_pem(&pem))
+                .map_err(|e| invalid(format!("{}: {}", file, e)))?;
+            Some(key)
+        } else {
+            None
+        };
+
+        // Default algorithm depends on the type of key.
+        let algorithms = match cfg.algorithms {
+            Some(ref algs) => {
+                algs.iter()
+                    .map(|a| Algorithm::from_str(a).map_err(|_| invalid(format!("unknown algorithm {}", a))))
+                    .collect::<io::Result<Vec<_>>>()?
+            },
+            None if cfg.key.is_some() => vec![Algorithm::HS256],
+            None => vec![Algorithm::RS256],
+        };
+
+        Ok(JwtAuth {
+            cfg: cfg.clone(),
+            algorithms,
+            key: key.map(Arc::new),
+            jwks: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    // Fetch the JWKS.
+    async fn fetch_jwks(&self, url: &str) -> io::Result<JwkSet> {
+        let uri = url.parse::<hyper::Uri>().map_err(|e| invalid(format!("{}: {}", url, e)))?;
+        let https = hyper_rustls::HttpsConnector::with_native_roots();
+        let client = hyper::Client::builder().build::<_, hyper::Body>(https);
+        let fetch = async {
+            let resp = client.get(uri).await.map_err(io::Error::other)?;
+            if !resp.status().is_success() {
+                return Err(io::Error::other(format!("{}: {}", url, resp.status())));
+            }
+            let body = hyper::body::to_bytes(resp.into_body())
+                .await
+                .map_err(io::Error::other)?;
+            serde_json::from_slice::<JwkSet>(&body).map_err(|e| invalid(format!("{}: {}", url, e)))
+        };
+        match tokio::time::timeout(Duration::from_secs(10), fetch).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("{}: timeout", url))),
+        }
+    }
+
+    // Find the decoding key for the token, fetching the JWKS if we need to.
+    async fn decoding_key(&self, kid: Option<&str>) -> io::Result<Arc<DecodingKey>> {
+        if let Some(ref key) = self.key {
+            return Ok(key.clone());
+        }
+        let url = match self.cfg.jwks_url {
+            Some(ref url) => url.as_str(),
+            None => return Err(invalid("no key or jwks-url configured")),
+        };
+        let refresh = Duration::from_secs(self.cfg.jwks_refresh.unwrap_or(3600));
+
+        let mut jwks = self.jwks.lock().await;
+        for attempt in 0..2 {
+            let age = jwks.as_ref().map(|j| j.fetched.elapsed());
+            let stale = age.map(|a| a > refresh).unwrap_or(true);
+            let may_refetch = attempt > 0 && age.map(|a| a > JWKS_MIN_REFRESH).unwrap_or(true);
+            if stale || may_refetch {
+                let keys = self.fetch_jwks(url).await?;
+                *jwks = Some(JwksCache {
+                    fetched: Instant::now(),
+                    keys,
+                });
+            }
+            let keys = &jwks.as_ref().unwrap().keys;
+            let jwk = match kid {
+                Some(kid) => keys.find(kid),
+                None if keys.keys.len() == 1 => keys.keys.first(),
+                None => None,
+            };
+            if let Some(jwk) = jwk {
+                let key = DecodingKey::from_jwk(jwk).map_err(|e| invalid(e.to_string()))?;
+                return Ok(Arc::new(key));
+            }
+        }
+        Err(auth_error(format!("no key found for kid {:?}", kid)))
+    }
+
+    /// Validate a token, and return its claims.
+    pub async fn claims(&self, token: &str) -> io::Result<HashMap<String, serde_json::Value>> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| auth_error(e.to_string()))?;
+        if !self.algorithms.contains(&header.alg) {
+            return Err(auth_error(format!("algorithm {:?} not allowed", header.alg)));
+        }
+        let key = self.decoding_key(header.kid.as_deref()).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = self.algorithms.clone();
+        if let Some(ref iss) = self.cfg.issuer {
+            validation.set_issuer(&[iss]);
+        }
+        if let Some(ref aud) = self.cfg.audience {
+            validation.set_audience(&[aud]);
+        }
+        let data = jsonwebtoken::decode::<HashMap<String, serde_json::Value>>(token, &key, &validation)
+            .map_err(|e| auth_error(e.to_string()))?;
+        Ok(data.claims)
+    }
+
+    /// Validate a token, and return the username.
+    pub async fn auth(&self, token: &str) -> io::Result<String> {
+        let claims = self.claims(token).await?;
+        self.username(&claims)
+    }
+
+    /// Map the claim to a username.
+    pub fn username(&self, claims: &HashMap<String, serde_json::Value>) -> io::Result<String> {
+        let claim = self.cfg.claim.as_deref().unwrap_or("preferred_username");
+        match claims.get(claim).and_then(|v| v.as_str()) {
+            Some(user) if !user.is_empty() => Ok(user.to_string()),
+            _ => Err(auth_error(format!("token has no {} claim", claim))),
+        }
+    }
+}
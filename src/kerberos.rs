@@ -0,0 +1,64 @@
+//
+// Kerberos (SPNEGO / "Negotiate") authentication.
+//
+// Only single round-trip negotiation is supported, which is what
+// Kerberos does in practice. The final mutual-auth token, if any,
+// is not sent back to the client; clients do not require it.
+//
+use std::io;
+
+use libgssapi::context::{SecurityContext, ServerCtx};
+use libgssapi::credential::{Cred, CredUsage};
+use libgssapi::name::Name;
+use libgssapi::oid::{OidSet, GSS_MECH_KRB5, GSS_MECH_SPNEGO, GSS_NT_KRB5_PRINCIPAL};
+
+use crate::config;
+
+fn gss_error(e: libgssapi::error::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, e.to_string())
+}
+
+fn auth_error(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, msg.into())
+}
+
+// Map "user@REALM" to a local username.
+fn map_principal(cfg: &config::Kerberos, principal: &str) -> io::Result<String> {
+    let (user, realm) = principal
+        .rsplit_once('@')
+        .ok_or_else(|| auth_error(format!("{}: no realm", principal)))?;
+    if user.is_empty() || user.contains('/') {
+        return Err(auth_error(format!("{}: not a user principal", principal)));
+    }
+    if !cfg.realms.is_empty() && !cfg.realms.iter().any(|r| r == realm) {
+        return Err(auth_error(format!("{}: realm not allowed", principal)));
+    }
+    if cfg.strip_realm.unwrap_or(true) {
+        Ok(user.to_string())
+    } else {
+        Ok(principal.to_string())
+    }
+}
+
+/// Accept the token from an "Authorization: Negotiate" header,
+/// and return the username of the client.
+pub fn accept(cfg: &config::Kerberos, token: &[u8]) -> io::Result<String> {
+    let mut mechs = OidSet::new().map_err(gss_error)?;
+    mechs.add(&GSS_MECH_SPNEGO).map_err(gss_error)?;
+    mechs.add(&GSS_MECH_KRB5).map_err(gss_error)?;
+
+    let name = match cfg.principal {
+        Some(ref p) => Some(Name::new(p.as_bytes(), Some(&GSS_NT_KRB5_PRINCIPAL)).map_err(gss_error)?),
+        None => None,
+    };
+    let cred = Cred::acquire(name.as_ref(), None, CredUsage::Accept, Some(&mechs)).map_err(gss_error)?;
+
+    let mut ctx = ServerCtx::new(cred);
+    ctx.step(token).map_err(gss_error)?;
+    if !ctx.is_complete() {
+        return Err(auth_error("multi-step negotiation not supported"));
+    }
+    let principal = ctx.source_name().map_err(gss_error)?;
+    let principal = principal.display_name().map_err(gss_error)?;
+    map_principal(cfg, &String::from_utf8_lossy(&principal))
+}
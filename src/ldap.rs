@@ -0,0 +1,113 @@
+//
+// LDAP authentication.
+//
+// Either the user's DN is built directly from a template (bind-dn),
+// or it is looked up first with a search (search-base / search-filter),
+// optionally using a separate service account. Then we try to bind
+// as that DN using the password the user supplied.
+//
+use std::io;
+use std::time::Duration;
+
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+use crate::config;
+
+fn ldap_error(e: ldap3::LdapError) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+fn auth_error(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, msg.into())
+}
+
+// Connect to the LDAP server, and spawn the connection driver.
+async fn connect(cfg: &config::Ldap) -> io::Result<Ldap> {
+    let timeout = Duration::from_secs(cfg.timeout.unwrap_or(10));
+    let settings = LdapConnSettings::new()
+        .set_conn_timeout(timeout)
+        .set_starttls(cfg.starttls)
+        .set_no_tls_verify(!cfg.tls_verify);
+    let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &cfg.url)
+        .await
+        .map_err(ldap_error)?;
+    ldap3::drive!(conn);
+    ldap.with_timeout(timeout);
+    Ok(ldap)
+}
+
+// Find the DN of a user.
+async fn find_user_dn(ldap: &mut Ldap, cfg: &config::Ldap, user: &str) -> io::Result<String> {
+    // Simple case: DN template.
+    if let Some(ref template) = cfg.bind_dn {
+        return Ok(template.replace("{user}", &dn_escape(user)));
+    }
+
+    // Search for the user, possibly using a service account.
+    let base = cfg.search_base.as_deref().unwrap_or("");
+    if let Some(ref dn) = cfg.search_bind_dn {
+        let pw = cfg.search_bind_password.as_deref().unwrap_or("");
+        ldap.simple_bind(dn, pw)
+            .await
+            .and_then(|r| r.success())
+            .map_err(ldap_error)?;
+    }
+    let filter = cfg
+        .search_filter
+        .as_deref()
+        .unwrap_or("(uid={user})")
+        .replace("{user}", &ldap_escape(user));
+    let (entries, _) = ldap
+        .search(base, Scope::Subtree, &filter, vec!["1.1"])
+        .await
+        .and_then(|r| r.success())
+        .map_err(ldap_error)?;
+    if entries.len() != 1 {
+        return Err(auth_error(format!("{}: found {} matching entries", filter, entries.len())));
+    }
+    let entry = SearchEntry::construct(entries.into_iter().next().unwrap());
+    Ok(entry.dn)
+}
+
+// Check if the user is a member of at least one group that matches the group-filter.
+async fn check_group(ldap: &mut Ldap, cfg: &config::Ldap, user: &str, dn: &str) -> io::Result<()> {
+    let filter = match cfg.group_filter {
+        Some(ref f) => f,
+        None => return Ok(()),
+    };
+    let filter = filter
+        .replace("{user}", &ldap_escape(user))
+        .replace("{dn}", &ldap_escape(dn));
+    let base = cfg.group_base.as_deref().or(cfg.search_base.as_deref()).unwrap_or("");
+    let (entries, _) = ldap
+        .search(base, Scope::Subtree, &filter, vec!["1.1"])
+        .await
+        .and_then(|r| r.success())
+        .map_err(ldap_error)?;
+    if entries.is_empty() {
+        return Err(auth_error(format!("{}: not a member of any group matching {}", dn, filter)));
+    }
+    Ok(())
+}
+
+/// Authenticate a user against an LDAP server.
+pub async fn auth(cfg: &config::Ldap, user: &str, pass: &str) -> io::Result<()> {
+    // An empty password would result in an "unauthenticated bind",
+    // which succeeds on most servers. Never allow that.
+    if user.is_empty() || pass.is_empty() {
+        return Err(auth_error("empty username or password"));
+    }
+
+    let mut ldap = connect(cfg).await?;
+    let res = async {
+        let dn = find_user_dn(&mut ldap, cfg, user).await?;
+        ldap.simple_bind(&dn, pass)
+            .await
+            .and_then(|r| r.success())
+            .map_err(ldap_error)?;
+        check_group(&mut ldap, cfg, user, &dn).await
+    }
+    .await;
+    let _ = ldap.unbind().await;
+    res
+}
@@ -0,0 +1,203 @@
+#![doc(html_root_url = "https://docs.rs/webdav-server/0.4.0")]
+//! # `webdav-server` is a webdav server that handles user-accounts.
+//!
+//! This is a webdav server that allows access to a users home directory,
+//! just like an ancient FTP server would (remember those?).
+//!
+//! Mostly this is an application, but the server itself (routing,
+//! authentication, uid switching and the handlers) can also be embedded
+//! in another program: build a [`Server`] with a [`Builder`] and hand it
+//! to hyper, or call it as a `tower::Service`.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! use webdav_server::{Builder, LocationBuilder};
+//!
+//! let server = Builder::new()
+//!     .set("accounts", "auth-type", "htpasswd.main")
+//!     .set("htpasswd.main", "htpasswd", "/etc/webdav-htpasswd")
+//!     .location(LocationBuilder::new("/dav/*path", "filesystem", "/srv/dav").set("auth", "true"))
+//!     .build()?;
+//! let addr = ([127, 0, 0, 1], 4918).into();
+//! hyper::Server::bind(&addr).serve(server).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The settings are those of the configuration file. If you want to
+//! build your _own_ webdav server, use the `webdav-handler` crate.
+//!
+//! See the [GitHub repository](https://github.com/miquels/webdav-server-rs/)
+//! for documentation on how to run the server.
+//!
+
+#[macro_use]
+extern crate log;
+
+#[doc(hidden)]
+pub mod accesslog;
+mod acl;
+#[doc(hidden)]
+pub mod admin;
+mod aliasfs;
+#[doc(hidden)]
+pub mod acme;
+mod antivirus;
+mod append;
+mod apppass;
+mod atomicput;
+#[doc(hidden)]
+pub mod auditlog;
+#[doc(hidden)]
+pub mod auth;
+#[doc(hidden)]
+pub mod authlog;
+mod bandwidth;
+mod builder;
+mod byteranges;
+mod cache;
+mod cachecontrol;
+#[doc(hidden)]
+pub mod caps;
+mod casefs;
+mod checksum;
+#[doc(hidden)]
+pub mod checkconfig;
+mod cidr;
+mod compress;
+mod confine;
+#[doc(hidden)]
+pub mod config;
+mod cors;
+mod crossroute;
+mod cryptfs;
+mod davclass;
+#[cfg(feature = "sqlite")]
+mod deadprops;
+mod deltav;
+mod depthlimit;
+mod errorpage;
+mod etag;
+mod execauth;
+mod digest;
+mod finder;
+mod forwarded;
+mod geoip;
+mod gzip;
+#[cfg(feature = "sqlite")]
+#[doc(hidden)]
+pub mod fulltext;
+#[doc(hidden)]
+pub mod health;
+mod hidefs;
+mod ifheader;
+mod htpasswd;
+#[doc(hidden)]
+pub mod inotify;
+#[doc(hidden)]
+pub mod janitor;
+mod jobs;
+mod jwt;
+#[cfg(feature = "kerberos")]
+mod kerberos;
+mod ldap;
+#[doc(hidden)]
+pub mod logger;
+mod limits;
+mod listing;
+#[cfg(feature = "sqlite")]
+#[doc(hidden)]
+pub mod lockdb;
+mod lockpolicy;
+mod memfs;
+mod metrics;
+pub mod middleware;
+mod mimetypes;
+mod mkcol;
+mod mkhome;
+mod mysql;
+mod namefs;
+mod oidc;
+#[doc(hidden)]
+pub mod otlp;
+mod ocupload;
+mod overlayfs;
+#[doc(hidden)]
+pub mod passhash;
+#[cfg(feature = "pam")]
+mod passwd;
+mod pgsql;
+mod pim;
+mod prefer;
+#[doc(hidden)]
+pub mod proxy;
+#[cfg(feature = "quic")]
+#[doc(hidden)]
+pub mod quic;
+mod ratelimit;
+mod readahead;
+mod report;
+mod rootfs;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "s3")]
+mod s3fs;
+mod search;
+pub mod sendfile;
+#[doc(hidden)]
+pub mod selftest;
+#[doc(hidden)]
+pub mod server;
+mod session;
+mod share;
+mod softquota;
+mod sortfs;
+mod spoolfs;
+mod sql;
+mod statefile;
+mod symlinks;
+#[cfg(feature = "sqlite")]
+#[doc(hidden)]
+pub mod syncdb;
+#[doc(hidden)]
+pub mod routefile;
+#[doc(hidden)]
+pub mod router;
+#[doc(hidden)]
+pub mod seccomp;
+#[doc(hidden)]
+pub mod suid;
+#[doc(hidden)]
+pub mod suidpool;
+#[doc(hidden)]
+pub mod systemd;
+mod throttle;
+#[doc(hidden)]
+pub mod tls;
+mod tracefs;
+mod trashfs;
+mod tus;
+mod unixuser;
+mod uploadlimit;
+#[cfg(feature = "io-uring")]
+mod uring;
+#[doc(hidden)]
+pub mod usage;
+#[cfg(feature = "sqlite")]
+#[doc(hidden)]
+pub mod userdb;
+#[doc(hidden)]
+pub mod userfs;
+mod versionfs;
+mod vobject;
+mod webhook;
+mod win32props;
+mod winclient;
+mod wormfs;
+mod xattrfs;
+
+pub use crate::builder::{Builder, LocationBuilder};
+pub use crate::server::{DavService, Server};
+
+#[doc(hidden)]
+pub static PROGNAME: &str = "webdav-server";
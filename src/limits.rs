@@ -0,0 +1,217 @@
+//
+// Limits on simultaneous connections and requests ([limits]).
+//
+// Connections are counted per client address, and per user once a
+// request on them was authenticated. Requests are counted per client
+// address and per user from when they come in until the response
+// starts. A connection over the limit is not refused, but its requests
+// get "503 Service Unavailable" and then it is closed. A request over the
+// limit gets "429 Too Many Requests".
+//
+// All connections are kept in a list, for the admin API.
+//
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::SystemTime;
+
+use crate::config;
+
+lazy_static::lazy_static! {
+    static ref CONNS_IP: Counts<IpAddr> = Counts::default();
+    static ref CONNS_USER: Counts<String> = Counts::default();
+    static ref REQS_IP: Counts<IpAddr> = Counts::default();
+    static ref REQS_USER: Counts<String> = Counts::default();
+    static ref CONNS: Mutex<HashMap<u64, Weak<Conn>>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Number of slots in use, per key.
+struct Counts<K>(Mutex<HashMap<K, usize>>);
+
+impl<K> Default for Counts<K> {
+    fn default() -> Self {
+        Counts(Mutex::new(HashMap::new()))
+    }
+}
+
+impl<K: Hash + Eq + Clone> Counts<K> {
+    fn acquire(&'static self, key: &K, max: usize) -> Option<Slot<K>> {
+        let mut map = self.0.lock().unwrap();
+        let count = map.entry(key.clone()).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(Slot {
+            counts: self,
+            key:    key.clone(),
+        })
+    }
+}
+
+/// A connection or request that is counted. The count goes down
+/// when this is dropped.
+pub struct Slot<K: Hash + Eq + Clone + 'static> {
+    counts: &'static Counts<K>,
+    key:    K,
+}
+
+impl<K: Hash + Eq + Clone + 'static> Drop for Slot<K> {
+    fn drop(&mut self) {
+        let mut map = self.counts.0.lock().unwrap();
+        if let Some(count) = map.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                map.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// A connection, as far as the limits are concerned.
+pub struct Conn {
+    id:         u64,
+    ip:         IpAddr,
+    since:      SystemTime,
+    over_limit: bool,
+    _ip:        Option<Slot<IpAddr>>,
+    user:       Mutex<Option<(String, Option<Slot<String>>)>>,
+}
+
+/// A connection that is open.
+pub struct Session {
+    pub id:    u64,
+    pub ip:    IpAddr,
+    pub since: SystemTime,
+    pub user:  Option<String>,
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        CONNS.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Conn {
+    /// Was the connection over the per-address limit when it came in.
+    pub fn over_limit(&self) -> bool {
+        self.over_limit
+    }
+
+    /// Count this connection for "user", if it was not yet. Returns false
+    /// if that would be over the limit.
+    pub fn set_user(&self, limits: &config::Limits, user: &str) -> bool {
+        let mut current = self.user.lock().unwrap();
+        if current.as_ref().map(|(u, _)| u == user).unwrap_or(false) {
+            return true;
+        }
+        let slot = match limits.connections_per_user {
+            Some(max) => {
+                match CONNS_USER.acquire(&user.to_string(), max) {
+                    Some(slot) => Some(slot),
+                    None => return false,
+                }
+            },
+            None => None,
+        };
+        *current = Some((user.to_string(), slot));
+        true
+    }
+}
+
+/// Count a new connection from "ip".
+pub fn connection(limits: &config::Limits, ip: IpAddr) -> Arc<Conn> {
+    let (over_limit, slot) = match limits.connections_per_ip {
+        Some(max) => {
+            match CONNS_IP.acquire(&ip, max) {
+                Some(slot) => (false, Some(slot)),
+                None => (true, None),
+            }
+        },
+        None => (false, None),
+    };
+    let conn = Arc::new(Conn {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        ip,
+        since: SystemTime::now(),
+        over_limit,
+        _ip: slot,
+        user: Mutex::new(None),
+    });
+    CONNS.lock().unwrap().insert(conn.id, Arc::downgrade(&conn));
+    conn
+}
+
+/// The connections that are open, oldest first.
+pub fn sessions() -> Vec<Session> {
+    let conns: Vec<_> = CONNS.lock().unwrap().values().filter_map(Weak::upgrade).collect();
+    let mut sessions: Vec<_> = conns
+        .iter()
+        .map(|c| {
+            Session {
+                id:    c.id,
+                ip:    c.ip,
+                since: c.since,
+                user:  c.user.lock().unwrap().as_ref().map(|(u, _)| u.clone()),
+            }
+        })
+        .collect();
+    sessions.sort_by_key(|s| s.id);
+    sessions
+}
+
+/// Count a request from "ip". None if that is over the limit.
+pub fn request_ip(limits: &config::Limits, ip: IpAddr) -> Option<Option<Slot<IpAddr>>> {
+    match limits.requests_per_ip {
+        Some(max) => REQS_IP.acquire(&ip, max).map(Some),
+        None => Some(None),
+    }
+}
+
+/// Count a request by "user". None if that is over the limit.
+pub fn request_user(limits: &config::Limits, user: &str) -> Option<Option<Slot<String>>> {
+    match limits.requests_per_user {
+        Some(max) => REQS_USER.acquire(&user.to_string(), max).map(Some),
+        None => Some(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let limits = config::Limits {
+            connections_per_ip: Some(1),
+            connections_per_user: Some(1),
+            requests_per_user: Some(2),
+            ..config::Limits::default()
+        };
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let c1 = connection(&limits, ip);
+        let c2 = connection(&limits, ip);
+        assert!(!c1.over_limit());
+        assert!(c2.over_limit());
+        drop(c1);
+        assert!(!connection(&limits, ip).over_limit());
+
+        let c1 = connection(&config::Limits::default(), ip);
+        let c2 = connection(&config::Limits::default(), ip);
+        assert!(c1.set_user(&limits, "alice"));
+        assert!(c1.set_user(&limits, "alice"));
+        assert!(!c2.set_user(&limits, "alice"));
+        assert!(c2.set_user(&limits, "bob"));
+
+        let r1 = request_user(&limits, "alice").unwrap();
+        let _r2 = request_user(&limits, "alice").unwrap();
+        assert!(request_user(&limits, "alice").is_none());
+        drop(r1);
+        assert!(request_user(&limits, "alice").is_some());
+        assert!(request_ip(&limits, ip).unwrap().is_none());
+    }
+}
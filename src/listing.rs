@@ -0,0 +1,424 @@
+//
+// HTML directory listings, for a GET of a collection (autoindex).
+//
+// Directories come first, then files (unless directories-first is
+// false), by name, size or mtime, as in "?sort=size&order=desc"; the
+// column headers link to that. Names are in the listing-order, and
+// those that start with a dot are left out. The page can be replaced
+// with an autoindex-template: an HTML file with {{path}},
+// {{breadcrumbs}}, {{header}} and {{rows}} in it.
+//
+// With webui, the page also gets a toolbar and a script, that upload,
+// create folders, rename and delete with PUT, MKCOL, MOVE and DELETE on
+// the same URLs, as the browser. It goes where {{webui}} is.
+//
+use std::cmp::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::StreamExt;
+use http::StatusCode;
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavFileSystem, ReadDirMeta};
+
+use crate::config::ListingOrder;
+use crate::report::{self, escape};
+use crate::sortfs;
+
+const NAME: &AsciiSet = &NON_ALPHANUMERIC.remove(b'.').remove(b'-').remove(b'_').remove(b'~');
+
+const TEMPLATE: &str = r#"<!DOCTYPE html>
+<html><head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Index of {{path}}</title>
+<style>
+body { font-family: sans-serif; margin: 1.5em; color: #222; }
+nav { font-size: 1.2em; margin-bottom: 1em; }
+nav a, table a { color: #0645ad; text-decoration: none; }
+table { border-collapse: collapse; min-width: 60%; }
+th, td { padding: 0.3em 1em; text-align: left; white-space: nowrap; }
+th { border-bottom: 1px solid #ccc; }
+tbody tr:nth-child(even) { background: #f5f5f5; }
+.size { text-align: right; font-variant-numeric: tabular-nums; }
+</style>
+</head><body>
+<nav>{{breadcrumbs}}</nav>
+{{webui}}
+<table>
+<thead>{{header}}</thead>
+<tbody>
+{{rows}}
+</tbody>
+</table>
+</body></html>
+"#;
+
+// The toolbar; the buttons that are not allowed are taken out.
+const TOOLBAR: &str = r#"<style>
+#webui { margin-bottom: 1em; }
+#webui button, td button { margin-right: 0.5em; }
+#webui-status { color: #555; margin-left: 1em; }
+body.webui-drop { outline: 3px dashed #0645ad; outline-offset: -6px; }
+</style>
+<div id="webui">
+<button id="webui-upload">Upload files</button>
+<input id="webui-files" type="file" multiple hidden>
+<button id="webui-mkdir">New folder</button>
+<span id="webui-status"></span>
+</div>
+"#;
+
+const SCRIPT: &str = r#"<script>
+document.addEventListener('DOMContentLoaded', function () {
+  var status = document.getElementById('webui-status');
+  function show(msg) { status.textContent = msg; }
+  function url(name) { return new URL(encodeURIComponent(name), location.href).href; }
+  function done(resp) {
+    if (!resp.ok) throw new Error(resp.status + ' ' + resp.statusText);
+  }
+  function fail(err) { show('Failed: ' + err.message); }
+
+  function upload(files) {
+    var todo = Array.prototype.slice.call(files), n = 0;
+    function next() {
+      if (todo.length == 0) { location.reload(); return; }
+      var file = todo.shift();
+      show('Uploading ' + file.name + ' (' + (++n) + ' of ' + files.length + ')');
+      fetch(url(file.name), { method: 'PUT', body: file }).then(done).then(next, fail);
+    }
+    next();
+  }
+
+  var button = document.getElementById('webui-upload');
+  var input = document.getElementById('webui-files');
+  if (button) {
+    button.onclick = function () { input.click(); };
+    input.onchange = function () { upload(input.files); };
+    document.body.ondragover = function (e) {
+      e.preventDefault();
+      document.body.classList.add('webui-drop');
+    };
+    document.body.ondragleave = function () { document.body.classList.remove('webui-drop'); };
+    document.body.ondrop = function (e) {
+      e.preventDefault();
+      document.body.classList.remove('webui-drop');
+      if (e.dataTransfer.files.length) upload(e.dataTransfer.files);
+    };
+  }
+
+  var mkdir = document.getElementById('webui-mkdir');
+  if (mkdir) mkdir.onclick = function () {
+    var name = prompt('Name of the new folder');
+    if (!name) return;
+    fetch(url(name) + '/', { method: 'MKCOL' }).then(done).then(function () { location.reload(); }, fail);
+  };
+
+  document.querySelectorAll('button[data-action]').forEach(function (b) {
+    var row = b.closest('tr'), href = row.getAttribute('data-href'), name = row.getAttribute('data-name');
+    var target = new URL(href, location.href).href;
+    b.onclick = function () {
+      var req;
+      if (b.getAttribute('data-action') == 'rename') {
+        var to = prompt('Rename ' + name + ' to', name.replace(/\/$/, ''));
+        if (!to) return;
+        var dest = url(to) + (href.endsWith('/') ? '/' : '');
+        req = { method: 'MOVE', headers: { 'Destination': dest, 'Overwrite': 'F' } };
+      } else {
+        if (!confirm('Delete ' + name + '?')) return;
+        req = { method: 'DELETE' };
+      }
+      fetch(target, req).then(done).then(function () { location.reload(); }, fail);
+    };
+  });
+});
+</script>
+"#;
+
+/// What the web UI can do, by the methods of the location.
+#[derive(Debug, Clone, Copy)]
+pub struct WebUi {
+    pub upload: bool,
+    pub mkdir:  bool,
+    pub rename: bool,
+    pub delete: bool,
+}
+
+impl WebUi {
+    fn toolbar(&self) -> String {
+        let mut html = TOOLBAR.to_string();
+        if !self.upload {
+            html = html.replace("<button id=\"webui-upload\">Upload files</button>\n", "");
+        }
+        if !self.mkdir {
+            html = html.replace("<button id=\"webui-mkdir\">New folder</button>\n", "");
+        }
+        html + SCRIPT
+    }
+
+    fn actions(&self) -> String {
+        let mut html = String::new();
+        if self.rename {
+            html.push_str("<button data-action=\"rename\">Rename</button>");
+        }
+        if self.delete {
+            html.push_str("<button data-action=\"delete\">Delete</button>");
+        }
+        html
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sort {
+    Name,
+    Size,
+    Modified,
+}
+
+struct Entry {
+    name:     String,
+    href:     String,
+    dir:      bool,
+    size:     u64,
+    modified: Option<SystemTime>,
+}
+
+// The sort order in the query string; by name, ascending, by default.
+fn sort_order(query: Option<&str>) -> (Sort, bool) {
+    let (mut sort, mut desc) = (Sort::Name, false);
+    for (key, value) in query.unwrap_or_default().split('&').filter_map(|kv| kv.split_once('=')) {
+        match (key, value) {
+            ("sort", "name") => sort = Sort::Name,
+            ("sort", "size") => sort = Sort::Size,
+            ("sort", "mtime") => sort = Sort::Modified,
+            ("order", "desc") => desc = true,
+            ("order", "asc") => desc = false,
+            _ => {},
+        }
+    }
+    (sort, desc)
+}
+
+fn display_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn display_time(tm: Option<SystemTime>) -> String {
+    let secs = tm.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs() as i64);
+    match secs {
+        Some(secs) => time::at_utc(time::Timespec::new(secs, 0))
+            .strftime("%Y-%m-%d %H:%M")
+            .map(|t| t.to_string())
+            .unwrap_or_default(),
+        None => String::new(),
+    }
+}
+
+// Links to the directories above this one, from the root of the route.
+fn breadcrumbs(path: &DavPath) -> String {
+    let prefix = path.prefix().trim_end_matches('/');
+    let mut href = format!("{}/", prefix);
+    let home = if prefix.is_empty() { "/" } else { prefix };
+    let mut crumbs = vec![format!("<a href=\"{}\">{}</a>", escape(&href), escape(home))];
+    for seg in path.as_url_string().split('/').filter(|s| !s.is_empty()) {
+        href.push_str(seg);
+        href.push('/');
+        let name = percent_decode_str(seg).decode_utf8_lossy();
+        crumbs.push(format!("<a href=\"{}\">{}</a>", escape(&href), escape(&name)));
+    }
+    crumbs.join(" / ")
+}
+
+fn header(sort: Sort, desc: bool, webui: Option<WebUi>) -> String {
+    let columns = [
+        (Sort::Name, "name", "Name", ""),
+        (Sort::Size, "size", "Size", " class=\"size\""),
+        (Sort::Modified, "mtime", "Modified (UTC)", ""),
+    ];
+    let mut html = String::from("<tr>");
+    for (s, key, title, class) in columns.iter() {
+        let (order, mark) = match (*s == sort, desc) {
+            (true, false) => ("desc", " &#9650;"),
+            (true, true) => ("asc", " &#9660;"),
+            (false, _) => ("asc", ""),
+        };
+        html.push_str(&format!(
+            "<th{}><a href=\"?sort={}&amp;order={}\">{}</a>{}</th>",
+            class, key, order, title, mark
+        ));
+    }
+    if webui.is_some() {
+        html.push_str("<th></th>");
+    }
+    html.push_str("</tr>");
+    html
+}
+
+fn rows(entries: &[Entry], root: bool, webui: Option<WebUi>) -> String {
+    let mut html = String::new();
+    let (extra, actions) = match webui {
+        Some(ui) => ("<td></td>", format!("<td>{}</td>", ui.actions())),
+        None => ("", String::new()),
+    };
+    if !root {
+        html.push_str(&format!(
+            "<tr><td><a href=\"../\">../</a></td><td></td><td></td>{}</tr>\n",
+            extra
+        ));
+    }
+    for e in entries {
+        let size = if e.dir { String::new() } else { display_size(e.size) };
+        html.push_str(&format!(
+            "<tr data-href=\"{0}\" data-name=\"{1}\"><td><a href=\"{0}\">{1}</a></td>\
+             <td class=\"size\">{2}</td><td>{3}</td>{4}</tr>\n",
+            escape(&e.href),
+            escape(&e.name),
+            size,
+            display_time(e.modified),
+            actions
+        ));
+    }
+    html
+}
+
+// The template with the {{name}}s in it replaced, in one pass, so that
+// what goes in is not looked at again.
+fn fill(template: &str, vars: &[(&str, String)]) -> String {
+    let mut html = String::with_capacity(template.len() * 2);
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        html.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest[2..].find("}}").and_then(|end| {
+            let name = &rest[2..2 + end];
+            let value = vars.iter().find(|(n, _)| *n == name)?;
+            Some((value, end + 4))
+        });
+        match value {
+            Some(((_, value), len)) => {
+                html.push_str(value);
+                rest = &rest[len..];
+            },
+            None => {
+                html.push_str("{{");
+                rest = &rest[2..];
+            },
+        }
+    }
+    html.push_str(rest);
+    html
+}
+
+/// The listing of a directory. None if this is not one, as far as the
+/// URL goes (no / at the end) or on disk.
+pub async fn handle(
+    req: &http::Request<hyper::Body>,
+    fs: &dyn DavFileSystem,
+    path: &DavPath,
+    template: Option<&str>,
+    webui: Option<WebUi>,
+    names: ListingOrder,
+    dirs_first: bool,
+) -> Result<Option<http::Response<hyper::Body>>, StatusCode>
+{
+    if !path.is_collection() {
+        return Ok(None);
+    }
+    match fs.metadata(path).await {
+        Ok(meta) if meta.is_dir() => {},
+        Ok(_) => return Ok(None),
+        Err(e) => return Err(report::status(e)),
+    }
+    let mut resp = http::Response::new(hyper::Body::empty());
+    let ctype = "text/html; charset=utf-8".parse().unwrap();
+    resp.headers_mut().insert(http::header::CONTENT_TYPE, ctype);
+    if req.method() == http::Method::HEAD {
+        return Ok(Some(resp));
+    }
+
+    let mut stream = fs.read_dir(path, ReadDirMeta::Data).await.map_err(report::status)?;
+    let mut entries = Vec::new();
+    while let Some(entry) = stream.next().await {
+        let name = entry.name();
+        if name.starts_with(b".") {
+            continue;
+        }
+        let meta = match entry.metadata().await {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        let dir = meta.is_dir();
+        let slash = if dir { "/" } else { "" };
+        entries.push(Entry {
+            name: format!("{}{}", String::from_utf8_lossy(&name), slash),
+            href: format!("{}{}", percent_encode(&name, NAME), slash),
+            dir,
+            size: meta.len(),
+            modified: meta.modified().ok(),
+        });
+    }
+
+    let (sort, desc) = sort_order(req.uri().query());
+    entries.sort_by(|a, b| {
+        let order = match sort {
+            Sort::Name => Ordering::Equal,
+            Sort::Size => a.size.cmp(&b.size),
+            Sort::Modified => a.modified.cmp(&b.modified),
+        };
+        let order = order.then_with(|| sortfs::compare(names, &a.name, &b.name));
+        let order = if desc { order.reverse() } else { order };
+        match dirs_first {
+            true => b.dir.cmp(&a.dir).then(order),
+            false => order,
+        }
+    });
+
+    let url = path.with_prefix().as_url_string();
+    let display = percent_decode_str(&url).decode_utf8_lossy();
+    let vars = [
+        ("path", escape(&display)),
+        ("breadcrumbs", breadcrumbs(path)),
+        ("webui", webui.map(|ui| ui.toolbar()).unwrap_or_default()),
+        ("header", header(sort, desc, webui)),
+        ("rows", rows(&entries, path.as_url_string() == "/", webui)),
+    ];
+    let html = fill(template.unwrap_or(TEMPLATE), &vars);
+    *resp.body_mut() = hyper::Body::from(html);
+    Ok(Some(resp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listing() {
+        assert_eq!(sort_order(None), (Sort::Name, false));
+        assert_eq!(sort_order(Some("sort=size&order=desc")), (Sort::Size, true));
+        assert_eq!(sort_order(Some("sort=mtime&x=y")), (Sort::Modified, false));
+
+        assert_eq!(display_size(100), "100 B");
+        assert_eq!(display_size(1536), "1.5 KiB");
+        assert_eq!(display_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+
+        let vars = [("a", "{{b}}".to_string()), ("b", "x".to_string())];
+        assert_eq!(fill("<{{a}}|{{b}}|{{c}}|{{", &vars), "<{{b}}|x|{{c}}|{{");
+
+        let mut path = DavPath::new("/dav/a%20b/c/").unwrap();
+        path.set_prefix("/dav").unwrap();
+        assert_eq!(
+            breadcrumbs(&path),
+            "<a href=\"/dav/\">/dav</a> / <a href=\"/dav/a%20b/\">a b</a> / <a href=\"/dav/a%20b/c/\">c</a>"
+        );
+    }
+}
@@ -0,0 +1,551 @@
+//
+// A lock system that keeps the locks in an SQLite database, so that they
+// survive a restart.
+//
+// All locks are kept in memory as well; the database is written on every
+// change, and read once, when it is opened. Expired locks are removed then,
+// whenever they are found later, and by the janitor. Locks are keyed by the directory
+// of the location ("root") and the path below it.
+//
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::ls::{DavLock, DavLockSystem};
+use xmltree::Element;
+
+lazy_static::lazy_static! {
+    static ref DATABASES: Mutex<HashMap<String, LockDb>> = Mutex::new(HashMap::new());
+}
+
+/// The lock database in file "path", opened on first use.
+pub fn open(path: &str) -> io::Result<LockDb> {
+    let mut databases = DATABASES.lock().unwrap();
+    if let Some(db) = databases.get(path) {
+        return Ok(db.clone());
+    }
+    let db = LockDb::open(path)?;
+    databases.insert(path.to_string(), db.clone());
+    Ok(db)
+}
+
+/// The number of locks held, in all databases.
+pub fn count() -> usize {
+    let now = SystemTime::now();
+    let databases = DATABASES.lock().unwrap();
+    databases
+        .values()
+        .map(|db| db.0.lock().unwrap().locks.iter().filter(|e| !e.expired(now)).count())
+        .sum()
+}
+
+/// A lock, as shown by the admin API.
+pub struct LockInfo {
+    pub db:         String,
+    pub root:       String,
+    pub token:      String,
+    pub path:       String,
+    pub principal:  Option<String>,
+    pub shared:     bool,
+    pub deep:       bool,
+    pub timeout_at: Option<SystemTime>,
+}
+
+/// All locks that are held, in all databases.
+pub fn list() -> Vec<LockInfo> {
+    let now = SystemTime::now();
+    let databases = DATABASES.lock().unwrap();
+    let mut locks = Vec::new();
+    for (path, db) in databases.iter() {
+        let inner = db.0.lock().unwrap();
+        for e in inner.locks.iter().filter(|e| !e.expired(now)) {
+            locks.push(LockInfo {
+                db:         path.clone(),
+                root:       e.root.clone(),
+                token:      e.lock.token.clone(),
+                path:       e.lock.path.with_prefix().as_url_string(),
+                principal:  e.lock.principal.clone(),
+                shared:     e.lock.shared,
+                deep:       e.lock.deep,
+                timeout_at: e.lock.timeout_at,
+            });
+        }
+    }
+    locks
+}
+
+/// Remove the lock with this token, whoever holds it. Returns false
+/// if there is no such lock.
+pub fn release(token: &str) -> bool {
+    let databases = DATABASES.lock().unwrap();
+    for db in databases.values() {
+        let mut inner = db.0.lock().unwrap();
+        if let Some(idx) = inner.locks.iter().position(|e| e.lock.token == token) {
+            inner.locks.remove(idx);
+            inner.delete(token);
+            info!("lockdb: {}: released by the admin API", token);
+            return true;
+        }
+    }
+    false
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn unix_time(t: SystemTime) -> i64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+// The path segments, without the prefix.
+fn segments(path: &DavPath) -> Vec<&[u8]> {
+    path.as_bytes().split(|&c| c == b'/').filter(|s| !s.is_empty()).collect()
+}
+
+// A random (version 4) uuid, as urn.
+fn new_token() -> String {
+    let mut b = [0u8; 16];
+    SystemRandom::new().fill(&mut b).expect("random");
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let hex = b.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!(
+        "urn:uuid:{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+struct Entry {
+    root: String,
+    lock: DavLock,
+}
+
+impl Entry {
+    fn expired(&self, now: SystemTime) -> bool {
+        self.lock.timeout_at.map(|t| t <= now).unwrap_or(false)
+    }
+
+    // Does this lock apply to "path": it is on the path itself, or it is
+    // a deep lock on a directory above it.
+    fn covers(&self, root: &str, path: &[&[u8]]) -> bool {
+        let segs = segments(&self.lock.path);
+        self.root == root && path.starts_with(&segs) && (self.lock.deep || segs.len() == path.len())
+    }
+
+    // Is this lock on "path", or on something below it.
+    fn is_below(&self, root: &str, path: &[&[u8]]) -> bool {
+        self.root == root && segments(&self.lock.path).starts_with(path)
+    }
+
+    fn held(&self, principal: Option<&str>, ignore_principal: bool, tokens: &[&str]) -> bool {
+        tokens.iter().any(|t| *t == self.lock.token) &&
+            (ignore_principal || principal == self.lock.principal.as_deref())
+    }
+}
+
+struct Inner {
+    db:    rusqlite::Connection,
+    locks: Vec<Entry>,
+}
+
+impl Inner {
+    // Remove the expired locks.
+    fn expire(&mut self) {
+        let now = SystemTime::now();
+        if !self.locks.iter().any(|e| e.expired(now)) {
+            return;
+        }
+        let mut expired = Vec::new();
+        self.locks.retain(|e| {
+            if e.expired(now) {
+                expired.push(e.lock.token.clone());
+            }
+            !e.expired(now)
+        });
+        for token in &expired {
+            self.delete(token);
+        }
+    }
+
+    fn insert(&self, root: &str, lock: &DavLock) {
+        let mut owner = Vec::new();
+        if let Some(ref elem) = lock.owner {
+            if let Err(e) = elem.write(&mut owner) {
+                warn!("lockdb: {}: owner: {}", lock.token, e);
+                owner.clear();
+            }
+        }
+        let res = self.db.execute(
+            "INSERT OR REPLACE INTO locks
+                 (token, root, path, prefix, principal, owner, timeout, timeout_at, shared, deep)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            rusqlite::params![
+                lock.token,
+                root,
+                lock.path.with_prefix().as_url_string(),
+                lock.path.prefix(),
+                lock.principal,
+                if owner.is_empty() { None } else { Some(owner) },
+                lock.timeout.map(|d| d.as_secs() as i64),
+                lock.timeout_at.map(unix_time),
+                lock.shared,
+                lock.deep,
+            ],
+        );
+        if let Err(e) = res {
+            error!("lockdb: insert {}: {}", lock.token, e);
+        }
+    }
+
+    fn delete(&self, token: &str) {
+        if let Err(e) = self.db.execute("DELETE FROM locks WHERE token = ?1", [token]) {
+            error!("lockdb: delete {}: {}", token, e);
+        }
+    }
+
+    // Where is the lock with this token that applies to "path".
+    fn find(&self, root: &str, path: &DavPath, token: &str) -> Option<usize> {
+        let segs = segments(path);
+        self.locks.iter().position(|e| e.lock.token == token && e.covers(root, &segs))
+    }
+
+    // Is there a lock on "path" or above it that is not ours.
+    fn check_to_path(
+        &self,
+        root: &str,
+        path: &[&[u8]],
+        principal: Option<&str>,
+        ignore_principal: bool,
+        tokens: &[&str],
+        shared_ok: bool,
+    ) -> Result<(), &DavLock>
+    {
+        let mut holds_lock = false;
+        let mut first_shared = None;
+        for e in self.locks.iter().filter(|e| e.covers(root, path)) {
+            if e.held(principal, ignore_principal, tokens) {
+                holds_lock = true;
+            } else if !e.lock.shared {
+                return Err(&e.lock);
+            } else if !shared_ok {
+                first_shared.get_or_insert(&e.lock);
+            }
+        }
+        match first_shared {
+            Some(lock) if !holds_lock => Err(lock),
+            _ => Ok(()),
+        }
+    }
+
+    // Is there a lock on "path" or below it that is not ours.
+    fn check_from_path(
+        &self,
+        root: &str,
+        path: &[&[u8]],
+        principal: Option<&str>,
+        ignore_principal: bool,
+        tokens: &[&str],
+        shared_ok: bool,
+    ) -> Result<(), &DavLock>
+    {
+        for e in self.locks.iter().filter(|e| e.is_below(root, path)) {
+            if (!e.lock.shared || !shared_ok) && !e.held(principal, ignore_principal, tokens) {
+                return Err(&e.lock);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A lock database. Cloning is cheap, the clones share the database.
+#[derive(Clone)]
+pub struct LockDb(Arc<Mutex<Inner>>);
+
+impl LockDb {
+    /// Open the database, and read the locks that have not expired.
+    pub fn open(path: &str) -> io::Result<LockDb> {
+        let db = rusqlite::Connection::open(path).map_err(sql_error)?;
+        db.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS locks (
+                 token      TEXT NOT NULL PRIMARY KEY,
+                 root       TEXT NOT NULL,
+                 path       TEXT NOT NULL,
+                 prefix     TEXT NOT NULL,
+                 principal  TEXT,
+                 owner      BLOB,
+                 timeout    INTEGER,
+                 timeout_at INTEGER,
+                 shared     INTEGER NOT NULL,
+                 deep       INTEGER NOT NULL
+             );",
+        )
+        .map_err(sql_error)?;
+        let now = unix_time(SystemTime::now());
+        let expired = db
+            .execute("DELETE FROM locks WHERE timeout_at <= ?1", [now])
+            .map_err(sql_error)?;
+
+        let mut locks = Vec::new();
+        {
+            let mut stmt = db
+                .prepare(
+                    "SELECT token, root, path, prefix, principal, owner, timeout, timeout_at, shared, deep
+                     FROM locks",
+                )
+                .map_err(sql_error)?;
+            let mut rows = stmt.query([]).map_err(sql_error)?;
+            while let Some(row) = rows.next().map_err(sql_error)? {
+                let token: String = row.get(0).map_err(sql_error)?;
+                let url: String = row.get(2).map_err(sql_error)?;
+                let prefix: String = row.get(3).map_err(sql_error)?;
+                let mut path = match DavPath::new(&url) {
+                    Ok(path) => path,
+                    Err(_) => {
+                        warn!("lockdb: {}: {}: bad path, ignored", token, url);
+                        continue;
+                    },
+                };
+                if path.set_prefix(&prefix).is_err() {
+                    warn!("lockdb: {}: {}: bad prefix {}, ignored", token, url, prefix);
+                    continue;
+                }
+                let owner = row
+                    .get::<_, Option<Vec<u8>>>(5)
+                    .map_err(sql_error)?
+                    .and_then(|xml| Element::parse(xml.as_slice()).ok());
+                let timeout = row.get::<_, Option<i64>>(6).map_err(sql_error)?;
+                let timeout_at = row.get::<_, Option<i64>>(7).map_err(sql_error)?;
+                let lock = DavLock {
+                    token,
+                    path,
+                    principal: row.get(4).map_err(sql_error)?,
+                    owner,
+                    timeout_at: timeout_at.map(|t| UNIX_EPOCH + Duration::from_secs(t as u64)),
+                    timeout: timeout.map(|t| Duration::from_secs(t as u64)),
+                    shared: row.get(8).map_err(sql_error)?,
+                    deep: row.get(9).map_err(sql_error)?,
+                };
+                locks.push(Entry {
+                    root: row.get(1).map_err(sql_error)?,
+                    lock,
+                });
+            }
+        }
+        if expired > 0 || !locks.is_empty() {
+            info!("lockdb: {}: {} locks, {} expired", path, locks.len(), expired);
+        }
+
+        Ok(LockDb(Arc::new(Mutex::new(Inner { db, locks }))))
+    }
+
+    /// Remove the expired locks. The number that were removed.
+    pub fn expire(&self) -> usize {
+        let mut inner = self.0.lock().unwrap();
+        let before = inner.locks.len();
+        inner.expire();
+        before - inner.locks.len()
+    }
+
+    /// Give the space of removed locks back to the filesystem.
+    pub fn compact(&self) -> io::Result<()> {
+        let inner = self.0.lock().unwrap();
+        inner.db.execute_batch("PRAGMA wal_checkpoint(TRUNCATE); VACUUM;").map_err(sql_error)
+    }
+
+    /// The lock system for the location with directory "root".
+    pub fn locksystem(&self, root: &str) -> Box<dyn DavLockSystem> {
+        Box::new(RootLs {
+            db:   self.clone(),
+            root: root.to_string(),
+        })
+    }
+}
+
+// The locks of one root.
+#[derive(Clone)]
+struct RootLs {
+    db:   LockDb,
+    root: String,
+}
+
+impl std::fmt::Debug for RootLs {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("RootLs").field("root", &self.root).finish()
+    }
+}
+
+impl DavLockSystem for RootLs {
+    fn lock(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        owner: Option<&Element>,
+        timeout: Option<Duration>,
+        shared: bool,
+        deep: bool,
+    ) -> Result<DavLock, DavLock>
+    {
+        let mut inner = self.db.0.lock().unwrap();
+        inner.expire();
+
+        let segs = segments(path);
+        inner.check_to_path(&self.root, &segs, None, true, &[], shared).map_err(DavLock::clone)?;
+        if deep {
+            inner.check_from_path(&self.root, &segs, None, true, &[], shared).map_err(DavLock::clone)?;
+        }
+
+        let lock = DavLock {
+            token: new_token(),
+            path: path.clone(),
+            principal: principal.map(|s| s.to_string()),
+            owner: owner.cloned(),
+            timeout_at: timeout.map(|d| SystemTime::now() + d),
+            timeout,
+            shared,
+            deep,
+        };
+        inner.insert(&self.root, &lock);
+        inner.locks.push(Entry {
+            root: self.root.clone(),
+            lock: lock.clone(),
+        });
+        Ok(lock)
+    }
+
+    fn unlock(&self, path: &DavPath, token: &str) -> Result<(), ()> {
+        let mut inner = self.db.0.lock().unwrap();
+        let idx = inner.find(&self.root, path, token).ok_or(())?;
+        inner.locks.remove(idx);
+        inner.delete(token);
+        Ok(())
+    }
+
+    fn refresh(&self, path: &DavPath, token: &str, timeout: Option<Duration>) -> Result<DavLock, ()> {
+        let mut inner = self.db.0.lock().unwrap();
+        inner.expire();
+        let idx = inner.find(&self.root, path, token).ok_or(())?;
+        let lock = {
+            let lock = &mut inner.locks[idx].lock;
+            lock.timeout = timeout;
+            lock.timeout_at = timeout.map(|d| SystemTime::now() + d);
+            lock.clone()
+        };
+        inner.insert(&self.root, &lock);
+        Ok(lock)
+    }
+
+    fn check(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        ignore_principal: bool,
+        deep: bool,
+        submitted_tokens: Vec<&str>,
+    ) -> Result<(), DavLock>
+    {
+        let mut inner = self.db.0.lock().unwrap();
+        inner.expire();
+        let segs = segments(path);
+        let tokens = submitted_tokens.as_slice();
+        let root = self.root.as_str();
+        inner
+            .check_to_path(root, &segs, principal, ignore_principal, tokens, false)
+            .map_err(DavLock::clone)?;
+        if deep {
+            inner
+                .check_from_path(root, &segs, principal, ignore_principal, tokens, false)
+                .map_err(DavLock::clone)?;
+        }
+        Ok(())
+    }
+
+    fn discover(&self, path: &DavPath) -> Vec<DavLock> {
+        let mut inner = self.db.0.lock().unwrap();
+        inner.expire();
+        let segs = segments(path);
+        inner
+            .locks
+            .iter()
+            .filter(|e| e.covers(&self.root, &segs))
+            .map(|e| e.lock.clone())
+            .collect()
+    }
+
+    fn delete(&self, path: &DavPath) -> Result<(), ()> {
+        let mut inner = self.db.0.lock().unwrap();
+        let segs = segments(path);
+        let root = self.root.clone();
+        let mut deleted = Vec::new();
+        inner.locks.retain(|e| {
+            let below = e.is_below(&root, &segs);
+            if below {
+                deleted.push(e.lock.token.clone());
+            }
+            !below
+        });
+        for token in &deleted {
+            inner.delete(token);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(p: &str) -> DavPath {
+        let mut path = DavPath::new(p).unwrap();
+        path.set_prefix("/dav").unwrap();
+        path
+    }
+
+    #[test]
+    fn test_locks() {
+        let dir = std::env::temp_dir().join(format!("lockdb-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("locks.db");
+        let file = file.to_str().unwrap();
+
+        let ls = LockDb::open(file).unwrap().locksystem("/data");
+        let hour = Some(Duration::from_secs(3600));
+        let deep = ls.lock(&path("/dav/a/"), Some("u"), None, hour, false, true).unwrap();
+        assert!(ls.lock(&path("/dav/a/b"), Some("u"), None, hour, false, false).is_err());
+        assert!(ls.check(&path("/dav/a/b"), Some("u"), false, false, vec![]).is_err());
+        assert!(ls
+            .check(&path("/dav/a/b"), Some("u"), false, false, vec![&deep.token])
+            .is_ok());
+        // another root.
+        let other = LockDb::open(file).unwrap().locksystem("/other");
+        assert!(other.check(&path("/dav/a/b"), None, true, false, vec![]).is_ok());
+        let gone = ls
+            .lock(&path("/dav/c"), None, None, Some(Duration::from_secs(0)), false, false)
+            .unwrap();
+        let _kept = ls.lock(&path("/dav/d"), None, None, None, true, false).unwrap();
+
+        // "restart".
+        let ls = LockDb::open(file).unwrap().locksystem("/data");
+        let locks = ls.discover(&path("/dav/a/b"));
+        assert_eq!(locks.len(), 1);
+        assert_eq!(locks[0].token, deep.token);
+        assert_eq!(locks[0].principal.as_deref(), Some("u"));
+        assert_eq!(locks[0].path.with_prefix().as_url_string(), "/dav/a/");
+        assert!(ls.discover(&path("/dav/c")).is_empty());
+        assert!(ls.unlock(&path("/dav/c"), &gone.token).is_err());
+        assert!(ls.lock(&path("/dav/d"), None, None, None, true, false).is_ok());
+        assert!(ls.lock(&path("/dav/d"), None, None, None, false, false).is_err());
+        ls.delete(&path("/dav/a/")).unwrap();
+        assert!(ls.discover(&path("/dav/a/b")).is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
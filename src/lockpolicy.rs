@@ -0,0 +1,169 @@
+//
+// The [locks] policy: shared locks, lock timeouts, and the tokens in
+// the If: header for require-lock.
+//
+use std::time::Duration;
+
+use webdav_handler::davpath::DavPath;
+use webdav_handler::ls::{DavLock, DavLockSystem};
+use xmltree::Element;
+
+use crate::config::Locks;
+
+/// A lock system that applies the [locks] settings to the one it wraps.
+#[derive(Debug, Clone)]
+pub struct PolicyLs {
+    ls:              Box<dyn DavLockSystem>,
+    shared:          bool,
+    max_timeout:     Option<Duration>,
+    default_timeout: Option<Duration>,
+}
+
+impl PolicyLs {
+    pub fn new(ls: Box<dyn DavLockSystem>, locks: &Locks) -> Box<PolicyLs> {
+        Box::new(PolicyLs {
+            ls,
+            shared: locks.shared.unwrap_or(true),
+            max_timeout: locks.max_timeout.map(Duration::from_secs),
+            default_timeout: locks.default_timeout.map(Duration::from_secs),
+        })
+    }
+
+    // No timeout (infinite) becomes the default, or else the maximum.
+    fn timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        match (timeout, self.max_timeout) {
+            (Some(t), Some(max)) => Some(t.min(max)),
+            (Some(t), None) => Some(t),
+            (None, max) => self.default_timeout.or(max),
+        }
+    }
+}
+
+impl DavLockSystem for PolicyLs {
+    fn lock(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        owner: Option<&Element>,
+        timeout: Option<Duration>,
+        shared: bool,
+        deep: bool,
+    ) -> Result<DavLock, DavLock>
+    {
+        if shared && !self.shared {
+            // the handler only looks at the error, not at the lock.
+            return Err(DavLock {
+                token: String::new(),
+                path: path.clone(),
+                principal: None,
+                owner: None,
+                timeout_at: None,
+                timeout: None,
+                shared: false,
+                deep: false,
+            });
+        }
+        let timeout = self.timeout(timeout);
+        self.ls.lock(path, principal, owner, timeout, shared, deep)
+    }
+
+    fn unlock(&self, path: &DavPath, token: &str) -> Result<(), ()> {
+        self.ls.unlock(path, token)
+    }
+
+    fn refresh(&self, path: &DavPath, token: &str, timeout: Option<Duration>) -> Result<DavLock, ()> {
+        self.ls.refresh(path, token, self.timeout(timeout))
+    }
+
+    fn check(
+        &self,
+        path: &DavPath,
+        principal: Option<&str>,
+        ignore_principal: bool,
+        deep: bool,
+        submitted_tokens: Vec<&str>,
+    ) -> Result<(), DavLock>
+    {
+        self.ls.check(path, principal, ignore_principal, deep, submitted_tokens)
+    }
+
+    fn discover(&self, path: &DavPath) -> Vec<DavLock> {
+        self.ls.discover(path)
+    }
+
+    fn delete(&self, path: &DavPath) -> Result<(), ()> {
+        self.ls.delete(path)
+    }
+}
+
+/// The lock tokens in an If: header. Those are the <coded-url>s inside the
+/// lists "( ... )", without a "Not" in front; the ones outside of them are
+/// resource tags.
+pub fn if_tokens(hdr: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut in_list = false;
+    let mut rest = hdr;
+    while let Some(idx) = rest.find(['(', ')', '<', '[']) {
+        let c = rest.as_bytes()[idx];
+        let not = rest[..idx].trim_end().ends_with("Not");
+        rest = &rest[idx + 1..];
+        let close = match c {
+            b'(' => {
+                in_list = true;
+                continue;
+            },
+            b')' => {
+                in_list = false;
+                continue;
+            },
+            b'<' => '>',
+            _ => ']',
+        };
+        let end = match rest.find(close) {
+            Some(end) => end,
+            None => break,
+        };
+        if in_list && c == b'<' && !not {
+            tokens.push(&rest[..end]);
+        }
+        rest = &rest[end + 1..];
+    }
+    tokens
+}
+
+/// Does the request hold a lock on "path": does it submit the token of
+/// one of the locks of "principal" that applies to it.
+pub fn holds_lock(ls: &dyn DavLockSystem, path: &DavPath, principal: Option<&str>, tokens: &[&str]) -> bool {
+    ls.discover(path)
+        .iter()
+        .any(|l| tokens.contains(&l.token.as_str()) && l.principal.as_deref() == principal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_tokens() {
+        assert_eq!(if_tokens("(<urn:uuid:1>)"), vec!["urn:uuid:1"]);
+        assert_eq!(
+            if_tokens("<http://h/a> (<urn:uuid:1> [\"etag<>\"]) (Not <urn:uuid:2>)"),
+            vec!["urn:uuid:1"]
+        );
+        assert!(if_tokens("<http://h/a> ([\"etag\"])").is_empty());
+        assert!(if_tokens("(<urn:uuid:1").is_empty());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let locks = Locks {
+            max_timeout: Some(600),
+            default_timeout: Some(60),
+            ..Locks::default()
+        };
+        let ls = PolicyLs::new(webdav_handler::fakels::FakeLs::new(), &locks);
+        assert_eq!(ls.timeout(None), Some(Duration::from_secs(60)));
+        assert_eq!(ls.timeout(Some(Duration::from_secs(3600))), Some(Duration::from_secs(600)));
+        assert_eq!(ls.timeout(Some(Duration::from_secs(10))), Some(Duration::from_secs(10)));
+    }
+}
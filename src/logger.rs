@@ -0,0 +1,227 @@
+//
+// Where the log goes: stderr (env_logger), syslog, or journald.
+//
+// The log level is set with RUST_LOG (or -D) for all of them. Journald
+// entries of a request have the fields WEBDAV_METHOD, WEBDAV_PATH and,
+// once authenticated, WEBDAV_USER, so that they can be queried with
+// journalctl, say "journalctl WEBDAV_USER=bob".
+//
+use std::ffi::CString;
+use std::future::Future;
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Arc, Mutex};
+
+use env_logger::filter::{Builder, Filter};
+use log::{Level, Log, Metadata, Record};
+
+use crate::config::{self, LogTarget};
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+tokio::task_local! {
+    static REQUEST: Arc<Request>;
+}
+
+/// The request that is being handled by the current task.
+pub struct Request {
+    method: String,
+    path:   String,
+    user:   Mutex<Option<String>>,
+}
+
+impl Request {
+    pub fn new<B>(req: &http::Request<B>) -> Request {
+        Request {
+            method: req.method().to_string(),
+            path:   req.uri().path().to_string(),
+            user:   Mutex::new(None),
+        }
+    }
+
+    /// Run the request, with its method and path in the journald fields.
+    pub async fn scope<F: Future>(self, fut: F) -> F::Output {
+        REQUEST.scope(Arc::new(self), fut).await
+    }
+}
+
+/// The request was authenticated as "user".
+pub fn set_user(user: &str) {
+    let _ = REQUEST.try_with(|r| *r.user.lock().unwrap() = Some(user.to_string()));
+}
+
+/// The user of the request of the current task, if it was authenticated.
+pub fn user() -> Option<String> {
+    REQUEST.try_with(|r| r.user.lock().unwrap().clone()).ok().flatten()
+}
+
+/// The syslog facility by name.
+pub fn facility(name: &str) -> Option<libc::c_int> {
+    let facility = match name {
+        "kern" => libc::LOG_KERN,
+        "user" => libc::LOG_USER,
+        "mail" => libc::LOG_MAIL,
+        "daemon" => libc::LOG_DAEMON,
+        "auth" => libc::LOG_AUTH,
+        "syslog" => libc::LOG_SYSLOG,
+        "lpr" => libc::LOG_LPR,
+        "news" => libc::LOG_NEWS,
+        "uucp" => libc::LOG_UUCP,
+        "cron" => libc::LOG_CRON,
+        "authpriv" => libc::LOG_AUTHPRIV,
+        "ftp" => libc::LOG_FTP,
+        "local0" => libc::LOG_LOCAL0,
+        "local1" => libc::LOG_LOCAL1,
+        "local2" => libc::LOG_LOCAL2,
+        "local3" => libc::LOG_LOCAL3,
+        "local4" => libc::LOG_LOCAL4,
+        "local5" => libc::LOG_LOCAL5,
+        "local6" => libc::LOG_LOCAL6,
+        "local7" => libc::LOG_LOCAL7,
+        _ => return None,
+    };
+    Some(facility)
+}
+
+fn priority(level: Level) -> libc::c_int {
+    match level {
+        Level::Error => libc::LOG_ERR,
+        Level::Warn => libc::LOG_WARNING,
+        Level::Info => libc::LOG_INFO,
+        Level::Debug | Level::Trace => libc::LOG_DEBUG,
+    }
+}
+
+/// Set up logging. "level" is the default if RUST_LOG is not set.
+pub fn init(cfg: &config::Log, level: Option<&str>) -> io::Result<()> {
+    let target = cfg.target.unwrap_or(LogTarget::Stderr);
+    if target == LogTarget::Stderr {
+        let env = env_logger::Env::default();
+        match level {
+            Some(level) => env_logger::Builder::from_env(env.default_filter_or(level)).init(),
+            None => env_logger::Builder::from_env(env).init(),
+        }
+        return Ok(());
+    }
+
+    let mut builder = Builder::new();
+    if let Some(spec) = std::env::var("RUST_LOG").ok().as_deref().or(level) {
+        builder.parse(spec);
+    }
+    let filter = builder.build();
+    let max_level = filter.filter();
+    let ident = cfg.syslog_ident.clone().unwrap_or_else(|| crate::PROGNAME.to_string());
+
+    let logger: Box<dyn Log> = match target {
+        LogTarget::Syslog => {
+            let name = cfg.syslog_facility.as_deref().unwrap_or("daemon");
+            let facility = facility(name).unwrap_or(libc::LOG_DAEMON);
+            // openlog() keeps the pointer, so the ident is never freed.
+            let ident = CString::new(ident).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let ident: &'static CString = Box::leak(Box::new(ident));
+            // SAFETY: ident is a valid C string that lives forever.
+            unsafe { libc::openlog(ident.as_ptr(), libc::LOG_PID | libc::LOG_NDELAY, facility) };
+            Box::new(Syslog { filter })
+        },
+        _ => {
+            let socket = UnixDatagram::unbound()?;
+            socket
+                .connect(JOURNAL_SOCKET)
+                .map_err(|e| io::Error::new(e.kind(), format!("journald: {}: {}", JOURNAL_SOCKET, e)))?;
+            Box::new(Journald { filter, socket, ident })
+        },
+    };
+    log::set_max_level(max_level);
+    log::set_boxed_logger(logger).map_err(|e| io::Error::other(e.to_string()))
+}
+
+struct Syslog {
+    filter: Filter,
+}
+
+impl Log for Syslog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let msg = format!("{}", record.args()).replace('\0', "\\0");
+        let msg = CString::new(msg).unwrap();
+        let fmt = b"%s\0".as_ptr() as *const libc::c_char;
+        // SAFETY: both are valid C strings, and "%s" takes one argument.
+        unsafe { libc::syslog(priority(record.level()), fmt, msg.as_ptr()) };
+    }
+
+    fn flush(&self) {}
+}
+
+struct Journald {
+    filter: Filter,
+    socket: UnixDatagram,
+    ident:  String,
+}
+
+// A field in the journal native protocol. Values with a newline in
+// them are sent with an explicit length.
+fn journal_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+impl Log for Journald {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let mut buf = Vec::new();
+        journal_field(&mut buf, "MESSAGE", &record.args().to_string());
+        journal_field(&mut buf, "PRIORITY", &priority(record.level()).to_string());
+        journal_field(&mut buf, "SYSLOG_IDENTIFIER", &self.ident);
+        journal_field(&mut buf, "CODE_MODULE", record.target());
+        if let Some(file) = record.file() {
+            journal_field(&mut buf, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            journal_field(&mut buf, "CODE_LINE", &line.to_string());
+        }
+        let _ = REQUEST.try_with(|r| {
+            journal_field(&mut buf, "WEBDAV_METHOD", &r.method);
+            journal_field(&mut buf, "WEBDAV_PATH", &r.path);
+            if let Some(ref user) = *r.user.lock().unwrap() {
+                journal_field(&mut buf, "WEBDAV_USER", user);
+            }
+        });
+        if self.socket.send(&buf).is_err() {
+            let _ = writeln!(io::stderr(), "{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_field() {
+        let mut buf = Vec::new();
+        journal_field(&mut buf, "MESSAGE", "hello");
+        journal_field(&mut buf, "MESSAGE", "a\nb");
+        assert_eq!(buf, b"MESSAGE=hello\nMESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n");
+    }
+}
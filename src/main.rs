@@ -1,381 +1,48 @@
-#![doc(html_root_url = "https://docs.rs/webdav-server/0.4.0")]
-//! # `webdav-server` is a webdav server that handles user-accounts.
-//!
-//! This is a webdav server that allows access to a users home directory,
-//! just like an ancient FTP server would (remember those?).
-//!
-//! This is an application. There is no API documentation here.
-//! If you want to build your _own_ webdav server, use the `webdav-handler` crate.
-//!
-//! See the [GitHub repository](https://github.com/miquels/webdav-server-rs/)
-//! for documentation on how to run the server.
-//!
-
-#[macro_use]
-extern crate log;
-
-mod auth;
-mod cache;
-mod config;
-mod rootfs;
-#[doc(hidden)]
-pub mod router;
-mod suid;
-mod tls;
-mod unixuser;
-mod userfs;
-
-use std::convert::TryFrom;
+//
+// The webdav-server binary: the command line, the listeners, TLS, and
+// the signals. The server itself is in the library.
+//
 use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::os::unix::io::{FromRawFd, AsRawFd};
 use std::process::exit;
 use std::sync::Arc;
+use std::time::Duration;
 
 use clap::clap_app;
-use headers::{authorization::Basic, Authorization, HeaderMapExt};
-use http::status::StatusCode;
+use futures::future::FutureExt;
 use hyper::{
     self,
-    server::conn::{AddrIncoming, AddrStream},
+    server::conn::AddrStream,
     service::{make_service_fn, service_fn},
 };
 use tls_listener::TlsListener;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_rustls::rustls::Session;
 use tokio_rustls::server::TlsStream;
-use webdav_handler::{davpath::DavPath, DavConfig, DavHandler, DavMethod, DavMethodSet};
-use webdav_handler::{fakels::FakeLs, fs::DavFileSystem, ls::DavLockSystem};
-
-use crate::config::{AcctType, Auth, CaseInsensitive, Handler, Location, OnNotfound};
-use crate::rootfs::RootFs;
-use crate::router::MatchedRoute;
-use crate::suid::proc_switch_ugid;
-use crate::tls::tls_config;
-use crate::userfs::UserFs;
-
-static PROGNAME: &'static str = "webdav-server";
-
-// Contains "state" and a handle to the config.
-#[derive(Clone)]
-struct Server {
-    dh:     DavHandler,
-    auth:   auth::Auth,
-    config: Arc<config::Config>,
-}
-
-type HttpResult = Result<hyper::Response<webdav_handler::body::Body>, io::Error>;
-type HttpRequest = http::Request<hyper::Body>;
-
-// Server implementation.
-impl Server {
-    // Constructor.
-    pub fn new(config: Arc<config::Config>, auth: auth::Auth) -> Self {
-        // mostly empty handler.
-        let ls = FakeLs::new() as Box<dyn DavLockSystem>;
-        let dh = DavHandler::builder().locksystem(ls).build_handler();
-
-        Server { dh, auth, config }
-    }
-
-    // check user account.
-    async fn acct<'a>(
-        &'a self,
-        location: &Location,
-        auth_user: Option<&'a String>,
-        user_param: Option<&'a str>,
-    ) -> Result<Option<Arc<unixuser::User>>, StatusCode>
-    {
-        // Get username - if any.
-        let user = match auth_user.map(|u| u.as_str()).or(user_param) {
-            Some(u) => u,
-            None => return Ok(None),
-        };
-
-        // If account is not set, fine.
-        let acct_type = location
-            .accounts
-            .acct_type
-            .as_ref()
-            .or(self.config.accounts.acct_type.as_ref());
-        match acct_type {
-            Some(&AcctType::Unix) => {},
-            None => return Ok(None),
-        };
-
-        // check if user exists.
-        let pwd = match cache::cached::unixuser(user, self.config.unix.aux_groups).await {
-            Ok(pwd) => pwd,
-            Err(_) => {
-                debug!("acct: unix: user {} not found", user);
-                return Err(StatusCode::UNAUTHORIZED);
-            },
-        };
-
-        // check minimum uid
-        if let Some(min_uid) = self.config.unix.min_uid {
-            if pwd.uid < min_uid {
-                debug!("acct: {}: uid {} too low (<{})", pwd.name, pwd.uid, min_uid);
-                return Err(StatusCode::FORBIDDEN);
-            }
-        }
-        Ok(Some(pwd))
-    }
-
-    // return a new response::Builder with the Server: header set.
-    fn response_builder(&self) -> http::response::Builder {
-        let mut builder = hyper::Response::builder();
-        let id = self
-            .config
-            .server
-            .identification
-            .as_ref()
-            .map(|s| s.as_str())
-            .unwrap_or("webdav-server-rs");
-        if id != "" {
-            builder = builder.header("Server", id);
-        }
-        builder
-    }
-
-    // Set Server: webdav-server-rs header.
-    fn set_server_header(&self, headers: &mut http::HeaderMap<http::header::HeaderValue>) {
-        let id = self
-            .config
-            .server
-            .identification
-            .as_ref()
-            .map(|s| s.as_str())
-            .unwrap_or("webdav-server-rs");
-        if id != "" {
-            headers.insert("server", id.parse().unwrap());
-        }
-    }
-
-    // handle a request.
-    async fn route(&self, req: HttpRequest, remote_ip: SocketAddr) -> HttpResult {
-        // Get the URI path.
-        let davpath = match DavPath::from_uri(req.uri()) {
-            Ok(p) => p,
-            Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
-        };
-        let path = davpath.as_bytes();
-
-        // Get the method.
-        let method = match DavMethod::try_from(req.method()) {
-            Ok(m) => m,
-            Err(_) => return self.error(http::StatusCode::METHOD_NOT_ALLOWED).await,
-        };
-
-        // Request is stored here.
-        let mut reqdata = Some(req);
-        let mut got_match = false;
-
-        // Match routes to one or more locations.
-        for route in self
-            .config
-            .router
-            .matches(path, method, &["user", "path"])
-            .drain(..)
-        {
-            got_match = true;
-
-            // Take the request from the option.
-            let req = reqdata.take().unwrap();
-
-            // if we might continue, store a clone of the request for the next round.
-            let location = &self.config.location[*route.data];
-            if let Some(OnNotfound::Continue) = location.on_notfound {
-                reqdata.get_or_insert(clone_httpreq(&req));
-            }
-
-            // handle request.
-            let res = self
-                .handle(req, method, path, route, location, remote_ip.clone())
-                .await?;
-
-            // no on_notfound? then this is final.
-            if reqdata.is_none() || res.status() != StatusCode::NOT_FOUND {
-                return Ok(res);
-            }
-        }
-
-        if !got_match {
-            debug!("route: no matching route for {:?}", davpath);
-        }
-
-        self.error(StatusCode::NOT_FOUND).await
-    }
-
-    // handle a request.
-    async fn handle<'a, 't: 'a, 'p: 'a>(
-        &'a self,
-        req: HttpRequest,
-        method: DavMethod,
-        path: &'a [u8],
-        route: MatchedRoute<'t, 'p, usize>,
-        location: &'a Location,
-        remote_ip: SocketAddr,
-    ) -> HttpResult
-    {
-        // See if we matched a :user parameter
-        // If so, it must be valid UTF-8, or we return NOT_FOUND.
-        let user_param = match route.params[0].as_ref() {
-            Some(p) => {
-                match p.as_str() {
-                    Some(p) => Some(p),
-                    None => {
-                        debug!("handle: invalid utf-8 in :user part of path");
-                        return self.error(StatusCode::NOT_FOUND).await;
-                    },
-                }
-            },
-            None => None,
-        };
-
-        // Do authentication if needed.
-        let auth_hdr = req.headers().typed_get::<Authorization<Basic>>();
-        let do_auth = match location.auth {
-            Some(Auth::True) => true,
-            Some(Auth::Write) => !DavMethodSet::WEBDAV_RO.contains(method) || auth_hdr.is_some(),
-            Some(Auth::False) => false,
-            Some(Auth::Opportunistic) | None => auth_hdr.is_some(),
-        };
-        let auth_user = if do_auth {
-            let user = match self.auth.auth(&req, location, remote_ip).await {
-                Ok(user) => user,
-                Err(status) => return self.auth_error(status, location).await,
-            };
-            // if there was a :user in the route, return error if it does not match.
-            if user_param.map(|u| u != &user).unwrap_or(false) {
-                debug!("handle: auth user and :user mismatch");
-                return self.auth_error(StatusCode::UNAUTHORIZED, location).await;
-            }
-            Some(user)
-        } else {
-            None
-        };
-
-        // Now see if we want to do a account lookup, for uid/gid/homedir.
-        let pwd = match self.acct(location, auth_user.as_ref(), user_param).await {
-            Ok(pwd) => pwd,
-            Err(status) => return self.auth_error(status, location).await,
-        };
-
-        // Expand "~" in the directory.
-        let dir = match expand_directory(location.directory.as_str(), pwd.as_ref()) {
-            Ok(d) => d,
-            Err(_) => return self.error(StatusCode::NOT_FOUND).await,
-        };
-
-        // If :path matched, we can calculate the prefix.
-        // If it didn't, the entire path _is_ the prefix.
-        let prefix = match route.params[1].as_ref() {
-            Some(p) => {
-                let mut start = p.start();
-                if start > 0 {
-                    start -= 1;
-                }
-                &path[..start]
-            },
-            None => path,
-        };
-        let prefix = match std::str::from_utf8(prefix) {
-            Ok(p) => p.to_string(),
-            Err(_) => {
-                debug!("handle: prefix is non-UTF8");
-                return self.error(StatusCode::NOT_FOUND).await;
-            },
-        };
-
-        // Get User-Agent for user-agent specific modes.
-        let user_agent = req
-            .headers()
-            .get("user-agent")
-            .and_then(|s| s.to_str().ok())
-            .unwrap_or("");
-
-        // Case insensitivity wanted?
-        let case_insensitive = match location.case_insensitive {
-            Some(CaseInsensitive::True) => true,
-            Some(CaseInsensitive::Ms) => user_agent.contains("Microsoft"),
-            Some(CaseInsensitive::False) | None => false,
-        };
-
-        // macOS optimizations?
-        let macos = user_agent.contains("WebDAVFS/") && user_agent.contains("Darwin");
-
-        // Get the filesystem.
-        let auth_ugid = if location.setuid {
-            pwd.as_ref().map(|p| (p.uid, p.gid, p.groups.as_slice()))
-        } else {
-            None
-        };
-        let fs = match location.handler {
-            Handler::Virtroot => {
-                let auth_user = auth_user.as_ref().map(String::to_owned);
-                RootFs::new(dir, auth_user, auth_ugid) as Box<dyn DavFileSystem>
-            },
-            Handler::Filesystem => {
-                UserFs::new(dir, auth_ugid, true, case_insensitive, macos) as Box<dyn DavFileSystem>
-            },
-        };
-
-        // Build a handler.
-        let methods = location
-            .methods
-            .unwrap_or(DavMethodSet::from_vec(vec!["GET", "HEAD"]).unwrap());
-        let hide_symlinks = location.hide_symlinks.clone().unwrap_or(true);
-
-        let mut config = DavConfig::new()
-            .filesystem(fs)
-            .strip_prefix(prefix)
-            .methods(methods)
-            .hide_symlinks(hide_symlinks)
-            .autoindex(location.autoindex);
-        if let Some(auth_user) = auth_user {
-            config = config.principal(auth_user);
-        }
-        if let Some(indexfile) = location.indexfile.clone() {
-            config = config.indexfile(indexfile);
-        }
-
-        // All set.
-        self.run_davhandler(config, req).await
-    }
-
-    async fn build_error(&self, code: StatusCode, location: Option<&Location>) -> HttpResult {
-        let msg = format!(
-            "<error>{} {}</error>\n",
-            code.as_u16(),
-            code.canonical_reason().unwrap_or("")
-        );
-        let mut response = self
-            .response_builder()
-            .status(code)
-            .header("Content-Type", "text/xml");
-        if code == StatusCode::UNAUTHORIZED {
-            let realm = location.and_then(|location| location.accounts.realm.as_ref());
-            let realm = realm.or(self.config.accounts.realm.as_ref());
-            let realm = realm.map(|s| s.as_str()).unwrap_or("Webdav Server");
-            response = response.header("WWW-Authenticate", format!("Basic realm=\"{}\"", realm).as_str());
-        }
-        Ok(response.body(msg.into()).unwrap())
-    }
 
-    async fn auth_error(&self, code: StatusCode, location: &Location) -> HttpResult {
-        self.build_error(code, Some(location)).await
-    }
+use webdav_server::config::{self, AuthScheme, Capability, ListenAddr, Seccomp};
+use webdav_server::sendfile::Socket;
+use webdav_server::server::{conn_timeouts, shutdown_signal};
+use webdav_server::suid::proc_switch_ugid;
+use webdav_server::tls::tls_config;
+#[cfg(feature = "quic")]
+use webdav_server::quic;
+#[cfg(feature = "sqlite")]
+use webdav_server::{fulltext, lockdb, syncdb, userdb};
+use webdav_server::{accesslog, acme, admin, auditlog, auth, authlog, caps, checkconfig, health, inotify};
+use webdav_server::{janitor, logger, middleware, otlp, passhash, proxy, seccomp, selftest, suid, suidpool};
+use webdav_server::{routefile, systemd, tls, usage, userfs};
+use webdav_server::{Server, PROGNAME};
 
-    async fn error(&self, code: StatusCode) -> HttpResult {
-        self.build_error(code, None).await
-    }
+type HttpRequest = http::Request<hyper::Body>;
 
-    // Call the davhandler, then add headers to the response.
-    async fn run_davhandler(&self, config: DavConfig, req: HttpRequest) -> HttpResult {
-        let resp = self.dh.handle_with(config, req).await;
-        let (mut parts, body) = resp.into_parts();
-        self.set_server_header(&mut parts.headers);
-        Ok(http::Response::from_parts(parts, body))
-    }
+// A listening socket.
+enum Listener {
+    Tcp(tokio::net::TcpListener),
+    Unix(tokio::net::UnixListener),
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -385,123 +52,290 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         (@arg CFG: -c --config +takes_value "configuration file (/etc/webdav-server.toml)")
         (@arg PORT: -p --port +takes_value "listen to this port on localhost only")
         (@arg DBG: -D --debug "enable debug level logging")
+        (@arg CHECK: --("check-config") "check the configuration and exit")
+        (@arg VERIFY: --("verify-audit-log") +takes_value "check the hash chain of an audit log and exit")
+        (@subcommand user =>
+            (about: "manage the users of auth-type sqlite")
+            (@setting SubcommandRequiredElseHelp)
+            (@subcommand add =>
+                (about: "add a user, asking for the password")
+                (@arg NAME: +required "username"))
+            (@subcommand passwd =>
+                (about: "set the password of a user")
+                (@arg NAME: +required "username"))
+            (@subcommand del =>
+                (about: "remove a user")
+                (@arg NAME: +required "username"))
+            (@subcommand list =>
+                (about: "list the users"))
+        )
+        (@subcommand password =>
+            (about: "password hashes for htpasswd files, auth-type sqlite and SQL databases")
+            (@setting SubcommandRequiredElseHelp)
+            (@subcommand hash =>
+                (about: "print a hash of a password, with the scheme of [password-hash]"))
+            (@subcommand migrate =>
+                (about: "list the hashes that are replaced at the next login, with rehash = true"))
+        )
+        (@subcommand selftest =>
+            (about: "run protocol checks and a load test against the configured server, in memory")
+            (@arg CLIENTS: --clients +takes_value "concurrent clients in the load test (8)")
+            (@arg REQUESTS: --requests +takes_value "requests per client in the load test (100)"))
     )
     .get_matches();
 
-    if matches.is_present("DBG") {
-        use env_logger::Env;
-        let level = "webdav_server=debug,webdav_handler=debug";
-        env_logger::Builder::from_env(Env::default().default_filter_or(level)).init();
-    } else {
-        env_logger::init();
+    if let Some(path) = matches.value_of("VERIFY") {
+        match auditlog::verify(path) {
+            Ok(lines) => println!("{}: {} lines, the chain is intact", path, lines),
+            Err(e) => {
+                eprintln!("{}: {}", PROGNAME, e);
+                exit(1);
+            },
+        }
+        exit(0);
     }
 
     let port = matches.value_of("PORT");
     let cfg = matches.value_of("CFG").unwrap_or("/etc/webdav-server.toml");
 
     // read config.
-    let mut config = match config::read(cfg.clone()) {
-        Err(e) => {
-            eprintln!("{}: {}: {}", PROGNAME, cfg, e);
+    let config = load_config(cfg, port, false).unwrap_or_else(|e| {
+        eprintln!("{}: {}", PROGNAME, e);
+        exit(1);
+    });
+
+    if let Some(matches) = matches.subcommand_matches("user") {
+        if let Err(e) = user_command(&config, matches) {
+            eprintln!("{}: {}", PROGNAME, e);
             exit(1);
-        },
-        Ok(c) => c,
-    };
-    config::check(cfg.clone(), &config);
+        }
+        exit(0);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("password") {
+        if let Err(e) = password_command(&config, matches) {
+            eprintln!("{}: {}", PROGNAME, e);
+            exit(1);
+        }
+        exit(0);
+    }
 
-    // build routes.
-    if let Err(e) = config::build_routes(cfg.clone(), &mut config) {
-        eprintln!("{}: {}: {}", PROGNAME, cfg, e);
+    // logging: stderr, syslog or journald.
+    let level = matches
+        .is_present("DBG")
+        .then_some("webdav_server=debug,webdav_handler=debug");
+    if let Err(e) = logger::init(&config.log, level) {
+        eprintln!("{}: {}", PROGNAME, e);
         exit(1);
     }
 
-    if let Some(port) = port {
-        let localhosts = vec![
-            ("127.0.0.1:".to_string() + port).parse::<SocketAddr>().unwrap(),
-            ("[::]:".to_string() + port).parse::<SocketAddr>().unwrap(),
-        ];
-        config.server.listen = config::OneOrManyAddr::Many(localhosts);
+    if let Some(matches) = matches.subcommand_matches("selftest") {
+        match selftest_command(cfg, matches) {
+            Ok(true) => exit(0),
+            Ok(false) => exit(1),
+            Err(e) => {
+                eprintln!("{}: {}", PROGNAME, e);
+                exit(1);
+            },
+        }
     }
-    let config = Arc::new(config);
 
-    // set cache timeouts.
-    if let Some(timeout) = config.unix.cache_timeout {
-        cache::cached::set_pwcache_timeout(timeout);
+    if matches.is_present("CHECK") {
+        let problems = checkconfig::check(&config);
+        for problem in &problems {
+            eprintln!("{}: {}: {}", PROGNAME, cfg, problem);
+        }
+        if !problems.is_empty() {
+            exit(1);
+        }
+        println!("{}: configuration OK", cfg);
+        exit(0);
     }
+    let config = Arc::new(config);
 
     // resolve addresses.
-    let addrs = config.server.listen.clone().to_socket_addrs().unwrap_or_else(|e| {
+    let tls_addrs = config.server.tls_listen.clone().to_socket_addrs().unwrap_or_else(|e| {
         eprintln!("{}: {}: [server] listen: {:?}", PROGNAME, cfg, e);
         exit(1);
     });
-    let tls_addrs = config.server.tls_listen.clone().to_socket_addrs().unwrap_or_else(|e| {
-        eprintln!("{}: {}: [server] listen: {:?}", PROGNAME, cfg, e);
+    #[cfg(feature = "quic")]
+    let quic_addrs = config.server.quic_listen.clone().to_socket_addrs().unwrap_or_else(|e| {
+        eprintln!("{}: {}: [server] quic_listen: {:?}", PROGNAME, cfg, e);
+        exit(1);
+    });
+
+    // sockets passed in by systemd.
+    let activated = systemd::listen_fds().unwrap_or_else(|e| {
+        eprintln!("{}: systemd socket activation: {}", PROGNAME, e);
         exit(1);
     });
 
     // initialize auth early.
     let auth = auth::Auth::new(config.clone())?;
 
+    // open log files while we still can.
+    if let Some(ref path) = config.log.auth_failures {
+        if let Err(e) = authlog::open(path) {
+            eprintln!("{}: {}", PROGNAME, e);
+            exit(1);
+        }
+    }
+    if let Some(ref path) = config.log.access {
+        let format = config.log.access_format.unwrap_or(config::AccessFormat::Json);
+        if let Err(e) = accesslog::open(path, format) {
+            eprintln!("{}: {}", PROGNAME, e);
+            exit(1);
+        }
+    }
+    if let Some(ref path) = config.log.audit {
+        if let Err(e) = auditlog::open(path, config.log.audit_chain) {
+            eprintln!("{}: {}", PROGNAME, e);
+            exit(1);
+        }
+    }
+
     // start tokio runtime and initialize the rest from within the runtime.
+    // By default, a worker per cpu, and blocking and metadata threads
+    // in proportion.
+    let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let workers = config.runtime.worker_threads.unwrap_or(cpus).max(1);
+    let max_blocking = config.runtime.max_blocking.unwrap_or((workers * 32).clamp(64, 512));
+    userfs::set_metadata_threads(config.runtime.metadata_threads.unwrap_or(workers * 8));
+    userfs::set_listing_fanout(config.runtime.listing_fanout.unwrap_or(1).max(1));
+    let pool_idle = config.runtime.setuid_pool_idle.unwrap_or(60);
+    suidpool::configure(config.runtime.setuid_pool.unwrap_or(0), pool_idle);
     let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(workers)
+        .max_blocking_threads(max_blocking.max(1))
         .enable_io()
         .enable_time()
         .build()?;
 
     rt.block_on(async move {
+        // tracing spans, sent to an OpenTelemetry collector.
+        if let Err(e) = otlp::start(&config.tracing) {
+            eprintln!("{}: {}", PROGNAME, e);
+            exit(1);
+        }
+
         // build servers (one for each listen address).
-        let dav_server = Server::new(config.clone(), auth);
+        let acme = config.acme.as_ref().map(acme::Acme::new);
+        let dav_server = Server::new(config.clone(), auth, acme.clone());
         let mut servers = Vec::new();
+        let proxy_protocol = config.server.proxy_protocol.unwrap_or(false);
         let mut tls_servers = Vec::new();
+        let (shutdown_tx, shutdown) = watch::channel(false);
+
+        // Listening sockets, from the config and from systemd.
+        let mut plain = Vec::new();
+        let mut secure = Vec::new();
+        for listen in config.server.listen.to_vec() {
+            let (desc, listener) = open_listener(&listen, &config.server);
+            plain.push((desc, listener, dav_server.clone(), proxy_protocol));
+        }
+        for sockaddr in tls_addrs {
+            let listener = open_tcp_listener(sockaddr);
+            let desc = format!("https://{:?}", sockaddr);
+            secure.push((desc, listener, dav_server.clone(), proxy_protocol));
+        }
+        for listen in &config.listen {
+            let server = dav_server.with_listen(listen);
+            let proxy_protocol = listen.proxy_protocol.unwrap_or(proxy_protocol);
+            for addr in listen.address.to_vec() {
+                match addr {
+                    // checked in config::check: no TLS on unix sockets.
+                    ListenAddr::Tcp(sockaddr) if listen.tls => {
+                        let listener = open_tcp_listener(sockaddr);
+                        let desc = format!("https://{:?}", sockaddr);
+                        secure.push((desc, listener, server.clone(), proxy_protocol));
+                    },
+                    addr => {
+                        let (desc, listener) = open_listener(&addr, &config.server);
+                        plain.push((desc, listener, server.clone(), proxy_protocol));
+                    },
+                }
+            }
+        }
+        for socket in activated {
+            match socket.listener {
+                systemd::Listener::Tcp(listener) => {
+                    let listener = tokio::net::TcpListener::from_std(listener)?;
+                    if socket.tls {
+                        let desc = format!("https://{} (systemd)", socket.addr);
+                        secure.push((desc, listener, dav_server.clone(), proxy_protocol));
+                    } else {
+                        let desc = format!("http://{} (systemd)", socket.addr);
+                        plain.push((desc, Listener::Tcp(listener), dav_server.clone(), proxy_protocol));
+                    }
+                },
+                systemd::Listener::Unix(_) if socket.tls => {
+                    eprintln!("{}: systemd socket {}: no TLS on unix sockets", PROGNAME, socket.name);
+                    exit(1);
+                },
+                systemd::Listener::Unix(listener) => {
+                    let listener = tokio::net::UnixListener::from_std(listener)?;
+                    let desc = format!("{} (systemd)", socket.addr);
+                    plain.push((desc, Listener::Unix(listener), dav_server.clone(), proxy_protocol));
+                },
+            }
+        }
+
+        let certs = if !secure.is_empty() {
+            let certs = tls::Certs::load(&config)?;
+            if certs.is_empty() && acme.is_none() {
+                eprintln!("{}: TLS listener, but no certificate configured", PROGNAME);
+                exit(1);
+            }
+            Some(certs)
+        } else {
+            None
+        };
 
         // Plaintext servers.
-        for sockaddr in addrs {
-            let listener = match make_listener(sockaddr) {
-                Ok(l) => l,
-                Err(e) => {
-                    eprintln!("{}: listener on {:?}: {}", PROGNAME, &sockaddr, e);
-                    exit(1);
+        let h2c = config.server.h2c.unwrap_or(false);
+        let (header_timeout, idle_timeout) = conn_timeouts(&config.server);
+        for (desc, listener, dav_server, proxy_protocol) in plain {
+            let shutdown = shutdown.clone();
+            let server = match listener {
+                Listener::Tcp(l) => {
+                    let incoming = proxy::Incoming::tcp(l, proxy_protocol).idle_timeout(idle_timeout);
+                    serve_http(incoming, dav_server, h2c, header_timeout, shutdown).boxed()
+                },
+                Listener::Unix(l) => {
+                    let incoming = proxy::Incoming::unix(l, proxy_protocol).idle_timeout(idle_timeout);
+                    serve_http(incoming, dav_server, h2c, header_timeout, shutdown).boxed()
                 },
             };
-            let dav_server = dav_server.clone();
-            let make_service = make_service_fn(move |socket: &AddrStream| {
-                let dav_server = dav_server.clone();
-                let remote_addr = socket.remote_addr();
-                async move {
-                    let func = move |req| {
-                        let dav_server = dav_server.clone();
-                        async move { dav_server.route(req, remote_addr).await }
-                    };
-                    Ok::<_, hyper::Error>(service_fn(func))
-                }
-            });
-            let incoming = AddrIncoming::from_listener(listener)?;
-            let server = hyper::Server::builder(incoming);
-            println!("Listening on http://{:?}", sockaddr);
-
-            servers.push(async move {
-                if let Err(e) = server.serve(make_service).await {
-                    eprintln!("{}: server error: {}", PROGNAME, e);
-                    exit(1);
-                }
-            });
+            println!("Listening on {}", desc);
+            servers.push(server);
         }
 
         // TLS servers.
-        for sockaddr in tls_addrs {
-            let listener = make_listener(sockaddr).unwrap_or_else(|e| {
-                eprintln!("{}: listener on {:?}: {}", PROGNAME, &sockaddr, e);
-                exit(1);
-            });
-            let dav_server = dav_server.clone();
-            let tls_config = tls_config(&config.server)?;
-            let make_service = make_service_fn(move |stream: &TlsStream<AddrStream>| {
+        for (desc, listener, dav_server, proxy_protocol) in secure {
+            let tls_config = tls_config(&config, certs.as_ref().unwrap(), acme.as_deref())?;
+            let make_service = make_service_fn(move |stream: &TlsStream<proxy::Conn<TcpStream>>| {
                 let dav_server = dav_server.clone();
-                let remote_addr = stream.get_ref().0.remote_addr();
+                let (conn, session) = stream.get_ref();
+                let remote_addr = conn.remote_addr();
+                let conn_limits = dav_server.new_connection(remote_addr);
+                let busy = conn.busy();
+                let cert_user = session
+                    .get_peer_certificates()
+                    .and_then(|certs| tls::client_cert_user(&dav_server.config().server, &certs))
+                    .filter(|_| dav_server.scheme_allowed(AuthScheme::ClientCert));
                 async move {
-                    let func = move |req| {
+                    let func = move |mut req: HttpRequest| {
                         let dav_server = dav_server.clone();
-                        async move { dav_server.route(req, remote_addr).await }
+                        if let Some(ref user) = cert_user {
+                            req.extensions_mut().insert(auth::ClientCertUser(user.clone()));
+                        }
+                        req.extensions_mut().insert(conn_limits.clone());
+                        req.extensions_mut().insert(tls::Https);
+                        let busy = busy.enter();
+                        async move {
+                            let _busy = busy;
+                            dav_server.route(req, remote_addr).await
+                        }
                     };
                     Ok::<_, hyper::Error>(service_fn(func))
                 }
@@ -514,7 +348,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let master_listen_fd = listener.as_raw_fd();
             std::mem::forget(listener);
 
-            println!("Listening on http://{:?}", sockaddr);
+            let http2 = config.server.http2.unwrap_or(true);
+            let (header_timeout, idle_timeout) = conn_timeouts(&config.server);
+            let shutdown = shutdown.clone();
+            println!("Listening on {}", desc);
             tls_servers.push(async move {
                 loop {
                     // reuse the incoming socket after the server exits.
@@ -534,36 +371,131 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             break;
                         }
                     };
-                    let a_incoming = match AddrIncoming::from_listener(listener) {
-                        Ok(a) => a,
-                        Err(e) => {
-                            eprintln!("{}: server error: new AddrIncoming: {}", PROGNAME, e);
-                            break;
-                        }
-                    };
-                    let incoming = TlsListener::new(tls_config.clone(), a_incoming);
-                    let server = hyper::Server::builder(incoming);
-                    if let Err(e) = server.serve(make_service.clone()).await {
-                        eprintln!("{}: server error: {} (retrying)", PROGNAME, e);
+                    let incoming = proxy::Incoming::tcp(listener, proxy_protocol).idle_timeout(idle_timeout);
+                    let incoming = TlsListener::new(tls_config.clone(), incoming);
+                    let mut server = hyper::Server::builder(incoming)
+                        .http1_only(!http2)
+                        .http2_adaptive_window(true);
+                    if let Some(timeout) = header_timeout {
+                        server = server.http1_header_read_timeout(timeout);
+                    }
+                    let server = server
+                        .serve(make_service.clone())
+                        .with_graceful_shutdown(shutdown_signal(shutdown.clone()));
+                    match server.await {
+                        Ok(()) => break,
+                        Err(e) => eprintln!("{}: server error: {} (retrying)", PROGNAME, e),
                     }
                 }
             });
         }
 
-        // drop privs.
-        match (&config.server.uid, &config.server.gid) {
-            (&Some(uid), &Some(gid)) => {
-                if !suid::have_suid_privs() {
-                    eprintln!(
-                        "{}: insufficent priviliges to switch uid/gid (not root).",
-                        PROGNAME
-                    );
+        // Admin listener, for the metrics and health checks.
+        let mut admin_servers = Vec::new();
+        for sockaddr in config.admin.listen.to_vec() {
+            let listener = open_tcp_listener(sockaddr);
+            let incoming = hyper::server::conn::AddrIncoming::from_listener(listener)?;
+            let server = dav_server.clone();
+            let make_service = make_service_fn(move |conn: &AddrStream| {
+                let server = server.clone();
+                let peer = conn.remote_addr().ip();
+                let func = move |req| {
+                    let (config, auth) = server.live();
+                    admin::handle(req, config, auth, peer)
+                };
+                async move { Ok::<_, hyper::Error>(service_fn(func)) }
+            });
+            let server = hyper::Server::builder(incoming)
+                .serve(make_service)
+                .with_graceful_shutdown(shutdown_signal(shutdown.clone()));
+            println!("Listening on http://{:?} (admin)", sockaddr);
+            admin_servers.push(server);
+        }
+
+        // Admin API listener.
+        let mut api_servers = Vec::new();
+        for sockaddr in config.admin.api_listen.to_vec() {
+            let listener = open_tcp_listener(sockaddr);
+            let incoming = hyper::server::conn::AddrIncoming::from_listener(listener)?;
+            let server = dav_server.clone();
+            let make_service = make_service_fn(move |conn: &AddrStream| {
+                let server = server.clone();
+                let peer = conn.remote_addr().ip();
+                let func = move |req| admin::api(req, server.clone(), peer);
+                async move { Ok::<_, hyper::Error>(service_fn(func)) }
+            });
+            let server = hyper::Server::builder(incoming)
+                .serve(make_service)
+                .with_graceful_shutdown(shutdown_signal(shutdown.clone()));
+            println!("Listening on http://{:?} (admin api)", sockaddr);
+            usage::enable();
+            api_servers.push(server);
+        }
+
+        // QUIC servers.
+        #[cfg(feature = "quic")]
+        let mut quic_servers = Vec::new();
+        #[cfg(feature = "quic")]
+        for sockaddr in quic_addrs {
+            let endpoint = quic::endpoint(&config.server, sockaddr).unwrap_or_else(|e| {
+                eprintln!("{}: quic listener on {:?}: {}", PROGNAME, &sockaddr, e);
+                exit(1);
+            });
+            println!("Listening on https://{:?} (quic)", sockaddr);
+            quic_servers.push(quic::serve(endpoint, dav_server.clone(), shutdown.clone()));
+        }
+
+        // Open the databases, while we can still write to them.
+        #[cfg(feature = "sqlite")]
+        for (section, location) in config.locations() {
+            if let Some(ref db) = location.lock_db {
+                if let Err(e) = lockdb::open(db) {
+                    eprintln!("{}: {}: lock-db {}: {}", PROGNAME, section, db, e);
                     exit(1);
                 }
-                let keep_privs = config.location.iter().any(|l| l.setuid);
-                proc_switch_ugid(uid, gid, keep_privs);
-            },
-            _ => {},
+            }
+            if let Some(ref db) = location.sync_db {
+                if let Err(e) = syncdb::open(db) {
+                    eprintln!("{}: {}: sync-db {}: {}", PROGNAME, section, db, e);
+                    exit(1);
+                }
+            }
+            if let Some(ref db) = location.fulltext_index {
+                if let Err(e) = fulltext::open(db) {
+                    eprintln!("{}: {}: fulltext-index {}: {}", PROGNAME, section, db, e);
+                    exit(1);
+                }
+            }
+        }
+
+        // keep only the capabilities that are needed, in all threads.
+        let mut keep = config.sandbox.capabilities.clone();
+        if let (Some(keep), Some(_)) = (keep.as_mut(), config.server.uid) {
+            // to switch the uid, just below.
+            keep.extend([Capability::Setuid, Capability::Setgid]);
+        }
+        if let Err(e) = caps::apply(keep.as_deref(), config.sandbox.no_new_privs) {
+            eprintln!("{}: {}", PROGNAME, e);
+            exit(1);
+        }
+
+        // drop privs.
+        if let (&Some(uid), &Some(gid)) = (&config.server.uid, &config.server.gid) {
+            if !suid::have_suid_privs() {
+                eprintln!(
+                    "{}: insufficent priviliges to switch uid/gid (not root).",
+                    PROGNAME
+                );
+                exit(1);
+            }
+            let keep_privs = config.any_setuid();
+            proc_switch_ugid(uid, gid, keep_privs);
+        }
+
+        // from here on only the system calls of serving requests.
+        if let Err(e) = seccomp::apply(config.sandbox.seccomp.unwrap_or(Seccomp::Off), false) {
+            eprintln!("{}: {}", PROGNAME, e);
+            exit(1);
         }
 
         // spawn all servers, and wait for them to finish.
@@ -574,46 +506,342 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         for server in tls_servers.drain(..) {
             tasks.push(tokio::spawn(server));
         }
-        for task in tasks.drain(..) {
-            let _ = task.await;
+        for server in admin_servers.drain(..) {
+            tasks.push(tokio::spawn(async move {
+                if let Err(e) = server.await {
+                    eprintln!("{}: admin server error: {}", PROGNAME, e);
+                }
+            }));
+        }
+        for server in api_servers.drain(..) {
+            tasks.push(tokio::spawn(async move {
+                if let Err(e) = server.await {
+                    eprintln!("{}: admin api server error: {}", PROGNAME, e);
+                }
+            }));
+        }
+        #[cfg(feature = "quic")]
+        for server in quic_servers.drain(..) {
+            tasks.push(tokio::spawn(server));
+        }
+        if let Some(acme) = acme {
+            tokio::spawn(acme.run());
+        }
+        if let Some(certs) = certs {
+            tokio::spawn(certs.watch());
+        }
+        tokio::spawn(janitor::run(dav_server.clone()));
+        tokio::spawn(reload_on_sighup(cfg.to_string(), port.map(String::from), dav_server));
+        tokio::spawn(reopen_on_sigusr1());
+        if config.locations().any(|(_, l)| l.watch) {
+            tokio::spawn(inotify::run());
+        }
+        health::set_listening(true);
+
+        // On SIGTERM or SIGINT, stop accepting connections, and give the
+        // requests in flight drain_timeout seconds to finish.
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            _ = sigterm.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+        println!("Shutting down");
+        health::set_listening(false);
+        let _ = shutdown_tx.send(true);
+        let drain_timeout = Duration::from_secs(config.server.drain_timeout.unwrap_or(30));
+        let drain = futures::future::join_all(tasks.drain(..));
+        if tokio::time::timeout(drain_timeout, drain).await.is_err() {
+            eprintln!("{}: drain timeout, closing active connections", PROGNAME);
         }
 
         Ok::<_, Box<dyn std::error::Error>>(())
     })
 }
 
-// Clones a http request with an empty body.
-fn clone_httpreq(req: &HttpRequest) -> HttpRequest {
-    let mut builder = http::Request::builder()
-        .method(req.method().clone())
-        .uri(req.uri().clone())
-        .version(req.version().clone());
-    for (name, value) in req.headers().iter() {
-        builder = builder.header(name, value);
+// Read and check the config file, and build the routes.
+// On a reload the listeners are not checked: they only change on a restart,
+// and the LISTEN_FDS of socket activation are gone by then.
+fn load_config(cfg: &str, port: Option<&str>, reload: bool) -> Result<config::Config, String> {
+    let mut config = config::read(cfg).map_err(|e| format!("{}: {}", cfg, e))?;
+    routefile::load(&mut config).map_err(|e| format!("{}: {}", cfg, e))?;
+    config::validate(&config).map_err(|e| format!("{}: {}", cfg, e))?;
+    if !reload {
+        config::validate_listeners(&config).map_err(|e| format!("{}: {}", cfg, e))?;
     }
-    builder.body(hyper::Body::empty()).unwrap()
+    // the binary has no middleware of its own.
+    middleware::check(&config, &Default::default()).map_err(|e| format!("{}: {}", cfg, e))?;
+    config::build_routes(cfg, &mut config).map_err(|e| format!("{}: {}", cfg, e))?;
+
+    if let Some(port) = port {
+        let localhosts = vec![
+            ("127.0.0.1:".to_string() + port).parse::<SocketAddr>().unwrap(),
+            ("[::]:".to_string() + port).parse::<SocketAddr>().unwrap(),
+        ];
+        config.server.listen = config::OneOrMany::Many(localhosts.into_iter().map(ListenAddr::Tcp).collect());
+    }
+    Ok(config)
 }
 
-fn expand_directory(dir: &str, pwd: Option<&Arc<unixuser::User>>) -> Result<String, StatusCode> {
-    // If it doesn't start with "~", skip.
-    if !dir.starts_with("~") {
-        return Ok(dir.to_string());
+// webdav-server selftest: true if all checks passed.
+fn selftest_command(cfg: &str, matches: &clap::ArgMatches) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut opts = selftest::Options::default();
+    if let Some(clients) = matches.value_of("CLIENTS") {
+        opts.clients = clients.parse().map_err(|_| format!("--clients {}: not a number", clients))?;
     }
-    // ~whatever doesn't work.
-    if dir.len() > 1 && !dir.starts_with("~/") {
-        debug!("expand_directory: rejecting {}", dir);
-        return Err(StatusCode::NOT_FOUND);
+    if let Some(requests) = matches.value_of("REQUESTS") {
+        opts.requests = requests.parse().map_err(|_| format!("--requests {}: not a number", requests))?;
     }
-    // must have a directory, and that dir must be UTF-8.
-    let pwd = match pwd {
-        Some(pwd) => pwd,
-        None => {
-            debug!("expand_directory: cannot expand {}: no account", dir);
-            return Err(StatusCode::NOT_FOUND);
+    let config = Arc::new(selftest::config(cfg)?);
+    let auth = auth::Auth::new(config.clone())?;
+    let server = webdav_server::Server::new(config, auth, None);
+    let rt = tokio::runtime::Runtime::new()?;
+    Ok(rt.block_on(selftest::run(server, &opts))?)
+}
+
+// webdav-server user add/passwd/del/list.
+#[cfg(feature = "sqlite")]
+fn user_command(config: &config::Config, matches: &clap::ArgMatches) -> io::Result<()> {
+    let path = config.sqlite.path();
+    let db = userdb::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    match matches.subcommand() {
+        ("add", Some(m)) => {
+            let name = m.value_of("NAME").unwrap();
+            userdb::check_name(name)?;
+            db.add(name, &passhash::hash(&config.passwords, &read_password()?)?)?;
+            println!("{}: added {}", path, name);
+        },
+        ("passwd", Some(m)) => {
+            let name = m.value_of("NAME").unwrap();
+            db.set_password(name, &passhash::hash(&config.passwords, &read_password()?)?)?;
+            println!("{}: changed the password of {}", path, name);
+        },
+        ("del", Some(m)) => {
+            let name = m.value_of("NAME").unwrap();
+            db.remove(name)?;
+            println!("{}: removed {}", path, name);
+        },
+        ("list", _) => {
+            for (name, changed) in db.list()? {
+                let changed = time::at_utc(time::Timespec::new(changed, 0));
+                println!("{}\t{}", name, changed.rfc3339());
+            }
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn user_command(_config: &config::Config, _matches: &clap::ArgMatches) -> io::Result<()> {
+    Err(io::Error::other("user: not built with the sqlite feature"))
+}
+
+// webdav-server password hash/migrate.
+fn password_command(config: &config::Config, matches: &clap::ArgMatches) -> io::Result<()> {
+    match matches.subcommand() {
+        ("hash", _) => println!("{}", passhash::hash(&config.passwords, &read_password()?)?),
+        ("migrate", _) => {
+            // (backend, user, hash) of the htpasswd files and the user database.
+            let mut hashes = Vec::new();
+            let mut sections: Vec<_> = config.htpasswd.iter().collect();
+            sections.sort_by_key(|(name, _)| name.as_str());
+            for (name, section) in sections {
+                let file = section.htpasswd.as_str();
+                let data = std::fs::read_to_string(file)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file, e)))?;
+                let lines = data.lines().map(|l| l.trim()).filter(|l| !l.starts_with('#'));
+                for (user, hash) in lines.filter_map(|l| l.split_once(':')) {
+                    let hash = hash.split(':').next().unwrap_or("");
+                    hashes.push((format!("htpasswd.{}", name), user.to_string(), hash.to_string()));
+                }
+            }
+            #[cfg(feature = "sqlite")]
+            if config.uses_sqlite() {
+                let db = userdb::open(config.sqlite.path())?;
+                for (user, _) in db.list()? {
+                    let hash = db.password(&user)?.unwrap_or_default();
+                    hashes.push(("sqlite".to_string(), user, hash));
+                }
+            }
+            let old: Vec<_> = hashes
+                .iter()
+                .filter(|(_, _, hash)| passhash::needs_rehash(&config.passwords, hash))
+                .collect();
+            for (backend, user, hash) in &old {
+                println!("{}\t{}\t{}", backend, user, passhash::scheme(hash));
+            }
+            eprintln!("{} of {} hashes are not in the configured scheme.", old.len(), hashes.len());
+            if !old.is_empty() && !config.passwords.rehash {
+                eprintln!("Set rehash = true in [password-hash] to replace them at the next login.");
+            }
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+// The new password: twice, without echo, from a terminal. Or one line
+// from stdin, for scripts.
+fn read_password() -> io::Result<String> {
+    use std::io::BufRead;
+
+    let read_line = || {
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        Ok::<_, io::Error>(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+    };
+    let password = if unsafe { libc::isatty(0) } == 1 {
+        let mut term: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(0, &mut term) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut noecho = term;
+        noecho.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(0, libc::TCSANOW, &noecho) };
+        let prompt = |msg: &str| {
+            eprint!("{}", msg);
+            let line = read_line();
+            eprintln!();
+            line
+        };
+        let res = prompt("New password: ").and_then(|p1| prompt("Again: ").map(|p2| (p1, p2)));
+        unsafe { libc::tcsetattr(0, libc::TCSANOW, &term) };
+        let (p1, p2) = res?;
+        if p1 != p2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the passwords are not the same"));
+        }
+        p1
+    } else {
+        read_line()?
+    };
+    if password.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty password"));
+    }
+    Ok(password)
+}
+
+// Re-read the config file on SIGHUP. Settings that need a restart are
+// reported and keep their old value.
+// On SIGUSR1, reopen the log files (after they were rotated).
+async fn reopen_on_sigusr1() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut usr1 = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}: SIGUSR1 handler: {}", PROGNAME, e);
+            return;
         },
     };
-    let homedir = pwd.dir.to_str().ok_or(StatusCode::NOT_FOUND)?;
-    Ok(format!("{}/{}", homedir, &dir[1..]))
+    while usr1.recv().await.is_some() {
+        for res in [accesslog::reopen(), authlog::reopen(), auditlog::reopen()] {
+            if let Err(e) = res {
+                eprintln!("{}: reopen: {}", PROGNAME, e);
+            }
+        }
+    }
+}
+
+async fn reload_on_sighup(cfg: String, port: Option<String>, server: Server) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}: SIGHUP handler: {}", PROGNAME, e);
+            return;
+        },
+    };
+    while hangup.recv().await.is_some() {
+        let config = match load_config(&cfg, port.as_deref(), true) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}: reload: {}", PROGNAME, e);
+                continue;
+            },
+        };
+        let skipped = match server.reload(config) {
+            Ok(skipped) => skipped,
+            Err(e) => {
+                eprintln!("{}: reload: {}: {}", PROGNAME, cfg, e);
+                continue;
+            },
+        };
+        for section in skipped {
+            eprintln!("{}: reload: {}: {}: changes need a restart", PROGNAME, cfg, section);
+        }
+        println!("Reloaded {}", cfg);
+    }
+}
+
+// Serve plain HTTP.
+async fn serve_http<S>(
+    incoming: proxy::Incoming<S>,
+    dav_server: Server,
+    h2c: bool,
+    header_timeout: Option<Duration>,
+    shutdown: watch::Receiver<bool>,
+) where
+    S: AsyncRead + AsyncWrite + Socket + Unpin + Send + 'static,
+{
+    let make_service = make_service_fn(move |conn: &proxy::Conn<S>| {
+        let dav_server = dav_server.clone();
+        let remote_addr = conn.remote_addr();
+        let conn_limits = dav_server.new_connection(remote_addr);
+        let busy = conn.busy();
+        let sendfile = conn.sendfile();
+        async move {
+            let func = move |mut req: HttpRequest| {
+                let dav_server = dav_server.clone();
+                req.extensions_mut().insert(conn_limits.clone());
+                if let Some(queue) = sendfile.as_ref().filter(|_| req.version() < hyper::Version::HTTP_2) {
+                    req.extensions_mut().insert(queue.clone());
+                }
+                let busy = busy.enter();
+                async move {
+                    let _busy = busy;
+                    dav_server.route(req, remote_addr).await
+                }
+            };
+            Ok::<_, hyper::Error>(service_fn(func))
+        }
+    });
+    let mut server = hyper::Server::builder(incoming)
+        .http1_only(!h2c)
+        .http2_adaptive_window(true);
+    if let Some(timeout) = header_timeout {
+        server = server.http1_header_read_timeout(timeout);
+    }
+    let server = server
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown_signal(shutdown));
+    if let Err(e) = server.await {
+        eprintln!("{}: server error: {}", PROGNAME, e);
+        exit(1);
+    }
+}
+
+// Open a plaintext listener, exit on error.
+fn open_listener(addr: &ListenAddr, cfg: &config::Server) -> (String, Listener) {
+    match addr {
+        ListenAddr::Tcp(sockaddr) => {
+            let listener = open_tcp_listener(*sockaddr);
+            (format!("http://{:?}", sockaddr), Listener::Tcp(listener))
+        },
+        ListenAddr::Unix(path) => {
+            let listener = make_unix_listener(path, cfg).unwrap_or_else(|e| {
+                eprintln!("{}: listener on {}: {}", PROGNAME, path, e);
+                exit(1);
+            });
+            (format!("unix:{}", path), Listener::Unix(listener))
+        },
+    }
+}
+
+// Open a TCP listener, exit on error.
+fn open_tcp_listener(sockaddr: SocketAddr) -> tokio::net::TcpListener {
+    make_listener(sockaddr).unwrap_or_else(|e| {
+        eprintln!("{}: listener on {:?}: {}", PROGNAME, &sockaddr, e);
+        exit(1);
+    })
 }
 
 // Make a new TcpListener, and if it's a V6 listener, set the
@@ -633,3 +861,44 @@ fn make_listener(addr: SocketAddr) -> io::Result<tokio::net::TcpListener> {
     let listener: std::net::TcpListener = s.into();
     tokio::net::TcpListener::from_std(listener)
 }
+
+// Make a new UnixListener, and set the permissions and ownership of the socket.
+fn make_unix_listener(path: &str, cfg: &config::Server) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use nix::unistd::{Gid, Group, Uid};
+
+    // remove a stale socket from a previous run.
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+
+    if let Some(ref mode) = cfg.unix_socket_mode {
+        // checked in config::check.
+        let mode = u32::from_str_radix(mode, 8).unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    let gid = match cfg.unix_socket_group {
+        Some(ref group) => {
+            match group.parse::<u32>() {
+                Ok(gid) => Some(gid),
+                Err(_) => {
+                    let group = Group::from_name(group)
+                        .map_err(|e| io::Error::other(e.to_string()))?
+                        .ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::NotFound, format!("unknown group {}", group))
+                        })?;
+                    Some(group.gid.as_raw())
+                },
+            }
+        },
+        None => cfg.gid,
+    };
+    if cfg.uid.is_some() || gid.is_some() {
+        nix::unistd::chown(path, cfg.uid.map(Uid::from_raw), gid.map(Gid::from_raw))
+            .map_err(|e| io::Error::other(format!("chown: {}", e)))?;
+    }
+    Ok(listener)
+}
@@ -0,0 +1,37 @@
+//
+// In-memory filesystems, for handler = "mem".
+//
+// The data lives as long as the server process does. There is one
+// filesystem per (expanded) directory, which is only used as a name, so
+// with "$user" in it every user gets their own.
+//
+// If mem-size is set, writes that would make the combined size of the files
+// larger than that fail with 507 Insufficient Storage.
+//
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use webdav_handler::fs::DavFileSystem;
+use webdav_handler::memfs::MemFs;
+
+use crate::softquota::{QuotaFs, Usage};
+
+lazy_static::lazy_static! {
+    static ref FILESYSTEMS: Mutex<HashMap<String, Box<dyn DavFileSystem>>> = Mutex::new(HashMap::new());
+}
+
+/// The in-memory filesystem called "name", created on first use.
+/// `max_size` is in bytes, and only used when the filesystem is created.
+pub fn get(name: &str, max_size: Option<u64>) -> Box<dyn DavFileSystem> {
+    let mut filesystems = FILESYSTEMS.lock().unwrap();
+    let fs = filesystems.entry(name.to_string()).or_insert_with(|| {
+        match max_size {
+            Some(max) => {
+                let usage = Arc::new(Usage::default());
+                QuotaFs::new(MemFs::new(), usage, max) as Box<dyn DavFileSystem>
+            },
+            None => MemFs::new() as Box<dyn DavFileSystem>,
+        }
+    });
+    fs.clone()
+}
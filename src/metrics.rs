@@ -0,0 +1,184 @@
+//
+// Prometheus metrics, served on /metrics of the [admin] listener.
+//
+// Everything is kept in a few global counters and histograms, and
+// rendered in the text exposition format on every scrape.
+//
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+lazy_static::lazy_static! {
+    static ref REQUESTS: Mutex<BTreeMap<(String, u16), u64>> = Mutex::new(BTreeMap::new());
+    static ref DURATIONS: Mutex<BTreeMap<String, Histogram>> = Mutex::new(BTreeMap::new());
+    static ref AUTH_DURATIONS: Mutex<BTreeMap<String, Histogram>> = Mutex::new(BTreeMap::new());
+}
+
+static BYTES_READ: AtomicU64 = AtomicU64::new(0);
+static BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS: AtomicI64 = AtomicI64::new(0);
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count:   u64,
+    sum:     f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bucket, le) in self.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if secs <= *le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn render(&self, out: &mut String, name: &str, label: &str, value: &str) {
+        for (count, le) in self.buckets.iter().zip(BUCKETS.iter()) {
+            let _ = writeln!(out, "{}_bucket{{{}=\"{}\",le=\"{}\"}} {}", name, label, value, le, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}=\"{}\",le=\"+Inf\"}} {}", name, label, value, self.count);
+        let _ = writeln!(out, "{}_sum{{{}=\"{}\"}} {}", name, label, value, self.sum);
+        let _ = writeln!(out, "{}_count{{{}=\"{}\"}} {}", name, label, value, self.count);
+    }
+}
+
+/// A request was answered.
+pub fn request(method: &str, status: u16, elapsed: Duration) {
+    *REQUESTS.lock().unwrap().entry((method.to_string(), status)).or_insert(0) += 1;
+    DURATIONS
+        .lock()
+        .unwrap()
+        .entry(method.to_string())
+        .or_default()
+        .observe(elapsed);
+}
+
+/// A password check by an authentication backend (not from the cache).
+pub fn auth(backend: &str, elapsed: Duration) {
+    AUTH_DURATIONS
+        .lock()
+        .unwrap()
+        .entry(backend.to_string())
+        .or_default()
+        .observe(elapsed);
+}
+
+/// Bytes read from and written to client connections.
+pub fn bytes_read(n: usize) {
+    BYTES_READ.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+pub fn bytes_written(n: usize) {
+    BYTES_WRITTEN.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+/// Counts an open connection, until it is dropped.
+pub struct Connection(());
+
+pub fn connection() -> Connection {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    Connection(())
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Name, help and type.
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// All metrics, in the Prometheus text format.
+pub fn render() -> String {
+    let mut out = String::new();
+
+    header(&mut out, "webdav_requests_total", "Requests, by method and status.", "counter");
+    for ((method, status), count) in REQUESTS.lock().unwrap().iter() {
+        let labels = format!("method=\"{}\",status=\"{}\"", escape(method), status);
+        let _ = writeln!(out, "webdav_requests_total{{{}}} {}", labels, count);
+    }
+
+    let name = "webdav_request_duration_seconds";
+    header(&mut out, name, "Time until the response starts, by method.", "histogram");
+    for (method, hist) in DURATIONS.lock().unwrap().iter() {
+        hist.render(&mut out, name, "method", &escape(method));
+    }
+
+    header(&mut out, "webdav_received_bytes_total", "Bytes received from clients.", "counter");
+    let _ = writeln!(out, "webdav_received_bytes_total {}", BYTES_READ.load(Ordering::Relaxed));
+    header(&mut out, "webdav_sent_bytes_total", "Bytes sent to clients.", "counter");
+    let _ = writeln!(out, "webdav_sent_bytes_total {}", BYTES_WRITTEN.load(Ordering::Relaxed));
+
+    header(&mut out, "webdav_connections", "Open client connections.", "gauge");
+    let _ = writeln!(out, "webdav_connections {}", CONNECTIONS.load(Ordering::Relaxed));
+
+    let name = "webdav_auth_duration_seconds";
+    header(&mut out, name, "Password checks by the backend, by backend.", "histogram");
+    for (backend, hist) in AUTH_DURATIONS.lock().unwrap().iter() {
+        hist.render(&mut out, name, "backend", &escape(backend));
+    }
+
+    header(&mut out, "webdav_cache_hits_total", "Cache hits, by cache.", "counter");
+    header(&mut out, "webdav_cache_misses_total", "Cache misses, by cache.", "counter");
+    let caches = [
+        ("auth", crate::cache::cached::authcache_stats()),
+        ("unixuser", crate::cache::cached::pwcache_stats()),
+    ];
+    for (cache, (hits, misses)) in caches.iter() {
+        let _ = writeln!(out, "webdav_cache_hits_total{{cache=\"{}\"}} {}", cache, hits);
+        let _ = writeln!(out, "webdav_cache_misses_total{{cache=\"{}\"}} {}", cache, misses);
+    }
+
+    let pools = crate::suidpool::stats();
+    let (threads, idle) = pools.iter().fold((0, 0), |(t, i), p| (t + p.threads, i + p.idle));
+    header(&mut out, "webdav_setuid_pool_users", "Users with a setuid pool.", "gauge");
+    let _ = writeln!(out, "webdav_setuid_pool_users {}", pools.len());
+    header(&mut out, "webdav_setuid_pool_threads", "Threads of the setuid pools, by state.", "gauge");
+    let _ = writeln!(out, "webdav_setuid_pool_threads{{state=\"busy\"}} {}", threads - idle);
+    let _ = writeln!(out, "webdav_setuid_pool_threads{{state=\"idle\"}} {}", idle);
+    header(&mut out, "webdav_setuid_pool_calls_total", "Calls run by the setuid pools.", "counter");
+    let _ = writeln!(out, "webdav_setuid_pool_calls_total {}", crate::suidpool::jobs());
+
+    #[cfg(feature = "sqlite")]
+    {
+        header(&mut out, "webdav_locks", "Locks held, in the lock databases.", "gauge");
+        let _ = writeln!(out, "webdav_locks {}", crate::lockdb::count());
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        request("PROPFIND", 207, Duration::from_millis(20));
+        request("PROPFIND", 207, Duration::from_secs(20));
+        let _conn = connection();
+        let out = render();
+        assert!(out.contains("webdav_requests_total{method=\"PROPFIND\",status=\"207\"} 2\n"));
+        assert!(out.contains("webdav_request_duration_seconds_bucket{method=\"PROPFIND\",le=\"0.025\"} 1\n"));
+        assert!(out.contains("webdav_request_duration_seconds_bucket{method=\"PROPFIND\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("webdav_connections 1\n"));
+    }
+}
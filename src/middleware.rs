@@ -0,0 +1,323 @@
+//
+// Middleware: hooks of a program that embeds the server.
+//
+// A Middleware is registered under a name with Builder::middleware, and
+// has four hooks, that do nothing unless they are implemented:
+//
+// - pre_dispatch: before the request is routed to a location. It can
+//   change the request (the path, headers), or answer it.
+// - auth: after authentication, with the user if there is one. An error
+//   status is the response.
+// - pre_write: before a change to the filesystem of a location. An error
+//   status fails that change.
+// - post_response: when the response is ready. It can change it, for
+//   example add headers.
+//
+// `middleware = [ ... ]` in [server] are the ones that run, in that
+// order; without it all of them, in the order they were registered. In a
+// location it is the list for the auth and pre_write hooks there.
+//
+// The hooks are called in the request, so they should be quick: slow
+// work (like sending an audit record) can be spawned as a task.
+//
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use futures::future::FutureExt;
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::config::{Config, Location};
+
+/// What a hook knows about the request.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method:    http::Method,
+    pub path:      String,
+    /// The authenticated user.
+    pub user:      Option<String>,
+    pub remote_ip: IpAddr,
+}
+
+/// A change to the filesystem. The paths are those below the location,
+/// %XX decoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Write<'a> {
+    /// A file is created or written.
+    File(&'a str),
+    Mkdir(&'a str),
+    Remove(&'a str),
+    Rename(&'a str, &'a str),
+    Copy(&'a str, &'a str),
+    /// Properties or timestamps.
+    Props(&'a str),
+}
+
+/// The hooks of a middleware.
+pub trait Middleware: Send + Sync {
+    /// Before routing. A response here is the answer to the request.
+    fn pre_dispatch(&self, _req: &mut http::Request<hyper::Body>) -> Option<http::Response<hyper::Body>> {
+        None
+    }
+
+    /// After authentication (`user` is None if there was none).
+    fn auth(&self, _req: &Request, _headers: &http::HeaderMap) -> Result<(), StatusCode> {
+        Ok(())
+    }
+
+    /// Before a change to the filesystem.
+    fn pre_write(&self, _req: &Request, _write: Write<'_>) -> Result<(), StatusCode> {
+        Ok(())
+    }
+
+    /// The response, before it is sent.
+    fn post_response(&self, _req: &Request, _resp: &mut http::response::Parts) {}
+}
+
+/// The middleware, by name, in the order they were registered.
+#[derive(Clone, Default)]
+pub struct Registry {
+    middleware: Vec<(String, Arc<dyn Middleware>)>,
+}
+
+impl Registry {
+    pub fn register(&mut self, name: &str, middleware: Arc<dyn Middleware>) {
+        self.middleware.retain(|(n, _)| n != name);
+        self.middleware.push((name.to_string(), middleware));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.middleware.is_empty()
+    }
+
+    // The middleware for a list of names, in that order.
+    fn chain(&self, names: Option<&Vec<String>>) -> Vec<Arc<dyn Middleware>> {
+        match names {
+            Some(names) => {
+                let find = |name: &String| self.middleware.iter().find(|(n, _)| n == name);
+                names.iter().filter_map(find).map(|(_, m)| m.clone()).collect()
+            },
+            None => self.middleware.iter().map(|(_, m)| m.clone()).collect(),
+        }
+    }
+
+    /// The middleware of the server.
+    pub fn server(&self, config: &Config) -> Vec<Arc<dyn Middleware>> {
+        self.chain(config.server.middleware.as_ref())
+    }
+
+    /// The middleware of a location.
+    pub fn location(&self, config: &Config, location: &Location) -> Vec<Arc<dyn Middleware>> {
+        self.chain(location.middleware.as_ref().or(config.server.middleware.as_ref()))
+    }
+}
+
+/// All names in the config must be registered.
+pub fn check(config: &Config, registry: &Registry) -> Result<(), String> {
+    let server = config.server.middleware.iter().map(|m| ("[server]".to_string(), m));
+    let locations = config.locations().filter_map(|(s, l)| l.middleware.as_ref().map(|m| (s, m)));
+    for (section, names) in server.chain(locations) {
+        for name in names {
+            if !registry.middleware.iter().any(|(n, _)| n == name) {
+                return Err(format!("{}: middleware {}: not registered", section, name));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn fs_error(status: StatusCode) -> FsError {
+    match status {
+        StatusCode::NOT_FOUND => FsError::NotFound,
+        StatusCode::PAYLOAD_TOO_LARGE => FsError::TooLarge,
+        StatusCode::INSUFFICIENT_STORAGE => FsError::InsufficientStorage,
+        StatusCode::INTERNAL_SERVER_ERROR => FsError::GeneralFailure,
+        _ => FsError::Forbidden,
+    }
+}
+
+fn name(path: &DavPath) -> String {
+    String::from_utf8_lossy(path.as_bytes()).into_owned()
+}
+
+/// Calls the pre_write hooks.
+#[derive(Clone)]
+pub struct MiddlewareFs {
+    fs:         Box<dyn DavFileSystem>,
+    req:        Arc<Request>,
+    middleware: Arc<Vec<Arc<dyn Middleware>>>,
+}
+
+impl MiddlewareFs {
+    pub fn new(
+        fs: Box<dyn DavFileSystem>,
+        req: Request,
+        middleware: Vec<Arc<dyn Middleware>>,
+    ) -> Box<MiddlewareFs>
+    {
+        Box::new(MiddlewareFs {
+            fs,
+            req: Arc::new(req),
+            middleware: Arc::new(middleware),
+        })
+    }
+
+    fn check(&self, write: Write<'_>) -> FsResult<()> {
+        for middleware in self.middleware.iter() {
+            if let Err(status) = middleware.pre_write(&self.req, write) {
+                debug!("middleware: {:?}: {}", write, status);
+                return Err(fs_error(status));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DavFileSystem for MiddlewareFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        if options.write || options.append || options.create || options.create_new || options.truncate {
+            if let Err(e) = self.check(Write::File(&name(path))) {
+                return futures::future::ready(Err(e)).boxed();
+            }
+        }
+        self.fs.open(path, options)
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        match self.check(Write::Mkdir(&name(path))) {
+            Ok(()) => self.fs.create_dir(path),
+            Err(e) => futures::future::ready(Err(e)).boxed(),
+        }
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        match self.check(Write::Remove(&name(path))) {
+            Ok(()) => self.fs.remove_dir(path),
+            Err(e) => futures::future::ready(Err(e)).boxed(),
+        }
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        match self.check(Write::Remove(&name(path))) {
+            Ok(()) => self.fs.remove_file(path),
+            Err(e) => futures::future::ready(Err(e)).boxed(),
+        }
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        match self.check(Write::Rename(&name(from), &name(to))) {
+            Ok(()) => self.fs.rename(from, to),
+            Err(e) => futures::future::ready(Err(e)).boxed(),
+        }
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        match self.check(Write::Copy(&name(from), &name(to))) {
+            Ok(()) => self.fs.copy(from, to),
+            Err(e) => futures::future::ready(Err(e)).boxed(),
+        }
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        match self.check(Write::Props(&name(path))) {
+            Ok(()) => self.fs.set_modified(path, tm),
+            Err(e) => futures::future::ready(Err(e)).boxed(),
+        }
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        match self.check(Write::Props(&name(path))) {
+            Ok(()) => self.fs.patch_props(path, patch),
+            Err(e) => futures::future::ready(Err(e)).boxed(),
+        }
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Named(&'static str);
+
+    impl Middleware for Named {
+        fn pre_write(&self, _req: &Request, write: Write<'_>) -> Result<(), StatusCode> {
+            match write {
+                Write::Remove(_) if self.0 == "worm" => Err(StatusCode::FORBIDDEN),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_middleware() {
+        let mut registry = Registry::default();
+        registry.register("audit", Arc::new(Named("audit")));
+        registry.register("worm", Arc::new(Named("worm")));
+        assert_eq!(registry.chain(None).len(), 2);
+        let names = vec!["worm".to_string()];
+        let chain = registry.chain(Some(&names));
+        assert_eq!(chain.len(), 1);
+        let req = Request {
+            method:    http::Method::DELETE,
+            path:      "/a".to_string(),
+            user:      None,
+            remote_ip: [127, 0, 0, 1].into(),
+        };
+        assert_eq!(chain[0].pre_write(&req, Write::Remove("/a")), Err(StatusCode::FORBIDDEN));
+        assert_eq!(chain[0].pre_write(&req, Write::Mkdir("/a")), Ok(()));
+        assert!(matches!(fs_error(StatusCode::LOCKED), FsError::Forbidden));
+
+        let toml = "[server]\nmiddleware = [ \"audit\", \"headers\" ]\n";
+        let config: Config = toml::from_str(toml).unwrap();
+        assert!(check(&config, &registry).unwrap_err().contains("headers"));
+        let toml = "[server]\nmiddleware = [ \"worm\" ]\n";
+        let config: Config = toml::from_str(toml).unwrap();
+        assert!(check(&config, &registry).is_ok());
+    }
+}
@@ -0,0 +1,89 @@
+//
+// The Content-Type of a file, by its extension.
+//
+// Looked up in the mime-types of the location, then in [mime-types]
+// types, the mime.types file, and the table that is built in (that of
+// mime_guess, which the handler uses). If none has it, the default-type
+// of the location or of [mime-types], or application/octet-stream.
+//
+use std::collections::HashMap;
+use std::io;
+
+use webdav_handler::davpath::DavPath;
+
+use crate::config::{Location, MimeTypes};
+use crate::report::file_name;
+
+/// The MIME types, for one location.
+pub struct Lookup<'a> {
+    global:   &'a MimeTypes,
+    location: &'a Location,
+}
+
+impl<'a> Lookup<'a> {
+    pub fn new(global: &'a MimeTypes, location: &'a Location) -> Lookup<'a> {
+        Lookup { global, location }
+    }
+
+    pub fn content_type(&self, path: &DavPath) -> String {
+        let name = file_name(path);
+        let ext = match name.rsplit_once('.') {
+            Some((_, ext)) => ext.to_ascii_lowercase(),
+            None => String::new(),
+        };
+        let configured = [&self.location.mime_types, &self.global.types, &self.global.file_types];
+        if let Some(t) = configured.iter().find_map(|m| m.get(&ext)) {
+            return t.clone();
+        }
+        if let Some(t) = mime_guess::from_ext(&ext).first_raw().filter(|_| !ext.is_empty()) {
+            return t.to_string();
+        }
+        let default = self.location.default_type.as_ref().or(self.global.default.as_ref());
+        default.map(|t| t.as_str()).unwrap_or("application/octet-stream").to_string()
+    }
+}
+
+/// Lowercase extensions, without a dot.
+pub fn normalize(types: &mut HashMap<String, String>) {
+    let map = std::mem::take(types);
+    types.extend(map.into_iter().map(|(ext, t)| (ext.trim_start_matches('.').to_ascii_lowercase(), t)));
+}
+
+/// Read a mime.types file: a type and its extensions on every line.
+pub fn read_file(path: &str) -> io::Result<HashMap<String, String>> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(parse(&text))
+}
+
+fn parse(text: &str) -> HashMap<String, String> {
+    let mut types = HashMap::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut words = line.split_whitespace();
+        if let Some(mime) = words.next() {
+            for ext in words {
+                types.insert(ext.to_ascii_lowercase(), mime.to_string());
+            }
+        }
+    }
+    types
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mimetypes() {
+        let text = "# comment\ntext/markdown md markdown\n\napplication/x-foo FOO # foo\n";
+        let types = parse(text);
+        assert_eq!(types.get("md").map(|s| s.as_str()), Some("text/markdown"));
+        assert_eq!(types.get("foo").map(|s| s.as_str()), Some("application/x-foo"));
+        assert_eq!(types.len(), 3);
+
+        let mut map = HashMap::new();
+        map.insert(".LOG".to_string(), "text/plain".to_string());
+        normalize(&mut map);
+        assert_eq!(map.get("log").map(|s| s.as_str()), Some("text/plain"));
+    }
+}
@@ -0,0 +1,206 @@
+//
+// Extended MKCOL (RFC 5689): a MKCOL with a DAV:mkcol body, that sets
+// properties on the new collection.
+//
+// The handler does not take a body with MKCOL. Here the body is read
+// and taken out, the handler makes the collection, and then the
+// properties are set. If that fails, the collection is removed again,
+// and the response is a DAV:mkcol-response with the status of every
+// property, like a PROPPATCH. The resourcetype can only be a plain
+// collection here; calendars and address books are in pim.rs.
+//
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavFileSystem, DavProp};
+use xmltree::{Element, XMLNode};
+
+use crate::report::{self, escape, is_dav};
+
+// The properties in DAV: that can be set, as dead properties.
+const DAV_PROPS: &[&str] = &["displayname", "getcontentlanguage"];
+
+fn elems(elem: &Element) -> impl Iterator<Item = &Element> {
+    elem.children.iter().filter_map(XMLNode::as_element)
+}
+
+/// The properties in the DAV:set elements of a DAV:mkcol (or a
+/// MKCALENDAR), without the resourcetype. The resourcetype must be a
+/// collection, and can be one of "types" (namespace, name) too; if not,
+/// the error is FORBIDDEN.
+pub fn props(root: &Element, types: &[(&str, &str)]) -> Result<Vec<DavProp>, StatusCode> {
+    let is_type = |e: &Element, (ns, name): &(&str, &str)| {
+        e.namespace.as_deref() == Some(ns) && e.name == *name
+    };
+    let valid = |e: &Element| is_dav(e, "collection") || types.iter().any(|t| is_type(e, t));
+    let mut props = Vec::new();
+    let sets = elems(root).filter(|e| is_dav(e, "set"));
+    for elem in sets.flat_map(|s| elems(s).filter(|e| is_dav(e, "prop"))).flat_map(elems) {
+        if is_dav(elem, "resourcetype") {
+            if !elems(elem).all(valid) {
+                return Err(StatusCode::FORBIDDEN);
+            }
+            continue;
+        }
+        let mut xml = Vec::new();
+        if elem.write(&mut xml).is_err() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        props.push(DavProp {
+            name:      elem.name.clone(),
+            prefix:    elem.prefix.clone(),
+            namespace: elem.namespace.clone(),
+            xml:       Some(xml),
+        });
+    }
+    Ok(props)
+}
+
+/// The response for an error from `props`.
+pub fn error(status: StatusCode) -> http::Response<String> {
+    match status {
+        StatusCode::FORBIDDEN => report::condition(status, "valid-resourcetype"),
+        status => report::error(status),
+    }
+}
+
+// The DAV:mkcol-response of a failed request. The properties that would
+// have been set get "424 Failed Dependency".
+fn response(results: Vec<(StatusCode, DavProp)>) -> http::Response<String> {
+    let mut by_status = BTreeMap::new();
+    for (status, prop) in results {
+        let status = match status.is_success() {
+            true => StatusCode::FAILED_DEPENDENCY,
+            false => status,
+        };
+        by_status.entry(status).or_insert_with(Vec::new).push(prop);
+    }
+    let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    body.push_str("<D:mkcol-response xmlns:D=\"DAV:\">\n");
+    for (status, props) in &by_status {
+        body.push_str("<D:propstat><D:prop>");
+        for prop in props {
+            match prop.namespace.as_deref() {
+                Some("DAV:") => write!(body, "<D:{}/>", prop.name),
+                Some(ns) => write!(body, "<{} xmlns=\"{}\"/>", prop.name, escape(ns)),
+                None => write!(body, "<{} xmlns=\"\"/>", prop.name),
+            }
+            .unwrap();
+        }
+        let reason = status.canonical_reason().unwrap_or("");
+        writeln!(body, "</D:prop><D:status>HTTP/1.1 {} {}</D:status></D:propstat>", status.as_u16(), reason)
+            .unwrap();
+    }
+    body.push_str("</D:mkcol-response>\n");
+    let status = match by_status.contains_key(&StatusCode::INSUFFICIENT_STORAGE) {
+        true => StatusCode::INSUFFICIENT_STORAGE,
+        false => StatusCode::FORBIDDEN,
+    };
+    report::response(status, body)
+}
+
+/// Read the body of a MKCOL for the properties to set. The request is
+/// given back without it, for the handler.
+pub async fn request(
+    req: http::Request<hyper::Body>,
+) -> Result<(http::Request<hyper::Body>, Vec<DavProp>), http::Response<String>>
+{
+    let (parts, body) = req.into_parts();
+    let body = report::body(http::Request::new(body)).await?;
+    let mut props = Vec::new();
+    if !body.is_empty() {
+        let root = match Element::parse(&body[..]) {
+            Ok(root) if is_dav(&root, "mkcol") => root,
+            Ok(_) => return Err(report::error(StatusCode::UNSUPPORTED_MEDIA_TYPE)),
+            Err(_) => return Err(report::error(StatusCode::BAD_REQUEST)),
+        };
+        props = self::props(&root, &[]).map_err(error)?;
+        // the live properties cannot be set.
+        let live = |p: &DavProp| {
+            p.namespace.as_deref() == Some("DAV:") && !DAV_PROPS.contains(&p.name.as_str())
+        };
+        if props.iter().any(live) {
+            let status = |p: &DavProp| if live(p) { StatusCode::FORBIDDEN } else { StatusCode::OK };
+            return Err(response(props.into_iter().map(|p| (status(&p), p)).collect()));
+        }
+    }
+    let mut parts = parts;
+    parts.headers.remove(http::header::CONTENT_TYPE);
+    parts.headers.remove(http::header::CONTENT_LENGTH);
+    Ok((http::Request::from_parts(parts, hyper::Body::empty()), props))
+}
+
+/// After the handler made the collection, set the properties. If that
+/// fails, the collection is removed, and the response is returned.
+pub async fn apply(
+    fs: &dyn DavFileSystem,
+    path: &DavPath,
+    props: Vec<DavProp>,
+) -> Option<http::Response<String>>
+{
+    if props.is_empty() {
+        return None;
+    }
+    let results = match fs.have_props(path).await {
+        true => {
+            match fs.patch_props(path, props.iter().cloned().map(|p| (true, p)).collect()).await {
+                Ok(results) => results,
+                Err(e) => props.into_iter().map(|p| (report::status(e), p)).collect(),
+            }
+        },
+        false => props.into_iter().map(|p| (StatusCode::FORBIDDEN, p)).collect(),
+    };
+    if results.iter().all(|(s, _)| s.is_success()) {
+        return None;
+    }
+    debug!("mkcol: {}: properties not set, removing it", path);
+    if let Err(e) = fs.remove_dir(path).await {
+        error!("mkcol: {}: {:?}", path, e);
+    }
+    Some(response(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prop(name: &str, ns: &str) -> DavProp {
+        DavProp {
+            name:      name.to_string(),
+            prefix:    None,
+            namespace: Some(ns.to_string()),
+            xml:       None,
+        }
+    }
+
+    #[test]
+    fn test_mkcol() {
+        let body = r#"<D:mkcol xmlns:D="DAV:" xmlns:X="urn:x"><D:set><D:prop>
+            <D:resourcetype><D:collection/></D:resourcetype>
+            <D:displayname>Stuff</D:displayname><X:color>red</X:color>
+            </D:prop></D:set></D:mkcol>"#;
+        let set = props(&Element::parse(body.as_bytes()).unwrap(), &[]).unwrap();
+        let names: Vec<_> = set.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["displayname", "color"]);
+        assert!(String::from_utf8_lossy(set[1].xml.as_ref().unwrap()).contains(">red<"));
+
+        let body = r#"<D:mkcol xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav"><D:set><D:prop>
+            <D:resourcetype><D:collection/><C:calendar/></D:resourcetype></D:prop></D:set></D:mkcol>"#;
+        let root = Element::parse(body.as_bytes()).unwrap();
+        let resp = error(props(&root, &[]).unwrap_err());
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(resp.body().contains("valid-resourcetype"));
+        assert!(props(&root, &[("urn:ietf:params:xml:ns:caldav", "calendar")]).unwrap().is_empty());
+
+        let resp = response(vec![
+            (StatusCode::FORBIDDEN, prop("getetag", "DAV:")),
+            (StatusCode::OK, prop("color", "urn:x")),
+        ]);
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = resp.body();
+        assert!(body.contains("<D:propstat><D:prop><D:getetag/></D:prop><D:status>HTTP/1.1 403 Forbidden"));
+        assert!(body.contains("<color xmlns=\"urn:x\"/></D:prop><D:status>HTTP/1.1 424 Failed Dependency"));
+    }
+}
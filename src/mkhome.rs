@@ -0,0 +1,110 @@
+//
+// Create the directory of a location on first use, like pam_mkhomedir.
+//
+// Missing directories are created as root, then chowned to the owner
+// (create-owner / create-group, or the account of the user). The contents
+// of the skeleton directory are copied into a newly created directory
+// while it is still root's and 0700, so the owner cannot put a symlink
+// where a file is about to be written; the tree is chowned at the end.
+//
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+
+use crate::config::{Location, Server};
+use crate::suid::UgidSwitch;
+use crate::unixuser::User;
+
+fn chown(path: &Path, (uid, gid): (u32, u32)) -> io::Result<()> {
+    let flags = FchownatFlags::NoFollowSymlink;
+    fchownat(None, path, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)), flags)
+        .map_err(|e| io::Error::other(format!("chown {:?}: {}", path, e)))
+}
+
+// Copy the contents of "from" into "to", recursively, as root.
+fn copy_skeleton(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let src = entry.path();
+        let dst = to.join(entry.file_name());
+        let meta = fs::symlink_metadata(&src)?;
+        if meta.file_type().is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&src)?, &dst)?;
+        } else if meta.is_dir() {
+            fs::DirBuilder::new().mode(meta.mode() & 0o7777).create(&dst)?;
+            copy_skeleton(&src, &dst)?;
+        } else if meta.is_file() {
+            fs::copy(&src, &dst)?;
+        } else {
+            // no devices, fifos or sockets.
+            continue;
+        }
+    }
+    Ok(())
+}
+
+// Chown what is in "dir", recursively, without following symlinks.
+fn chown_tree(dir: &Path, owner: (u32, u32)) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if fs::symlink_metadata(&path)?.is_dir() {
+            chown_tree(&path, owner)?;
+        }
+        chown(&path, owner)?;
+    }
+    Ok(())
+}
+
+fn create_dirs(dir: &Path, mode: u32, owner: (u32, u32), skeleton: Option<&Path>) -> io::Result<bool> {
+    // find the directories that need to be created, top down.
+    let mut missing = Vec::new();
+    let mut cur = Some(dir);
+    while let Some(d) = cur.filter(|d| fs::symlink_metadata(d).is_err()) {
+        missing.push(d);
+        cur = d.parent();
+    }
+    for d in missing.iter().rev() {
+        match fs::DirBuilder::new().mode(0o700).create(d) {
+            // someone else was first.
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+            Err(e) => return Err(e),
+            Ok(()) => {},
+        }
+    }
+    if missing.is_empty() {
+        return Ok(false);
+    }
+    if let Some(skeleton) = skeleton {
+        copy_skeleton(skeleton, dir)?;
+        chown_tree(dir, owner)?;
+    }
+    for d in &missing {
+        // chown first, it might clear the setgid bit. Set the mode
+        // explicitly, the umask does not apply.
+        chown(d, owner)?;
+        fs::set_permissions(d, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(true)
+}
+
+/// Create the directory if it does not exist yet. Returns true if it was created.
+pub fn create(location: &Location, dir: &str, pwd: Option<&User>, server: &Server) -> io::Result<bool> {
+    let dir = Path::new(dir);
+    if dir.exists() {
+        return Ok(false);
+    }
+    let uid = location.create_owner.or(pwd.map(|p| p.uid)).or(server.uid).unwrap_or(0);
+    let gid = location.create_group.or(pwd.map(|p| p.gid)).or(server.gid).unwrap_or(0);
+    let mode = location
+        .create_mode
+        .as_ref()
+        .and_then(|m| u32::from_str_radix(m, 8).ok())
+        .unwrap_or(0o700);
+    let skeleton = location.skeleton.as_ref().map(Path::new);
+    tokio::task::block_in_place(|| {
+        UgidSwitch::new(Some((0, 0, &[]))).run(|| create_dirs(dir, mode, (uid, gid), skeleton))
+    })
+}
@@ -0,0 +1,341 @@
+//
+// A small MySQL / MariaDB client: log in (mysql_native_password or
+// caching_sha2_password), and run one query. The values are read as
+// text. The text protocol has no parameters, so the caller puts the
+// username in the query as a literal().
+//
+use std::convert::TryInto;
+use std::io;
+
+use ring::digest;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::sql::{Row, Stream, Url};
+
+const LONG_PASSWORD: u32 = 0x1;
+const CONNECT_WITH_DB: u32 = 0x8;
+const PROTOCOL_41: u32 = 0x200;
+const TRANSACTIONS: u32 = 0x2000;
+const SECURE_CONNECTION: u32 = 0x8000;
+const PLUGIN_AUTH: u32 = 0x8_0000;
+
+// utf8mb4_general_ci.
+const CHARSET: u8 = 45;
+
+/// A string literal that nothing in it can break out of.
+pub fn literal(s: &str) -> String {
+    let hex: String = s.bytes().map(|b| format!("{:02X}", b)).collect();
+    format!("_utf8mb4 X'{}'", hex)
+}
+
+fn protocol_error(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("mysql: {}", msg.into()))
+}
+
+// ERR packet: 0xff, code, "#", sqlstate, message.
+fn error_packet(pkt: &[u8]) -> io::Error {
+    let code = match pkt.len() >= 3 {
+        true => u16::from_le_bytes([pkt[1], pkt[2]]),
+        false => 0,
+    };
+    let mut msg = pkt.get(3..).unwrap_or(&[]);
+    if msg.first() == Some(&b'#') && msg.len() >= 6 {
+        msg = &msg[6..];
+    }
+    io::Error::other(format!("mysql: {}: {}", code, String::from_utf8_lossy(msg)))
+}
+
+struct Conn<'a> {
+    stream: &'a mut Box<dyn Stream>,
+    seq:    u8,
+}
+
+impl Conn<'_> {
+    async fn read(&mut self) -> io::Result<Vec<u8>> {
+        let mut pkt = Vec::new();
+        loop {
+            let mut hdr = [0u8; 4];
+            self.stream.read_exact(&mut hdr).await?;
+            let len = u32::from_le_bytes([hdr[0], hdr[1], hdr[2], 0]) as usize;
+            self.seq = hdr[3].wrapping_add(1);
+            let start = pkt.len();
+            pkt.resize(start + len, 0);
+            self.stream.read_exact(&mut pkt[start..]).await?;
+            // a packet of 0xffffff bytes is continued in the next one.
+            if len < 0xff_ffff {
+                return Ok(pkt);
+            }
+            if pkt.len() > 16 * 1024 * 1024 {
+                return Err(protocol_error("packet too large"));
+            }
+        }
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut pkt = (data.len() as u32).to_le_bytes();
+        pkt[3] = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        let mut buf = pkt.to_vec();
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf).await
+    }
+}
+
+// A cursor over the contents of a packet.
+struct Packet<'a>(&'a [u8]);
+
+impl<'a> Packet<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(protocol_error("short packet"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn nul_str(&mut self) -> io::Result<&'a [u8]> {
+        let end = self.0.iter().position(|&b| b == 0).ok_or_else(|| protocol_error("no NUL"))?;
+        let s = self.bytes(end)?;
+        self.bytes(1)?;
+        Ok(s)
+    }
+
+    // length-encoded integer; None for 0xfb, NULL.
+    fn lenenc(&mut self) -> io::Result<Option<u64>> {
+        let n = match self.u8()? {
+            0xfb => return Ok(None),
+            0xfc => 2,
+            0xfd => 3,
+            0xfe => 8,
+            b => return Ok(Some(b as u64)),
+        };
+        let mut val = [0u8; 8];
+        val[..n].copy_from_slice(self.bytes(n)?);
+        Ok(Some(u64::from_le_bytes(val)))
+    }
+
+    fn lenenc_str(&mut self) -> io::Result<Option<&'a [u8]>> {
+        match self.lenenc()? {
+            Some(len) => Ok(Some(self.bytes(len as usize)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn is_eof(pkt: &[u8]) -> bool {
+    pkt.first() == Some(&0xfe) && pkt.len() < 9
+}
+
+// mysql_native_password: SHA1(password) XOR SHA1(scramble + SHA1(SHA1(password))).
+fn native_password(password: &str, scramble: &[u8]) -> Vec<u8> {
+    if password.is_empty() {
+        return Vec::new();
+    }
+    let hash1 = Sha1::digest(password.as_bytes());
+    let hash2 = Sha1::digest(&hash1);
+    let mut h = Sha1::new();
+    h.update(scramble);
+    h.update(hash2);
+    hash1.iter().zip(h.finalize().iter()).map(|(a, b)| a ^ b).collect()
+}
+
+// caching_sha2_password: SHA256(password) XOR SHA256(SHA256(SHA256(password)) + scramble).
+fn sha2_password(password: &str, scramble: &[u8]) -> Vec<u8> {
+    if password.is_empty() {
+        return Vec::new();
+    }
+    let sha256 = |data: &[u8]| digest::digest(&digest::SHA256, data);
+    let hash1 = sha256(password.as_bytes());
+    let hash2 = sha256(hash1.as_ref());
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(hash2.as_ref());
+    ctx.update(scramble);
+    let hash3 = ctx.finish();
+    hash1.as_ref().iter().zip(hash3.as_ref()).map(|(a, b)| a ^ b).collect()
+}
+
+fn scramble_for(plugin: &[u8], password: &str, scramble: &[u8]) -> io::Result<Vec<u8>> {
+    match plugin {
+        b"mysql_native_password" => Ok(native_password(password, scramble)),
+        b"caching_sha2_password" => Ok(sha2_password(password, scramble)),
+        other => {
+            let other = String::from_utf8_lossy(other);
+            Err(protocol_error(format!("authentication plugin {} not supported", other)))
+        },
+    }
+}
+
+async fn login(conn: &mut Conn<'_>, url: &Url, unix: bool) -> io::Result<()> {
+    // the initial handshake, protocol version 10.
+    let pkt = conn.read().await?;
+    if pkt.first() == Some(&0xff) {
+        return Err(error_packet(&pkt));
+    }
+    let mut p = Packet(&pkt);
+    if p.u8()? != 10 {
+        return Err(protocol_error("protocol version not supported"));
+    }
+    p.nul_str()?;
+    p.bytes(4)?;
+    let mut scramble = p.bytes(8)?.to_vec();
+    p.bytes(1)?;
+    let caps_lo = u16::from_le_bytes(p.bytes(2)?.try_into().unwrap()) as u32;
+    p.bytes(3)?;
+    let caps_hi = u16::from_le_bytes(p.bytes(2)?.try_into().unwrap()) as u32;
+    let server_caps = caps_lo | caps_hi << 16;
+    let auth_len = p.u8()? as usize;
+    p.bytes(10)?;
+    if server_caps & SECURE_CONNECTION != 0 {
+        let len = auth_len.saturating_sub(8).max(13);
+        let part2 = p.bytes(len.min(p.0.len()))?;
+        scramble.extend_from_slice(part2.strip_suffix(&[0]).unwrap_or(part2));
+    }
+    let mut plugin = b"mysql_native_password".to_vec();
+    if server_caps & PLUGIN_AUTH != 0 && !p.0.is_empty() {
+        plugin = p.0.strip_suffix(&[0]).unwrap_or(p.0).to_vec();
+    }
+    if server_caps & PROTOCOL_41 == 0 {
+        return Err(protocol_error("server too old"));
+    }
+
+    // HandshakeResponse41.
+    let caps = LONG_PASSWORD | CONNECT_WITH_DB | PROTOCOL_41 | TRANSACTIONS | SECURE_CONNECTION | PLUGIN_AUTH;
+    let mut resp = caps.to_le_bytes().to_vec();
+    resp.extend_from_slice(&(16u32 * 1024 * 1024).to_le_bytes());
+    resp.push(CHARSET);
+    resp.extend_from_slice(&[0; 23]);
+    resp.extend_from_slice(url.user.as_bytes());
+    resp.push(0);
+    let auth = scramble_for(&plugin, &url.password, &scramble)?;
+    resp.push(auth.len() as u8);
+    resp.extend_from_slice(&auth);
+    resp.extend_from_slice(url.database.as_bytes());
+    resp.push(0);
+    resp.extend_from_slice(&plugin);
+    resp.push(0);
+    conn.write(&resp).await?;
+
+    loop {
+        let pkt = conn.read().await?;
+        match pkt.first() {
+            Some(0x00) => return Ok(()),
+            Some(0xff) => return Err(error_packet(&pkt)),
+            // AuthSwitchRequest: another plugin, another scramble.
+            Some(0xfe) => {
+                let mut p = Packet(&pkt[1..]);
+                plugin = p.nul_str()?.to_vec();
+                scramble = p.0.strip_suffix(&[0]).unwrap_or(p.0).to_vec();
+                let auth = scramble_for(&plugin, &url.password, &scramble)?;
+                conn.write(&auth).await?;
+            },
+            // caching_sha2_password: 3 is fast auth, 4 wants the password.
+            Some(0x01) if pkt.get(1) == Some(&3) => {},
+            Some(0x01) if pkt.get(1) == Some(&4) => {
+                if !unix {
+                    let msg = "caching_sha2_password wants the password, only sent over a unix socket";
+                    return Err(protocol_error(msg));
+                }
+                let mut pass = url.password.as_bytes().to_vec();
+                pass.push(0);
+                conn.write(&pass).await?;
+            },
+            _ => return Err(protocol_error("unexpected packet during login")),
+        }
+    }
+}
+
+/// Log in, and run the query.
+pub async fn query(stream: &mut Box<dyn Stream>, url: &Url, unix: bool, query: &str) -> io::Result<Vec<Row>> {
+    let mut conn = Conn { stream, seq: 0 };
+    login(&mut conn, url, unix).await?;
+
+    // COM_QUERY.
+    conn.seq = 0;
+    let mut cmd = vec![0x03];
+    cmd.extend_from_slice(query.as_bytes());
+    conn.write(&cmd).await?;
+
+    let pkt = conn.read().await?;
+    let rows = match pkt.first() {
+        Some(0xff) => Err(error_packet(&pkt)),
+        // OK, not a resultset.
+        Some(0x00) => Ok(Vec::new()),
+        _ => {
+            let count = Packet(&pkt).lenenc()?.unwrap_or(0);
+            let mut columns = Vec::new();
+            for _ in 0..count {
+                let pkt = conn.read().await?;
+                let mut p = Packet(&pkt);
+                // catalog, schema, table, org_table, name.
+                for _ in 0..4 {
+                    p.lenenc_str()?;
+                }
+                let name = p.lenenc_str()?.unwrap_or(b"");
+                columns.push(String::from_utf8_lossy(name).into_owned());
+            }
+            if !is_eof(&conn.read().await?) {
+                return Err(protocol_error("no EOF after the columns"));
+            }
+            let mut rows = Vec::new();
+            loop {
+                let pkt = conn.read().await?;
+                if is_eof(&pkt) {
+                    break;
+                }
+                if pkt.first() == Some(&0xff) {
+                    return Err(error_packet(&pkt));
+                }
+                let mut p = Packet(&pkt);
+                let mut row = Row::new();
+                for name in &columns {
+                    let value = p.lenenc_str()?.map(|v| String::from_utf8_lossy(v).into_owned());
+                    row.insert(name.to_string(), value);
+                }
+                rows.push(row);
+            }
+            Ok(rows)
+        },
+    };
+
+    // COM_QUIT.
+    conn.seq = 0;
+    let _ = conn.write(&[0x01]).await;
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mysql() {
+        assert_eq!(literal("a'b"), "_utf8mb4 X'612762'");
+        assert_eq!(literal(""), "_utf8mb4 X''");
+
+        let scramble = b"01234567890123456789";
+        let auth = native_password("secret", scramble);
+        assert_eq!(auth.len(), 20);
+        // the server checks: SHA1(auth XOR SHA1(scramble + hash2)) == hash2.
+        let hash2 = Sha1::digest(&Sha1::digest(b"secret"));
+        let mut h = Sha1::new();
+        h.update(scramble);
+        h.update(hash2);
+        let hash1: Vec<u8> = auth.iter().zip(h.finalize().iter()).map(|(a, b)| a ^ b).collect();
+        assert_eq!(Sha1::digest(&hash1), hash2);
+        assert!(native_password("", scramble).is_empty());
+        assert_eq!(sha2_password("secret", scramble).len(), 32);
+
+        let mut p = Packet(&[0xfc, 0x01, 0x01, 0xfb, 0x03, b'a', b'b', b'c']);
+        assert_eq!(p.lenenc().unwrap(), Some(257));
+        assert_eq!(p.lenenc_str().unwrap(), None);
+        assert_eq!(p.lenenc_str().unwrap(), Some(&b"abc"[..]));
+        let err = error_packet(b"\xff\x15\x04#28000Access denied");
+        assert_eq!(err.to_string(), "mysql: 1045: Access denied");
+    }
+}
@@ -0,0 +1,439 @@
+//
+// Names on disk that are not valid UTF-8 (non-utf8-names), from old
+// shares that were written in another character set.
+//
+// - raw: as they are. The bytes are percent-encoded in the URL, but
+//   clients cannot show them, and some drop or mangle them.
+// - skip: left out of listings.
+// - percent: the bytes that are not UTF-8 are shown as "%XX", so that
+//   "caf\xe9.txt" is "caf%E9.txt". The client can use that name.
+// - transcode: converted from the legacy-charset (latin1, shift-jis, or
+//   any other name that iconv knows). What does not convert is shown as
+//   with "percent".
+//
+// Valid UTF-8 names are never changed. A name from a client is only
+// translated back when it does not exist as it is, so new files and
+// directories always get the UTF-8 name.
+//
+use std::ffi::CString;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use futures::future::FutureExt;
+use futures::StreamExt;
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::casefs::{join, segments};
+use crate::config::NonUtf8Names;
+
+// A conversion descriptor. iconv() keeps state in it, so one at a time.
+struct Iconv(libc::iconv_t);
+
+unsafe impl Send for Iconv {}
+
+impl Iconv {
+    fn open(to: &str, from: &str) -> io::Result<Iconv> {
+        let cstr = |s: &str| CString::new(s).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput));
+        let (to, from) = (cstr(to)?, cstr(from)?);
+        let cd = unsafe { libc::iconv_open(to.as_ptr(), from.as_ptr()) };
+        if cd as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Iconv(cd))
+    }
+
+    // All of the input, converted. None if some of it does not convert.
+    fn convert(&mut self, input: &[u8]) -> Option<Vec<u8>> {
+        let mut out = vec![0u8; input.len() * 4 + 16];
+        let mut inbuf = input.as_ptr() as *mut libc::c_char;
+        let mut inleft = input.len();
+        let mut outbuf = out.as_mut_ptr() as *mut libc::c_char;
+        let mut outleft = out.len();
+        let (nobuf, noleft) = (std::ptr::null_mut(), std::ptr::null_mut());
+        unsafe {
+            libc::iconv(self.0, nobuf, noleft, nobuf, noleft);
+            if libc::iconv(self.0, &mut inbuf, &mut inleft, &mut outbuf, &mut outleft) == usize::MAX {
+                return None;
+            }
+            // back to the initial shift state.
+            if libc::iconv(self.0, nobuf, noleft, &mut outbuf, &mut outleft) == usize::MAX {
+                return None;
+            }
+        }
+        let len = out.len() - outleft;
+        out.truncate(len);
+        Some(out)
+    }
+}
+
+impl Drop for Iconv {
+    fn drop(&mut self) {
+        unsafe { libc::iconv_close(self.0) };
+    }
+}
+
+/// A legacy character set, converted to and from UTF-8. Opened when the
+/// config is read: after a chroot, iconv might not find its modules.
+pub struct Charset {
+    decoder: Mutex<Iconv>,
+    encoder: Mutex<Iconv>,
+}
+
+impl Charset {
+    pub fn open(name: &str) -> io::Result<Charset> {
+        let error = |e: io::Error| io::Error::new(e.kind(), format!("legacy-charset {}: {}", name, e));
+        Ok(Charset {
+            decoder: Mutex::new(Iconv::open("UTF-8", name).map_err(error)?),
+            encoder: Mutex::new(Iconv::open(name, "UTF-8").map_err(error)?),
+        })
+    }
+
+    fn decode(&self, name: &[u8]) -> Option<String> {
+        let out = self.decoder.lock().unwrap().convert(name)?;
+        String::from_utf8(out).ok()
+    }
+
+    fn encode(&self, name: &str) -> Option<Vec<u8>> {
+        self.encoder.lock().unwrap().convert(name.as_bytes())
+    }
+}
+
+impl std::fmt::Debug for Charset {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Charset")
+    }
+}
+
+// The bytes that are not UTF-8 as "%XX".
+fn escape(mut name: &[u8]) -> String {
+    let mut out = String::new();
+    loop {
+        match std::str::from_utf8(name) {
+            Ok(s) => {
+                out.push_str(s);
+                return out;
+            },
+            Err(e) => {
+                let (good, rest) = name.split_at(e.valid_up_to());
+                out.push_str(std::str::from_utf8(good).unwrap());
+                let bad = e.error_len().unwrap_or(rest.len());
+                rest[..bad].iter().for_each(|b| out.push_str(&format!("%{:02X}", b)));
+                name = &rest[bad..];
+            },
+        }
+    }
+}
+
+// Undo escape(): "%XX" of the bytes that can not be UTF-8 on their own.
+fn unescape(name: &[u8]) -> Option<Vec<u8>> {
+    let hex = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    let mut out = Vec::with_capacity(name.len());
+    let mut found = false;
+    let mut i = 0;
+    while i < name.len() {
+        if name[i] == b'%' && i + 2 < name.len() {
+            if let (Some(h), Some(l)) = (hex(name[i + 1]), hex(name[i + 2])) {
+                if h >= 8 {
+                    out.push(h * 16 + l);
+                    found = true;
+                    i += 3;
+                    continue;
+                }
+            }
+        }
+        out.push(name[i]);
+        i += 1;
+    }
+    Some(out).filter(|out| found && std::str::from_utf8(out).is_err())
+}
+
+#[derive(Clone)]
+pub struct NameFs {
+    fs:      Box<dyn DavFileSystem>,
+    policy:  NonUtf8Names,
+    charset: Option<Arc<Charset>>,
+}
+
+impl NameFs {
+    pub fn new(
+        fs: Box<dyn DavFileSystem>,
+        policy: NonUtf8Names,
+        charset: Option<Arc<Charset>>,
+    ) -> Box<NameFs>
+    {
+        Box::new(NameFs { fs, policy, charset })
+    }
+
+    // The name on disk as the client sees it. None: leave it out.
+    fn show(&self, name: &[u8]) -> Option<Vec<u8>> {
+        if std::str::from_utf8(name).is_ok() {
+            return Some(name.to_vec());
+        }
+        let converted = self.charset.as_ref().and_then(|c| c.decode(name));
+        match self.policy {
+            NonUtf8Names::Raw => Some(name.to_vec()),
+            NonUtf8Names::Skip => None,
+            NonUtf8Names::Percent => Some(escape(name).into_bytes()),
+            NonUtf8Names::Transcode => Some(converted.unwrap_or_else(|| escape(name)).into_bytes()),
+        }
+    }
+
+    // What a name from the client could be on disk, other than itself.
+    fn candidates(&self, name: &[u8]) -> Vec<Vec<u8>> {
+        let mut names = Vec::new();
+        if self.policy == NonUtf8Names::Transcode {
+            let encoded = std::str::from_utf8(name).ok().and_then(|n| self.charset.as_ref()?.encode(n));
+            names.extend(encoded.filter(|e| e != name));
+        }
+        if matches!(self.policy, NonUtf8Names::Percent | NonUtf8Names::Transcode) {
+            names.extend(unescape(name));
+        }
+        names
+    }
+
+    // The path as it is on disk. After the first name that is not found,
+    // the rest is as it was.
+    async fn resolve(&self, path: &DavPath) -> FsResult<DavPath> {
+        if matches!(self.policy, NonUtf8Names::Raw | NonUtf8Names::Skip) {
+            return Ok(path.clone());
+        }
+        let plain = |n: &[u8]| n.iter().all(|&c| c < 0x80 && c != b'%');
+        if segments(path).into_iter().all(plain) || self.fs.symlink_metadata(path).await.is_ok() {
+            return Ok(path.clone());
+        }
+        let mut names: Vec<Vec<u8>> = Vec::new();
+        let mut found = true;
+        for name in segments(path) {
+            if found && !plain(name) {
+                let mut here = names.clone();
+                here.push(name.to_vec());
+                if self.fs.symlink_metadata(&join(&here, false)?).await.is_err() {
+                    let mut other = None;
+                    for candidate in self.candidates(name) {
+                        here.pop();
+                        here.push(candidate.clone());
+                        if self.fs.symlink_metadata(&join(&here, false)?).await.is_ok() {
+                            other = Some(candidate);
+                            break;
+                        }
+                    }
+                    match other {
+                        Some(other) => {
+                            names.push(other);
+                            continue;
+                        },
+                        None => found = false,
+                    }
+                }
+            }
+            names.push(name.to_vec());
+        }
+        join(&names, path.is_collection())
+    }
+}
+
+struct NameDirEntry {
+    entry: Box<dyn DavDirEntry>,
+    name:  Vec<u8>,
+}
+
+impl DavDirEntry for NameDirEntry {
+    fn name(&self) -> Vec<u8> {
+        self.name.clone()
+    }
+
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        self.entry.metadata()
+    }
+
+    fn is_dir(&self) -> FsFuture<'_, bool> {
+        self.entry.is_dir()
+    }
+
+    fn is_file(&self) -> FsFuture<'_, bool> {
+        self.entry.is_file()
+    }
+
+    fn is_symlink(&self) -> FsFuture<'_, bool> {
+        self.entry.is_symlink()
+    }
+}
+
+impl DavFileSystem for NameFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.open(&path, options).await
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            let path = self.resolve(path).await?;
+            let strm = self.fs.read_dir(&path, meta).await?;
+            let this = self.clone();
+            let strm = strm.filter_map(move |entry| {
+                let name = this.show(&entry.name());
+                let entry = name.map(|name| Box::new(NameDirEntry { entry, name }) as Box<dyn DavDirEntry>);
+                futures::future::ready(entry)
+            });
+            Ok(Box::pin(strm) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.metadata(&path).await
+        }
+        .boxed()
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.symlink_metadata(&path).await
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.create_dir(&path).await
+        }
+        .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.remove_dir(&path).await
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.remove_file(&path).await
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let from = self.resolve(from).await?;
+            let to = self.resolve(to).await?;
+            self.fs.rename(&from, &to).await
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let from = self.resolve(from).await?;
+            let to = self.resolve(to).await?;
+            self.fs.copy(&from, &to).await
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.set_accessed(&path, tm).await
+        }
+        .boxed()
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.set_modified(&path, tm).await
+        }
+        .boxed()
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        async move {
+            match self.resolve(path).await {
+                Ok(path) => self.fs.have_props(&path).await,
+                Err(_) => false,
+            }
+        }
+        .boxed()
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.patch_props(&path, patch).await
+        }
+        .boxed()
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.get_props(&path, do_content).await
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        async move {
+            let path = self.resolve(path).await?;
+            self.fs.get_prop(&path, prop).await
+        }
+        .boxed()
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_namefs() {
+        assert_eq!(escape(b"caf\xe9.txt"), "caf%E9.txt");
+        assert_eq!(escape("café.txt".as_bytes()), "café.txt");
+        assert_eq!(escape(b"\xff\xfe"), "%FF%FE");
+        assert_eq!(unescape(b"caf%E9.txt"), Some(b"caf\xe9.txt".to_vec()));
+        assert_eq!(unescape(b"%FF%FE"), Some(b"\xff\xfe".to_vec()));
+        // not something escape() makes.
+        assert_eq!(unescape(b"100%25.txt"), None);
+        assert_eq!(unescape(b"caf%C3%A9"), None);
+        assert_eq!(unescape(b"a%E"), None);
+
+        let latin1 = Charset::open("latin1").unwrap();
+        assert_eq!(latin1.decode(b"caf\xe9").as_deref(), Some("café"));
+        assert_eq!(latin1.encode("café"), Some(b"caf\xe9".to_vec()));
+        assert_eq!(latin1.encode("日本"), None);
+        let sjis = Charset::open("shift-jis").unwrap();
+        assert_eq!(sjis.decode(b"\x83e\x83X\x83g").as_deref(), Some("テスト"));
+        assert_eq!(sjis.encode("テスト"), Some(b"\x83e\x83X\x83g".to_vec()));
+        assert!(Charset::open("no-such-charset").is_err());
+    }
+}
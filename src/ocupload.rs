@@ -0,0 +1,218 @@
+//
+// Chunked uploads of the ownCloud and Nextcloud clients (chunking v2),
+// on a location with oc-chunking, like /remote.php/dav/uploads/:user.
+//
+// The client makes a directory for an upload with MKCOL, PUTs the chunks
+// into it, and then MOVEs "<upload>/.file" to the destination. All but
+// that last MOVE are handled as usual. The MOVE checks OC-Total-Length and
+// OC-Checksum against the chunks, and is then done as a PUT of the chunks,
+// in order, to the Destination. That PUT is routed like any other request,
+// usually to another location, with its own permissions and limits.
+// Uploads that are never finished are removed by the janitor.
+//
+use std::io;
+use std::time::SystemTime;
+
+use futures::{StreamExt, TryStreamExt};
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavFile, DavFileSystem, FsError, FsResult, OpenOptions, ReadDirMeta};
+
+use crate::checksum::Checksum;
+use crate::trashfs::join;
+
+const CHUNK: usize = 65536;
+
+fn read_options() -> OpenOptions {
+    OpenOptions {
+        read: true,
+        ..OpenOptions::default()
+    }
+}
+
+/// Is this the MOVE that finishes an upload.
+pub fn is_final(path: &DavPath) -> bool {
+    let path = path.as_bytes();
+    path.ends_with(b"/.file") && path.len() > 6
+}
+
+/// The directory of the upload, for the path of ".file".
+pub fn upload_dir(path: &DavPath) -> FsResult<DavPath> {
+    let url = path.as_url_string();
+    let dir = url.strip_suffix("/.file").ok_or(FsError::NotFound)?;
+    DavPath::new(dir).map_err(|_| FsError::GeneralFailure)
+}
+
+/// The chunks of an upload, with their sizes. Chunks are numbered, so
+/// they are in order of the length of the name, then the name.
+pub async fn chunks(fs: &dyn DavFileSystem, dir: &DavPath) -> FsResult<Vec<(DavPath, u64)>> {
+    let mut entries = fs.read_dir(dir, ReadDirMeta::Data).await?;
+    let mut chunks = Vec::new();
+    while let Some(entry) = entries.next().await {
+        let name = entry.name();
+        let meta = entry.metadata().await?;
+        if meta.is_file() && !name.starts_with(b".") {
+            chunks.push((name, meta.len()));
+        }
+    }
+    chunks.sort_by(|a, b| (a.0.len(), &a.0).cmp(&(b.0.len(), &b.0)));
+    chunks
+        .into_iter()
+        .map(|(name, len)| Ok((join(dir, &name)?, len)))
+        .collect()
+}
+
+/// Check the chunks against the OC-Total-Length and OC-Checksum headers.
+/// A checksum with an unknown algorithm is not checked.
+pub async fn verify(
+    fs: &dyn DavFileSystem,
+    chunks: &[(DavPath, u64)],
+    headers: &http::HeaderMap,
+) -> Result<u64, StatusCode>
+{
+    let header = |name| headers.get(name).and_then(|h| h.to_str().ok()).map(str::trim);
+    let total = chunks.iter().map(|(_, len)| len).sum();
+    if let Some(length) = header("OC-Total-Length") {
+        if length.parse::<u64>().ok() != Some(total) {
+            debug!("oc-chunking: OC-Total-Length {}, chunks have {}", length, total);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+    let (algorithm, expected) = match header("OC-Checksum").and_then(|c| c.split_once(':')) {
+        Some(checksum) => checksum,
+        None => return Ok(total),
+    };
+    let mut checksum = match Checksum::new(algorithm) {
+        Some(checksum) => checksum,
+        None => return Ok(total),
+    };
+    for (path, _) in chunks {
+        let mut file = fs.open(path, read_options()).await.map_err(|_| StatusCode::CONFLICT)?;
+        loop {
+            let data = file.read_bytes(CHUNK).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            if data.is_empty() {
+                break;
+            }
+            checksum.update(&data);
+        }
+    }
+    let actual = checksum.finish();
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        debug!("oc-chunking: OC-Checksum {}:{}, chunks have {}", algorithm, expected, actual);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(total)
+}
+
+/// The chunks one after the other, as a request body.
+pub fn body(fs: Box<dyn DavFileSystem>, chunks: Vec<(DavPath, u64)>) -> hyper::Body {
+    let chunks = chunks.into_iter().map(|(path, _)| path);
+    let state = (fs, chunks, None::<Box<dyn DavFile>>);
+    let stream = futures::stream::try_unfold(state, |(fs, mut chunks, mut file)| {
+        async move {
+            loop {
+                let current = match file {
+                    Some(ref mut current) => current,
+                    None => {
+                        match chunks.next() {
+                            Some(path) => file.insert(fs.open(&path, read_options()).await?),
+                            None => return FsResult::Ok(None),
+                        }
+                    },
+                };
+                let data = current.read_bytes(CHUNK).await?;
+                if data.is_empty() {
+                    file = None;
+                    continue;
+                }
+                return Ok(Some((data, (fs, chunks, file))));
+            }
+        }
+    });
+    hyper::Body::wrap_stream(stream.map_err(io::Error::from))
+}
+
+/// The PUT to the Destination of the MOVE, with the same headers.
+/// Overwrite: F becomes If-None-Match: *.
+pub fn put_request(
+    mut req: http::Request<hyper::Body>,
+    body: hyper::Body,
+    total: u64,
+) -> Option<http::Request<hyper::Body>>
+{
+    let dest = req.headers().get("Destination")?.to_str().ok()?.parse::<http::Uri>().ok()?;
+    let no_overwrite = req.headers().get("Overwrite").map(|o| o == "F").unwrap_or(false);
+    let headers = req.headers_mut();
+    for name in &["Destination", "Overwrite", "Depth", "Content-Type", "Transfer-Encoding"] {
+        headers.remove(*name);
+    }
+    headers.insert("Content-Length", total.into());
+    if no_overwrite {
+        headers.insert("If-None-Match", http::HeaderValue::from_static("*"));
+    }
+    *req.method_mut() = http::Method::PUT;
+    *req.uri_mut() = dest;
+    *req.body_mut() = body;
+    Some(req)
+}
+
+/// Remove the upload, after it is done.
+pub async fn remove(fs: &dyn DavFileSystem, dir: &DavPath) {
+    let chunks = match chunks(fs, dir).await {
+        Ok(chunks) => chunks,
+        Err(_) => return,
+    };
+    for (path, _) in chunks {
+        let _ = fs.remove_file(&path).await;
+    }
+    if let Err(e) = fs.remove_dir(dir).await {
+        debug!("oc-chunking: remove {}: {:?}", dir.as_url_string(), e);
+    }
+}
+
+/// Remove the uploads that were last written to before "cutoff": the
+/// directories in the root of the location. The number removed.
+pub async fn expire(fs: &dyn DavFileSystem, cutoff: SystemTime) -> usize {
+    let root = DavPath::new("/").unwrap();
+    let mut entries = match fs.read_dir(&root, ReadDirMeta::DataSymlink).await {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    let mut old = Vec::new();
+    while let Some(entry) = entries.next().await {
+        match entry.metadata().await {
+            Ok(meta) if meta.is_dir() && meta.modified().map(|m| m < cutoff).unwrap_or(false) => {
+                old.push(entry.name());
+            },
+            _ => {},
+        }
+    }
+    let mut removed = 0;
+    for name in old {
+        if let Ok(dir) = join(&root, &name) {
+            remove(fs, &dir).await;
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// The clients want the ETag as OC-ETag too.
+pub fn response_headers(headers: &mut http::HeaderMap) {
+    if let Some(etag) = headers.get("ETag").cloned() {
+        headers.insert("OC-ETag", etag);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_final() {
+        let path = DavPath::new("/abc/.file").unwrap();
+        assert!(is_final(&path));
+        assert_eq!(upload_dir(&path).unwrap().as_url_string(), "/abc");
+        assert!(!is_final(&DavPath::new("/.file").unwrap()));
+    }
+}
@@ -0,0 +1,322 @@
+//
+// OpenID Connect login (auth-type "oidc.<name>").
+//
+// A browser that is not logged in is redirected to the identity provider.
+// It comes back at the redirect-uri with a code, which is exchanged for an
+// id token; the claim in that token is the username. That is then kept in
+// a signed session cookie, which is good for all locations that use the
+// same [oidc.<name>] section. Clients that cannot follow the redirect can
+// send a token of the provider as "Authorization: Bearer".
+//
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+use ring::hmac;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use url::form_urlencoded;
+
+use crate::config;
+use crate::jwt::JwtAuth;
+use crate::session::{b64, cookie, has_cookie, now, random, sign, verify};
+
+type HttpRequest = http::Request<hyper::Body>;
+
+/// Prefix of the name of the session cookie, "webdav-oidc.<name>".
+pub const COOKIE: &str = "webdav-oidc.";
+// Cookie that ties the login at the provider to the browser that started it.
+const STATE_COOKIE: &str = "webdav-oidc-state.";
+// How long a login at the provider may take.
+const STATE_TIMEOUT: u64 = 600;
+
+lazy_static::lazy_static! {
+    // random cookie secrets, by section, so that sessions survive a reload.
+    static ref SECRETS: StdMutex<HashMap<String, Vec<u8>>> = StdMutex::new(HashMap::new());
+}
+
+// What we need from the discovery document.
+#[derive(Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint:         String,
+    jwks_uri:               String,
+}
+
+struct Provider {
+    authorization_endpoint: String,
+    token_endpoint:         String,
+    jwt:                    JwtAuth,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Session {
+    user:    String,
+    expires: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    nonce:   String,
+    url:     String,
+    expires: u64,
+}
+
+#[derive(Clone)]
+pub struct OidcAuth {
+    name:     String,
+    cfg:      config::Oidc,
+    key:      hmac::Key,
+    provider: Arc<Mutex<Option<Arc<Provider>>>>,
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn auth_error(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, msg.into())
+}
+
+/// Does the request have a session cookie (valid or not).
+pub fn has_session(req: &HttpRequest) -> bool {
+    has_cookie(req, COOKIE)
+}
+
+// GET or POST, and parse the JSON that comes back.
+async fn fetch<T: DeserializeOwned>(req: http::Request<hyper::Body>) -> io::Result<T> {
+    let url = req.uri().to_string();
+    let https = hyper_rustls::HttpsConnector::with_native_roots();
+    let client = hyper::Client::builder().build::<_, hyper::Body>(https);
+    let fetch = async {
+        let resp = client.request(req).await.map_err(io::Error::other)?;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body())
+            .await
+            .map_err(io::Error::other)?;
+        if !status.is_success() {
+            let body = String::from_utf8_lossy(&body[..body.len().min(200)]).into_owned();
+            return Err(io::Error::other(format!("{}: {}: {}", url, status, body)));
+        }
+        serde_json::from_slice::<T>(&body).map_err(|e| invalid(format!("{}: {}", url, e)))
+    };
+    match tokio::time::timeout(Duration::from_secs(10), fetch).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("{}: timeout", url))),
+    }
+}
+
+impl OidcAuth {
+    pub fn new(name: &str, cfg: &config::Oidc) -> io::Result<OidcAuth> {
+        let secret = match cfg.cookie_secret {
+            Some(ref secret) => secret.as_bytes().to_vec(),
+            None => {
+                let mut secrets = SECRETS.lock().unwrap();
+                secrets.entry(name.to_string()).or_insert_with(|| random(32)).clone()
+            },
+        };
+        Ok(OidcAuth {
+            name: name.to_string(),
+            cfg: cfg.clone(),
+            key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+            provider: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// The path of the redirect-uri, where the browser comes back.
+    pub fn callback_path(&self) -> String {
+        url::Url::parse(&self.cfg.redirect_uri)
+            .map(|u| u.path().to_string())
+            .unwrap_or_default()
+    }
+
+    // Discover the endpoints of the provider, the first time we need them.
+    async fn provider(&self) -> io::Result<Arc<Provider>> {
+        let mut provider = self.provider.lock().await;
+        if let Some(ref provider) = *provider {
+            return Ok(provider.clone());
+        }
+        let url = format!("{}/.well-known/openid-configuration", self.cfg.issuer.trim_end_matches('/'));
+        let req = http::Request::get(url.as_str()).body(hyper::Body::empty()).map_err(io::Error::other)?;
+        let disc: Discovery = fetch(req).await?;
+        let jwt = config::Jwt {
+            jwks_url: Some(disc.jwks_uri),
+            issuer: Some(self.cfg.issuer.clone()),
+            audience: Some(self.cfg.client_id.clone()),
+            claim: self.cfg.claim.clone(),
+            algorithms: self.cfg.algorithms.clone(),
+            ..config::Jwt::default()
+        };
+        let p = Arc::new(Provider {
+            authorization_endpoint: disc.authorization_endpoint,
+            token_endpoint: disc.token_endpoint,
+            jwt: JwtAuth::new(&jwt)?,
+        });
+        *provider = Some(p.clone());
+        Ok(p)
+    }
+
+    fn set_cookie(&self, name: &str, value: &str, max_age: u64) -> String {
+        let secure = self.cfg.redirect_uri.starts_with("https:");
+        format!(
+            "{}{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            name,
+            self.name,
+            value,
+            max_age,
+            if secure { "; Secure" } else { "" }
+        )
+    }
+
+    /// The user of a valid session cookie.
+    pub fn session(&self, req: &HttpRequest) -> Option<String> {
+        let session: Session = verify(&self.key, cookie(req, &format!("{}{}", COOKIE, self.name))?)?;
+        match session.expires > now() {
+            true => Some(session.user),
+            false => None,
+        }
+    }
+
+    /// Validate a bearer token of the provider, and return the username.
+    pub async fn bearer(&self, token: &str) -> io::Result<String> {
+        self.provider().await?.jwt.auth(token).await
+    }
+
+    /// Where to send the browser to log in, and the state cookie to set.
+    /// After the login it goes back to "url", a path on this server.
+    pub async fn login(&self, url: &str) -> io::Result<(String, String)> {
+        let provider = self.provider().await?;
+        let nonce = b64(&random(16));
+        let state = State {
+            nonce:   nonce.clone(),
+            url:     url.to_string(),
+            expires: now() + STATE_TIMEOUT,
+        };
+        let scopes = self.cfg.scopes.as_ref().map(|s| s.join(" "));
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.cfg.client_id)
+            .append_pair("redirect_uri", &self.cfg.redirect_uri)
+            .append_pair("scope", scopes.as_deref().unwrap_or("openid profile email"))
+            .append_pair("state", &sign(&self.key, &state))
+            .append_pair("nonce", &nonce)
+            .finish();
+        let sep = if provider.authorization_endpoint.contains('?') { '&' } else { '?' };
+        let location = format!("{}{}{}", provider.authorization_endpoint, sep, query);
+        Ok((location, self.set_cookie(STATE_COOKIE, &nonce, STATE_TIMEOUT)))
+    }
+
+    /// The browser is back from the provider. Returns the path to send it
+    /// on to, and the cookies to set.
+    pub async fn callback(&self, req: &HttpRequest) -> io::Result<(String, Vec<String>)> {
+        let query = req.uri().query().unwrap_or("");
+        let params: HashMap<_, _> = form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        if let Some(error) = params.get("error") {
+            let desc = params.get("error_description").map(|d| d.as_str()).unwrap_or("");
+            return Err(auth_error(format!("provider: {} {}", error, desc)));
+        }
+        let state = params.get("state").ok_or_else(|| auth_error("no state"))?;
+        let state: State = verify(&self.key, state).ok_or_else(|| auth_error("invalid state"))?;
+        if state.expires < now() {
+            return Err(auth_error("login took too long"));
+        }
+        if cookie(req, &format!("{}{}", STATE_COOKIE, self.name)) != Some(state.nonce.as_str()) {
+            return Err(auth_error("state cookie missing or does not match"));
+        }
+        let code = params.get("code").ok_or_else(|| auth_error("no code"))?;
+
+        // Exchange the code for the tokens.
+        let provider = self.provider().await?;
+        let token_req = {
+            let mut form = form_urlencoded::Serializer::new(String::new());
+            form.append_pair("grant_type", "authorization_code")
+                .append_pair("code", code)
+                .append_pair("redirect_uri", &self.cfg.redirect_uri);
+            let mut token_req = http::Request::post(provider.token_endpoint.as_str())
+                .header("Content-Type", "application/x-www-form-urlencoded")
+                .header("Accept", "application/json");
+            match self.cfg.client_secret {
+                Some(ref secret) => {
+                    let enc = |s: &str| form_urlencoded::byte_serialize(s.as_bytes()).collect::<String>();
+                    let basic = base64::encode(format!("{}:{}", enc(&self.cfg.client_id), enc(secret)));
+                    token_req = token_req.header("Authorization", format!("Basic {}", basic));
+                },
+                None => {
+                    form.append_pair("client_id", &self.cfg.client_id);
+                },
+            }
+            token_req.body(hyper::Body::from(form.finish())).map_err(io::Error::other)?
+        };
+        let tokens: HashMap<String, serde_json::Value> = fetch(token_req).await?;
+        let id_token = tokens.get("id_token").and_then(|t| t.as_str());
+        let id_token = id_token.ok_or_else(|| invalid("token endpoint: no id_token"))?;
+
+        let claims = provider.jwt.claims(id_token).await?;
+        if claims.get("nonce").and_then(|n| n.as_str()) != Some(state.nonce.as_str()) {
+            return Err(auth_error("id token: nonce does not match"));
+        }
+        let user = provider.jwt.username(&claims)?;
+
+        let timeout = self.cfg.session_timeout.unwrap_or(28800);
+        let session = Session {
+            user,
+            expires: now() + timeout,
+        };
+        let cookies = vec![
+            self.set_cookie(COOKIE, &sign(&self.key, &session), timeout),
+            self.set_cookie(STATE_COOKIE, "", 0),
+        ];
+        // only go back to a path on this server.
+        let url = match state.url.starts_with('/') && !state.url.starts_with("//") {
+            true => state.url,
+            false => "/".to_string(),
+        };
+        Ok((url, cookies))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oidc() {
+        let cfg = config::Oidc {
+            redirect_uri: "https://dav.example.com/oidc/callback".to_string(),
+            cookie_secret: Some("secret".to_string()),
+            ..config::Oidc::default()
+        };
+        let oidc = OidcAuth::new("sso", &cfg).unwrap();
+        assert_eq!(oidc.callback_path(), "/oidc/callback");
+        let session = Session {
+            user:    "alice".to_string(),
+            expires: now() + 60,
+        };
+        let value = sign(&oidc.key, &session);
+        let set_cookie = oidc.set_cookie(COOKIE, &value, 60);
+        assert!(set_cookie.starts_with("webdav-oidc.sso="));
+        assert!(set_cookie.ends_with("; Secure"));
+
+        let req = |cookie: &str| {
+            http::Request::get("/")
+                .header("Cookie", "a=1")
+                .header("Cookie", cookie)
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+        let good = req(&format!("webdav-oidc.sso={}", value));
+        assert_eq!(oidc.session(&good).as_deref(), Some("alice"));
+        assert!(has_session(&good));
+        assert!(oidc.session(&req(&format!("webdav-oidc.other={}", value))).is_none());
+        let other = OidcAuth::new("sso", &config::Oidc::default()).unwrap();
+        assert!(other.session(&good).is_none());
+        let expired = Session {
+            user:    "alice".to_string(),
+            expires: now() - 1,
+        };
+        let expired = req(&format!("webdav-oidc.sso={}", sign(&oidc.key, &expired)));
+        assert!(oidc.session(&expired).is_none());
+    }
+}
@@ -0,0 +1,355 @@
+//
+// Tracing spans, exported to an OpenTelemetry collector ([tracing]).
+//
+// The request path is instrumented with `tracing` spans. When an
+// otlp-endpoint is configured, this subscriber collects the spans of this
+// crate and sends them in batches to the collector, as OTLP/HTTP JSON.
+//
+// A W3C "traceparent" header on the request makes the request span a
+// child of the span of the client (say, a reverse proxy), and requests to
+// an s3 backend get a traceparent header of their own.
+//
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::num::NonZeroU64;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+use tracing_core::span::Current;
+
+use crate::config;
+
+// Spans waiting to be sent are dropped beyond this.
+const MAX_QUEUED: usize = 8192;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static::lazy_static! {
+    static ref SPANS: Mutex<HashMap<u64, Span>> = Mutex::new(HashMap::new());
+    static ref QUEUE: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+    static ref RNG: SystemRandom = SystemRandom::new();
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    // The spans that are entered on this thread.
+    static CURRENT: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+// A span that is open.
+struct Span {
+    metadata:   &'static Metadata<'static>,
+    trace_id:   [u8; 16],
+    span_id:    [u8; 8],
+    parent_id:  Option<[u8; 8]>,
+    server:     bool,
+    start:      SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error:      bool,
+    refs:       usize,
+}
+
+impl Span {
+    fn finish(&self) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(k, v)| json!({ "key": k, "value": v }))
+            .collect();
+        let mut span = json!({
+            "traceId": hex(&self.trace_id),
+            "spanId": hex(&self.span_id),
+            "name": self.metadata.name(),
+            "kind": if self.server { 2 } else { 1 },
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(SystemTime::now()),
+            "attributes": attributes,
+            "status": { "code": if self.error { 2 } else { 0 } },
+        });
+        if let Some(ref parent) = self.parent_id {
+            span["parentSpanId"] = Value::String(hex(parent));
+        }
+        span
+    }
+}
+
+// Collects the fields of a span.
+#[derive(Default)]
+struct Fields {
+    attributes:  Vec<(&'static str, Value)>,
+    traceparent: Option<([u8; 16], [u8; 8])>,
+    error:       bool,
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "traceparent" {
+            self.traceparent = parse_traceparent(value);
+            return;
+        }
+        self.attributes.push((field.name(), json!({ "stringValue": value })));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "http.response.status_code" && value >= 500 {
+            self.error = true;
+        }
+        self.attributes.push((field.name(), json!({ "intValue": value.to_string() })));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.attributes.push((field.name(), json!({ "intValue": value.to_string() })));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == "error" {
+            self.error |= value;
+        }
+        self.attributes.push((field.name(), json!({ "boolValue": value })));
+    }
+}
+
+// The subscriber. All state is global, so that traceparent() can get at it.
+struct Otlp;
+
+impl Subscriber for Otlp {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_span() && metadata.target().starts_with("webdav_server")
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+
+        let parent = if attrs.is_root() {
+            None
+        } else if let Some(parent) = attrs.parent() {
+            Some(parent.into_u64())
+        } else {
+            CURRENT.with(|c| c.borrow().last().cloned())
+        };
+
+        let mut spans = SPANS.lock().unwrap();
+        let parent = parent.and_then(|p| spans.get(&p)).map(|p| (p.trace_id, p.span_id));
+        let (trace_id, parent_id, server) = match (parent, fields.traceparent) {
+            (Some((trace_id, span_id)), _) => (trace_id, Some(span_id), false),
+            (None, Some((trace_id, span_id))) => (trace_id, Some(span_id), true),
+            (None, None) => (random(), None, true),
+        };
+        spans.insert(id, Span {
+            metadata: attrs.metadata(),
+            trace_id,
+            span_id: random(),
+            parent_id,
+            server,
+            start: SystemTime::now(),
+            attributes: fields.attributes,
+            error: fields.error,
+            refs: 1,
+        });
+        Id::from_non_zero_u64(NonZeroU64::new(id).unwrap())
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut fields = Fields::default();
+        values.record(&mut fields);
+        if let Some(span) = SPANS.lock().unwrap().get_mut(&span.into_u64()) {
+            span.attributes.extend(fields.attributes);
+            span.error |= fields.error;
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        CURRENT.with(|c| c.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        let id = span.into_u64();
+        CURRENT.with(|c| {
+            let mut c = c.borrow_mut();
+            if let Some(pos) = c.iter().rposition(|&s| s == id) {
+                c.remove(pos);
+            }
+        });
+    }
+
+    fn current_span(&self) -> Current {
+        let id = match CURRENT.with(|c| c.borrow().last().cloned()) {
+            Some(id) => id,
+            None => return Current::none(),
+        };
+        match SPANS.lock().unwrap().get(&id) {
+            Some(span) => Current::new(Id::from_u64(id), span.metadata),
+            None => Current::none(),
+        }
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(span) = SPANS.lock().unwrap().get_mut(&span.into_u64()) {
+            span.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = SPANS.lock().unwrap();
+        let id = span.into_u64();
+        match spans.get_mut(&id) {
+            Some(span) if span.refs > 1 => {
+                span.refs -= 1;
+                false
+            },
+            Some(_) => {
+                let span = spans.remove(&id).unwrap();
+                drop(spans);
+                let mut queue = QUEUE.lock().unwrap();
+                if queue.len() < MAX_QUEUED {
+                    queue.push(span.finish());
+                }
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// Is the exporter running.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// A traceparent header for the current span, to pass on to a backend.
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+pub fn traceparent() -> Option<String> {
+    if !enabled() {
+        return None;
+    }
+    let id = CURRENT.with(|c| c.borrow().last().cloned())?;
+    let spans = SPANS.lock().unwrap();
+    let span = spans.get(&id)?;
+    Some(format!("00-{}-{}-01", hex(&span.trace_id), hex(&span.span_id)))
+}
+
+/// Install the subscriber and start the exporter, if an otlp-endpoint
+/// is configured. Must be called from within the tokio runtime.
+pub fn start(cfg: &config::Tracing) -> Result<(), String> {
+    let endpoint = match cfg.otlp_endpoint {
+        Some(ref endpoint) => endpoint.clone(),
+        None => return Ok(()),
+    };
+    tracing::subscriber::set_global_default(Otlp).map_err(|e| format!("tracing: {}", e))?;
+    ENABLED.store(true, Ordering::Relaxed);
+
+    let service = cfg.service_name.clone().unwrap_or_else(|| "webdav-server".to_string());
+    let https = hyper_rustls::HttpsConnector::with_native_roots();
+    let client = hyper::Client::builder().build::<_, hyper::Body>(https);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let spans = std::mem::take(&mut *QUEUE.lock().unwrap());
+            if spans.is_empty() {
+                continue;
+            }
+            let body = json!({
+                "resourceSpans": [{
+                    "resource": {
+                        "attributes": [{ "key": "service.name", "value": { "stringValue": service } }],
+                    },
+                    "scopeSpans": [{
+                        "scope": { "name": "webdav-server", "version": env!("CARGO_PKG_VERSION") },
+                        "spans": spans,
+                    }],
+                }],
+            });
+            let req = http::Request::post(&endpoint)
+                .header("content-type", "application/json")
+                .body(hyper::Body::from(body.to_string()))
+                .unwrap();
+            match tokio::time::timeout(Duration::from_secs(30), client.request(req)).await {
+                Ok(Ok(resp)) if resp.status().is_success() => {},
+                Ok(Ok(resp)) => warn!("otlp: {}: {}", endpoint, resp.status()),
+                Ok(Err(e)) => warn!("otlp: {}: {}", endpoint, e),
+                Err(_) => warn!("otlp: {}: timeout", endpoint),
+            }
+        }
+    });
+    Ok(())
+}
+
+// "00-<trace-id>-<parent-id>-<flags>".
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8])> {
+    let mut parts = value.trim().split('-');
+    if parts.next()? != "00" {
+        return None;
+    }
+    let trace_id = unhex(parts.next()?)?;
+    let span_id = unhex(parts.next()?)?;
+    let _flags = parts.next()?;
+    if trace_id == [0; 16] || span_id == [0; 8] || parts.next().is_some() {
+        return None;
+    }
+    Some((trace_id, span_id))
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut id = [0u8; N];
+    let _ = RNG.fill(&mut id);
+    id
+}
+
+fn hex(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 2);
+    for b in data {
+        let _ = write!(s, "{:02x}", b);
+    }
+    s
+}
+
+fn unhex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 {
+        return None;
+    }
+    let mut data = [0u8; N];
+    for (i, b) in data.iter_mut().enumerate() {
+        *b = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(data)
+}
+
+fn nanos(t: SystemTime) -> String {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traceparent() {
+        let tp = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let (trace_id, span_id) = parse_traceparent(tp).unwrap();
+        assert_eq!(hex(&trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&span_id), "00f067aa0ba902b7");
+        assert!(parse_traceparent("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("00-4bf92f3577b34da6-00f067aa0ba902b7-01").is_none());
+    }
+}
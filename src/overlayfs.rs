@@ -0,0 +1,587 @@
+//
+// Overlay filesystem: a read-only base directory, with a writable layer
+// on top of it.
+//
+// Reads come from the upper (writable) layer if the file is there, and from
+// the base otherwise. A file from the base is copied to the upper layer
+// before it is changed. Deleting something that is in the base leaves a
+// whiteout, an empty ".wh.<name>" file, in the upper layer. A directory that
+// was deleted and created again gets a ".wh..wh..opq" file, so that the
+// contents of the base are not visible in it anymore. These are the same
+// names that aufs uses. Names starting with ".wh." cannot be accessed.
+//
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use futures::future::FutureExt;
+use futures::StreamExt;
+use http::StatusCode;
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+const WHITEOUT: &str = ".wh.";
+const OPAQUE: &str = ".wh..wh..opq";
+
+#[derive(Clone)]
+pub struct OverlayFs {
+    lower: Box<dyn DavFileSystem>,
+    upper: Box<dyn DavFileSystem>,
+}
+
+// What is at a path, in both layers. "lower" is only set if the base is
+// not hidden by a whiteout, an opaque directory or a file in the upper layer.
+struct Found {
+    upper: Option<Box<dyn DavMetaData>>,
+    lower: Option<Box<dyn DavMetaData>>,
+}
+
+impl Found {
+    fn meta(&self) -> Option<&dyn DavMetaData> {
+        self.upper.as_deref().or(self.lower.as_deref())
+    }
+
+    fn is_dir(&self) -> bool {
+        self.meta().map(|m| m.is_dir()).unwrap_or(false)
+    }
+}
+
+// The URL encoded segments of a path.
+fn segments(path: &DavPath) -> Vec<String> {
+    let url = path.as_url_string();
+    url.split('/').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect()
+}
+
+// Build a path from segments, plus an optional last segment.
+fn make_path(segs: &[String], last: Option<&str>) -> FsResult<DavPath> {
+    let mut path = String::from("/");
+    for seg in segs.iter().map(|s| s.as_str()).chain(last) {
+        if path.len() > 1 {
+            path.push('/');
+        }
+        path.push_str(seg);
+    }
+    DavPath::new(&path).map_err(|_| FsError::GeneralFailure)
+}
+
+fn read_options() -> OpenOptions {
+    OpenOptions {
+        read: true,
+        ..OpenOptions::default()
+    }
+}
+
+fn write_options() -> OpenOptions {
+    OpenOptions {
+        write: true,
+        create: true,
+        truncate: true,
+        ..OpenOptions::default()
+    }
+}
+
+fn encode(name: &[u8]) -> String {
+    percent_encode(name, NON_ALPHANUMERIC).to_string()
+}
+
+// whiteout files and opaque markers cannot be accessed.
+fn check(path: &DavPath) -> FsResult<()> {
+    let mut segs = path.as_bytes().split(|&c| c == b'/');
+    if segs.any(|s| s.starts_with(WHITEOUT.as_bytes())) {
+        return Err(FsError::Forbidden);
+    }
+    Ok(())
+}
+
+async fn exists(fs: &dyn DavFileSystem, path: &DavPath) -> FsResult<Option<Box<dyn DavMetaData>>> {
+    match fs.metadata(path).await {
+        Ok(meta) => Ok(Some(meta)),
+        Err(FsError::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl OverlayFs {
+    pub fn new(lower: Box<dyn DavFileSystem>, upper: Box<dyn DavFileSystem>) -> Box<OverlayFs> {
+        Box::new(OverlayFs { lower, upper })
+    }
+
+    // Can we see the base at "segs", or is it hidden by the upper layer.
+    async fn lower_visible(&self, segs: &[String]) -> FsResult<bool> {
+        for idx in 0..segs.len() {
+            let parent = &segs[..idx];
+            match exists(&*self.upper, &make_path(parent, None)?).await? {
+                Some(meta) if meta.is_dir() => {},
+                Some(_) => return Ok(false),
+                // no directory, so no whiteouts below it either.
+                None => return Ok(true),
+            }
+            let whiteout = format!("{}{}", WHITEOUT, segs[idx]);
+            for name in &[whiteout.as_str(), OPAQUE] {
+                if exists(&*self.upper, &make_path(parent, Some(name))?).await?.is_some() {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    async fn find(&self, segs: &[String]) -> FsResult<Found> {
+        let path = make_path(segs, None)?;
+        let upper = exists(&*self.upper, &path).await?;
+        let lower = match upper {
+            Some(ref meta) if !meta.is_dir() => None,
+            _ if self.lower_visible(segs).await? => exists(&*self.lower, &path).await?,
+            _ => None,
+        };
+        // a directory in the upper layer hides a file in the base.
+        let lower = lower.filter(|l| upper.is_none() || l.is_dir());
+        Ok(Found { upper, lower })
+    }
+
+    // Make sure the parent directories of "segs" exist in the upper layer.
+    async fn upper_parents(&self, segs: &[String]) -> FsResult<()> {
+        for idx in 1..segs.len() {
+            let path = make_path(&segs[..idx], None)?;
+            match exists(&*self.upper, &path).await? {
+                Some(meta) if meta.is_dir() => continue,
+                Some(_) => return Err(FsError::Forbidden),
+                None => {},
+            }
+            if !self.find(&segs[..idx]).await?.is_dir() {
+                return Err(FsError::NotFound);
+            }
+            self.upper.create_dir(&path).await?;
+        }
+        Ok(())
+    }
+
+    // Copy a file from the base at "from", to the upper layer at "to".
+    async fn copy_up(&self, from: &DavPath, to: &DavPath) -> FsResult<()> {
+        let mut src = self.lower.open(from, read_options()).await?;
+        let mtime = src.metadata().await?.modified();
+        let mut dst = self.upper.open(to, write_options()).await?;
+        loop {
+            let data = src.read_bytes(65536).await?;
+            if data.is_empty() {
+                break;
+            }
+            dst.write_bytes(data).await?;
+        }
+        dst.flush().await?;
+        if let Ok(mtime) = mtime {
+            let _ = self.upper.set_modified(to, mtime).await;
+        }
+        Ok(())
+    }
+
+    // Make sure "segs" is in the upper layer, before changing it.
+    async fn ensure_upper(&self, segs: &[String]) -> FsResult<()> {
+        let found = self.find(segs).await?;
+        if found.upper.is_some() {
+            return Ok(());
+        }
+        let path = make_path(segs, None)?;
+        self.upper_parents(segs).await?;
+        match found.lower {
+            Some(ref meta) if meta.is_dir() => self.upper.create_dir(&path).await,
+            Some(_) => self.copy_up(&path, &path).await,
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    // Create an empty file in the upper layer.
+    async fn create_marker(&self, path: &DavPath) -> FsResult<()> {
+        self.upper.open(path, write_options()).await?.flush().await
+    }
+
+    async fn whiteout(&self, segs: &[String]) -> FsResult<()> {
+        let (name, parent) = segs.split_last().ok_or(FsError::Forbidden)?;
+        self.upper_parents(segs).await?;
+        let whiteout = format!("{}{}", WHITEOUT, name);
+        self.create_marker(&make_path(parent, Some(&whiteout))?).await
+    }
+
+    // Remove the whiteout for "segs", if there is one. Returns true if so.
+    async fn remove_whiteout(&self, segs: &[String]) -> FsResult<bool> {
+        let (name, parent) = segs.split_last().ok_or(FsError::Forbidden)?;
+        let whiteout = format!("{}{}", WHITEOUT, name);
+        match self.upper.remove_file(&make_path(parent, Some(&whiteout))?).await {
+            Ok(()) => Ok(true),
+            Err(FsError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // A new directory where there was a whiteout should not show the base.
+    async fn replace_whiteout(&self, segs: &[String], is_dir: bool) -> FsResult<()> {
+        let (name, parent) = segs.split_last().ok_or(FsError::Forbidden)?;
+        let whiteout = make_path(parent, Some(&format!("{}{}", WHITEOUT, name)))?;
+        let had_whiteout = exists(&*self.upper, &whiteout).await?.is_some();
+        if had_whiteout {
+            if is_dir {
+                self.create_marker(&make_path(segs, Some(OPAQUE))?).await?;
+            }
+            self.remove_whiteout(segs).await?;
+        }
+        Ok(())
+    }
+
+    // Names of the entries in a directory.
+    async fn names(&self, path: &DavPath) -> FsResult<Vec<Vec<u8>>> {
+        let mut entries = self.read_dir(path, ReadDirMeta::None).await?;
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next().await {
+            names.push(entry.name());
+        }
+        Ok(names)
+    }
+
+    // The layer to read "path" from.
+    async fn layer(&self, path: &DavPath) -> FsResult<&dyn DavFileSystem> {
+        check(path)?;
+        let found = self.find(&segments(path)).await?;
+        match (found.upper, found.lower) {
+            (Some(_), _) => Ok(&*self.upper),
+            (None, Some(_)) => Ok(&*self.lower),
+            (None, None) => Err(FsError::NotFound),
+        }
+    }
+}
+
+impl DavFileSystem for OverlayFs {
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move { self.layer(path).await?.metadata(path).await }.boxed()
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move { self.layer(path).await?.symlink_metadata(path).await }.boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            check(path)?;
+            let found = self.find(&segments(path)).await?;
+            if !found.is_dir() {
+                return Err(FsError::Forbidden);
+            }
+
+            let mut entries = Vec::new();
+            let mut names = HashSet::new();
+            let mut opaque = false;
+            if found.upper.is_some() {
+                let mut upper = self.upper.read_dir(path, meta).await?;
+                while let Some(entry) = upper.next().await {
+                    let name = entry.name();
+                    if name == OPAQUE.as_bytes() {
+                        opaque = true;
+                    } else if let Some(name) = name.strip_prefix(WHITEOUT.as_bytes()) {
+                        names.insert(name.to_vec());
+                    } else {
+                        names.insert(name);
+                        entries.push(entry);
+                    }
+                }
+            }
+            if found.lower.is_some() && !opaque {
+                let mut lower = self.lower.read_dir(path, meta).await?;
+                while let Some(entry) = lower.next().await {
+                    let name = entry.name();
+                    if !names.contains(&name) && !name.starts_with(WHITEOUT.as_bytes()) {
+                        entries.push(entry);
+                    }
+                }
+            }
+            Ok(Box::pin(futures::stream::iter(entries)) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            check(path)?;
+            let segs = segments(path);
+            let found = self.find(&segs).await?;
+            if found.is_dir() {
+                return Err(FsError::Forbidden);
+            }
+            let writing = options.write || options.append || options.truncate;
+            if !writing && !options.create && !options.create_new {
+                return match found.upper {
+                    Some(_) => self.upper.open(path, options).await,
+                    None if found.lower.is_some() => self.lower.open(path, options).await,
+                    None => Err(FsError::NotFound),
+                };
+            }
+            if options.create_new && found.meta().is_some() {
+                return Err(FsError::Exists);
+            }
+            if found.upper.is_none() {
+                if found.lower.is_none() && !options.create && !options.create_new {
+                    return Err(FsError::NotFound);
+                }
+                self.upper_parents(&segs).await?;
+                if found.lower.is_some() && !options.truncate {
+                    self.copy_up(path, path).await?;
+                }
+                let file = self.upper.open(path, options).await?;
+                self.remove_whiteout(&segs).await?;
+                return Ok(file);
+            }
+            self.upper.open(path, options).await
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            check(path)?;
+            let segs = segments(path);
+            if self.find(&segs).await?.meta().is_some() {
+                return Err(FsError::Exists);
+            }
+            self.upper_parents(&segs).await?;
+            self.upper.create_dir(path).await?;
+            self.replace_whiteout(&segs, true).await
+        }
+        .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            check(path)?;
+            let segs = segments(path);
+            let found = self.find(&segs).await?;
+            if found.meta().is_none() {
+                return Err(FsError::NotFound);
+            }
+            if !found.is_dir() || segs.is_empty() {
+                return Err(FsError::Forbidden);
+            }
+            if !self.names(path).await?.is_empty() {
+                return Err(FsError::Exists);
+            }
+            if found.upper.is_some() {
+                // only whiteouts are left, remove them.
+                let mut entries = self.upper.read_dir(path, ReadDirMeta::None).await?;
+                let mut markers = Vec::new();
+                while let Some(entry) = entries.next().await {
+                    markers.push(entry.name());
+                }
+                for name in markers {
+                    let name = encode(&name);
+                    self.upper.remove_file(&make_path(&segs, Some(&name))?).await?;
+                }
+                self.upper.remove_dir(path).await?;
+            }
+            if found.lower.is_some() {
+                self.whiteout(&segs).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            check(path)?;
+            let segs = segments(path);
+            let found = self.find(&segs).await?;
+            if found.meta().is_none() {
+                return Err(FsError::NotFound);
+            }
+            if found.is_dir() {
+                return Err(FsError::Forbidden);
+            }
+            if found.upper.is_some() {
+                self.upper.remove_file(path).await?;
+            }
+            // the file might still be in the base.
+            if self.lower_visible(&segs).await? && exists(&*self.lower, path).await?.is_some() {
+                self.whiteout(&segs).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            check(from)?;
+            check(to)?;
+            let (from_segs, to_segs) = (segments(from), segments(to));
+            let found = self.find(&from_segs).await?;
+            let is_dir = match found.meta() {
+                Some(meta) => meta.is_dir(),
+                None => return Err(FsError::NotFound),
+            };
+            if from_segs.is_empty() || to_segs.is_empty() {
+                return Err(FsError::Forbidden);
+            }
+            if self.find(&to_segs).await?.is_dir() {
+                return Err(FsError::Exists);
+            }
+            self.upper_parents(&to_segs).await?;
+
+            if is_dir && found.lower.is_some() {
+                // part of it is in the base, so move it entry by entry.
+                self.create_dir(to).await?;
+                for name in self.names(from).await? {
+                    let name = encode(&name);
+                    let child_from = make_path(&from_segs, Some(&name))?;
+                    let child_to = make_path(&to_segs, Some(&name))?;
+                    self.rename(&child_from, &child_to).await?;
+                }
+                return self.remove_dir(from).await;
+            }
+
+            match found.upper {
+                Some(_) => self.upper.rename(from, to).await?,
+                None => self.copy_up(from, to).await?,
+            }
+            if self.lower_visible(&from_segs).await? && exists(&*self.lower, from).await?.is_some() {
+                self.whiteout(&from_segs).await?;
+            }
+            self.replace_whiteout(&to_segs, is_dir).await
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            check(from)?;
+            check(to)?;
+            let (from_segs, to_segs) = (segments(from), segments(to));
+            let found = self.find(&from_segs).await?;
+            if found.meta().is_none() {
+                return Err(FsError::NotFound);
+            }
+            if found.is_dir() {
+                return Err(FsError::Forbidden);
+            }
+            self.upper_parents(&to_segs).await?;
+            match found.upper {
+                Some(_) => self.upper.copy(from, to).await?,
+                None => self.copy_up(from, to).await?,
+            }
+            self.replace_whiteout(&to_segs, false).await
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            check(path)?;
+            self.ensure_upper(&segments(path)).await?;
+            self.upper.set_accessed(path, tm).await
+        }
+        .boxed()
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            check(path)?;
+            self.ensure_upper(&segments(path)).await?;
+            self.upper.set_modified(path, tm).await
+        }
+        .boxed()
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.upper.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        async move {
+            check(path)?;
+            self.ensure_upper(&segments(path)).await?;
+            self.upper.patch_props(path, patch).await
+        }
+        .boxed()
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        async move { self.layer(path).await?.get_props(path, do_content).await }.boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        async move { self.layer(path).await?.get_prop(path, prop).await }.boxed()
+    }
+
+    // quota of the upper layer, that is where the writes go.
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.upper.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webdav_handler::memfs::MemFs;
+
+    fn path(p: &str) -> DavPath {
+        DavPath::new(p).unwrap()
+    }
+
+    async fn put(fs: &dyn DavFileSystem, p: &str, data: &'static [u8]) {
+        let mut file = fs.open(&path(p), write_options()).await.unwrap();
+        file.write_bytes(hyper::body::Bytes::from_static(data)).await.unwrap();
+    }
+
+    async fn names(fs: &OverlayFs, p: &str) -> Vec<String> {
+        let mut names: Vec<String> = fs
+            .names(&path(p))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|n| String::from_utf8(n).unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_overlay() {
+        let lower = MemFs::new();
+        lower.create_dir(&path("/dir")).await.unwrap();
+        put(&*lower, "/dir/a", b"lower").await;
+        put(&*lower, "/b", b"lower").await;
+        let upper = MemFs::new();
+        let fs = OverlayFs::new(lower.clone(), upper.clone());
+
+        // copy on write.
+        put(&*fs, "/dir/a", b"upper").await;
+        assert_eq!(fs.metadata(&path("/dir/a")).await.unwrap().len(), 5);
+        assert!(upper.metadata(&path("/dir/a")).await.is_ok());
+        assert_eq!(names(&fs, "/").await, vec!["b", "dir"]);
+
+        // whiteouts.
+        fs.remove_file(&path("/dir/a")).await.unwrap();
+        assert!(fs.metadata(&path("/dir/a")).await.is_err());
+        assert!(upper.metadata(&path("/dir/.wh.a")).await.is_ok());
+        assert!(fs.metadata(&path("/dir/.wh.a")).await.is_err());
+        fs.remove_dir(&path("/dir")).await.unwrap();
+        assert_eq!(names(&fs, "/").await, vec!["b"]);
+
+        // a new directory does not show the old contents.
+        fs.create_dir(&path("/dir")).await.unwrap();
+        assert!(names(&fs, "/dir").await.is_empty());
+
+        fs.rename(&path("/b"), &path("/dir/c")).await.unwrap();
+        assert_eq!(names(&fs, "/").await, vec!["dir"]);
+        assert_eq!(names(&fs, "/dir").await, vec!["c"]);
+        assert!(lower.metadata(&path("/b")).await.is_ok());
+    }
+}
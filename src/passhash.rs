@@ -0,0 +1,619 @@
+//
+// Password hashes for the backends that keep their own: htpasswd files,
+// the sqlite user database and SQL databases. New hashes use the scheme
+// of the [password-hash] section:
+//
+// - argon2id: $argon2id$v=19$m=<KiB>,t=<passes>,p=<lanes>$<salt>$<hash>
+// - scrypt:   $scrypt$ln=<log2 N>,r=<r>,p=<p>$<salt>$<hash>
+// - bcrypt:   $2b$<cost>$... (the default)
+//
+// Salt and hash are base64 without padding, as in the PHC string format
+// that libargon2 and passlib use. Argon2 (RFC 9106) and scrypt (RFC 7914)
+// are implemented here, on Blake2b and PBKDF2-HMAC-SHA256 from ring, and
+// checked against the test vectors of the RFCs. They are to be replaced
+// by the RustCrypto argon2 and scrypt crates, which are not available to
+// this build yet.
+//
+// The parameters come from the hashes, and so from whoever can write the
+// password file or the database: a verify may use at most MAX_MEMORY.
+//
+// With rehash = true, a hash that is not in the configured scheme, or has
+// other parameters, is replaced after a successful login: that is how the
+// old hashes get migrated.
+//
+use std::convert::{TryFrom, TryInto};
+use std::io;
+use std::num::NonZeroU32;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::config::{HashScheme, PasswordHash};
+
+const ARGON2_MEMORY: u32 = 19456;
+const ARGON2_TIME: u32 = 2;
+const ARGON2_LANES: u32 = 1;
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+const BCRYPT_COST: u32 = 10;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+// The most memory, and hash length, that a hash may ask for.
+const MAX_MEMORY: u64 = 256 << 20;
+const MAX_HASH_LEN: usize = 64;
+
+// The parameters of a hash.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Params {
+    Argon2id { m: u32, t: u32, p: u32 },
+    Scrypt { ln: u8, r: u32, p: u32 },
+    Bcrypt { cost: u32 },
+}
+
+impl Params {
+    fn configured(cfg: &PasswordHash) -> Params {
+        match cfg.scheme.unwrap_or(HashScheme::Bcrypt) {
+            HashScheme::Argon2id => Params::Argon2id {
+                m: cfg.argon2_memory.unwrap_or(ARGON2_MEMORY),
+                t: cfg.argon2_time.unwrap_or(ARGON2_TIME),
+                p: cfg.argon2_lanes.unwrap_or(ARGON2_LANES),
+            },
+            HashScheme::Scrypt => Params::Scrypt {
+                ln: cfg.scrypt_log_n.unwrap_or(SCRYPT_LOG_N),
+                r:  cfg.scrypt_r.unwrap_or(SCRYPT_R),
+                p:  cfg.scrypt_p.unwrap_or(SCRYPT_P),
+            },
+            HashScheme::Bcrypt => Params::Bcrypt { cost: cfg.bcrypt_cost.unwrap_or(BCRYPT_COST) },
+        }
+    }
+
+    // Argon2 and scrypt: the parameters, salt and hash of a PHC string.
+    fn parse_phc(hash: &str) -> Option<(Params, Vec<u8>, Vec<u8>)> {
+        let mut fields = hash.split('$');
+        let scheme = (fields.next()?, fields.next()?).1;
+        if scheme == "argon2id" && fields.next()? != "v=19" {
+            return None;
+        }
+        let mut values = [0u32; 3];
+        let names: &[&str] = if scheme == "argon2id" { &["m", "t", "p"] } else { &["ln", "r", "p"] };
+        for param in fields.next()?.split(',') {
+            let (name, v) = param.split_once('=')?;
+            let idx = names.iter().position(|n| *n == name)?;
+            values[idx] = v.parse().ok()?;
+        }
+        let params = match scheme {
+            "argon2id" => Params::Argon2id { m: values[0], t: values[1], p: values[2] },
+            "scrypt" => Params::Scrypt { ln: u8::try_from(values[0]).ok()?, r: values[1], p: values[2] },
+            _ => return None,
+        };
+        params.check().ok()?;
+        let salt = base64::decode_config(fields.next()?, base64::STANDARD_NO_PAD).ok()?;
+        let hash = base64::decode_config(fields.next()?, base64::STANDARD_NO_PAD).ok()?;
+        match fields.next().is_none() && salt.len() >= 8 && !hash.is_empty() && hash.len() <= MAX_HASH_LEN {
+            true => Some((params, salt, hash)),
+            false => None,
+        }
+    }
+
+    // Parameters that are in range, and do not take all memory.
+    fn check(&self) -> Result<(), String> {
+        match *self {
+            Params::Argon2id { m, t, p } => {
+                let mem = m as u64 * 1024;
+                if !(1..=64).contains(&p) || !(1..=16).contains(&t) || m < 8 * p || mem > MAX_MEMORY {
+                    return Err("argon2: t 1..16, p 1..64, m 8p..256MiB".to_string());
+                }
+            },
+            Params::Scrypt { ln, r, p } => {
+                let mem = 128u64 * r as u64 * (1u64 << ln.min(63));
+                if ln == 0 || ln > 24 || r == 0 || p == 0 || p > 16 || mem > MAX_MEMORY {
+                    return Err("scrypt: ln 1..24, r >= 1, p 1..16, 128*r*2^ln <= 256MiB".to_string());
+                }
+            },
+            Params::Bcrypt { cost } => {
+                if !(4..=31).contains(&cost) {
+                    return Err("bcrypt: cost 4..31".to_string());
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Check the parameters of the [password-hash] section.
+pub fn check(cfg: &PasswordHash) -> Result<(), String> {
+    Params::configured(cfg).check()
+}
+
+fn salt() -> io::Result<[u8; SALT_LEN]> {
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| io::Error::other("no random numbers"))?;
+    Ok(salt)
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::STANDARD_NO_PAD)
+}
+
+/// A new hash of a password, in the configured scheme.
+pub fn hash(cfg: &PasswordHash, password: &str) -> io::Result<String> {
+    let params = Params::configured(cfg);
+    params.check().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let salt = salt()?;
+    Ok(match params {
+        Params::Argon2id { m, t, p } => {
+            let hash = argon2id(password.as_bytes(), &salt, &[], &[], m, t, p, HASH_LEN);
+            format!("$argon2id$v=19$m={},t={},p={}${}${}", m, t, p, b64(&salt), b64(&hash))
+        },
+        Params::Scrypt { ln, r, p } => {
+            let hash = scrypt(password.as_bytes(), &salt, ln, r, p, HASH_LEN);
+            format!("$scrypt$ln={},r={},p={}${}${}", ln, r, p, b64(&salt), b64(&hash))
+        },
+        Params::Bcrypt { cost } => {
+            let setup = pwhash::bcrypt::BcryptSetup { cost: Some(cost), ..Default::default() };
+            pwhash::bcrypt::hash_with(setup, password).map_err(io::Error::other)?
+        },
+    })
+}
+
+/// Verify an argon2id or scrypt hash. None if it is in another format.
+pub fn verify(password: &str, hash: &str) -> Option<bool> {
+    if !hash.starts_with("$argon2id$") && !hash.starts_with("$scrypt$") {
+        return None;
+    }
+    let (params, salt, hash) = match Params::parse_phc(hash) {
+        Some(phc) => phc,
+        None => return Some(false),
+    };
+    let pw = password.as_bytes();
+    let computed = match params {
+        Params::Argon2id { m, t, p } => argon2id(pw, &salt, &[], &[], m, t, p, hash.len()),
+        Params::Scrypt { ln, r, p } => scrypt(pw, &salt, ln, r, p, hash.len()),
+        Params::Bcrypt { .. } => return Some(false),
+    };
+    Some(crate::htpasswd::consteq(&computed, &hash))
+}
+
+/// True if a hash is not in the configured scheme, or has other parameters.
+pub fn needs_rehash(cfg: &PasswordHash, hash: &str) -> bool {
+    let params = match hash.split('$').nth(1) {
+        Some("argon2id") | Some("scrypt") => Params::parse_phc(hash).map(|(params, _, _)| params),
+        Some("2a") | Some("2b") | Some("2y") => {
+            let cost = hash.split('$').nth(2).and_then(|c| c.parse().ok());
+            cost.map(|cost| Params::Bcrypt { cost })
+        },
+        _ => None,
+    };
+    params != Some(Params::configured(cfg))
+}
+
+/// With rehash, a new hash of the password if the old hash needs it.
+pub fn rehash(cfg: &PasswordHash, password: &str, hash: &str) -> Option<io::Result<String>> {
+    match cfg.rehash && needs_rehash(cfg, hash) {
+        true => Some(self::hash(cfg, password)),
+        false => None,
+    }
+}
+
+/// The name of the format of a hash, for "password migrate".
+pub fn scheme(hash: &str) -> &'static str {
+    match hash.split('$').nth(1) {
+        _ if hash.starts_with("{SHA}") => "sha1",
+        Some("argon2id") => "argon2id",
+        Some("scrypt") => "scrypt",
+        Some("2a") | Some("2b") | Some("2y") => "bcrypt",
+        Some("apr1") | Some("1") => "md5-crypt",
+        Some("5") => "sha256-crypt",
+        Some("6") => "sha512-crypt",
+        _ if hash.len() == 13 => "des-crypt",
+        _ => "unknown",
+    }
+}
+
+//
+// Blake2b (RFC 7693), unkeyed, for Argon2.
+//
+
+const BLAKE2B_IV: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+const SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+fn blake2b_compress(h: &mut [u64; 8], block: &[u8], count: u128, last: bool) {
+    let mut m = [0u64; 16];
+    for (w, b) in m.iter_mut().zip(block.chunks(8)) {
+        *w = u64::from_le_bytes(b.try_into().unwrap());
+    }
+    let mut v = [0u64; 16];
+    v[..8].copy_from_slice(h);
+    v[8..].copy_from_slice(&BLAKE2B_IV);
+    v[12] ^= count as u64;
+    v[13] ^= (count >> 64) as u64;
+    if last {
+        v[14] = !v[14];
+    }
+    let g = |v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64| {
+        v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+        v[d] = (v[d] ^ v[a]).rotate_right(32);
+        v[c] = v[c].wrapping_add(v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(24);
+        v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+        v[d] = (v[d] ^ v[a]).rotate_right(16);
+        v[c] = v[c].wrapping_add(v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(63);
+    };
+    for round in 0..12 {
+        let s = &SIGMA[round % 10];
+        g(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+        g(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+        g(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+        g(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+        g(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+        g(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+        g(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+        g(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+    }
+    for i in 0..8 {
+        h[i] ^= v[i] ^ v[i + 8];
+    }
+}
+
+// Blake2b of the concatenation of "input", "len" (1..64) bytes long.
+fn blake2b(len: usize, input: &[&[u8]]) -> Vec<u8> {
+    let data = input.concat();
+    let mut h = BLAKE2B_IV;
+    h[0] ^= 0x01010000 ^ len as u64;
+    let blocks = data.len().div_ceil(128).max(1);
+    for i in 0..blocks {
+        let mut block = [0u8; 128];
+        let chunk = &data[i * 128..data.len().min((i + 1) * 128)];
+        block[..chunk.len()].copy_from_slice(chunk);
+        let count = (i * 128 + chunk.len()) as u128;
+        blake2b_compress(&mut h, &block, count, i == blocks - 1);
+    }
+    let out: Vec<u8> = h.iter().flat_map(|w| w.to_le_bytes()).collect();
+    out[..len].to_vec()
+}
+
+//
+// Argon2id (RFC 9106), version 0x13. The lanes are filled one after the
+// other, not in parallel: the result is the same.
+//
+
+const BLOCK_WORDS: usize = 128;
+type Block = [u64; BLOCK_WORDS];
+
+// H', the hash of variable length.
+fn argon2_hash(len: usize, input: &[&[u8]]) -> Vec<u8> {
+    let len32 = (len as u32).to_le_bytes();
+    let mut parts = vec![&len32[..]];
+    parts.extend_from_slice(input);
+    if len <= 64 {
+        return blake2b(len, &parts);
+    }
+    // 32 bytes of each of r hashes of 64 bytes, then the rest.
+    let r = len.div_ceil(32) - 2;
+    let mut out = Vec::with_capacity(len);
+    let mut v = blake2b(64, &parts);
+    out.extend_from_slice(&v[..32]);
+    for _ in 1..r {
+        v = blake2b(64, &[&v]);
+        out.extend_from_slice(&v[..32]);
+    }
+    out.extend(blake2b(len - 32 * r, &[&v]));
+    out
+}
+
+fn fblamka(x: u64, y: u64) -> u64 {
+    let m = (x & 0xffffffff).wrapping_mul(y & 0xffffffff);
+    x.wrapping_add(y).wrapping_add(m.wrapping_mul(2))
+}
+
+fn permute(v: &mut Block, idx: [usize; 16]) {
+    let mut gb = |a: usize, b: usize, c: usize, d: usize| {
+        let (a, b, c, d) = (idx[a], idx[b], idx[c], idx[d]);
+        v[a] = fblamka(v[a], v[b]);
+        v[d] = (v[d] ^ v[a]).rotate_right(32);
+        v[c] = fblamka(v[c], v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(24);
+        v[a] = fblamka(v[a], v[b]);
+        v[d] = (v[d] ^ v[a]).rotate_right(16);
+        v[c] = fblamka(v[c], v[d]);
+        v[b] = (v[b] ^ v[c]).rotate_right(63);
+    };
+    gb(0, 4, 8, 12);
+    gb(1, 5, 9, 13);
+    gb(2, 6, 10, 14);
+    gb(3, 7, 11, 15);
+    gb(0, 5, 10, 15);
+    gb(1, 6, 11, 12);
+    gb(2, 7, 8, 13);
+    gb(3, 4, 9, 14);
+}
+
+// The compression function G. With "xor", the result is xor-ed into "out".
+fn compress(x: &Block, y: &Block, out: &mut Block, xor: bool) {
+    let mut r = [0u64; BLOCK_WORDS];
+    for i in 0..BLOCK_WORDS {
+        r[i] = x[i] ^ y[i];
+    }
+    let mut z = r;
+    for row in 0..8 {
+        let mut idx = [0; 16];
+        for (i, n) in idx.iter_mut().enumerate() {
+            *n = row * 16 + i;
+        }
+        permute(&mut z, idx);
+    }
+    for col in 0..8 {
+        let mut idx = [0; 16];
+        for (i, n) in idx.iter_mut().enumerate() {
+            *n = (i / 2) * 16 + col * 2 + i % 2;
+        }
+        permute(&mut z, idx);
+    }
+    for i in 0..BLOCK_WORDS {
+        let v = z[i] ^ r[i];
+        out[i] = if xor { out[i] ^ v } else { v };
+    }
+}
+
+fn to_block(bytes: &[u8]) -> Block {
+    let mut block = [0u64; BLOCK_WORDS];
+    for (w, b) in block.iter_mut().zip(bytes.chunks(8)) {
+        *w = u64::from_le_bytes(b.try_into().unwrap());
+    }
+    block
+}
+
+#[allow(clippy::too_many_arguments)]
+fn argon2id(pw: &[u8], salt: &[u8], key: &[u8], ad: &[u8], m: u32, t: u32, p: u32, len: usize) -> Vec<u8> {
+    const SYNC_POINTS: usize = 4;
+    let le = |n: usize| (n as u32).to_le_bytes();
+    let h0 = blake2b(64, &[
+        &p.to_le_bytes(),
+        &le(len),
+        &m.to_le_bytes(),
+        &t.to_le_bytes(),
+        &0x13u32.to_le_bytes(),
+        &2u32.to_le_bytes(),
+        &le(pw.len()),
+        pw,
+        &le(salt.len()),
+        salt,
+        &le(key.len()),
+        key,
+        &le(ad.len()),
+        ad,
+    ]);
+
+    let lanes = p as usize;
+    let seg_len = m as usize / (SYNC_POINTS * lanes);
+    let lane_len = seg_len * SYNC_POINTS;
+    let mut mem = vec![[0u64; BLOCK_WORDS]; lane_len * lanes];
+    for l in 0..lanes {
+        for j in 0..2 {
+            mem[l * lane_len + j] = to_block(&argon2_hash(1024, &[&h0, &le(j), &le(l)]));
+        }
+    }
+
+    let zero = [0u64; BLOCK_WORDS];
+    for pass in 0..t as usize {
+        for slice in 0..SYNC_POINTS {
+            for lane in 0..lanes {
+                // Argon2id: data-independent addresses in the first half of the first pass.
+                let independent = pass == 0 && slice < SYNC_POINTS / 2;
+                let mut input = [0u64; BLOCK_WORDS];
+                let mut addresses = [0u64; BLOCK_WORDS];
+                let position = [pass as u64, lane as u64, slice as u64, mem.len() as u64, t as u64, 2];
+                input[..6].copy_from_slice(&position);
+                let next_addresses = |input: &mut Block, addresses: &mut Block| {
+                    input[6] += 1;
+                    let mut tmp = [0u64; BLOCK_WORDS];
+                    compress(&zero, input, &mut tmp, false);
+                    compress(&zero, &tmp, addresses, false);
+                };
+                let start = if pass == 0 && slice == 0 { 2 } else { 0 };
+                if independent && start == 2 {
+                    next_addresses(&mut input, &mut addresses);
+                }
+                for index in start..seg_len {
+                    let col = slice * seg_len + index;
+                    let cur = lane * lane_len + col;
+                    let prev = if col == 0 { cur + lane_len - 1 } else { cur - 1 };
+                    let rand = if independent {
+                        if index % BLOCK_WORDS == 0 {
+                            next_addresses(&mut input, &mut addresses);
+                        }
+                        addresses[index % BLOCK_WORDS]
+                    } else {
+                        mem[prev][0]
+                    };
+                    let ref_lane = match pass == 0 && slice == 0 {
+                        true => lane,
+                        false => (rand >> 32) as usize % lanes,
+                    };
+                    let same_lane = ref_lane == lane;
+                    let done = if pass == 0 { slice * seg_len } else { lane_len - seg_len };
+                    let area = match same_lane {
+                        true => done + index - 1,
+                        false => done - (index == 0) as usize,
+                    };
+                    let x = ((rand & 0xffffffff) * (rand & 0xffffffff)) >> 32;
+                    let rel = area - 1 - ((area as u64 * x) >> 32) as usize;
+                    let begin = match pass != 0 && slice != SYNC_POINTS - 1 {
+                        true => (slice + 1) * seg_len,
+                        false => 0,
+                    };
+                    let refb = ref_lane * lane_len + (begin + rel) % lane_len;
+                    let (pb, rb) = (mem[prev], mem[refb]);
+                    compress(&pb, &rb, &mut mem[cur], pass > 0);
+                }
+            }
+        }
+    }
+
+    let mut last = mem[lane_len - 1];
+    for l in 1..lanes {
+        for (w, x) in last.iter_mut().zip(mem[l * lane_len + lane_len - 1].iter()) {
+            *w ^= x;
+        }
+    }
+    let bytes: Vec<u8> = last.iter().flat_map(|w| w.to_le_bytes()).collect();
+    argon2_hash(len, &[&bytes])
+}
+
+//
+// scrypt (RFC 7914).
+//
+
+fn salsa20_8(b: &mut [u32; 16]) {
+    let mut x = *b;
+    let mut quarter = |a: usize, b: usize, c: usize, d: usize| {
+        x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+        x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+        x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+        x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+    };
+    for _ in 0..4 {
+        quarter(0, 4, 8, 12);
+        quarter(5, 9, 13, 1);
+        quarter(10, 14, 2, 6);
+        quarter(15, 3, 7, 11);
+        quarter(0, 1, 2, 3);
+        quarter(5, 6, 7, 4);
+        quarter(10, 11, 8, 9);
+        quarter(15, 12, 13, 14);
+    }
+    for (w, x) in b.iter_mut().zip(x.iter()) {
+        *w = w.wrapping_add(*x);
+    }
+}
+
+// BlockMix with Salsa20/8, on 2r blocks of 16 words.
+fn block_mix(b: &[u32], out: &mut [u32]) {
+    let r = b.len() / 32;
+    let mut x = [0u32; 16];
+    x.copy_from_slice(&b[b.len() - 16..]);
+    for i in 0..2 * r {
+        for (w, v) in x.iter_mut().zip(&b[i * 16..(i + 1) * 16]) {
+            *w ^= v;
+        }
+        salsa20_8(&mut x);
+        let pos = (i / 2 + (i % 2) * r) * 16;
+        out[pos..pos + 16].copy_from_slice(&x);
+    }
+}
+
+fn ro_mix(b: &mut [u32], n: usize) {
+    let len = b.len();
+    let mut v = vec![0u32; len * n];
+    let mut x = b.to_vec();
+    let mut y = vec![0u32; len];
+    for i in 0..n {
+        v[i * len..(i + 1) * len].copy_from_slice(&x);
+        block_mix(&x, &mut y);
+        std::mem::swap(&mut x, &mut y);
+    }
+    for _ in 0..n {
+        let j = x[len - 16] as usize & (n - 1);
+        for (w, v) in x.iter_mut().zip(&v[j * len..(j + 1) * len]) {
+            *w ^= v;
+        }
+        block_mix(&x, &mut y);
+        std::mem::swap(&mut x, &mut y);
+    }
+    b.copy_from_slice(&x);
+}
+
+fn pbkdf2_sha256(pw: &[u8], salt: &[u8], out: &mut [u8]) {
+    let one = NonZeroU32::new(1).unwrap();
+    ring::pbkdf2::derive(ring::pbkdf2::PBKDF2_HMAC_SHA256, one, salt, pw, out);
+}
+
+fn scrypt(pw: &[u8], salt: &[u8], ln: u8, r: u32, p: u32, len: usize) -> Vec<u8> {
+    let block = 128 * r as usize;
+    let mut bytes = vec![0u8; block * p as usize];
+    pbkdf2_sha256(pw, salt, &mut bytes);
+    for chunk in bytes.chunks_mut(block) {
+        let mut words: Vec<u32> = chunk
+            .chunks(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        ro_mix(&mut words, 1 << ln);
+        for (b, w) in chunk.chunks_mut(4).zip(words) {
+            b.copy_from_slice(&w.to_le_bytes());
+        }
+    }
+    let mut out = vec![0u8; len];
+    pbkdf2_sha256(pw, &bytes, &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_passhash() {
+        // RFC 9106 5.3, and RFC 7914 12.
+        let tag = argon2id(&[1; 32], &[2; 16], &[3; 8], &[4; 12], 32, 3, 4, 32);
+        assert_eq!(hex(&tag), "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659");
+        let dk = scrypt(b"password", b"NaCl", 10, 8, 16, 16);
+        assert_eq!(hex(&dk), "fdbabe1c9d3472007856e7190d01e9fe");
+
+        let mut cfg = PasswordHash {
+            scheme: Some(HashScheme::Argon2id),
+            argon2_memory: Some(64),
+            argon2_time: Some(1),
+            ..PasswordHash::default()
+        };
+        let hash = hash(&cfg, "secret").unwrap();
+        assert_eq!(verify("secret", &hash), Some(true));
+        assert_eq!(verify("Secret", &hash), Some(false));
+        assert!(crate::htpasswd::verify("secret", &hash));
+        assert!(!needs_rehash(&cfg, &hash));
+        cfg.argon2_time = Some(2);
+        assert!(needs_rehash(&cfg, &hash));
+
+        cfg.scheme = Some(HashScheme::Scrypt);
+        cfg.scrypt_log_n = Some(4);
+        let hash = super::hash(&cfg, "secret").unwrap();
+        assert!(hash.starts_with("$scrypt$ln=4,r=8,p=1$"));
+        assert_eq!(verify("secret", &hash), Some(true));
+        assert!(needs_rehash(&PasswordHash::default(), &hash));
+        assert!(needs_rehash(&cfg, "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ="));
+        assert_eq!(verify("secret", "$2y$05$bvIG6Nmid91Mu9RcmmWZfO5HJIMCT8riNW0hEp8f6/FuA2/mHZFpe"), None);
+
+        // from a password file: too much memory is refused, not tried.
+        let big = "$argon2id$v=19$m=4194304,t=1,p=1$c2FsdHNhbHQ$aGFzaGhhc2hoYXNo";
+        assert_eq!(verify("secret", big), Some(false));
+        assert_eq!(verify("secret", "$scrypt$ln=21,r=8,p=1$c2FsdHNhbHQ$aGFzaGhhc2hoYXNo"), Some(false));
+        assert!(Params::Argon2id { m: 262144, t: 1, p: 1 }.check().is_ok());
+    }
+}
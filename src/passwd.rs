@@ -0,0 +1,176 @@
+//
+// Changing the password, for users who log in with PAM ([pam]
+// change-password). Once a password has expired, no login works (with
+// account = true), and without a shell somewhere there is no way to set
+// a new one. A GET of the URL is a form, a POST changes the password,
+// from the fields
+//
+//   username, password, new-password (and new-password2, the same again)
+//
+// as application/x-www-form-urlencoded (the form) or JSON. The old
+// password is always in the body: a browser sends an Authorization:
+// header by itself, so another site could post the form with it.
+//
+// The form gets a page back, JSON "204 No Content". It is 403 if the old
+// password is wrong, 400 if PAM does not accept the new one, and 429
+// after too many failures, which count as failed logins.
+//
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use http::{Method, StatusCode};
+use serde::Deserialize;
+use url::form_urlencoded;
+
+use crate::auth::Auth;
+use crate::report::escape;
+
+type HttpRequest = http::Request<hyper::Body>;
+type HttpResponse = http::Response<hyper::Body>;
+
+const MAX_BODY: usize = 8192;
+
+const HTML: &str = r#"<!DOCTYPE html>
+<html><head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Change password</title>
+<style>
+body { font-family: sans-serif; margin: 3em auto; max-width: 25em; color: #222; }
+h1 { font-weight: normal; }
+label { display: block; margin-top: 1em; }
+input { width: 100%; box-sizing: border-box; }
+.message { padding: 0.5em; background: #eee; }
+</style>
+</head><body>
+<h1>Change password</h1>
+{{message}}
+<form method="post">
+<label>Username<br><input name="username" value="{{username}}" autocomplete="username" required></label>
+<label>Current password<br>
+<input name="password" type="password" autocomplete="current-password" required></label>
+<label>New password<br>
+<input name="new-password" type="password" autocomplete="new-password" required></label>
+<label>New password again<br>
+<input name="new-password2" type="password" autocomplete="new-password" required></label>
+<p><button type="submit">Change password</button></p>
+</form>
+</body></html>
+"#;
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+struct Change {
+    #[serde(default)]
+    username:      String,
+    #[serde(default)]
+    password:      String,
+    #[serde(rename = "new-password", default)]
+    new_password:  String,
+    #[serde(rename = "new-password2", default)]
+    new_password2: Option<String>,
+}
+
+// The fields, from a form or JSON.
+fn parse(json: bool, body: &[u8]) -> Option<Change> {
+    if json {
+        return serde_json::from_slice(body).ok();
+    }
+    let mut fields: HashMap<_, _> = form_urlencoded::parse(body).into_owned().collect();
+    let mut field = |name: &str| fields.remove(name);
+    Some(Change {
+        username:      field("username").unwrap_or_default(),
+        password:      field("password").unwrap_or_default(),
+        new_password:  field("new-password").unwrap_or_default(),
+        new_password2: field("new-password2"),
+    })
+}
+
+// The form with a message, or for JSON the message as "error".
+fn answer(json: bool, status: StatusCode, message: &str, username: &str) -> HttpResponse {
+    let resp = http::Response::builder()
+        .status(status)
+        .header("Cache-Control", "no-store")
+        .header("Content-Security-Policy", "frame-ancestors 'none'");
+    let (content_type, body) = match json {
+        true if status == StatusCode::NO_CONTENT => return resp.body(hyper::Body::empty()).unwrap(),
+        true => ("application/json", format!("{}\n", serde_json::json!({ "error": message }))),
+        false => {
+            let message = match message {
+                "" => String::new(),
+                m => format!("<p class=\"message\">{}</p>", escape(m)),
+            };
+            let page = HTML.replace("{{message}}", &message).replace("{{username}}", &escape(username));
+            ("text/html; charset=utf-8", page)
+        },
+    };
+    resp.header("Content-Type", content_type).body(body.into()).unwrap()
+}
+
+/// A request for the change-password URL.
+pub async fn handle(auth: &Auth, mut req: HttpRequest, remote_ip: SocketAddr) -> HttpResponse {
+    match *req.method() {
+        Method::GET | Method::HEAD => return answer(false, StatusCode::OK, "", ""),
+        Method::POST => {},
+        _ => {
+            let mut resp = answer(true, StatusCode::METHOD_NOT_ALLOWED, "method not allowed", "");
+            resp.headers_mut().insert("Allow", "GET, HEAD, POST".parse().unwrap());
+            return resp;
+        },
+    }
+    let content_type = req.headers().get(http::header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    let json = content_type.map(|c| c.starts_with("application/json")).unwrap_or(false);
+
+    if crate::uploadlimit::content_length(&req).map(|l| l > MAX_BODY as u64).unwrap_or(false) {
+        return answer(json, StatusCode::PAYLOAD_TOO_LARGE, "request too large", "");
+    }
+    let body = match hyper::body::to_bytes(std::mem::take(req.body_mut())).await {
+        Ok(body) if body.len() <= MAX_BODY => body,
+        Ok(_) => return answer(json, StatusCode::PAYLOAD_TOO_LARGE, "request too large", ""),
+        Err(_) => return answer(json, StatusCode::BAD_REQUEST, "bad request", ""),
+    };
+    let change = match parse(json, &body) {
+        Some(change) => change,
+        None => return answer(json, StatusCode::BAD_REQUEST, "bad request", ""),
+    };
+    let user = change.username.as_str();
+    if user.is_empty() || change.password.is_empty() || change.new_password.is_empty() {
+        let message = "username, password and new-password are needed";
+        return answer(json, StatusCode::BAD_REQUEST, message, user);
+    }
+    if change.new_password2.as_ref().map(|p| p != &change.new_password).unwrap_or(false) {
+        return answer(json, StatusCode::BAD_REQUEST, "the new passwords are not the same", user);
+    }
+
+    let res = auth.change_password(user, &change.password, &change.new_password, remote_ip);
+    match res.await {
+        Ok(()) if json => answer(json, StatusCode::NO_CONTENT, "", user),
+        Ok(()) => answer(json, StatusCode::OK, "The password was changed.", user),
+        Err(StatusCode::FORBIDDEN) => answer(json, StatusCode::FORBIDDEN, "wrong username or password", user),
+        Err(StatusCode::BAD_REQUEST) => {
+            answer(json, StatusCode::BAD_REQUEST, "the new password was not accepted", user)
+        },
+        Err(StatusCode::TOO_MANY_REQUESTS) => {
+            answer(json, StatusCode::TOO_MANY_REQUESTS, "too many failures, try again later", user)
+        },
+        Err(status) => answer(json, status, status.canonical_reason().unwrap_or(""), user),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let form = b"username=bob&password=old%26pw&new-password=n%C3%A9w&new-password2=n%C3%A9w";
+        let change = parse(false, form).unwrap();
+        assert_eq!((change.username.as_str(), change.password.as_str()), ("bob", "old&pw"));
+        assert_eq!((change.new_password.as_str(), change.new_password2.as_deref()), ("néw", Some("néw")));
+
+        let json = br#"{"username": "bob", "password": "old", "new-password": "new"}"#;
+        let change = parse(true, json).unwrap();
+        assert_eq!((change.new_password.as_str(), change.new_password2), ("new", None));
+        assert!(parse(true, b"username=bob").is_none());
+        assert_eq!(parse(false, b"").unwrap(), Change::default());
+    }
+}
@@ -0,0 +1,349 @@
+//
+// A small PostgreSQL client: log in (password, md5 or SCRAM-SHA-256),
+// and run one query with one text parameter, $1. The values are read
+// as text. There is no TLS, so password and md5, which give away the
+// password or what works as one, are only done over a unix socket.
+//
+use std::convert::TryInto;
+use std::io;
+use std::num::NonZeroU32;
+
+use md5::{Digest, Md5};
+use ring::{digest, hmac, pbkdf2, rand::SecureRandom};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::sql::{Row, Stream, Url};
+
+// A message: the type, the length, and the contents.
+fn message(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut msg = vec![tag];
+    msg.extend_from_slice(&(body.len() as i32 + 4).to_be_bytes());
+    msg.extend_from_slice(body);
+    msg
+}
+
+fn cstr(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+}
+
+fn protocol_error(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("postgres: {}", msg.into()))
+}
+
+async fn read_message(stream: &mut Box<dyn Stream>) -> io::Result<(u8, Vec<u8>)> {
+    let tag = stream.read_u8().await?;
+    let len = stream.read_i32().await?;
+    if !(4..=16 * 1024 * 1024).contains(&len) {
+        return Err(protocol_error(format!("message length {}", len)));
+    }
+    let mut body = vec![0u8; len as usize - 4];
+    stream.read_exact(&mut body).await?;
+    Ok((tag, body))
+}
+
+// The message of an ErrorResponse: severity and text.
+fn error_response(body: &[u8]) -> io::Error {
+    let (mut severity, mut text) = ("ERROR", "");
+    for field in body.split(|&b| b == 0).filter(|f| !f.is_empty()) {
+        let value = std::str::from_utf8(&field[1..]).unwrap_or("");
+        match field[0] {
+            b'S' => severity = value,
+            b'M' => text = value,
+            _ => {},
+        }
+    }
+    io::Error::other(format!("postgres: {}: {}", severity, text))
+}
+
+// A cursor over the contents of a message.
+struct Body<'a>(&'a [u8]);
+
+impl<'a> Body<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(protocol_error("short message"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn cstr(&mut self) -> io::Result<String> {
+        let end = self.0.iter().position(|&b| b == 0).ok_or_else(|| protocol_error("no NUL"))?;
+        let s = String::from_utf8_lossy(&self.0[..end]).into_owned();
+        self.0 = &self.0[end + 1..];
+        Ok(s)
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// "md5" authentication: md5(md5(password + user) + salt).
+fn md5_password(user: &str, password: &str, salt: &[u8]) -> String {
+    let inner = hex(&Md5::digest(format!("{}{}", password, user).as_bytes()));
+    let mut outer = Md5::new();
+    outer.update(inner.as_bytes());
+    outer.update(salt);
+    format!("md5{}", hex(&outer.finalize()))
+}
+
+// How far a SCRAM-SHA-256 exchange (RFC 5802, 7677) is.
+struct Scram {
+    password:     String,
+    client_first: String,
+    nonce:        String,
+    server_key:   Option<Vec<u8>>,
+    auth_message: String,
+}
+
+impl Scram {
+    // The username is in the startup message, not here.
+    fn new(password: &str, nonce: &str, user: &str) -> Scram {
+        Scram {
+            password:     password.to_string(),
+            client_first: format!("n={},r={}", user, nonce),
+            nonce:        nonce.to_string(),
+            server_key:   None,
+            auth_message: String::new(),
+        }
+    }
+
+    fn client_first(&self) -> String {
+        format!("n,,{}", self.client_first)
+    }
+
+    // The answer to the server-first-message, with the proof.
+    fn client_final(&mut self, server_first: &str) -> io::Result<String> {
+        let attr = |name: &str| {
+            server_first.split(',').find_map(|a| a.strip_prefix(name)).ok_or_else(|| {
+                protocol_error(format!("SCRAM: no {} in {}", name, server_first))
+            })
+        };
+        let nonce = attr("r=")?;
+        if !nonce.starts_with(&self.nonce) {
+            return Err(protocol_error("SCRAM: server nonce"));
+        }
+        let salt = base64::decode(attr("s=")?).map_err(|_| protocol_error("SCRAM: salt"))?;
+        let iterations = attr("i=")?.parse().ok().and_then(NonZeroU32::new);
+        let iterations = iterations.ok_or_else(|| protocol_error("SCRAM: iterations"))?;
+
+        let mut salted = [0u8; 32];
+        let pbkdf = pbkdf2::PBKDF2_HMAC_SHA256;
+        pbkdf2::derive(pbkdf, iterations, &salt, self.password.as_bytes(), &mut salted);
+        let salted = hmac::Key::new(hmac::HMAC_SHA256, &salted);
+        let client_key = hmac::sign(&salted, b"Client Key");
+        let stored_key = digest::digest(&digest::SHA256, client_key.as_ref());
+
+        let without_proof = format!("c=biws,r={}", nonce);
+        self.auth_message = format!("{},{},{}", self.client_first, server_first, without_proof);
+        let stored_key = hmac::Key::new(hmac::HMAC_SHA256, stored_key.as_ref());
+        let signature = hmac::sign(&stored_key, self.auth_message.as_bytes());
+        let proof: Vec<u8> = client_key.as_ref().iter().zip(signature.as_ref()).map(|(a, b)| a ^ b).collect();
+        self.server_key = Some(hmac::sign(&salted, b"Server Key").as_ref().to_vec());
+        Ok(format!("{},p={}", without_proof, base64::encode(proof)))
+    }
+
+    // Check the signature of the server-final-message.
+    fn verify(&self, server_final: &str) -> io::Result<()> {
+        let key = self.server_key.as_ref().ok_or_else(|| protocol_error("SCRAM: out of order"))?;
+        let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+        let signature = base64::encode(hmac::sign(&key, self.auth_message.as_bytes()));
+        match server_final.strip_prefix("v=") {
+            Some(v) if crate::htpasswd::consteq(v.as_bytes(), signature.as_bytes()) => Ok(()),
+            _ => Err(protocol_error("SCRAM: server signature")),
+        }
+    }
+}
+
+fn nonce() -> io::Result<String> {
+    let mut nonce = [0u8; 18];
+    ring::rand::SystemRandom::new().fill(&mut nonce).map_err(|_| io::Error::other("no random"))?;
+    Ok(base64::encode(nonce))
+}
+
+async fn login(stream: &mut Box<dyn Stream>, url: &Url, unix: bool) -> io::Result<()> {
+    let mut startup = 196608i32.to_be_bytes().to_vec();
+    for (name, value) in [("user", &url.user), ("database", &url.database)] {
+        if !value.is_empty() {
+            cstr(&mut startup, name);
+            cstr(&mut startup, value);
+        }
+    }
+    cstr(&mut startup, "client_encoding");
+    cstr(&mut startup, "UTF8");
+    startup.push(0);
+    let mut msg = (startup.len() as i32 + 4).to_be_bytes().to_vec();
+    msg.extend_from_slice(&startup);
+    stream.write_all(&msg).await?;
+
+    let mut scram = None;
+    loop {
+        let (tag, body) = read_message(stream).await?;
+        let mut b = Body(&body);
+        match tag {
+            b'R' => {},
+            b'E' => return Err(error_response(&body)),
+            _ => return Err(protocol_error(format!("unexpected message {:?}", tag as char))),
+        }
+        let answer = match b.i32()? {
+            0 => break,
+            code @ (3 | 5) if !unix => {
+                let method = if code == 3 { "password" } else { "md5" };
+                let msg = format!("{} authentication is only done over a unix socket", method);
+                return Err(protocol_error(msg));
+            },
+            // cleartext.
+            3 => {
+                let mut pass = Vec::new();
+                cstr(&mut pass, &url.password);
+                pass
+            },
+            5 => {
+                let mut pass = Vec::new();
+                cstr(&mut pass, &md5_password(&url.user, &url.password, b.bytes(4)?));
+                pass
+            },
+            // SASL: the mechanisms the server has.
+            10 => {
+                let mut mechanisms = Vec::new();
+                while !b.0.is_empty() && b.0[0] != 0 {
+                    mechanisms.push(b.cstr()?);
+                }
+                if !mechanisms.iter().any(|m| m == "SCRAM-SHA-256") {
+                    return Err(protocol_error(format!("no supported SASL mechanism in {:?}", mechanisms)));
+                }
+                let s = Scram::new(&url.password, &nonce()?, "");
+                let first = s.client_first();
+                scram = Some(s);
+                let mut answer = Vec::new();
+                cstr(&mut answer, "SCRAM-SHA-256");
+                answer.extend_from_slice(&(first.len() as i32).to_be_bytes());
+                answer.extend_from_slice(first.as_bytes());
+                answer
+            },
+            11 => {
+                let scram = scram.as_mut().ok_or_else(|| protocol_error("SASL: out of order"))?;
+                scram.client_final(&String::from_utf8_lossy(b.0))?.into_bytes()
+            },
+            12 => {
+                let scram = scram.as_ref().ok_or_else(|| protocol_error("SASL: out of order"))?;
+                scram.verify(&String::from_utf8_lossy(b.0))?;
+                continue;
+            },
+            code => return Err(protocol_error(format!("authentication method {} not supported", code))),
+        };
+        stream.write_all(&message(b'p', &answer)).await?;
+    }
+
+    // parameters, the key to cancel with, notices; then ReadyForQuery.
+    loop {
+        match read_message(stream).await? {
+            (b'Z', _) => return Ok(()),
+            (b'E', body) => return Err(error_response(&body)),
+            _ => {},
+        }
+    }
+}
+
+/// Log in, and run the query with "param" for $1.
+pub async fn query(
+    stream: &mut Box<dyn Stream>,
+    url: &Url,
+    unix: bool,
+    query: &str,
+    param: &str,
+) -> io::Result<Vec<Row>>
+{
+    login(stream, url, unix).await?;
+
+    // parse, bind, describe, execute, sync.
+    let mut parse = vec![0];
+    cstr(&mut parse, query);
+    parse.extend_from_slice(&0i16.to_be_bytes());
+    let mut bind = vec![0, 0];
+    bind.extend_from_slice(&0i16.to_be_bytes());
+    bind.extend_from_slice(&1i16.to_be_bytes());
+    bind.extend_from_slice(&(param.len() as i32).to_be_bytes());
+    bind.extend_from_slice(param.as_bytes());
+    bind.extend_from_slice(&0i16.to_be_bytes());
+    let mut msg = message(b'P', &parse);
+    msg.extend(message(b'B', &bind));
+    msg.extend(message(b'D', b"P\0"));
+    msg.extend(message(b'E', &[0, 0, 0, 0, 0]));
+    msg.extend(message(b'S', b""));
+    stream.write_all(&msg).await?;
+
+    let mut columns = Vec::new();
+    let mut rows = Vec::new();
+    let mut error = None;
+    loop {
+        let (tag, body) = read_message(stream).await?;
+        let mut b = Body(&body);
+        match tag {
+            b'T' => {
+                for _ in 0..b.i16()? {
+                    columns.push(b.cstr()?);
+                    b.bytes(18)?;
+                }
+            },
+            b'D' => {
+                let mut row = Row::new();
+                for idx in 0..b.i16()? as usize {
+                    let value = match b.i32()? {
+                        -1 => None,
+                        len => Some(String::from_utf8_lossy(b.bytes(len.max(0) as usize)?).into_owned()),
+                    };
+                    if let Some(name) = columns.get(idx) {
+                        row.insert(name.to_string(), value);
+                    }
+                }
+                rows.push(row);
+            },
+            b'E' => error = Some(error_response(&body)),
+            b'Z' => break,
+            _ => {},
+        }
+    }
+    let _ = stream.write_all(&message(b'X', b"")).await;
+    match error {
+        Some(e) => Err(e),
+        None => Ok(rows),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pgsql() {
+        // RFC 7677, section 3.
+        let mut scram = Scram::new("pencil", "rOprNGfwEbeRWgbNEkqO", "user");
+        assert_eq!(scram.client_first(), "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        assert_eq!(
+            scram.client_final(server_first).unwrap(),
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        assert!(scram.verify("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=").is_ok());
+        assert!(scram.verify("v=AAAA").is_err());
+        assert!(Scram::new("pencil", "xyz", "").client_final(server_first).is_err());
+
+        assert_eq!(md5_password("postgres", "secret", b"abcd"), "md568a34aeb823f3662497ea0b9fb65e46a");
+        let err = error_response(b"SERROR\0C42P01\0Mrelation \"users\" does not exist\0\0");
+        assert_eq!(err.to_string(), "postgres: ERROR: relation \"users\" does not exist");
+    }
+}
@@ -0,0 +1,609 @@
+//
+// Calendars (CalDAV, RFC 4791) and address books (CardDAV, RFC 6352), on a
+// location with handler = "caldav" or "carddav".
+//
+// The root of the location is the home of the user, and also its
+// principal. Every directory in the root is a calendar or address book,
+// and the files in it are its objects (.ics or .vcf). The handler serves
+// GET, PUT, DELETE, MOVE, PROPPATCH and LOCK as usual. PROPFIND,
+// MKCALENDAR, MKCOL and the query and multiget REPORTs are done here, as
+// the handler only knows plain collections.
+//
+// The CTag of a collection is a digest of the names and ETags of its
+// members. With a sync-db, there is a DAV:sync-token too.
+//
+use futures::StreamExt;
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavFileSystem, DavMetaData, DavProp, FsError, FsResult, OpenOptions, ReadDirMeta};
+use webdav_handler::DavMethod;
+use xmltree::{Element, EmitterConfig, XMLNode};
+
+use crate::config::Handler;
+use crate::mkcol;
+use crate::report::{self, escape, is_dav, prop_xml, Multistatus};
+use crate::trashfs::join;
+use crate::vobject;
+
+const CS: &str = "http://calendarserver.org/ns/";
+
+// Objects larger than this are not read for a report.
+const MAX_OBJECT: usize = 1024 * 1024;
+
+/// The kind of collections of a location.
+pub struct Kind {
+    pub ns:         &'static str,
+    pub collection: &'static str,
+    pub home_set:   &'static str,
+    pub data:       &'static str,
+    pub query:      &'static str,
+    pub multiget:   &'static str,
+    pub mkcol:      &'static str,
+    // the first is the one for responses.
+    pub media_type: &'static [&'static str],
+    pub dav_class:  &'static str,
+    // properties of a collection, with their value.
+    pub props:      &'static [(&'static str, &'static str)],
+    // does an object match the filter of a query.
+    pub matches:    fn(&Element, &vobject::Component) -> bool,
+}
+
+pub static CALDAV: Kind = Kind {
+    ns:         "urn:ietf:params:xml:ns:caldav",
+    collection: "calendar",
+    home_set:   "calendar-home-set",
+    data:       "calendar-data",
+    query:      "calendar-query",
+    multiget:   "calendar-multiget",
+    mkcol:      "MKCALENDAR",
+    media_type: &["text/calendar"],
+    dav_class:  "calendar-access",
+    props:      &[
+        (
+            "supported-calendar-component-set",
+            "<C:comp name=\"VEVENT\"/><C:comp name=\"VTODO\"/><C:comp name=\"VJOURNAL\"/>",
+        ),
+        ("supported-calendar-data", "<C:calendar-data content-type=\"text/calendar\" version=\"2.0\"/>"),
+    ],
+    matches:    vobject::calendar_matches,
+};
+
+pub static CARDDAV: Kind = Kind {
+    ns:         "urn:ietf:params:xml:ns:carddav",
+    collection: "addressbook",
+    home_set:   "addressbook-home-set",
+    data:       "address-data",
+    query:      "addressbook-query",
+    multiget:   "addressbook-multiget",
+    mkcol:      "MKCOL",
+    media_type: &["text/vcard", "text/x-vcard"],
+    dav_class:  "addressbook",
+    props:      &[(
+        "supported-address-data",
+        "<CR:address-data-type content-type=\"text/vcard\" version=\"3.0\"/>\
+         <CR:address-data-type content-type=\"text/vcard\" version=\"4.0\"/>",
+    )],
+    matches:    vobject::card_matches,
+};
+
+/// The kind of collections of a handler, if it has them.
+pub fn kind(handler: Handler) -> Option<&'static Kind> {
+    match handler {
+        Handler::Caldav => Some(&CALDAV),
+        Handler::Carddav => Some(&CARDDAV),
+        _ => None,
+    }
+}
+
+/// What the location allows, for the properties.
+pub struct Access {
+    pub writable:   bool,
+    pub sync_token: Option<String>,
+}
+
+/// For routing and permissions, MKCALENDAR is taken as MKCOL.
+pub fn dav_method(method: &http::Method) -> Option<DavMethod> {
+    match method.as_str() {
+        "MKCALENDAR" => Some(DavMethod::MkCol),
+        _ => None,
+    }
+}
+
+/// Fix up the headers of a response of the handler: the DAV class and the
+/// methods for OPTIONS, and the content type of an object.
+pub fn headers(kind: &Kind, method: DavMethod, path: &DavPath, headers: &mut http::HeaderMap) {
+    if matches!(method, DavMethod::Get | DavMethod::Head) && level(path) == 2 {
+        let ctype = format!("{}; charset=utf-8", kind.media_type[0]);
+        if headers.contains_key("Content-Type") {
+            headers.insert("Content-Type", ctype.parse().unwrap());
+        }
+    }
+    if method != DavMethod::Options {
+        return;
+    }
+    let allow = match kind.mkcol {
+        "MKCOL" => "REPORT".to_string(),
+        mkcol => format!("REPORT,{}", mkcol),
+    };
+    for (name, add) in [("DAV", kind.dav_class), ("Allow", allow.as_str())].iter() {
+        let value = match headers.get(*name).and_then(|v| v.to_str().ok()) {
+            Some(v) => format!("{},{}", v, add),
+            None => continue,
+        };
+        if let Ok(value) = value.parse() {
+            headers.insert(*name, value);
+        }
+    }
+}
+
+/// The Depth header, or "default" if there is none. Infinity is u32::MAX.
+pub fn depth(headers: &http::HeaderMap, default: u32) -> Option<u32> {
+    match headers.get("Depth").map(|d| d.to_str().map(str::trim)) {
+        None => Some(default),
+        Some(Ok("0")) => Some(0),
+        Some(Ok("1")) => Some(1),
+        Some(Ok(d)) if d.eq_ignore_ascii_case("infinity") => Some(u32::MAX),
+        Some(_) => None,
+    }
+}
+
+/// Is this a query or multiget report of the kind.
+pub fn is_report(kind: &Kind, root: &Element) -> bool {
+    root.namespace.as_deref() == Some(kind.ns) && (root.name == kind.query || root.name == kind.multiget)
+}
+
+fn read_options() -> OpenOptions {
+    OpenOptions {
+        read: true,
+        ..OpenOptions::default()
+    }
+}
+
+// The number of segments of a path: 0 is the home, 1 a collection in it,
+// 2 an object in a collection.
+fn level(path: &DavPath) -> usize {
+    path.as_bytes().split(|&c| c == b'/').filter(|s| !s.is_empty()).count()
+}
+
+// A DAV:error response, with a precondition in the namespace of the kind.
+fn condition(kind: &Kind, status: StatusCode, name: &str) -> http::Response<String> {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <D:error xmlns:D=\"DAV:\" xmlns:C=\"{}\"><C:{}/></D:error>\n",
+        kind.ns, name
+    );
+    report::response(status, body)
+}
+
+// An empty element, in a value.
+fn empty(ns: &str, name: &str) -> String {
+    prop_xml(ns, name, "").trim_end().to_string()
+}
+
+// A property element stored as XML, without the XML declaration.
+fn element_xml(xml: &[u8]) -> Option<String> {
+    let elem = Element::parse(xml).ok()?;
+    let mut out = Vec::new();
+    let config = EmitterConfig::new().write_document_declaration(false);
+    elem.write_with_config(&mut out, config).ok()?;
+    let mut out = String::from_utf8(out).ok()?;
+    out.push('\n');
+    Some(out)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Type {
+    Home,
+    Collection,
+    Object,
+    Other,
+}
+
+fn type_of(path: &DavPath, meta: &dyn DavMetaData) -> Type {
+    match (level(path), meta.is_dir()) {
+        (0, _) => Type::Home,
+        (1, true) => Type::Collection,
+        (2, false) => Type::Object,
+        _ => Type::Other,
+    }
+}
+
+// The properties of a request, or None for DAV:allprop.
+type Wanted = Option<Vec<(String, String)>>;
+
+struct Ctx<'a> {
+    kind:   &'a Kind,
+    fs:     &'a dyn DavFileSystem,
+    prefix: &'a str,
+    access: &'a Access,
+}
+
+impl Ctx<'_> {
+    // The names of the properties for DAV:allprop.
+    fn allprop(&self, rtype: Type, meta: &dyn DavMetaData) -> Vec<(String, String)> {
+        let mut names = vec!["resourcetype", "displayname", "getetag", "getlastmodified"];
+        if !meta.is_dir() {
+            names.extend(&["getcontenttype", "getcontentlength"]);
+        }
+        if rtype == Type::Collection && self.access.sync_token.is_some() {
+            names.push("sync-token");
+        }
+        let mut props: Vec<_> = names.iter().map(|n| ("DAV:".to_string(), n.to_string())).collect();
+        if rtype == Type::Collection {
+            props.push((CS.to_string(), "getctag".to_string()));
+            props.extend(self.kind.props.iter().map(|(n, _)| (self.kind.ns.to_string(), n.to_string())));
+        }
+        props
+    }
+
+    async fn dead(&self, path: &DavPath, ns: &str, name: &str) -> Option<String> {
+        if !self.fs.have_props(path).await {
+            return None;
+        }
+        let prop = DavProp {
+            name:      name.to_string(),
+            prefix:    None,
+            namespace: Some(ns.to_string()),
+            xml:       None,
+        };
+        element_xml(&self.fs.get_prop(path, prop).await.ok()?)
+    }
+
+    async fn data(&self, path: &DavPath) -> FsResult<String> {
+        let mut file = self.fs.open(path, read_options()).await?;
+        let mut data = Vec::new();
+        loop {
+            let buf = file.read_bytes(65536).await?;
+            if buf.is_empty() || data.len() > MAX_OBJECT {
+                break;
+            }
+            data.extend_from_slice(&buf);
+        }
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    }
+
+    // A digest of the names and etags of the members.
+    async fn ctag(&self, path: &DavPath) -> Option<String> {
+        let mut entries = self.fs.read_dir(path, ReadDirMeta::Data).await.ok()?;
+        let mut members = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let etag = entry.metadata().await.ok().and_then(|m| m.etag()).unwrap_or_default();
+            members.push((entry.name(), etag));
+        }
+        members.sort();
+        let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+        for (name, etag) in &members {
+            ctx.update(name);
+            ctx.update(b"\0");
+            ctx.update(etag.as_bytes());
+            ctx.update(b"\0");
+        }
+        let digest = ctx.finish();
+        Some(digest.as_ref()[..16].iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    // The value of a property, as an element.
+    async fn prop(
+        &self,
+        ns: &str,
+        name: &str,
+        path: &DavPath,
+        meta: &dyn DavMetaData,
+        data: Option<&str>,
+    ) -> Option<String>
+    {
+        let kind = self.kind;
+        let rtype = type_of(path, meta);
+        let href = |p: &str| format!("<D:href>{}</D:href>", escape(p));
+        let home = format!("{}/", self.prefix);
+        let value = match (ns, name) {
+            ("DAV:", "resourcetype") => {
+                match rtype {
+                    Type::Home => "<D:collection/><D:principal/>".to_string(),
+                    Type::Collection => format!("<D:collection/>{}", empty(kind.ns, kind.collection)),
+                    _ => report::live_prop(name, path, meta)?,
+                }
+            },
+            ("DAV:", "displayname") => {
+                match self.dead(path, ns, name).await {
+                    Some(elem) => return Some(elem),
+                    None => report::live_prop(name, path, meta)?,
+                }
+            },
+            ("DAV:", "getcontenttype") if rtype == Type::Object => kind.media_type[0].to_string(),
+            ("DAV:", "current-user-principal") | ("DAV:", "principal-URL") | ("DAV:", "owner") => href(&home),
+            ("DAV:", "current-user-privilege-set") => {
+                let mut privileges = vec!["read"];
+                if self.access.writable {
+                    privileges.extend(&["write", "write-properties", "write-content", "bind", "unbind"]);
+                }
+                privileges.iter().map(|p| format!("<D:privilege><D:{}/></D:privilege>", p)).collect()
+            },
+            ("DAV:", "supported-report-set") if meta.is_dir() => {
+                let mut reports = vec![empty(kind.ns, kind.query), empty(kind.ns, kind.multiget)];
+                if self.access.sync_token.is_some() {
+                    reports.push(empty("DAV:", "sync-collection"));
+                }
+                let report = |r: &String| {
+                    format!("<D:supported-report><D:report>{}</D:report></D:supported-report>", r)
+                };
+                reports.iter().map(report).collect()
+            },
+            ("DAV:", "sync-token") if rtype == Type::Collection => escape(self.access.sync_token.as_ref()?),
+            ("DAV:", _) => {
+                match report::live_prop(name, path, meta) {
+                    Some(value) => value,
+                    None => return self.dead(path, ns, name).await,
+                }
+            },
+            (CS, "getctag") if rtype == Type::Collection => self.ctag(path).await?,
+            _ if ns == kind.ns && name == kind.home_set => href(&home),
+            _ if ns == kind.ns && name == kind.data && rtype == Type::Object => {
+                match data {
+                    Some(data) => escape(data),
+                    None => escape(&self.data(path).await.ok()?),
+                }
+            },
+            _ if ns == kind.ns && rtype == Type::Collection => {
+                match kind.props.iter().find(|(n, _)| *n == name) {
+                    Some((_, value)) => value.to_string(),
+                    None => return self.dead(path, ns, name).await,
+                }
+            },
+            _ => return self.dead(path, ns, name).await,
+        };
+        Some(prop_xml(ns, name, &value))
+    }
+
+    // A response with the properties of a resource. With "names", only
+    // the names of the properties.
+    async fn response(
+        &self,
+        multi: &mut Multistatus,
+        path: &DavPath,
+        meta: &dyn DavMetaData,
+        wanted: &Wanted,
+        names: bool,
+        data: Option<&str>,
+    )
+    {
+        let mut found = String::new();
+        let mut missing = String::new();
+        let props = match wanted {
+            Some(wanted) => wanted.clone(),
+            None => self.allprop(type_of(path, meta), meta),
+        };
+        for (ns, name) in &props {
+            match self.prop(ns, name, path, meta, data).await {
+                Some(_) if names => found.push_str(&prop_xml(ns, name, "")),
+                Some(elem) => found.push_str(&elem),
+                None if wanted.is_some() => missing.push_str(&prop_xml(ns, name, "")),
+                None => {},
+            }
+        }
+        if wanted.is_none() && self.fs.have_props(path).await {
+            for prop in self.fs.get_props(path, !names).await.unwrap_or_default() {
+                let ns = prop.namespace.as_deref().unwrap_or_default();
+                if props.iter().any(|(n, p)| n == ns && *p == prop.name) {
+                    continue;
+                }
+                match prop.xml.as_deref().and_then(element_xml) {
+                    Some(elem) if !names => found.push_str(&elem),
+                    _ => found.push_str(&prop_xml(ns, &prop.name, "")),
+                }
+            }
+        }
+        let href = report::href(self.prefix, path, meta.is_dir());
+        multi.propstat(&href, &found, &missing);
+    }
+
+    // The resource at "path" and the ones below it, up to "depth".
+    async fn walk(&self, path: &DavPath, depth: u32) -> FsResult<Vec<(DavPath, Box<dyn DavMetaData>)>> {
+        let meta = self.fs.metadata(path).await?;
+        let mut todo = vec![(path.clone(), meta, 0)];
+        let mut done = Vec::new();
+        while let Some((path, meta, below)) = todo.pop() {
+            if meta.is_dir() && below < depth {
+                let mut entries = self.fs.read_dir(&path, ReadDirMeta::Data).await?;
+                let mut members = Vec::new();
+                while let Some(entry) = entries.next().await {
+                    if let Ok(meta) = entry.metadata().await {
+                        members.push((join(&path, &entry.name())?, meta, below + 1));
+                    }
+                }
+                members.sort_by(|a, b| b.0.as_bytes().cmp(a.0.as_bytes()));
+                todo.extend(members);
+            }
+            done.push((path, meta));
+        }
+        Ok(done)
+    }
+}
+
+/// Handle a request on the location, if it is one that is done here.
+/// Otherwise the request is given back, for the handler.
+pub async fn handle(
+    kind: &Kind,
+    req: http::Request<hyper::Body>,
+    fs: &dyn DavFileSystem,
+    path: &DavPath,
+    prefix: &str,
+    access: &Access,
+) -> Result<http::Response<String>, http::Request<hyper::Body>>
+{
+    let ctx = Ctx {
+        kind,
+        fs,
+        prefix,
+        access,
+    };
+    let method = req.method().as_str();
+    let resp = match method {
+        "PROPFIND" => propfind(&ctx, req, path).await,
+        m if m == kind.mkcol => mkcol(&ctx, req, path).await,
+        "MKCOL" if level(path) != 1 => report::error(StatusCode::FORBIDDEN),
+        "MKCOL" => mkcol(&ctx, req, path).await,
+        "PUT" if level(path) != 2 => report::error(StatusCode::FORBIDDEN),
+        "PATCH" => report::error(StatusCode::FORBIDDEN),
+        "PUT" => {
+            let ctype = req.headers().get("Content-Type").and_then(|v| v.to_str().ok());
+            let ctype = ctype.map(|t| t.trim_start().to_ascii_lowercase());
+            match ctype {
+                Some(t) if !kind.media_type.iter().any(|m| t.starts_with(m)) => {
+                    condition(kind, StatusCode::FORBIDDEN, &format!("supported-{}", kind.data))
+                },
+                _ => return Err(req),
+            }
+        },
+        _ => return Err(req),
+    };
+    Ok(resp)
+}
+
+async fn propfind(ctx: &Ctx<'_>, req: http::Request<hyper::Body>, path: &DavPath) -> http::Response<String> {
+    let depth = match depth(req.headers(), u32::MAX) {
+        Some(depth) => depth,
+        None => return report::error(StatusCode::BAD_REQUEST),
+    };
+    let body = match report::body(req).await {
+        Ok(body) => body,
+        Err(resp) => return resp,
+    };
+    let (wanted, names) = match body.is_empty() {
+        true => (None, false),
+        false => {
+            let root = match Element::parse(&body[..]) {
+                Ok(root) if is_dav(&root, "propfind") => root,
+                _ => return report::error(StatusCode::BAD_REQUEST),
+            };
+            match root.children.iter().filter_map(XMLNode::as_element).next() {
+                Some(e) if is_dav(e, "prop") => (Some(report::wanted(&root, &[])), false),
+                Some(e) if is_dav(e, "propname") => (None, true),
+                Some(e) if is_dav(e, "allprop") => (None, false),
+                _ => return report::error(StatusCode::BAD_REQUEST),
+            }
+        },
+    };
+    let resources = match ctx.walk(path, depth).await {
+        Ok(r) => r,
+        Err(e) => return report::error(report::status(e)),
+    };
+    let mut multi = Multistatus::new();
+    for (path, meta) in &resources {
+        ctx.response(&mut multi, path, &**meta, &wanted, names, None).await;
+    }
+    multi.finish("")
+}
+
+// MKCALENDAR, or a MKCOL (extended, RFC 5689), with the properties of the
+// DAV:set in the body.
+async fn mkcol(ctx: &Ctx<'_>, req: http::Request<hyper::Body>, path: &DavPath) -> http::Response<String> {
+    if level(path) != 1 {
+        return report::error(StatusCode::FORBIDDEN);
+    }
+    let body = match report::body(req).await {
+        Ok(body) => body,
+        Err(resp) => return resp,
+    };
+    let mut props = Vec::new();
+    if !body.is_empty() {
+        let root = match Element::parse(&body[..]) {
+            Ok(root) => root,
+            Err(_) => return report::error(StatusCode::BAD_REQUEST),
+        };
+        // every collection is one of the kind.
+        props = match mkcol::props(&root, &[(ctx.kind.ns, ctx.kind.collection)]) {
+            Ok(props) => props.into_iter().map(|p| (true, p)).collect(),
+            Err(status) => return mkcol::error(status),
+        };
+    }
+    if let Err(e) = ctx.fs.create_dir(path).await {
+        let status = match e {
+            FsError::Exists => StatusCode::METHOD_NOT_ALLOWED,
+            FsError::NotFound => StatusCode::CONFLICT,
+            e => report::status(e),
+        };
+        return report::error(status);
+    }
+    if !props.is_empty() && ctx.fs.have_props(path).await {
+        match ctx.fs.patch_props(path, props).await {
+            Ok(res) if res.iter().all(|(s, _)| s.is_success()) => {},
+            res => debug!("{}: {}: properties not set: {:?}", ctx.kind.mkcol, path, res),
+        }
+    }
+    report::error(StatusCode::CREATED)
+}
+
+/// The query and multiget reports. "depth" is for a query.
+pub async fn report(
+    kind: &Kind,
+    root: &Element,
+    fs: &dyn DavFileSystem,
+    path: &DavPath,
+    prefix: &str,
+    access: &Access,
+    depth: u32,
+) -> http::Response<String>
+{
+    let ctx = Ctx {
+        kind,
+        fs,
+        prefix,
+        access,
+    };
+    let wanted = Some(report::wanted(root, &["getetag"]));
+    let mut multi = Multistatus::new();
+
+    if root.name == kind.multiget {
+        let hrefs = root.children.iter().filter_map(XMLNode::as_element).filter(|e| is_dav(e, "href"));
+        for href in hrefs.filter_map(|e| e.get_text()) {
+            let meta = match report::href_path(&href, path, prefix) {
+                Some(p) => fs.metadata(&p).await.map(|m| (p, m)).ok(),
+                None => None,
+            };
+            match meta {
+                Some((p, meta)) if type_of(&p, &*meta) == Type::Object => {
+                    ctx.response(&mut multi, &p, &*meta, &wanted, false, None).await;
+                },
+                _ => multi.status(href.trim(), StatusCode::NOT_FOUND),
+            }
+        }
+        return multi.finish("");
+    }
+
+    let filter = root.get_child("filter");
+    let limit = root.get_child("limit").and_then(|l| l.get_child("nresults")).and_then(|n| n.get_text());
+    let mut limit = limit.and_then(|n| n.trim().parse::<usize>().ok());
+    let resources = match ctx.walk(path, depth).await {
+        Ok(r) => r,
+        Err(e) => return report::error(report::status(e)),
+    };
+    for (object, meta) in &resources {
+        if type_of(object, &**meta) != Type::Object {
+            continue;
+        }
+        let data = match ctx.data(object).await {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        let matches = match (filter, vobject::parse(&data)) {
+            (None, _) => true,
+            (Some(filter), Some(object)) => (kind.matches)(filter, &object),
+            (Some(_), None) => false,
+        };
+        if !matches {
+            continue;
+        }
+        match limit.as_mut() {
+            Some(0) => {
+                // truncated, see RFC 6352, 8.6.1.
+                multi.status(&report::href(prefix, path, true), StatusCode::INSUFFICIENT_STORAGE);
+                break;
+            },
+            Some(n) => *n -= 1,
+            None => {},
+        }
+        ctx.response(&mut multi, object, &**meta, &wanted, false, Some(&data)).await;
+    }
+    multi.finish("")
+}
@@ -0,0 +1,232 @@
+//
+// RFC 8144 preferences for PROPFIND, in the Prefer header (RFC 7240):
+//
+// - return=minimal: the propstats for properties that were not found
+//   ("404 Not Found") are left out.
+// - depth-noroot: the response for the collection of the request is left
+//   out, only its members are shown. Ignored with Depth: 0.
+//
+// The multistatus of the handler is filtered while it is streamed, one
+// response at a time. The preferences that were used are in the
+// Preference-Applied header, and the response has "Vary: Prefer".
+//
+use futures::stream;
+use http::StatusCode;
+use hyper::body::{Bytes, HttpBody};
+
+const START: &[u8] = b"<D:response>";
+const END: &[u8] = b"</D:response>";
+const PROPSTAT_START: &[u8] = b"<D:propstat>";
+const PROPSTAT_END: &[u8] = b"</D:propstat>";
+const NOT_FOUND: &[u8] = b"<D:status>HTTP/1.1 404 ";
+// What is left of a response when all its properties were not found.
+const EMPTY: &[u8] = b"<D:propstat><D:prop></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Prefer {
+    pub minimal: bool,
+    pub noroot:  bool,
+}
+
+impl Prefer {
+    /// The preferences of a PROPFIND request that we know.
+    pub fn new(headers: &http::HeaderMap) -> Prefer {
+        let mut prefer = Prefer::default();
+        let values = headers.get_all("Prefer").into_iter().filter_map(|v| v.to_str().ok());
+        for pref in values.flat_map(|v| v.split(',')) {
+            // parameters after the ";" are ignored.
+            let pref = pref.split(';').next().unwrap_or("").trim();
+            let (name, value) = match pref.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim().trim_matches('"')),
+                None => (pref, ""),
+            };
+            if name.eq_ignore_ascii_case("return") && value.eq_ignore_ascii_case("minimal") {
+                prefer.minimal = true;
+            }
+            if name.eq_ignore_ascii_case("depth-noroot") {
+                prefer.noroot = true;
+            }
+        }
+        let depth = headers.get("Depth").and_then(|d| d.to_str().ok()).map(str::trim);
+        if depth == Some("0") {
+            prefer.noroot = false;
+        }
+        prefer
+    }
+
+    fn applied(&self) -> Option<&'static str> {
+        match (self.minimal, self.noroot) {
+            (true, true) => Some("return=minimal, depth-noroot"),
+            (true, false) => Some("return=minimal"),
+            (false, true) => Some("depth-noroot"),
+            (false, false) => None,
+        }
+    }
+}
+
+fn find(data: &[u8], what: &[u8]) -> Option<usize> {
+    data.windows(what.len()).position(|w| w == what)
+}
+
+// One response, without the propstats of the properties that were not found.
+fn minimal(resp: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(resp.len());
+    let mut rest = resp;
+    let (mut kept, mut dropped) = (0, 0);
+    while let Some(start) = find(rest, PROPSTAT_START) {
+        let end = match find(&rest[start..], PROPSTAT_END) {
+            Some(end) => start + end + PROPSTAT_END.len(),
+            None => break,
+        };
+        out.extend_from_slice(&rest[..start]);
+        let propstat = &rest[start..end];
+        if find(propstat, NOT_FOUND).is_some() {
+            dropped += 1;
+        } else {
+            out.extend_from_slice(propstat);
+            kept += 1;
+        }
+        rest = &rest[end..];
+    }
+    if kept == 0 && dropped > 0 {
+        out.extend_from_slice(EMPTY);
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+// Filters the multistatus, as it comes in.
+struct Filter {
+    prefer: Prefer,
+    first:  bool,
+    buf:    Vec<u8>,
+}
+
+impl Filter {
+    fn new(prefer: Prefer) -> Filter {
+        Filter {
+            prefer,
+            first: true,
+            buf: Vec::new(),
+        }
+    }
+
+    // Add data, and return what is ready to go out.
+    fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        self.buf.extend_from_slice(data);
+        let mut out = Vec::new();
+        loop {
+            let start = match find(&self.buf, START) {
+                Some(start) => start,
+                None => {
+                    // the start of a response might have been cut off.
+                    let keep = self.buf.len().min(START.len() - 1);
+                    out.extend(self.buf.drain(..self.buf.len() - keep));
+                    break;
+                },
+            };
+            let end = match find(&self.buf[start..], END) {
+                Some(end) => start + end + END.len(),
+                None => {
+                    out.extend(self.buf.drain(..start));
+                    break;
+                },
+            };
+            out.extend_from_slice(&self.buf[..start]);
+            let first = std::mem::replace(&mut self.first, false);
+            if !(first && self.prefer.noroot) {
+                match self.prefer.minimal {
+                    true => out.extend(minimal(&self.buf[start..end])),
+                    false => out.extend_from_slice(&self.buf[start..end]),
+                }
+            }
+            self.buf.drain(..end);
+        }
+        out
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+/// The PROPFIND multistatus of the handler, as the client prefers.
+pub fn response(resp: http::Response<hyper::Body>, prefer: Prefer) -> http::Response<hyper::Body> {
+    if resp.status() != StatusCode::MULTI_STATUS {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    parts.headers.append(http::header::VARY, "Prefer".parse().unwrap());
+    let applied = match prefer.applied() {
+        Some(applied) => applied,
+        None => return http::Response::from_parts(parts, body),
+    };
+    parts.headers.insert("Preference-Applied", applied.parse().unwrap());
+    parts.headers.remove(http::header::CONTENT_LENGTH);
+    let state = Some((body, Filter::new(prefer)));
+    let strm = stream::unfold(state, |state| {
+        async move {
+            let (mut body, mut filter) = state?;
+            loop {
+                match body.data().await {
+                    Some(Ok(data)) => {
+                        let out = filter.push(&data);
+                        if !out.is_empty() {
+                            return Some((Ok(Bytes::from(out)), Some((body, filter))));
+                        }
+                    },
+                    Some(Err(e)) => return Some((Err(e), None)),
+                    None => return Some((Ok(Bytes::from(filter.finish())), None)),
+                }
+            }
+        }
+    });
+    http::Response::from_parts(parts, hyper::Body::wrap_stream(strm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefer() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(Prefer::new(&headers), Prefer::default());
+        headers.insert("Prefer", "Return=\"minimal\"; foo, depth-noroot".parse().unwrap());
+        assert_eq!(Prefer::new(&headers), Prefer { minimal: true, noroot: true });
+        headers.insert("Depth", "0".parse().unwrap());
+        assert_eq!(Prefer::new(&headers), Prefer { minimal: true, noroot: false });
+        headers.insert("Prefer", "return=representation".parse().unwrap());
+        assert_eq!(Prefer::new(&headers), Prefer::default());
+
+        let body = concat!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?><D:multistatus xmlns:D=\"DAV:\">",
+            "<D:response><D:href>/</D:href><D:propstat><D:prop><D:foo></D:foo></D:prop>",
+            "<D:status>HTTP/1.1 404 Not Found</D:status></D:propstat></D:response>",
+            "<D:response><D:href>/a.txt</D:href><D:propstat><D:prop><D:getcontentlength>3",
+            "</D:getcontentlength></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>",
+            "<D:propstat><D:prop><D:foo></D:foo></D:prop><D:status>HTTP/1.1 404 Not Found</D:status>",
+            "</D:propstat></D:response></D:multistatus>"
+        );
+        let filter = |prefer: Prefer| {
+            let mut filter = Filter::new(prefer);
+            let mut out = Vec::new();
+            for chunk in body.as_bytes().chunks(7) {
+                out.extend(filter.push(chunk));
+            }
+            out.extend(filter.finish());
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(filter(Prefer::default()), body);
+
+        let out = filter(Prefer { minimal: true, noroot: false });
+        assert!(!out.contains("404"));
+        assert!(out.contains("<D:href>/</D:href><D:propstat><D:prop></D:prop><D:status>HTTP/1.1 200 OK"));
+        assert!(out.contains("<D:getcontentlength>3</D:getcontentlength>"));
+        assert!(out.ends_with("</D:propstat></D:response></D:multistatus>"));
+
+        let out = filter(Prefer { minimal: false, noroot: true });
+        assert!(!out.contains("<D:href>/</D:href>"));
+        assert!(out.contains("<D:multistatus xmlns:D=\"DAV:\"><D:response><D:href>/a.txt</D:href>"));
+    }
+}
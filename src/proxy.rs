@@ -0,0 +1,466 @@
+//
+// Accepting connections, optionally behind the PROXY protocol.
+//
+// The PROXY protocol (v1 and v2) is used by haproxy and others to pass
+// on the address of the client when proxying in TCP mode, see
+// https://www.haproxy.org/download/2.4/doc/proxy-protocol.txt
+//
+// Connections are accepted in a separate task, and the header is read
+// in a task per connection, so that a slow client cannot block the
+// accept loop.
+//
+// A connection that does not send or receive anything for idle_timeout
+// is closed, unless a request on it is still being worked on. Open
+// connections and the bytes sent and received are counted for metrics.
+//
+// Writes of a body from sendfile::response are done with sendfile.
+//
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Sleep};
+
+use crate::sendfile::{self, Socket};
+
+// Time allowed to send the PROXY header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Maximum length of a v1 header, including CRLF.
+const V1_MAX_LEN: usize = 107;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// An accepted connection.
+pub struct Conn<S> {
+    stream:      S,
+    remote_addr: SocketAddr,
+    busy:        Busy,
+    idle:        Option<Idle>,
+    sendfile:    sendfile::Queue,
+    _metrics:    crate::metrics::Connection,
+}
+
+struct Idle {
+    timeout: Duration,
+    sleep:   Pin<Box<Sleep>>,
+}
+
+/// The number of requests on a connection that are being worked on.
+#[derive(Clone, Default)]
+pub struct Busy(Arc<AtomicUsize>);
+
+impl Busy {
+    /// Count a request until the guard is dropped.
+    pub fn enter(&self) -> BusyGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        BusyGuard(self.0.clone())
+    }
+}
+
+pub struct BusyGuard(Arc<AtomicUsize>);
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<S> Conn<S> {
+    fn new(stream: S, remote_addr: SocketAddr) -> Conn<S> {
+        Conn {
+            stream,
+            remote_addr,
+            busy: Busy::default(),
+            idle: None,
+            sendfile: sendfile::Queue::default(),
+            _metrics: crate::metrics::connection(),
+        }
+    }
+
+    /// Address of the client. For unix sockets without a PROXY header,
+    /// this is localhost.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Requests in progress. The idle timeout does not run while there are any.
+    pub fn busy(&self) -> Busy {
+        self.busy.clone()
+    }
+
+    fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle = Some(Idle {
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+        });
+    }
+
+    // Called after every read or write. Progress resets the timer,
+    // otherwise see if it ran out.
+    fn check_idle<T>(&mut self, cx: &mut Context<'_>, res: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        let idle = match self.idle.as_mut() {
+            Some(idle) => idle,
+            None => return res,
+        };
+        if res.is_ready() {
+            let deadline = Instant::now() + idle.timeout;
+            idle.sleep.as_mut().reset(deadline);
+            return res;
+        }
+        if self.busy.0.load(Ordering::SeqCst) == 0 && idle.sleep.as_mut().poll(cx).is_ready() {
+            debug!("{}: idle timeout", self.remote_addr);
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout")));
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite> Conn<S> {
+    /// The queue for sendfile::response, if hyper can write through it.
+    /// Only for HTTP/1 and without TLS.
+    pub fn sendfile(&self) -> Option<sendfile::Queue> {
+        match self.stream.is_write_vectored() {
+            true => Some(self.sendfile.clone()),
+            false => None,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Socket + Unpin> Conn<S> {
+    // Write up to the first buffer from sendfile::response, or from it.
+    fn poll_write_sendfile(
+        &mut self,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>>
+    {
+        match bufs.iter().position(|buf| sendfile::is_zeroes(buf)) {
+            Some(0) => self.sendfile.poll_send(cx, &self.stream, &bufs[0]),
+            Some(n) => Pin::new(&mut self.stream).poll_write_vectored(cx, &bufs[..n]),
+            None => Pin::new(&mut self.stream).poll_write_vectored(cx, bufs),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Conn<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>>
+    {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.stream).poll_read(cx, buf);
+        crate::metrics::bytes_read(buf.filled().len() - filled);
+        self.check_idle(cx, res)
+    }
+}
+
+impl<S: AsyncWrite + Socket + Unpin> AsyncWrite for Conn<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let res = match self.sendfile.is_empty() {
+            true => Pin::new(&mut self.stream).poll_write(cx, buf),
+            false => self.poll_write_sendfile(cx, &[io::IoSlice::new(buf)]),
+        };
+        if let Poll::Ready(Ok(n)) = res {
+            crate::metrics::bytes_written(n);
+        }
+        self.check_idle(cx, res)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>>
+    {
+        let res = match self.sendfile.is_empty() {
+            true => Pin::new(&mut self.stream).poll_write_vectored(cx, bufs),
+            false => self.poll_write_sendfile(cx, bufs),
+        };
+        if let Poll::Ready(Ok(n)) = res {
+            crate::metrics::bytes_written(n);
+        }
+        self.check_idle(cx, res)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// Listeners we can accept connections on.
+trait Listen: Send + 'static {
+    type Stream: AsyncRead + Unpin + Send + 'static;
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(Self::Stream, SocketAddr)>>;
+}
+
+impl Listen for TcpListener {
+    type Stream = TcpStream;
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(TcpStream, SocketAddr)>> {
+        TcpListener::poll_accept(self, cx).map_ok(|(stream, addr)| {
+            let _ = stream.set_nodelay(true);
+            (stream, addr)
+        })
+    }
+}
+
+impl Listen for UnixListener {
+    type Stream = UnixStream;
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<(UnixStream, SocketAddr)>> {
+        let localhost = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        UnixListener::poll_accept(self, cx).map_ok(|(stream, _)| (stream, localhost))
+    }
+}
+
+/// Stream of accepted connections.
+pub struct Incoming<S> {
+    rx:   mpsc::Receiver<Conn<S>>,
+    idle: Option<Duration>,
+}
+
+impl Incoming<TcpStream> {
+    pub fn tcp(listener: TcpListener, proxy_protocol: bool) -> Incoming<TcpStream> {
+        Incoming::new(listener, proxy_protocol)
+    }
+}
+
+impl Incoming<UnixStream> {
+    pub fn unix(listener: UnixListener, proxy_protocol: bool) -> Incoming<UnixStream> {
+        Incoming::new(listener, proxy_protocol)
+    }
+}
+
+impl<S: AsyncRead + Unpin + Send + 'static> Incoming<S> {
+    fn new<L: Listen<Stream = S>>(listener: L, proxy_protocol: bool) -> Incoming<S> {
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(accept_loop(listener, proxy_protocol, tx));
+        Incoming { rx, idle: None }
+    }
+}
+
+impl<S> Incoming<S> {
+    /// Close connections that are idle for this long.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Incoming<S> {
+        self.idle = timeout;
+        self
+    }
+
+    fn poll_conn(&mut self, cx: &mut Context<'_>) -> Poll<Option<Conn<S>>> {
+        let idle = self.idle;
+        self.rx.poll_recv(cx).map(|conn| {
+            conn.map(|mut conn| {
+                if let Some(timeout) = idle {
+                    conn.set_idle_timeout(timeout);
+                }
+                conn
+            })
+        })
+    }
+}
+
+impl<S> hyper::server::accept::Accept for Incoming<S> {
+    type Conn = Conn<S>;
+    type Error = io::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Conn<S>>>> {
+        self.poll_conn(cx).map(|conn| conn.map(Ok))
+    }
+}
+
+impl<S> tls_listener::AsyncAccept for Incoming<S> {
+    type Connection = Conn<S>;
+    type Error = io::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Conn<S>>> {
+        self.poll_conn(cx).map(|conn| {
+            conn.ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "listener closed"))
+        })
+    }
+}
+
+async fn accept_loop<L: Listen>(mut listener: L, proxy_protocol: bool, tx: mpsc::Sender<Conn<L::Stream>>) {
+    loop {
+        // stop accepting when the server is gone (shutdown).
+        let accepted = tokio::select! {
+            accepted = futures::future::poll_fn(|cx| listener.poll_accept(cx)) => accepted,
+            _ = tx.closed() => break,
+        };
+        let (mut stream, remote_addr) = match accepted {
+            Ok(s) => s,
+            Err(e) => {
+                // errors on the accepted connection, not on the listener.
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
+                ) {
+                    continue;
+                }
+                // probably EMFILE, back off a little.
+                error!("accept: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            },
+        };
+
+        if !proxy_protocol {
+            if tx.send(Conn::new(stream, remote_addr)).await.is_err() {
+                break;
+            }
+            continue;
+        }
+
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+                Ok(Ok(addr)) => {
+                    let remote_addr = addr.unwrap_or(remote_addr);
+                    let _ = tx.send(Conn::new(stream, remote_addr)).await;
+                },
+                Ok(Err(e)) => debug!("proxy protocol: {}: {}", remote_addr, e),
+                Err(_) => debug!("proxy protocol: {}: timeout", remote_addr),
+            }
+        });
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Read a v1 or v2 header. Returns the client address, or None if the
+// proxy did not send one (LOCAL / UNKNOWN, or a unix socket).
+async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // every header is at least 12 bytes, and we must not read beyond it.
+    let mut hdr = [0u8; 16];
+    stream.read_exact(&mut hdr[..12]).await?;
+
+    if &hdr[..12] == V2_SIGNATURE {
+        stream.read_exact(&mut hdr[12..]).await?;
+        let len = u16::from_be_bytes([hdr[14], hdr[15]]) as usize;
+        let mut data = vec![0u8; len];
+        stream.read_exact(&mut data).await?;
+        return parse_v2(&hdr, &data);
+    }
+
+    if hdr.starts_with(b"PROXY ") {
+        let mut line = hdr[..12].to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("v1 header too long"));
+            }
+            let mut b = [0u8; 1];
+            stream.read_exact(&mut b).await?;
+            line.push(b[0]);
+        }
+        return parse_v1(&line);
+    }
+
+    Err(invalid("no PROXY protocol header"))
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("v1 header not utf-8"))?;
+    let words: Vec<&str> = line.trim_end_matches("\r\n").split(' ').collect();
+    match words.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", proto, src, _dst, sport, _dport] if *proto == "TCP4" || *proto == "TCP6" => {
+            let ip = src.parse::<IpAddr>().map_err(|_| invalid("v1 header: bad address"))?;
+            let port = sport.parse::<u16>().map_err(|_| invalid("v1 header: bad port"))?;
+            if ip.is_ipv4() != (*proto == "TCP4") {
+                return Err(invalid("v1 header: address does not match protocol"));
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        },
+        _ => Err(invalid("v1 header: syntax error")),
+    }
+}
+
+fn parse_v2(hdr: &[u8; 16], data: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if hdr[12] >> 4 != 2 {
+        return Err(invalid("v2 header: unsupported version"));
+    }
+    match hdr[12] & 0x0f {
+        // LOCAL: health check from the proxy itself.
+        0 => return Ok(None),
+        1 => {},
+        _ => return Err(invalid("v2 header: unsupported command")),
+    }
+    let port = |d: &[u8]| u16::from_be_bytes([d[0], d[1]]);
+    match hdr[13] >> 4 {
+        // AF_INET
+        1 => {
+            if data.len() < 12 {
+                return Err(invalid("v2 header: short address"));
+            }
+            let ip = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port(&data[8..]))))
+        },
+        // AF_INET6
+        2 => {
+            if data.len() < 36 {
+                return Err(invalid("v2 header: short address"));
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&data[..16]);
+            let ip = Ipv6Addr::from(octets);
+            Ok(Some(SocketAddr::new(IpAddr::V6(ip), port(&data[32..]))))
+        },
+        // AF_UNSPEC, AF_UNIX.
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v1() {
+        let addr = parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        let addr = parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(parse_v1(b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let mut msg = V2_SIGNATURE.to_vec();
+        msg.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        msg.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        msg.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let mut stream = &msg[..];
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        // the request must still be there.
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        // LOCAL command.
+        let mut msg = V2_SIGNATURE.to_vec();
+        msg.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut &msg[..]).await.unwrap(), None);
+
+        // no header.
+        assert!(read_header(&mut &b"GET / HTTP/1.1\r\n"[..]).await.is_err());
+    }
+}
@@ -0,0 +1,212 @@
+//
+// Experimental HTTP/3 (QUIC) listener.
+//
+// Requests are converted from the http 1.x types that h3 uses to the
+// http 0.2 / hyper types, and handed to the same Server::route() as
+// requests from the TCP listeners.
+//
+// Only the [server] tls_cert / tls_key certificate is used, and client
+// certificates are not supported.
+//
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::{Buf, Bytes};
+use hyper::body::HttpBody;
+use rustls_quic::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio::sync::watch;
+
+use crate::config;
+use crate::Server;
+
+type H3Error = Box<dyn std::error::Error + Send + Sync>;
+
+// Connection specific headers are not allowed in HTTP/3.
+const HOP_BY_HOP: &[&str] = &["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn server_config(cfg: &config::Server) -> io::Result<quinn::ServerConfig> {
+    let cert_fn = cfg.tls_cert.as_deref().unwrap_or_default();
+    let pkey_fn = cfg.tls_key.as_deref().unwrap_or_default();
+
+    let cert_data = std::fs::read(cert_fn).map_err(|e| {
+        io::Error::new(e.kind(), format!("{}: {}", cert_fn, e))
+    })?;
+    let certs = rustls_pemfile::certs(&mut &cert_data[..])
+        .collect::<Result<Vec<CertificateDer>, _>>()
+        .map_err(|_| invalid_data(format!("{}: invalid data", cert_fn)))?;
+    let pkey_data = std::fs::read(pkey_fn).map_err(|e| {
+        io::Error::new(e.kind(), format!("{}: {}", pkey_fn, e))
+    })?;
+    let pkey: PrivateKeyDer = rustls_pemfile::private_key(&mut &pkey_data[..])
+        .ok()
+        .flatten()
+        .ok_or_else(|| invalid_data(format!("{}: no private key found", pkey_fn)))?;
+
+    let provider = Arc::new(rustls_quic::crypto::ring::default_provider());
+    let mut tls = rustls_quic::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls_quic::version::TLS13])
+        .and_then(|b| b.with_no_client_auth().with_single_cert(certs, pkey))
+        .map_err(|e| invalid_data(format!("{}/{}: {}", pkey_fn, cert_fn, e)))?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)
+        .map_err(|e| invalid_data(format!("{}/{}: {}", pkey_fn, cert_fn, e)))?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// Bind a QUIC endpoint. Must be called before dropping privileges.
+pub fn endpoint(cfg: &config::Server, addr: SocketAddr) -> io::Result<quinn::Endpoint> {
+    use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+    let server_config = server_config(cfg)?;
+    let s = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        s.set_only_v6(true)?;
+    }
+    s.set_reuse_address(true)?;
+    let sockaddr: SockAddr = addr.into();
+    s.bind(&sockaddr)?;
+    let runtime = Arc::new(quinn::TokioRuntime);
+    quinn::Endpoint::new(quinn::EndpointConfig::default(), Some(server_config), s.into(), runtime)
+}
+
+/// Accept connections, and serve requests.
+pub async fn serve(endpoint: quinn::Endpoint, server: Server, shutdown: watch::Receiver<bool>) {
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => incoming,
+            _ = crate::server::shutdown_signal(shutdown.clone()) => None,
+        };
+        let incoming = match incoming {
+            Some(incoming) => incoming,
+            None => break,
+        };
+        let server = server.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let conn = match incoming.await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    debug!("quic: connection failed: {}", e);
+                    return;
+                },
+            };
+            let remote_addr = conn.remote_address();
+            let mut h3_conn = match h3::server::builder().build(h3_quinn::Connection::new(conn)).await {
+                Ok(c) => c,
+                Err(e) => {
+                    debug!("quic: {}: {}", remote_addr, e);
+                    return;
+                },
+            };
+            let mut draining = false;
+            loop {
+                let accepted = tokio::select! {
+                    accepted = h3_conn.accept() => Some(accepted),
+                    _ = crate::server::shutdown_signal(shutdown.clone()), if !draining => None,
+                };
+                let accepted = match accepted {
+                    Some(accepted) => accepted,
+                    None => {
+                        // send GOAWAY, then finish the requests in flight.
+                        draining = true;
+                        if let Err(e) = h3_conn.shutdown(0).await {
+                            debug!("quic: {}: {}", remote_addr, e);
+                            break;
+                        }
+                        continue;
+                    },
+                };
+                match accepted {
+                    Ok(Some(resolver)) => {
+                        let server = server.clone();
+                        tokio::spawn(async move {
+                            let (req, stream) = match resolver.resolve_request().await {
+                                Ok(r) => r,
+                                Err(e) => {
+                                    debug!("quic: {}: {}", remote_addr, e);
+                                    return;
+                                },
+                            };
+                            if let Err(e) = handle(server, req, stream, remote_addr).await {
+                                debug!("quic: {}: {}", remote_addr, e);
+                            }
+                        });
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        debug!("quic: {}: {}", remote_addr, e);
+                        break;
+                    },
+                }
+            }
+        });
+    }
+
+    // refuse new connections, and wait for the active ones to finish.
+    endpoint.set_server_config(None);
+    endpoint.wait_idle().await;
+}
+
+async fn handle(
+    server: Server,
+    req: http1::Request<()>,
+    stream: h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    remote_addr: SocketAddr,
+) -> Result<(), H3Error>
+{
+    let (mut send, mut recv) = stream.split();
+
+    // Stream the request body into a hyper::Body.
+    let (mut tx, body) = hyper::Body::channel();
+    tokio::spawn(async move {
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut data)) => {
+                    let data = data.copy_to_bytes(data.remaining());
+                    if tx.send_data(data).await.is_err() {
+                        break;
+                    }
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("quic: request body: {}", e);
+                    tx.abort();
+                    break;
+                },
+            }
+        }
+    });
+
+    // Build a http 0.2 request.
+    let mut builder = http::Request::builder()
+        .method(req.method().as_str())
+        .uri(req.uri().to_string())
+        .version(http::Version::HTTP_3);
+    for (name, value) in req.headers() {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    let mut req = builder.body(body)?;
+    req.extensions_mut().insert(crate::tls::Https);
+
+    let resp = server.route(req, remote_addr).await?;
+
+    // And convert the response back to http 1.x.
+    let (parts, mut body) = resp.into_parts();
+    let mut builder = http1::Response::builder().status(parts.status.as_u16());
+    for (name, value) in parts.headers.iter() {
+        if !HOP_BY_HOP.contains(&name.as_str()) {
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+    }
+    send.send_response(builder.body(())?).await?;
+    while let Some(data) = body.data().await {
+        send.send_data(data?).await?;
+    }
+    send.finish().await?;
+    Ok(())
+}
@@ -0,0 +1,127 @@
+//
+// Request rate limits per client address: request-rate (requests per
+// second, on average) and request-burst (how many may come at once), in
+// [limits] for all requests and in a location for the requests there.
+//
+// Every address has a token bucket that holds at most request-burst
+// requests and fills at request-rate. A request takes one; if there is
+// none it gets "429 Too Many Requests" with a Retry-After. The address
+// is the one after trusted-proxies. This is not the [throttle] on failed
+// logins, which only counts those.
+//
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+lazy_static::lazy_static! {
+    static ref BUCKETS: Mutex<Buckets> = Mutex::new(Buckets::default());
+}
+
+// Full buckets are forgotten when there are more than this.
+const MAX_BUCKETS: usize = 16384;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    last:   Instant,
+    rate:   f64,
+    burst:  f64,
+}
+
+impl Bucket {
+    // Take a token; if there is none, how long until there is one.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let (rate, burst) = (self.rate, self.burst);
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens + now.saturating_duration_since(self.last).as_secs_f64() * self.rate >= self.burst
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    // by the scope ("" for [limits], the location), and the address.
+    map: HashMap<(String, IpAddr), Bucket>,
+}
+
+impl Buckets {
+    fn check(
+        &mut self,
+        scope: &str,
+        ip: IpAddr,
+        rate: f64,
+        burst: f64,
+        now: Instant,
+    ) -> Result<(), Duration>
+    {
+        if self.map.len() >= MAX_BUCKETS {
+            self.map.retain(|_, b| !b.is_full(now));
+        }
+        let bucket = self.map.entry((scope.to_string(), ip)).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+            rate,
+            burst,
+        });
+        // the config may have been reloaded.
+        bucket.rate = rate;
+        bucket.burst = burst;
+        bucket.take(now)
+    }
+}
+
+/// The burst, if it is not set: a second worth of requests, at least one.
+pub fn burst(rate: f64, burst: Option<u32>) -> f64 {
+    burst.map(|b| b as f64).unwrap_or_else(|| rate.ceil().max(1.0))
+}
+
+/// Can a request from "ip" go ahead. If not, how many seconds until it
+/// can (for Retry-After).
+pub fn check(scope: &str, ip: IpAddr, rate: Option<f64>, burst: Option<u32>) -> Result<(), u64> {
+    let rate = match rate {
+        Some(rate) => rate,
+        None => return Ok(()),
+    };
+    let burst = self::burst(rate, burst);
+    let mut buckets = BUCKETS.lock().unwrap();
+    buckets
+        .check(scope, ip, rate, burst, Instant::now())
+        .map_err(|wait| wait.as_secs_f64().ceil().max(1.0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ratelimit() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+        let mut buckets = Buckets::default();
+        for _ in 0..3 {
+            assert!(buckets.check("", ip, 1.0, 3.0, now).is_ok());
+        }
+        let wait = buckets.check("", ip, 1.0, 3.0, now).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+        assert!(buckets.check("", other, 1.0, 3.0, now).is_ok());
+        assert!(buckets.check("/files", ip, 1.0, 3.0, now).is_ok());
+        let later = now + Duration::from_millis(1500);
+        assert!(buckets.check("", ip, 1.0, 3.0, later).is_ok());
+        assert!(buckets.check("", ip, 1.0, 3.0, later).is_err());
+
+        assert_eq!(burst(0.5, None), 1.0);
+        assert_eq!(burst(10.0, None), 10.0);
+        assert_eq!(burst(10.0, Some(50)), 50.0);
+    }
+}
@@ -0,0 +1,176 @@
+//
+// Read-ahead for GET of local files.
+//
+// The handler reads a file 16 KiB at a time, and for a local filesystem
+// every read is a task on the blocking thread pool. For large downloads
+// that is most of the CPU time. A ReadAheadFs reads bigger blocks, that
+// grow up to 1 MiB while the file is read from start to end, and hands
+// them out in the size that is asked for, without copying.
+//
+// With [server] sendfile, a download on a plain HTTP/1 connection does
+// not read through here at all, see sendfile.rs.
+//
+use std::future::Future;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use futures::future::FutureExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+const MAX_BLOCK: usize = 1024 * 1024;
+
+/// A filesystem that reads ahead, for files that are only read.
+#[derive(Clone)]
+pub struct ReadAheadFs {
+    fs: Box<dyn DavFileSystem>,
+}
+
+impl ReadAheadFs {
+    pub fn new(fs: Box<dyn DavFileSystem>) -> Box<ReadAheadFs> {
+        Box::new(ReadAheadFs { fs })
+    }
+}
+
+impl DavFileSystem for ReadAheadFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let file = self.fs.open(path, options).await?;
+            if options.write || options.append {
+                return Ok(file);
+            }
+            let file = ReadAheadFile {
+                file,
+                buf: Bytes::new(),
+                next: 0,
+            };
+            Ok(Box::new(file) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_dir(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_file(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.rename(from, to)
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.copy(from, to)
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        self.fs.patch_props(path, patch)
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[derive(Debug)]
+struct ReadAheadFile {
+    file: Box<dyn DavFile>,
+    // what was read, but not asked for yet.
+    buf:  Bytes,
+    // the size of the next block.
+    next: usize,
+}
+
+impl DavFile for ReadAheadFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.file.metadata()
+    }
+
+    fn write_buf<'a>(&'a mut self, buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        self.file.write_buf(buf)
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        self.file.write_bytes(buf)
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        async move {
+            if self.buf.is_empty() {
+                // The first block is what is asked for, for a small range.
+                let size = count.max(self.next);
+                self.buf = self.file.read_bytes(size).await?;
+                self.next = (size * 2).min(MAX_BLOCK);
+            }
+            let n = count.min(self.buf.len());
+            Ok(self.buf.split_to(n))
+        }
+        .boxed()
+    }
+
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> FsFuture<'a, u64> {
+        // The file is ahead of us by what is in the buffer.
+        let pos = match pos {
+            SeekFrom::Current(n) => SeekFrom::Current(n - self.buf.len() as i64),
+            pos => pos,
+        };
+        self.buf.clear();
+        self.next = 0;
+        self.file.seek(pos)
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        self.file.flush()
+    }
+}
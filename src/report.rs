@@ -0,0 +1,248 @@
+//
+// REPORT requests (RFC 3253). The body is parsed here, and the report is
+// handled by the module it belongs to: DAV:version-tree by deltav,
+// DAV:sync-collection by syncdb, and the calendar reports by pim. Any
+// other report is refused with a DAV:supported-report error.
+//
+use std::fmt::Write;
+use std::time::UNIX_EPOCH;
+
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavMetaData, FsError, FsResult};
+use xmltree::{Element, XMLNode};
+
+const MAX_BODY: usize = 65536;
+
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// A response, with an XML body if it is not empty.
+pub fn response(status: StatusCode, body: String) -> http::Response<String> {
+    let mut resp = http::Response::new(body);
+    *resp.status_mut() = status;
+    if !resp.body().is_empty() {
+        let ctype = "application/xml; charset=utf-8".parse().unwrap();
+        resp.headers_mut().insert("Content-Type", ctype);
+    }
+    resp
+}
+
+/// A response without a body.
+pub fn error(status: StatusCode) -> http::Response<String> {
+    response(status, String::new())
+}
+
+/// A DAV:error response, with a precondition like "supported-report".
+pub fn condition(status: StatusCode, name: &str) -> http::Response<String> {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"DAV:\"><D:{}/></D:error>\n",
+        name
+    );
+    response(status, body)
+}
+
+pub fn status(e: FsError) -> StatusCode {
+    match e {
+        FsError::NotFound => StatusCode::NOT_FOUND,
+        FsError::Forbidden => StatusCode::FORBIDDEN,
+        FsError::InsufficientStorage => StatusCode::INSUFFICIENT_STORAGE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Read the body of a request, which is XML.
+pub async fn body(req: http::Request<hyper::Body>) -> Result<hyper::body::Bytes, http::Response<String>> {
+    match hyper::body::to_bytes(req.into_body()).await {
+        Ok(b) if b.len() <= MAX_BODY => Ok(b),
+        Ok(_) => Err(error(StatusCode::PAYLOAD_TOO_LARGE)),
+        Err(_) => Err(error(StatusCode::BAD_REQUEST)),
+    }
+}
+
+/// Read and parse the body of a REPORT or SEARCH.
+pub async fn parse(req: http::Request<hyper::Body>) -> Result<Element, http::Response<String>> {
+    let body = body(req).await?;
+    Element::parse(&body[..]).map_err(|_| error(StatusCode::BAD_REQUEST))
+}
+
+/// Is this the DAV: element "name".
+pub fn is_dav(elem: &Element, name: &str) -> bool {
+    elem.name == name && elem.namespace.as_deref() == Some("DAV:")
+}
+
+/// The properties in the DAV:prop of a report, as (namespace, name), or
+/// the "default" DAV: properties if there is none.
+pub fn wanted(root: &Element, default: &[&str]) -> Vec<(String, String)> {
+    match root.get_child("prop") {
+        Some(prop) => {
+            prop.children
+                .iter()
+                .filter_map(XMLNode::as_element)
+                .map(|e| (e.namespace.clone().unwrap_or_default(), e.name.clone()))
+                .collect()
+        },
+        None => default.iter().map(|p| ("DAV:".to_string(), p.to_string())).collect(),
+    }
+}
+
+/// The href of a path, in the location with this prefix. A collection
+/// ends in a slash.
+pub fn href(prefix: &str, path: &DavPath, is_dir: bool) -> String {
+    let url = path.as_url_string();
+    match is_dir && !url.ends_with('/') {
+        true => format!("{}{}/", prefix, url),
+        false => format!("{}{}", prefix, url),
+    }
+}
+
+/// The path of an href, in the location with this prefix. A relative
+/// href is relative to the request path.
+pub fn href_path(href: &str, path: &DavPath, prefix: &str) -> Option<DavPath> {
+    let href = href.trim();
+    let href = match href.parse::<http::Uri>() {
+        Ok(uri) if uri.scheme().is_some() => uri.path().to_string(),
+        _ if href.starts_with('/') => href.to_string(),
+        _ => format!("{}{}", self::href(prefix, path, true), href),
+    };
+    let rest = href.strip_prefix(prefix)?;
+    match rest {
+        "" => DavPath::new("/").ok(),
+        r if r.starts_with('/') => DavPath::new(r).ok(),
+        _ => None,
+    }
+}
+
+/// The last segment of a path.
+pub fn file_name(path: &DavPath) -> String {
+    let name = path.as_bytes().split(|&c| c == b'/').rfind(|s| !s.is_empty());
+    String::from_utf8_lossy(name.unwrap_or_default()).into_owned()
+}
+
+/// The content type, by the extension, like the handler does.
+pub fn content_type(path: &DavPath, meta: &dyn DavMetaData) -> String {
+    if meta.is_dir() {
+        return "httpd/unix-directory".to_string();
+    }
+    let mime = mime_guess::from_path(file_name(path)).first_raw();
+    mime.unwrap_or("application/octet-stream").to_string()
+}
+
+/// The seconds since the epoch of a time of a resource.
+pub fn unix_time(t: FsResult<std::time::SystemTime>) -> Option<i64> {
+    Some(t.ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64)
+}
+
+/// The value of a live DAV: property of a resource, as XML.
+pub fn live_prop(name: &str, path: &DavPath, meta: &dyn DavMetaData) -> Option<String> {
+    let value = match name {
+        "displayname" => escape(&file_name(path)),
+        "getetag" => format!("\"{}\"", escape(&meta.etag()?)),
+        "getcontentlength" if !meta.is_dir() => meta.len().to_string(),
+        "getcontenttype" => content_type(path, meta),
+        "getlastmodified" => {
+            let secs = unix_time(meta.modified())?;
+            time::at_utc(time::Timespec::new(secs, 0)).rfc822().to_string()
+        },
+        "resourcetype" if meta.is_dir() => "<D:collection/>".to_string(),
+        "resourcetype" => String::new(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+// The namespaces that have a fixed prefix in a multistatus response.
+const NAMESPACES: &[(&str, &str)] = &[
+    ("D", "DAV:"),
+    ("C", "urn:ietf:params:xml:ns:caldav"),
+    ("CR", "urn:ietf:params:xml:ns:carddav"),
+    ("CS", "http://calendarserver.org/ns/"),
+];
+
+/// A property element, with a value that is XML. The value may use the
+/// fixed prefixes, like D: for DAV:.
+pub fn prop_xml(ns: &str, name: &str, value: &str) -> String {
+    let name = escape(name);
+    match NAMESPACES.iter().find(|(_, n)| *n == ns) {
+        Some((pfx, _)) if value.is_empty() => format!("<{}:{}/>\n", pfx, name),
+        Some((pfx, _)) => format!("<{}:{}>{}</{}:{}>\n", pfx, name, value, pfx, name),
+        None if value.is_empty() => format!("<X:{} xmlns:X=\"{}\"/>\n", name, escape(ns)),
+        None => format!("<X:{} xmlns:X=\"{}\">{}</X:{}>\n", name, escape(ns), value, name),
+    }
+}
+
+/// The body of a multistatus response.
+pub struct Multistatus(String);
+
+impl Multistatus {
+    pub fn new() -> Multistatus {
+        let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus");
+        for (pfx, ns) in NAMESPACES {
+            let _ = write!(body, " xmlns:{}=\"{}\"", pfx, ns);
+        }
+        body.push_str(">\n");
+        Multistatus(body)
+    }
+
+    /// A response with properties. "value" gives the XML value of a wanted
+    /// property, or None if it does not have it.
+    pub fn props<F>(&mut self, href: &str, wanted: &[(String, String)], mut value: F)
+    where F: FnMut(&str, &str) -> Option<String> {
+        let mut found = String::new();
+        let mut missing = String::new();
+        for (ns, name) in wanted {
+            match value(ns, name) {
+                Some(value) => found.push_str(&prop_xml(ns, name, &value)),
+                None => missing.push_str(&prop_xml(ns, name, "")),
+            }
+        }
+        self.propstat(href, &found, &missing);
+    }
+
+    /// A response with the properties that were found, and the ones that
+    /// were not, as XML elements.
+    pub fn propstat(&mut self, href: &str, found: &str, missing: &str) {
+        let _ = writeln!(self.0, "<D:response>\n<D:href>{}</D:href>", escape(href));
+        for (props, status) in [(found, "200 OK"), (missing, "404 Not Found")].iter() {
+            if !props.is_empty() {
+                let _ = write!(self.0, "<D:propstat>\n<D:prop>\n{}</D:prop>\n", props);
+                let _ = writeln!(self.0, "<D:status>HTTP/1.1 {}</D:status>\n</D:propstat>", status);
+            }
+        }
+        self.0.push_str("</D:response>\n");
+    }
+
+    /// A response with the live properties of a resource.
+    pub fn resource(
+        &mut self,
+        prefix: &str,
+        path: &DavPath,
+        meta: &dyn DavMetaData,
+        wanted: &[(String, String)],
+    )
+    {
+        let href = href(prefix, path, meta.is_dir());
+        self.props(&href, wanted, |ns, name| {
+            match ns {
+                "DAV:" => live_prop(name, path, meta),
+                _ => None,
+            }
+        });
+    }
+
+    /// A response with just a status.
+    pub fn status(&mut self, href: &str, status: StatusCode) {
+        let _ = writeln!(self.0, "<D:response>\n<D:href>{}</D:href>", escape(href));
+        let reason = status.canonical_reason().unwrap_or_default();
+        let _ = writeln!(self.0, "<D:status>HTTP/1.1 {} {}</D:status>", status.as_u16(), reason);
+        self.0.push_str("</D:response>\n");
+    }
+
+    /// The 207 response. "extra" is added after the responses.
+    pub fn finish(mut self, extra: &str) -> http::Response<String> {
+        self.0.push_str(extra);
+        self.0.push_str("</D:multistatus>\n");
+        response(StatusCode::MULTI_STATUS, self.0)
+    }
+}
@@ -0,0 +1,157 @@
+//
+// Locations added at runtime, with the admin API. They are kept in the
+// routes-file of [admin], as tables under [routes], by name:
+//
+//   [routes.acme]
+//   route = [ "/acme/*path" ]
+//   handler = "filesystem"
+//   directory = "/srv/shares/acme"
+//   auth = "true"
+//
+// The keys are those of a [[location]]. The locations come after the ones
+// in the config file, and are read again on a reload. A change is checked
+// like the config file is, before it is written and used.
+//
+use std::io;
+use std::sync::Mutex;
+
+use toml::value::{Table, Value};
+
+use crate::config::{self, Config, Location};
+use crate::server::Server;
+
+lazy_static::lazy_static! {
+    // one change at a time.
+    static ref UPDATE: Mutex<()> = Mutex::new(());
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// The routes in the file, by name.
+pub fn read(file: &str) -> io::Result<Table> {
+    let data = match std::fs::read_to_string(file) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Table::new()),
+        Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", file, e))),
+    };
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", file, e));
+    let mut table: Table = toml::from_str(&data).map_err(|e| invalid(e.to_string()))?;
+    match table.remove("routes") {
+        Some(Value::Table(routes)) => Ok(routes),
+        Some(_) => Err(invalid("routes: not a table".to_string())),
+        None => Ok(Table::new()),
+    }
+}
+
+fn write(file: &str, routes: &Table) -> io::Result<()> {
+    let mut table = Table::new();
+    table.insert("routes".to_string(), Value::Table(routes.clone()));
+    let data = toml::to_string(&table).map_err(io::Error::other)?;
+    let data = format!("# Locations added with the admin API (/api/routes).\n{}", data);
+    crate::statefile::write(file, data.as_bytes())
+}
+
+// Letters, digits, ".", "-" and "_".
+fn check_name(name: &str) -> io::Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || ".-_".contains(c);
+    if name.is_empty() || name.len() > 64 || !name.chars().all(valid) {
+        return Err(invalid_input(format!("{}: not a valid route name", name)));
+    }
+    Ok(())
+}
+
+// The locations of the config, with these routes after the ones of the
+// config file instead of the ones it had.
+fn apply(config: &mut Config, routes: &Table) -> io::Result<()> {
+    config.location.retain(|l| l.runtime_name.is_none());
+    for (name, value) in routes {
+        let mut location: Location = value
+            .clone()
+            .try_into()
+            .map_err(|e| invalid_input(format!("routes.{}: {}", name, e)))?;
+        location.runtime_name = Some(name.clone());
+        config.location.push(location);
+    }
+    Ok(())
+}
+
+/// Add the locations in the routes-file, if there is one, to a config
+/// that was just read.
+pub fn load(config: &mut Config) -> io::Result<()> {
+    let file = match config.admin.routes_file.clone() {
+        Some(file) => file,
+        None => return Ok(()),
+    };
+    let routes = read(&file)?;
+    apply(config, &routes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", file, e)))
+}
+
+/// Add, replace (Some) or remove (None) a route, and use it from now on.
+/// True if it was there before.
+pub fn update(server: &Server, name: &str, location: Option<Value>) -> io::Result<bool> {
+    let _guard = UPDATE.lock().unwrap();
+    let (live, _) = server.live();
+    let file = live
+        .admin
+        .routes_file
+        .as_deref()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no routes-file in [admin]"))?;
+    check_name(name)?;
+    let mut routes = read(file)?;
+    let existed = match location {
+        Some(Value::Table(location)) => routes.insert(name.to_string(), Value::Table(location)).is_some(),
+        Some(_) => return Err(invalid_input(format!("{}: not an object", name))),
+        None => {
+            if routes.remove(name).is_none() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: no such route", name)));
+            }
+            true
+        },
+    };
+
+    let mut config = (*live).clone();
+    apply(&mut config, &routes)?;
+    config::validate(&config).map_err(invalid_input)?;
+    config::build_routes(file, &mut config).map_err(|e| invalid_input(e.to_string()))?;
+    write(file, &routes).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file, e)))?;
+    server.reload(config)?;
+    info!("routes: {} {}", name, if existed { "updated" } else { "added" });
+    Ok(existed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routefile() {
+        let file = std::env::temp_dir().join(format!("webdav-server-routes-{}.toml", std::process::id()));
+        let file = file.to_str().unwrap();
+        assert!(read(file).unwrap().is_empty());
+
+        let mut routes = Table::new();
+        let location = "route = [ \"/a/*path\" ]\nhandler = \"mem\"\ndirectory = \"a\"\n";
+        let location: Table = toml::from_str(location).unwrap();
+        routes.insert("a".to_string(), Value::Table(location));
+        write(file, &routes).unwrap();
+        assert_eq!(read(file).unwrap(), routes);
+        std::fs::remove_file(file).unwrap();
+
+        let toml = "[server]\n[[location]]\nhandler = \"mem\"\ndirectory = \"x\"\n";
+        let mut config = config::from_value(toml::from_str(toml).unwrap()).unwrap();
+        apply(&mut config, &routes).unwrap();
+        apply(&mut config, &routes).unwrap();
+        assert_eq!(config.location.len(), 2);
+        assert_eq!(config.location[1].route, vec!["/a/*path"]);
+        assert_eq!(config.location[1].runtime_name.as_deref(), Some("a"));
+        routes.insert("b".to_string(), Value::Integer(1));
+        assert!(apply(&mut config, &routes).is_err());
+
+        assert!(check_name("acme-1.x_y").is_ok());
+        for bad in &["", "a/b", "a b", "%2e"] {
+            assert!(check_name(bad).is_err(), "{}", bad);
+        }
+    }
+}
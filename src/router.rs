@@ -9,7 +9,7 @@ use regex::bytes::{Match, Regex, RegexSet};
 use webdav_handler::{DavMethod, DavMethodSet};
 
 // internal representation of a route.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Route<T: Debug> {
     regex:   Regex,
     methods: Option<DavMethodSet>,
@@ -156,14 +156,14 @@ impl<T: Debug> Builder<T> {
     pub fn build(&mut self) -> Router<T> {
         let set = RegexSet::new(self.routes.iter().map(|r| r.regex.as_str())).unwrap();
         Router {
-            routes: std::mem::replace(&mut self.routes, Vec::new()),
+            routes: std::mem::take(&mut self.routes),
             set,
         }
     }
 }
 
 /// Dead simple HTTP router.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Router<T: Debug> {
     set:    RegexSet,
     routes: Vec<Route<T>>,
@@ -201,7 +201,7 @@ impl<T: Debug> Router<T> {
                 let mut params = Vec::new();
                 if let Some(caps) = route.regex.captures(path) {
                     for name in param_names {
-                        params.push(caps.name(name).map(|p| Param(p)));
+                        params.push(caps.name(name).map(Param));
                     }
                 } else {
                     for _ in param_names {
@@ -220,6 +220,7 @@ impl<T: Debug> Router<T> {
 }
 
 #[cfg(test)]
+#[allow(clippy::len_zero, clippy::comparison_to_empty)]
 mod tests {
     use super::*;
     use webdav_handler::DavMethod;
@@ -0,0 +1,540 @@
+//
+// S3 filesystem.
+//
+// Files are objects, with the location directory as key prefix. S3 has
+// no directories, so "a/b/" is a directory if there are objects below it.
+// MKCOL creates an empty "a/b/" object so that empty directories exist.
+//
+// PUT bodies are sent in parts with a multipart upload, so large files
+// are never kept in memory. Files can only be written front to back, not
+// appended to or written at an offset.
+//
+use std::fmt;
+use std::io::SeekFrom;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::future::{self, FutureExt};
+use futures::stream::{self, StreamExt};
+use hyper::body::HttpBody;
+use hyper::Body;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::s3::{Listing, Object, S3Client};
+
+// Listing page size.
+const PAGE_SIZE: usize = 1000;
+
+#[derive(Clone)]
+pub struct S3Fs {
+    s3:     Arc<S3Client>,
+    prefix: String,
+}
+
+#[derive(Debug, Clone)]
+struct S3Meta {
+    len:      u64,
+    modified: Option<SystemTime>,
+    etag:     Option<String>,
+    dir:      bool,
+}
+
+impl S3Meta {
+    fn dir() -> S3Meta {
+        S3Meta {
+            len:      0,
+            modified: None,
+            etag:     None,
+            dir:      true,
+        }
+    }
+
+    fn file(obj: &Object) -> S3Meta {
+        S3Meta {
+            len:      obj.size,
+            modified: obj.modified,
+            etag:     obj.etag.clone(),
+            dir:      false,
+        }
+    }
+}
+
+impl DavMetaData for S3Meta {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn modified(&self) -> FsResult<SystemTime> {
+        self.modified.ok_or(FsError::NotImplemented)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.dir
+    }
+
+    fn etag(&self) -> Option<String> {
+        self.etag.clone()
+    }
+}
+
+impl S3Fs {
+    /// `dir` is the key prefix, "" for the whole bucket.
+    pub fn new(s3: Arc<S3Client>, dir: &str) -> Box<S3Fs> {
+        let mut prefix = dir.trim_matches('/').to_string();
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        Box::new(S3Fs { s3, prefix })
+    }
+
+    // The key of a file. Keys are UTF-8.
+    fn key(&self, path: &DavPath) -> FsResult<String> {
+        let path = std::str::from_utf8(path.as_bytes()).map_err(|_| FsError::Forbidden)?;
+        Ok(format!("{}{}", self.prefix, path.trim_matches('/')))
+    }
+
+    // The key of a directory: the prefix of everything in it.
+    fn dir_key(&self, path: &DavPath) -> FsResult<String> {
+        let key = self.key(path)?;
+        if key.is_empty() || key.ends_with('/') {
+            return Ok(key);
+        }
+        Ok(key + "/")
+    }
+
+    fn is_root(&self, key: &str) -> bool {
+        key.is_empty() || key == self.prefix
+    }
+
+    // A directory exists if there is anything below it.
+    async fn dir_exists(&self, dir_key: &str) -> FsResult<bool> {
+        if self.is_root(dir_key) {
+            return Ok(true);
+        }
+        let l = self.s3.list(dir_key, true, 1, None).await?;
+        Ok(!l.objects.is_empty() || !l.prefixes.is_empty())
+    }
+
+    // Does the directory that "key" is in exist.
+    async fn parent_exists(&self, key: &str) -> FsResult<bool> {
+        let parent = match key.trim_end_matches('/').rfind('/') {
+            Some(pos) => &key[..pos + 1],
+            None => "",
+        };
+        self.dir_exists(parent).await
+    }
+
+    async fn file_exists(&self, key: &str) -> FsResult<bool> {
+        match self.s3.head(key).await {
+            Ok(_) => Ok(true),
+            Err(FsError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// The directory entries in one page of a listing.
+fn entries(dir_key: &str, l: Listing) -> Vec<Box<dyn DavDirEntry>> {
+    let mut v = Vec::new();
+    for obj in &l.objects {
+        let name = &obj.key[dir_key.len().min(obj.key.len())..];
+        // the directory marker itself.
+        if name.is_empty() || name.contains('/') {
+            continue;
+        }
+        v.push(Box::new(S3DirEntry {
+            name: name.to_string(),
+            meta: S3Meta::file(obj),
+        }) as Box<dyn DavDirEntry>);
+    }
+    for pfx in &l.prefixes {
+        let name = pfx[dir_key.len().min(pfx.len())..].trim_end_matches('/');
+        if !name.is_empty() {
+            v.push(Box::new(S3DirEntry {
+                name: name.to_string(),
+                meta: S3Meta::dir(),
+            }));
+        }
+    }
+    v
+}
+
+impl DavFileSystem for S3Fs {
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let key = self.key(path)?;
+            if self.is_root(&key) {
+                return Ok(Box::new(S3Meta::dir()) as Box<dyn DavMetaData>);
+            }
+            if !path.is_collection() {
+                match self.s3.head(&key).await {
+                    Ok(obj) => return Ok(Box::new(S3Meta::file(&obj)) as Box<dyn DavMetaData>),
+                    Err(FsError::NotFound) => {},
+                    Err(e) => return Err(e),
+                }
+            }
+            if self.dir_exists(&self.dir_key(path)?).await? {
+                return Ok(Box::new(S3Meta::dir()) as Box<dyn DavMetaData>);
+            }
+            Err(FsError::NotFound)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        _meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            // get the first page now, so that we can return errors.
+            let dir_key = self.dir_key(path)?;
+            let first = self.s3.list(&dir_key, true, PAGE_SIZE, None).await?;
+            if first.objects.is_empty() && first.prefixes.is_empty() && !self.is_root(&dir_key) {
+                return Err(FsError::NotFound);
+            }
+            let next = first.next.clone();
+            let first = entries(&dir_key, first);
+
+            // and the rest when they are needed.
+            let s3 = self.s3.clone();
+            let rest = stream::unfold(next, move |token| {
+                let s3 = s3.clone();
+                let dir_key = dir_key.clone();
+                async move {
+                    let token = token?;
+                    match s3.list(&dir_key, true, PAGE_SIZE, Some(&token)).await {
+                        Ok(l) => {
+                            let next = l.next.clone();
+                            Some((entries(&dir_key, l), next))
+                        },
+                        Err(e) => {
+                            error!("s3: list {}: {:?}", dir_key, e);
+                            None
+                        },
+                    }
+                }
+            });
+            let strm = stream::once(future::ready(first))
+                .chain(rest)
+                .flat_map(stream::iter);
+            Ok(Box::pin(strm) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let key = self.key(path)?;
+            if self.is_root(&key) {
+                return Err(FsError::Forbidden);
+            }
+            if !options.write {
+                let meta = S3Meta::file(&self.s3.head(&key).await?);
+                return Ok(Box::new(S3File::new(self.s3.clone(), key, meta, false)) as Box<dyn DavFile>);
+            }
+
+            // writing: only from the start.
+            if options.append || (!options.truncate && self.file_exists(&key).await?) {
+                return Err(FsError::NotImplemented);
+            }
+            if options.create_new && self.file_exists(&key).await? {
+                return Err(FsError::Exists);
+            }
+            if !options.create && !self.file_exists(&key).await? {
+                return Err(FsError::NotFound);
+            }
+            if !self.parent_exists(&key).await? {
+                return Err(FsError::NotFound);
+            }
+            if self.dir_exists(&self.dir_key(path)?).await? {
+                return Err(FsError::Forbidden);
+            }
+            let meta = S3Meta {
+                len:      0,
+                modified: Some(SystemTime::now()),
+                etag:     None,
+                dir:      false,
+            };
+            Ok(Box::new(S3File::new(self.s3.clone(), key, meta, true)) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let key = self.key(path)?;
+            let dir_key = self.dir_key(path)?;
+            if self.dir_exists(&dir_key).await? || self.file_exists(&key).await? {
+                return Err(FsError::Exists);
+            }
+            if !self.parent_exists(&key).await? {
+                return Err(FsError::NotFound);
+            }
+            self.s3.put(&dir_key, Bytes::new()).await
+        }
+        .boxed()
+    }
+
+    // the contents have already been removed, so just remove the marker.
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let dir_key = self.dir_key(path)?;
+            if self.is_root(&dir_key) {
+                return Err(FsError::Forbidden);
+            }
+            self.s3.delete(&dir_key).await
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let key = self.key(path)?;
+            if !self.file_exists(&key).await? {
+                return Err(FsError::NotFound);
+            }
+            self.s3.delete(&key).await
+        }
+        .boxed()
+    }
+
+    // S3 cannot rename, so copy and delete. For a directory, that is
+    // every object below it.
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let (from_key, to_key) = (self.key(from)?, self.key(to)?);
+            if self.is_root(&from_key) || self.is_root(&to_key) {
+                return Err(FsError::Forbidden);
+            }
+            if self.file_exists(&from_key).await? {
+                self.s3.copy(&from_key, &to_key).await?;
+                return self.s3.delete(&from_key).await;
+            }
+            let (from_dir, to_dir) = (self.dir_key(from)?, self.dir_key(to)?);
+            let mut token = None;
+            loop {
+                let l = self.s3.list(&from_dir, false, PAGE_SIZE, token.as_deref()).await?;
+                for obj in &l.objects {
+                    let dest = format!("{}{}", to_dir, &obj.key[from_dir.len()..]);
+                    self.s3.copy(&obj.key, &dest).await?;
+                    self.s3.delete(&obj.key).await?;
+                }
+                token = match l.next {
+                    Some(t) => Some(t),
+                    None => break,
+                };
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // only called for files.
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let (from_key, to_key) = (self.key(from)?, self.key(to)?);
+            if !self.parent_exists(&to_key).await? {
+                return Err(FsError::NotFound);
+            }
+            self.s3.copy(&from_key, &to_key).await
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct S3DirEntry {
+    meta: S3Meta,
+    name: String,
+}
+
+impl DavDirEntry for S3DirEntry {
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        Box::pin(future::ready(Ok(Box::new(self.meta.clone()) as Box<dyn DavMetaData>)))
+    }
+
+    fn name(&self) -> Vec<u8> {
+        self.name.as_bytes().to_vec()
+    }
+
+    fn is_dir(&self) -> FsFuture<'_, bool> {
+        Box::pin(future::ready(Ok(self.meta.dir)))
+    }
+}
+
+struct S3File {
+    s3:     Arc<S3Client>,
+    key:    String,
+    meta:   S3Meta,
+    pos:    u64,
+    // reading: the body of the GET at "pos", and data we got but did not return yet.
+    body:   Option<Body>,
+    rbuf:   Bytes,
+    // writing: data for the next part, the upload id, and the ETags of the parts.
+    write:  bool,
+    wbuf:   BytesMut,
+    upload: Option<String>,
+    parts:  Vec<String>,
+    done:   bool,
+}
+
+impl fmt::Debug for S3File {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("S3File")
+            .field("key", &self.key)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+impl S3File {
+    fn new(s3: Arc<S3Client>, key: String, meta: S3Meta, write: bool) -> S3File {
+        S3File {
+            s3,
+            key,
+            meta,
+            pos: 0,
+            body: None,
+            rbuf: Bytes::new(),
+            write,
+            wbuf: BytesMut::new(),
+            upload: None,
+            parts: Vec::new(),
+            done: false,
+        }
+    }
+
+    // Upload the data we have as the next part.
+    async fn upload_part(&mut self, data: Bytes) -> FsResult<()> {
+        let id = match self.upload {
+            Some(ref id) => id.clone(),
+            None => {
+                let id = self.s3.create_upload(&self.key).await?;
+                self.upload = Some(id.clone());
+                id
+            },
+        };
+        let etag = self.s3.upload_part(&self.key, &id, self.parts.len() + 1, data).await?;
+        self.parts.push(etag);
+        Ok(())
+    }
+}
+
+impl DavFile for S3File {
+    fn metadata(&mut self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        Box::pin(future::ready(Ok(Box::new(self.meta.clone()) as Box<dyn DavMetaData>)))
+    }
+
+    fn write_buf(&mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'_, ()> {
+        let data = buf.copy_to_bytes(buf.remaining());
+        self.write_bytes(data)
+    }
+
+    fn write_bytes(&mut self, buf: Bytes) -> FsFuture<'_, ()> {
+        async move {
+            if !self.write || self.done {
+                return Err(FsError::Forbidden);
+            }
+            self.pos += buf.len() as u64;
+            self.wbuf.extend_from_slice(&buf);
+            let part_size = self.s3.part_size();
+            while self.wbuf.len() >= part_size {
+                let part = self.wbuf.split_to(part_size).freeze();
+                self.upload_part(part).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn read_bytes(&mut self, count: usize) -> FsFuture<'_, Bytes> {
+        async move {
+            loop {
+                if !self.rbuf.is_empty() {
+                    let data = self.rbuf.split_to(count.min(self.rbuf.len()));
+                    self.pos += data.len() as u64;
+                    return Ok(data);
+                }
+                if self.write || self.pos >= self.meta.len {
+                    return Ok(Bytes::new());
+                }
+                if self.body.is_none() {
+                    self.body = Some(self.s3.get(&self.key, self.pos).await?);
+                }
+                match self.body.as_mut().unwrap().data().await {
+                    Some(Ok(data)) => self.rbuf = data,
+                    Some(Err(e)) => {
+                        error!("s3: get {}: {}", self.key, e);
+                        return Err(FsError::GeneralFailure);
+                    },
+                    None => return Ok(Bytes::new()),
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsFuture<'_, u64> {
+        let newpos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+            SeekFrom::End(d) => self.meta.len.checked_add_signed(d),
+        };
+        let res = match newpos {
+            Some(p) if p == self.pos => Ok(p),
+            // we can only write from the start, to the end.
+            Some(_) if self.write => Err(FsError::NotImplemented),
+            Some(p) => {
+                self.pos = p;
+                self.body = None;
+                self.rbuf = Bytes::new();
+                Ok(p)
+            },
+            None => Err(FsError::GeneralFailure),
+        };
+        Box::pin(future::ready(res))
+    }
+
+    // Finish the upload.
+    fn flush(&mut self) -> FsFuture<'_, ()> {
+        async move {
+            if !self.write || self.done {
+                return Ok(());
+            }
+            let data = self.wbuf.split().freeze();
+            match self.upload.clone() {
+                None => self.s3.put(&self.key, data).await?,
+                Some(id) => {
+                    if !data.is_empty() {
+                        self.upload_part(data).await?;
+                    }
+                    self.s3.complete_upload(&self.key, &id, &self.parts).await?;
+                },
+            }
+            self.done = true;
+            self.meta.len = self.pos;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+// An upload that was not finished, because of an error or because the
+// client went away, is removed.
+impl Drop for S3File {
+    fn drop(&mut self) {
+        if let (Some(id), false) = (self.upload.take(), self.done) {
+            let s3 = self.s3.clone();
+            let key = std::mem::take(&mut self.key);
+            tokio::spawn(async move {
+                if let Err(e) = s3.abort_upload(&key, &id).await {
+                    warn!("s3: abort upload {}: {:?}", key, e);
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,440 @@
+//
+// SEARCH (RFC 5323) with the DAV:basicsearch grammar.
+//
+// The scope is one collection in the location, with depth 0, 1 or
+// infinity. Conditions can be on displayname (the name), getcontenttype
+// (by the extension), getcontentlength and getlastmodified, with and, or,
+// not, eq, lt, gt, lte, gte, like, is-collection and is-defined. With a
+// full-text index (fulltext), contains matches the contents. Results can
+// be ordered on the same properties, and limited with nresults.
+//
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use futures::StreamExt;
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavFileSystem, DavMetaData, ReadDirMeta};
+use webdav_handler::DavMethod;
+use xmltree::{Element, XMLNode};
+
+use crate::report::{self, content_type, file_name, is_dav, unix_time, Multistatus};
+use crate::trashfs::join;
+
+/// Full-text lookup: the paths of the files that contain all the words.
+pub type Contains = Box<dyn Fn(&str) -> Result<HashSet<Vec<u8>>, StatusCode> + Send + Sync>;
+
+// The properties of a result, if the query does not select any.
+const PROPS: &[&str] = &[
+    "displayname",
+    "getcontenttype",
+    "getcontentlength",
+    "getlastmodified",
+    "getetag",
+    "resourcetype",
+];
+
+/// For routing and permissions, SEARCH is taken as PROPFIND.
+pub fn dav_method(method: &http::Method) -> Option<DavMethod> {
+    match method.as_str() {
+        "SEARCH" => Some(DavMethod::PropFind),
+        _ => None,
+    }
+}
+
+/// Add the DASL header to an OPTIONS response, and SEARCH to Allow.
+pub fn options(headers: &mut http::HeaderMap) {
+    headers.insert("DASL", "<DAV:basicsearch>".parse().unwrap());
+    let value = match headers.get("Allow").and_then(|v| v.to_str().ok()) {
+        Some(v) => format!("{},SEARCH", v),
+        None => return,
+    };
+    if let Ok(value) = value.parse() {
+        headers.insert("Allow", value);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Prop {
+    DisplayName,
+    ContentType,
+    ContentLength,
+    LastModified,
+}
+
+#[derive(Debug, PartialEq, PartialOrd)]
+enum Value {
+    Str(String),
+    Num(u64),
+    Time(i64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Lt,
+    Gt,
+    Lte,
+    Gte,
+}
+
+#[derive(Debug, PartialEq)]
+enum Cond {
+    And(Vec<Cond>),
+    Or(Vec<Cond>),
+    Not(Box<Cond>),
+    Compare(Op, Prop, Value, bool),
+    Like(Prop, String, bool),
+    // the words, and the paths of the files that contain them.
+    Contains(String, HashSet<Vec<u8>>),
+    IsCollection,
+    IsDefined(Prop),
+}
+
+// A found resource.
+struct Entry {
+    path: DavPath,
+    meta: Box<dyn DavMetaData>,
+}
+
+impl Prop {
+    fn parse(elem: &Element) -> Result<Prop, StatusCode> {
+        let prop = elem.get_child("prop").and_then(|p| p.children.iter().find_map(XMLNode::as_element));
+        let prop = match prop {
+            Some(p) if p.namespace.as_deref() == Some("DAV:") => p,
+            Some(_) => return Err(StatusCode::UNPROCESSABLE_ENTITY),
+            None => return Err(StatusCode::BAD_REQUEST),
+        };
+        match prop.name.as_str() {
+            "displayname" => Ok(Prop::DisplayName),
+            "getcontenttype" => Ok(Prop::ContentType),
+            "getcontentlength" => Ok(Prop::ContentLength),
+            "getlastmodified" => Ok(Prop::LastModified),
+            _ => Err(StatusCode::UNPROCESSABLE_ENTITY),
+        }
+    }
+
+    // A literal, as a value of this property.
+    fn literal(self, lit: &str) -> Result<Value, StatusCode> {
+        let lit = lit.trim();
+        match self {
+            Prop::DisplayName | Prop::ContentType => Ok(Value::Str(lit.to_string())),
+            Prop::ContentLength => lit.parse().map(Value::Num).map_err(|_| StatusCode::BAD_REQUEST),
+            Prop::LastModified => {
+                let tm = time::strptime(lit, "%a, %d %b %Y %H:%M:%S GMT")
+                    .or_else(|_| time::strptime(lit, "%Y-%m-%dT%H:%M:%SZ"))
+                    .map_err(|_| StatusCode::BAD_REQUEST)?;
+                Ok(Value::Time(tm.to_timespec().sec))
+            },
+        }
+    }
+
+    fn value(self, entry: &Entry) -> Option<Value> {
+        match self {
+            Prop::DisplayName => Some(Value::Str(file_name(&entry.path))),
+            Prop::ContentType => Some(Value::Str(content_type(&entry.path, &*entry.meta))),
+            Prop::ContentLength if entry.meta.is_dir() => None,
+            Prop::ContentLength => Some(Value::Num(entry.meta.len())),
+            Prop::LastModified => unix_time(entry.meta.modified()).map(Value::Time),
+        }
+    }
+}
+
+fn elements(elem: &Element) -> impl Iterator<Item = &Element> {
+    elem.children.iter().filter_map(XMLNode::as_element)
+}
+
+fn caseless(elem: &Element) -> bool {
+    elem.attributes.get("caseless").map(|v| v == "yes").unwrap_or(false)
+}
+
+impl Cond {
+    fn parse(elem: &Element) -> Result<Cond, StatusCode> {
+        if elem.namespace.as_deref() != Some("DAV:") {
+            return Err(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        let literal = || elem.get_child("literal").and_then(|l| l.get_text()).unwrap_or_default();
+        let op = match elem.name.as_str() {
+            "and" => return Ok(Cond::And(elements(elem).map(Cond::parse).collect::<Result<_, _>>()?)),
+            "or" => return Ok(Cond::Or(elements(elem).map(Cond::parse).collect::<Result<_, _>>()?)),
+            "not" => {
+                let mut conds = elements(elem);
+                return match (conds.next(), conds.next()) {
+                    (Some(cond), None) => Ok(Cond::Not(Box::new(Cond::parse(cond)?))),
+                    _ => Err(StatusCode::BAD_REQUEST),
+                };
+            },
+            "like" => {
+                let prop = Prop::parse(elem)?;
+                if !matches!(prop, Prop::DisplayName | Prop::ContentType) {
+                    return Err(StatusCode::UNPROCESSABLE_ENTITY);
+                }
+                return Ok(Cond::Like(prop, literal().into_owned(), caseless(elem)));
+            },
+            "contains" => {
+                let words = elem.get_text().unwrap_or_default().into_owned();
+                return Ok(Cond::Contains(words, HashSet::new()));
+            },
+            "is-collection" => return Ok(Cond::IsCollection),
+            "is-defined" => return Ok(Cond::IsDefined(Prop::parse(elem)?)),
+            "eq" => Op::Eq,
+            "lt" => Op::Lt,
+            "gt" => Op::Gt,
+            "lte" => Op::Lte,
+            "gte" => Op::Gte,
+            _ => return Err(StatusCode::UNPROCESSABLE_ENTITY),
+        };
+        let prop = Prop::parse(elem)?;
+        Ok(Cond::Compare(op, prop, prop.literal(&literal())?, caseless(elem)))
+    }
+
+    // Look up the words of DAV:contains, which needs a full-text index.
+    fn resolve(&mut self, contains: Option<&Contains>) -> Result<(), StatusCode> {
+        match self {
+            Cond::And(conds) | Cond::Or(conds) => conds.iter_mut().try_for_each(|c| c.resolve(contains)),
+            Cond::Not(cond) => cond.resolve(contains),
+            Cond::Contains(words, paths) => {
+                let contains = contains.ok_or(StatusCode::UNPROCESSABLE_ENTITY)?;
+                *paths = contains(words)?;
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            Cond::And(conds) => conds.iter().all(|c| c.matches(entry)),
+            Cond::Or(conds) => conds.iter().any(|c| c.matches(entry)),
+            Cond::Not(cond) => !cond.matches(entry),
+            Cond::Compare(op, prop, lit, caseless) => {
+                let ord = match (prop.value(entry), lit) {
+                    (Some(Value::Str(a)), Value::Str(b)) if *caseless => {
+                        a.to_lowercase().cmp(&b.to_lowercase())
+                    },
+                    (Some(a), b) => match a.partial_cmp(b) {
+                        Some(ord) => ord,
+                        None => return false,
+                    },
+                    (None, _) => return false,
+                };
+                match op {
+                    Op::Eq => ord == Ordering::Equal,
+                    Op::Lt => ord == Ordering::Less,
+                    Op::Gt => ord == Ordering::Greater,
+                    Op::Lte => ord != Ordering::Greater,
+                    Op::Gte => ord != Ordering::Less,
+                }
+            },
+            Cond::Like(prop, pattern, caseless) => {
+                match prop.value(entry) {
+                    Some(Value::Str(s)) if *caseless => like(&pattern.to_lowercase(), &s.to_lowercase()),
+                    Some(Value::Str(s)) => like(pattern, &s),
+                    _ => false,
+                }
+            },
+            Cond::Contains(_, paths) => paths.contains(entry.path.as_bytes()),
+            Cond::IsCollection => entry.meta.is_dir(),
+            Cond::IsDefined(prop) => prop.value(entry).is_some(),
+        }
+    }
+}
+
+// SQL-like matching: "%" is any string, "_" any character, and "\"
+// escapes them.
+fn like(pattern: &str, s: &str) -> bool {
+    fn like(p: &[char], s: &[char]) -> bool {
+        match p.first() {
+            None => s.is_empty(),
+            Some('%') => (0..=s.len()).any(|i| like(&p[1..], &s[i..])),
+            Some('_') => !s.is_empty() && like(&p[1..], &s[1..]),
+            Some('\\') if p.len() > 1 => s.first() == Some(&p[1]) && like(&p[2..], &s[1..]),
+            Some(c) => s.first() == Some(c) && like(&p[1..], &s[1..]),
+        }
+    }
+    let p = pattern.chars().collect::<Vec<_>>();
+    let s = s.chars().collect::<Vec<_>>();
+    like(&p, &s)
+}
+
+/// Handle a SEARCH request. "path" is the path of the request, and
+/// "prefix" the prefix of the location. Without "contains", there is
+/// no full-text search.
+pub async fn handle(
+    req: http::Request<hyper::Body>,
+    fs: &dyn DavFileSystem,
+    contains: Option<&Contains>,
+    path: &DavPath,
+    prefix: &str,
+) -> http::Response<String>
+{
+    let root = match report::parse(req).await {
+        Ok(root) => root,
+        Err(resp) => return resp,
+    };
+    let search = match root.get_child("basicsearch") {
+        Some(s) if is_dav(&root, "searchrequest") && s.namespace.as_deref() == Some("DAV:") => s,
+        _ => return report::condition(StatusCode::UNPROCESSABLE_ENTITY, "search-grammar-supported"),
+    };
+
+    // what to return.
+    let wanted = match search.get_child("select") {
+        Some(select) if select.get_child("allprop").is_none() => report::wanted(select, PROPS),
+        _ => report::wanted(search, PROPS),
+    };
+
+    // where to search.
+    let scopes = search.get_child("from").map(|f| elements(f).collect::<Vec<_>>()).unwrap_or_default();
+    let scope = match scopes.as_slice() {
+        [scope] if is_dav(scope, "scope") => scope,
+        [_, _, ..] => return report::condition(StatusCode::BAD_REQUEST, "search-multiple-scope-supported"),
+        _ => return report::error(StatusCode::BAD_REQUEST),
+    };
+    let href = scope.get_child("href").and_then(|h| h.get_text()).unwrap_or_default();
+    let scope_path = match report::href_path(&href, path, prefix) {
+        Some(p) => p,
+        None => return report::condition(StatusCode::BAD_REQUEST, "search-scope-valid"),
+    };
+    let depth = scope.get_child("depth").and_then(|d| d.get_text());
+    let depth = match depth.as_deref().map(str::trim) {
+        Some("0") => Some(0),
+        Some("1") => Some(1),
+        Some("infinity") | None => None,
+        Some(_) => return report::error(StatusCode::BAD_REQUEST),
+    };
+
+    // the conditions, order and limit.
+    let cond = match search.get_child("where").map(|w| elements(w).collect::<Vec<_>>()) {
+        Some(conds) if conds.len() == 1 => Some(Cond::parse(conds[0])),
+        Some(conds) if conds.is_empty() => None,
+        Some(_) => Some(Err(StatusCode::BAD_REQUEST)),
+        None => None,
+    };
+    let mut cond = match cond.transpose() {
+        Ok(cond) => cond,
+        Err(status) => return report::error(status),
+    };
+    if let Err(status) = cond.as_mut().map(|c| c.resolve(contains)).transpose() {
+        return report::error(status);
+    }
+    let mut order = Vec::new();
+    for elem in search.get_child("orderby").iter().flat_map(|o| elements(o)) {
+        match Prop::parse(elem) {
+            Ok(prop) => order.push((prop, elem.get_child("descending").is_some())),
+            Err(status) => return report::error(status),
+        }
+    }
+    let limit = search.get_child("limit").and_then(|l| l.get_child("nresults")).and_then(|n| n.get_text());
+    let limit = match limit.map(|n| n.trim().parse::<usize>()) {
+        Some(Ok(n)) => Some(n),
+        Some(Err(_)) => return report::error(StatusCode::BAD_REQUEST),
+        None => None,
+    };
+
+    let mut found = match find(fs, &scope_path, depth, cond.as_ref()).await {
+        Ok(found) => found,
+        Err(status) => return report::error(status),
+    };
+    if !order.is_empty() {
+        found.sort_by(|a, b| {
+            for &(prop, descending) in &order {
+                let ord = match (prop.value(a), prop.value(b)) {
+                    (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+                    (a, b) => a.is_none().cmp(&b.is_none()),
+                };
+                let ord = if descending { ord.reverse() } else { ord };
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            Ordering::Equal
+        });
+    }
+
+    let mut multi = Multistatus::new();
+    let truncated = limit.map(|n| found.len() > n).unwrap_or(false);
+    for entry in found.iter().take(limit.unwrap_or(usize::MAX)) {
+        multi.resource(prefix, &entry.path, &*entry.meta, &wanted);
+    }
+    // more results than the limit.
+    if truncated {
+        multi.status(&report::href(prefix, path, true), StatusCode::INSUFFICIENT_STORAGE);
+    }
+    multi.finish("")
+}
+
+// The resources in the scope that match.
+async fn find(
+    fs: &dyn DavFileSystem,
+    scope: &DavPath,
+    depth: Option<u32>,
+    cond: Option<&Cond>,
+) -> Result<Vec<Entry>, StatusCode>
+{
+    let meta = fs.metadata(scope).await.map_err(report::status)?;
+    let mut found = Vec::new();
+    let mut dirs = Vec::new();
+    if meta.is_dir() && depth != Some(0) {
+        dirs.push((scope.clone(), 1));
+    }
+    let entry = Entry { path: scope.clone(), meta };
+    if cond.map(|c| c.matches(&entry)).unwrap_or(true) {
+        found.push(entry);
+    }
+    while let Some((dir, level)) = dirs.pop() {
+        let mut entries = match fs.read_dir(&dir, ReadDirMeta::Data).await {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        while let Some(dirent) = entries.next().await {
+            let meta = match dirent.metadata().await {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            let path = match join(&dir, &dirent.name()) {
+                Ok(path) => path,
+                Err(_) => continue,
+            };
+            if meta.is_dir() && depth.map(|d| level < d).unwrap_or(true) {
+                dirs.push((path.clone(), level + 1));
+            }
+            let entry = Entry { path, meta };
+            if cond.map(|c| c.matches(&entry)).unwrap_or(true) {
+                found.push(entry);
+            }
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_like() {
+        assert!(like("%.txt", "notes.txt"));
+        assert!(!like("%.txt", "notes.txt~"));
+        assert!(like("a_c", "abc"));
+        assert!(!like("a_c", "ac"));
+        assert!(like("100\\%", "100%"));
+        assert!(!like("100\\%", "1000"));
+        assert!(like("%", ""));
+    }
+
+    #[test]
+    fn test_parse() {
+        let xml = r#"<D:and xmlns:D="DAV:">
+            <D:like caseless="yes"><D:prop><D:displayname/></D:prop><D:literal>%.TXT</D:literal></D:like>
+            <D:not><D:gt><D:prop><D:getcontentlength/></D:prop><D:literal>100</D:literal></D:gt></D:not>
+        </D:and>"#;
+        let cond = Cond::parse(&Element::parse(xml.as_bytes()).unwrap()).unwrap();
+        let gt = Cond::Compare(Op::Gt, Prop::ContentLength, Value::Num(100), false);
+        let like = Cond::Like(Prop::DisplayName, "%.TXT".to_string(), true);
+        assert_eq!(cond, Cond::And(vec![like, Cond::Not(Box::new(gt))]));
+
+        let xml = r#"<D:lt xmlns:D="DAV:"><D:prop><D:getetag/></D:prop><D:literal>x</D:literal></D:lt>"#;
+        let res = Cond::parse(&Element::parse(xml.as_bytes()).unwrap());
+        assert_eq!(res, Err(StatusCode::UNPROCESSABLE_ENTITY));
+    }
+}
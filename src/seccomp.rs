@@ -0,0 +1,177 @@
+//
+// seccomp-bpf allowlists ([sandbox] seccomp and pam-seccomp).
+//
+// The server gets its filter when it is up: the listeners are open, the
+// databases too, and the privileges are dropped. From then on it only
+// needs the system calls of serving files and talking to the network;
+// it never starts a program. The PAM server process gets a filter of
+// its own, that also allows what PAM modules do (exec helpers, switch
+// uids), when it starts.
+//
+// A system call that is not in the list is logged by the kernel
+// (audit), fails with EPERM (deny), or kills the process (kill). The
+// audit mode is for trying it out: see the audit log or dmesg for
+// "type=1326", with the number of the system call.
+//
+use std::io;
+
+use crate::config::Seccomp;
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod syscalls {
+    use libc::*;
+
+    #[cfg(target_arch = "x86_64")]
+    pub const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    pub const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    // What the server does: files, xattrs, sockets, threads, timers,
+    // switching the uid of a thread, io_uring, inotify, quota.
+    pub const SERVER: &[c_long] = &[
+        SYS_read, SYS_write, SYS_readv, SYS_writev, SYS_pread64, SYS_pwrite64, SYS_preadv, SYS_pwritev,
+        SYS_preadv2, SYS_pwritev2, SYS_lseek, SYS_close, SYS_close_range, SYS_openat, SYS_fstat,
+        SYS_newfstatat, SYS_statx, SYS_statfs, SYS_fstatfs, SYS_faccessat, SYS_faccessat2, SYS_getdents64,
+        SYS_getcwd, SYS_chdir, SYS_fchdir, SYS_mkdirat, SYS_unlinkat, SYS_renameat2, SYS_linkat,
+        SYS_symlinkat, SYS_readlinkat, SYS_fchmod, SYS_fchmodat, SYS_fchown, SYS_fchownat, SYS_utimensat,
+        SYS_umask, SYS_fsync, SYS_fdatasync, SYS_ftruncate, SYS_fallocate, SYS_flock, SYS_fcntl, SYS_ioctl,
+        SYS_sendfile, SYS_copy_file_range, SYS_splice, SYS_getxattr, SYS_lgetxattr, SYS_fgetxattr,
+        SYS_setxattr, SYS_lsetxattr, SYS_fsetxattr, SYS_listxattr, SYS_llistxattr, SYS_flistxattr,
+        SYS_removexattr, SYS_lremovexattr, SYS_fremovexattr, SYS_quotactl, SYS_socket, SYS_socketpair,
+        SYS_connect, SYS_bind, SYS_listen, SYS_accept4, SYS_getsockname, SYS_getpeername, SYS_setsockopt,
+        SYS_getsockopt, SYS_sendto, SYS_recvfrom, SYS_sendmsg, SYS_recvmsg, SYS_sendmmsg, SYS_recvmmsg,
+        SYS_shutdown, SYS_epoll_create1, SYS_epoll_ctl, SYS_epoll_pwait, SYS_eventfd2, SYS_ppoll,
+        SYS_pselect6, SYS_pipe2, SYS_dup, SYS_dup3, SYS_mmap, SYS_munmap, SYS_mremap, SYS_mprotect,
+        SYS_madvise, SYS_brk, SYS_memfd_create, SYS_futex, SYS_set_robust_list, SYS_get_robust_list, SYS_rseq,
+        SYS_membarrier, SYS_clone, SYS_clone3, SYS_exit, SYS_exit_group, SYS_sched_yield,
+        SYS_sched_getaffinity, SYS_getcpu, SYS_nanosleep, SYS_clock_nanosleep, SYS_clock_gettime,
+        SYS_clock_getres, SYS_gettimeofday, SYS_rt_sigaction, SYS_rt_sigprocmask, SYS_rt_sigreturn,
+        SYS_sigaltstack, SYS_restart_syscall, SYS_tgkill, SYS_gettid, SYS_getpid, SYS_getppid, SYS_getuid,
+        SYS_geteuid, SYS_getgid, SYS_getegid, SYS_getresuid, SYS_getresgid, SYS_getgroups, SYS_setresuid,
+        SYS_setresgid, SYS_setgroups, SYS_capget, SYS_prctl, SYS_prlimit64, SYS_getrusage, SYS_sysinfo,
+        SYS_uname, SYS_getrandom, SYS_inotify_init1, SYS_inotify_add_watch, SYS_inotify_rm_watch,
+        SYS_io_uring_setup, SYS_io_uring_enter, SYS_io_uring_register,
+    ];
+
+    // The old ones that aarch64 does not have.
+    #[cfg(target_arch = "x86_64")]
+    pub const SERVER_X86_64: &[c_long] = &[
+        SYS_open, SYS_stat, SYS_lstat, SYS_access, SYS_getdents, SYS_mkdir, SYS_rmdir, SYS_unlink,
+        SYS_rename, SYS_link, SYS_symlink, SYS_readlink, SYS_chmod, SYS_chown, SYS_lchown, SYS_poll,
+        SYS_select, SYS_epoll_wait, SYS_epoll_create, SYS_pipe, SYS_dup2, SYS_getrlimit, SYS_arch_prctl,
+        SYS_time, SYS_renameat,
+    ];
+    #[cfg(target_arch = "aarch64")]
+    pub const SERVER_X86_64: &[c_long] = &[];
+
+    // And what PAM modules do: run helpers, switch uids, wait for them.
+    pub const PAM: &[c_long] = &[
+        SYS_execve, SYS_execveat, SYS_wait4, SYS_waitid, SYS_kill, SYS_setsid, SYS_setpgid, SYS_getpgid,
+        SYS_getsid, SYS_setuid, SYS_setgid, SYS_setreuid, SYS_setregid, SYS_setfsuid, SYS_setfsgid,
+        SYS_capset, SYS_rt_sigsuspend, SYS_rt_sigtimedwait, SYS_setitimer, SYS_getitimer, SYS_mlock,
+        SYS_munlock, SYS_mincore, SYS_unshare,
+    ];
+    #[cfg(target_arch = "x86_64")]
+    pub const PAM_X86_64: &[c_long] = &[SYS_fork, SYS_vfork, SYS_alarm, SYS_pause, SYS_getpgrp];
+    #[cfg(target_arch = "aarch64")]
+    pub const PAM_X86_64: &[c_long] = &[];
+}
+
+// The filter: the architecture must be ours, then the allowed calls,
+// then the action for the rest.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn filter(arch: u32, allow: &[libc::c_long], action: u32) -> Vec<libc::sock_filter> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+    let stmt = |code: u32, k: u32| libc::sock_filter { code: code as u16, jt: 0, jf: 0, k };
+    let jeq = |k: u32, jt: u8, jf: u8| {
+        libc::sock_filter {
+            code: (BPF_JMP | BPF_JEQ | BPF_K) as u16,
+            jt,
+            jf,
+            k,
+        }
+    };
+
+    // offsets in struct seccomp_data.
+    let (nr, arch_offset) = (0, 4);
+    let mut prog = vec![
+        stmt(BPF_LD | BPF_W | BPF_ABS, arch_offset),
+        jeq(arch, 1, 0),
+        stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD | BPF_W | BPF_ABS, nr),
+    ];
+    for call in allow {
+        prog.push(jeq(*call as u32, 0, 1));
+        prog.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    }
+    prog.push(stmt(BPF_RET | BPF_K, action));
+    prog
+}
+
+/// Apply the filter of the server, or the one of the PAM server, to all
+/// threads of the process.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn apply(mode: Seccomp, pam: bool) -> io::Result<()> {
+    use self::syscalls::*;
+
+    let action = match mode {
+        Seccomp::Off => return Ok(()),
+        Seccomp::Audit => libc::SECCOMP_RET_LOG,
+        Seccomp::Deny => libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+        Seccomp::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+    };
+    let mut allow = [SERVER, SERVER_X86_64].concat();
+    if pam {
+        allow.extend_from_slice(PAM);
+        allow.extend_from_slice(PAM_X86_64);
+    }
+    let prog = filter(AUDIT_ARCH, &allow, action);
+    let fprog = libc::sock_fprog {
+        len:    prog.len() as u16,
+        filter: prog.as_ptr() as *mut _,
+    };
+    unsafe {
+        // needed for a filter without CAP_SYS_ADMIN.
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = libc::SECCOMP_FILTER_FLAG_TSYNC;
+        let fprog = &fprog as *const libc::sock_fprog;
+        match libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, flags, fprog) {
+            0 => Ok(()),
+            r if r > 0 => Err(io::Error::other(format!("seccomp: thread {} could not be synced", r))),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+pub fn apply(mode: Seccomp, _pam: bool) -> io::Result<()> {
+    match mode {
+        Seccomp::Off => Ok(()),
+        _ => Err(io::Error::other("seccomp: only on Linux, x86_64 and aarch64")),
+    }
+}
+
+#[cfg(all(test, target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seccomp() {
+        let allow = [libc::SYS_read, libc::SYS_write];
+        let prog = filter(syscalls::AUDIT_ARCH, &allow, libc::SECCOMP_RET_LOG);
+        assert_eq!(prog.len(), 4 + 2 * allow.len() + 1);
+        assert_eq!(prog[1].k, syscalls::AUDIT_ARCH);
+        assert_eq!((prog[4].k, prog[4].jt, prog[4].jf), (libc::SYS_read as u32, 0, 1));
+        assert_eq!(prog[5].k, libc::SECCOMP_RET_ALLOW);
+        assert_eq!(prog.last().unwrap().k, libc::SECCOMP_RET_LOG);
+
+        let mut all = [syscalls::SERVER, syscalls::SERVER_X86_64, syscalls::PAM].concat();
+        let len = all.len();
+        all.sort_unstable();
+        all.dedup();
+        assert_eq!(all.len(), len);
+    }
+}
@@ -0,0 +1,447 @@
+//
+// webdav-server selftest: the configured server, with an in-memory
+// location in front of the others, on a listener on localhost. A suite of
+// protocol checks is run against it, much like litmus does (basic methods,
+// COPY and MOVE, properties, preconditions, and locks), and then a load
+// test with a number of concurrent clients.
+//
+// Only that location is used, so no files are touched. The server-wide
+// settings (headers, limits, the lock policy, ...) are the configured
+// ones. The access, audit and auth-failure logs are not opened. With the
+// "sqlite" feature the location gets a lock-db in a temporary file, so
+// that the locks are real; without it, only what fake locks do is checked.
+//
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use http::{HeaderMap, StatusCode};
+use hyper::client::HttpConnector;
+use toml::value::{Table, Value};
+
+use crate::config::{self, Config};
+use crate::server::Server;
+
+const ROUTE: &str = "/webdav-server-selftest";
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The settings of the load test.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub clients:  usize,
+    pub requests: usize,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            clients:  8,
+            requests: 100,
+        }
+    }
+}
+
+/// The config file "cfg", with the location for the selftest in front.
+pub fn config(cfg: &str) -> io::Result<Config> {
+    let err = |e: String| invalid_data(format!("{}: {}", cfg, e));
+    let mut table = match config::read_toml(cfg)? {
+        Value::Table(table) => table,
+        _ => return Err(err("not a table".to_string())),
+    };
+    if let Some(log) = table.get_mut("log").and_then(Value::as_table_mut) {
+        for key in &["access", "audit", "auth-failures"] {
+            log.remove(*key);
+        }
+    }
+    let mut location = Table::new();
+    location.insert("route".to_string(), Value::Array(vec![format!("{}/*path", ROUTE).into()]));
+    location.insert("handler".to_string(), "mem".into());
+    location.insert("directory".to_string(), format!("selftest:{}", std::process::id()).into());
+    location.insert("methods".to_string(), Value::Array(vec!["webdav-rw".into()]));
+    location.insert("auth".to_string(), "false".into());
+    if cfg!(feature = "sqlite") {
+        let db = std::env::temp_dir().join(format!("webdav-server-selftest-{}.db", std::process::id()));
+        location.insert("lock-db".to_string(), db.to_string_lossy().into_owned().into());
+    }
+    let locations = table
+        .entry("location".to_string())
+        .or_insert_with(|| Value::Array(Vec::new()));
+    match locations.as_array_mut() {
+        Some(locations) => locations.insert(0, Value::Table(location)),
+        None => return Err(err("location: not an array".to_string())),
+    }
+    let mut config = config::from_value(Value::Table(table)).map_err(|e| err(e.to_string()))?;
+    config::validate(&config).map_err(err)?;
+    config::build_routes(cfg, &mut config)?;
+    Ok(config)
+}
+
+// A response, with all of the body.
+struct Response {
+    status:  StatusCode,
+    headers: HeaderMap,
+    body:    String,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> &str {
+        self.headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or("")
+    }
+}
+
+#[derive(Clone)]
+struct Client {
+    http: hyper::Client<HttpConnector>,
+    base: String,
+}
+
+impl Client {
+    // The absolute URL of a path in the selftest location.
+    fn url(&self, path: &str) -> String {
+        format!("{}{}/{}", self.base, ROUTE, path)
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> io::Result<Response>
+    {
+        let mut req = http::Request::builder().method(method).uri(self.url(path));
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        let req = req
+            .body(hyper::Body::from(body.to_string()))
+            .map_err(|e| invalid_data(e.to_string()))?;
+        let resp = self.http.request(req).await.map_err(io::Error::other)?;
+        let (parts, body) = resp.into_parts();
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(io::Error::other)?;
+        Ok(Response {
+            status:  parts.status,
+            headers: parts.headers,
+            body:    String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+}
+
+// The checks, and their outcome.
+struct Suite {
+    client: Client,
+    passed: usize,
+    failed: usize,
+}
+
+impl Suite {
+    fn report(&mut self, name: &str, problem: Option<String>) {
+        match problem {
+            None => {
+                println!("ok    {}", name);
+                self.passed += 1;
+            },
+            Some(problem) => {
+                println!("FAIL  {}: {}", name, problem);
+                self.failed += 1;
+            },
+        }
+    }
+
+    // Send a request, and check the status of the response.
+    async fn check(
+        &mut self,
+        name: &str,
+        req: (&str, &str, &[(&str, &str)], &str),
+        status: &[u16],
+    ) -> Option<Response>
+    {
+        let (method, path, headers, body) = req;
+        match self.client.send(method, path, headers, body).await {
+            Ok(resp) if status.contains(&resp.status.as_u16()) => {
+                self.report(name, None);
+                Some(resp)
+            },
+            Ok(resp) => {
+                let problem = format!("{} {}: status {}, expected {:?}", method, path, resp.status, status);
+                self.report(name, Some(problem));
+                None
+            },
+            Err(e) => {
+                self.report(name, Some(format!("{} {}: {}", method, path, e)));
+                None
+            },
+        }
+    }
+
+    // Check something about a response.
+    fn verify(&mut self, name: &str, resp: Option<&Response>, ok: impl Fn(&Response) -> bool, what: &str) {
+        if let Some(resp) = resp {
+            let problem = Some(format!("expected {}", what)).filter(|_| !ok(resp));
+            self.report(name, problem);
+        }
+    }
+
+    async fn basic(&mut self) {
+        let resp = self.check("options", ("OPTIONS", "", &[], ""), &[200]).await;
+        self.verify("options_dav", resp.as_ref(), |r| r.header("DAV").contains('1'), "DAV: 1");
+        self.check("mkcol", ("MKCOL", "coll/", &[], ""), &[201]).await;
+        self.check("mkcol_again", ("MKCOL", "coll/", &[], ""), &[405]).await;
+        self.check("mkcol_no_parent", ("MKCOL", "none/coll/", &[], ""), &[409]).await;
+        self.check("mkcol_with_body", ("MKCOL", "body/", &[], "<x/>"), &[415]).await;
+
+        self.check("put", ("PUT", "coll/a.txt", &[], "selftest data"), &[201]).await;
+        let resp = self.check("get", ("GET", "coll/a.txt", &[], ""), &[200]).await;
+        self.verify("get_content", resp.as_ref(), |r| r.body == "selftest data", "what was PUT");
+        self.check("put_overwrite", ("PUT", "coll/a.txt", &[], "selftest data"), &[200, 204]).await;
+        self.check("put_no_parent", ("PUT", "none/a.txt", &[], "x"), &[409]).await;
+        let range = [("Range", "bytes=0-7")];
+        let resp = self.check("get_range", ("GET", "coll/a.txt", &range, ""), &[206]).await;
+        self.verify("get_range_content", resp.as_ref(), |r| r.body == "selftest", "the first 8 bytes");
+        self.check("get_missing", ("GET", "coll/missing.txt", &[], ""), &[404]).await;
+
+        let dest = self.client.url("coll/b.txt");
+        self.check("copy", ("COPY", "coll/a.txt", &[("Destination", dest.as_str())], ""), &[201]).await;
+        let nooverwrite = [("Destination", dest.as_str()), ("Overwrite", "F")];
+        self.check("copy_nooverwrite", ("COPY", "coll/a.txt", &nooverwrite, ""), &[412]).await;
+        let overwrite = [("Destination", dest.as_str()), ("Overwrite", "T")];
+        self.check("copy_overwrite", ("COPY", "coll/a.txt", &overwrite, ""), &[204]).await;
+        let dest = self.client.url("coll/c.txt");
+        self.check("move", ("MOVE", "coll/b.txt", &[("Destination", dest.as_str())], ""), &[201]).await;
+        self.check("move_source_gone", ("GET", "coll/b.txt", &[], ""), &[404]).await;
+        self.check("mkcol_sub", ("MKCOL", "coll/sub/", &[], ""), &[201]).await;
+        let dest = self.client.url("coll/sub2/");
+        self.check("move_coll", ("MOVE", "coll/sub/", &[("Destination", dest.as_str())], ""), &[201]).await;
+        self.check("delete", ("DELETE", "coll/c.txt", &[], ""), &[204]).await;
+        self.check("delete_gone", ("GET", "coll/c.txt", &[], ""), &[404]).await;
+        self.check("delete_coll", ("DELETE", "coll/sub2/", &[], ""), &[204]).await;
+        self.check("delete_missing", ("DELETE", "coll/c.txt", &[], ""), &[404]).await;
+    }
+
+    async fn props(&mut self) {
+        let propfind = r#"<?xml version="1.0" encoding="utf-8"?>
+            <D:propfind xmlns:D="DAV:"><D:prop><D:getcontentlength/></D:prop></D:propfind>"#;
+        let depth0 = [("Depth", "0")];
+        let resp = self.check("propfind_d0", ("PROPFIND", "coll/a.txt", &depth0, propfind), &[207]).await;
+        let length = "<D:getcontentlength>13</D:getcontentlength>";
+        self.verify("propfind_d0_length", resp.as_ref(), |r| r.body.contains(length), "getcontentlength 13");
+        let depth1 = [("Depth", "1")];
+        let resp = self.check("propfind_d1", ("PROPFIND", "coll/", &depth1, propfind), &[207]).await;
+        let href = format!("{}/coll/a.txt<", ROUTE);
+        self.verify("propfind_d1_members", resp.as_ref(), |r| r.body.contains(&href), "coll/a.txt in it");
+        self.check("propfind_invalid", ("PROPFIND", "coll/", &depth0, "<D:propfind"), &[400]).await;
+
+        let proppatch = r#"<?xml version="1.0" encoding="utf-8"?>
+            <D:propertyupdate xmlns:D="DAV:" xmlns:X="urn:selftest"><D:set><D:prop>
+            <X:color>blue</X:color></D:prop></D:set></D:propertyupdate>"#;
+        let resp = self.check("proppatch", ("PROPPATCH", "coll/a.txt", &[], proppatch), &[207]).await;
+        self.verify("proppatch_ok", resp.as_ref(), |r| r.body.contains(" 200 "), "200 OK for the property");
+        let propfind = r#"<?xml version="1.0" encoding="utf-8"?>
+            <D:propfind xmlns:D="DAV:"><D:prop><color xmlns="urn:selftest"/></D:prop></D:propfind>"#;
+        let resp = self.check("propget", ("PROPFIND", "coll/a.txt", &depth0, propfind), &[207]).await;
+        self.verify("propget_value", resp.as_ref(), |r| r.body.contains(">blue<"), "the value set");
+    }
+
+    async fn preconditions(&mut self) {
+        let resp = self.check("head_etag", ("HEAD", "coll/a.txt", &[], ""), &[200]).await;
+        self.verify("head_etag_present", resp.as_ref(), |r| !r.header("ETag").is_empty(), "an ETag");
+        let etag = resp.map(|r| r.header("ETag").to_string()).unwrap_or_default();
+        let mismatch = [("If-Match", "\"selftest\"")];
+        self.check("put_if_match_fail", ("PUT", "coll/a.txt", &mismatch, "x"), &[412]).await;
+        let matches = [("If-Match", etag.as_str())];
+        self.check("put_if_match", ("PUT", "coll/a.txt", &matches, "selftest data"), &[200, 204]).await;
+        let etag = match self.client.send("HEAD", "coll/a.txt", &[], "").await {
+            Ok(resp) => resp.header("ETag").to_string(),
+            Err(_) => etag,
+        };
+        let exists = [("If-None-Match", "*")];
+        self.check("put_if_none_match", ("PUT", "coll/a.txt", &exists, "x"), &[412]).await;
+        let unchanged = [("If-None-Match", etag.as_str())];
+        self.check("get_not_modified", ("GET", "coll/a.txt", &unchanged, ""), &[304]).await;
+        let strong = etag.trim_start_matches("W/");
+        let cond = format!("([{}])", strong);
+        let if_etag = [("If", cond.as_str())];
+        self.check("if_etag", ("PUT", "coll/a.txt", &if_etag, "selftest data"), &[200, 204]).await;
+        let etag = match self.client.send("HEAD", "coll/a.txt", &[], "").await {
+            Ok(resp) => resp.header("ETag").to_string(),
+            Err(_) => etag,
+        };
+        let cond = format!("(Not [{}])", etag.trim_start_matches("W/"));
+        self.check("if_not_etag", ("PUT", "coll/a.txt", &[("If", cond.as_str())], "x"), &[412]).await;
+        let cond = format!("<{}> ([\"selftest\"])", self.client.url("coll/a.txt"));
+        self.check("if_tagged_fail", ("PUT", "coll/a.txt", &[("If", cond.as_str())], "x"), &[412]).await;
+        self.check("if_invalid", ("PUT", "coll/a.txt", &[("If", "(<urn:x")], "x"), &[400]).await;
+    }
+
+    async fn locks(&mut self, real: bool) {
+        let lockinfo = r#"<?xml version="1.0" encoding="utf-8"?>
+            <D:lockinfo xmlns:D="DAV:"><D:lockscope><D:exclusive/></D:lockscope>
+            <D:locktype><D:write/></D:locktype><D:owner>selftest</D:owner></D:lockinfo>"#;
+        let timeout = [("Timeout", "Second-60")];
+        let resp = self.check("lock", ("LOCK", "coll/a.txt", &timeout, lockinfo), &[200]).await;
+        self.verify("lock_token", resp.as_ref(), |r| r.header("Lock-Token").starts_with('<'), "a Lock-Token");
+        let lock_token = resp.map(|r| r.header("Lock-Token").to_string()).unwrap_or_default();
+        let token_if = format!("({})", lock_token);
+        let if_token = [("If", token_if.as_str())];
+        if real {
+            self.check("put_locked", ("PUT", "coll/a.txt", &[], "x"), &[423]).await;
+            self.check("lock_conflict", ("LOCK", "coll/a.txt", &timeout, lockinfo), &[423]).await;
+            self.check("delete_locked", ("DELETE", "coll/a.txt", &[], ""), &[423]).await;
+            let bad_token = [("If", "(<urn:uuid:00000000-0000-0000-0000-000000000000>)")];
+            self.check("put_wrong_token", ("PUT", "coll/a.txt", &bad_token, "x"), &[412, 423]).await;
+        }
+        self.check("put_with_token", ("PUT", "coll/a.txt", &if_token, "selftest data"), &[200, 204]).await;
+        let refresh = [("If", token_if.as_str()), ("Timeout", "Second-60")];
+        self.check("lock_refresh", ("LOCK", "coll/a.txt", &refresh, ""), &[200]).await;
+        let unlock = [("Lock-Token", lock_token.as_str())];
+        self.check("unlock", ("UNLOCK", "coll/a.txt", &unlock, ""), &[204]).await;
+        self.check("put_unlocked", ("PUT", "coll/a.txt", &[], "selftest data"), &[200, 204]).await;
+
+        let resp = self.check("lock_new", ("LOCK", "coll/new.txt", &timeout, lockinfo), &[200, 201]).await;
+        let lock_token = resp.map(|r| r.header("Lock-Token").to_string()).unwrap_or_default();
+        self.check("lock_new_exists", ("GET", "coll/new.txt", &[], ""), &[200]).await;
+        let unlock = [("Lock-Token", lock_token.as_str())];
+        self.check("unlock_new", ("UNLOCK", "coll/new.txt", &unlock, ""), &[204]).await;
+    }
+
+    // Concurrent clients, each with a file of their own: PUT, GET,
+    // PROPFIND and DELETE, over and over.
+    async fn load(&mut self, opts: &Options) {
+        if opts.clients == 0 || opts.requests == 0 {
+            return;
+        }
+        if self.check("load_mkcol", ("MKCOL", "load/", &[], ""), &[201]).await.is_none() {
+            return;
+        }
+        let start = Instant::now();
+        let mut tasks = Vec::new();
+        for id in 0..opts.clients {
+            let client = self.client.clone();
+            let requests = opts.requests;
+            tasks.push(tokio::spawn(async move {
+                let path = format!("load/client-{}.txt", id);
+                let data = format!("selftest load data of client {}\n", id).repeat(32);
+                let mut latencies = Vec::with_capacity(requests);
+                let mut failed = 0;
+                for n in 0..requests {
+                    let (method, headers, body, status): (_, &[(&str, &str)], _, &[u16]) = match n % 4 {
+                        0 => ("PUT", &[], data.as_str(), &[201, 204]),
+                        1 => ("GET", &[], "", &[200]),
+                        2 => ("PROPFIND", &[("Depth", "0")], "", &[207]),
+                        _ => ("DELETE", &[], "", &[204]),
+                    };
+                    let t = Instant::now();
+                    match client.send(method, &path, headers, body).await {
+                        Ok(resp) if status.contains(&resp.status.as_u16()) => {},
+                        _ => failed += 1,
+                    }
+                    latencies.push(t.elapsed());
+                }
+                (latencies, failed)
+            }));
+        }
+        let mut latencies = Vec::new();
+        let mut failed = 0;
+        for task in tasks {
+            match task.await {
+                Ok((l, f)) => {
+                    latencies.extend(l);
+                    failed += f;
+                },
+                Err(_) => failed += opts.requests,
+            }
+        }
+        let elapsed = start.elapsed();
+        latencies.sort();
+        let pct = |p: usize| latencies.get(latencies.len() * p / 100).copied().unwrap_or_default();
+        let max = latencies.last().copied().unwrap_or_default();
+        let ms = |d: Duration| format!("{:.1}ms", d.as_secs_f64() * 1000.0);
+        println!(
+            "load  {} clients, {} requests in {:.2}s, {:.0} req/s, latency p50 {} p99 {} max {}, {} failed",
+            opts.clients,
+            latencies.len(),
+            elapsed.as_secs_f64(),
+            latencies.len() as f64 / elapsed.as_secs_f64().max(0.001),
+            ms(pct(50)),
+            ms(pct(99)),
+            ms(max),
+            failed,
+        );
+        let problem = format!("{} of {} requests failed", failed, latencies.len());
+        let problem = Some(problem).filter(|_| failed > 0);
+        self.report("load", problem);
+    }
+}
+
+/// Run the protocol checks and the load test against the server, built
+/// from `config()`. True if everything passed.
+pub async fn run(server: Server, opts: &Options) -> io::Result<bool> {
+    let lock_db = server
+        .config()
+        .location
+        .first()
+        .and_then(|l| l.lock_db.clone());
+
+    let listener = std::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+    let addr = listener.local_addr()?;
+    let (stop_tx, stop) = tokio::sync::oneshot::channel::<()>();
+    let http = hyper::Server::from_tcp(listener)
+        .map_err(io::Error::other)?
+        .serve(server)
+        .with_graceful_shutdown(async move {
+            let _ = stop.await;
+        });
+    let http = tokio::spawn(http);
+    println!("selftest: http://{}{}/", addr, ROUTE);
+
+    let mut suite = Suite {
+        client: Client {
+            http: hyper::Client::new(),
+            base: format!("http://{}", addr),
+        },
+        passed: 0,
+        failed: 0,
+    };
+    suite.basic().await;
+    suite.props().await;
+    suite.preconditions().await;
+    suite.locks(lock_db.is_some()).await;
+    suite.load(opts).await;
+    println!("selftest: {} passed, {} failed", suite.passed, suite.failed);
+
+    let _ = stop_tx.send(());
+    let _ = http.await;
+    if let Some(db) = lock_db {
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", db, suffix));
+        }
+    }
+    Ok(suite.failed == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_selftest() {
+        let cfg = std::env::temp_dir().join(format!("webdav-server-selftest-{}.toml", std::process::id()));
+        std::fs::write(&cfg, "[server]\nidentification = \"selftest\"\n").unwrap();
+        let config = config(cfg.to_str().unwrap());
+        std::fs::remove_file(&cfg).unwrap();
+        let config = Arc::new(config.unwrap());
+        assert_eq!(config.location.len(), 1);
+        let auth = crate::auth::Auth::new(config.clone()).unwrap();
+        let opts = Options {
+            clients:  2,
+            requests: 8,
+        };
+        assert!(run(Server::new(config, auth, None), &opts).await.unwrap());
+    }
+}
@@ -0,0 +1,320 @@
+//
+// Zero-copy GET with sendfile(2) ([server] sendfile).
+//
+// hyper writes the response, and wants the body in buffers. So a GET of
+// a whole local file, or of one range of it, gets a body of slices of a
+// buffer of zeroes that is never written to, and for every slice the
+// file, offset and length go on a queue of the connection. When the
+// connection (proxy::Conn) is asked to write bytes from that buffer, it
+// takes them from the queue and calls sendfile instead.
+//
+// hyper passes the body on as it is, in vectored writes, for HTTP/1 on
+// a socket that can do those. So the queue is only given to requests on
+// the plain listeners with HTTP/1, and the body is only replaced at the
+// very end, if nothing compressed or changed it.
+//
+// UserFs opens the file once more for this, as the user. Then the body
+// is only replaced if that was the only file opened for reading, and it
+// still has the size and mtime of the response.
+//
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::ready;
+use headers::HeaderMapExt;
+use http::{header, StatusCode};
+use hyper::body::Bytes;
+use tokio::io::Interest;
+use tokio::net::{TcpStream, UnixStream};
+
+const ZEROES_SIZE: usize = 1024 * 1024;
+
+lazy_static::lazy_static! {
+    // Only the virtual memory: the pages are not touched.
+    static ref ZEROES: &'static [u8] = Box::leak(vec![0u8; ZEROES_SIZE].into_boxed_slice());
+}
+
+// Where a slice starts in ZEROES, if it is in there.
+fn zeroes_offset(buf: &[u8]) -> Option<usize> {
+    let start = ZEROES.as_ptr() as usize;
+    let ptr = buf.as_ptr() as usize;
+    match !buf.is_empty() && ptr >= start && ptr + buf.len() <= start + ZEROES_SIZE {
+        true => Some(ptr - start),
+        false => None,
+    }
+}
+
+// Part of a file, for one slice of the body.
+struct Region {
+    file:   Arc<File>,
+    offset: u64,
+    len:    usize,
+    sent:   usize,
+}
+
+/// The regions to send on a connection, in the order hyper writes them.
+#[derive(Clone, Default)]
+pub struct Queue(Arc<Mutex<VecDeque<Region>>>);
+
+impl Queue {
+    pub fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    /// Send the bytes that are in "buf" from the file. Only call this if
+    /// `is_zeroes(buf)`.
+    pub fn poll_send<S: Socket>(
+        &self,
+        cx: &mut Context<'_>,
+        sock: &S,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>>
+    {
+        let at = zeroes_offset(buf).unwrap_or(0);
+        let mut queue = self.0.lock().unwrap();
+        let region = match queue.front_mut() {
+            Some(region) if region.sent == at && at + buf.len() <= region.len => region,
+            _ => {
+                let err = io::Error::new(io::ErrorKind::InvalidData, "sendfile: out of order");
+                return Poll::Ready(Err(err));
+            },
+        };
+        let offset = region.offset + region.sent as u64;
+        let n = ready!(sock.poll_sendfile(cx, &region.file, offset, buf.len()))?;
+        region.sent += n;
+        if region.sent == region.len {
+            queue.pop_front();
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Does this come from a body of `response`?
+pub fn is_zeroes(buf: &[u8]) -> bool {
+    zeroes_offset(buf).is_some()
+}
+
+/// The files that UserFs opened for reading during a request.
+#[derive(Clone, Default)]
+pub struct Opened(Arc<Mutex<(usize, Option<File>)>>);
+
+impl Opened {
+    /// A file was opened; "file" is the second fd, if that worked.
+    pub fn add(&self, file: Option<File>) {
+        let mut opened = self.0.lock().unwrap();
+        opened.0 += 1;
+        opened.1 = file;
+    }
+
+    fn take(&self) -> Option<File> {
+        let mut opened = self.0.lock().unwrap();
+        match opened.0 {
+            1 => opened.1.take(),
+            _ => None,
+        }
+    }
+}
+
+/// A GET that may be sent with sendfile.
+#[derive(Clone)]
+pub struct Get {
+    queue:  Queue,
+    opened: Opened,
+}
+
+// The body to send, in the extensions of the response until `response`.
+#[derive(Clone)]
+struct Body {
+    queue:  Queue,
+    file:   Arc<File>,
+    offset: u64,
+    len:    u64,
+}
+
+impl Get {
+    pub fn new(queue: Queue) -> Get {
+        Get {
+            queue,
+            opened: Opened::default(),
+        }
+    }
+
+    /// For UserFs::set_sendfile.
+    pub fn opened(&self) -> Opened {
+        self.opened.clone()
+    }
+
+    /// After the handler: if it is the file that was opened, mark the
+    /// response for `response`.
+    pub fn prepare(&self, resp: &mut http::Response<hyper::Body>) {
+        let file = match self.opened.take() {
+            Some(file) => file,
+            None => return,
+        };
+        let (offset, len, size) = match range(resp) {
+            Some(range) => range,
+            None => return,
+        };
+        let meta = match file.metadata() {
+            Ok(meta) if meta.is_file() => meta,
+            _ => return,
+        };
+        let modified = resp.headers().typed_get::<headers::LastModified>().map(SystemTime::from);
+        let modified = modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs() as i64);
+        if meta.size() != size || len == 0 || modified != Some(meta.mtime()) {
+            return;
+        }
+        resp.extensions_mut().insert(Body {
+            queue: self.queue.clone(),
+            file: Arc::new(file),
+            offset,
+            len,
+        });
+    }
+}
+
+// Offset, length and size of the file, for a 200 or a 206 with one range.
+fn range(resp: &http::Response<hyper::Body>) -> Option<(u64, u64, u64)> {
+    let headers = resp.headers();
+    if headers.contains_key(header::CONTENT_ENCODING) {
+        return None;
+    }
+    let len: u64 = headers.get(header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()?;
+    match resp.status() {
+        StatusCode::OK => Some((0, len, len)),
+        StatusCode::PARTIAL_CONTENT => {
+            let range = headers.get(header::CONTENT_RANGE)?.to_str().ok()?.strip_prefix("bytes ")?;
+            let (range, size) = range.split_once('/')?;
+            let (start, end) = range.split_once('-')?;
+            let (start, end): (u64, u64) = (start.parse().ok()?, end.parse().ok()?);
+            match end.checked_sub(start)?.checked_add(1)? == len {
+                true => Some((start, len, size.parse().ok()?)),
+                false => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Replace the body of a response that `Get::prepare` marked, if it still
+/// is the same.
+pub fn response(mut resp: http::Response<hyper::Body>) -> http::Response<hyper::Body> {
+    let body = match resp.extensions_mut().remove::<Body>() {
+        Some(body) => body,
+        None => return resp,
+    };
+    match range(&resp) {
+        Some((offset, len, _)) if offset == body.offset && len == body.len => {},
+        _ => return resp,
+    }
+    let (parts, _) = resp.into_parts();
+    let stream = futures::stream::unfold((body, 0), |(body, done)| async move {
+        if done == body.len {
+            return None;
+        }
+        let len = std::cmp::min(body.len - done, ZEROES_SIZE as u64) as usize;
+        body.queue.0.lock().unwrap().push_back(Region {
+            file: body.file.clone(),
+            offset: body.offset + done,
+            len,
+            sent: 0,
+        });
+        let data = Bytes::from_static(&ZEROES[..len]);
+        Some((Ok::<_, io::Error>(data), (body, done + len as u64)))
+    });
+    http::Response::from_parts(parts, hyper::Body::wrap_stream(stream))
+}
+
+/// A socket that sendfile can write to.
+pub trait Socket {
+    fn poll_sendfile(
+        &self,
+        cx: &mut Context<'_>,
+        file: &File,
+        offset: u64,
+        len: usize,
+    ) -> Poll<io::Result<usize>>;
+}
+
+impl Socket for TcpStream {
+    fn poll_sendfile(
+        &self,
+        cx: &mut Context<'_>,
+        file: &File,
+        offset: u64,
+        len: usize,
+    ) -> Poll<io::Result<usize>>
+    {
+        loop {
+            ready!(self.poll_write_ready(cx))?;
+            match self.try_io(Interest::WRITABLE, || sendfile(self.as_raw_fd(), file, offset, len)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+}
+
+impl Socket for UnixStream {
+    fn poll_sendfile(
+        &self,
+        cx: &mut Context<'_>,
+        file: &File,
+        offset: u64,
+        len: usize,
+    ) -> Poll<io::Result<usize>>
+    {
+        loop {
+            ready!(self.poll_write_ready(cx))?;
+            match self.try_io(Interest::WRITABLE, || sendfile(self.as_raw_fd(), file, offset, len)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+}
+
+fn sendfile(sock: RawFd, file: &File, offset: u64, len: usize) -> io::Result<usize> {
+    let mut off = offset as libc::off_t;
+    let n = unsafe { libc::sendfile(sock, file.as_raw_fd(), &mut off, len) };
+    match n {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        0 => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "sendfile: file got shorter")),
+        n => Ok(n as usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_range() {
+        let resp = |status: u16, headers: &[(&str, &str)]| {
+            let mut resp = http::Response::builder().status(status);
+            for (name, value) in headers {
+                resp = resp.header(*name, *value);
+            }
+            resp.body(hyper::Body::empty()).unwrap()
+        };
+        assert_eq!(range(&resp(200, &[("Content-Length", "10")])), Some((0, 10, 10)));
+        let partial = [("Content-Length", "5"), ("Content-Range", "bytes 3-7/10")];
+        assert_eq!(range(&resp(206, &partial)), Some((3, 5, 10)));
+        let wrong = [("Content-Length", "4"), ("Content-Range", "bytes 3-7/10")];
+        assert_eq!(range(&resp(206, &wrong)), None);
+        let gzip = [("Content-Length", "10"), ("Content-Encoding", "gzip")];
+        assert_eq!(range(&resp(200, &gzip)), None);
+        assert_eq!(range(&resp(206, &[("Content-Type", "multipart/byteranges")])), None);
+        assert_eq!(range(&resp(304, &[("Content-Length", "10")])), None);
+
+        assert!(is_zeroes(&ZEROES[10..20]));
+        assert!(!is_zeroes(&[0u8; 10]));
+        assert!(!is_zeroes(&ZEROES[10..10]));
+    }
+}
@@ -0,0 +1,2361 @@
+//
+// The server: routing, authentication, the uid of the account, and the
+// handlers of the locations. The binary hands it the connections of its
+// listeners; a program that embeds it can do the same (see DavService).
+//
+use std::collections::HashMap;
+use std::convert::{Infallible, TryFrom};
+use std::future::Future;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::future::FutureExt;
+use futures::stream::StreamExt;
+use http::status::StatusCode;
+use hyper::server::conn::AddrStream;
+use tokio::sync::watch;
+use tracing::Instrument;
+use webdav_handler::{davpath::DavPath, DavConfig, DavHandler, DavMethod, DavMethodSet};
+use webdav_handler::{fakels::FakeLs, fs::DavFileSystem, ls::DavLockSystem};
+
+use crate::config::{
+    AcctType, Auth, AuthScheme, CaseCollisions, CaseInsensitive, Encrypt, EtagScheme, Finder, Fsync,
+    Handler, ListingOrder, Location, NonUtf8Names, OnNotfound, PropfindInfinity, Quota, Symlinks,
+    Template,
+};
+use crate::aliasfs::AliasFs;
+use crate::atomicput::AtomicPutFs;
+use crate::lockpolicy::PolicyLs;
+use crate::overlayfs::OverlayFs;
+use crate::rootfs::RootFs;
+use crate::router::MatchedRoute;
+use crate::softquota::QuotaFs;
+use crate::trashfs::TrashFs;
+use crate::uploadlimit::LimitFs;
+use crate::userfs::{NewFiles, UserFs};
+use crate::versionfs::VersionFs;
+use crate::*;
+
+/// The webdav server, with its configuration. A clone is cheap, and
+/// serves the same locations.
+#[derive(Clone)]
+pub struct Server {
+    dh:         DavHandler,
+    auth:       auth::Auth,
+    acme:       Option<Arc<acme::Acme>>,
+    alt_svc:    Option<String>,
+    listen:     Option<Arc<config::Listen>>,
+    config:     Arc<config::Config>,
+    // the current config and auth, replaced on reload.
+    live:       Arc<RwLock<(Arc<config::Config>, auth::Auth)>>,
+    middleware: Arc<middleware::Registry>,
+}
+
+type HttpResult = Result<hyper::Response<hyper::Body>, io::Error>;
+type HttpRequest = http::Request<hyper::Body>;
+type HttpResponse = hyper::Response<accesslog::Body>;
+
+// Server implementation.
+impl Server {
+    // Constructor.
+    #[doc(hidden)]
+    pub fn new(config: Arc<config::Config>, auth: auth::Auth, acme: Option<Arc<acme::Acme>>) -> Self {
+        cache::cached::set_pwcache(&config.unix);
+
+        // mostly empty handler.
+        let ls = FakeLs::new() as Box<dyn DavLockSystem>;
+        let dh = DavHandler::builder().locksystem(ls).build_handler();
+
+        // Advertise HTTP/3, if enabled.
+        let alt_svc = config
+            .server
+            .quic_listen
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut a| a.next())
+            .map(|a| format!("h3=\":{}\"", a.port()));
+
+        let live = Arc::new(RwLock::new((config.clone(), auth.clone())));
+        Server {
+            dh,
+            auth,
+            acme,
+            alt_svc,
+            listen: None,
+            config,
+            live,
+            middleware: Arc::new(middleware::Registry::default()),
+        }
+    }
+
+    // The same server, with the hooks of these middleware.
+    #[doc(hidden)]
+    pub fn with_middleware(mut self, registry: middleware::Registry) -> io::Result<Server> {
+        middleware::check(&self.config, &registry).map_err(io::Error::other)?;
+        self.middleware = Arc::new(registry);
+        Ok(self)
+    }
+
+    /// The service for the requests of a connection from this address.
+    pub fn service(&self, remote_addr: SocketAddr) -> DavService {
+        DavService {
+            conn: self.new_connection(remote_addr),
+            server: self.clone(),
+            remote_addr,
+        }
+    }
+
+    /// Use a new configuration from now on, for example one that was
+    /// re-read from its file. It has the same sections as they are
+    /// returned, as those need a restart.
+    pub fn reload(&self, config: config::Config) -> io::Result<Vec<&'static str>> {
+        let mut config = config;
+        let old = self.live.read().unwrap().0.clone();
+        let skipped = config::keep_restart_only(&old, &mut config);
+        middleware::check(&config, &self.middleware).map_err(io::Error::other)?;
+        let config = Arc::new(config);
+        let auth = self.auth.reload(config.clone())?;
+        // accounts may have changed too.
+        cache::cached::set_pwcache(&config.unix);
+        cache::cached::flush_accounts();
+        *self.live.write().unwrap() = (config, auth);
+        Ok(skipped)
+    }
+
+    // The current config and auth.
+    #[doc(hidden)]
+    pub fn live(&self) -> (Arc<config::Config>, auth::Auth) {
+        self.live.read().unwrap().clone()
+    }
+
+    // The config the server started with.
+    #[doc(hidden)]
+    pub fn config(&self) -> &config::Config {
+        &self.config
+    }
+
+    // A copy of the server for the listeners of a [[listen]] block.
+    #[doc(hidden)]
+    pub fn with_listen(&self, listen: &config::Listen) -> Server {
+        let mut server = self.clone();
+        server.listen = Some(Arc::new(listen.clone()));
+        server
+    }
+
+    // Is this authentication scheme allowed on this listener.
+    #[doc(hidden)]
+    pub fn scheme_allowed(&self, scheme: AuthScheme) -> bool {
+        match self.listen.as_ref().and_then(|l| l.auth_schemes.as_ref()) {
+            Some(schemes) => schemes.contains(&scheme),
+            None => true,
+        }
+    }
+
+    // check user account.
+    async fn acct<'a>(
+        &'a self,
+        location: &Location,
+        auth_user: Option<&'a String>,
+        user_param: Option<&'a str>,
+    ) -> Result<Option<Arc<unixuser::User>>, StatusCode>
+    {
+        // Get username - if any.
+        let user = match auth_user.map(|u| u.as_str()).or(user_param) {
+            Some(u) => u,
+            None => return Ok(None),
+        };
+
+        // If account is not set, fine.
+        let acct_type = location
+            .accounts
+            .acct_type
+            .as_ref()
+            .or(self.config.accounts.acct_type.as_ref());
+        match acct_type {
+            Some(AcctType::Unix) => {},
+            Some(AcctType::Sql(section)) => return self.acct_sql(section, user).await.map(Some),
+            Some(AcctType::Exec) => {
+                return match crate::execauth::account(user) {
+                    Some(pwd) => Ok(Some(pwd)),
+                    None => {
+                        debug!("acct: exec: no account for {}", user);
+                        Err(StatusCode::UNAUTHORIZED)
+                    },
+                };
+            },
+            None => return Ok(None),
+        };
+
+        // check if user exists.
+        let pwd = match cache::cached::unixuser(user, self.config.unix.aux_groups).await {
+            Ok(pwd) => pwd,
+            Err(_) => {
+                debug!("acct: unix: user {} not found", user);
+                return Err(StatusCode::UNAUTHORIZED);
+            },
+        };
+
+        // check minimum uid
+        if let Some(min_uid) = self.config.unix.min_uid {
+            if pwd.uid < min_uid {
+                debug!("acct: {}: uid {} too low (<{})", pwd.name, pwd.uid, min_uid);
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+        Ok(Some(pwd))
+    }
+
+    // an account from [sql.<section>].
+    async fn acct_sql(&self, section: &str, user: &str) -> Result<Arc<unixuser::User>, StatusCode> {
+        let cfg = self.config.sql.get(section).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        match cache::cached::sqluser(section, cfg, user).await {
+            Ok(pwd) => Ok(pwd),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                debug!("acct: sql.{}: user {} not found", section, user);
+                Err(StatusCode::UNAUTHORIZED)
+            },
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                debug!("acct: sql.{}: {}: {}", section, user, e);
+                Err(StatusCode::FORBIDDEN)
+            },
+            Err(e) => {
+                error!("acct: sql.{}: {}: {}", section, user, e);
+                Err(StatusCode::SERVICE_UNAVAILABLE)
+            },
+        }
+    }
+
+    // Did the request come in over TLS, here or at a proxy in front of a
+    // listener with tls = true.
+    fn is_tls(&self, req: &HttpRequest) -> bool {
+        req.extensions().get::<tls::Https>().is_some() || self.listen.as_ref().map(|l| l.tls).unwrap_or(false)
+    }
+
+    // is this location read-only, for everyone or for this user.
+    fn read_only(&self, location: &Location, template: Option<&Template>, user: Option<&str>) -> bool {
+        let mut users = self.config.accounts.read_only_users.iter().chain(&location.accounts.read_only_users);
+        let read_only = template.and_then(|t| t.read_only).unwrap_or(location.read_only);
+        read_only || user.map(|u| users.any(|r| r == u)).unwrap_or(false)
+    }
+
+    // return a new response::Builder with the Server: header set.
+    fn response_builder(&self) -> http::response::Builder {
+        let mut builder = hyper::Response::builder();
+        let id = self.config.server.identification.as_deref().unwrap_or("webdav-server-rs");
+        if !id.is_empty() {
+            builder = builder.header("Server", id);
+        }
+        if let Some(ref alt_svc) = self.alt_svc {
+            builder = builder.header("Alt-Svc", alt_svc.as_str());
+        }
+        builder
+    }
+
+    // Strict-Transport-Security and the tls-headers, on https responses.
+    fn tls_headers(&self, headers: &mut http::HeaderMap<http::header::HeaderValue>) {
+        let cfg = &self.config.server;
+        if let Some(hsts) = cfg.hsts.as_ref().and_then(|v| v.parse().ok()) {
+            headers.insert(http::header::STRICT_TRANSPORT_SECURITY, hsts);
+        }
+        for (name, value) in &cfg.tls_headers {
+            let name = http::header::HeaderName::from_bytes(name.as_bytes());
+            if let (Ok(name), Ok(value)) = (name, value.parse()) {
+                headers.insert(name, value);
+            }
+        }
+    }
+
+    // 301 to the same host and path, on https.
+    async fn redirect_https(&self, req: &HttpRequest, port: u16) -> HttpResult {
+        let host = match req.headers().get(http::header::HOST).and_then(|h| h.to_str().ok()) {
+            Some(host) => host,
+            None => return self.error(StatusCode::BAD_REQUEST).await,
+        };
+        // without the port, but an IPv6 address keeps its brackets.
+        let host = match host.find(']') {
+            Some(end) if host.starts_with('[') => &host[..=end],
+            _ => host.split(':').next().unwrap_or(host),
+        };
+        let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let location = match port {
+            443 => format!("https://{}{}", host, path),
+            port => format!("https://{}:{}{}", host, port, path),
+        };
+        debug!("route: redirect to {}", location);
+        let resp = self
+            .response_builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header(http::header::LOCATION, location)
+            .header(http::header::CONTENT_LENGTH, "0")
+            .body(hyper::Body::empty())
+            .unwrap();
+        Ok(resp)
+    }
+
+    // Set Server: webdav-server-rs header.
+    fn set_server_header(&self, headers: &mut http::HeaderMap<http::header::HeaderValue>) {
+        let id = self.config.server.identification.as_deref().unwrap_or("webdav-server-rs");
+        if !id.is_empty() {
+            headers.insert("server", id.parse().unwrap());
+        }
+        if let Some(ref alt_svc) = self.alt_svc {
+            headers.insert("alt-svc", alt_svc.parse().unwrap());
+        }
+    }
+
+    // count a new connection, with the current [limits].
+    #[doc(hidden)]
+    pub fn new_connection(&self, remote_addr: SocketAddr) -> Arc<limits::Conn> {
+        let config = self.live.read().unwrap().0.clone();
+        limits::connection(&config.limits, remote_addr.ip())
+    }
+
+    // handle a request, with the current config.
+    #[doc(hidden)]
+    pub async fn route(&self, mut req: HttpRequest, remote_ip: SocketAddr) -> io::Result<HttpResponse> {
+        let mut server = self.clone();
+        let (config, auth) = self.live.read().unwrap().clone();
+        server.config = config;
+        server.auth = auth;
+
+        // Health checks on the normal listeners. Not logged or counted.
+        let path = req.uri().path();
+        let health_method = matches!(*req.method(), http::Method::GET | http::Method::HEAD);
+        if server.config.admin.health_on_listen && health_method && health::is_health_path(path) {
+            let (status, body) = health::check(path, &server.config, &server.auth).await;
+            let resp = hyper::Response::builder()
+                .status(status)
+                .header("Content-Type", "text/plain")
+                .body(body.into())
+                .unwrap();
+            return Ok(accesslog::response(resp, None));
+        }
+
+        // Count the request, and how long it took.
+        let entry = accesslog::Entry::new(&mut req, remote_ip.ip());
+        let trusted = &server.config.server.trusted_proxies;
+        let client_ip = forwarded::client_addr(trusted, req.headers(), remote_ip);
+        let audit = auditlog::Entry::new(&mut req, client_ip.ip());
+        let set_cookie = session::SetCookie::default();
+        req.extensions_mut().insert(set_cookie.clone());
+        let start = std::time::Instant::now();
+        let method = match DavMethod::try_from(req.method()) {
+            Ok(_) => req.method().to_string(),
+            Err(_) => "other".to_string(),
+        };
+        let traceparent = req.headers().get("traceparent").and_then(|h| h.to_str().ok()).unwrap_or("");
+        let span = tracing::info_span!(
+            "request",
+            http.request.method = %req.method(),
+            url.path = %req.uri().path(),
+            http.response.status_code = tracing::field::Empty,
+            enduser.id = tracing::field::Empty,
+            traceparent
+        );
+        let request = logger::Request::new(&req);
+        // For an error page, if the client wants one.
+        let error_format = errorpage::format(req.headers());
+        let head = req.method() == http::Method::HEAD;
+        let uri_path = error_format.map(|_| req.uri().path().to_string()).unwrap_or_default();
+        let origin = req.headers().get("origin").cloned();
+        let tls = server.is_tls(&req);
+        let hooks = server.middleware.server(&server.config);
+        let mut hook_req = match hooks.is_empty() {
+            true => None,
+            false => Some(middleware::Request {
+                method:    req.method().clone(),
+                path:      req.uri().path().to_string(),
+                user:      None,
+                remote_ip: remote_ip.ip(),
+            }),
+        };
+        let route = server.route_timeout(req, remote_ip).instrument(span.clone());
+        let (res, user) = request.scope(async move { (route.await, logger::user()) }).await;
+        let errors = &server.config.errors;
+        let res = res.map(|resp| errorpage::response(errors, error_format, head, &uri_path, resp));
+        let res = res.map(|mut resp| {
+            cors::headers(&server.config.cors, origin.as_ref(), resp.headers_mut());
+            if let Some(cookie) = set_cookie.header(tls).and_then(|c| c.parse().ok()) {
+                resp.headers_mut().append(http::header::SET_COOKIE, cookie);
+            }
+            if tls {
+                server.tls_headers(resp.headers_mut());
+            }
+            resp
+        });
+        let res = match hook_req.as_mut() {
+            Some(hook_req) => {
+                hook_req.user = user.clone();
+                res.map(|resp| {
+                    let (mut parts, body) = resp.into_parts();
+                    for hook in &hooks {
+                        hook.post_response(hook_req, &mut parts);
+                    }
+                    http::Response::from_parts(parts, body)
+                })
+            },
+            None => res,
+        };
+        if let (Some(audit), Ok(resp)) = (audit, res.as_ref()) {
+            audit.log(resp.status().as_u16(), user.as_deref());
+        }
+        if let Ok(ref resp) = res {
+            metrics::request(&method, resp.status().as_u16(), start.elapsed());
+            span.record("http.response.status_code", resp.status().as_u16() as u64);
+        }
+        res.map(|resp| accesslog::response(sendfile::response(resp), entry))
+    }
+
+    // route_request, with the request timeouts.
+    async fn route_timeout(&self, req: HttpRequest, remote_ip: SocketAddr) -> HttpResult {
+        // Timeouts. The whole request, and reading the body.
+        let cfg = &self.config.server;
+        let timeout = match *req.method() {
+            http::Method::PUT | http::Method::PATCH | http::Method::GET => cfg.transfer_timeout,
+            _ => cfg.request_timeout,
+        };
+        let (_, idle_timeout) = conn_timeouts(cfg);
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let req = match idle_timeout {
+            Some(idle) => body_timeout(req, idle, timed_out.clone()),
+            None => req,
+        };
+        let version = req.version();
+        let res = match timeout.filter(|&t| t > 0) {
+            Some(secs) => {
+                let route = self.route_request(req, remote_ip);
+                match tokio::time::timeout(Duration::from_secs(secs), route).await {
+                    Ok(res) => res,
+                    Err(_) => {
+                        debug!("route: {}: request timeout", remote_ip);
+                        return self.close_error(StatusCode::REQUEST_TIMEOUT, version).await;
+                    },
+                }
+            },
+            None => self.route_request(req, remote_ip).await,
+        };
+        if timed_out.load(Ordering::SeqCst) {
+            debug!("route: {}: timeout reading the request body", remote_ip);
+            return self.close_error(StatusCode::REQUEST_TIMEOUT, version).await;
+        }
+        res
+    }
+
+    // A request of the server to itself, see crossroute.
+    pub(crate) fn subrequest(
+        &self,
+        req: HttpRequest,
+        remote_ip: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = HttpResult> + Send + '_>>
+    {
+        Box::pin(self.route_request(req, remote_ip))
+    }
+
+    async fn route_request(&self, req: HttpRequest, remote_ip: SocketAddr) -> HttpResult {
+        // Behind a trusted proxy? Then get the real client address.
+        let trusted = &self.config.server.trusted_proxies;
+        let remote_ip = if trusted.is_empty() {
+            remote_ip
+        } else {
+            forwarded::client_addr(trusted, req.headers(), remote_ip)
+        };
+        if let Some(client) = req.extensions().get::<accesslog::Client>() {
+            client.set_addr(remote_ip.ip());
+        }
+
+        // ACME HTTP-01 challenge?
+        if let Some(ref acme) = self.acme {
+            if let Some(token) = req.uri().path().strip_prefix(acme::HTTP01_PREFIX) {
+                if let Some(key_auth) = acme.http01(token) {
+                    let resp = self
+                        .response_builder()
+                        .header("Content-Type", "application/octet-stream")
+                        .body(key_auth.into())
+                        .unwrap();
+                    return Ok(resp);
+                }
+            }
+        }
+
+        // A listener that only sends clients to https.
+        if let Some(listen) = self.listen.as_ref().filter(|l| l.redirect_https) {
+            return self.redirect_https(&req, listen.https_port.unwrap_or(443)).await;
+        }
+
+        // Too many connections or requests from this address?
+        let limits = &self.config.limits;
+        if let Some(conn) = req.extensions().get::<Arc<limits::Conn>>() {
+            if conn.over_limit() {
+                debug!("route: {}: too many connections", remote_ip.ip());
+                return self.close_error(StatusCode::SERVICE_UNAVAILABLE, req.version()).await;
+            }
+        }
+        // (a subrequest is part of a request that already has a slot).
+        let subrequest = req.extensions().get::<auth::Subrequest>().is_some();
+        let _request_slot = match limits::request_ip(limits, remote_ip.ip()) {
+            _ if subrequest => None,
+            Some(slot) => slot,
+            None => {
+                debug!("route: {}: too many requests", remote_ip.ip());
+                return self.error(StatusCode::TOO_MANY_REQUESTS).await;
+            },
+        };
+
+        // Faster than the request-rate of [limits]?
+        if !subrequest {
+            let (rate, burst) = (limits.request_rate, limits.request_burst);
+            if let Err(retry) = ratelimit::check("", remote_ip.ip(), rate, burst) {
+                debug!("route: {}: over the request-rate", remote_ip.ip());
+                return self.rate_limited(retry).await;
+            }
+        }
+
+        // CORS preflight from a browser.
+        let cors = &self.config.cors;
+        if let Some(resp) = cors::preflight(cors, req.method(), req.headers(), self.response_builder()) {
+            return Ok(resp);
+        }
+
+        // Back from logging in at an OpenID Connect provider?
+        if let Some(res) = self.auth.oidc_callback(&req).await {
+            let (url, cookies) = match res {
+                Ok(res) => res,
+                Err(status) => return self.error(status).await,
+            };
+            let mut resp = self.response_builder().status(StatusCode::FOUND).header("Location", url);
+            for cookie in &cookies {
+                resp = resp.header("Set-Cookie", cookie.as_str());
+            }
+            return Ok(resp.body(hyper::Body::empty()).unwrap());
+        }
+
+        // A share link? (not in the request for one).
+        if let Some(prefix) = share::prefix(&self.config.shares) {
+            let link = req.extensions().get::<share::Link>().is_some();
+            if !link && share::is_link(prefix, req.uri().path()) {
+                let tls = self.is_tls(&req);
+                return match share::handle(self, &self.auth, prefix, req, remote_ip, tls).await {
+                    Ok(mut resp) => {
+                        self.set_server_header(resp.headers_mut());
+                        Ok(resp)
+                    },
+                    Err(StatusCode::UNAUTHORIZED) => {
+                        let mut resp = self.error(StatusCode::UNAUTHORIZED).await?;
+                        let challenge = "Basic realm=\"share\", charset=\"UTF-8\"".parse().unwrap();
+                        resp.headers_mut().insert("WWW-Authenticate", challenge);
+                        Ok(resp)
+                    },
+                    Err(status) => self.error(status).await,
+                };
+            }
+        }
+
+        // The status URL of a COPY or MOVE in the background?
+        if let Some(prefix) = jobs::prefix(&self.config.jobs) {
+            if req.uri().path().starts_with(prefix) {
+                let cfg = self.config.jobs.as_ref().unwrap();
+                let (mut parts, body) = jobs::handle(cfg, req.method(), req.uri().path()).into_parts();
+                self.set_server_header(&mut parts.headers);
+                return Ok(http::Response::from_parts(parts, body.into()));
+            }
+        }
+
+        // The page to change a PAM password?
+        #[cfg(feature = "pam")]
+        if self.config.pam.change_password.as_deref() == Some(req.uri().path()) {
+            let mut resp = passwd::handle(&self.auth, req, remote_ip).await;
+            self.set_server_header(resp.headers_mut());
+            return Ok(resp);
+        }
+
+        // Windows WebClient paths with backslashes.
+        let windows = self.config.locations().any(|(_, l)| l.windows);
+        let req = match windows && winclient::is_webclient(req.headers()) {
+            true => winclient::translate(req),
+            false => req,
+        };
+
+        // Middleware that rewrites or answers the request.
+        let mut req = req;
+        for hook in self.middleware.server(&self.config) {
+            if let Some(resp) = hook.pre_dispatch(&mut req) {
+                return Ok(resp);
+            }
+        }
+
+        // Get the URI path.
+        let davpath = match DavPath::from_uri(req.uri()) {
+            Ok(p) => p,
+            Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
+        };
+        let path = davpath.as_bytes();
+
+        // Get the method. DeltaV, SEARCH, MKCALENDAR and POST are routed like a WebDAV method.
+        let dav_method = DavMethod::try_from(req.method()).ok();
+        let extension = || {
+            deltav::dav_method(req.method())
+                .or_else(|| search::dav_method(req.method()))
+                .or_else(|| pim::dav_method(req.method()))
+                .or_else(|| tus::dav_method(req.method()))
+        };
+        let method = match dav_method.or_else(extension) {
+            Some(m) => m,
+            None => return self.error(http::StatusCode::METHOD_NOT_ALLOWED).await,
+        };
+
+        // Read-only server or listener?
+        let read_only = self.config.server.read_only.unwrap_or(false) ||
+            self.listen.as_ref().map(|l| l.read_only).unwrap_or(false);
+        if read_only && !DavMethodSet::WEBDAV_RO.contains(method) {
+            debug!("route: {:?} on a read-only server or listener", method);
+            return self.error(StatusCode::FORBIDDEN).await;
+        }
+
+        // Larger than max-request-body?
+        let max_body = self.config.server.max_request_body.map(|s| s.0);
+        if let (Some(max), Some(len)) = (max_body, uploadlimit::content_length(&req)) {
+            if len > max {
+                debug!("route: Content-Length {} larger than max-request-body", len);
+                return self.error(StatusCode::PAYLOAD_TOO_LARGE).await;
+            }
+        }
+
+        // Virtual host?
+        let vhost = request_host(&req).and_then(|host| self.config.vhost(&host));
+        let (router, locations) = match vhost {
+            Some(vhost) => (&vhost.router, &vhost.location),
+            None => (&self.config.router, &self.config.location),
+        };
+
+        // Request is stored here.
+        let mut reqdata = Some(req);
+        let mut got_match = false;
+
+        // Match routes to one or more locations.
+        for route in router.matches(path, method, &["user", "path"]).drain(..) {
+            got_match = true;
+
+            // Take the request from the option.
+            let req = reqdata.take().unwrap();
+
+            // if we might continue, store a clone of the request for the next round.
+            let location = &locations[*route.data];
+            if let Some(OnNotfound::Continue) = location.on_notfound {
+                reqdata.get_or_insert(clone_httpreq(&req));
+            }
+
+            // handle request.
+            let res = self
+                .handle(req, method, path, route, location, remote_ip)
+                .await?;
+
+            // no on_notfound? then this is final.
+            if reqdata.is_none() || res.status() != StatusCode::NOT_FOUND {
+                return Ok(res);
+            }
+        }
+
+        if !got_match {
+            debug!("route: no matching route for {:?}", davpath);
+            // The root of the server, asked for by the Windows WebClient.
+            if let Some(req) = reqdata.as_ref().filter(|_| windows) {
+                let builder = self.response_builder();
+                if let Some(resp) = winclient::root(builder, method, path, req.headers(), locations) {
+                    return Ok(resp);
+                }
+            }
+        }
+
+        self.error(StatusCode::NOT_FOUND).await
+    }
+
+    // handle a request.
+    async fn handle<'a, 't: 'a, 'p: 'a>(
+        &'a self,
+        req: HttpRequest,
+        method: DavMethod,
+        path: &'a [u8],
+        route: MatchedRoute<'t, 'p, usize>,
+        location: &'a Location,
+        remote_ip: SocketAddr,
+    ) -> HttpResult
+    {
+        // See if we matched a :user parameter
+        // If so, it must be valid UTF-8, or we return NOT_FOUND.
+        let user_param = match route.params[0].as_ref() {
+            Some(p) => {
+                match p.as_str() {
+                    Some(p) => Some(p),
+                    None => {
+                        debug!("handle: invalid utf-8 in :user part of path");
+                        return self.error(StatusCode::NOT_FOUND).await;
+                    },
+                }
+            },
+            None => None,
+        };
+
+        // A share link, to a location without shares?
+        if req.extensions().get::<share::Link>().is_some() && !location.shares {
+            debug!("handle: share link to {:?}, which has no shares", location.route);
+            return self.error(StatusCode::NOT_FOUND).await;
+        }
+
+        // Is the client address allowed here?
+        if !cidr::allowed(&location.allow_from, &location.deny_from, remote_ip.ip()) {
+            debug!("handle: {}: not allowed in {:?}", remote_ip.ip(), location.route);
+            return self.error(StatusCode::FORBIDDEN).await;
+        }
+
+        // And is its country?
+        if !location.allow_countries.is_empty() || !location.deny_countries.is_empty() {
+            let reader = self.config.geoip.reader.as_ref();
+            let country = reader.and_then(|r| r.country(remote_ip.ip()));
+            if !geoip::allowed(&location.allow_countries, &location.deny_countries, country.as_deref()) {
+                let country = country.as_deref().unwrap_or("unknown");
+                debug!("handle: {} ({}): not allowed in {:?}", remote_ip.ip(), country, location.route);
+                return self.error(StatusCode::FORBIDDEN).await;
+            }
+        }
+
+        // Faster than the request-rate of the location?
+        if req.extensions().get::<auth::Subrequest>().is_none() {
+            let (rate, burst) = (location.request_rate, location.request_burst);
+            let scope = location.route.join(" ");
+            if let Err(retry) = ratelimit::check(&scope, remote_ip.ip(), rate, burst) {
+                debug!("handle: {}: over the request-rate of {}", remote_ip.ip(), scope);
+                return self.rate_limited(retry).await;
+            }
+        }
+
+        // Only over TLS? Then not even the password is checked.
+        let tls = self.is_tls(&req);
+        if location.require_tls && !tls {
+            debug!("handle: {:?} on a require-tls location, not over TLS", method);
+            return self.error(StatusCode::FORBIDDEN).await;
+        }
+
+        // Do authentication if needed.
+        let auth_hdr = auth::has_credentials(&req);
+        let do_auth = match location.auth {
+            Some(Auth::True) => true,
+            Some(Auth::Write) => !DavMethodSet::WEBDAV_RO.contains(method) || auth_hdr,
+            Some(Auth::False) => false,
+            Some(Auth::Opportunistic) | None => auth_hdr,
+        };
+        let auth_user = if do_auth {
+            // can we authenticate on this listener at all.
+            let scheme = match req.extensions().get::<auth::ClientCertUser>() {
+                Some(_) => AuthScheme::ClientCert,
+                None => self.auth.scheme(location),
+            };
+            if !self.scheme_allowed(scheme) {
+                debug!("handle: auth scheme {:?} not allowed on this listener", scheme);
+                return self.error(StatusCode::FORBIDDEN).await;
+            }
+            let span = tracing::info_span!("auth");
+            let user = match self.auth.auth(&req, location, remote_ip).instrument(span).await {
+                Ok(user) => user,
+                Err(status) => return self.auth_error(status, location, &req).await,
+            };
+            // if there was a :user in the route, return error if it does not match.
+            if user_param.map(|u| u != user).unwrap_or(false) {
+                debug!("handle: auth user and :user mismatch");
+                return self.auth_error(StatusCode::UNAUTHORIZED, location, &req).await;
+            }
+            tracing::Span::current().record("enduser.id", user.as_str());
+            if let Some(client) = req.extensions().get::<accesslog::Client>() {
+                client.set_user(&user);
+            }
+            logger::set_user(&user);
+            Some(user)
+        } else {
+            None
+        };
+
+        // The middleware of the location.
+        let hooks = self.middleware.location(&self.config, location);
+        let hook_req = middleware::Request {
+            method:    req.method().clone(),
+            path:      String::from_utf8_lossy(path).into_owned(),
+            user:      auth_user.clone(),
+            remote_ip: remote_ip.ip(),
+        };
+        for hook in &hooks {
+            if let Err(status) = hook.auth(&hook_req, req.headers()) {
+                debug!("handle: {}: refused by middleware", status);
+                return match status {
+                    StatusCode::UNAUTHORIZED => self.auth_error(status, location, &req).await,
+                    _ => self.error(status).await,
+                };
+            }
+        }
+
+        // Too many connections or requests by this user?
+        let limits = &self.config.limits;
+        let subrequest = req.extensions().get::<auth::Subrequest>().is_some();
+        let _request_slot = match auth_user {
+            Some(_) if subrequest => None,
+            Some(ref user) => {
+                let conn = req.extensions().get::<Arc<limits::Conn>>();
+                if !conn.map(|c| c.set_user(limits, user)).unwrap_or(true) {
+                    debug!("handle: {}: too many connections", user);
+                    return self.close_error(StatusCode::SERVICE_UNAVAILABLE, req.version()).await;
+                }
+                match limits::request_user(limits, user) {
+                    Some(slot) => slot,
+                    None => {
+                        debug!("handle: {}: too many requests", user);
+                        return self.error(StatusCode::TOO_MANY_REQUESTS).await;
+                    },
+                }
+            },
+            None => None,
+        };
+
+        // An app password can be limited to reading, and to some paths.
+        if let Some(app) = auth_user.as_ref().and_then(|_| auth::app_password(&req)) {
+            let dest = req
+                .headers()
+                .get("destination")
+                .and_then(|d| d.to_str().ok())
+                .and_then(|d| d.parse::<http::Uri>().ok())
+                .and_then(|u| DavPath::from_uri(&u).ok());
+            let paths_ok = app.allows(path) && dest.map(|d| app.allows(d.as_bytes())).unwrap_or(true);
+            if !paths_ok || (app.read_only && !DavMethodSet::WEBDAV_RO.contains(method)) {
+                debug!("handle: {:?} not allowed with app password {}", method, app.id);
+                return self.error(StatusCode::FORBIDDEN).await;
+            }
+        }
+
+        // Larger than max-file-size?
+        let max_file_size = uploadlimit::max_file_size(&self.config.accounts, location, auth_user.as_deref());
+        if let (Some(max), Some(len)) = (max_file_size, uploadlimit::content_length(&req)) {
+            if matches!(method, DavMethod::Put | DavMethod::Patch) && len > max {
+                debug!("handle: {:?} of {} bytes larger than max-file-size", method, len);
+                return self.error(StatusCode::PAYLOAD_TOO_LARGE).await;
+            }
+        }
+
+        // PAM session that lasts as long as this request.
+        #[cfg(feature = "pam")]
+        let _pam_session = match auth_user {
+            Some(ref user) => {
+                match self.auth.pam_session(location, user, remote_ip).await {
+                    Ok(session) => session,
+                    Err(status) => return self.error(status).await,
+                }
+            },
+            None => None,
+        };
+
+        // Now see if we want to do a account lookup, for uid/gid/homedir.
+        let pwd = match self.acct(location, auth_user.as_ref(), user_param).await {
+            Ok(pwd) => pwd,
+            Err(status) => return self.auth_error(status, location, &req).await,
+        };
+
+        // Not authenticated, and there is a guest account? Run as that.
+        let guest = match (&auth_user, &location.guest) {
+            (None, Some(name)) => {
+                match cache::cached::unixuser(name, self.config.unix.aux_groups).await {
+                    Ok(pwd) => Some(pwd),
+                    Err(e) => {
+                        error!("handle: guest account {}: {}", name, e);
+                        return self.error(StatusCode::INTERNAL_SERVER_ERROR).await;
+                    },
+                }
+            },
+            _ => None,
+        };
+
+        // The template for the user, or for one of their groups.
+        let template = template(location, auth_user.as_deref(), pwd.as_deref());
+        if template.map(|t| t.require_tls).unwrap_or(false) && !tls {
+            debug!("handle: {:?} by a require-tls user, not over TLS", method);
+            return self.error(StatusCode::FORBIDDEN).await;
+        }
+
+        // Read-only location or user? A COPY only writes at the Destination:,
+        // so that is checked below, once it is known where that is.
+        let read_only = self.read_only(location, template, auth_user.as_deref());
+        if !DavMethodSet::WEBDAV_RO.contains(method) && method != DavMethod::Copy && read_only {
+            debug!("handle: {:?} on a read-only location or by a read-only user", method);
+            return self.error(StatusCode::FORBIDDEN).await;
+        }
+
+        // Expand "~" in the directory.
+        let user = auth_user.as_deref().or(user_param);
+        let pam_env = auth_user.as_deref().and_then(auth::pam_env);
+        let directory = template.and_then(|t| t.directory.as_deref()).unwrap_or(&location.directory);
+        let dir = match expand_directory(directory, user, pwd.as_ref(), pam_env.as_deref()) {
+            Ok(d) => d,
+            Err(_) => return self.error(StatusCode::NOT_FOUND).await,
+        };
+
+        // Create the directory if it is not there yet.
+        if location.create_directory {
+            match mkhome::create(location, &dir, pwd.as_deref(), &self.config.server) {
+                Ok(true) => info!("created {}", dir),
+                Ok(false) => {},
+                Err(e) => {
+                    error!("handle: create {}: {}", dir, e);
+                    return self.error(StatusCode::INTERNAL_SERVER_ERROR).await;
+                },
+            }
+        }
+
+        // If :path matched, we can calculate the prefix.
+        // If it didn't, the entire path _is_ the prefix.
+        let prefix = match route.params[1].as_ref() {
+            Some(p) => {
+                let start = p.start().saturating_sub(1);
+                &path[..start]
+            },
+            None => path,
+        };
+        let prefix = match std::str::from_utf8(prefix) {
+            Ok(p) => p.to_string(),
+            Err(_) => {
+                debug!("handle: prefix is non-UTF8");
+                return self.error(StatusCode::NOT_FOUND).await;
+            },
+        };
+
+        // The request path and the Destination: relative to the location.
+        let rel = |p: &[u8]| {
+            match &p[prefix.len()..] {
+                b"" => "/".to_string(),
+                p => String::from_utf8_lossy(p).into_owned(),
+            }
+        };
+        let dest = req
+            .headers()
+            .get("destination")
+            .and_then(|d| d.to_str().ok())
+            .and_then(|d| d.parse::<http::Uri>().ok())
+            .and_then(|u| DavPath::from_uri(&u).ok())
+            .filter(|d| d.as_bytes().starts_with(prefix.as_bytes()))
+            .map(|d| rel(d.as_bytes()));
+
+        // The root of an alias can not be deleted, moved or overwritten,
+        // only what is in it. Neither can the root of the location, that
+        // would include the aliases.
+        let is_alias = |p: &str| {
+            let p = p.trim_matches('/');
+            let mut aliases = location.alias.iter();
+            !location.alias.is_empty() && (p.is_empty() || aliases.any(|a| a.path.trim_matches('/') == p))
+        };
+        let dest_is_alias = dest.as_deref().map(is_alias).unwrap_or(false);
+        if (matches!(method, DavMethod::Delete | DavMethod::Move) && is_alias(&rel(path))) || dest_is_alias {
+            debug!("handle: {:?} on the root of an alias", method);
+            return self.error(StatusCode::FORBIDDEN).await;
+        }
+
+        // Access control lists.
+        if acl::enabled(location) {
+            let gids: Vec<u32> = guest
+                .as_ref()
+                .or(pwd.as_ref())
+                .iter()
+                .flat_map(|p| std::iter::once(p.gid).chain(p.groups.iter().copied()))
+                .collect();
+            let who = acl::Principal {
+                user: auth_user.as_deref(),
+                gids: &gids,
+            };
+            // Depth: 0 and 1 stop at the members, anything else goes all the way
+            // down. SEARCH and REPORT have their scope in the body.
+            let depth = req.headers().get("depth").and_then(|v| v.to_str().ok()).map(|d| d.trim());
+            let recursive = match method {
+                DavMethod::Delete | DavMethod::Move => true,
+                DavMethod::Copy | DavMethod::PropFind | DavMethod::Lock => {
+                    matches!(req.method().as_str(), "SEARCH" | "REPORT") ||
+                        !matches!(depth, Some("0") | Some("1"))
+                },
+                _ => false,
+            };
+            if !acl::check(location, &dir, method, &rel(path), dest.as_deref(), recursive, &who) {
+                debug!("handle: {:?} {}: denied by acl", method, String::from_utf8_lossy(path));
+                return self.error(StatusCode::FORBIDDEN).await;
+            }
+        }
+
+        // COPY or MOVE in the background?
+        let job = jobs::job(&self.config, method, req.headers(), auth_user.as_deref(), path);
+
+        // COPY or MOVE to another location.
+        if matches!(method, DavMethod::Copy | DavMethod::Move) && dest.is_none() {
+            if let Some(to) = crossroute::destination(&req, &prefix) {
+                let sub = auth_user.as_ref().map(|user| {
+                    auth::Subrequest {
+                        user:  user.clone(),
+                        scope: self.auth.session_scope(location),
+                    }
+                });
+                let resp = match job {
+                    Some(job) => {
+                        let (server, path) = (self.clone(), path.to_vec());
+                        let mut head = crossroute::head(&req);
+                        head.extensions_mut().insert(job.progress());
+                        let run = async move {
+                            crossroute::copy_move(&server, &head, method, &path, to, sub, remote_ip).await
+                        };
+                        match job.start(run) {
+                            Ok(resp) => resp,
+                            Err(status) => return self.error(status).await,
+                        }
+                    },
+                    None => crossroute::copy_move(self, &req, method, path, to, sub, remote_ip).await,
+                };
+                let (mut parts, body) = resp.into_parts();
+                self.set_server_header(&mut parts.headers);
+                return Ok(http::Response::from_parts(parts, body.into()));
+            }
+        }
+
+        // COPY within a read-only location. (Another location checks its own.)
+        if method == DavMethod::Copy && read_only {
+            debug!("handle: COPY to a read-only location or by a read-only user");
+            return self.error(StatusCode::FORBIDDEN).await;
+        }
+
+        // Get User-Agent for user-agent specific modes.
+        let user_agent = req
+            .headers()
+            .get("user-agent")
+            .and_then(|s| s.to_str().ok())
+            .unwrap_or("");
+
+        // macOS optimizations?
+        let macos = user_agent.contains("WebDAVFS/") && user_agent.contains("Darwin");
+
+        // Case insensitivity wanted?
+        let case_insensitive = match location.case_insensitive {
+            Some(CaseInsensitive::True) => true,
+            Some(CaseInsensitive::Ms) => user_agent.contains("Microsoft"),
+            Some(CaseInsensitive::MsMacos) => user_agent.contains("Microsoft") || macos,
+            Some(CaseInsensitive::False) | None => false,
+        };
+        let case_rename = dest.as_deref().map(|d| casefs::differs_in_case(&rel(path), d)).unwrap_or(false);
+        if case_insensitive && case_rename && matches!(method, DavMethod::Copy | DavMethod::Move) {
+            debug!("handle: {:?} to the same name in another case", method);
+            return self.error(StatusCode::FORBIDDEN).await;
+        }
+
+        // Word, Excel and the WebClient.
+        let ms_client = user_agent.contains("Microsoft");
+
+        // Get the filesystem.
+        let run_as = match guest {
+            Some(ref guest) => Some(guest),
+            None if location.setuid => pwd.as_ref(),
+            None => None,
+        };
+        let auth_ugid = run_as.map(|p| (p.uid, p.gid, p.groups.as_slice()));
+        let symlinks = location.symlinks.unwrap_or(Symlinks::Follow);
+        // Mode and group of new files and directories.
+        let octal = |mode: &Option<String>| mode.as_ref().and_then(|m| u32::from_str_radix(m, 8).ok());
+        let new_files = NewFiles {
+            file_mode: octal(&location.file_mode),
+            dir_mode:  octal(&location.dir_mode),
+            umask:     octal(&location.umask),
+            gid:       location.file_gid,
+        };
+        // Uploads to a temporary file, and fsync.
+        let atomic_put = |fs: Box<UserFs>| {
+            match location.atomic_put || !matches!(location.fsync, None | Some(Fsync::None)) {
+                true => {
+                    let length = req.headers().get(http::header::CONTENT_LENGTH);
+                    let length = length.and_then(|h| h.to_str().ok()).and_then(|l| l.parse().ok());
+                    let expected = length.filter(|_| method == DavMethod::Put);
+                    let preserve = location.preserve_mode;
+                    let (atomic, fsync) = (location.atomic_put, location.fsync);
+                    AtomicPutFs::new(fs, atomic, fsync, preserve, expected) as Box<dyn DavFileSystem>
+                },
+                false => fs as Box<dyn DavFileSystem>,
+            }
+        };
+        // Downloads with sendfile, on a plain HTTP/1 connection.
+        let sendfile = match (method, &location.handler, location.encrypt) {
+            (DavMethod::Get, Handler::Filesystem, None) if self.config.server.sendfile.unwrap_or(false) => {
+                req.extensions().get::<sendfile::Queue>().cloned().map(sendfile::Get::new)
+            },
+            _ => None,
+        };
+        // the local filesystem, for xattrs and such.
+        let mut local_fs = None;
+        let fs = match location.handler {
+            Handler::Virtroot => {
+                let auth_user = auth_user.as_ref().map(String::to_owned);
+                RootFs::new(&dir, auth_user, auth_ugid) as Box<dyn DavFileSystem>
+            },
+            Handler::Filesystem | Handler::Caldav | Handler::Carddav => {
+                let mut userfs = UserFs::new(&dir, auth_ugid, true, case_insensitive, macos);
+                userfs.set_symlinks(symlinks);
+                userfs.set_confine(location.confine);
+                userfs.set_new_files(new_files);
+                if let Some(ref sendfile) = sendfile {
+                    userfs.set_sendfile(sendfile.opened());
+                }
+                #[cfg(feature = "quota")]
+                if let Some(quota) = location.quota {
+                    userfs.set_quota(quota);
+                }
+                local_fs = Some(userfs.clone());
+                let mut fs = atomic_put(userfs);
+                if let Some(ref base) = location.overlay_base {
+                    let base = match expand_directory(base, user, pwd.as_ref(), pam_env.as_deref()) {
+                        Ok(d) => d,
+                        Err(status) => return self.error(status).await,
+                    };
+                    let mut lower = UserFs::new(base, auth_ugid, true, case_insensitive, macos);
+                    lower.set_symlinks(symlinks);
+                    lower.set_confine(location.confine);
+                    fs = OverlayFs::new(lower, fs) as Box<dyn DavFileSystem>;
+                }
+                if let Some(Quota::Limit(max)) = location.quota {
+                    let usage = softquota::usage(std::path::Path::new(&dir), location.watch).await;
+                    fs = QuotaFs::new(fs, usage, max) as Box<dyn DavFileSystem>;
+                }
+                if location.alias.is_empty() {
+                    fs
+                } else {
+                    let mut aliases = Vec::new();
+                    for alias in &location.alias {
+                        let env = pam_env.as_deref();
+                        let adir = match expand_directory(&alias.directory, user, pwd.as_ref(), env) {
+                            Ok(d) => d,
+                            Err(status) => return self.error(status).await,
+                        };
+                        let name = alias.path.trim_matches('/').to_string();
+                        let mut fs = UserFs::new(adir, auth_ugid, true, case_insensitive, macos);
+                        fs.set_symlinks(symlinks);
+                        fs.set_confine(location.confine);
+                        fs.set_new_files(new_files);
+                        aliases.push((name, atomic_put(fs)));
+                    }
+                    AliasFs::new(fs, aliases) as Box<dyn DavFileSystem>
+                }
+            },
+            #[cfg(feature = "s3")]
+            Handler::S3 => {
+                let name = location.s3.as_deref().unwrap_or_default();
+                match self.config.s3.get(name) {
+                    Some(cfg) => {
+                        let client = s3::S3Client::shared(name, cfg);
+                        s3fs::S3Fs::new(client, &dir) as Box<dyn DavFileSystem>
+                    },
+                    None => return self.error(StatusCode::INTERNAL_SERVER_ERROR).await,
+                }
+            },
+            Handler::Mem => {
+                let max_size = location.mem_size.map(|s| s * 1024 * 1024);
+                memfs::get(&dir, max_size)
+            },
+            // validate() does not allow this.
+            #[cfg(not(feature = "s3"))]
+            Handler::S3 => return self.error(StatusCode::INTERNAL_SERVER_ERROR).await,
+        };
+
+        // Uploads to local disk first, then to the backend.
+        let fs = match (location.spool, method) {
+            (true, DavMethod::Put) => {
+                let max_memory = location.spool_memory.map(|s| s.0);
+                let dir = location.spool_dir.as_deref();
+                spoolfs::SpoolFs::new(fs, dir, max_memory, location.spool_retries) as Box<dyn DavFileSystem>
+            },
+            _ => fs,
+        };
+
+        // Bigger reads for downloads from local files.
+        let fs = match (method, &location.handler) {
+            (DavMethod::Get, Handler::Filesystem) => {
+                readahead::ReadAheadFs::new(fs) as Box<dyn DavFileSystem>
+            },
+            _ => fs,
+        };
+
+        // Properties and locks in a database, and content ETags, are keyed
+        // by this. A prefix is only unique per bucket.
+        let db_root = match location.handler {
+            Handler::S3 => format!("s3.{}:{}", location.s3.as_deref().unwrap_or_default(), dir),
+            _ => dir.clone(),
+        };
+        // A bucket has no inodes, and with etag = "mtime-size" they are not
+        // to be trusted.
+        #[cfg(feature = "sqlite")]
+        let use_inode = match location.handler {
+            Handler::S3 => false,
+            _ => location.overlay_base.is_none() && location.etag != Some(EtagScheme::MtimeSize),
+        };
+
+        // Dead properties in a database.
+        #[cfg(feature = "sqlite")]
+        let fs = match location.dead_props {
+            Some(ref db) => {
+                let store = match deadprops::store(db) {
+                    Ok(store) => store,
+                    Err(e) => {
+                        error!("handle: dead-props {}: {}", db, e);
+                        return self.error(StatusCode::INTERNAL_SERVER_ERROR).await;
+                    },
+                };
+                let limits = deadprops::Limits {
+                    count:      location.props_max_count,
+                    size:       location.props_max_size.map(|s| s.0),
+                    user_count: location.props_user_count,
+                    user_size:  location.props_user_size.map(|s| s.0),
+                };
+                let owner = auth_user.as_deref();
+                let pfs = deadprops::PropFs::new(fs, store, &db_root, use_inode, limits, owner);
+                pfs as Box<dyn DavFileSystem>
+            },
+            None => fs,
+        };
+
+        // Extended attributes as properties.
+        let fs = match local_fs {
+            Some(ref local) if location.xattr_props => {
+                xattrfs::XattrFs::new(fs, local.clone()) as Box<dyn DavFileSystem>
+            },
+            _ => fs,
+        };
+
+        // Encryption at rest.
+        let fs = match location.encrypt {
+            Some(encrypt) => {
+                let master = location.master_key.as_ref().map(|k| k.0.as_slice());
+                let keys = match (encrypt, user, auth::basic_password(&req)) {
+                    (Encrypt::Master, _, _) => cryptfs::Keys::from_master(master.unwrap_or_default(), user),
+                    (Encrypt::Password, Some(user), Some(pass)) => {
+                        cryptfs::Keys::from_password(master, user, &pass)
+                    },
+                    (Encrypt::Password, _, _) => {
+                        debug!("handle: encrypt = password, but no password");
+                        return self.error(StatusCode::FORBIDDEN).await;
+                    },
+                };
+                cryptfs::CryptFs::new(fs, keys, location.encrypt_names) as Box<dyn DavFileSystem>
+            },
+            None => fs,
+        };
+
+        // Another kind of ETag.
+        let fs = match location.etag {
+            Some(scheme) if scheme != EtagScheme::Inode => {
+                etag::EtagFs::new(fs, scheme, &db_root) as Box<dyn DavFileSystem>
+            },
+            _ => fs,
+        };
+
+        // Checksums of the contents, as properties.
+        let mut checksums = None;
+        let fs = match location.checksums {
+            Some(ref types) if !types.is_empty() => {
+                let cfs = checksum::ChecksumFs::new(fs, types);
+                checksums = Some(cfs.clone());
+                cfs as Box<dyn DavFileSystem>
+            },
+            _ => fs,
+        };
+
+        // Virus scanning of uploads.
+        let scan = match self.config.antivirus {
+            Some(ref av) if location.antivirus && matches!(method, DavMethod::Put | DavMethod::Patch) => {
+                Some(antivirus::Scan::new(av, auth_user.as_deref()))
+            },
+            _ => None,
+        };
+        let fs = match scan {
+            Some(ref scan) => antivirus::ScanFs::new(fs, scan.clone()) as Box<dyn DavFileSystem>,
+            None => fs,
+        };
+
+        // Deleted and overwritten files go to the trash.
+        let fs = match location.trash_dir {
+            Some(ref name) => {
+                let retention = location.trash_retention;
+                TrashFs::new(fs, name, location.trash_visible, retention, &dir) as Box<dyn DavFileSystem>
+            },
+            None => fs,
+        };
+
+        // Old versions of files that are overwritten.
+        let mut versions = None;
+        let fs = match location.versions_dir {
+            Some(ref name) => {
+                let (max, days) = (location.versions_max, location.versions_days);
+                let visible = location.versions_visible;
+                let vfs = VersionFs::new(fs, name, visible, location.deltav, max, days);
+                versions = Some(vfs.clone());
+                vfs as Box<dyn DavFileSystem>
+            },
+            None => fs,
+        };
+
+        // A journal of the changes, for sync-collection reports.
+        #[cfg(feature = "sqlite")]
+        let mut syncfs = None;
+        #[cfg(feature = "sqlite")]
+        let fs = match location.sync_db {
+            Some(ref db) => {
+                let journal = match syncdb::open(db) {
+                    Ok(journal) => journal,
+                    Err(e) => {
+                        error!("handle: sync-db {}: {}", db, e);
+                        return self.error(StatusCode::INTERNAL_SERVER_ERROR).await;
+                    },
+                };
+                let retention = location.sync_retention.unwrap_or(30);
+                let sfs = syncdb::SyncFs::new(fs, journal, &db_root, retention);
+                if location.watch {
+                    sfs.watch(std::path::Path::new(&dir));
+                }
+                syncfs = Some(sfs.clone());
+                sfs as Box<dyn DavFileSystem>
+            },
+            None => fs,
+        };
+
+        // A full-text index, for SEARCH.
+        #[allow(unused_mut)]
+        let mut contains: Option<search::Contains> = None;
+        #[cfg(feature = "sqlite")]
+        let fs = match location.fulltext_index {
+            Some(ref db) => {
+                let index = match fulltext::open(db) {
+                    Ok(index) => index,
+                    Err(e) => {
+                        error!("handle: fulltext-index {}: {}", db, e);
+                        return self.error(StatusCode::INTERNAL_SERVER_ERROR).await;
+                    },
+                };
+                let max_size = location.fulltext_max.map(|s| s.0).unwrap_or(10 * 1024 * 1024);
+                contains = Some(index.searcher(&db_root));
+                let ifs = fulltext::IndexFs::new(fs, index, &db_root, max_size);
+                if location.watch {
+                    ifs.watch(std::path::Path::new(&dir));
+                }
+                ifs as Box<dyn DavFileSystem>
+            },
+            None => fs,
+        };
+
+        // Names on disk that are not UTF-8.
+        let fs = match location.non_utf8_names {
+            Some(NonUtf8Names::Raw) | None => fs,
+            Some(policy) => {
+                let charset = location.charset.clone();
+                namefs::NameFs::new(fs, policy, charset) as Box<dyn DavFileSystem>
+            },
+        };
+
+        // Hidden and denied files.
+        let fs = match location.hide_rules {
+            Some(ref rules) => {
+                // PROPFIND, and REPORT and SEARCH, which are routed as one.
+                let listing = matches!(method, DavMethod::PropFind | DavMethod::Get | DavMethod::Head);
+                hidefs::HideFs::new(fs, rules.clone(), listing) as Box<dyn DavFileSystem>
+            },
+            None => fs,
+        };
+
+        // The order of the entries in a PROPFIND.
+        let sort = location.listing_order.is_some() || location.dirs_first == Some(true);
+        let fs = match sort && method == DavMethod::PropFind {
+            true => {
+                let order = location.listing_order.unwrap_or(ListingOrder::Bytes);
+                let dirs_first = location.dirs_first.unwrap_or(false);
+                sortfs::SortFs::new(fs, order, dirs_first) as Box<dyn DavFileSystem>
+            },
+            false => fs,
+        };
+
+        // ._name and .DS_Store files from macOS.
+        let fs = match location.finder {
+            Some(Finder::Discard) | Some(Finder::Xattr) => {
+                let store = memfs::get(&format!("finder:{}", db_root), Some(64 * 1024 * 1024));
+                let listing = matches!(method, DavMethod::PropFind | DavMethod::Get | DavMethod::Head);
+                let xattr = local_fs.clone().filter(|_| location.finder == Some(Finder::Xattr));
+                finder::FinderFs::new(fs, store, xattr, listing) as Box<dyn DavFileSystem>
+            },
+            Some(Finder::Off) | None => fs,
+        };
+
+        // Case-insensitive lookups where the filesystem does not do them
+        // itself, and no new names that are there in another case.
+        let lookup = case_insensitive && matches!(location.handler, Handler::Mem | Handler::S3);
+        let unique = match location.case_collisions {
+            Some(collisions) => collisions == CaseCollisions::Deny,
+            None => !matches!(location.case_insensitive, None | Some(CaseInsensitive::False)),
+        };
+        let fs = match lookup || unique {
+            true => casefs::CaseFs::new(fs, lookup, unique) as Box<dyn DavFileSystem>,
+            false => fs,
+        };
+
+        // Write once: nothing is overwritten or deleted.
+        let fs = match location.worm {
+            true => wormfs::WormFs::new(fs, location.worm_retention) as Box<dyn DavFileSystem>,
+            false => fs,
+        };
+
+        // Upload limits, checked while the body streams in.
+        let max_body = self.config.server.max_request_body.map(|s| s.0);
+        let fs = match (max_file_size, max_body) {
+            (None, None) => fs,
+            _ => LimitFs::new(fs, max_file_size, max_body) as Box<dyn DavFileSystem>,
+        };
+
+        // Bandwidth limits, shared by all requests of the user.
+        let rate = |l: &Location, d| {
+            let rate = |a: &config::Accounts| {
+                match d {
+                    bandwidth::Direction::Upload => a.upload_rate,
+                    bandwidth::Direction::Download => a.download_rate,
+                }
+            };
+            let who = auth_user.clone().unwrap_or_else(|| remote_ip.ip().to_string());
+            rate(&l.accounts)
+                .or_else(|| rate(&self.config.accounts))
+                .map(|r| bandwidth::bucket(d, &who, r.0))
+        };
+        let upload = rate(location, bandwidth::Direction::Upload);
+        let download = rate(location, bandwidth::Direction::Download);
+        let sendfile = sendfile.filter(|_| download.is_none());
+        let fs = match (upload, download) {
+            (None, None) => fs,
+            (upload, download) => bandwidth::RateFs::new(fs, upload, download) as Box<dyn DavFileSystem>,
+        };
+
+        // The pre-write hooks of the middleware.
+        let fs = match hooks.is_empty() {
+            true => fs,
+            false => middleware::MiddlewareFs::new(fs, hook_req, hooks) as Box<dyn DavFileSystem>,
+        };
+
+        // Usage counters per user, for the admin API.
+        let sendfile = sendfile.filter(|_| auth_user.is_none() || !usage::enabled());
+        let fs = match auth_user {
+            Some(ref user) if usage::enabled() => {
+                usage::UsageFs::new(fs, usage::request(user)) as Box<dyn DavFileSystem>
+            },
+            _ => fs,
+        };
+
+        // PROPFIND with Depth: infinity, if allowed, and its limits.
+        let infinity = method == DavMethod::PropFind && depthlimit::is_infinity(req.headers());
+        if infinity && location.infinity == Some(PropfindInfinity::Deny) {
+            debug!("handle: PROPFIND with Depth: infinity");
+            let resp = report::condition(StatusCode::FORBIDDEN, "propfind-finite-depth");
+            let (mut parts, body) = resp.into_parts();
+            self.set_server_header(&mut parts.headers);
+            return Ok(http::Response::from_parts(parts, body.into()));
+        }
+        let allow_infinity = location.infinity == Some(PropfindInfinity::Allow);
+        let depth_limits = location.max_depth.is_some() || location.max_resources.is_some();
+        let mut truncated = None;
+        let fs = match dav_path(req.uri(), &prefix) {
+            Ok(ref davpath) if infinity && allow_infinity && depth_limits => {
+                let dfs = depthlimit::DepthFs::new(fs, davpath, location.max_depth, location.max_resources);
+                truncated = Some((dfs.truncated(), req.uri().path().to_string()));
+                dfs as Box<dyn DavFileSystem>
+            },
+            _ => fs,
+        };
+
+        // RFC 8144 Prefer: return=minimal and depth-noroot.
+        let prefer = match method {
+            DavMethod::PropFind => Some(prefer::Prefer::new(req.headers())),
+            _ => None,
+        };
+
+        // Spans around the filesystem operations, for the OTLP exporter.
+        let fs = match otlp::enabled() {
+            true => tracefs::TraceFs::new(fs) as Box<dyn DavFileSystem>,
+            false => fs,
+        };
+
+        // Locks, and the [locks] policy.
+        let locks = &self.config.locks;
+        if method == DavMethod::Lock && auth_user.is_none() && !locks.anonymous.unwrap_or(true) {
+            debug!("handle: LOCK without authentication");
+            return self.error(StatusCode::FORBIDDEN).await;
+        }
+        let mut ls: Option<Box<dyn DavLockSystem>> = None;
+        #[cfg(feature = "sqlite")]
+        if let Some(ref db) = location.lock_db {
+            match lockdb::open(db) {
+                Ok(db) => ls = Some(db.locksystem(&db_root)),
+                Err(e) => {
+                    error!("handle: lock-db {}: {}", db, e);
+                    return self.error(StatusCode::INTERNAL_SERVER_ERROR).await;
+                },
+            }
+        }
+        if locks.is_set() {
+            let inner = ls.unwrap_or_else(|| FakeLs::new() as Box<dyn DavLockSystem>);
+            ls = Some(PolicyLs::new(inner, locks) as Box<dyn DavLockSystem>);
+        }
+        if !locks.require_router.matches(path, method, &[]).is_empty() {
+            let davpath = dav_path(req.uri(), &prefix);
+            let tokens = match req.headers().get("If").and_then(|h| h.to_str().ok()) {
+                Some(hdr) => lockpolicy::if_tokens(hdr),
+                None => Vec::new(),
+            };
+            let held = match (ls.as_ref(), davpath) {
+                (Some(ls), Ok(p)) => lockpolicy::holds_lock(&**ls, &p, auth_user.as_deref(), &tokens),
+                _ => false,
+            };
+            if !held {
+                debug!("handle: require-lock: {} without a lock", req.method());
+                return self.error(StatusCode::LOCKED).await;
+            }
+        }
+
+        // The If: header, evaluated here, see ifheader.rs.
+        let req = match ifheader::lists(req.headers()) {
+            Some(Some(lists)) => {
+                let davpath = match dav_path(req.uri(), &prefix) {
+                    Ok(davpath) => davpath,
+                    Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
+                };
+                let real_ls = ls.as_deref().filter(|_| location.lock_db.is_some());
+                if !ifheader::evaluate(&lists, &davpath, &prefix, &*fs, real_ls).await {
+                    debug!("handle: If: precondition failed");
+                    return self.error(StatusCode::PRECONDITION_FAILED).await;
+                }
+                let mut req = req;
+                req.headers_mut().remove("If");
+                req.headers_mut().insert("If", ifheader::rewrite(&lists).parse().unwrap());
+                req
+            },
+            Some(None) => {
+                debug!("handle: If: invalid header");
+                return self.error(StatusCode::BAD_REQUEST).await;
+            },
+            None => req,
+        };
+
+        // Webhooks, sent if the request succeeds. For a PUT or PATCH, the
+        // size is that of the file afterwards.
+        let webhook_event = webhook::event(method)
+            .filter(|&event| webhook::wanted(&self.config, &location.webhooks, event))
+            .map(|event| {
+                webhook::Event {
+                    event,
+                    user: auth_user.clone(),
+                    path: String::from_utf8_lossy(path).into_owned(),
+                    destination: dest.as_ref().map(|d| format!("{}{}", prefix, d)),
+                    size: None,
+                }
+            });
+        let webhook_stat = match webhook_event {
+            Some(ref e) if e.event == config::WebhookEvent::Put => {
+                let davpath = dav_path(req.uri(), &prefix);
+                davpath.ok().map(|p| (fs.clone(), p))
+            },
+            _ => None,
+        };
+
+        // Build a handler.
+        let methods = match guest {
+            Some(_) => location.guest_methods.unwrap_or(DavMethodSet::WEBDAV_RO),
+            None => {
+                location
+                    .methods
+                    .unwrap_or(DavMethodSet::from_vec(vec!["GET", "HEAD"]).unwrap())
+            },
+        };
+        let methods = davclass::methods(location, methods);
+        let hide_symlinks = location.hide_symlinks.unwrap_or(true);
+
+        // Calendars and address books: PROPFIND, MKCALENDAR and MKCOL, and the checks on PUT.
+        let pim_kind = pim::kind(location.handler);
+        #[allow(unused_mut)]
+        let mut pim_access = pim::Access {
+            writable:   methods.contains(DavMethod::Put),
+            sync_token: None,
+        };
+        #[cfg(feature = "sqlite")]
+        if pim_kind.is_some() && method == DavMethod::PropFind {
+            pim_access.sync_token = syncfs.as_ref().and_then(|sfs| sfs.token());
+        }
+        let other = pim_kind.map(|k| k.mkcol) != Some(req.method().as_str());
+        if pim::dav_method(req.method()).is_some() && (other || !methods.contains(method)) {
+            return self.error(StatusCode::METHOD_NOT_ALLOWED).await;
+        }
+        let pim_path = match pim_kind {
+            Some(_) => {
+                match dav_path(req.uri(), &prefix) {
+                    Ok(p) => Some(p),
+                    Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
+                }
+            },
+            None => None,
+        };
+        let req = match (pim_kind, pim_path.as_ref()) {
+            (Some(kind), Some(davpath)) if methods.contains(method) => {
+                match pim::handle(kind, req, &*fs, davpath, &prefix, &pim_access).await {
+                    Ok(resp) => {
+                        let (mut parts, body) = resp.into_parts();
+                        self.set_server_header(&mut parts.headers);
+                        let resp = http::Response::from_parts(parts, body.into());
+                        let resp = match truncated {
+                            Some((truncated, href)) => depthlimit::response(resp, truncated, href),
+                            None => resp,
+                        };
+                        return Ok(match prefer {
+                            Some(prefer) => prefer::response(resp, prefer),
+                            None => resp,
+                        });
+                    },
+                    Err(req) => req,
+                }
+            },
+            _ => req,
+        };
+
+        // REPORT, and the DeltaV methods on top of the file versions.
+        if deltav::dav_method(req.method()).is_some() {
+            let is_report = req.method().as_str() == "REPORT";
+            // the usage report, from the accounting of a quota kept by the server.
+            let usage = match location.quota {
+                Some(Quota::Limit(_)) if matches!(location.handler, Handler::Filesystem) => {
+                    Some(softquota::usage(std::path::Path::new(&dir), location.watch).await)
+                },
+                _ => None,
+            };
+            let reports =
+                location.sync_db.is_some() || pim_kind.is_some() || location.shares || usage.is_some();
+            let enabled = location.deltav || (is_report && reports);
+            if !enabled || !methods.contains(method) {
+                return self.error(StatusCode::METHOD_NOT_ALLOWED).await;
+            }
+            let davpath = dav_path(req.uri(), &prefix);
+            let davpath = match davpath {
+                Ok(p) => p,
+                Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
+            };
+            let versions = versions.filter(|_| location.deltav);
+            // share links are made by their owner, who must be able to write
+            // for an upload-only one.
+            let share_owner = auth_user.as_ref().filter(|_| location.shares).map(|user| {
+                let app_read_only = auth::app_password(&req).map(|a| a.read_only).unwrap_or(false);
+                share::Owner {
+                    user:      user.clone(),
+                    scope:     self.auth.session_scope(location),
+                    may_write: !app_read_only && !self.read_only(location, template, Some(user)),
+                }
+            });
+            let resp = if is_report {
+                // only on the resource itself, but for the calendar queries
+                // and the usage of the collections in a collection.
+                let usage_depth = |depth| depth == 1 && usage.is_some();
+                let depth = match pim::depth(req.headers(), 0) {
+                    Some(depth) if depth == 0 || pim_kind.is_some() || usage_depth(depth) => depth,
+                    _ => return self.error(StatusCode::BAD_REQUEST).await,
+                };
+                match report::parse(req).await {
+                    Err(resp) => resp,
+                    Ok(root) if pim_kind.map(|k| pim::is_report(k, &root)).unwrap_or(false) => {
+                        let kind = pim_kind.unwrap();
+                        pim::report(kind, &root, &*fs, &davpath, &prefix, &pim_access, depth).await
+                    },
+                    Ok(root) if softquota::is_report(&root) && usage.is_some() => {
+                        let usage = usage.as_ref().unwrap();
+                        softquota::report(usage, &*fs, &davpath, &prefix, depth).await
+                    },
+                    Ok(root) if share::is_report(&root) && location.shares => {
+                        match share_owner {
+                            Some(ref owner) => share::report(&root, &*fs, &davpath, &prefix, owner).await,
+                            None => report::error(StatusCode::FORBIDDEN),
+                        }
+                    },
+                    Ok(root) if report::is_dav(&root, "version-tree") && versions.is_some() => {
+                        let vfs = versions.as_ref().unwrap();
+                        deltav::version_tree(&root, vfs, &davpath, &prefix).await
+                    },
+                    #[cfg(feature = "sqlite")]
+                    Ok(root) if report::is_dav(&root, "sync-collection") && syncfs.is_some() => {
+                        let sfs = syncfs.as_ref().unwrap();
+                        sfs.report(&root, &davpath, &prefix).await
+                    },
+                    Ok(_) => report::condition(StatusCode::FORBIDDEN, "supported-report"),
+                }
+            } else {
+                match versions {
+                    Some(vfs) => deltav::handle(req.method(), &vfs, &davpath, &prefix).await,
+                    None => return self.error(StatusCode::METHOD_NOT_ALLOWED).await,
+                }
+            };
+            let (mut parts, body) = resp.into_parts();
+            self.set_server_header(&mut parts.headers);
+            return Ok(http::Response::from_parts(parts, body.into()));
+        }
+
+        // SEARCH, in the collection of the request.
+        if search::dav_method(req.method()).is_some() {
+            if !location.search || !methods.contains(method) {
+                return self.error(StatusCode::METHOD_NOT_ALLOWED).await;
+            }
+            let davpath = dav_path(req.uri(), &prefix);
+            let davpath = match davpath {
+                Ok(p) => p,
+                Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
+            };
+            let resp = search::handle(req, &*fs, contains.as_ref(), &davpath, &prefix).await;
+            let (mut parts, body) = resp.into_parts();
+            self.set_server_header(&mut parts.headers);
+            return Ok(http::Response::from_parts(parts, body.into()));
+        }
+
+        // The tus endpoint, for resumable uploads. POST is only for that.
+        if location.tus {
+            let davpath = dav_path(req.uri(), &prefix);
+            let davpath = match davpath {
+                Ok(p) if tus::is_tus(&p) => Some(p),
+                Ok(_) => None,
+                Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
+            };
+            if let Some(davpath) = davpath {
+                if !methods.contains(DavMethod::Put) {
+                    return self.error(StatusCode::METHOD_NOT_ALLOWED).await;
+                }
+                let resp = match tus::handle(req, &*fs, &davpath, &prefix, max_file_size).await {
+                    tus::Reply::Response(resp) => resp,
+                    tus::Reply::Complete(mut complete) => {
+                        let status = match complete.put_request(fs.clone(), &prefix) {
+                            Some(put) => Box::pin(self.route_request(put, remote_ip)).await?.status(),
+                            None => StatusCode::BAD_REQUEST,
+                        };
+                        complete.finish(&*fs, status).await
+                    },
+                };
+                let (mut parts, body) = resp.into_parts();
+                self.set_server_header(&mut parts.headers);
+                return Ok(http::Response::from_parts(parts, body.into()));
+            }
+        }
+        if req.method() == http::Method::POST {
+            return self.error(StatusCode::METHOD_NOT_ALLOWED).await;
+        }
+
+        // The MOVE that finishes a chunked upload, done as a PUT of the chunks.
+        if location.oc_chunking && method == DavMethod::Move {
+            let davpath = dav_path(req.uri(), &prefix);
+            let davpath = match davpath {
+                Ok(p) if ocupload::is_final(&p) => Some(p),
+                Ok(_) => None,
+                Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
+            };
+            if let Some(davpath) = davpath {
+                let dir = ocupload::upload_dir(&davpath);
+                let chunks = match dir {
+                    Ok(ref dir) => ocupload::chunks(&*fs, dir).await,
+                    Err(e) => Err(e),
+                };
+                let (dir, chunks) = match (dir, chunks) {
+                    (Ok(dir), Ok(chunks)) => (dir, chunks),
+                    (Err(e), _) | (_, Err(e)) => return self.error(report::status(e)).await,
+                };
+                let total = match ocupload::verify(&*fs, &chunks, req.headers()).await {
+                    Ok(total) => total,
+                    Err(status) => return self.error(status).await,
+                };
+                let body = ocupload::body(fs.clone(), chunks);
+                let put = match ocupload::put_request(req, body, total) {
+                    Some(put) => put,
+                    None => return self.error(StatusCode::BAD_REQUEST).await,
+                };
+                let mut resp = Box::pin(self.route_request(put, remote_ip)).await?;
+                if resp.status().is_success() {
+                    ocupload::remove(&*fs, &dir).await;
+                    ocupload::response_headers(resp.headers_mut());
+                }
+                return Ok(resp);
+            }
+        }
+
+        // The Content-Type of a file, by the mime-types.
+        let mime = mimetypes::Lookup::new(&self.config.mime, location);
+
+        // GET of more than one range.
+        let mut req = match method {
+            DavMethod::Get | DavMethod::Head if byteranges::is_multi(req.headers()) => {
+                let davpath = dav_path(req.uri(), &prefix);
+                let davpath = match davpath {
+                    Ok(p) => p,
+                    Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
+                };
+                let content_type = mime.content_type(&davpath);
+                match byteranges::handle(req, fs.clone(), &davpath, &content_type).await {
+                    Ok(resp) => {
+                        let (mut parts, body) = resp.into_parts();
+                        self.set_server_header(&mut parts.headers);
+                        cachecontrol::response(location, parts.status, &mut parts.headers);
+                        return Ok(http::Response::from_parts(parts, body));
+                    },
+                    Err(req) => req,
+                }
+            },
+            _ => req,
+        };
+
+        // A partial update (PATCH with X-Update-Range) is of a file that exists.
+        if method == DavMethod::Patch {
+            let davpath = dav_path(req.uri(), &prefix);
+            let meta = match davpath {
+                Ok(p) => fs.metadata(&p).await,
+                Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
+            };
+            match meta {
+                Ok(m) if m.is_dir() => return self.error(StatusCode::METHOD_NOT_ALLOWED).await,
+                Ok(_) => {},
+                Err(e) => return self.error(report::status(e)).await,
+            }
+        }
+
+        // Appends and ranges to a file, one at a time.
+        let append_turn = match method {
+            DavMethod::Put if location.append => {
+                let davpath = match dav_path(req.uri(), &prefix) {
+                    Ok(p) => p,
+                    Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
+                };
+                let key = format!("{}{}", db_root, davpath.as_url_string());
+                match append::prepare(&mut req, &*fs, &davpath, &key, max_file_size).await {
+                    Ok(turn) => turn,
+                    Err(resp) => {
+                        let (mut parts, body) = resp.into_parts();
+                        self.set_server_header(&mut parts.headers);
+                        return Ok(http::Response::from_parts(parts, body.into()));
+                    },
+                }
+            },
+            DavMethod::Put if append::is_append(req.headers()) => {
+                return self.error(StatusCode::NOT_IMPLEMENTED).await;
+            },
+            _ => None,
+        };
+
+        // Compression of the response.
+        let compress = match location.compress {
+            true => Some((req.method().clone(), compress::accepts_gzip(req.headers()))),
+            false => None,
+        };
+        // If-None-Match compares weakly.
+        cachecontrol::if_none_match(req.headers_mut());
+
+        // The path, for an OC-Checksum header on GET.
+        let checksum_path = match (&checksums, method) {
+            (Some(_), DavMethod::Get | DavMethod::Head) => {
+                dav_path(req.uri(), &prefix).ok()
+            },
+            _ => None,
+        };
+
+        // Win32 properties: set them after a PROPPATCH, and give them back
+        // to Microsoft clients in a PROPFIND.
+        let win32 = location.win32_props.unwrap_or(location.windows);
+        let win32_patch = match (win32, method) {
+            (true, DavMethod::PropPatch) => {
+                let davpath = match dav_path(req.uri(), &prefix) {
+                    Ok(p) => p,
+                    Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
+                };
+                let props = match win32props::proppatch(req).await {
+                    Ok((r, props)) => {
+                        req = r;
+                        props
+                    },
+                    Err(resp) => {
+                        let (mut parts, body) = resp.into_parts();
+                        self.set_server_header(&mut parts.headers);
+                        return Ok(http::Response::from_parts(parts, body.into()));
+                    },
+                };
+                Some((fs.clone(), davpath, props))
+            },
+            _ => None,
+        };
+        // Extended MKCOL: the properties in the body are set afterwards.
+        let mkcol_props = match method {
+            DavMethod::MkCol if pim_kind.is_none() && methods.contains(method) => {
+                let davpath = match dav_path(req.uri(), &prefix) {
+                    Ok(p) => p,
+                    Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
+                };
+                match mkcol::request(req).await {
+                    Ok((r, props)) => {
+                        req = r;
+                        Some((fs.clone(), davpath, props))
+                    },
+                    Err(resp) => {
+                        let (mut parts, body) = resp.into_parts();
+                        self.set_server_header(&mut parts.headers);
+                        return Ok(http::Response::from_parts(parts, body.into()));
+                    },
+                }
+            },
+            _ => None,
+        };
+        let win32_propfind = match (win32, method) {
+            (true, DavMethod::PropFind) if ms_client && local_fs.is_some() => {
+                dav_path(req.uri(), &prefix).ok().map(|p| (p, prefix.clone()))
+            },
+            _ => None,
+        };
+
+        // An HTML listing of a directory. On by default if PROPFIND is allowed.
+        let autoindex = location.autoindex.unwrap_or_else(|| methods.contains(DavMethod::PropFind));
+        let autoindex = autoindex || location.webui;
+        let get = matches!(method, DavMethod::Get | DavMethod::Head) && methods.contains(method);
+        if get && autoindex && location.indexfile.is_none() {
+            let davpath = dav_path(req.uri(), &prefix);
+            let davpath = match davpath {
+                Ok(p) => p,
+                Err(_) => return self.error(StatusCode::BAD_REQUEST).await,
+            };
+            let template = location.autoindex_html.as_deref();
+            let webui = location.webui.then(|| listing::WebUi {
+                upload: methods.contains(DavMethod::Put),
+                mkdir:  methods.contains(DavMethod::MkCol),
+                rename: methods.contains(DavMethod::Move),
+                delete: methods.contains(DavMethod::Delete),
+            });
+            let order = location.listing_order.unwrap_or(ListingOrder::Bytes);
+            let dirs_first = location.dirs_first.unwrap_or(true);
+            match listing::handle(&req, &*fs, &davpath, template, webui, order, dirs_first).await {
+                Ok(Some(resp)) => {
+                    let (mut parts, body) = resp.into_parts();
+                    self.set_server_header(&mut parts.headers);
+                    let mut resp = http::Response::from_parts(parts, body);
+                    if let Some((ref http_method, accepts)) = compress {
+                        resp = compress::response(location, http_method, accepts, resp);
+                    }
+                    return Ok(resp);
+                },
+                Ok(None) => {},
+                Err(status) => return self.error(status).await,
+            }
+        }
+
+        // For GET and HEAD of a file, the handler's Content-Type is replaced.
+        let content_type = match method {
+            DavMethod::Get | DavMethod::Head => dav_path(req.uri(), &prefix).ok(),
+            _ => None,
+        };
+        let content_type = content_type.filter(|p| !p.is_collection()).map(|p| mime.content_type(&p));
+
+        // The handler only does Depth: infinity for litmus.
+        req.headers_mut().remove("X-Litmus");
+        if infinity && allow_infinity {
+            req.headers_mut().insert("X-Litmus", "propfind-infinity".parse().unwrap());
+        }
+
+        let mut config = DavConfig::new()
+            .filesystem(fs)
+            .strip_prefix(prefix)
+            .methods(methods)
+            .hide_symlinks(hide_symlinks)
+            .autoindex(autoindex);
+        if let Some(auth_user) = auth_user {
+            config = config.principal(auth_user);
+        }
+        if let Some(indexfile) = location.indexfile.clone() {
+            config = config.indexfile(indexfile);
+        }
+        if let Some(ls) = ls {
+            config = config.locksystem(ls);
+        }
+
+        // All set. A COPY or MOVE in the background sends its webhook when
+        // it is done.
+        if let Some(job) = job {
+            let server = self.clone();
+            let webhooks = location.webhooks.clone();
+            let run = async move {
+                let resp = match server.run_davhandler(config, req).await {
+                    Ok(resp) => resp,
+                    Err(_) => return report::error(StatusCode::INTERNAL_SERVER_ERROR),
+                };
+                let (parts, body) = resp.into_parts();
+                let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+                if let Some(event) = webhook_event.filter(|_| parts.status.is_success()) {
+                    webhook::send(&server.config, &webhooks, event);
+                }
+                http::Response::from_parts(parts, String::from_utf8_lossy(&body).into_owned())
+            };
+            return match job.start(run) {
+                Ok(resp) => {
+                    let (mut parts, body) = resp.into_parts();
+                    self.set_server_header(&mut parts.headers);
+                    Ok(http::Response::from_parts(parts, body.into()))
+                },
+                Err(status) => self.error(status).await,
+            };
+        }
+        let mut resp = self.run_davhandler(config, req).await?;
+        if let Some(sendfile) = sendfile {
+            sendfile.prepare(&mut resp);
+        }
+        if let Some(turn) = append_turn {
+            if resp.status().is_success() {
+                resp.headers_mut().insert("X-Append-Offset", turn.size.into());
+            }
+        }
+        if let Some((truncated, href)) = truncated {
+            resp = depthlimit::response(resp, truncated, href);
+        }
+        if let Some(prefer) = prefer {
+            resp = prefer::response(resp, prefer);
+        }
+        if content_type.is_some() {
+            cachecontrol::response(location, resp.status(), resp.headers_mut());
+        }
+        if let Some(content_type) = content_type {
+            let ctype = resp.headers().get(http::header::CONTENT_TYPE);
+            let replace = ctype.map(|c| !c.as_bytes().starts_with(b"multipart/")).unwrap_or(false);
+            if let (true, Ok(value)) = (replace, content_type.parse()) {
+                resp.headers_mut().insert(http::header::CONTENT_TYPE, value);
+            }
+        }
+        if let Some((fs, davpath, props)) = mkcol_props.filter(|_| resp.status() == StatusCode::CREATED) {
+            if let Some(failed) = mkcol::apply(&*fs, &davpath, props).await {
+                let (mut parts, body) = failed.into_parts();
+                self.set_server_header(&mut parts.headers);
+                return Ok(http::Response::from_parts(parts, body.into()));
+            }
+        }
+        let patched = resp.status() == StatusCode::MULTI_STATUS;
+        if let Some((fs, davpath, props)) = win32_patch.filter(|_| patched) {
+            win32props::apply(props, &*fs, local_fs.as_deref(), &davpath).await;
+        }
+        if let (Some((davpath, prefix)), Some(local)) = (win32_propfind, local_fs.as_ref()) {
+            resp = win32props::propfind(resp, local, &davpath, &prefix);
+        }
+        if let (Some(cfs), Some(davpath)) = (checksums, checksum_path) {
+            if resp.status().is_success() {
+                if let Some(sum) = cfs.header(&davpath).await {
+                    resp.headers_mut().insert("OC-Checksum", sum.parse().unwrap());
+                }
+            }
+        }
+        if method == DavMethod::Options {
+            davclass::options(location, methods, resp.headers_mut());
+        }
+        if location.deltav && method == DavMethod::Options {
+            deltav::options(resp.headers_mut());
+        }
+        if location.search && method == DavMethod::Options {
+            search::options(resp.headers_mut());
+        }
+        if location.ms_author_via.unwrap_or(location.windows) && method == DavMethod::Options {
+            winclient::options(resp.headers_mut());
+        }
+        if let (Some(kind), Some(davpath)) = (pim_kind, pim_path) {
+            pim::headers(kind, method, &davpath, resp.headers_mut());
+        }
+        if let Some((ref http_method, accepts)) = compress {
+            resp = compress::response(location, http_method, accepts, resp);
+        }
+        if let Some(rejected) = scan.as_ref().and_then(|s| s.rejected()) {
+            let (status, body) = scan.unwrap().response(&rejected);
+            let resp = self
+                .response_builder()
+                .status(status)
+                .header("Content-Type", "text/plain")
+                .body(body.into())
+                .unwrap();
+            return Ok(resp);
+        }
+        if let Some(mut event) = webhook_event.filter(|_| resp.status().is_success()) {
+            if let Some((fs, davpath)) = webhook_stat {
+                event.size = fs.metadata(&davpath).await.ok().map(|m| m.len());
+            }
+            webhook::send(&self.config, &location.webhooks, event);
+        }
+        Ok(resp)
+    }
+
+    async fn build_error(
+        &self,
+        code: StatusCode,
+        location: Option<&Location>,
+        req: Option<&HttpRequest>,
+    ) -> HttpResult
+    {
+        let msg = format!(
+            "<error>{} {}</error>\n",
+            code.as_u16(),
+            code.canonical_reason().unwrap_or("")
+        );
+        let mut response = self
+            .response_builder()
+            .status(code)
+            .header("Content-Type", "text/xml");
+        if code == StatusCode::UNAUTHORIZED {
+            let challenge = self.auth.www_authenticate(location, req);
+            response = response.header("WWW-Authenticate", challenge.as_str());
+        }
+        Ok(response.body(msg.into()).unwrap())
+    }
+
+    async fn auth_error(&self, code: StatusCode, location: &Location, req: &HttpRequest) -> HttpResult {
+        // a browser can go and log in at the OpenID Connect provider.
+        if code == StatusCode::UNAUTHORIZED {
+            if let Some((url, cookie)) = self.auth.login_redirect(location, req).await {
+                let resp = self
+                    .response_builder()
+                    .status(StatusCode::FOUND)
+                    .header("Location", url)
+                    .header("Set-Cookie", cookie)
+                    .body(hyper::Body::empty())
+                    .unwrap();
+                return Ok(resp);
+            }
+        }
+        self.build_error(code, Some(location), Some(req)).await
+    }
+
+    // 429, and when to try again.
+    async fn rate_limited(&self, retry_after: u64) -> HttpResult {
+        let mut resp = self.build_error(StatusCode::TOO_MANY_REQUESTS, None, None).await?;
+        resp.headers_mut().insert("Retry-After", retry_after.into());
+        Ok(resp)
+    }
+
+    async fn error(&self, code: StatusCode) -> HttpResult {
+        self.build_error(code, None, None).await
+    }
+
+    // An error, after which the connection is closed.
+    async fn close_error(&self, code: StatusCode, version: http::Version) -> HttpResult {
+        let mut resp = self.build_error(code, None, None).await?;
+        if version < http::Version::HTTP_2 {
+            resp.headers_mut().insert("connection", "close".parse().unwrap());
+        }
+        Ok(resp)
+    }
+
+    // Call the davhandler, then add headers to the response.
+    async fn run_davhandler(&self, config: DavConfig, req: HttpRequest) -> HttpResult {
+        let resp = self.dh.handle_with(config, req).await;
+        let (mut parts, body) = resp.into_parts();
+        self.set_server_header(&mut parts.headers);
+        Ok(http::Response::from_parts(parts, hyper::Body::wrap_stream(body)))
+    }
+}
+
+/// The requests of one connection, as a `tower::Service` (which is
+/// `hyper::service::Service`).
+#[derive(Clone)]
+pub struct DavService {
+    server:      Server,
+    remote_addr: SocketAddr,
+    conn:        Arc<limits::Conn>,
+}
+
+impl hyper::service::Service<HttpRequest> for DavService {
+    type Response = HttpResponse;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<HttpResponse>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: HttpRequest) -> Self::Future {
+        let server = self.server.clone();
+        let remote_addr = self.remote_addr;
+        req.extensions_mut().insert(self.conn.clone());
+        async move { server.route(req, remote_addr).await }.boxed()
+    }
+}
+
+// The server makes a DavService for every connection of a hyper::Server.
+impl<'a> hyper::service::Service<&'a AddrStream> for Server {
+    type Response = DavService;
+    type Error = Infallible;
+    type Future = futures::future::Ready<Result<DavService, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, stream: &'a AddrStream) -> Self::Future {
+        futures::future::ready(Ok(self.service(stream.remote_addr())))
+    }
+}
+
+// The header-read and idle timeout of connections. 0 is no timeout.
+pub fn conn_timeouts(server: &config::Server) -> (Option<Duration>, Option<Duration>) {
+    let secs = |t: u64| Some(Duration::from_secs(t)).filter(|_| t > 0);
+    let header = secs(server.header_read_timeout.unwrap_or(30));
+    let idle = secs(server.idle_timeout.unwrap_or(300));
+    (header, idle)
+}
+
+// Fail reading the body if the client does not send anything for "timeout".
+// The flag is set when that happens.
+fn body_timeout(req: HttpRequest, timeout: Duration, timed_out: Arc<AtomicBool>) -> HttpRequest {
+    req.map(|body| {
+        let body = futures::stream::unfold(body, move |mut body| {
+            let timed_out = timed_out.clone();
+            async move {
+                match tokio::time::timeout(timeout, body.next()).await {
+                    Ok(Some(data)) => Some((data.map_err(io::Error::other), body)),
+                    Ok(None) => None,
+                    Err(_) => {
+                        timed_out.store(true, Ordering::SeqCst);
+                        Some((Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout")), body))
+                    },
+                }
+            }
+        });
+        hyper::Body::wrap_stream(body)
+    })
+}
+
+// Resolves when the server starts shutting down.
+pub async fn shutdown_signal(mut shutdown: watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+// Clones a http request with an empty body.
+fn clone_httpreq(req: &HttpRequest) -> HttpRequest {
+    let mut builder = http::Request::builder()
+        .method(req.method().clone())
+        .uri(req.uri().clone())
+        .version(req.version());
+    for (name, value) in req.headers().iter() {
+        builder = builder.header(name, value);
+    }
+    if let Some(user) = req.extensions().get::<auth::ClientCertUser>() {
+        builder = builder.extension(user.clone());
+    }
+    if let Some(conn) = req.extensions().get::<Arc<limits::Conn>>() {
+        builder = builder.extension(conn.clone());
+    }
+    if let Some(set_cookie) = req.extensions().get::<session::SetCookie>() {
+        builder = builder.extension(set_cookie.clone());
+    }
+    if let Some(client) = req.extensions().get::<accesslog::Client>() {
+        builder = builder.extension(client.clone());
+    }
+    builder.body(hyper::Body::empty()).unwrap()
+}
+
+// The path of the request below the prefix, with %XX decoded and "." and ".." resolved.
+fn dav_path(uri: &http::Uri, prefix: &str) -> Result<DavPath, webdav_handler::davpath::ParseError> {
+    let mut path = DavPath::new(uri.path())?;
+    path.set_prefix(prefix)?;
+    Ok(path)
+}
+
+// Hostname from the request URI (HTTP/2) or Host header, without port, lowercase.
+fn request_host(req: &HttpRequest) -> Option<String> {
+    if let Some(host) = req.uri().host() {
+        return Some(host.to_ascii_lowercase());
+    }
+    let host = req.headers().get("host")?.to_str().ok()?;
+    let host = host.parse::<http::uri::Authority>().ok()?;
+    Some(host.host().to_ascii_lowercase())
+}
+
+// The first template of the location for this user or their groups.
+fn template<'a>(
+    location: &'a Location,
+    user: Option<&str>,
+    pwd: Option<&unixuser::User>,
+) -> Option<&'a Template>
+{
+    let user = user?;
+    let in_group = |gid: &u32| pwd.map(|p| p.gid == *gid || p.groups.contains(gid)).unwrap_or(false);
+    let mut templates = location.template.iter();
+    templates.find(|t| t.users.iter().any(|u| u == user) || t.gids.iter().any(in_group))
+}
+
+// Replace "${pam:NAME}" with the value from the PAM environment. It can
+// have slashes in it, but not "." or "..".
+fn expand_pam_env(dir: &str, env: Option<&HashMap<String, String>>) -> Result<String, StatusCode> {
+    let mut expanded = String::new();
+    let mut rest = dir;
+    while let Some(idx) = rest.find("${pam:") {
+        expanded.push_str(&rest[..idx]);
+        let var = &rest[idx + 6..];
+        let end = var.find('}').ok_or(StatusCode::NOT_FOUND)?;
+        match env.and_then(|e| e.get(&var[..end])) {
+            Some(v) if !v.is_empty() && !v.split('/').any(|n| n == "." || n == "..") => expanded.push_str(v),
+            _ => {
+                debug!("expand_directory: cannot expand {}: no valid {}", dir, &var[..end]);
+                return Err(StatusCode::NOT_FOUND);
+            },
+        }
+        rest = &var[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn expand_directory(
+    dir: &str,
+    user: Option<&str>,
+    pwd: Option<&Arc<unixuser::User>>,
+    pam_env: Option<&HashMap<String, String>>,
+) -> Result<String, StatusCode>
+{
+    // Replace "$user" with the username. It must be safe to use in a path.
+    let expanded;
+    let dir = if dir.contains("$user") {
+        match user {
+            Some(u) if !u.is_empty() && !u.contains('/') && u != "." && u != ".." => {
+                expanded = dir.replace("$user", u);
+                expanded.as_str()
+            },
+            _ => {
+                debug!("expand_directory: cannot expand {}: no valid user", dir);
+                return Err(StatusCode::NOT_FOUND);
+            },
+        }
+    } else {
+        dir
+    };
+    let expanded_env;
+    let dir = if dir.contains("${pam:") {
+        expanded_env = expand_pam_env(dir, pam_env)?;
+        expanded_env.as_str()
+    } else {
+        dir
+    };
+    // If it doesn't start with "~", skip.
+    if !dir.starts_with("~") {
+        return Ok(dir.to_string());
+    }
+    // ~whatever doesn't work.
+    if dir.len() > 1 && !dir.starts_with("~/") {
+        debug!("expand_directory: rejecting {}", dir);
+        return Err(StatusCode::NOT_FOUND);
+    }
+    // must have a directory, and that dir must be UTF-8.
+    let pwd = match pwd {
+        Some(pwd) => pwd,
+        None => {
+            debug!("expand_directory: cannot expand {}: no account", dir);
+            return Err(StatusCode::NOT_FOUND);
+        },
+    };
+    let homedir = pwd.dir.to_str().ok_or(StatusCode::NOT_FOUND)?;
+    Ok(format!("{}/{}", homedir, &dir[1..]))
+}
@@ -0,0 +1,203 @@
+//
+// Session cookies ([accounts] session-cookie-timeout).
+//
+// After a login with a password (Basic or Digest), the response sets a
+// signed cookie with the user in it. A client that sends it back is let
+// in without checking the password again, until it expires. A cookie is
+// only good for locations with the same auth-type and realm, and, with
+// session-cookie-bind-ip, only from the address it was given to.
+//
+// The key is made up at startup, so a restart logs everyone out. So does
+// a change of the password: the cookie has the generation of the user's
+// password in it, and that goes up with every change.
+//
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use ring::{hmac, rand::SecureRandom};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+type HttpRequest = http::Request<hyper::Body>;
+
+pub const COOKIE: &str = "webdav-session";
+
+lazy_static::lazy_static! {
+    static ref KEY: hmac::Key = hmac::Key::new(hmac::HMAC_SHA256, &random(32));
+    // user -> how many times the password was changed since startup.
+    static ref GENERATIONS: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Serialize, Deserialize)]
+struct Session {
+    user:    String,
+    scope:   String,
+    ip:      Option<IpAddr>,
+    expires: u64,
+    gen:     u64,
+}
+
+fn generation(user: &str) -> u64 {
+    GENERATIONS.lock().unwrap().get(user).copied().unwrap_or(0)
+}
+
+/// The password of "user" was changed: the cookies issued before are no
+/// longer good.
+#[cfg_attr(not(feature = "pam"), allow(dead_code))]
+pub fn password_changed(user: &str) {
+    *GENERATIONS.lock().unwrap().entry(user.to_string()).or_insert(0) += 1;
+}
+
+/// The cookie to set on the response, if any. It is stored in the
+/// request extensions, and filled in by the authentication.
+#[derive(Clone, Default)]
+pub struct SetCookie(Arc<Mutex<Option<(String, u64)>>>);
+
+impl SetCookie {
+    pub fn set(&self, value: String, max_age: u64) {
+        *self.0.lock().unwrap() = Some((value, max_age));
+    }
+
+    /// The Set-Cookie: header, if a cookie was set.
+    pub fn header(&self, secure: bool) -> Option<String> {
+        let (value, max_age) = self.0.lock().unwrap().take()?;
+        let secure = if secure { "; Secure" } else { "" };
+        Some(format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict{}",
+            COOKIE, value, max_age, secure
+        ))
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+    now.map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub(crate) fn random(len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    ring::rand::SystemRandom::new().fill(&mut buf).expect("random numbers");
+    buf
+}
+
+pub(crate) fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+/// payload "." signature.
+pub(crate) fn sign<T: Serialize>(key: &hmac::Key, value: &T) -> String {
+    let payload = b64(&serde_json::to_vec(value).unwrap());
+    let tag = hmac::sign(key, payload.as_bytes());
+    format!("{}.{}", payload, b64(tag.as_ref()))
+}
+
+pub(crate) fn verify<T: DeserializeOwned>(key: &hmac::Key, token: &str) -> Option<T> {
+    let (payload, tag) = token.split_once('.')?;
+    let tag = base64::decode_config(tag, base64::URL_SAFE_NO_PAD).ok()?;
+    hmac::verify(key, payload.as_bytes(), &tag).ok()?;
+    let json = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+// All cookies of the request.
+fn cookies(req: &HttpRequest) -> impl Iterator<Item = &str> {
+    let headers = req.headers().get_all(http::header::COOKIE);
+    headers.into_iter().filter_map(|h| h.to_str().ok()).flat_map(|h| h.split(';')).map(|c| c.trim())
+}
+
+/// The value of a cookie in the request.
+pub(crate) fn cookie<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    cookies(req).find_map(|c| c.strip_prefix(name)?.strip_prefix('='))
+}
+
+/// Is there a cookie whose name starts with this (valid or not).
+pub(crate) fn has_cookie(req: &HttpRequest, prefix: &str) -> bool {
+    cookies(req).any(|c| c.starts_with(prefix))
+}
+
+// The scope goes into the cookie as a hash, it is none of the client's business.
+fn scope_hash(scope: &str) -> String {
+    b64(&ring::digest::digest(&ring::digest::SHA256, scope.as_bytes()).as_ref()[..12])
+}
+
+/// A new session cookie value.
+pub fn issue(user: &str, scope: &str, ip: Option<IpAddr>, timeout: u64) -> String {
+    let session = Session {
+        user: user.to_string(),
+        scope: scope_hash(scope),
+        ip,
+        expires: now() + timeout,
+        gen: generation(user),
+    };
+    sign(&KEY, &session)
+}
+
+/// The user of a valid session cookie in the request.
+pub fn check(req: &HttpRequest, scope: &str, ip: IpAddr) -> Option<String> {
+    check_cookie(req, COOKIE, scope, ip)
+}
+
+/// The same, for a cookie with another name.
+pub fn check_cookie(req: &HttpRequest, name: &str, scope: &str, ip: IpAddr) -> Option<String> {
+    let session: Session = verify(&KEY, cookie(req, name)?)?;
+    let same_ip = session.ip.map(|i| i == ip).unwrap_or(true);
+    let valid = session.scope == scope_hash(scope) && session.expires > now() && same_ip;
+    match valid && session.gen == generation(&session.user) {
+        true => Some(session.user),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(cookie: &str) -> HttpRequest {
+        http::Request::get("/")
+            .header("Cookie", cookie)
+            .body(hyper::Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let token = sign(&key, &("alice", 1234));
+        assert_eq!(verify::<(String, u64)>(&key, &token), Some(("alice".to_string(), 1234)));
+        let other = hmac::Key::new(hmac::HMAC_SHA256, b"other");
+        assert!(verify::<(String, u64)>(&other, &token).is_none());
+        let (payload, tag) = token.split_once('.').unwrap();
+        let forged = b64(br#"["root",1234]"#);
+        assert!(verify::<(String, u64)>(&key, &format!("{}.{}", forged, tag)).is_none());
+        assert!(verify::<(String, u64)>(&key, payload).is_none());
+
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other_ip: IpAddr = "192.0.2.2".parse().unwrap();
+        let bound = format!("a=1; {}={}", COOKIE, issue("alice", "pam", Some(ip), 60));
+        assert_eq!(check(&request(&bound), "pam", ip).as_deref(), Some("alice"));
+        assert!(check(&request(&bound), "pam", other_ip).is_none());
+        assert!(check(&request(&bound), "htpasswd.x", ip).is_none());
+        let unbound = format!("{}={}", COOKIE, issue("alice", "pam", None, 60));
+        assert_eq!(check(&request(&unbound), "pam", other_ip).as_deref(), Some("alice"));
+        let expired = format!("{}={}", COOKIE, issue("alice", "pam", None, 0));
+        assert!(check(&request(&expired), "pam", ip).is_none());
+        assert!(has_cookie(&request(&expired), COOKIE));
+        assert_eq!(cookie(&request("x=1;y=2"), "y"), Some("2"));
+
+        let before = format!("{}={}", COOKIE, issue("carol", "pam", None, 60));
+        password_changed("carol");
+        assert!(check(&request(&before), "pam", ip).is_none());
+        let after = format!("{}={}", COOKIE, issue("carol", "pam", None, 60));
+        assert_eq!(check(&request(&after), "pam", ip).as_deref(), Some("carol"));
+
+        let set = SetCookie::default();
+        set.set("v".to_string(), 60);
+        assert_eq!(
+            set.header(true).as_deref(),
+            Some("webdav-session=v; Path=/; Max-Age=60; HttpOnly; SameSite=Strict; Secure")
+        );
+        assert!(set.header(true).is_none());
+    }
+}
@@ -0,0 +1,590 @@
+//
+// Share links: a public URL for a file or a folder in the space of a
+// user, for people who have no account. A link is read-only, or
+// upload-only (files can be put in the folder, but nothing can be seen
+// or overwritten), and can expire and have a password.
+//
+// A user makes and removes links with a REPORT on the resource, in a
+// location with "shares = true"; they are kept in the [shares] file. A
+// request for a link (/s/<id>/...) is done as a request to the server
+// itself, for the owner of the link, so everything that limits what the
+// owner can do there limits the link too.
+//
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use http::{Method, StatusCode};
+use percent_encoding::{percent_decode_str, percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::DavFileSystem;
+use xmltree::Element;
+
+use crate::auth::{Auth, Subrequest};
+use crate::config;
+use crate::report::{self, escape};
+use crate::session::{self, now};
+use crate::server::Server;
+use crate::statefile;
+
+type HttpRequest = http::Request<hyper::Body>;
+type HttpResponse = http::Response<hyper::Body>;
+
+lazy_static::lazy_static! {
+    static ref STORE: Mutex<Option<Store>> = Mutex::new(None);
+}
+
+/// The namespace of the share reports.
+pub const NS: &str = "urn:webdav-server-rs:share";
+
+const DEFAULT_PREFIX: &str = "/s";
+
+// No 0/o, 1/l, like the app passwords.
+// The cookie after the password of a link, and how long it is good for.
+const COOKIE: &str = "webdav-share";
+const COOKIE_TIMEOUT: u64 = 3600;
+
+// listings that are rewritten are read into memory.
+const MAX_BODY: usize = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    ReadOnly,
+    UploadOnly,
+}
+
+impl Mode {
+    fn parse(s: &str) -> Option<Mode> {
+        match s {
+            "read-only" => Some(Mode::ReadOnly),
+            "upload-only" => Some(Mode::UploadOnly),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Mode::ReadOnly => "read-only",
+            Mode::UploadOnly => "upload-only",
+        }
+    }
+
+    fn allows(&self, method: &Method) -> bool {
+        match self {
+            Mode::ReadOnly => matches!(method.as_str(), "GET" | "HEAD" | "OPTIONS" | "PROPFIND"),
+            Mode::UploadOnly => matches!(method.as_str(), "PUT" | "MKCOL" | "OPTIONS"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Share {
+    pub id:       String,
+    pub user:     String,
+    // the URL path of the resource, ending in a slash for a folder.
+    pub path:     String,
+    // the auth scope of its location, for the subrequests.
+    pub scope:    String,
+    pub mode:     Mode,
+    pub created:  u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires:  Option<u64>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub password: String,
+}
+
+impl Share {
+    fn is_dir(&self) -> bool {
+        self.path.ends_with('/')
+    }
+
+    fn expired(&self, now: u64) -> bool {
+        self.expires.map(|e| e <= now).unwrap_or(false)
+    }
+}
+
+/// Set on the subrequest of a share link.
+#[derive(Clone, Debug)]
+pub struct Link;
+
+/// The owner of the shares made with a REPORT.
+pub struct Owner {
+    pub user:      String,
+    pub scope:     String,
+    // may the owner write here (for upload-only links).
+    pub may_write: bool,
+}
+
+struct Store {
+    file:     String,
+    prefix:   String,
+    max_days: Option<u64>,
+    shares:   Vec<Arc<Share>>,
+}
+
+// 32 characters: 160 bits.
+fn generate() -> String {
+    statefile::token(32)
+}
+
+fn read(file: &str) -> io::Result<Vec<Arc<Share>>> {
+    let data = match std::fs::read(file) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", file, e))),
+    };
+    let shares: Vec<Share> = serde_json::from_slice(&data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", file, e)))?;
+    Ok(shares.into_iter().map(Arc::new).collect())
+}
+
+fn write(file: &str, shares: &[Arc<Share>]) -> io::Result<()> {
+    let shares: Vec<&Share> = shares.iter().map(|s| &**s).collect();
+    let data = serde_json::to_vec_pretty(&shares).map_err(io::Error::other)?;
+    statefile::write(file, &data)
+}
+
+/// The path the links are under, if there are shares.
+pub fn prefix(config: &config::Shares) -> Option<&str> {
+    config.file.as_ref()?;
+    Some(config.prefix.as_deref().unwrap_or(DEFAULT_PREFIX))
+}
+
+/// (Re)read the file. Without one, shares are off.
+pub fn load(config: &config::Shares) -> io::Result<()> {
+    let store = match config.file {
+        Some(ref file) => {
+            Some(Store {
+                file:     file.to_string(),
+                prefix:   prefix(config).unwrap_or(DEFAULT_PREFIX).to_string(),
+                max_days: config.max_days,
+                shares:   read(file)?,
+            })
+        },
+        None => None,
+    };
+    *STORE.lock().unwrap() = store;
+    Ok(())
+}
+
+/// A share that has not expired.
+pub fn lookup(id: &str) -> Option<Arc<Share>> {
+    let store = STORE.lock().unwrap();
+    let now = now();
+    let mut shares = store.as_ref()?.shares.iter();
+    shares
+        .find(|s| crate::htpasswd::consteq(s.id.as_bytes(), id.as_bytes()) && !s.expired(now))
+        .cloned()
+}
+
+/// The shares of a user (or of everyone), without the password hashes.
+pub fn list(user: Option<&str>) -> io::Result<Vec<Share>> {
+    let store = STORE.lock().unwrap();
+    let store = store.as_ref().ok_or_else(|| io::Error::other("no [shares] file"))?;
+    let shares = store.shares.iter().filter(|s| user.map(|u| u == s.user).unwrap_or(true));
+    Ok(shares
+        .map(|s| {
+            Share {
+                password: String::new(),
+                ..(**s).clone()
+            }
+        })
+        .collect())
+}
+
+/// A new share of a path. The expiry is in seconds from now, and is at
+/// most max-days. Expired shares are removed on the way.
+pub fn create(
+    owner: &Owner,
+    path: &str,
+    mode: Mode,
+    expires_in: Option<u64>,
+    password: Option<&str>,
+) -> io::Result<Share>
+{
+    let hash = match password {
+        Some(password) => pwhash::bcrypt::hash(password).map_err(io::Error::other)?,
+        None => String::new(),
+    };
+    let mut store = STORE.lock().unwrap();
+    let store = store.as_mut().ok_or_else(|| io::Error::other("no [shares] file"))?;
+    let now = now();
+    let max = store.max_days.map(|d| d * 86400);
+    let expires_in = match (expires_in, max) {
+        (Some(e), Some(max)) => Some(e.min(max)),
+        (e, max) => e.or(max),
+    };
+    let id = loop {
+        let id = generate();
+        if !store.shares.iter().any(|s| s.id == id) {
+            break id;
+        }
+    };
+    let share = Share {
+        id,
+        user: owner.user.clone(),
+        path: path.to_string(),
+        scope: owner.scope.clone(),
+        mode,
+        created: now,
+        expires: expires_in.map(|e| now + e),
+        password: hash,
+    };
+    let mut shares: Vec<_> = store.shares.iter().filter(|s| !s.expired(now)).cloned().collect();
+    shares.push(Arc::new(share.clone()));
+    write(&store.file, &shares)?;
+    store.shares = shares;
+    Ok(share)
+}
+
+/// Remove a share (of this user).
+pub fn revoke(user: Option<&str>, id: &str) -> io::Result<()> {
+    let mut store = STORE.lock().unwrap();
+    let store = store.as_mut().ok_or_else(|| io::Error::other("no [shares] file"))?;
+    let mut shares = store.shares.clone();
+    let len = shares.len();
+    shares.retain(|s| !(s.id == id && user.map(|u| u == s.user).unwrap_or(true)));
+    if shares.len() == len {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: not found", id)));
+    }
+    write(&store.file, &shares)?;
+    store.shares = shares;
+    Ok(())
+}
+
+/// The URL of a share.
+fn href(prefix: &str, share: &Share) -> String {
+    format!("{}/{}{}", prefix, share.id, if share.is_dir() { "/" } else { "" })
+}
+
+/// Is this path under the prefix of the links.
+pub fn is_link(prefix: &str, path: &str) -> bool {
+    path.strip_prefix(prefix).map(|r| r.is_empty() || r.starts_with('/')).unwrap_or(false)
+}
+
+// The path in a share for the rest of the link, encoded like the
+// Destination: of a cross-location copy. Every "." and ".." is refused,
+// also when it is %-encoded.
+fn target(share: &Share, rest: &str) -> Option<String> {
+    let mut path = share.path.trim_end_matches('/').to_string();
+    for seg in rest.split('/').filter(|s| !s.is_empty()) {
+        let name = percent_decode_str(seg).collect::<Vec<u8>>();
+        if name == b"." || name == b".." || name.contains(&0) || name.contains(&b'/') {
+            return None;
+        }
+        path.push('/');
+        path.push_str(&percent_encode(&name, NON_ALPHANUMERIC).to_string());
+    }
+    if rest.ends_with('/') || (rest.is_empty() && share.is_dir()) {
+        path.push('/');
+    }
+    Some(path)
+}
+
+// The paths of the owner in a multistatus or listing, as paths of the link.
+fn rewrite(body: &str, from: &str, to: &str) -> String {
+    body.replace(&format!(">{}", from), &format!(">{}", to))
+        .replace(&format!("\"{}", from), &format!("\"{}", to))
+}
+
+/// A request for a link under this prefix. UNAUTHORIZED means it needs
+/// the password. A wrong password counts as a failed login of
+/// "share:<id>", and a right one gets a cookie for the link, so that it
+/// is not checked again on every request.
+pub async fn handle(
+    server: &Server,
+    auth: &Auth,
+    prefix: &str,
+    req: HttpRequest,
+    remote_ip: SocketAddr,
+    secure: bool,
+) -> Result<HttpResponse, StatusCode>
+{
+    let rest = req.uri().path()[prefix.len()..].trim_start_matches('/');
+    let (id, rest) = match rest.split_once('/') {
+        Some((id, rest)) => (id, Some(rest.to_string())),
+        None => (rest, None),
+    };
+    let share = lookup(id).ok_or(StatusCode::NOT_FOUND)?;
+
+    if !share.mode.allows(req.method()) {
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let mut cookie = None;
+    if !share.password.is_empty() {
+        // the hash is in the scope: a new password logs everyone out.
+        let scope = format!("share:{}:{}", share.id, share.password);
+        let ip = remote_ip.ip();
+        if session::check_cookie(&req, COOKIE, &scope, ip).is_none() {
+            auth.share_password(&req, &share.id, &share.password, remote_ip)?;
+            let value = session::issue("", &scope, Some(ip), COOKIE_TIMEOUT);
+            let path = format!("{}/{}", prefix, share.id);
+            let secure = if secure { "; Secure" } else { "" };
+            cookie = Some(format!(
+                "{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax{}",
+                COOKIE, value, path, COOKIE_TIMEOUT, secure
+            ));
+        }
+    }
+    let mut resp = forward(server, prefix, &share, rest.as_deref(), req, remote_ip).await?;
+    if let Some(cookie) = cookie.and_then(|c| c.parse().ok()) {
+        resp.headers_mut().append(http::header::SET_COOKIE, cookie);
+    }
+    Ok(resp)
+}
+
+// The request as a subrequest for the owner, and the response with the
+// paths of the link.
+async fn forward(
+    server: &Server,
+    prefix: &str,
+    share: &Share,
+    rest: Option<&str>,
+    req: HttpRequest,
+    remote_ip: SocketAddr,
+) -> Result<HttpResponse, StatusCode>
+{
+    let link = href(prefix, share);
+    let path = match (share.is_dir(), rest) {
+        (false, None) => share.path.clone(),
+        (false, Some(_)) => return Err(StatusCode::NOT_FOUND),
+        (true, None) => {
+            let resp = http::Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header("Location", link.as_str())
+                .body(hyper::Body::empty())
+                .unwrap();
+            return Ok(resp);
+        },
+        (true, Some(rest)) => target(share, rest).ok_or(StatusCode::BAD_REQUEST)?,
+    };
+    if req.method().as_str() == "PROPFIND" {
+        let depth = req.headers().get("depth").and_then(|d| d.to_str().ok());
+        if !matches!(depth, Some("0") | Some("1")) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
+
+    let (mut parts, body) = req.into_parts();
+    let uri = match parts.uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.clone(),
+    };
+    parts.uri = uri.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    parts.headers.remove(http::header::AUTHORIZATION);
+    parts.headers.remove(http::header::COOKIE);
+    if share.mode == Mode::UploadOnly {
+        // nothing that is there can be overwritten.
+        parts.headers.insert(http::header::IF_NONE_MATCH, "*".parse().unwrap());
+    }
+    parts.extensions.insert(Subrequest {
+        user:  share.user.clone(),
+        scope: share.scope.clone(),
+    });
+    parts.extensions.insert(Link);
+    let method = parts.method.clone();
+    let req = http::Request::from_parts(parts, body);
+    let resp = server.subrequest(req, remote_ip).await.map_err(|e| {
+        debug!("share: {}: {}", share.id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let (mut parts, body) = resp.into_parts();
+    parts.headers.remove(http::header::WWW_AUTHENTICATE);
+    if let Some(location) = parts.headers.get("location").and_then(|l| l.to_str().ok()) {
+        let location = location.replacen(&share.path, &link, 1);
+        parts.headers.insert("location", location.parse().map_err(|_| StatusCode::BAD_GATEWAY)?);
+    }
+    let html = parts.headers.get("content-type").and_then(|c| c.to_str().ok()).unwrap_or_default();
+    let listing = method == Method::GET && path.ends_with('/') && html.starts_with("text/html");
+    if parts.status != StatusCode::MULTI_STATUS && !listing {
+        return Ok(http::Response::from_parts(parts, body));
+    }
+    let body = hyper::body::to_bytes(body).await.map_err(|_| StatusCode::BAD_GATEWAY)?;
+    if body.len() > MAX_BODY {
+        debug!("share: {}: response too large to rewrite", share.id);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let body = rewrite(&String::from_utf8_lossy(&body), &share.path, &link);
+    parts.headers.remove(http::header::CONTENT_LENGTH);
+    Ok(http::Response::from_parts(parts, body.into()))
+}
+
+/// Is this a share report.
+pub fn is_report(root: &Element) -> bool {
+    root.namespace.as_deref() == Some(NS) &&
+        matches!(root.name.as_str(), "share-create" | "share-list" | "share-delete")
+}
+
+// An S:share element, with the attributes (the xmlns on its own).
+fn share_xml(prefix: &str, share: &Share, attrs: &str) -> String {
+    let mut xml = format!(
+        "<S:share{}><S:id>{}</S:id><S:href>{}</S:href><S:path>{}</S:path><S:mode>{}</S:mode>\
+         <S:created>{}</S:created>",
+        attrs,
+        share.id,
+        escape(&href(prefix, share)),
+        escape(&share.path),
+        share.mode.as_str(),
+        share.created
+    );
+    if let Some(expires) = share.expires {
+        xml.push_str(&format!("<S:expires>{}</S:expires>", expires));
+    }
+    xml.push_str("</S:share>\n");
+    xml
+}
+
+fn child_text(root: &Element, name: &str) -> Option<String> {
+    let text = root.get_child(name)?.get_text()?;
+    Some(text.trim().to_string())
+}
+
+/// A share report on a resource: S:share-create (with S:mode, S:expires-in
+/// in seconds, S:password), S:share-list and S:share-delete (with S:id).
+pub async fn report(
+    root: &Element,
+    fs: &dyn DavFileSystem,
+    path: &DavPath,
+    prefix: &str,
+    owner: &Owner,
+) -> http::Response<String>
+{
+    let links = match STORE.lock().unwrap().as_ref() {
+        Some(store) => store.prefix.clone(),
+        None => return report::error(StatusCode::NOT_FOUND),
+    };
+    let meta = match fs.metadata(path).await {
+        Ok(meta) => meta,
+        Err(e) => return report::error(report::status(e)),
+    };
+    let href = report::href(prefix, path, meta.is_dir());
+    let start = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n";
+    match root.name.as_str() {
+        "share-create" => {
+            let mode = match child_text(root, "mode") {
+                Some(mode) => Mode::parse(&mode),
+                None => Some(Mode::ReadOnly),
+            };
+            let expires_in = child_text(root, "expires-in").map(|e| e.parse::<u64>().ok().filter(|&e| e > 0));
+            let (mode, expires_in) = match (mode, expires_in) {
+                (Some(mode), Some(Some(e))) => (mode, Some(e)),
+                (Some(mode), None) => (mode, None),
+                _ => return report::error(StatusCode::BAD_REQUEST),
+            };
+            if mode == Mode::UploadOnly && !meta.is_dir() {
+                return report::error(StatusCode::BAD_REQUEST);
+            }
+            if mode == Mode::UploadOnly && !owner.may_write {
+                return report::error(StatusCode::FORBIDDEN);
+            }
+            let password = child_text(root, "password").filter(|p| !p.is_empty());
+            match create(owner, &href, mode, expires_in, password.as_deref()) {
+                Ok(share) => {
+                    info!("share: {} ({}) of {} created by {}", share.id, mode.as_str(), href, owner.user);
+                    let body = share_xml(&links, &share, &format!(" xmlns:S=\"{}\"", NS));
+                    report::response(StatusCode::OK, format!("{}{}", start, body))
+                },
+                Err(e) => {
+                    error!("share: {}: {}", href, e);
+                    report::error(StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        },
+        "share-list" => {
+            let shares = match list(Some(&owner.user)) {
+                Ok(shares) => shares,
+                Err(_) => return report::error(StatusCode::NOT_FOUND),
+            };
+            let now = now();
+            let mut body = format!("{}<S:shares xmlns:S=\"{}\">\n", start, NS);
+            let below = |s: &&Share| s.path == href || (href.ends_with('/') && s.path.starts_with(&href));
+            for share in shares.iter().filter(below).filter(|s| !s.expired(now)) {
+                body.push_str(&share_xml(&links, share, ""));
+            }
+            body.push_str("</S:shares>\n");
+            report::response(StatusCode::OK, body)
+        },
+        _ => {
+            let id = match child_text(root, "id") {
+                Some(id) => id,
+                None => return report::error(StatusCode::BAD_REQUEST),
+            };
+            match revoke(Some(&owner.user), &id) {
+                Ok(()) => {
+                    info!("share: {} removed by {}", id, owner.user);
+                    report::error(StatusCode::NO_CONTENT)
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => report::error(StatusCode::NOT_FOUND),
+                Err(e) => {
+                    error!("share: {}: {}", id, e);
+                    report::error(StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share() {
+        let id = generate();
+        assert_eq!(id.len(), 32);
+        assert_ne!(generate(), id);
+
+        let dir = std::env::temp_dir().join(format!("share-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("shares.json");
+        let config = config::Shares {
+            file:     Some(file.to_str().unwrap().to_string()),
+            prefix:   None,
+            max_days: Some(1),
+        };
+        load(&config).unwrap();
+        let owner = Owner {
+            user:      "alice".to_string(),
+            scope:     "scope".to_string(),
+            may_write: true,
+        };
+        let share = create(&owner, "/home/alice/docs/", Mode::ReadOnly, None, Some("pw")).unwrap();
+        assert_eq!(share.expires, Some(share.created + 86400));
+        let found = lookup(&share.id).unwrap();
+        assert!(crate::htpasswd::verify("pw", &found.password));
+        let again = create(&owner, "/home/alice/a.txt", Mode::ReadOnly, Some(60), None).unwrap();
+        assert_eq!(again.expires, Some(again.created + 60));
+
+        load(&config).unwrap();
+        let list = list(Some("alice")).unwrap();
+        assert_eq!((list.len(), list[0].password.as_str()), (2, ""));
+        assert!(revoke(Some("bob"), &share.id).is_err());
+        revoke(Some("alice"), &share.id).unwrap();
+        assert!(lookup(&share.id).is_none());
+        assert!(lookup(&again.id).is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut share = share;
+        share.expires = Some(10);
+        assert!(share.expired(10) && !share.expired(9));
+        assert_eq!(href("/s", &share), format!("/s/{}/", share.id));
+        assert_eq!(target(&share, "").unwrap(), "/home/alice/docs/");
+        assert_eq!(target(&share, "a b/c.txt").unwrap(), "/home/alice/docs/a%20b/c%2Etxt");
+        assert_eq!(target(&share, "sub/").unwrap(), "/home/alice/docs/sub/");
+        for bad in &["..", "a/%2e%2E/b", "./x", "a%2fb"] {
+            assert!(target(&share, bad).is_none(), "{}", bad);
+        }
+        assert!(is_link("/s", "/s/x") && is_link("/s", "/s") && !is_link("/s", "/sx"));
+        assert!(Mode::UploadOnly.allows(&Method::PUT) && !Mode::UploadOnly.allows(&Method::GET));
+        assert!(!Mode::ReadOnly.allows(&Method::DELETE));
+
+        let body = "<D:href>/home/alice/docs/a</D:href><a href=\"/home/alice/docs/b\">";
+        assert_eq!(
+            rewrite(body, "/home/alice/docs/", "/s/x/"),
+            "<D:href>/s/x/a</D:href><a href=\"/s/x/b\">"
+        );
+    }
+}
@@ -0,0 +1,734 @@
+//
+// Quotas kept by the server itself, for quota = "<size>" and mem-size.
+//
+// A QuotaFs wraps a filesystem and adds up the size of the files in it.
+// Writes that would make the total larger than the limit fail with
+// 507 Insufficient Storage.
+//
+// For a directory on disk, the total comes from a scan of the directory
+// when it is first used, and that is repeated every hour in the background
+// to pick up changes made outside of the server. There is one total per
+// (expanded) directory, so with "$user" in it every user has their own.
+// With watch = true, changes are noticed right away (see inotify.rs).
+//
+// The scan also gives the totals below every directory: bytes, files and
+// directories, like du. They are kept up to date by the QuotaFs as well,
+// and answer the usage REPORT, so that clients can show the size of a
+// folder without walking it.
+//
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use std::os::unix::ffi::OsStrExt;
+
+use futures::future::FutureExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+use xmltree::Element;
+
+use crate::report;
+
+/// The namespace of the usage report.
+pub const NS: &str = "urn:webdav-server-rs:usage";
+
+const RESCAN_INTERVAL: Duration = Duration::from_secs(3600);
+
+lazy_static::lazy_static! {
+    static ref USAGE: Mutex<HashMap<PathBuf, Arc<Usage>>> = Mutex::new(HashMap::new());
+}
+
+/// What is below a directory, not counting itself.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub bytes: u64,
+    pub files: u64,
+    pub dirs:  u64,
+}
+
+// A change to the Stats of a directory and the ones above it.
+#[derive(Debug, Default, Clone, Copy)]
+struct Delta {
+    bytes: i64,
+    files: i64,
+    dirs:  i64,
+}
+
+impl Stats {
+    fn apply(&mut self, d: Delta) {
+        let add = |v: u64, d: i64| (v as i64).saturating_add(d).max(0) as u64;
+        self.bytes = add(self.bytes, d.bytes);
+        self.files = add(self.files, d.files);
+        self.dirs = add(self.dirs, d.dirs);
+    }
+}
+
+/// The space used in a directory.
+#[derive(Debug, Default)]
+pub struct Usage {
+    used:      AtomicU64,
+    scanned:   Mutex<Option<Instant>>,
+    scanning:  AtomicBool,
+    // the directories by path, "" is the root and "/a/b" a directory in it.
+    tree:      Mutex<HashMap<Vec<u8>, Stats>>,
+    // when the tree was scanned.
+    scan_time: Mutex<Option<SystemTime>>,
+}
+
+// The key of a path in the tree.
+fn key(path: &DavPath) -> Vec<u8> {
+    let path = path.as_bytes();
+    path.strip_suffix(b"/").unwrap_or(path).to_vec()
+}
+
+// The key of the directory a key is in.
+fn parent(key: &[u8]) -> &[u8] {
+    match key.iter().rposition(|&c| c == b'/') {
+        Some(pos) => &key[..pos],
+        None => b"",
+    }
+}
+
+impl Usage {
+    // Add to a directory and the ones above it.
+    fn add(&self, dir: &[u8], delta: Delta) {
+        let mut tree = self.tree.lock().unwrap();
+        let mut dir = dir;
+        loop {
+            tree.entry(dir.to_vec()).or_default().apply(delta);
+            if dir.is_empty() {
+                break;
+            }
+            dir = parent(dir);
+        }
+    }
+
+    // Move a directory and what is below it to another path.
+    fn rename_dir(&self, from: &[u8], to: &[u8]) {
+        let mut tree = self.tree.lock().unwrap();
+        let below = |k: &[u8]| k == from || (k.starts_with(from) && k.get(from.len()) == Some(&b'/'));
+        let keys: Vec<Vec<u8>> = tree.keys().filter(|k| below(k)).cloned().collect();
+        for k in keys {
+            let stats = tree.remove(&k).unwrap();
+            let mut new = to.to_vec();
+            new.extend_from_slice(&k[from.len()..]);
+            tree.insert(new, stats);
+        }
+    }
+
+    fn stats(&self, dir: &[u8]) -> Stats {
+        self.tree.lock().unwrap().get(dir).copied().unwrap_or_default()
+    }
+
+    // The directories right below a directory, by name.
+    fn children(&self, dir: &[u8]) -> Vec<(Vec<u8>, Stats)> {
+        let tree = self.tree.lock().unwrap();
+        let mut children: Vec<_> = tree
+            .iter()
+            .filter(|(k, _)| !k.is_empty() && parent(k) == dir)
+            .map(|(k, s)| (k[dir.len() + 1..].to_vec(), *s))
+            .collect();
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+        children
+    }
+
+    fn set_tree(&self, tree: HashMap<Vec<u8>, Stats>) {
+        *self.tree.lock().unwrap() = tree;
+        *self.scan_time.lock().unwrap() = Some(SystemTime::now());
+    }
+}
+
+/// The usage of directory "dir". It is scanned on first use. With "watch",
+/// the directories get an inotify watch while they are scanned.
+pub async fn usage(dir: &Path, watch: bool) -> Arc<Usage> {
+    let usage = USAGE
+        .lock()
+        .unwrap()
+        .entry(dir.to_path_buf())
+        .or_insert_with(|| Arc::new(Usage::default()))
+        .clone();
+    let scanned = *usage.scanned.lock().unwrap();
+    match scanned {
+        None => {
+            // every request waits for the first scan.
+            let dir = dir.to_path_buf();
+            let tree = tokio::task::block_in_place(|| scan(&dir, watch));
+            let mut scanned = usage.scanned.lock().unwrap();
+            if scanned.is_none() {
+                let used = tree.get(&b""[..]).map(|s| s.bytes).unwrap_or(0);
+                usage.used.store(used, Ordering::SeqCst);
+                usage.set_tree(tree);
+                *scanned = Some(Instant::now());
+            }
+        },
+        Some(t) if t.elapsed() > RESCAN_INTERVAL => start_rescan(dir, usage.clone()),
+        Some(_) => {},
+    }
+    usage
+}
+
+/// Rescan the usage of directory "dir" in the background, if it was
+/// scanned before.
+pub fn rescan(dir: &Path) {
+    let usage = USAGE.lock().unwrap().get(dir).cloned();
+    if let Some(usage) = usage.filter(|u| u.scanned.lock().unwrap().is_some()) {
+        start_rescan(dir, usage);
+    }
+}
+
+fn start_rescan(dir: &Path, usage: Arc<Usage>) {
+    if usage.scanning.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let before = usage.used.load(Ordering::SeqCst);
+        let tree = scan(&dir, false);
+        let used = tree.get(&b""[..]).map(|s| s.bytes).unwrap_or(0);
+        usage.set_tree(tree);
+        // keep the changes that were made during the scan.
+        let now = usage.used.load(Ordering::SeqCst);
+        let used = (used as i128 + now as i128 - before as i128).max(0) as u64;
+        usage.used.store(used, Ordering::SeqCst);
+        *usage.scanned.lock().unwrap() = Some(Instant::now());
+        usage.scanning.store(false, Ordering::SeqCst);
+    });
+}
+
+// Add up the size of the files in "dir", and below every directory in
+// it. Symlinks are not followed.
+fn scan(root: &Path, watch: bool) -> HashMap<Vec<u8>, Stats> {
+    let mut direct = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        if watch {
+            crate::inotify::add(root, &dir);
+        }
+        let rel = dir.strip_prefix(root).map(|r| r.as_os_str().as_bytes()).unwrap_or_default();
+        let mut key = Vec::new();
+        if !rel.is_empty() {
+            key.push(b'/');
+            key.extend_from_slice(rel);
+        }
+        let mut stats = Stats::default();
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("softquota: scan {:?}: {}", dir, e);
+                direct.push((key, stats));
+                continue;
+            },
+        };
+        for entry in entries.flatten() {
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => {
+                    stats.dirs += 1;
+                    dirs.push(entry.path());
+                },
+                Ok(t) if t.is_file() => {
+                    stats.files += 1;
+                    stats.bytes += entry.metadata().map(|m| m.len()).unwrap_or(0);
+                },
+                _ => {},
+            }
+        }
+        direct.push((key, stats));
+    }
+    // what is in a directory counts for all the ones above it too.
+    let mut tree = HashMap::new();
+    for (key, stats) in direct {
+        let delta = Delta {
+            bytes: stats.bytes as i64,
+            files: stats.files as i64,
+            dirs:  stats.dirs as i64,
+        };
+        let mut dir = &key[..];
+        loop {
+            tree.entry(dir.to_vec()).or_insert_with(Stats::default).apply(delta);
+            if dir.is_empty() {
+                break;
+            }
+            dir = parent(dir);
+        }
+    }
+    tree
+}
+
+/// The directories that have a usage, with the totals in them and when
+/// they were last scanned.
+pub fn roots() -> Vec<(PathBuf, Stats, Option<SystemTime>)> {
+    let usage = USAGE.lock().unwrap();
+    let mut roots: Vec<_> = usage
+        .iter()
+        .map(|(dir, u)| (dir.clone(), u.stats(b""), *u.scan_time.lock().unwrap()))
+        .collect();
+    roots.sort_by(|a, b| a.0.cmp(&b.0));
+    roots
+}
+
+/// Is this the usage report.
+pub fn is_report(root: &Element) -> bool {
+    root.namespace.as_deref() == Some(NS) && root.name == "usage"
+}
+
+fn collection_xml(href: &str, stats: &Stats) -> String {
+    format!(
+        "<U:collection><D:href>{}</D:href><U:bytes>{}</U:bytes><U:files>{}</U:files>\
+         <U:collections>{}</U:collections></U:collection>\n",
+        report::escape(href),
+        stats.bytes,
+        stats.files,
+        stats.dirs
+    )
+}
+
+/// The usage report of a collection: the bytes, files and collections
+/// below it, and with Depth: 1 of the collections in it too.
+pub async fn report(
+    usage: &Usage,
+    fs: &dyn DavFileSystem,
+    path: &DavPath,
+    prefix: &str,
+    depth: u32,
+) -> http::Response<String>
+{
+    match fs.metadata(path).await {
+        Ok(meta) if meta.is_dir() => {},
+        Ok(_) => return report::error(StatusCode::FORBIDDEN),
+        Err(e) => return report::error(report::status(e)),
+    }
+    let dir = key(path);
+    let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    body.push_str(&format!("<U:usage xmlns:D=\"DAV:\" xmlns:U=\"{}\">\n", NS));
+    body.push_str(&collection_xml(&report::href(prefix, path, true), &usage.stats(&dir)));
+    if depth > 0 {
+        let base = report::href("", path, true);
+        for (name, stats) in usage.children(&dir) {
+            let child = format!("{}{}/", base, percent_encode(&name, NON_ALPHANUMERIC));
+            if let Ok(child) = DavPath::new(&child) {
+                body.push_str(&collection_xml(&report::href(prefix, &child, true), &stats));
+            }
+        }
+    }
+    let scanned = *usage.scan_time.lock().unwrap();
+    if let Some(secs) = scanned.and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok()) {
+        body.push_str(&format!("<U:scanned>{}</U:scanned>\n", secs.as_secs()));
+    }
+    body.push_str("</U:usage>\n");
+    report::response(StatusCode::OK, body)
+}
+
+// Add "n" bytes to the total, if it fits.
+fn reserve(used: &AtomicU64, max: u64, n: u64) -> FsResult<()> {
+    used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |u| {
+        u.checked_add(n).filter(|&t| t <= max)
+    })
+    .map(drop)
+    .map_err(|_| FsError::InsufficientStorage)
+}
+
+fn release(used: &AtomicU64, n: u64) {
+    let _ = used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |u| Some(u.saturating_sub(n)));
+}
+
+/// A filesystem that keeps track of the size of the files in it.
+#[derive(Clone)]
+pub struct QuotaFs {
+    fs:    Box<dyn DavFileSystem>,
+    usage: Arc<Usage>,
+    max:   u64,
+}
+
+impl QuotaFs {
+    /// `max` is in bytes.
+    pub fn new(fs: Box<dyn DavFileSystem>, usage: Arc<Usage>, max: u64) -> Box<QuotaFs> {
+        Box::new(QuotaFs { fs, usage, max })
+    }
+
+    fn used(&self) -> &AtomicU64 {
+        &self.usage.used
+    }
+
+    // Size of the file at "path", 0 if it is not a file.
+    async fn file_len(&self, path: &DavPath) -> u64 {
+        self.file(path).await.unwrap_or(0)
+    }
+
+    // Size of the file at "path", if it is a file.
+    async fn file(&self, path: &DavPath) -> Option<u64> {
+        match self.fs.metadata(path).await {
+            Ok(meta) if meta.is_file() => Some(meta.len()),
+            _ => None,
+        }
+    }
+
+    // A file of "len" bytes added (or removed, with -1) in the directory of path.
+    fn count_file(&self, path: &DavPath, files: i64, len: u64) {
+        let delta = Delta {
+            bytes: files * len as i64,
+            files,
+            dirs: 0,
+        };
+        self.usage.add(parent(&key(path)), delta);
+    }
+}
+
+impl DavFileSystem for QuotaFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let write = options.write;
+            let existing = if write { self.file(path).await } else { None };
+            let truncated = if options.truncate { existing.unwrap_or(0) } else { 0 };
+            let append = options.append;
+            let file = self.fs.open(path, options).await?;
+            release(self.used(), truncated);
+            if write {
+                let delta = Delta {
+                    bytes: -(truncated as i64),
+                    files: existing.is_none() as i64,
+                    dirs:  0,
+                };
+                self.usage.add(parent(&key(path)), delta);
+            }
+            Ok(Box::new(QuotaFile {
+                file,
+                pos: 0,
+                append,
+                usage: self.usage.clone(),
+                max: self.max,
+                dir: parent(&key(path)).to_vec(),
+            }) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.create_dir(path).await?;
+            let delta = Delta {
+                dirs: 1,
+                ..Delta::default()
+            };
+            self.usage.add(parent(&key(path)), delta);
+            self.usage.add(&key(path), Delta::default());
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.remove_dir(path).await?;
+            let delta = Delta {
+                dirs: -1,
+                ..Delta::default()
+            };
+            self.usage.add(parent(&key(path)), delta);
+            self.usage.tree.lock().unwrap().remove(&key(path));
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let len = self.file(path).await;
+            self.fs.remove_file(path).await?;
+            release(self.used(), len.unwrap_or(0));
+            if let Some(len) = len {
+                self.count_file(path, -1, len);
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // a file at the destination is replaced.
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let replaced = if from != to { self.file(to).await } else { None };
+            let moved = self.file(from).await;
+            self.fs.rename(from, to).await?;
+            release(self.used(), replaced.unwrap_or(0));
+            if from == to {
+                return Ok(());
+            }
+            if let Some(len) = replaced {
+                self.count_file(to, -1, len);
+            }
+            match moved {
+                Some(len) => {
+                    self.count_file(from, -1, len);
+                    self.count_file(to, 1, len);
+                },
+                None => {
+                    let (from, to) = (key(from), key(to));
+                    let stats = self.usage.stats(&from);
+                    let delta = Delta {
+                        bytes: stats.bytes as i64,
+                        files: stats.files as i64,
+                        dirs:  stats.dirs as i64 + 1,
+                    };
+                    let minus = Delta {
+                        bytes: -delta.bytes,
+                        files: -delta.files,
+                        dirs:  -delta.dirs,
+                    };
+                    self.usage.add(parent(&from), minus);
+                    self.usage.rename_dir(&from, &to);
+                    self.usage.add(parent(&to), delta);
+                },
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            let len = self.file_len(from).await;
+            let replaced = self.file(to).await;
+            let grow = len.saturating_sub(replaced.unwrap_or(0));
+            reserve(self.used(), self.max, grow)?;
+            if let Err(e) = self.fs.copy(from, to).await {
+                release(self.used(), grow);
+                return Err(e);
+            }
+            release(self.used(), replaced.unwrap_or(0).saturating_sub(len));
+            if let Some(replaced) = replaced {
+                self.count_file(to, -1, replaced);
+            }
+            self.count_file(to, 1, len);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        self.fs.patch_props(path, patch)
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        let used = self.used().load(Ordering::SeqCst);
+        Box::pin(futures::future::ok((used, Some(self.max))))
+    }
+}
+
+// A file. Before a write, the number of bytes it adds to the
+// file is reserved.
+#[derive(Debug)]
+struct QuotaFile {
+    file:   Box<dyn DavFile>,
+    pos:    u64,
+    append: bool,
+    usage:  Arc<Usage>,
+    max:    u64,
+    // the directory it is in, in the tree.
+    dir:    Vec<u8>,
+}
+
+impl QuotaFile {
+    async fn reserve(&mut self, count: usize) -> FsResult<u64> {
+        let len = self.file.metadata().await?.len();
+        let start = if self.append { len } else { self.pos };
+        let end = start + count as u64;
+        let grow = end.saturating_sub(len);
+        reserve(&self.usage.used, self.max, grow)?;
+        self.pos = end;
+        self.grow(grow as i64);
+        Ok(grow)
+    }
+
+    fn grow(&self, bytes: i64) {
+        if bytes != 0 {
+            let delta = Delta {
+                bytes,
+                ..Delta::default()
+            };
+            self.usage.add(&self.dir, delta);
+        }
+    }
+
+    // a write that failed does not count.
+    fn failed(&self, grow: u64) {
+        release(&self.usage.used, grow);
+        self.grow(-(grow as i64));
+    }
+}
+
+impl DavFile for QuotaFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.file.metadata()
+    }
+
+    fn write_buf<'a>(&'a mut self, buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        async move {
+            let grow = self.reserve(buf.remaining()).await?;
+            let res = self.file.write_buf(buf).await;
+            if res.is_err() {
+                self.failed(grow);
+            }
+            res
+        }
+        .boxed()
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        async move {
+            let grow = self.reserve(buf.len()).await?;
+            let res = self.file.write_bytes(buf).await;
+            if res.is_err() {
+                self.failed(grow);
+            }
+            res
+        }
+        .boxed()
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        async move {
+            let data = self.file.read_bytes(count).await?;
+            self.pos += data.len() as u64;
+            Ok(data)
+        }
+        .boxed()
+    }
+
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> FsFuture<'a, u64> {
+        async move {
+            self.pos = self.file.seek(pos).await?;
+            Ok(self.pos)
+        }
+        .boxed()
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webdav_handler::memfs::MemFs;
+
+    fn path(p: &str) -> DavPath {
+        DavPath::new(p).unwrap()
+    }
+
+    fn write() -> OpenOptions {
+        OpenOptions {
+            write: true,
+            create: true,
+            truncate: true,
+            ..OpenOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_size() {
+        let fs = QuotaFs::new(MemFs::new(), Arc::new(Usage::default()), 10);
+        let used = || fs.usage.used.load(Ordering::SeqCst);
+        let mut f = fs.open(&path("/a"), write()).await.unwrap();
+        f.write_bytes(Bytes::from_static(b"123456")).await.unwrap();
+        assert!(f.write_bytes(Bytes::from_static(b"12345")).await.is_err());
+        // overwriting does not count.
+        f.seek(SeekFrom::Start(0)).await.unwrap();
+        f.write_bytes(Bytes::from_static(b"1234")).await.unwrap();
+        assert_eq!(used(), 6);
+
+        assert!(fs.copy(&path("/a"), &path("/b")).await.is_err());
+        fs.open(&path("/a"), write()).await.unwrap();
+        assert_eq!(used(), 0);
+        let mut f = fs.open(&path("/b"), write()).await.unwrap();
+        f.write_bytes(Bytes::from_static(b"1234")).await.unwrap();
+        fs.remove_file(&path("/b")).await.unwrap();
+        assert_eq!(used(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_tree() {
+        let fs = QuotaFs::new(MemFs::new(), Arc::new(Usage::default()), 100);
+        let stats = |bytes, files, dirs| Stats { bytes, files, dirs };
+        fs.create_dir(&path("/d/")).await.unwrap();
+        fs.create_dir(&path("/d/e/")).await.unwrap();
+        let mut f = fs.open(&path("/d/a"), write()).await.unwrap();
+        f.write_bytes(Bytes::from_static(b"1234")).await.unwrap();
+        let mut f = fs.open(&path("/d/e/b"), write()).await.unwrap();
+        f.write_bytes(Bytes::from_static(b"12")).await.unwrap();
+        assert_eq!(fs.usage.stats(b""), stats(6, 2, 2));
+        assert_eq!(fs.usage.stats(b"/d"), stats(6, 2, 1));
+        assert_eq!(fs.usage.stats(b"/d/e"), stats(2, 1, 0));
+
+        fs.rename(&path("/d/e/"), &path("/x/")).await.unwrap();
+        assert_eq!(fs.usage.stats(b""), stats(6, 2, 2));
+        assert_eq!(fs.usage.stats(b"/d"), stats(4, 1, 0));
+        assert_eq!(fs.usage.stats(b"/x"), stats(2, 1, 0));
+        let names: Vec<_> = fs.usage.children(b"").into_iter().map(|(n, _)| n).collect();
+        assert_eq!(names, vec![b"d".to_vec(), b"x".to_vec()]);
+        fs.copy(&path("/d/a"), &path("/x/a")).await.unwrap();
+        fs.remove_file(&path("/x/b")).await.unwrap();
+        assert_eq!(fs.usage.stats(b"/x"), stats(4, 1, 0));
+        fs.open(&path("/x/a"), write()).await.unwrap();
+        assert_eq!(fs.usage.stats(b""), stats(4, 2, 2));
+
+        let dir = std::env::temp_dir().join(format!("softquota-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+        std::fs::write(dir.join("a/f"), b"123").unwrap();
+        std::fs::write(dir.join("a/b/g"), b"12").unwrap();
+        let tree = scan(&dir, false);
+        assert_eq!(tree[&b""[..]], stats(5, 2, 2));
+        assert_eq!(tree[&b"/a/b"[..]], stats(2, 1, 0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+//
+// The order of the entries of a directory in a PROPFIND (listing-order,
+// directories-first). Without them they come as the filesystem reads
+// them, which on most filesystems is no order at all, and some clients
+// show them just like that. The HTML listings use the same order for
+// names.
+//
+// - bytes:            the bytes of the names, so "B" before "a"
+// - case-insensitive: by the lowercase letters, "a" before "B"
+// - natural:          case-insensitive, and numbers by their value,
+//                     so "2.jpg" before "10.jpg"
+//
+// Letters are compared as Unicode lowercase; that is not the collation of
+// a locale, but it does keep "É" next to "é". The names of a directory
+// are all read before the first one goes out.
+//
+use std::cmp::Ordering;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use futures::future::FutureExt;
+use futures::stream::{self, StreamExt};
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::config::ListingOrder;
+
+// Lowercase, letter by letter.
+fn case_insensitive(a: &str, b: &str) -> Ordering {
+    a.chars().flat_map(char::to_lowercase).cmp(b.chars().flat_map(char::to_lowercase))
+}
+
+// Runs of digits by their value, the rest case-insensitive.
+fn natural(mut a: &str, mut b: &str) -> Ordering {
+    let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    loop {
+        let (x, y) = match (a.chars().next(), b.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => (x, y),
+        };
+        if x.is_ascii_digit() && y.is_ascii_digit() {
+            let (n, m) = (digits(a), digits(b));
+            let (da, db) = (a[..n].trim_start_matches('0'), b[..m].trim_start_matches('0'));
+            let order = da.len().cmp(&db.len()).then_with(|| da.cmp(db));
+            if order != Ordering::Equal {
+                return order;
+            }
+            a = &a[n..];
+            b = &b[m..];
+        } else {
+            let order = x.to_lowercase().cmp(y.to_lowercase());
+            if order != Ordering::Equal {
+                return order;
+            }
+            a = &a[x.len_utf8()..];
+            b = &b[y.len_utf8()..];
+        }
+    }
+}
+
+/// Two names in this order. Names that are the same to it ("a" and "A",
+/// "1" and "01") are then in byte order, so the order is always the same.
+pub fn compare(order: ListingOrder, a: &str, b: &str) -> Ordering {
+    let first = match order {
+        ListingOrder::Bytes => Ordering::Equal,
+        ListingOrder::CaseInsensitive => case_insensitive(a, b),
+        ListingOrder::Natural => natural(a, b),
+    };
+    first.then_with(|| a.cmp(b))
+}
+
+#[derive(Clone)]
+pub struct SortFs {
+    fs:         Box<dyn DavFileSystem>,
+    order:      ListingOrder,
+    dirs_first: bool,
+}
+
+impl SortFs {
+    pub fn new(fs: Box<dyn DavFileSystem>, order: ListingOrder, dirs_first: bool) -> Box<SortFs> {
+        Box::new(SortFs { fs, order, dirs_first })
+    }
+}
+
+impl DavFileSystem for SortFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        self.fs.open(path, options)
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            let mut strm = self.fs.read_dir(path, meta).await?;
+            let mut entries = Vec::new();
+            while let Some(entry) = strm.next().await {
+                let name = String::from_utf8_lossy(&entry.name()).into_owned();
+                let dir = self.dirs_first && entry.is_dir().await.unwrap_or(false);
+                entries.push((dir, name, entry));
+            }
+            let order = self.order;
+            entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| compare(order, &a.1, &b.1)));
+            let strm = stream::iter(entries.into_iter().map(|(_, _, entry)| entry));
+            Ok(Box::pin(strm) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_dir(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_file(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.rename(from, to)
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.copy(from, to)
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        self.fs.patch_props(path, patch)
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sortfs() {
+        let sorted = |order, names: &[&'static str]| {
+            let mut names = names.to_vec();
+            names.sort_by(|a, b| compare(order, a, b));
+            names
+        };
+        let names = ["b10.txt", "B2.txt", "a", "A", "b2.txt", "b02.txt", "éa", "Éb"];
+        assert_eq!(
+            sorted(ListingOrder::Bytes, &names),
+            ["A", "B2.txt", "a", "b02.txt", "b10.txt", "b2.txt", "Éb", "éa"]
+        );
+        assert_eq!(
+            sorted(ListingOrder::CaseInsensitive, &names),
+            ["A", "a", "b02.txt", "b10.txt", "B2.txt", "b2.txt", "éa", "Éb"]
+        );
+        assert_eq!(
+            sorted(ListingOrder::Natural, &names),
+            ["A", "a", "B2.txt", "b02.txt", "b2.txt", "b10.txt", "éa", "Éb"]
+        );
+        assert_eq!(compare(ListingOrder::Natural, "x", "x1"), Ordering::Less);
+        assert_eq!(compare(ListingOrder::Natural, "99999999999999999999999", "1"), Ordering::Greater);
+    }
+}
@@ -0,0 +1,373 @@
+//
+// Spooling of uploads (spool = true), for slow backends like S3 or NFS.
+//
+// The body of a PUT is first kept in memory, up to spool-memory, and
+// after that in a file in spool-dir, that is unlinked right away. When
+// all of it is there, it is written to the backend in one go, so a slow
+// backend does not hold up the client, and a backend that fails with an
+// I/O error is tried again, up to spool-retries times, from the start.
+//
+// The backend file is opened when the upload starts, so that a PUT that
+// cannot work fails before the body is sent. Partial writes (PATCH,
+// Content-Range) are not spooled.
+//
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use futures::future::FutureExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+const CHUNK: usize = 1024 * 1024;
+const MAX_MEMORY: u64 = 1024 * 1024;
+const RETRIES: u32 = 2;
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A filesystem that spools the files that are uploaded to it.
+#[derive(Clone)]
+pub struct SpoolFs {
+    fs:         Box<dyn DavFileSystem>,
+    dir:        PathBuf,
+    max_memory: u64,
+    retries:    u32,
+}
+
+impl SpoolFs {
+    pub fn new(
+        fs: Box<dyn DavFileSystem>,
+        dir: Option<&str>,
+        max_memory: Option<u64>,
+        retries: Option<u32>,
+    ) -> Box<SpoolFs>
+    {
+        Box::new(SpoolFs {
+            fs,
+            dir: dir.map(PathBuf::from).unwrap_or_else(std::env::temp_dir),
+            max_memory: max_memory.unwrap_or(MAX_MEMORY),
+            retries: retries.unwrap_or(RETRIES),
+        })
+    }
+}
+
+// The data of an upload: in memory, or in a file once it gets bigger.
+#[derive(Debug)]
+struct Spool {
+    dir:        PathBuf,
+    max_memory: u64,
+    mem:        Vec<u8>,
+    file:       Option<File>,
+    len:        u64,
+    pos:        u64,
+}
+
+impl Spool {
+    // A new file in the spool directory, that only we have.
+    fn tmpfile(&self) -> io::Result<File> {
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.join(format!(".webdav-spool.{}.{}", std::process::id(), n));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        std::fs::remove_file(&path)?;
+        Ok(file)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let end = self.pos + data.len() as u64;
+        if self.file.is_none() && end > self.max_memory {
+            let file = self.tmpfile()?;
+            file.write_all_at(&self.mem, 0)?;
+            self.mem = Vec::new();
+            self.file = Some(file);
+        }
+        match self.file {
+            Some(ref file) => file.write_all_at(data, self.pos)?,
+            None => {
+                let pos = self.pos as usize;
+                if self.mem.len() < pos + data.len() {
+                    self.mem.resize(pos + data.len(), 0);
+                }
+                self.mem[pos..pos + data.len()].copy_from_slice(data);
+            },
+        }
+        self.pos = end;
+        self.len = self.len.max(end);
+        Ok(())
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> FsResult<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+        };
+        self.pos = pos.ok_or(FsError::GeneralFailure)?;
+        Ok(self.pos)
+    }
+
+    // The part of the data at "offset".
+    fn read(&self, offset: u64) -> io::Result<Bytes> {
+        let count = (self.len - offset).min(CHUNK as u64) as usize;
+        match self.file {
+            Some(ref file) => {
+                let mut buf = vec![0; count];
+                file.read_exact_at(&mut buf, offset)?;
+                Ok(buf.into())
+            },
+            None => Ok(Bytes::copy_from_slice(&self.mem[offset as usize..offset as usize + count])),
+        }
+    }
+}
+
+struct SpoolFile {
+    file:    Box<dyn DavFile>,
+    fs:      Box<dyn DavFileSystem>,
+    path:    DavPath,
+    spool:   Spool,
+    retries: u32,
+    done:    bool,
+}
+
+impl std::fmt::Debug for SpoolFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpoolFile")
+            .field("path", &self.path)
+            .field("len", &self.spool.len)
+            .finish()
+    }
+}
+
+impl SpoolFile {
+    async fn write(&mut self, data: &[u8]) -> FsResult<()> {
+        tokio::task::block_in_place(|| self.spool.write(data)).map_err(|e| {
+            error!("spool: {}: {}", self.path.as_url_string(), e);
+            FsError::GeneralFailure
+        })
+    }
+
+    // Write all of the data to the backend file.
+    async fn commit(&mut self) -> FsResult<()> {
+        let mut offset = 0;
+        while offset < self.spool.len {
+            let data = tokio::task::block_in_place(|| self.spool.read(offset)).map_err(|e| {
+                error!("spool: {}: {}", self.path.as_url_string(), e);
+                FsError::GeneralFailure
+            })?;
+            offset += data.len() as u64;
+            self.file.write_bytes(data).await?;
+        }
+        self.file.flush().await
+    }
+
+    // Commit, and on an I/O error open the file again and start over.
+    async fn commit_retry(&mut self) -> FsResult<()> {
+        let mut tries = 0;
+        loop {
+            let res = match tries {
+                0 => self.commit().await,
+                _ => {
+                    let options = OpenOptions {
+                        write: true,
+                        create: true,
+                        truncate: true,
+                        ..OpenOptions::default()
+                    };
+                    match self.fs.open(&self.path, options).await {
+                        Ok(file) => {
+                            self.file = file;
+                            self.commit().await
+                        },
+                        Err(e) => Err(e),
+                    }
+                },
+            };
+            match res {
+                Err(FsError::GeneralFailure) if tries < self.retries => {
+                    tries += 1;
+                    let path = self.path.as_url_string();
+                    warn!("spool: {}: write failed, try {} of {}", path, tries, self.retries);
+                    tokio::time::sleep(Duration::from_millis(100 << tries)).await;
+                },
+                res => return res,
+            }
+        }
+    }
+}
+
+impl DavFile for SpoolFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.file.metadata()
+    }
+
+    fn write_buf<'a>(&'a mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        async move {
+            while buf.has_remaining() {
+                let len = buf.chunk().len();
+                let chunk = buf.copy_to_bytes(len);
+                self.write(&chunk).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        async move { self.write(&buf).await }.boxed()
+    }
+
+    fn read_bytes<'a>(&'a mut self, _count: usize) -> FsFuture<'a, Bytes> {
+        async move { Err(FsError::NotImplemented) }.boxed()
+    }
+
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> FsFuture<'a, u64> {
+        async move { self.spool.seek(pos) }.boxed()
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        async move {
+            if std::mem::replace(&mut self.done, true) {
+                return self.file.flush().await;
+            }
+            self.commit_retry().await
+        }
+        .boxed()
+    }
+}
+
+impl DavFileSystem for SpoolFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let file = self.fs.open(path, options).await?;
+            if !options.write || !options.truncate || options.append || options.read {
+                return Ok(file);
+            }
+            Ok(Box::new(SpoolFile {
+                file,
+                fs: self.fs.clone(),
+                path: path.clone(),
+                spool: Spool {
+                    dir:        self.dir.clone(),
+                    max_memory: self.max_memory,
+                    mem:        Vec::new(),
+                    file:       None,
+                    len:        0,
+                    pos:        0,
+                },
+                retries: self.retries,
+                done: false,
+            }) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_dir(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_file(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.rename(from, to)
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.copy(from, to)
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        self.fs.patch_props(path, patch)
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webdav_handler::memfs::MemFs;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_spool() {
+        let path = DavPath::new("/a").unwrap();
+        let backend = MemFs::new();
+        let fs = SpoolFs::new(backend.clone(), None, Some(4), None);
+        let options = OpenOptions {
+            write: true,
+            create: true,
+            truncate: true,
+            ..OpenOptions::default()
+        };
+        let mut f = fs.open(&path, options).await.unwrap();
+        f.write_bytes(Bytes::from_static(b"abc")).await.unwrap();
+        f.write_bytes(Bytes::from_static(b"defghij")).await.unwrap();
+        f.seek(SeekFrom::Start(1)).await.unwrap();
+        f.write_bytes(Bytes::from_static(b"B")).await.unwrap();
+        assert_eq!(backend.metadata(&path).await.unwrap().len(), 0);
+        f.flush().await.unwrap();
+
+        let mut f = backend.open(&path, OpenOptions { read: true, ..OpenOptions::default() }).await.unwrap();
+        assert_eq!(&f.read_bytes(100).await.unwrap()[..], b"aBcdefghij");
+    }
+}
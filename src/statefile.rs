@@ -0,0 +1,58 @@
+//
+// Files that the server writes itself: the app passwords (apppass.rs),
+// the share links (share.rs) and the routes added with the admin API
+// (routefile.rs). They are small, and written whole: to a temporary file
+// next to it first, that is then moved into place, so that a crash never
+// leaves half a file. And the random tokens that go in them.
+//
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+use ring::rand::SecureRandom;
+
+// No 0/o, 1/l: easy to type over from a screen.
+pub const ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+/// Write a new version of the file, readable by the server only, and
+/// move it into place.
+pub fn write(file: &str, data: &[u8]) -> io::Result<()> {
+    let tmp = format!("{}.tmp", file);
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    std::fs::rename(&tmp, file)
+}
+
+/// A random token of "len" characters from ALPHABET, 5 bits each.
+pub fn token(len: usize) -> String {
+    let mut buf = vec![0u8; len];
+    ring::rand::SystemRandom::new().fill(&mut buf).expect("random numbers");
+    buf.iter().map(|b| ALPHABET[(*b & 31) as usize] as char).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statefile() {
+        let token = token(32);
+        assert!(token.len() == 32 && token.bytes().all(|b| ALPHABET.contains(&b)));
+        assert_ne!(super::token(32), token);
+
+        let dir = std::env::temp_dir().join(format!("statefile-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("state.json");
+        let file = file.to_str().unwrap();
+        write(file, b"one").unwrap();
+        write(file, b"two").unwrap();
+        assert_eq!(std::fs::read(file).unwrap(), b"two");
+        assert!(std::fs::metadata(format!("{}.tmp", file)).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
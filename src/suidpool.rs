@@ -0,0 +1,221 @@
+//
+// Threads that stay switched to a user ([runtime] setuid-pool).
+//
+// Without the pool, a filesystem call of a user runs on a worker thread,
+// which switches to the user and back for every call. With it, each user
+// that is active has a pool of threads of its own, that switch once, when
+// they start; the calls of that user are queued for them. A thread that
+// has been idle for setuid-pool-idle seconds exits, and a pool without
+// threads is forgotten when the next one is made.
+//
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::suid::{has_thread_switch_ugid, UgidSwitch};
+
+static SIZE: AtomicUsize = AtomicUsize::new(0);
+static IDLE: AtomicU64 = AtomicU64::new(60);
+static JOBS: AtomicU64 = AtomicU64::new(0);
+
+// uid, gid, groups.
+type Creds = (u32, u32, Vec<u32>);
+
+lazy_static::lazy_static! {
+    static ref POOLS: Mutex<HashMap<Creds, Arc<Pool>>> = Mutex::new(HashMap::new());
+}
+
+/// Threads per user (0: no pools), and after how many seconds an idle
+/// thread exits. Must be set before the first request.
+pub fn configure(size: usize, idle: u64) {
+    SIZE.store(size, Ordering::Relaxed);
+    IDLE.store(idle.max(1), Ordering::Relaxed);
+}
+
+/// The pool of a user, if there are pools.
+pub fn get(uid: u32, gid: u32, groups: &[u32]) -> Option<Arc<Pool>> {
+    let size = SIZE.load(Ordering::Relaxed);
+    if size == 0 || !has_thread_switch_ugid() {
+        return None;
+    }
+    let mut pools = POOLS.lock().unwrap();
+    let key = (uid, gid, groups.to_vec());
+    if let Some(pool) = pools.get(&key) {
+        return Some(pool.clone());
+    }
+    pools.retain(|_, pool| Arc::strong_count(pool) > 1 || pool.state.lock().unwrap().threads > 0);
+    let idle = Duration::from_secs(IDLE.load(Ordering::Relaxed));
+    let pool = Arc::new(Pool::new(Some((uid, gid, groups)), size, idle));
+    pools.insert(key, pool.clone());
+    Some(pool)
+}
+
+/// The pools, for the metrics and the admin API.
+pub fn stats() -> Vec<Stats> {
+    let pools = POOLS.lock().unwrap();
+    let mut stats: Vec<_> = pools.values().map(|pool| pool.stats()).collect();
+    stats.sort_by_key(|s| s.uid);
+    stats
+}
+
+/// Calls that were run by all pools.
+pub fn jobs() -> u64 {
+    JOBS.load(Ordering::Relaxed)
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub uid:     u32,
+    pub threads: usize,
+    pub idle:    usize,
+    pub queued:  usize,
+    /// Calls that were run, since the pool was made.
+    pub jobs:    u64,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct State {
+    jobs:    VecDeque<Job>,
+    threads: usize,
+    idle:    usize,
+}
+
+pub struct Pool {
+    uid:     u32,
+    creds:   Arc<UgidSwitch>,
+    size:    usize,
+    idle:    Duration,
+    state:   Mutex<State>,
+    wakeup:  Condvar,
+    jobs:    AtomicU64,
+    runtime: Option<tokio::runtime::Handle>,
+}
+
+impl Pool {
+    fn new(creds: Option<(u32, u32, &[u32])>, size: usize, idle: Duration) -> Pool {
+        Pool {
+            uid: creds.map(|c| c.0).unwrap_or(0),
+            creds: Arc::new(UgidSwitch::new(creds)),
+            size: size.max(1),
+            idle,
+            state: Mutex::new(State::default()),
+            wakeup: Condvar::new(),
+            jobs: AtomicU64::new(0),
+            runtime: tokio::runtime::Handle::try_current().ok(),
+        }
+    }
+
+    /// Run a blocking call on a thread of the pool.
+    pub async fn run<F, R>(self: &Arc<Self>, func: F) -> R
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = futures::channel::oneshot::channel();
+        self.submit(Box::new(move || {
+            let _ = tx.send(func());
+        }));
+        // the sender is only dropped without a result if the call panicked.
+        rx.await.expect("setuid pool: call panicked")
+    }
+
+    fn submit(self: &Arc<Self>, job: Job) {
+        let mut state = self.state.lock().unwrap();
+        state.jobs.push_back(job);
+        if state.idle >= state.jobs.len() || state.threads >= self.size {
+            self.wakeup.notify_one();
+            return;
+        }
+        state.threads += 1;
+        drop(state);
+        let pool = self.clone();
+        let res = std::thread::Builder::new()
+            .name(format!("setuid-{}", self.uid))
+            .spawn(move || pool.worker());
+        if let Err(e) = res {
+            error!("setuid pool: cannot start a thread: {}", e);
+            let mut state = self.state.lock().unwrap();
+            state.threads -= 1;
+            // without threads, the call is run here, as the user.
+            if state.threads == 0 {
+                let job = state.jobs.pop_back();
+                drop(state);
+                if let Some(job) = job {
+                    self.creds.run(job);
+                }
+            }
+        }
+    }
+
+    fn worker(&self) {
+        // the switch back, when the thread exits, does not matter.
+        let _guard = self.creds.guard();
+        // LocalFs looks at the runtime it is called from.
+        let _enter = self.runtime.as_ref().map(|r| r.enter());
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let job = match state.jobs.pop_front() {
+                Some(job) => job,
+                None => {
+                    state.idle += 1;
+                    let (s, timeout) = self.wakeup.wait_timeout(state, self.idle).unwrap();
+                    state = s;
+                    state.idle -= 1;
+                    if timeout.timed_out() && state.jobs.is_empty() {
+                        state.threads -= 1;
+                        return;
+                    }
+                    continue;
+                },
+            };
+            drop(state);
+            self.jobs.fetch_add(1, Ordering::Relaxed);
+            JOBS.fetch_add(1, Ordering::Relaxed);
+            // a panic is reported to the caller, the thread goes on.
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            state = self.state.lock().unwrap();
+        }
+    }
+
+    fn stats(&self) -> Stats {
+        let state = self.state.lock().unwrap();
+        Stats {
+            uid:     self.uid,
+            threads: state.threads,
+            idle:    state.idle,
+            queued:  state.jobs.len(),
+            jobs:    self.jobs.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl std::fmt::Debug for Pool {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Pool").field("uid", &self.uid).field("size", &self.size).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suidpool() {
+        let pool = Arc::new(Pool::new(None, 2, Duration::from_millis(200)));
+        let calls = (0..8).map(|i| pool.run(move || i * 2));
+        let results = futures::executor::block_on(futures::future::join_all(calls));
+        assert_eq!(results, vec![0, 2, 4, 6, 8, 10, 12, 14]);
+        let stats = pool.stats();
+        assert!(stats.threads >= 1 && stats.threads <= 2);
+        assert_eq!((stats.jobs, stats.queued), (8, 0));
+
+        // the threads exit when they are idle.
+        std::thread::sleep(Duration::from_millis(600));
+        assert_eq!(pool.stats().threads, 0);
+        assert_eq!(futures::executor::block_on(pool.run(|| "again")), "again");
+    }
+}
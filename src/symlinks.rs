@@ -0,0 +1,193 @@
+//
+// Where symbolic links in a filesystem location may lead.
+//
+// "deny" does not follow any symlink below the directory of the location,
+// "follow-within-root" only those that stay below it, and "follow" (the
+// default) all of them. A path is resolved the way the kernel would open
+// it, with openat2() and RESOLVE_NO_SYMLINKS or RESOLVE_BENEATH; on
+// kernels without openat2 (before 5.6) the links are read one by one.
+// With follow-within-root a symlink with an absolute target is not
+// followed, wherever it points.
+//
+// The directory of the location itself may be a symlink, and so may the
+// directories above it.
+//
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::config::Symlinks;
+
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+const RESOLVE_BENEATH: u64 = 0x08;
+
+#[repr(C)]
+struct OpenHow {
+    flags:   u64,
+    mode:    u64,
+    resolve: u64,
+}
+
+// The name in a directory, ignoring case, as a case-insensitive LocalFs
+// would find it.
+fn find_name(dir: &Path, name: &std::ffi::OsStr) -> Option<PathBuf> {
+    let lname = name.to_string_lossy().to_lowercase();
+    std::fs::read_dir(dir).ok()?.filter_map(|e| e.ok()).find_map(|e| {
+        match e.file_name().to_string_lossy().to_lowercase() == lname {
+            true => Some(PathBuf::from(e.file_name())),
+            false => None,
+        }
+    })
+}
+
+// The path below the root with the names as they are on disk.
+fn real_path(root: &Path, rel: &Path) -> PathBuf {
+    let mut real = PathBuf::new();
+    for comp in rel.components() {
+        let name = match comp {
+            Component::Normal(name) => name,
+            _ => continue,
+        };
+        match std::fs::symlink_metadata(root.join(&real).join(name)) {
+            Ok(_) => real.push(name),
+            Err(_) => real.push(find_name(&root.join(&real), name).unwrap_or_else(|| name.into())),
+        }
+    }
+    real
+}
+
+// openat2(), if the kernel has it. The path is relative to the root.
+fn openat2(root: &Path, rel: &Path, resolve: u64) -> Option<io::Result<()>> {
+    let croot = CString::new(root.as_os_str().as_bytes()).ok()?;
+    let crel = CString::new(rel.as_os_str().as_bytes()).ok()?;
+    let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC;
+    let dirfd = unsafe { libc::open(croot.as_ptr(), flags) };
+    if dirfd < 0 {
+        return Some(Err(io::Error::last_os_error()));
+    }
+    let how = OpenHow {
+        flags:   (libc::O_PATH | libc::O_CLOEXEC) as u64,
+        mode:    0,
+        resolve: resolve | RESOLVE_NO_MAGICLINKS,
+    };
+    let size = std::mem::size_of::<OpenHow>();
+    let fd = unsafe { libc::syscall(libc::SYS_openat2, dirfd, crel.as_ptr(), &how as *const OpenHow, size) };
+    let res = match fd {
+        fd if fd >= 0 => {
+            unsafe { libc::close(fd as libc::c_int) };
+            Ok(())
+        },
+        _ => Err(io::Error::last_os_error()),
+    };
+    unsafe { libc::close(dirfd) };
+    match res {
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOSYS) => None,
+        res => Some(res),
+    }
+}
+
+// Without openat2: look at every symlink on the way.
+fn walk(root: &Path, rel: &Path, policy: Symlinks) -> bool {
+    let croot = match root.canonicalize() {
+        Ok(croot) => croot,
+        Err(_) => return true,
+    };
+    let mut path = root.to_path_buf();
+    for comp in rel.components() {
+        match comp {
+            Component::Normal(name) => path.push(name),
+            _ => continue,
+        }
+        let target = match std::fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => std::fs::read_link(&path),
+            Ok(_) => continue,
+            Err(_) => return true,
+        };
+        match (policy, target) {
+            (Symlinks::FollowWithinRoot, Ok(target)) if target.is_relative() => {},
+            _ => return false,
+        }
+        // not a link that points nowhere: a PUT would create its target.
+        match path.canonicalize() {
+            Ok(cpath) if cpath.starts_with(&croot) => path = cpath,
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Can the path below the root be used, with this policy. A path that
+/// does not exist (yet) can, if its parent can.
+pub fn allowed(root: &Path, rel: &Path, policy: Symlinks, case_insensitive: bool) -> bool {
+    let resolve = match policy {
+        Symlinks::Follow => return true,
+        Symlinks::Deny => RESOLVE_NO_SYMLINKS,
+        Symlinks::FollowWithinRoot => RESOLVE_BENEATH,
+    };
+    let real;
+    let mut rel = match case_insensitive {
+        true => {
+            real = real_path(root, rel);
+            real.as_path()
+        },
+        false => rel,
+    };
+    loop {
+        if rel.as_os_str().is_empty() {
+            return true;
+        }
+        match openat2(root, rel, resolve) {
+            None => return walk(root, rel, policy),
+            Some(Ok(())) => return true,
+            Some(Err(e)) => {
+                match e.raw_os_error() {
+                    Some(libc::ELOOP) | Some(libc::EXDEV) => return false,
+                    Some(libc::ENOENT) => {},
+                    // EACCES and such: the call itself will fail.
+                    _ => return true,
+                }
+            },
+        }
+        // a dangling symlink ends up here too: its target was checked.
+        rel = rel.parent().unwrap_or_else(|| Path::new(""));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_symlinks() {
+        let dir = std::env::temp_dir().join(format!("symlinks-test-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(dir.join("outside")).unwrap();
+        symlink("sub", root.join("inside")).unwrap();
+        symlink("../outside", root.join("escape")).unwrap();
+        symlink(dir.join("root/sub"), root.join("absolute")).unwrap();
+        symlink("../nowhere", root.join("dangling")).unwrap();
+
+        let ok = |rel: &str, policy| allowed(&root, Path::new(rel), policy, false);
+        for policy in [Symlinks::Deny, Symlinks::FollowWithinRoot, Symlinks::Follow].iter().copied() {
+            assert!(ok("", policy));
+            assert!(ok("sub", policy));
+            assert!(ok("sub/new/file", policy));
+            assert!(ok("escape", policy) == (policy == Symlinks::Follow));
+            assert!(ok("escape/file", policy) == (policy == Symlinks::Follow));
+            assert!(ok("dangling", policy) == (policy == Symlinks::Follow));
+            assert!(ok("absolute", policy) == (policy == Symlinks::Follow));
+        }
+        assert!(!ok("inside/x", Symlinks::Deny));
+        assert!(ok("inside/x", Symlinks::FollowWithinRoot));
+        assert!(!walk(&root, Path::new("escape"), Symlinks::FollowWithinRoot));
+        assert!(walk(&root, Path::new("inside"), Symlinks::FollowWithinRoot));
+        assert!(!walk(&root, Path::new("inside"), Symlinks::Deny));
+        assert_eq!(real_path(&root, Path::new("SUB/x")), Path::new("sub/x"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
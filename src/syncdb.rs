@@ -0,0 +1,733 @@
+//
+// Collection synchronization (RFC 6578): a journal of changes in an
+// SQLite database, and the DAV:sync-collection report.
+//
+// Every change made through the server is recorded with the path and a
+// sequence number, and only the last change of a path is kept. A
+// sync-token is the sequence number of the last change in the database:
+// the report returns the members that changed, or were removed, after it.
+//
+// Removals are dropped from the journal after sync-retention days. Tokens
+// from before that are refused with DAV:valid-sync-token, and the client
+// starts over with an empty token. Changes made outside the server are
+// only seen with watch = true (see inotify.rs). The journal is keyed by
+// the directory of the location ("root"), like the dead properties.
+//
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::future::{BoxFuture, FutureExt};
+use futures::StreamExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use ring::rand::{SecureRandom, SystemRandom};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+use xmltree::Element;
+
+use crate::report::{self, Multistatus};
+use crate::trashfs::join;
+
+lazy_static::lazy_static! {
+    static ref JOURNALS: Mutex<HashMap<String, Journal>> = Mutex::new(HashMap::new());
+}
+
+const TOKEN_PREFIX: &str = "urn:webdav-server-rs:sync:";
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+// The properties of a member, if the report does not ask for any.
+const PROPS: &[&str] = &["getetag", "getcontentlength", "getlastmodified", "resourcetype"];
+
+/// The journal in database file "path", opened on first use.
+pub fn open(path: &str) -> io::Result<Journal> {
+    let mut journals = JOURNALS.lock().unwrap();
+    if let Some(journal) = journals.get(path) {
+        return Ok(journal.clone());
+    }
+    let journal = Journal::open(path)?;
+    journals.insert(path.to_string(), journal.clone());
+    Ok(journal)
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn unix_time(t: SystemTime) -> i64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+// The path without a trailing slash. "/" stays "/".
+fn key(path: &[u8]) -> &[u8] {
+    match path.len() {
+        0 | 1 => b"/",
+        n if path[n - 1] == b'/' => &path[..n - 1],
+        _ => path,
+    }
+}
+
+// Is "path" below (and not equal to) "dir".
+fn is_below(dir: &[u8], path: &[u8]) -> bool {
+    path != dir && (dir == b"/" || (path.starts_with(dir) && path[dir.len()] == b'/'))
+}
+
+// Is "path" a member of "dir".
+fn is_member(dir: &[u8], path: &[u8]) -> bool {
+    let name = if dir == b"/" { 1 } else { dir.len() + 1 };
+    is_below(dir, path) && !path[name..].contains(&b'/')
+}
+
+/// A key from the journal (or a path from inotify), as path.
+pub fn to_path(path: &[u8]) -> FsResult<DavPath> {
+    let segs = path.split(|&c| c == b'/').map(|s| percent_encode(s, NON_ALPHANUMERIC).to_string());
+    DavPath::new(&segs.collect::<Vec<_>>().join("/")).map_err(|_| FsError::GeneralFailure)
+}
+
+/// Changed paths, as (path, removed).
+pub type Changes = Vec<(Vec<u8>, bool)>;
+
+#[derive(Clone)]
+pub struct Journal(Arc<Inner>);
+
+struct Inner {
+    db:     Mutex<rusqlite::Connection>,
+    // identifies the database in the tokens.
+    id:     String,
+    // when the removals of a root were last purged.
+    purged: Mutex<HashMap<String, Instant>>,
+}
+
+impl Journal {
+    /// Open the database, and create the tables if needed.
+    pub fn open(path: &str) -> io::Result<Journal> {
+        let db = rusqlite::Connection::open(path).map_err(sql_error)?;
+        db.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS sync_id (
+                 id TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS changes (
+                 seq     INTEGER PRIMARY KEY AUTOINCREMENT,
+                 root    TEXT NOT NULL,
+                 path    BLOB NOT NULL,
+                 deleted INTEGER NOT NULL,
+                 time    INTEGER NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS changes_path ON changes (root, path);
+             CREATE TABLE IF NOT EXISTS purged (
+                 root TEXT NOT NULL PRIMARY KEY,
+                 seq  INTEGER NOT NULL
+             );",
+        )
+        .map_err(sql_error)?;
+        let id = db.query_row("SELECT id FROM sync_id", [], |row| row.get::<_, String>(0));
+        let id = match id {
+            Ok(id) => id,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                let mut b = [0u8; 8];
+                SystemRandom::new().fill(&mut b).expect("random");
+                let id = b.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+                db.execute("INSERT INTO sync_id (id) VALUES (?1)", [&id]).map_err(sql_error)?;
+                id
+            },
+            Err(e) => return Err(sql_error(e)),
+        };
+        Ok(Journal(Arc::new(Inner {
+            db: Mutex::new(db),
+            id,
+            purged: Mutex::new(HashMap::new()),
+        })))
+    }
+
+    /// Record a change of "path". A removal replaces the changes below it.
+    pub fn record(&self, root: &str, path: &[u8], deleted: bool) -> io::Result<()> {
+        let path = key(path);
+        let mut below = path.to_vec();
+        below.push(b'/');
+        let mut db = self.0.db.lock().unwrap();
+        let tx = db.transaction().map_err(sql_error)?;
+        tx.execute("DELETE FROM changes WHERE root = ?1 AND path = ?2", rusqlite::params![root, path])
+            .map_err(sql_error)?;
+        if deleted {
+            tx.execute(
+                "DELETE FROM changes WHERE root = ?1 AND substr(path, 1, ?2) = ?3",
+                rusqlite::params![root, below.len() as i64, below],
+            )
+            .map_err(sql_error)?;
+        }
+        tx.execute(
+            "INSERT INTO changes (root, path, deleted, time) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![root, path, deleted, unix_time(SystemTime::now())],
+        )
+        .map_err(sql_error)?;
+        tx.commit().map_err(sql_error)
+    }
+
+    // The sequence number of the last change.
+    fn last(db: &rusqlite::Connection) -> rusqlite::Result<i64> {
+        let sql = "SELECT seq FROM sqlite_sequence WHERE name = 'changes'";
+        let seq = db.query_row(sql, [], |row| row.get(0));
+        match seq {
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(0),
+            other => other,
+        }
+    }
+
+    /// The current token.
+    pub fn token(&self) -> io::Result<String> {
+        let db = self.0.db.lock().unwrap();
+        let seq = Journal::last(&db).map_err(sql_error)?;
+        Ok(format!("{}{}:{}", TOKEN_PREFIX, self.0.id, seq))
+    }
+
+    /// The changes of members of "dir" (or everything below it, with
+    /// "deep") since "token". None if the token is not valid (anymore).
+    pub fn changes(
+        &self,
+        root: &str,
+        dir: &[u8],
+        deep: bool,
+        token: &str,
+    ) -> io::Result<Option<Changes>>
+    {
+        let since = match token.strip_prefix(TOKEN_PREFIX).and_then(|t| t.split_once(':')) {
+            Some((id, seq)) if id == self.0.id => seq.parse::<i64>().ok(),
+            _ => None,
+        };
+        let since = match since {
+            Some(seq) => seq,
+            None => return Ok(None),
+        };
+        let db = self.0.db.lock().unwrap();
+        let purged = db.query_row("SELECT seq FROM purged WHERE root = ?1", [root], |row| row.get(0));
+        let purged: i64 = match purged {
+            Ok(seq) => seq,
+            Err(rusqlite::Error::QueryReturnedNoRows) => 0,
+            Err(e) => return Err(sql_error(e)),
+        };
+        if since < purged || since > Journal::last(&db).map_err(sql_error)? {
+            return Ok(None);
+        }
+        let mut stmt = db
+            .prepare_cached("SELECT path, deleted FROM changes WHERE root = ?1 AND seq > ?2 ORDER BY seq")
+            .map_err(sql_error)?;
+        let rows = stmt
+            .query_map(rusqlite::params![root, since], |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get(1)?)))
+            .map_err(sql_error)?;
+        let dir = key(dir);
+        let mut changes = Vec::new();
+        for row in rows {
+            let (path, deleted) = row.map_err(sql_error)?;
+            if (deep && is_below(dir, &path)) || is_member(dir, &path) {
+                changes.push((path, deleted));
+            }
+        }
+        Ok(Some(changes))
+    }
+
+    /// Make all the tokens of "root" invalid, after changes were missed.
+    pub fn invalidate(&self, root: &str) -> io::Result<()> {
+        // a change of its own, so that the current token is an old one.
+        self.record(root, b"/", false)?;
+        let db = self.0.db.lock().unwrap();
+        let seq = Journal::last(&db).map_err(sql_error)?;
+        db.execute(
+            "INSERT INTO purged (root, seq) VALUES (?1, ?2)
+             ON CONFLICT (root) DO UPDATE SET seq = MAX(seq, excluded.seq)",
+            rusqlite::params![root, seq],
+        )
+        .map_err(sql_error)?;
+        Ok(())
+    }
+
+    /// Drop the removals of "root" older than "days", at most once per
+    /// interval. Tokens from before the last one dropped are then invalid.
+    pub fn purge(&self, root: &str, days: u64) -> io::Result<()> {
+        {
+            let mut purged = self.0.purged.lock().unwrap();
+            let now = Instant::now();
+            if purged.get(root).map(|t| now - *t < PURGE_INTERVAL).unwrap_or(false) {
+                return Ok(());
+            }
+            purged.insert(root.to_string(), now);
+        }
+        let cutoff = unix_time(SystemTime::now()) - (days * 86400) as i64;
+        let mut db = self.0.db.lock().unwrap();
+        let tx = db.transaction().map_err(sql_error)?;
+        let seq: Option<i64> = tx
+            .query_row(
+                "SELECT MAX(seq) FROM changes WHERE root = ?1 AND deleted = 1 AND time < ?2",
+                rusqlite::params![root, cutoff],
+                |row| row.get(0),
+            )
+            .map_err(sql_error)?;
+        let seq = match seq {
+            Some(seq) => seq,
+            None => return Ok(()),
+        };
+        tx.execute(
+            "DELETE FROM changes WHERE root = ?1 AND deleted = 1 AND seq <= ?2",
+            rusqlite::params![root, seq],
+        )
+        .map_err(sql_error)?;
+        tx.execute(
+            "INSERT INTO purged (root, seq) VALUES (?1, ?2)
+             ON CONFLICT (root) DO UPDATE SET seq = MAX(seq, excluded.seq)",
+            rusqlite::params![root, seq],
+        )
+        .map_err(sql_error)?;
+        tx.commit().map_err(sql_error)
+    }
+}
+
+/// A filesystem that records its changes in the journal.
+#[derive(Clone)]
+pub struct SyncFs {
+    fs:        Box<dyn DavFileSystem>,
+    journal:   Journal,
+    root:      String,
+    retention: u64,
+}
+
+impl SyncFs {
+    /// Removals are kept for "retention" days, 0 is forever.
+    pub fn new(fs: Box<dyn DavFileSystem>, journal: Journal, root: &str, retention: u64) -> Box<SyncFs> {
+        Box::new(SyncFs {
+            fs,
+            journal,
+            root: root.to_string(),
+            retention,
+        })
+    }
+
+    /// Also record the changes made outside the server, in directory "dir"
+    /// (see inotify.rs).
+    pub fn watch(&self, dir: &std::path::Path) {
+        let (journal, root, retention) = (self.journal.clone(), self.root.clone(), self.retention);
+        let hook = move |change: crate::inotify::Change| {
+            let res = match change {
+                crate::inotify::Change::Path(path, deleted) => {
+                    journal.record(&root, path, deleted).and_then(|_| {
+                        match deleted && retention > 0 {
+                            true => journal.purge(&root, retention),
+                            false => Ok(()),
+                        }
+                    })
+                },
+                crate::inotify::Change::Lost => journal.invalidate(&root),
+            };
+            if let Err(e) = res {
+                error!("sync-db: {}: {}", root, e);
+            }
+        };
+        tokio::task::block_in_place(|| crate::inotify::watch(dir, "sync-db", Box::new(hook)));
+    }
+
+    // Record a change after it happened, so a failure is only logged.
+    fn record(&self, path: &DavPath, deleted: bool) {
+        let res = tokio::task::block_in_place(|| {
+            self.journal.record(&self.root, path.as_bytes(), deleted)?;
+            match deleted && self.retention > 0 {
+                true => self.journal.purge(&self.root, self.retention),
+                false => Ok(()),
+            }
+        });
+        if let Err(e) = res {
+            error!("sync-db: {}: {}", path.as_url_string(), e);
+        }
+    }
+
+    /// The current sync token, for the DAV:sync-token property.
+    pub fn token(&self) -> Option<String> {
+        match tokio::task::block_in_place(|| self.journal.token()) {
+            Ok(token) => Some(token),
+            Err(e) => {
+                error!("sync-db: {}", e);
+                None
+            },
+        }
+    }
+
+    // Add the members of "dir" (and everything below it, with "deep").
+    fn walk<'a>(
+        &'a self,
+        multi: &'a mut Multistatus,
+        seen: &'a mut HashSet<Vec<u8>>,
+        dir: &'a DavPath,
+        deep: bool,
+        wanted: &'a [(String, String)],
+        prefix: &'a str,
+    ) -> BoxFuture<'a, FsResult<()>>
+    {
+        async move {
+            let mut entries = self.fs.read_dir(dir, ReadDirMeta::Data).await?;
+            let mut dirs = Vec::new();
+            while let Some(entry) = entries.next().await {
+                let path = join(dir, &entry.name())?;
+                let meta = match entry.metadata().await {
+                    Ok(meta) => meta,
+                    Err(_) => continue,
+                };
+                if !seen.insert(key(path.as_bytes()).to_vec()) {
+                    continue;
+                }
+                multi.resource(prefix, &path, &*meta, wanted);
+                if deep && meta.is_dir() {
+                    dirs.push(path);
+                }
+            }
+            for dir in dirs {
+                self.walk(multi, seen, &dir, deep, wanted, prefix).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// The DAV:sync-collection report on the collection "path".
+    pub async fn report(&self, root: &Element, path: &DavPath, prefix: &str) -> http::Response<String> {
+        let deep = match root.get_child("sync-level").and_then(|e| e.get_text()) {
+            Some(level) if level.trim() == "1" => false,
+            Some(level) if level.trim() == "infinite" => true,
+            _ => return report::error(StatusCode::BAD_REQUEST),
+        };
+        let token = root.get_child("sync-token").and_then(|e| e.get_text()).unwrap_or_default();
+        let token = token.trim();
+        match self.fs.metadata(path).await {
+            Ok(meta) if meta.is_dir() => {},
+            Ok(_) => return report::condition(StatusCode::FORBIDDEN, "supported-report"),
+            Err(e) => return report::error(report::status(e)),
+        }
+        let wanted = report::wanted(root, PROPS);
+        let mut multi = Multistatus::new();
+        let mut seen = HashSet::new();
+
+        // the new token first, so that changes made meanwhile are in the next report.
+        let changes = tokio::task::block_in_place(|| -> io::Result<_> {
+            let new_token = self.journal.token()?;
+            match token.is_empty() {
+                true => Ok(Some((new_token, None))),
+                false => {
+                    let changes = self.journal.changes(&self.root, path.as_bytes(), deep, token)?;
+                    Ok(changes.map(|c| (new_token, Some(c))))
+                },
+            }
+        });
+        let (new_token, changes) = match changes {
+            Ok(Some(c)) => c,
+            Ok(None) => return report::condition(StatusCode::FORBIDDEN, "valid-sync-token"),
+            Err(e) => {
+                error!("sync-db: {}: {}", path.as_url_string(), e);
+                return report::error(StatusCode::INTERNAL_SERVER_ERROR);
+            },
+        };
+
+        let res = match changes {
+            None => self.walk(&mut multi, &mut seen, path, deep, &wanted, prefix).await,
+            Some(changes) => self.changed(&mut multi, &mut seen, changes, deep, &wanted, prefix).await,
+        };
+        if let Err(e) = res {
+            return report::error(report::status(e));
+        }
+        let extra = format!("<D:sync-token>{}</D:sync-token>\n", report::escape(&new_token));
+        multi.finish(&extra)
+    }
+
+    // Add the changed and removed paths. A changed collection, with
+    // "deep", is added with everything below it.
+    async fn changed(
+        &self,
+        multi: &mut Multistatus,
+        seen: &mut HashSet<Vec<u8>>,
+        changes: Changes,
+        deep: bool,
+        wanted: &[(String, String)],
+        prefix: &str,
+    ) -> FsResult<()>
+    {
+        for (key, deleted) in changes {
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            let path = to_path(&key)?;
+            let meta = match deleted {
+                true => Err(FsError::NotFound),
+                false => self.fs.metadata(&path).await,
+            };
+            match meta {
+                Ok(meta) => {
+                    multi.resource(prefix, &path, &*meta, wanted);
+                    if deep && meta.is_dir() {
+                        self.walk(multi, seen, &path, deep, wanted, prefix).await?;
+                    }
+                },
+                Err(FsError::NotFound) => {
+                    multi.status(&report::href(prefix, &path, false), StatusCode::NOT_FOUND);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Records a change when a file was written to.
+#[derive(Debug)]
+struct SyncFile {
+    file:    Box<dyn DavFile>,
+    fs:      SyncFs,
+    path:    DavPath,
+    written: bool,
+}
+
+impl std::fmt::Debug for SyncFs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SyncFs").field("root", &self.root).finish()
+    }
+}
+
+impl DavFile for SyncFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.file.metadata()
+    }
+
+    fn write_buf<'a>(&'a mut self, buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        self.written = true;
+        self.file.write_buf(buf)
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        self.written = true;
+        self.file.write_bytes(buf)
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        self.file.read_bytes(count)
+    }
+
+    fn seek<'a>(&'a mut self, pos: std::io::SeekFrom) -> FsFuture<'a, u64> {
+        self.file.seek(pos)
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        async move {
+            self.file.flush().await?;
+            if std::mem::replace(&mut self.written, false) {
+                self.fs.record(&self.path, false);
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+impl DavFileSystem for SyncFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let write = options.write || options.append || options.create || options.create_new;
+            let file = self.fs.open(path, options).await?;
+            if !write {
+                return Ok(file);
+            }
+            self.record(path, false);
+            Ok(Box::new(SyncFile {
+                file,
+                fs: self.clone(),
+                path: path.clone(),
+                written: false,
+            }) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.create_dir(path).await?;
+            self.record(path, false);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.remove_dir(path).await?;
+            self.record(path, true);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.remove_file(path).await?;
+            self.record(path, true);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.rename(from, to).await?;
+            self.record(from, true);
+            self.record(to, false);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.copy(from, to).await?;
+            self.record(to, false);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.set_modified(path, tm).await?;
+            self.record(path, false);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        async move {
+            let res = self.fs.patch_props(path, patch).await?;
+            self.record(path, false);
+            Ok(res)
+        }
+        .boxed()
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_members() {
+        assert!(is_member(b"/", b"/a"));
+        assert!(!is_member(b"/", b"/a/b"));
+        assert!(is_member(b"/a", b"/a/b"));
+        assert!(!is_member(b"/a", b"/ab"));
+        assert!(!is_member(b"/a", b"/a"));
+        assert!(is_below(b"/a", b"/a/b/c"));
+        assert!(!is_below(b"/", b"/"));
+        assert_eq!(key(b"/a/"), b"/a");
+    }
+
+    #[test]
+    fn test_journal() {
+        let journal = Journal::open(":memory:").unwrap();
+        let start = journal.token().unwrap();
+        journal.record("r", b"/a/x", false).unwrap();
+        journal.record("r", b"/a/d/y", false).unwrap();
+        journal.record("r", b"/b", false).unwrap();
+        let changes = journal.changes("r", b"/a/", false, &start).unwrap().unwrap();
+        assert_eq!(changes, vec![(b"/a/x".to_vec(), false)]);
+        let changes = journal.changes("r", b"/a", true, &start).unwrap().unwrap();
+        assert_eq!(changes.len(), 2);
+
+        let token = journal.token().unwrap();
+        journal.record("r", b"/a/d", true).unwrap();
+        let changes = journal.changes("r", b"/a", true, &start).unwrap().unwrap();
+        assert_eq!(changes, vec![(b"/a/x".to_vec(), false), (b"/a/d".to_vec(), true)]);
+        let changes = journal.changes("r", b"/a", true, &token).unwrap().unwrap();
+        assert_eq!(changes, vec![(b"/a/d".to_vec(), true)]);
+        assert!(journal.changes("r", b"/a", true, "urn:other").unwrap().is_none());
+
+        journal.invalidate("r").unwrap();
+        assert!(journal.changes("r", b"/a", true, &token).unwrap().is_none());
+        let token = journal.token().unwrap();
+        assert_eq!(journal.changes("r", b"/", true, &token).unwrap(), Some(vec![]));
+    }
+
+    // With watch = true, a file written outside of the server is in the
+    // next sync-collection report.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_watch() {
+        let dir = std::env::temp_dir().join(format!("syncdb-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        let toml = format!(
+            "[server]\n[[location]]\nroute = [ \"/*path\" ]\nhandler = \"filesystem\"\ndirectory = {:?}\n\
+             methods = [ \"webdav-ro\" ]\nsync-db = {:?}\nwatch = true\n",
+            dir.join("data").to_str().unwrap(),
+            dir.join("sync.db").to_str().unwrap()
+        );
+        let server = crate::builder::Builder::from_toml(&toml).build().unwrap();
+        tokio::spawn(crate::inotify::run());
+        let peer: std::net::SocketAddr = "127.0.0.1:4711".parse().unwrap();
+        let report = |token: &str| {
+            let body = format!(
+                "<?xml version=\"1.0\"?><D:sync-collection xmlns:D=\"DAV:\">\
+                 <D:sync-token>{}</D:sync-token><D:sync-level>1</D:sync-level></D:sync-collection>",
+                token
+            );
+            http::Request::builder().method("REPORT").uri("/").body(hyper::Body::from(body)).unwrap()
+        };
+        let body = |resp: http::Response<_>| async move {
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        let resp = body(server.route(report(""), peer).await.unwrap()).await;
+        let token = resp.split("<D:sync-token>").nth(1).and_then(|t| t.split('<').next()).unwrap();
+        std::fs::write(dir.join("data/outside.txt"), "x\n").unwrap();
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        let resp = body(server.route(report(token), peer).await.unwrap()).await;
+        assert!(resp.contains("/outside.txt"), "{}", resp);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+//
+// systemd socket activation, see sd_listen_fds(3).
+//
+// Sockets named "https" (FileDescriptorName= in the .socket unit) are
+// TLS listeners, all other sockets serve plain HTTP.
+//
+use std::env;
+use std::io;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+pub struct Socket {
+    pub name:     String,
+    pub addr:     String,
+    pub tls:      bool,
+    pub listener: Listener,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+// Is this a SOCK_STREAM socket.
+fn is_stream(fd: RawFd) -> io::Result<bool> {
+    let mut ty: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ty_ptr = &mut ty as *mut libc::c_int as *mut libc::c_void;
+    // SAFETY: ty and len are valid for the duration of the call.
+    if unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, ty_ptr, &mut len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ty == libc::SOCK_STREAM)
+}
+
+/// Take the sockets that systemd passed to us, if any.
+///
+/// The LISTEN_* environment variables are removed, so that they
+/// are not inherited by child processes.
+pub fn listen_fds() -> io::Result<Vec<Socket>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let nfds = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let nfds = match (pid, nfds) {
+        (Some(pid), Some(nfds)) if pid.parse::<u32>().ok() == Some(std::process::id()) => nfds,
+        _ => return Ok(Vec::new()),
+    };
+    let nfds = nfds
+        .parse::<RawFd>()
+        .map_err(|_| invalid(format!("LISTEN_FDS: invalid value {}", nfds)))?;
+    let names: Vec<&str> = names.split(':').collect();
+
+    let mut sockets = Vec::new();
+    for idx in 0..nfds {
+        let fd = SD_LISTEN_FDS_START + idx;
+        let name = names.get(idx as usize).copied().unwrap_or("").to_string();
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(io::Error::other)?;
+        if !is_stream(fd)? {
+            return Err(invalid(format!("fd {} ({}): not a stream socket", fd, name)));
+        }
+
+        // SAFETY: systemd passed this fd to us, nothing else owns it.
+        let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+        socket.set_nonblocking(true)?;
+        let local = socket.local_addr()?;
+        let (addr, listener) = match local.as_socket() {
+            Some(sockaddr) => {
+                let listener: std::net::TcpListener = socket.into();
+                (format!("{:?}", sockaddr), Listener::Tcp(listener))
+            },
+            None if local.family() == libc::AF_UNIX as libc::sa_family_t => {
+                // SAFETY: we own the fd, and it is a unix socket.
+                let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(socket.into_raw_fd()) };
+                let path = listener.local_addr()?;
+                let path = path.as_pathname().map(|p| p.display().to_string());
+                (format!("unix:{}", path.unwrap_or_default()), Listener::Unix(listener))
+            },
+            None => return Err(invalid(format!("fd {} ({}): unsupported address family", fd, name))),
+        };
+        sockets.push(Socket {
+            tls: name == "https",
+            name,
+            addr,
+            listener,
+        });
+    }
+    Ok(sockets)
+}
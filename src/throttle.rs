@@ -0,0 +1,171 @@
+//
+// Login throttling.
+//
+// Failed logins are counted per (remote ip, username) and per remote ip.
+// Once a counter reaches its limit, further attempts are refused for a
+// while. That period doubles with every additional failure, up to a
+// maximum. A successful login resets the (ip, username) counter.
+//
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+// Start cleaning up old entries when the map grows beyond this.
+const CLEANUP_THRESHOLD: usize = 1024;
+
+// (remote ip, username). The username is None for the per-ip counter.
+type Key = (IpAddr, Option<String>);
+
+#[derive(Debug)]
+struct Entry {
+    failures:     u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+pub struct Throttle {
+    enabled:         bool,
+    max_failures:    u32,
+    max_failures_ip: u32,
+    backoff:         Duration,
+    max_lockout:     Duration,
+    forget:          Duration,
+    map:             Mutex<HashMap<Key, Entry>>,
+}
+
+impl Throttle {
+    pub fn new(cfg: &config::Throttle) -> Throttle {
+        Throttle {
+            enabled:         cfg.enabled.unwrap_or(true),
+            max_failures:    cfg.max_failures.unwrap_or(5),
+            max_failures_ip: cfg.max_failures_ip.unwrap_or(20),
+            backoff:         Duration::from_secs(cfg.backoff.unwrap_or(1)),
+            max_lockout:     Duration::from_secs(cfg.max_lockout.unwrap_or(300)),
+            forget:          Duration::from_secs(cfg.forget.unwrap_or(900)),
+            map:             Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn check_at(&self, ip: IpAddr, user: &str, now: Instant) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        let map = self.map.lock().unwrap();
+        let locked = |key: &Key| {
+            map.get(key)
+                .and_then(|e| e.locked_until)
+                .map(|t| t > now)
+                .unwrap_or(false)
+        };
+        !locked(&(ip, Some(user.to_string()))) && !locked(&(ip, None))
+    }
+
+    fn failure_at(&self, ip: IpAddr, user: &str, now: Instant) {
+        if !self.enabled {
+            return;
+        }
+        let mut map = self.map.lock().unwrap();
+        if map.len() > CLEANUP_THRESHOLD {
+            let forget = self.forget;
+            map.retain(|_, e| {
+                now.duration_since(e.last_failure) < forget ||
+                    e.locked_until.map(|t| t > now).unwrap_or(false)
+            });
+        }
+        let keys = [
+            ((ip, Some(user.to_string())), self.max_failures),
+            ((ip, None), self.max_failures_ip),
+        ];
+        for (key, max) in keys.iter().cloned() {
+            if max == 0 {
+                continue;
+            }
+            let entry = map.entry(key).or_insert(Entry {
+                failures:     0,
+                last_failure: now,
+                locked_until: None,
+            });
+            if now.duration_since(entry.last_failure) >= self.forget {
+                entry.failures = 0;
+                entry.locked_until = None;
+            }
+            entry.failures += 1;
+            entry.last_failure = now;
+            if entry.failures >= max {
+                let shift = std::cmp::min(entry.failures - max, 16);
+                let lockout = std::cmp::min(self.backoff * (1u32 << shift), self.max_lockout);
+                entry.locked_until = Some(now + lockout);
+            }
+        }
+    }
+
+    /// Is this ip / user allowed to try to login.
+    pub fn check(&self, ip: IpAddr, user: &str) -> bool {
+        self.check_at(ip, user, Instant::now())
+    }
+
+    /// Record a failed login.
+    pub fn failure(&self, ip: IpAddr, user: &str) {
+        self.failure_at(ip, user, Instant::now())
+    }
+
+    /// Record a successful login.
+    pub fn success(&self, ip: IpAddr, user: &str) {
+        if self.enabled {
+            self.map.lock().unwrap().remove(&(ip, Some(user.to_string())));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let cfg = config::Throttle {
+            max_failures: Some(3),
+            max_failures_ip: Some(10),
+            ..config::Throttle::default()
+        };
+        let t = Throttle::new(&cfg);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+
+        t.failure_at(ip, "bob", now);
+        t.failure_at(ip, "bob", now);
+        assert!(t.check_at(ip, "bob", now));
+        t.failure_at(ip, "bob", now);
+        assert!(!t.check_at(ip, "bob", now));
+        assert!(t.check_at(ip, "bob", now + Duration::from_secs(1)));
+        assert!(t.check_at(ip, "alice", now));
+
+        // next failure doubles the lockout.
+        t.failure_at(ip, "bob", now);
+        assert!(!t.check_at(ip, "bob", now + Duration::from_secs(1)));
+        assert!(t.check_at(ip, "bob", now + Duration::from_secs(2)));
+
+        // a successful login resets the counter.
+        t.success(ip, "bob");
+        assert!(t.check_at(ip, "bob", now));
+    }
+
+    #[test]
+    fn test_per_ip() {
+        let cfg = config::Throttle {
+            max_failures_ip: Some(3),
+            ..config::Throttle::default()
+        };
+        let t = Throttle::new(&cfg);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+        for user in &["a", "b", "c"] {
+            t.failure_at(ip, user, now);
+        }
+        assert!(!t.check_at(ip, "d", now));
+        assert!(t.check_at("192.0.2.2".parse().unwrap(), "d", now));
+    }
+}
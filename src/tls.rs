@@ -1,39 +1,259 @@
 use std::fs::File;
 use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
 
+use tokio::signal::unix::{signal, SignalKind};
 use tokio_rustls::rustls::internal::pemfile;
-use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate, ClientHello,
+    NoClientAuth, ResolvesServerCert, RootCertStore, ServerConfig,
+};
+use x509_parser::extensions::GeneralName;
 
-use crate::config::Server;
+use crate::acme::{Acme, ACME_TLS_ALPN};
+use crate::config::{hostname_matches, Config, Server, TlsClientAuth, TlsClientUser};
 
-pub fn tls_config(cfg: &Server) -> io::Result<ServerConfig> {
-    let pkey_fn = cfg.tls_key.as_ref().ok_or_else(|| {
-        io::Error::new(io::ErrorKind::NotFound, "config: server: tls_key not set")
-    })?;
-    let cert_fn = cfg.tls_cert.as_ref().ok_or_else(|| {
-        io::Error::new(io::ErrorKind::NotFound, "config: server: tls_cert not set")
-    })?;
-    let pkey_file = File::open(pkey_fn).map_err(|e| {
+// How often to check if the certificate files have changed.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn tls_config(cfg: &Config, certs: &Arc<Certs>, acme: Option<&Acme>) -> io::Result<ServerConfig> {
+    let mut config = new_server_config(&cfg.server)?;
+
+    // ALPN.
+    let mut protocols = Vec::new();
+    if cfg.server.http2.unwrap_or(true) {
+        protocols.push(b"h2".to_vec());
+    }
+    protocols.push(b"http/1.1".to_vec());
+
+    // With ACME, the default certificate is provided by the ACME client.
+    let acme = acme.map(|acme| {
+        protocols.push(ACME_TLS_ALPN.to_vec());
+        acme.resolver() as Arc<dyn ResolvesServerCert>
+    });
+    config.set_protocols(&protocols);
+
+    config.cert_resolver = Arc::new(SniResolver {
+        certs: certs.clone(),
+        acme,
+    });
+    Ok(config)
+}
+
+// A certificate and key loaded from files.
+struct CertFile {
+    cert_fn: String,
+    pkey_fn: String,
+    mtimes:  Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+    key:     RwLock<CertifiedKey>,
+}
+
+impl CertFile {
+    fn load(cert_fn: &str, pkey_fn: &str) -> io::Result<CertFile> {
+        let mtimes = Self::mtimes(cert_fn, pkey_fn);
+        Ok(CertFile {
+            cert_fn: cert_fn.to_string(),
+            pkey_fn: pkey_fn.to_string(),
+            key:     RwLock::new(load_cert(cert_fn, pkey_fn)?),
+            mtimes:  Mutex::new(mtimes),
+        })
+    }
+
+    fn mtimes(cert_fn: &str, pkey_fn: &str) -> (Option<SystemTime>, Option<SystemTime>) {
+        let mtime = |name| std::fs::metadata(name).and_then(|m| m.modified()).ok();
+        (mtime(cert_fn), mtime(pkey_fn))
+    }
+
+    // Reload if the files have changed. On error, keep using the old certificate.
+    fn reload(&self, force: bool) {
+        let mtimes = Self::mtimes(&self.cert_fn, &self.pkey_fn);
+        {
+            let mut old = self.mtimes.lock().unwrap();
+            if !force && *old == mtimes {
+                return;
+            }
+            *old = mtimes;
+        }
+        match load_cert(&self.cert_fn, &self.pkey_fn) {
+            Ok(key) => {
+                *self.key.write().unwrap() = key;
+                info!("tls: reloaded {}", self.cert_fn);
+            },
+            Err(e) => error!("tls: reload failed, keeping old certificate: {}", e),
+        }
+    }
+}
+
+/// In the extensions of a request that came in over TLS.
+#[derive(Clone, Copy, Debug)]
+pub struct Https;
+
+/// The certificates from the config file.
+pub struct Certs {
+    default: Option<CertFile>,
+    vhosts:  Vec<(Vec<String>, CertFile)>,
+}
+
+impl Certs {
+    /// Load [server] tls_cert / tls_key and the [[vhost]] certificates.
+    pub fn load(cfg: &Config) -> io::Result<Arc<Certs>> {
+        let default = match (cfg.server.tls_cert.as_ref(), cfg.server.tls_key.as_ref()) {
+            (Some(cert_fn), Some(pkey_fn)) => Some(CertFile::load(cert_fn, pkey_fn)?),
+            _ => None,
+        };
+        let mut vhosts = Vec::new();
+        for vhost in &cfg.vhost {
+            if let (Some(cert_fn), Some(pkey_fn)) = (vhost.tls_cert.as_ref(), vhost.tls_key.as_ref()) {
+                vhosts.push((vhost.hostname.clone(), CertFile::load(cert_fn, pkey_fn)?));
+            }
+        }
+        Ok(Arc::new(Certs { default, vhosts }))
+    }
+
+    /// No certificates configured at all.
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.vhosts.is_empty()
+    }
+
+    fn files(&self) -> impl Iterator<Item = &CertFile> {
+        self.default.iter().chain(self.vhosts.iter().map(|(_, c)| c))
+    }
+
+    /// Reload certificates when the files change, or right away on SIGHUP.
+    pub async fn watch(self: Arc<Self>) {
+        if self.files().next().is_none() {
+            return;
+        }
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("tls: cannot install SIGHUP handler: {}", e);
+                return;
+            },
+        };
+        loop {
+            let force = tokio::select! {
+                _ = sighup.recv() => true,
+                _ = tokio::time::sleep(RELOAD_INTERVAL) => false,
+            };
+            for file in self.files() {
+                file.reload(force);
+            }
+        }
+    }
+}
+
+// Load a certificate chain and its private key.
+fn load_cert(cert_fn: &str, pkey_fn: &str) -> io::Result<CertifiedKey> {
+    let pkey_data = std::fs::read(pkey_fn).map_err(|e| {
         io::Error::new(e.kind(), format!("{}: {}", pkey_fn, e))
     })?;
-    let mut pkey_file = io::BufReader::new(pkey_file);
     let cert_file = File::open(cert_fn).map_err(|e| {
         io::Error::new(e.kind(), format!("{}: {}", cert_fn, e))
     })?;
     let mut cert_file = io::BufReader::new(cert_file);
-    let mut pkey = pemfile::rsa_private_keys(&mut pkey_file).map_err(|_| {
+    // "BEGIN PRIVATE KEY" (PKCS#8, RSA or ECDSA) or "BEGIN RSA PRIVATE KEY" (PKCS#1).
+    let mut pkey = pemfile::pkcs8_private_keys(&mut &pkey_data[..]).map_err(|_| {
         io::Error::new(io::ErrorKind::InvalidData, format!("{}: invalid data", pkey_fn))
     })?;
+    if pkey.is_empty() {
+        pkey = pemfile::rsa_private_keys(&mut &pkey_data[..]).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: invalid data", pkey_fn))
+        })?;
+    }
     if pkey.len() != 1 {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: expected one private key (found {})", pkey_fn, pkey.len())));
+        let msg = format!("{}: expected one private key (found {})", pkey_fn, pkey.len());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
     }
     let cert = pemfile::certs(&mut cert_file).map_err(|_| {
         io::Error::new(io::ErrorKind::InvalidData, format!("{}: invalid data", cert_fn))
     })?;
-    let mut config = ServerConfig::new(NoClientAuth::new());
-    config.set_single_cert(cert, pkey.pop().unwrap()).map_err(|e| {
+    let pkey = sign::any_supported_type(&pkey[0]).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: unsupported key type", pkey_fn))
+    })?;
+    let key = CertifiedKey::new(cert, Arc::new(pkey));
+    key.cross_check_end_entity_cert(None).map_err(|e| {
         io::Error::new(io::ErrorKind::InvalidData, format!("{}/{}: {}", pkey_fn, cert_fn, e))
     })?;
-    Ok(config)
+    Ok(key)
+}
+
+// Picks the certificate of the [[vhost]] that matches the SNI name,
+// and falls back to the default certificate.
+struct SniResolver {
+    certs: Arc<Certs>,
+    acme:  Option<Arc<dyn ResolvesServerCert>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello) -> Option<CertifiedKey> {
+        // ACME tls-alpn-01 challenges are answered by the ACME resolver.
+        let acme_alpn = hello.alpn().map(|a| a.contains(&ACME_TLS_ALPN)).unwrap_or(false);
+        if !acme_alpn {
+            if let Some(name) = hello.server_name() {
+                let name: &str = name.into();
+                let found = self
+                    .certs
+                    .vhosts
+                    .iter()
+                    .find(|(names, _)| names.iter().any(|n| hostname_matches(n, name)));
+                if let Some((_, file)) = found {
+                    return Some(file.key.read().unwrap().clone());
+                }
+            }
+        }
+        match self.acme {
+            Some(ref acme) => acme.resolve(hello),
+            None => Some(self.certs.default.as_ref()?.key.read().unwrap().clone()),
+        }
+    }
+}
+
+// New ServerConfig, with client certificate verification if configured.
+fn new_server_config(cfg: &Server) -> io::Result<ServerConfig> {
+    let client_auth = match cfg.tls_client_ca {
+        Some(ref ca_fn) => {
+            let ca_file = File::open(ca_fn).map_err(|e| {
+                io::Error::new(e.kind(), format!("{}: {}", ca_fn, e))
+            })?;
+            let mut roots = RootCertStore::empty();
+            match roots.add_pem_file(&mut io::BufReader::new(ca_file)) {
+                Ok((n, _)) if n > 0 => {},
+                _ => {
+                    let msg = format!("{}: no valid certificates", ca_fn);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+                },
+            }
+            match cfg.tls_client_auth {
+                Some(TlsClientAuth::Optional) => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+                Some(TlsClientAuth::Required) | None => AllowAnyAuthenticatedClient::new(roots),
+            }
+        },
+        None => NoClientAuth::new(),
+    };
+    Ok(ServerConfig::new(client_auth))
 }
 
+// Map a verified client certificate to a username.
+pub fn client_cert_user(cfg: &Server, certs: &[Certificate]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&certs.first()?.0).ok()?;
+    let user = match cfg.tls_client_user.unwrap_or(TlsClientUser::Cn) {
+        TlsClientUser::Cn => cert.subject().iter_common_name().next()?.as_str().ok()?,
+        TlsClientUser::Email | TlsClientUser::Dns => {
+            let san = cert.subject_alternative_name().ok()??;
+            san.value.general_names.iter().find_map(|name| {
+                match (cfg.tls_client_user, name) {
+                    (Some(TlsClientUser::Email), GeneralName::RFC822Name(s)) => Some(*s),
+                    (Some(TlsClientUser::Dns), GeneralName::DNSName(s)) => Some(*s),
+                    _ => None,
+                }
+            })?
+        },
+    };
+    if user.is_empty() {
+        return None;
+    }
+    Some(user.to_string())
+}
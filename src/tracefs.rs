@@ -0,0 +1,219 @@
+//
+// A filesystem that puts a tracing span around every operation.
+//
+// Only used when the OTLP exporter is enabled. An open file has a span
+// from open until it is closed, that counts the bytes read and written,
+// so a GET or PUT shows how long the body took to stream.
+//
+use std::future::Future;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use futures::future::FutureExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use tracing::field::Empty;
+use tracing::{info_span, Instrument};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+#[derive(Clone)]
+pub struct TraceFs {
+    fs:   Box<dyn DavFileSystem>,
+    span: tracing::Span,
+}
+
+impl TraceFs {
+    /// The spans are children of the current span (the request), also
+    /// for operations that run while the response body streams out.
+    pub fn new(fs: Box<dyn DavFileSystem>) -> Box<TraceFs> {
+        let span = tracing::Span::current();
+        Box::new(TraceFs { fs, span })
+    }
+}
+
+impl DavFileSystem for TraceFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        let span = info_span!(
+            parent: &self.span,
+            "fs.file",
+            path = %path.as_url_string(),
+            write = options.write,
+            bytes_read = Empty,
+            bytes_written = Empty
+        );
+        async move {
+            let file = self.fs.open(path, options).instrument(span.clone()).await?;
+            Ok(Box::new(TraceFile {
+                file,
+                span,
+                read: 0,
+                written: 0,
+            }) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        let span = info_span!(parent: &self.span, "fs.read_dir", path = %path.as_url_string());
+        self.fs.read_dir(path, meta).instrument(span).boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        let span = info_span!(parent: &self.span, "fs.metadata", path = %path.as_url_string());
+        self.fs.metadata(path).instrument(span).boxed()
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        let span = info_span!(
+            parent: &self.span,
+            "fs.symlink_metadata",
+            path = %path.as_url_string()
+        );
+        self.fs.symlink_metadata(path).instrument(span).boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        let span = info_span!(parent: &self.span, "fs.create_dir", path = %path.as_url_string());
+        self.fs.create_dir(path).instrument(span).boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        let span = info_span!(parent: &self.span, "fs.remove_dir", path = %path.as_url_string());
+        self.fs.remove_dir(path).instrument(span).boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        let span = info_span!(parent: &self.span, "fs.remove_file", path = %path.as_url_string());
+        self.fs.remove_file(path).instrument(span).boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        let span = info_span!(
+            parent: &self.span,
+            "fs.rename",
+            from = %from.as_url_string(),
+            to = %to.as_url_string()
+        );
+        self.fs.rename(from, to).instrument(span).boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        let span = info_span!(
+            parent: &self.span,
+            "fs.copy",
+            from = %from.as_url_string(),
+            to = %to.as_url_string()
+        );
+        self.fs.copy(from, to).instrument(span).boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        let span = info_span!(parent: &self.span, "fs.patch_props", path = %path.as_url_string());
+        self.fs.patch_props(path, patch).instrument(span).boxed()
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        let span = info_span!(parent: &self.span, "fs.get_props", path = %path.as_url_string());
+        self.fs.get_props(path, do_content).instrument(span).boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        let span = info_span!(parent: &self.span, "fs.get_prop", path = %path.as_url_string());
+        self.fs.get_prop(path, prop).instrument(span).boxed()
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        let span = info_span!(parent: &self.span, "fs.get_quota");
+        self.fs.get_quota().instrument(span).boxed()
+    }
+}
+
+#[derive(Debug)]
+struct TraceFile {
+    file:    Box<dyn DavFile>,
+    span:    tracing::Span,
+    read:    u64,
+    written: u64,
+}
+
+impl Drop for TraceFile {
+    fn drop(&mut self) {
+        self.span.record("bytes_read", self.read);
+        self.span.record("bytes_written", self.written);
+    }
+}
+
+impl DavFile for TraceFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        let span = self.span.clone();
+        self.file.metadata().instrument(span).boxed()
+    }
+
+    fn write_buf<'a>(&'a mut self, buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        let span = self.span.clone();
+        async move {
+            let count = buf.remaining() as u64;
+            self.file.write_buf(buf).await?;
+            self.written += count;
+            Ok(())
+        }
+        .instrument(span)
+        .boxed()
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        let span = self.span.clone();
+        async move {
+            let count = buf.len() as u64;
+            self.file.write_bytes(buf).await?;
+            self.written += count;
+            Ok(())
+        }
+        .instrument(span)
+        .boxed()
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        let span = self.span.clone();
+        async move {
+            let data = self.file.read_bytes(count).await?;
+            self.read += data.len() as u64;
+            Ok(data)
+        }
+        .instrument(span)
+        .boxed()
+    }
+
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> FsFuture<'a, u64> {
+        self.file.seek(pos)
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        let span = self.span.clone();
+        self.file.flush().instrument(span).boxed()
+    }
+}
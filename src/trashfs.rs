@@ -0,0 +1,424 @@
+//
+// Trash: files that are deleted, or overwritten by a MOVE or COPY, are
+// moved to a trash directory in the location instead of being removed.
+//
+// Every request that deletes something gets its own directory in the
+// trash, named after the time ("20210601-120000.123"), with the paths of
+// the deleted files in it. Directories in the trash that are older than
+// the retention time are removed. That is checked at most once an hour,
+// when something is deleted, and by the janitor if there is one.
+//
+// The trash is hidden, or, with trash-visible, a read-only collection:
+// files can be restored with a MOVE out of it, and removed for good with
+// a DELETE in it.
+//
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::StreamExt;
+use http::StatusCode;
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+const RETENTION_DAYS: u64 = 30;
+const STAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+lazy_static::lazy_static! {
+    // when each trash was last purged.
+    static ref PURGED: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone)]
+pub struct TrashFs {
+    fs:        Box<dyn DavFileSystem>,
+    name:      String,
+    visible:   bool,
+    retention: Option<Duration>,
+    // identifies the trash, for PURGED.
+    key:       String,
+    // the directory in the trash for this request.
+    stamp:     String,
+}
+
+/// How long trash is kept, by trash-retention in days: 30 if not set,
+/// and forever (None) with 0.
+pub fn retention(days: Option<u64>) -> Option<Duration> {
+    match days.unwrap_or(RETENTION_DAYS) {
+        0 => None,
+        days => Some(Duration::from_secs(days * 86400)),
+    }
+}
+
+/// The name of a directory in the trash (or of a version), from the time.
+pub fn stamp(tm: &time::Tm) -> String {
+    let secs = time::strftime(STAMP_FORMAT, tm).unwrap_or_default();
+    format!("{}.{:03}", secs, tm.tm_nsec / 1_000_000)
+}
+
+/// The unix time of a directory in the trash.
+pub fn stamp_time(name: &str) -> Option<i64> {
+    let secs = name.get(..15)?;
+    time::strptime(secs, STAMP_FORMAT).ok().map(|tm| tm.to_timespec().sec)
+}
+
+pub fn encode(name: &str) -> String {
+    percent_encode(name.as_bytes(), NON_ALPHANUMERIC).to_string()
+}
+
+pub fn join(path: &DavPath, name: &[u8]) -> FsResult<DavPath> {
+    let url = path.as_url_string();
+    let name = percent_encode(name, NON_ALPHANUMERIC);
+    DavPath::new(&format!("{}/{}", url.trim_end_matches('/'), name)).map_err(|_| FsError::GeneralFailure)
+}
+
+/// Is "path" in the directory "name" in the root.
+pub fn in_dir(path: &DavPath, name: &str) -> bool {
+    let first = path.as_bytes().get(1..).and_then(|p| p.split(|&c| c == b'/').next());
+    first == Some(name.as_bytes())
+}
+
+/// Create a directory and its parents.
+pub async fn create_dirs(fs: &dyn DavFileSystem, path: &DavPath) -> FsResult<()> {
+    let url = path.as_url_string();
+    let mut dir = String::new();
+    for seg in url.split('/').filter(|s| !s.is_empty()) {
+        dir.push('/');
+        dir.push_str(seg);
+        let path = DavPath::new(&dir).map_err(|_| FsError::GeneralFailure)?;
+        match fs.create_dir(&path).await {
+            Ok(()) | Err(FsError::Exists) => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+impl TrashFs {
+    /// "name" is the directory of the trash, in the root of the location.
+    /// Trash older than "retention" days is removed, see `retention`.
+    pub fn new(
+        fs: Box<dyn DavFileSystem>,
+        name: &str,
+        visible: bool,
+        retention: Option<u64>,
+        key: &str,
+    ) -> Box<TrashFs>
+    {
+        Box::new(TrashFs {
+            fs,
+            name: name.trim_matches('/').to_string(),
+            visible,
+            retention: self::retention(retention),
+            key: format!("{}:{}", key, name),
+            stamp: stamp(&time::now_utc()),
+        })
+    }
+
+    fn in_trash(&self, path: &DavPath) -> bool {
+        in_dir(path, &self.name)
+    }
+
+    fn is_trash_root(&self, path: &DavPath) -> bool {
+        let p = path.as_bytes();
+        let p = p.strip_suffix(b"/").unwrap_or(p);
+        p.get(1..) == Some(self.name.as_bytes())
+    }
+
+    // Access to the trash itself: hidden, or read-only.
+    fn check_read(&self, path: &DavPath) -> FsResult<()> {
+        match self.in_trash(path) && !self.visible {
+            true => Err(FsError::NotFound),
+            false => Ok(()),
+        }
+    }
+
+    fn check_write(&self, path: &DavPath) -> FsResult<()> {
+        self.check_read(path)?;
+        match self.in_trash(path) {
+            true => Err(FsError::Forbidden),
+            false => Ok(()),
+        }
+    }
+
+    // Where "path" goes in the trash.
+    fn trash_path(&self, path: &DavPath) -> FsResult<DavPath> {
+        let url = path.as_url_string();
+        let url = format!("/{}/{}{}", encode(&self.name), self.stamp, url.trim_end_matches('/'));
+        DavPath::new(&url).map_err(|_| FsError::GeneralFailure)
+    }
+
+    // Move a file to the trash.
+    async fn trash_file(&self, path: &DavPath) -> FsResult<()> {
+        let dest = self.trash_path(path)?;
+        let parent = dest.as_url_string();
+        let parent = &parent[..parent.rfind('/').unwrap_or(0)];
+        create_dirs(&*self.fs, &DavPath::new(parent).map_err(|_| FsError::GeneralFailure)?).await?;
+        self.fs.rename(path, &dest).await?;
+        self.purge();
+        Ok(())
+    }
+
+    // A file that is overwritten by a MOVE or COPY. Directories were
+    // already deleted by the handler, through remove_file and remove_dir.
+    async fn trash_target(&self, path: &DavPath) -> FsResult<()> {
+        match self.fs.symlink_metadata(path).await {
+            Ok(meta) if !meta.is_dir() => self.trash_file(path).await,
+            _ => Ok(()),
+        }
+    }
+
+    // Remove a directory, and keep an empty one in the trash. Its
+    // contents were moved to the trash before.
+    async fn trash_dir(&self, path: &DavPath) -> FsResult<()> {
+        create_dirs(&*self.fs, &self.trash_path(path)?).await?;
+        self.fs.remove_dir(path).await?;
+        self.purge();
+        Ok(())
+    }
+
+    // Remove old trash in the background, at most once per interval.
+    fn purge(&self) {
+        let retention = match self.retention {
+            Some(r) => r,
+            None => return,
+        };
+        {
+            let mut purged = PURGED.lock().unwrap();
+            let now = Instant::now();
+            if purged.get(&self.key).map(|t| now - *t < PURGE_INTERVAL).unwrap_or(false) {
+                return;
+            }
+            purged.insert(self.key.clone(), now);
+        }
+        let fs = self.fs.clone();
+        let name = self.name.clone();
+        tokio::spawn(async move { remove_old(&*fs, &name, retention).await });
+    }
+}
+
+/// Remove the directories in the trash "name" that are older than
+/// "retention". The number that were removed.
+pub async fn remove_old(fs: &dyn DavFileSystem, name: &str, retention: Duration) -> usize {
+    let root = match DavPath::new(&format!("/{}/", encode(name))) {
+        Ok(p) => p,
+        Err(_) => return 0,
+    };
+    let mut entries = match fs.read_dir(&root, ReadDirMeta::None).await {
+        Ok(e) => e,
+        Err(_) => return 0,
+    };
+    let cutoff = time::now_utc().to_timespec().sec - retention.as_secs() as i64;
+    let mut old = Vec::new();
+    while let Some(entry) = entries.next().await {
+        let name = String::from_utf8_lossy(&entry.name()).into_owned();
+        if stamp_time(&name).map(|t| t < cutoff).unwrap_or(false) {
+            old.push(entry.name());
+        }
+    }
+    let mut removed = 0;
+    for name in old {
+        if let Ok(path) = join(&root, &name) {
+            match remove_tree(fs, path.clone()).await {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("trash: purge {}: {:?}", path.as_url_string(), e),
+            }
+        }
+    }
+    removed
+}
+
+/// Remove a file, or a directory with everything in it.
+pub fn remove_tree(fs: &dyn DavFileSystem, path: DavPath) -> BoxFuture<'_, FsResult<()>> {
+    async move {
+        let meta = fs.symlink_metadata(&path).await?;
+        if !meta.is_dir() {
+            return fs.remove_file(&path).await;
+        }
+        let mut names = Vec::new();
+        let mut entries = fs.read_dir(&path, ReadDirMeta::None).await?;
+        while let Some(entry) = entries.next().await {
+            names.push(entry.name());
+        }
+        for name in names {
+            remove_tree(fs, join(&path, &name)?).await?;
+        }
+        fs.remove_dir(&path).await
+    }
+    .boxed()
+}
+
+impl DavFileSystem for TrashFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            if options.write || options.append || options.create || options.create_new {
+                self.check_write(path)?;
+            } else {
+                self.check_read(path)?;
+            }
+            self.fs.open(path, options).await
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            self.check_read(path)?;
+            let strm = self.fs.read_dir(path, meta).await?;
+            if self.visible || path.as_bytes() != b"/" {
+                return Ok(strm);
+            }
+            let name = self.name.as_bytes().to_vec();
+            let strm = strm.filter(move |e| future::ready(e.name() != name));
+            Ok(Box::pin(strm) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            self.check_read(path)?;
+            self.fs.metadata(path).await
+        }
+        .boxed()
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            self.check_read(path)?;
+            self.fs.symlink_metadata(path).await
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check_write(path)?;
+            self.fs.create_dir(path).await
+        }
+        .boxed()
+    }
+
+    // In the trash, this removes it for good.
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check_read(path)?;
+            match self.in_trash(path) {
+                true => self.fs.remove_dir(path).await,
+                false => self.trash_dir(path).await,
+            }
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check_read(path)?;
+            match self.in_trash(path) {
+                true => self.fs.remove_file(path).await,
+                false => self.trash_file(path).await,
+            }
+        }
+        .boxed()
+    }
+
+    // Moving out of the trash restores a file.
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check_read(from)?;
+            self.check_write(to)?;
+            if self.is_trash_root(from) {
+                return Err(FsError::Forbidden);
+            }
+            self.trash_target(to).await?;
+            self.fs.rename(from, to).await
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check_read(from)?;
+            self.check_write(to)?;
+            self.trash_target(to).await?;
+            self.fs.copy(from, to).await
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            self.check_write(path)?;
+            self.fs.set_accessed(path, tm).await
+        }
+        .boxed()
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            self.check_write(path)?;
+            self.fs.set_modified(path, tm).await
+        }
+        .boxed()
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        async move {
+            self.check_write(path)?;
+            self.fs.patch_props(path, patch).await
+        }
+        .boxed()
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        async move {
+            self.check_read(path)?;
+            self.fs.get_props(path, do_content).await
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        async move {
+            self.check_read(path)?;
+            self.fs.get_prop(path, prop).await
+        }
+        .boxed()
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stamp() {
+        let tm = time::at_utc(time::Timespec::new(1622548800, 123_000_000));
+        assert_eq!(stamp(&tm), "20210601-120000.123");
+        assert_eq!(stamp_time("20210601-120000.123"), Some(1622548800));
+        assert_eq!(stamp_time("junk"), None);
+    }
+}
@@ -0,0 +1,453 @@
+//
+// Resumable uploads with the tus protocol (https://tus.io, 1.0.0), on a
+// location with tus = true. The endpoint is "/.tus/" in the location.
+//
+// An upload is created with POST, with its Upload-Length, and the name
+// of the file as "filename" in Upload-Metadata. It is a path in the
+// location, so it can be in a directory. The data is written to
+// "/.tus/<id>" with PATCH, and can be resumed at the Upload-Offset from
+// HEAD after the connection broke. When the upload is complete, the
+// server PUTs it to its name, with the headers of the last request, so
+// that locks and If: headers count as for any PUT. If that fails, the
+// PATCH can be sent again, without data. Uploads that are not finished
+// are removed by the janitor after a while.
+//
+// Supported extensions: creation, creation-with-upload, termination.
+//
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::time::SystemTime;
+
+use futures::StreamExt;
+use http::StatusCode;
+use hyper::body::HttpBody;
+use ring::rand::SecureRandom;
+use serde::{Deserialize, Serialize};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::{DavFileSystem, FsError, FsResult, OpenOptions, ReadDirMeta};
+use webdav_handler::DavMethod;
+
+use crate::report;
+use crate::trashfs::{self, join};
+
+const DIR: &str = ".tus";
+const VERSION: &str = "1.0.0";
+const EXTENSIONS: &str = "creation,creation-with-upload,termination";
+const OFFSET_TYPE: &str = "application/offset+octet-stream";
+
+// An upload, stored next to its data as "<id>.json".
+#[derive(Serialize, Deserialize)]
+pub struct Upload {
+    length:   u64,
+    name:     String,
+    metadata: String,
+}
+
+/// POST is taken as PUT, for routing and permissions.
+pub fn dav_method(method: &http::Method) -> Option<DavMethod> {
+    match method.as_str() {
+        "POST" => Some(DavMethod::Put),
+        _ => None,
+    }
+}
+
+/// Is this a request on the endpoint.
+pub fn is_tus(path: &DavPath) -> bool {
+    trashfs::in_dir(path, DIR)
+}
+
+/// A response, or an upload that is complete.
+pub enum Reply {
+    Response(http::Response<String>),
+    Complete(Complete),
+}
+
+/// A complete upload: `put_request` for the server, then `finish`.
+pub struct Complete {
+    parts:   http::request::Parts,
+    id:      String,
+    upload:  Upload,
+    // the POST that created it (201 Created, and Location).
+    created: Option<String>,
+}
+
+impl Complete {
+    /// The PUT of the data to the name of the upload.
+    pub fn put_request(
+        &mut self,
+        fs: Box<dyn DavFileSystem>,
+        prefix: &str,
+    ) -> Option<http::Request<hyper::Body>>
+    {
+        let to = target(&self.upload.name)?;
+        let uri = format!("{}{}", prefix.trim_end_matches('/'), to.as_url_string());
+        let mut parts = std::mem::replace(&mut self.parts, http::Request::new(()).into_parts().0);
+        parts.method = http::Method::PUT;
+        parts.uri = uri.parse().ok()?;
+        let tus = ["Upload-Offset", "Upload-Length", "Upload-Metadata"];
+        for name in ["Content-Type", "Transfer-Encoding"].iter().chain(&tus) {
+            parts.headers.remove(*name);
+        }
+        parts.headers.insert("Content-Length", self.upload.length.into());
+        let (data, _) = paths(&self.id).ok()?;
+        let body = crate::ocupload::body(fs, vec![(data, self.upload.length)]);
+        Some(http::Request::from_parts(parts, body))
+    }
+
+    /// After the PUT: the upload is removed if it worked, the response
+    /// has the status of the PUT if not.
+    pub async fn finish(self, fs: &dyn DavFileSystem, status: StatusCode) -> http::Response<String> {
+        let done = status.is_success();
+        if done || self.created.is_some() {
+            if let Ok((data, info)) = paths(&self.id) {
+                let _ = fs.remove_file(&info).await;
+                let _ = fs.remove_file(&data).await;
+            }
+        }
+        if !done {
+            debug!("tus: {}: PUT to {}: {}", self.id, self.upload.name, status);
+            return response(status);
+        }
+        let mut resp = response(StatusCode::NO_CONTENT);
+        resp.headers_mut().insert("Upload-Offset", self.upload.length.into());
+        if let Some(location) = self.created.and_then(|l| l.parse().ok()) {
+            *resp.status_mut() = StatusCode::CREATED;
+            resp.headers_mut().insert("Location", location);
+        }
+        resp
+    }
+}
+
+fn response(status: StatusCode) -> http::Response<String> {
+    let mut resp = report::error(status);
+    resp.headers_mut().insert("Tus-Resumable", VERSION.parse().unwrap());
+    resp
+}
+
+fn header<'a>(req: &'a http::Request<hyper::Body>, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|h| h.to_str().ok()).map(str::trim)
+}
+
+// The value of a key in Upload-Metadata: "key base64,key base64".
+fn metadata(meta: &str, key: &str) -> Option<String> {
+    let value = meta.split(',').find_map(|kv| {
+        let mut kv = kv.trim().splitn(2, ' ');
+        match kv.next() == Some(key) {
+            true => Some(kv.next().unwrap_or_default()),
+            false => None,
+        }
+    })?;
+    String::from_utf8(base64::decode(value.trim()).ok()?).ok()
+}
+
+// The path of a file name from the metadata. It must stay in the
+// location, and not be in the endpoint.
+fn target(name: &str) -> Option<DavPath> {
+    let mut path = DavPath::new("/").ok()?;
+    for segment in name.split('/').filter(|s| !s.is_empty()) {
+        if segment == "." || segment == ".." {
+            return None;
+        }
+        path = join(&path, segment.as_bytes()).ok()?;
+    }
+    match path.as_bytes() == b"/" || is_tus(&path) {
+        true => None,
+        false => Some(path),
+    }
+}
+
+fn new_id() -> String {
+    let mut id = [0u8; 16];
+    ring::rand::SystemRandom::new().fill(&mut id).unwrap();
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn endpoint() -> DavPath {
+    DavPath::new(&format!("/{}", DIR)).unwrap()
+}
+
+// The data and the description of an upload.
+fn paths(id: &str) -> FsResult<(DavPath, DavPath)> {
+    if id.is_empty() || !id.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(FsError::NotFound);
+    }
+    let dir = endpoint();
+    Ok((join(&dir, id.as_bytes())?, join(&dir, format!("{}.json", id).as_bytes())?))
+}
+
+async fn read_upload(fs: &dyn DavFileSystem, path: &DavPath) -> FsResult<Upload> {
+    let options = OpenOptions {
+        read: true,
+        ..OpenOptions::default()
+    };
+    let mut file = fs.open(path, options).await?;
+    let mut data = Vec::new();
+    loop {
+        let buf = file.read_bytes(4096).await?;
+        if buf.is_empty() || data.len() > 65536 {
+            break;
+        }
+        data.extend_from_slice(&buf);
+    }
+    serde_json::from_slice(&data).map_err(|_| FsError::GeneralFailure)
+}
+
+async fn write_upload(fs: &dyn DavFileSystem, path: &DavPath, upload: &Upload) -> FsResult<()> {
+    let options = OpenOptions {
+        write: true,
+        create_new: true,
+        ..OpenOptions::default()
+    };
+    let mut file = fs.open(path, options).await?;
+    let data = serde_json::to_vec(upload).map_err(|_| FsError::GeneralFailure)?;
+    file.write_bytes(data.into()).await?;
+    file.flush().await
+}
+
+/// Handle a request on the endpoint. "path" is the path of the request,
+/// "prefix" the prefix of the location, and "max_size" the largest upload.
+pub async fn handle(
+    req: http::Request<hyper::Body>,
+    fs: &dyn DavFileSystem,
+    path: &DavPath,
+    prefix: &str,
+    max_size: Option<u64>,
+) -> Reply
+{
+    if req.method() == http::Method::OPTIONS {
+        let mut resp = response(StatusCode::NO_CONTENT);
+        let headers = resp.headers_mut();
+        headers.insert("Tus-Version", VERSION.parse().unwrap());
+        headers.insert("Tus-Extension", EXTENSIONS.parse().unwrap());
+        if let Some(max) = max_size {
+            headers.insert("Tus-Max-Size", max.into());
+        }
+        return Reply::Response(resp);
+    }
+    if header(&req, "Tus-Resumable") != Some(VERSION) {
+        let mut resp = response(StatusCode::PRECONDITION_FAILED);
+        resp.headers_mut().insert("Tus-Version", VERSION.parse().unwrap());
+        return Reply::Response(resp);
+    }
+
+    let id = report::file_name(path);
+    let res = match req.method().as_str() {
+        "POST" if id == DIR => create(req, fs, prefix, max_size).await,
+        "HEAD" if id != DIR => status(fs, &id).await.map(Reply::Response),
+        "PATCH" if id != DIR => {
+            match header(&req, "Content-Type") {
+                Some(t) if t == OFFSET_TYPE => append(req, fs, &id).await,
+                _ => Err(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            }
+        },
+        "DELETE" if id != DIR => {
+            match paths(&id) {
+                Ok((data, info)) => {
+                    let _ = fs.remove_file(&info).await;
+                    fs.remove_file(&data).await.map(|_| Reply::Response(response(StatusCode::NO_CONTENT)))
+                },
+                Err(e) => Err(e),
+            }
+            .map_err(report::status)
+        },
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    };
+    res.unwrap_or_else(|status| Reply::Response(response(status)))
+}
+
+/// Remove the uploads that were last written to before "cutoff". The
+/// number that were removed.
+pub async fn expire(fs: &dyn DavFileSystem, cutoff: SystemTime) -> usize {
+    let mut entries = match fs.read_dir(&endpoint(), ReadDirMeta::Data).await {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    // the last write of an upload, to its data or the description.
+    let mut uploads: HashMap<String, SystemTime> = HashMap::new();
+    while let Some(entry) = entries.next().await {
+        let name = String::from_utf8_lossy(&entry.name()).into_owned();
+        let id = name.strip_suffix(".json").unwrap_or(&name).to_string();
+        if let Ok(modified) = entry.metadata().await.and_then(|m| m.modified()) {
+            let last = uploads.entry(id).or_insert(modified);
+            *last = (*last).max(modified);
+        }
+    }
+    let mut removed = 0;
+    for (id, _) in uploads.into_iter().filter(|(_, last)| *last < cutoff) {
+        if let Ok((data, info)) = paths(&id) {
+            let _ = fs.remove_file(&info).await;
+            match fs.remove_file(&data).await {
+                Ok(()) | Err(FsError::NotFound) => removed += 1,
+                Err(e) => warn!("tus: remove {}: {:?}", data.as_url_string(), e),
+            }
+        }
+    }
+    removed
+}
+
+// POST: create an upload, with data if there is a body.
+async fn create(
+    req: http::Request<hyper::Body>,
+    fs: &dyn DavFileSystem,
+    prefix: &str,
+    max_size: Option<u64>,
+) -> Result<Reply, StatusCode>
+{
+    let length = match header(&req, "Upload-Length").map(|l| l.parse::<u64>()) {
+        Some(Ok(length)) => length,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+    if max_size.map(|max| length > max).unwrap_or(false) {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let meta = header(&req, "Upload-Metadata").unwrap_or_default().to_string();
+    let name = match metadata(&meta, "filename").or_else(|| metadata(&meta, "name")) {
+        Some(name) => name,
+        None => return Err(StatusCode::BAD_REQUEST),
+    };
+    if target(&name).is_none() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    match fs.create_dir(&endpoint()).await {
+        Ok(()) | Err(FsError::Exists) => {},
+        Err(e) => return Err(report::status(e)),
+    }
+    let id = new_id();
+    let (data, info) = paths(&id).map_err(report::status)?;
+    let upload = Upload {
+        length,
+        name,
+        metadata: meta,
+    };
+    write_upload(fs, &info, &upload).await.map_err(report::status)?;
+    let options = OpenOptions {
+        write: true,
+        create_new: true,
+        ..OpenOptions::default()
+    };
+    let mut file = fs.open(&data, options).await.map_err(report::status)?;
+    file.flush().await.map_err(report::status)?;
+    drop(file);
+
+    let location = format!("{}/{}/{}", prefix, DIR, id);
+    let with_data = header(&req, "Content-Type") == Some(OFFSET_TYPE);
+    let mut resp = match with_data {
+        true => {
+            match append(req, fs, &id).await {
+                Ok(Reply::Response(resp)) => resp,
+                Ok(Reply::Complete(mut complete)) => {
+                    complete.created = Some(location);
+                    return Ok(Reply::Complete(complete));
+                },
+                Err(status) => {
+                    let _ = fs.remove_file(&info).await;
+                    let _ = fs.remove_file(&data).await;
+                    return Err(status);
+                },
+            }
+        },
+        false => response(StatusCode::CREATED),
+    };
+    *resp.status_mut() = StatusCode::CREATED;
+    resp.headers_mut().insert("Location", location.parse().unwrap());
+    Ok(Reply::Response(resp))
+}
+
+// HEAD: the offset and length of an upload.
+async fn status(fs: &dyn DavFileSystem, id: &str) -> Result<http::Response<String>, StatusCode> {
+    let (data, info) = paths(id).map_err(report::status)?;
+    let upload = read_upload(fs, &info).await.map_err(report::status)?;
+    let offset = fs.metadata(&data).await.map_err(report::status)?.len();
+    let mut resp = response(StatusCode::OK);
+    let headers = resp.headers_mut();
+    headers.insert("Upload-Offset", offset.into());
+    headers.insert("Upload-Length", upload.length.into());
+    if let Ok(meta) = upload.metadata.parse() {
+        headers.insert("Upload-Metadata", meta);
+    }
+    headers.insert("Cache-Control", "no-store".parse().unwrap());
+    Ok(resp)
+}
+
+// PATCH: write the body at Upload-Offset, which must be the end of
+// what was uploaded. What arrived is kept, even if the body is cut off.
+async fn append(
+    req: http::Request<hyper::Body>,
+    fs: &dyn DavFileSystem,
+    id: &str,
+) -> Result<Reply, StatusCode>
+{
+    let (data, info) = paths(id).map_err(report::status)?;
+    let upload = read_upload(fs, &info).await.map_err(report::status)?;
+    let mut offset = fs.metadata(&data).await.map_err(report::status)?.len();
+    // With the POST that creates it, the data is at the start.
+    let start = match req.method() == http::Method::POST {
+        true => Some(Ok(0)),
+        false => header(&req, "Upload-Offset").map(|o| o.parse::<u64>()),
+    };
+    match start {
+        Some(Ok(o)) if o == offset => {},
+        Some(Ok(_)) => return Err(StatusCode::CONFLICT),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    }
+
+    let options = OpenOptions {
+        write: true,
+        ..OpenOptions::default()
+    };
+    let mut file = fs.open(&data, options).await.map_err(report::status)?;
+    file.seek(SeekFrom::Start(offset)).await.map_err(report::status)?;
+    let (parts, mut body) = req.into_parts();
+    let mut result = Ok(());
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) => break,
+        };
+        if offset + chunk.len() as u64 > upload.length {
+            result = Err(StatusCode::BAD_REQUEST);
+            break;
+        }
+        let len = chunk.len() as u64;
+        if let Err(e) = file.write_bytes(chunk).await {
+            result = Err(report::status(e));
+            break;
+        }
+        offset += len;
+    }
+    file.flush().await.map_err(report::status)?;
+    drop(file);
+    result?;
+
+    // Complete? Then the server puts it in place.
+    if offset == upload.length {
+        target(&upload.name).ok_or(StatusCode::BAD_REQUEST)?;
+        return Ok(Reply::Complete(Complete {
+            parts,
+            id: id.to_string(),
+            upload,
+            created: None,
+        }));
+    }
+    let mut resp = response(StatusCode::NO_CONTENT);
+    resp.headers_mut().insert("Upload-Offset", offset.into());
+    Ok(Reply::Response(resp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata() {
+        let meta = "relativePath bnVsbA==,filename ZG9jcy9hLnR4dA==,is_confidential";
+        assert_eq!(metadata(meta, "filename").as_deref(), Some("docs/a.txt"));
+        assert_eq!(metadata(meta, "filetype"), None);
+        assert_eq!(target("docs/a.txt").unwrap().as_url_string(), "/docs/a.txt");
+        assert!(target("../a").is_none());
+        assert!(target("/.tus/x").is_none());
+        assert!(target("").is_none());
+        assert!(paths("../x").is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//
+// Upload limits: max-request-body and max-file-size.
+//
+// If there is a Content-Length the request is refused right away. If
+// there is not, or it was not telling the truth, the limits are checked
+// on every write while the body streams in, and the PUT fails with
+// 413 Payload Too Large as soon as one of them is reached.
+//
+use std::future::Future;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use futures::future::FutureExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::config::{Accounts, Location, Size};
+
+/// The Content-Length of a request, if it has one. Finder sends its PUTs
+/// chunked, with the length in X-Expected-Entity-Length.
+pub fn content_length<B>(req: &http::Request<B>) -> Option<u64> {
+    let headers = req.headers();
+    headers
+        .get(http::header::CONTENT_LENGTH)
+        .or_else(|| headers.get("x-expected-entity-length"))
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<u64>().ok())
+}
+
+/// The max-file-size for this user. A per-user setting comes before
+/// the default, and the location before [accounts].
+pub fn max_file_size(global: &Accounts, location: &Location, user: Option<&str>) -> Option<u64> {
+    let accounts = [&location.accounts, global];
+    let per_user = user.and_then(|u| accounts.iter().find_map(|a| a.max_file_size_users.get(u)));
+    per_user
+        .or_else(|| accounts.iter().find_map(|a| a.max_file_size.as_ref()))
+        .map(|&Size(n)| n)
+}
+
+/// A filesystem that limits the size of the files written to it, and the
+/// number of bytes written to a file in one go.
+#[derive(Clone)]
+pub struct LimitFs {
+    fs:        Box<dyn DavFileSystem>,
+    max_size:  Option<u64>,
+    max_write: Option<u64>,
+}
+
+impl LimitFs {
+    pub fn new(fs: Box<dyn DavFileSystem>, max_size: Option<u64>, max_write: Option<u64>) -> Box<LimitFs> {
+        Box::new(LimitFs {
+            fs,
+            max_size,
+            max_write,
+        })
+    }
+}
+
+impl DavFileSystem for LimitFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let append = options.append;
+            let file = self.fs.open(path, options).await?;
+            Ok(Box::new(LimitFile {
+                file,
+                pos: 0,
+                append,
+                written: 0,
+                max_size: self.max_size,
+                max_write: self.max_write,
+            }) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_dir(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_file(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.rename(from, to)
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.copy(from, to)
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        self.fs.patch_props(path, patch)
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+// A file, that checks the limits before every write.
+#[derive(Debug)]
+struct LimitFile {
+    file:      Box<dyn DavFile>,
+    pos:       u64,
+    append:    bool,
+    written:   u64,
+    max_size:  Option<u64>,
+    max_write: Option<u64>,
+}
+
+impl LimitFile {
+    async fn check(&mut self, count: usize) -> FsResult<()> {
+        let count = count as u64;
+        if self.max_write.map(|max| self.written + count > max).unwrap_or(false) {
+            debug!("uploadlimit: max-request-body reached");
+            return Err(FsError::TooLarge);
+        }
+        if let Some(max) = self.max_size {
+            let start = if self.append { self.file.metadata().await?.len() } else { self.pos };
+            if start + count > max {
+                debug!("uploadlimit: max-file-size reached");
+                return Err(FsError::TooLarge);
+            }
+        }
+        Ok(())
+    }
+
+    fn wrote(&mut self, count: usize) {
+        self.pos += count as u64;
+        self.written += count as u64;
+    }
+}
+
+impl DavFile for LimitFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.file.metadata()
+    }
+
+    fn write_buf<'a>(&'a mut self, buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        async move {
+            let count = buf.remaining();
+            self.check(count).await?;
+            self.file.write_buf(buf).await?;
+            self.wrote(count);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        async move {
+            let count = buf.len();
+            self.check(count).await?;
+            self.file.write_bytes(buf).await?;
+            self.wrote(count);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        async move {
+            let data = self.file.read_bytes(count).await?;
+            self.pos += data.len() as u64;
+            Ok(data)
+        }
+        .boxed()
+    }
+
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> FsFuture<'a, u64> {
+        async move {
+            self.pos = self.file.seek(pos).await?;
+            Ok(self.pos)
+        }
+        .boxed()
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webdav_handler::memfs::MemFs;
+
+    fn write() -> OpenOptions {
+        OpenOptions {
+            write: true,
+            create: true,
+            truncate: true,
+            ..OpenOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let path = DavPath::new("/a").unwrap();
+        let fs = LimitFs::new(MemFs::new(), Some(10), None);
+        let mut f = fs.open(&path, write()).await.unwrap();
+        f.write_bytes(Bytes::from_static(b"123456")).await.unwrap();
+        assert!(f.write_bytes(Bytes::from_static(b"12345")).await.is_err());
+        f.seek(SeekFrom::Start(0)).await.unwrap();
+        f.write_bytes(Bytes::from_static(b"1234567890")).await.unwrap();
+
+        let fs = LimitFs::new(MemFs::new(), None, Some(8));
+        let mut f = fs.open(&path, write()).await.unwrap();
+        f.write_bytes(Bytes::from_static(b"123456")).await.unwrap();
+        f.seek(SeekFrom::Start(0)).await.unwrap();
+        assert!(f.write_bytes(Bytes::from_static(b"123")).await.is_err());
+    }
+}
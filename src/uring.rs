@@ -0,0 +1,470 @@
+//
+// File reads and writes with io_uring (Linux 5.6 and newer).
+//
+// There is one ring for the whole process. Requests are submitted by the
+// task that does the I/O, and a thread waits for the completions and
+// wakes it up. The buffer of a request belongs to the ring until it is
+// completed, so that a task that goes away does not leave the kernel
+// writing into freed memory.
+//
+// If the ring is full, or io_uring cannot be set up, the blocking thread
+// pool is used. Opening files and metadata (stat) are still done there,
+// by LocalFs.
+//
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures::channel::oneshot;
+use futures::future::FutureExt;
+use hyper::body::{Buf, Bytes};
+use libc::c_long;
+use webdav_handler::fs::{DavFile, DavMetaData, FsError, FsFuture};
+
+const ENTRIES: u32 = 256;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+const IORING_FEAT_RW_CUR_POS: u32 = 1 << 3;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqOffsets {
+    head:         u32,
+    tail:         u32,
+    ring_mask:    u32,
+    ring_entries: u32,
+    flags:        u32,
+    dropped:      u32,
+    array:        u32,
+    resv1:        u32,
+    user_addr:    u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqOffsets {
+    head:         u32,
+    tail:         u32,
+    ring_mask:    u32,
+    ring_entries: u32,
+    overflow:     u32,
+    cqes:         u32,
+    flags:        u32,
+    resv1:        u32,
+    user_addr:    u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries:     u32,
+    cq_entries:     u32,
+    flags:          u32,
+    sq_thread_cpu:  u32,
+    sq_thread_idle: u32,
+    features:       u32,
+    wq_fd:          u32,
+    resv:           [u32; 3],
+    sq_off:         SqOffsets,
+    cq_off:         CqOffsets,
+}
+
+#[repr(C)]
+struct Sqe {
+    opcode:    u8,
+    flags:     u8,
+    ioprio:    u16,
+    fd:        i32,
+    off:       u64,
+    addr:      u64,
+    len:       u32,
+    rw_flags:  u32,
+    user_data: u64,
+    pad:       [u64; 3],
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res:       i32,
+    flags:     u32,
+}
+
+// The memory of a request, while the kernel has it.
+enum Buffer {
+    Read(Vec<u8>),
+    Write(Bytes),
+}
+
+struct Request {
+    // keeps the file open.
+    _file:  Arc<File>,
+    buffer: Buffer,
+    done:   oneshot::Sender<(i32, Buffer)>,
+}
+
+// Pointers into the rings, that the kernel shares with us.
+struct Submit {
+    head:    *const AtomicU32,
+    tail:    *const AtomicU32,
+    mask:    u32,
+    array:   *mut u32,
+    sqes:    *mut Sqe,
+    next_id: u64,
+}
+
+struct Complete {
+    head: *const AtomicU32,
+    tail: *const AtomicU32,
+    mask: u32,
+    cqes: *const Cqe,
+}
+
+pub struct Uring {
+    fd:       RawFd,
+    entries:  u32,
+    submit:   Mutex<Submit>,
+    requests: Mutex<HashMap<u64, Request>>,
+}
+
+// The pointers are only used with the locks held, or by the one thread
+// that reaps the completions.
+unsafe impl Send for Submit {}
+unsafe impl Send for Complete {}
+
+lazy_static::lazy_static! {
+    static ref URING: Option<Arc<Uring>> = match Uring::new() {
+        Ok(ring) => {
+            info!("io_uring: enabled");
+            Some(ring)
+        },
+        Err(e) => {
+            info!("io_uring: not available ({}), using the thread pool", e);
+            None
+        },
+    };
+}
+
+/// The ring, if the kernel has it.
+pub fn get() -> Option<Arc<Uring>> {
+    URING.clone()
+}
+
+unsafe fn enter(fd: RawFd, submit: u32, wait: u32, flags: u32) -> c_long {
+    let (fd, submit, wait, flags) = (fd as c_long, submit as c_long, wait as c_long, flags as c_long);
+    libc::syscall(libc::SYS_io_uring_enter, fd, submit, wait, flags, 0 as c_long, 0 as c_long)
+}
+
+unsafe fn map(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<*mut u8> {
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let ptr = libc::mmap(ptr::null_mut(), len, prot, libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset);
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ptr as *mut u8)
+}
+
+impl Uring {
+    fn new() -> io::Result<Arc<Uring>> {
+        let mut p = Params::default();
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, ENTRIES as c_long, &mut p as *mut Params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = fd as RawFd;
+        if p.features & IORING_FEAT_RW_CUR_POS == 0 {
+            unsafe { libc::close(fd) };
+            return Err(io::Error::other("kernel too old"));
+        }
+
+        // The rings are never unmapped; the ring lives as long as we do.
+        let sq_len = p.sq_off.array as usize + p.sq_entries as usize * 4;
+        let cq_len = p.cq_off.cqes as usize + p.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let (sq, cq, sqes) = unsafe {
+            let cleanup = |e| {
+                libc::close(fd);
+                e
+            };
+            let (sq, cq) = if p.features & IORING_FEAT_SINGLE_MMAP != 0 {
+                let sq = map(fd, sq_len.max(cq_len), IORING_OFF_SQ_RING).map_err(cleanup)?;
+                (sq, sq)
+            } else {
+                let sq = map(fd, sq_len, IORING_OFF_SQ_RING).map_err(cleanup)?;
+                (sq, map(fd, cq_len, IORING_OFF_CQ_RING).map_err(cleanup)?)
+            };
+            let sqes_len = p.sq_entries as usize * std::mem::size_of::<Sqe>();
+            (sq, cq, map(fd, sqes_len, IORING_OFF_SQES).map_err(cleanup)?)
+        };
+
+        let (submit, complete) = unsafe {
+            let submit = Submit {
+                head:    sq.add(p.sq_off.head as usize) as *const AtomicU32,
+                tail:    sq.add(p.sq_off.tail as usize) as *const AtomicU32,
+                mask:    *(sq.add(p.sq_off.ring_mask as usize) as *const u32),
+                array:   sq.add(p.sq_off.array as usize) as *mut u32,
+                sqes:    sqes as *mut Sqe,
+                next_id: 0,
+            };
+            let complete = Complete {
+                head: cq.add(p.cq_off.head as usize) as *const AtomicU32,
+                tail: cq.add(p.cq_off.tail as usize) as *const AtomicU32,
+                mask: *(cq.add(p.cq_off.ring_mask as usize) as *const u32),
+                cqes: cq.add(p.cq_off.cqes as usize) as *const Cqe,
+            };
+            (submit, complete)
+        };
+
+        let ring = Arc::new(Uring {
+            fd,
+            entries: p.sq_entries.min(p.cq_entries),
+            submit: Mutex::new(submit),
+            requests: Mutex::new(HashMap::new()),
+        });
+        let reaper = ring.clone();
+        std::thread::Builder::new()
+            .name("io_uring".to_string())
+            .spawn(move || reaper.reap(complete))?;
+        Ok(ring)
+    }
+
+    // Wait for completions, and hand them out.
+    fn reap(&self, cq: Complete) {
+        loop {
+            let r = unsafe { enter(self.fd, 0, 1, IORING_ENTER_GETEVENTS) };
+            if r < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    error!("io_uring: {}", e);
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                }
+            }
+            let mut done = Vec::new();
+            unsafe {
+                let mut head = (*cq.head).load(Ordering::Relaxed);
+                let tail = (*cq.tail).load(Ordering::Acquire);
+                while head != tail {
+                    let cqe = &*cq.cqes.add((head & cq.mask) as usize);
+                    done.push((cqe.user_data, cqe.res));
+                    head = head.wrapping_add(1);
+                }
+                (*cq.head).store(head, Ordering::Release);
+            }
+            let mut requests = self.requests.lock().unwrap();
+            for (id, res) in done {
+                if let Some(req) = requests.remove(&id) {
+                    let _ = req.done.send((res, req.buffer));
+                }
+            }
+        }
+    }
+
+    // Submit a read or a write. Gives the buffer back if the ring is full.
+    fn submit(
+        &self,
+        opcode: u8,
+        file: &Arc<File>,
+        offset: u64,
+        buffer: Buffer,
+    ) -> Result<oneshot::Receiver<(i32, Buffer)>, Buffer>
+    {
+        let (addr, len) = match buffer {
+            Buffer::Read(ref buf) => (buf.as_ptr() as u64, buf.capacity() as u32),
+            Buffer::Write(ref buf) => (buf.as_ptr() as u64, buf.len() as u32),
+        };
+        let mut sq = self.submit.lock().unwrap();
+        let mut requests = self.requests.lock().unwrap();
+        let head = unsafe { (*sq.head).load(Ordering::Acquire) };
+        let tail = unsafe { (*sq.tail).load(Ordering::Relaxed) };
+        if tail.wrapping_sub(head) >= self.entries || requests.len() >= self.entries as usize {
+            return Err(buffer);
+        }
+        let id = sq.next_id;
+        sq.next_id += 1;
+        let (tx, rx) = oneshot::channel();
+        requests.insert(
+            id,
+            Request {
+                _file: file.clone(),
+                buffer,
+                done: tx,
+            },
+        );
+        drop(requests);
+        unsafe {
+            let idx = tail & sq.mask;
+            ptr::write(
+                sq.sqes.add(idx as usize),
+                Sqe {
+                    opcode,
+                    flags: 0,
+                    ioprio: 0,
+                    fd: file.as_raw_fd(),
+                    off: offset,
+                    addr,
+                    len,
+                    rw_flags: 0,
+                    user_data: id,
+                    pad: [0; 3],
+                },
+            );
+            *sq.array.add(idx as usize) = idx;
+            (*sq.tail).store(tail.wrapping_add(1), Ordering::Release);
+            let r = enter(self.fd, 1, 0, 0);
+            if r < 0 {
+                // Not taken by the kernel, so not completed either.
+                let e = io::Error::last_os_error();
+                error!("io_uring: submit: {}", e);
+                (*sq.tail).store(tail, Ordering::Release);
+                let req = self.requests.lock().unwrap().remove(&id);
+                return Err(req.map(|r| r.buffer).unwrap());
+            }
+        }
+        Ok(rx)
+    }
+
+    /// Read at most `len` bytes at `offset`.
+    pub async fn read_at(&self, file: &Arc<File>, offset: u64, len: usize) -> io::Result<Bytes> {
+        let buffer = Buffer::Read(Vec::with_capacity(len));
+        let (res, buffer) = match self.submit(IORING_OP_READ, file, offset, buffer) {
+            Ok(rx) => rx.await.map_err(|_| io::Error::from(io::ErrorKind::Other))?,
+            Err(_) => {
+                let file = file.clone();
+                let read = tokio::task::spawn_blocking(move || {
+                    let mut buf = vec![0; len];
+                    let n = file.read_at(&mut buf, offset)?;
+                    buf.truncate(n);
+                    Ok::<_, io::Error>(buf)
+                });
+                return read.await?.map(Bytes::from);
+            },
+        };
+        if res < 0 {
+            return Err(io::Error::from_raw_os_error(-res));
+        }
+        match buffer {
+            Buffer::Read(mut buf) => {
+                // the kernel filled it.
+                unsafe { buf.set_len(res as usize) };
+                Ok(Bytes::from(buf))
+            },
+            Buffer::Write(_) => unreachable!(),
+        }
+    }
+
+    /// Write some of `data` at `offset`. Returns how much was written.
+    pub async fn write_at(&self, file: &Arc<File>, offset: u64, data: Bytes) -> io::Result<usize> {
+        let res = match self.submit(IORING_OP_WRITE, file, offset, Buffer::Write(data)) {
+            Ok(rx) => rx.await.map_err(|_| io::Error::from(io::ErrorKind::Other))?.0,
+            Err(Buffer::Write(data)) => {
+                let file = file.clone();
+                return tokio::task::spawn_blocking(move || file.write_at(&data, offset)).await?;
+            },
+            Err(Buffer::Read(_)) => unreachable!(),
+        };
+        if res < 0 {
+            return Err(io::Error::from_raw_os_error(-res));
+        }
+        Ok(res as usize)
+    }
+}
+
+/// A file that is read and written with the ring. The data does not go
+/// through `inner`, the same file opened by the filesystem; that is used
+/// for the metadata, which then is the same as for the path. If the file
+/// is opened for appending, the kernel writes at the end, whatever the
+/// position is.
+#[derive(Debug)]
+pub struct UringFile {
+    inner: Box<dyn DavFile>,
+    file:  Arc<File>,
+    ring:  Arc<Uring>,
+    pos:   u64,
+}
+
+impl UringFile {
+    pub fn new(inner: Box<dyn DavFile>, file: File, ring: Arc<Uring>) -> UringFile {
+        UringFile {
+            inner,
+            file: Arc::new(file),
+            ring,
+            pos: 0,
+        }
+    }
+}
+
+impl std::fmt::Debug for Uring {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Uring").field("fd", &self.fd).finish()
+    }
+}
+
+impl DavFile for UringFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.inner.metadata()
+    }
+
+    fn write_buf<'a>(&'a mut self, mut buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        let data = buf.copy_to_bytes(buf.remaining());
+        self.write_bytes(data)
+    }
+
+    fn write_bytes<'a>(&'a mut self, mut buf: Bytes) -> FsFuture<'a, ()> {
+        async move {
+            while !buf.is_empty() {
+                let n = self.ring.write_at(&self.file, self.pos, buf.clone()).await?;
+                if n == 0 {
+                    return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+                }
+                buf.advance(n);
+                self.pos += n as u64;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        async move {
+            let data = self.ring.read_at(&self.file, self.pos, count).await?;
+            self.pos += data.len() as u64;
+            Ok(data)
+        }
+        .boxed()
+    }
+
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> FsFuture<'a, u64> {
+        async move {
+            let pos = match pos {
+                SeekFrom::Start(n) => Some(n),
+                SeekFrom::Current(n) => offset(self.pos, n),
+                SeekFrom::End(n) => offset(self.inner.metadata().await?.len(), n),
+            };
+            self.pos = pos.ok_or(FsError::GeneralFailure)?;
+            Ok(self.pos)
+        }
+        .boxed()
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        // Nothing is buffered.
+        Box::pin(futures::future::ready(Ok(())))
+    }
+}
+
+fn offset(pos: u64, n: i64) -> Option<u64> {
+    match n < 0 {
+        true => pos.checked_sub(n.unsigned_abs()),
+        false => pos.checked_add(n as u64),
+    }
+}
@@ -0,0 +1,218 @@
+//
+// Usage counters per user, for the admin API.
+//
+// Requests are counted once they are authenticated. The bytes are those
+// of file contents, read (downloaded) and written (uploaded) by the user,
+// counted by a UsageFs around the filesystem of the request. Counting is
+// only done when the admin API is enabled.
+//
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::FutureExt;
+use http::StatusCode;
+use hyper::body::{Buf, Bytes};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+lazy_static::lazy_static! {
+    static ref USERS: Mutex<HashMap<String, Arc<Counters>>> = Mutex::new(HashMap::new());
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The counters of one user.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub requests:      AtomicU64,
+    pub bytes_read:    AtomicU64,
+    pub bytes_written: AtomicU64,
+    // unix time of the last request.
+    pub last_request:  AtomicU64,
+}
+
+/// Start counting.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Are the counters kept.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Count a request by "user". Returns the counters of the user.
+pub fn request(user: &str) -> Arc<Counters> {
+    let counters = USERS
+        .lock()
+        .unwrap()
+        .entry(user.to_string())
+        .or_default()
+        .clone();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    counters.requests.fetch_add(1, Ordering::Relaxed);
+    counters.last_request.store(now, Ordering::Relaxed);
+    counters
+}
+
+/// The counters of all users that made a request.
+pub fn users() -> Vec<(String, Arc<Counters>)> {
+    let mut users: Vec<_> = USERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(u, c)| (u.clone(), c.clone()))
+        .collect();
+    users.sort_by(|a, b| a.0.cmp(&b.0));
+    users
+}
+
+/// A filesystem that counts the bytes read and written.
+#[derive(Clone)]
+pub struct UsageFs {
+    fs:       Box<dyn DavFileSystem>,
+    counters: Arc<Counters>,
+}
+
+impl UsageFs {
+    pub fn new(fs: Box<dyn DavFileSystem>, counters: Arc<Counters>) -> Box<UsageFs> {
+        Box::new(UsageFs { fs, counters })
+    }
+}
+
+impl DavFileSystem for UsageFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let file = self.fs.open(path, options).await?;
+            Ok(Box::new(UsageFile {
+                file,
+                counters: self.counters.clone(),
+            }) as Box<dyn DavFile>)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_dir(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_file(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.rename(from, to)
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.copy(from, to)
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        self.fs.patch_props(path, patch)
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[derive(Debug)]
+struct UsageFile {
+    file:     Box<dyn DavFile>,
+    counters: Arc<Counters>,
+}
+
+impl DavFile for UsageFile {
+    fn metadata<'a>(&'a mut self) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.file.metadata()
+    }
+
+    fn write_buf<'a>(&'a mut self, buf: Box<dyn Buf + Send>) -> FsFuture<'a, ()> {
+        async move {
+            let count = buf.remaining() as u64;
+            self.file.write_buf(buf).await?;
+            self.counters.bytes_written.fetch_add(count, Ordering::Relaxed);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn write_bytes<'a>(&'a mut self, buf: Bytes) -> FsFuture<'a, ()> {
+        async move {
+            let count = buf.len() as u64;
+            self.file.write_bytes(buf).await?;
+            self.counters.bytes_written.fetch_add(count, Ordering::Relaxed);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn read_bytes<'a>(&'a mut self, count: usize) -> FsFuture<'a, Bytes> {
+        async move {
+            let data = self.file.read_bytes(count).await?;
+            self.counters.bytes_read.fetch_add(data.len() as u64, Ordering::Relaxed);
+            Ok(data)
+        }
+        .boxed()
+    }
+
+    fn seek<'a>(&'a mut self, pos: SeekFrom) -> FsFuture<'a, u64> {
+        self.file.seek(pos)
+    }
+
+    fn flush<'a>(&'a mut self) -> FsFuture<'a, ()> {
+        self.file.flush()
+    }
+}
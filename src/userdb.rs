@@ -0,0 +1,195 @@
+//
+// The built-in user database (auth-type "sqlite").
+//
+// Users and their password hashes (see passhash.rs) in an SQLite file,
+// managed with "webdav-server user add/passwd/del/list". Nothing else is needed:
+// no system accounts, no PAM, and the server can run unprivileged. With
+// "$user" in the directory of a location, every user gets their own.
+//
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::config::PasswordHash;
+use crate::passhash;
+
+lazy_static::lazy_static! {
+    static ref DBS: Mutex<HashMap<String, Arc<UserDb>>> = Mutex::new(HashMap::new());
+}
+
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn now() -> i64 {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+    now.map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+/// A name we can store: it goes in paths ("$user") and Basic auth.
+pub fn check_name(name: &str) -> io::Result<()> {
+    let bad = name.is_empty()
+        || name.len() > 256
+        || name == "."
+        || name == ".."
+        || name.chars().any(|c| c == '/' || c == ':' || c.is_control());
+    match bad {
+        true => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}: invalid username", name))),
+        false => Ok(()),
+    }
+}
+
+pub struct UserDb {
+    db: Mutex<rusqlite::Connection>,
+}
+
+/// The database in file "path", opened on first use.
+pub fn open(path: &str) -> io::Result<Arc<UserDb>> {
+    let mut dbs = DBS.lock().unwrap();
+    if let Some(db) = dbs.get(path) {
+        return Ok(db.clone());
+    }
+    let db = Arc::new(UserDb::open(path)?);
+    dbs.insert(path.to_string(), db.clone());
+    Ok(db)
+}
+
+impl UserDb {
+    pub fn open(path: &str) -> io::Result<UserDb> {
+        let db = rusqlite::Connection::open(path).map_err(sql_error)?;
+        db.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS users (
+                 name     TEXT PRIMARY KEY NOT NULL,
+                 password TEXT NOT NULL,
+                 created  INTEGER NOT NULL,
+                 changed  INTEGER NOT NULL
+             );",
+        )
+        .map_err(sql_error)?;
+        Ok(UserDb { db: Mutex::new(db) })
+    }
+
+    /// Add a user, with a password hash. Fails if it is already there.
+    pub fn add(&self, name: &str, hash: &str) -> io::Result<()> {
+        check_name(name)?;
+        let db = self.db.lock().unwrap();
+        let sql = "INSERT OR IGNORE INTO users (name, password, created, changed) VALUES (?1, ?2, ?3, ?3)";
+        match db.execute(sql, rusqlite::params![name, hash, now()]).map_err(sql_error)? {
+            0 => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{}: user exists", name))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Set a new password hash.
+    pub fn set_password(&self, name: &str, hash: &str) -> io::Result<()> {
+        let db = self.db.lock().unwrap();
+        let sql = "UPDATE users SET password = ?2, changed = ?3 WHERE name = ?1";
+        match db.execute(sql, rusqlite::params![name, hash, now()]).map_err(sql_error)? {
+            0 => Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: no such user", name))),
+            _ => Ok(()),
+        }
+    }
+
+    // A new hash of the same password: "changed" stays.
+    fn rehash(&self, name: &str, hash: &str) -> io::Result<()> {
+        let db = self.db.lock().unwrap();
+        let sql = "UPDATE users SET password = ?2 WHERE name = ?1";
+        db.execute(sql, rusqlite::params![name, hash]).map_err(sql_error)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        let db = self.db.lock().unwrap();
+        match db.execute("DELETE FROM users WHERE name = ?1", rusqlite::params![name]).map_err(sql_error)? {
+            0 => Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: no such user", name))),
+            _ => Ok(()),
+        }
+    }
+
+    /// All users, by name, with the time the password was last set
+    /// (seconds since the epoch).
+    pub fn list(&self) -> io::Result<Vec<(String, i64)>> {
+        let db = self.db.lock().unwrap();
+        let mut stmt = db.prepare("SELECT name, changed FROM users ORDER BY name").map_err(sql_error)?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(sql_error)?;
+        rows.collect::<rusqlite::Result<Vec<_>>>().map_err(sql_error)
+    }
+
+    /// The password hash of a user.
+    pub fn password(&self, name: &str) -> io::Result<Option<String>> {
+        use rusqlite::OptionalExtension;
+        let db = self.db.lock().unwrap();
+        let mut stmt = db.prepare_cached("SELECT password FROM users WHERE name = ?1").map_err(sql_error)?;
+        stmt.query_row(rusqlite::params![name], |row| row.get(0))
+            .optional()
+            .map_err(sql_error)
+    }
+
+    /// Check the password of a user. With rehash, an old hash is replaced.
+    pub fn auth(&self, name: &str, pass: &str, cfg: &PasswordHash) -> io::Result<()> {
+        let denied = |msg: &str| io::Error::new(io::ErrorKind::PermissionDenied, msg.to_string());
+        match self.password(name)? {
+            Some(hash) if crate::htpasswd::verify(pass, &hash) => {
+                let rehash = passhash::rehash(cfg, pass, &hash);
+                match rehash.map(|h| h.and_then(|h| self.rehash(name, &h))) {
+                    Some(Ok(())) => info!("userdb: {}: password rehashed", name),
+                    Some(Err(e)) => warn!("userdb: {}: rehash: {}", name, e),
+                    None => {},
+                }
+                Ok(())
+            },
+            Some(_) => Err(denied("wrong password")),
+            None => Err(denied("no such user")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_userdb() {
+        let dir = std::env::temp_dir().join(format!("userdb-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = UserDb::open(dir.join("users.db").to_str().unwrap()).unwrap();
+
+        let cfg = PasswordHash::default();
+        let hash = |pw: &str| passhash::hash(&cfg, pw);
+        db.add("alice", &hash("secret").unwrap()).unwrap();
+        db.add("bob", &hash("hunter2").unwrap()).unwrap();
+        assert_eq!(db.add("alice", "x").unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert!(db.add("a/b", "x").is_err());
+        assert!(db.add("..", "x").is_err());
+        assert!(db.add("a:b", "x").is_err());
+
+        assert!(db.auth("alice", "secret", &cfg).is_ok());
+        assert!(db.auth("alice", "hunter2", &cfg).is_err());
+        assert!(db.auth("carol", "secret", &cfg).is_err());
+        db.set_password("alice", &hash("other").unwrap()).unwrap();
+        assert!(db.auth("alice", "secret", &cfg).is_err());
+        assert!(db.auth("alice", "other", &cfg).is_ok());
+        assert!(db.set_password("carol", "x").is_err());
+
+        let scrypt = PasswordHash {
+            scheme: Some(crate::config::HashScheme::Scrypt),
+            scrypt_log_n: Some(4),
+            rehash: true,
+            ..PasswordHash::default()
+        };
+        assert!(db.auth("alice", "other", &scrypt).is_ok());
+        assert!(db.password("alice").unwrap().unwrap().starts_with("$scrypt$ln=4,"));
+        assert!(db.auth("alice", "other", &cfg).is_ok());
+
+        db.remove("bob").unwrap();
+        assert!(db.remove("bob").is_err());
+        let names: Vec<_> = db.list().unwrap().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["alice".to_string()]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
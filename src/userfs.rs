@@ -1,17 +1,139 @@
 use std::any::Any;
+use std::future::Future;
+use std::ffi::{CStr, CString, OsStr};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::future::{self, FutureExt};
+use futures::stream::{self, StreamExt};
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use tokio::sync::Semaphore;
 
 use webdav_handler::davpath::DavPath;
 use webdav_handler::fs::*;
 use webdav_handler::localfs::LocalFs;
 
+#[cfg(feature = "quota")]
+use crate::config::Quota;
+use crate::config::Symlinks;
+use crate::confine::{self, ConfinedFile, ConfinedMeta};
 use crate::suid::UgidSwitch;
+use crate::suidpool::{self, Pool};
+use crate::symlinks;
+
+static METADATA_THREADS: AtomicUsize = AtomicUsize::new(64);
+
+static LISTING_FANOUT: AtomicUsize = AtomicUsize::new(1);
+
+// Directory entries read at once, as the user.
+const READ_DIR_BATCH: usize = 256;
+
+lazy_static::lazy_static! {
+    // stat and opendir calls that may run at the same time.
+    static ref METADATA: Semaphore = Semaphore::new(METADATA_THREADS.load(Ordering::Relaxed).max(1));
+}
+
+/// How many stat and opendir calls may be running at the same time. Must
+/// be set before the first request.
+pub fn set_metadata_threads(n: usize) {
+    METADATA_THREADS.store(n, Ordering::Relaxed);
+}
+
+type DirStream = FsStream<Box<dyn DavDirEntry>>;
+
+// The next entries of a directory, and the rest if there are more.
+async fn next_batch(mut entries: DirStream) -> (Option<DirStream>, Vec<Box<dyn DavDirEntry>>) {
+    let mut batch = Vec::new();
+    while batch.len() < READ_DIR_BATCH {
+        match entries.next().await {
+            Some(entry) => batch.push(entry),
+            None => return (None, batch),
+        }
+    }
+    (Some(entries), batch)
+}
+
+// The entries of a directory, read a batch at a time by "next".
+fn batches<S, F, Fut>(state: Option<S>, mut next: F) -> DirStream
+where
+    S: Send + 'static,
+    F: FnMut(S) -> Fut + Send + 'static,
+    Fut: Future<Output = (Option<S>, Vec<Box<dyn DavDirEntry>>)> + Send + 'static,
+{
+    let strm = stream::unfold(state, move |state| {
+        let fut = state.map(&mut next);
+        async move {
+            let (state, batch) = fut?.await;
+            match batch.is_empty() {
+                true => None,
+                false => Some((stream::iter(batch), state)),
+            }
+        }
+    });
+    Box::pin(strm.flatten())
+}
+
+/// How many entries of a directory listing are looked at (stat) at the
+/// same time. 1 is one after the other.
+pub fn set_listing_fanout(n: usize) {
+    LISTING_FANOUT.store(n, Ordering::Relaxed);
+}
+
+/// Mode and group of new files and directories. Without a mode, it is
+/// 0666 (0777 for directories, and 0600/0700 if not public) less the umask.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NewFiles {
+    pub file_mode: Option<u32>,
+    pub dir_mode:  Option<u32>,
+    pub umask:     Option<u32>,
+    pub gid:       Option<u32>,
+}
+
+impl NewFiles {
+    fn is_set(&self) -> bool {
+        self.file_mode.is_some() || self.dir_mode.is_some() || self.umask.is_some() || self.gid.is_some()
+    }
+
+    fn mode(&self, dir: bool, public: bool) -> Option<u32> {
+        let mode = if dir { self.dir_mode } else { self.file_mode };
+        if mode.is_none() && self.umask.is_none() {
+            return None;
+        }
+        let default = match (dir, public) {
+            (true, true) => 0o777,
+            (true, false) => 0o700,
+            (false, true) => 0o666,
+            (false, false) => 0o600,
+        };
+        Some(mode.unwrap_or(default) & !self.umask.unwrap_or(0))
+    }
+}
 
 #[derive(Clone)]
 pub struct UserFs {
     pub fs:  LocalFs,
     basedir: PathBuf,
+    #[cfg(feature = "quota")]
     uid:     u32,
+    symlinks: Symlinks,
+    case_insensitive: bool,
+    // resolve paths in basedir, as if it were the root.
+    confine: bool,
+    public:  bool,
+    new_files: NewFiles,
+    // file contents with io_uring; not if names need resolving.
+    #[cfg(feature = "io-uring")]
+    uring:   bool,
+    #[cfg(feature = "quota")]
+    quota:   Quota,
+    // files opened for reading get a second fd for sendfile.
+    sendfile: Option<crate::sendfile::Opened>,
+    // the threads that stay switched to the user, if there are pools.
+    pool:    Option<Arc<Pool>>,
 }
 
 impl UserFs {
@@ -23,12 +145,18 @@ impl UserFs {
         macos: bool,
     ) -> Box<UserFs>
     {
-        // uid is used for quota() calls.
+        // uid is used for quota() calls, and in the span.
         let uid = target_creds.as_ref().map(|ugid| ugid.0).unwrap_or(0);
+        let pool = target_creds.and_then(|(uid, gid, groups)| suidpool::get(uid, gid, groups));
 
-        // set up the LocalFs hooks for uid switching.
-        let switch = UgidSwitch::new(target_creds.clone());
-        let blocking_guard = Box::new(move || Box::new(switch.guard()) as Box<dyn Any>);
+        // set up the LocalFs hooks for uid switching. The switch is done
+        // on a blocking thread, so the span of the request is passed on.
+        let switch = UgidSwitch::new(target_creds);
+        let request_span = tracing::Span::current();
+        let blocking_guard = Box::new(move || {
+            let _span = tracing::info_span!(parent: &request_span, "switch_uid", uid).entered();
+            Box::new(switch.guard()) as Box<dyn Any>
+        });
 
         Box::new(UserFs {
             basedir: dir.as_ref().to_path_buf(),
@@ -39,58 +167,709 @@ impl UserFs {
                 macos,
                 Some(blocking_guard),
             ),
-            uid:     uid,
+            #[cfg(feature = "quota")]
+            uid,
+            symlinks: Symlinks::Follow,
+            case_insensitive,
+            confine: false,
+            public,
+            new_files: NewFiles::default(),
+            #[cfg(feature = "io-uring")]
+            uring: !case_insensitive,
+            #[cfg(feature = "quota")]
+            quota: Quota::User,
+            sendfile: None,
+            pool,
+        })
+    }
+
+    /// Which quota is reported (default: user).
+    #[cfg(feature = "quota")]
+    pub fn set_quota(&mut self, quota: Quota) {
+        self.quota = quota;
+    }
+
+    /// Where symlinks may lead (default: follow).
+    pub fn set_symlinks(&mut self, symlinks: Symlinks) {
+        self.symlinks = symlinks;
+    }
+
+    /// Keep all file operations inside the directory (default: no).
+    pub fn set_confine(&mut self, confine: bool) {
+        self.confine = confine;
+    }
+
+    /// The mode and group of new files and directories.
+    pub fn set_new_files(&mut self, new_files: NewFiles) {
+        self.new_files = new_files;
+    }
+
+    /// Open files that are only read once more, for sendfile.
+    pub fn set_sendfile(&mut self, opened: crate::sendfile::Opened) {
+        self.sendfile = Some(opened);
+    }
+
+    // Open a file that LocalFs opened once more, as the user.
+    async fn reopen(&self, path: &DavPath, oo: OpenOptions) -> FsResult<std::fs::File> {
+        match self.confine {
+            true => self.confined(path, move |root, rel| confine::open(root, rel, &oo, 0)).await,
+            false => {
+                let ospath = self.basedir.join(path.as_rel_ospath());
+                let mut std_oo = std::fs::OpenOptions::new();
+                std_oo.read(oo.read).write(oo.write).append(oo.append);
+                self.blocking(move || std_oo.open(ospath)).await.map_err(|e| e.into())
+            },
+        }
+    }
+
+    // The second fd for sendfile; not if names need resolving.
+    async fn open_sendfile(&self, path: &DavPath, options: &OpenOptions) {
+        let opened = match self.sendfile {
+            Some(ref opened) if !options.write && !options.append => opened,
+            _ => return,
+        };
+        if self.case_insensitive {
+            return opened.add(None);
+        }
+        let oo = OpenOptions {
+            read: true,
+            ..OpenOptions::default()
+        };
+        match self.reopen(path, oo).await {
+            Ok(fd) => opened.add(Some(fd)),
+            Err(e) => {
+                debug!("open: sendfile: {:?}", e);
+                opened.add(None);
+            },
+        }
+    }
+
+    // A blocking call as the user: on a thread of the pool, or switched
+    // to the user by LocalFs.
+    async fn blocking<F, R>(&self, func: F) -> R
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        match self.pool {
+            Some(ref pool) => pool.run(func).await,
+            None => self.fs.blocking(func).await,
+        }
+    }
+
+    // A call of LocalFs, on a thread of the pool if there is one. There
+    // the switch of the fs_access_guard is a no-op, as the thread already
+    // is the user.
+    async fn pooled<F, Fut, T>(&self, func: F) -> T
+    where
+        F: FnOnce(LocalFs) -> Fut + Send + 'static,
+        Fut: Future<Output = T> + Send,
+        T: Send + 'static,
+    {
+        let fs = self.fs.clone();
+        match self.pool {
+            Some(ref pool) => pool.run(move || futures::executor::block_on(func(fs))).await,
+            None => func(fs).await,
+        }
+    }
+
+    // A call of confine, with the directory and the path below it, as the user.
+    async fn confined<F, R>(&self, path: &DavPath, func: F) -> FsResult<R>
+    where
+        F: FnOnce(&Path, &Path) -> io::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (basedir, rel) = (self.basedir.clone(), path.as_rel_ospath().to_path_buf());
+        self.blocking(move || func(&basedir, &rel)).await.map_err(|e| e.into())
+    }
+
+    // metadata or symlink_metadata.
+    async fn stat(&self, path: &DavPath, follow: bool) -> FsResult<Box<dyn DavMetaData>> {
+        if self.confine {
+            let meta = self.confined(path, move |root, rel| confine::metadata(root, rel, follow)).await?;
+            return Ok(ConfinedMeta::new(meta) as Box<dyn DavMetaData>);
+        }
+        let path = path.clone();
+        match follow {
+            true => self.pooled(move |fs| async move { fs.metadata(&path).await }).await,
+            false => self.pooled(move |fs| async move { fs.symlink_metadata(&path).await }).await,
+        }
+    }
+
+    // Open a file confined; new files get the mode LocalFs would give them.
+    async fn open_confined(&self, path: &DavPath, options: OpenOptions) -> FsResult<Box<dyn DavFile>> {
+        let mode = if self.public { 0o644 } else { 0o600 };
+        let file = self.confined(path, move |root, rel| confine::open(root, rel, &options, mode)).await?;
+        Ok(ConfinedFile::new(file) as Box<dyn DavFile>)
+    }
+
+    // A directory listing. With a pool it is read there, as the stream of
+    // LocalFs reads more of it where it is polled; confined it is read as
+    // the user. Both in batches, so that all of a huge directory is not in
+    // memory at once, and it is read no faster than it is sent.
+    async fn read_dir_pooled(
+        &self,
+        path: &DavPath,
+        meta: ReadDirMeta,
+    ) -> FsResult<FsStream<Box<dyn DavDirEntry>>>
+    {
+        if self.confine {
+            let follow = meta == ReadDirMeta::Data;
+            let entries = self.confined(path, move |root, rel| confine::read_dir(root, rel, follow)).await?;
+            let this = self.clone();
+            return Ok(batches(Some(entries), move |mut entries| {
+                let this = this.clone();
+                async move {
+                    this.blocking(move || {
+                        let mut batch = Vec::new();
+                        while batch.len() < READ_DIR_BATCH {
+                            let (name, _, meta) = match entries.next() {
+                                Some(Ok(entry)) => entry,
+                                Some(Err(e)) => {
+                                    debug!("userfs: read_dir: {}", e);
+                                    return (None, batch);
+                                },
+                                None => return (None, batch),
+                            };
+                            let name = name.as_bytes().to_vec();
+                            let meta = ConfinedMeta::new(meta) as Box<dyn DavMetaData>;
+                            batch.push(Box::new(LinkEntry { name, meta }) as Box<dyn DavDirEntry>);
+                        }
+                        (Some(entries), batch)
+                    })
+                    .await
+                }
+            }));
+        }
+        if self.pool.is_none() {
+            return self.fs.read_dir(path, meta).await;
+        }
+        let path = path.clone();
+        let (rest, first) = self
+            .pooled(move |fs| {
+                async move {
+                    let entries = fs.read_dir(&path, meta).await?;
+                    Ok::<_, FsError>(next_batch(entries).await)
+                }
+            })
+            .await?;
+        let this = self.clone();
+        let rest = batches(rest, move |entries| {
+            let this = this.clone();
+            async move { this.pooled(move |_| next_batch(entries)).await }
+        });
+        Ok(Box::pin(stream::iter(first).chain(rest)) as FsStream<Box<dyn DavDirEntry>>)
+    }
+
+    // A directory listing where the entries are looked at a few at the
+    // same time, "fanout" of them, and come out in the order of the
+    // directory. On NFS a stat is a round trip to the server.
+    async fn read_dir_fanout(
+        &self,
+        path: &DavPath,
+        meta: ReadDirMeta,
+        fanout: usize,
+    ) -> FsResult<FsStream<Box<dyn DavDirEntry>>>
+    {
+        if self.symlinks != Symlinks::Follow {
+            self.check(path, FsError::NotFound).await?;
+        }
+        let names = self.read_dir_pooled(path, ReadDirMeta::None).await?;
+        let (fs, dir) = (self.clone(), path.clone());
+        let entries = names.map(move |entry| {
+            let (fs, dir) = (fs.clone(), dir.clone());
+            async move { fs.dir_entry(&dir, entry.name(), meta).await }
+        });
+        let entries = entries.buffered(fanout).filter_map(future::ready);
+        Ok(Box::pin(entries) as FsStream<Box<dyn DavDirEntry>>)
+    }
+
+    // An entry of a listing with its metadata, if the symlink policy lets
+    // it be shown.
+    async fn dir_entry(
+        &self,
+        dir: &DavPath,
+        name: Vec<u8>,
+        meta: ReadDirMeta,
+    ) -> Option<Box<dyn DavDirEntry>>
+    {
+        let dir = dir.as_url_string();
+        let encoded = percent_encode(&name, NON_ALPHANUMERIC).to_string();
+        let path = DavPath::new(&format!("{}/{}", dir.trim_end_matches('/'), encoded)).ok()?;
+        let _permit = METADATA.acquire().await;
+        let lmeta = self.stat(&path, false).await.ok()?;
+        if !lmeta.is_symlink() {
+            return Some(Box::new(LinkEntry { name, meta: lmeta }) as Box<dyn DavDirEntry>);
+        }
+        let allowed = match self.symlinks {
+            Symlinks::Follow => true,
+            Symlinks::Deny => false,
+            Symlinks::FollowWithinRoot => self.check(&path, FsError::NotFound).await.is_ok(),
+        };
+        if !allowed {
+            return None;
+        }
+        let meta = match meta {
+            ReadDirMeta::Data => self.stat(&path, true).await.ok()?,
+            _ => lmeta,
+        };
+        Some(Box::new(LinkEntry { name, meta }) as Box<dyn DavDirEntry>)
+    }
+
+    // Is the path allowed by the symlink policy; if not, the error.
+    async fn check(&self, path: &DavPath, err: FsError) -> FsResult<()> {
+        if self.symlinks == Symlinks::Follow {
+            return Ok(());
+        }
+        let (basedir, rel) = (self.basedir.clone(), path.as_rel_ospath().to_path_buf());
+        let (policy, ci) = (self.symlinks, self.case_insensitive);
+        match self.blocking(move || symlinks::allowed(&basedir, &rel, policy, ci)).await {
+            true => Ok(()),
+            false => {
+                debug!("userfs: {}: symlink not followed", path.as_url_string());
+                Err(err)
+            },
+        }
+    }
+
+    // The path as a C string, and a call on it (xattrs, chmod), as the user.
+    async fn xattr<F, R>(&self, path: &DavPath, func: F) -> FsResult<R>
+    where
+        F: FnOnce(&CStr) -> io::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let rel = path.as_rel_ospath().to_path_buf();
+        let ospath = self.basedir.join(&rel);
+        let cpath = CString::new(ospath.as_os_str().as_bytes()).map_err(|_| FsError::GeneralFailure)?;
+        let (basedir, policy, ci) = (self.basedir.clone(), self.symlinks, self.case_insensitive);
+        let confine = self.confine;
+        let func = move || {
+            if !symlinks::allowed(&basedir, &rel, policy, ci) {
+                return Err(io::Error::from_raw_os_error(libc::ELOOP));
+            }
+            if !confine {
+                return func(&cpath);
+            }
+            // through the directory it is in, opened confined.
+            let parent = confine::Parent::new(&basedir, &rel)?;
+            let cpath = CString::new(parent.path().as_os_str().as_bytes())?;
+            func(&cpath)
+        };
+        self.blocking(func).await.map_err(|e| {
+            match e.raw_os_error() {
+                Some(libc::ENOENT) | Some(libc::ENOTDIR) => FsError::NotFound,
+                Some(libc::EACCES) | Some(libc::EPERM) | Some(libc::ELOOP) => FsError::Forbidden,
+                Some(libc::ENOSPC) | Some(libc::EDQUOT) | Some(libc::E2BIG) => FsError::InsufficientStorage,
+                Some(libc::ENOTSUP) => FsError::NotImplemented,
+                _ => FsError::GeneralFailure,
+            }
+        })
+    }
+
+    /// An extended attribute ("user.*") of a file, not following symlinks.
+    pub async fn get_xattr(&self, path: &DavPath, name: &CStr) -> FsResult<Option<Vec<u8>>> {
+        let name = name.to_owned();
+        self.xattr(path, move |cpath| {
+            let mut buf = vec![0u8; 65536];
+            let n = unsafe {
+                libc::lgetxattr(cpath.as_ptr(), name.as_ptr(), buf.as_mut_ptr() as *mut _, buf.len())
+            };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                return match e.raw_os_error() {
+                    Some(libc::ENODATA) => Ok(None),
+                    _ => Err(e),
+                };
+            }
+            buf.truncate(n as usize);
+            Ok(Some(buf))
+        })
+        .await
+    }
+
+    pub async fn set_xattr(&self, path: &DavPath, name: &CStr, value: Vec<u8>) -> FsResult<()> {
+        let name = name.to_owned();
+        self.xattr(path, move |cpath| {
+            let value_ptr = value.as_ptr() as *const _;
+            let r = unsafe { libc::lsetxattr(cpath.as_ptr(), name.as_ptr(), value_ptr, value.len(), 0) };
+            match r {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        })
+        .await
+    }
+
+    /// Remove an extended attribute. True if it was there.
+    pub async fn remove_xattr(&self, path: &DavPath, name: &CStr) -> FsResult<bool> {
+        let name = name.to_owned();
+        self.xattr(path, move |cpath| {
+            if unsafe { libc::lremovexattr(cpath.as_ptr(), name.as_ptr()) } == 0 {
+                return Ok(true);
+            }
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::ENODATA) => Ok(false),
+                _ => Err(e),
+            }
+        })
+        .await
+    }
+
+    /// The names of the extended attributes of a file.
+    pub async fn list_xattrs(&self, path: &DavPath) -> FsResult<Vec<CString>> {
+        self.xattr(path, move |cpath| {
+            let mut buf = vec![0u8; 65536];
+            let n = unsafe { libc::llistxattr(cpath.as_ptr(), buf.as_mut_ptr() as *mut _, buf.len()) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            buf.truncate(n as usize);
+            let names = buf.split(|&c| c == 0).filter(|n| !n.is_empty());
+            Ok(names.filter_map(|n| CString::new(n).ok()).collect())
         })
+        .await
+    }
+
+    // utimensat, for the times that are given.
+    async fn set_times(
+        &self,
+        path: &DavPath,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> FsResult<()>
+    {
+        let timespec = |t: Option<SystemTime>| {
+            match t.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+                Some(d) => {
+                    libc::timespec {
+                        tv_sec:  d.as_secs() as libc::time_t,
+                        tv_nsec: d.subsec_nanos() as _,
+                    }
+                },
+                None => {
+                    libc::timespec {
+                        tv_sec:  0,
+                        tv_nsec: libc::UTIME_OMIT,
+                    }
+                },
+            }
+        };
+        let times = [timespec(atime), timespec(mtime)];
+        self.xattr(path, move |cpath| {
+            let flags = libc::AT_SYMLINK_NOFOLLOW;
+            match unsafe { libc::utimensat(libc::AT_FDCWD, cpath.as_ptr(), times.as_ptr(), flags) } {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        })
+        .await
+    }
+
+    /// Write a file (only its data, with data_only) or a directory to
+    /// disk, as the user.
+    pub async fn sync(&self, path: &DavPath, data_only: bool) -> FsResult<()> {
+        self.xattr(path, move |cpath| {
+            let file = std::fs::File::open(Path::new(OsStr::from_bytes(cpath.to_bytes())))?;
+            match data_only {
+                true => file.sync_data(),
+                false => file.sync_all(),
+            }
+        })
+        .await
+    }
+
+    // Will open() create the file.
+    async fn creates(&self, path: &DavPath, options: &OpenOptions) -> bool {
+        let create = options.create || options.create_new;
+        self.new_files.is_set() && create && self.stat(path, false).await.is_err()
+    }
+
+    // chown and chmod a new file or directory. It is there already, so
+    // that is all that is logged if it fails.
+    async fn set_new_mode(&self, path: &DavPath, dir: bool) {
+        let (gid, mode) = (self.new_files.gid, self.new_files.mode(dir, self.public));
+        let res = self.chown_chmod(path, gid, mode).await;
+        if let Err(e) = res {
+            warn!("userfs: {}: setting mode: {:?}", path.as_url_string(), e);
+        }
+    }
+
+    // lchown to the group (first, it clears the setgid bit), and chmod.
+    async fn chown_chmod(&self, path: &DavPath, gid: Option<u32>, mode: Option<u32>) -> FsResult<()> {
+        self.xattr(path, move |cpath| {
+            use std::os::unix::fs::PermissionsExt;
+            if let Some(gid) = gid {
+                if unsafe { libc::lchown(cpath.as_ptr(), u32::MAX, gid) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            let path = Path::new(OsStr::from_bytes(cpath.to_bytes()));
+            match mode {
+                Some(mode) if !std::fs::symlink_metadata(path)?.file_type().is_symlink() => {
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                },
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    /// Give a file the mode and group of another one.
+    pub async fn copy_mode(&self, from: &DavPath, to: &DavPath) -> FsResult<()> {
+        let (mode, gid) = self
+            .xattr(from, move |cpath| {
+                use std::os::unix::fs::MetadataExt;
+                let meta = std::fs::symlink_metadata(Path::new(OsStr::from_bytes(cpath.to_bytes())))?;
+                Ok((meta.mode() & 0o7777, meta.gid()))
+            })
+            .await?;
+        // a group the user is not in can not be given.
+        if self.chown_chmod(to, Some(gid), Some(mode)).await.is_err() {
+            self.chown_chmod(to, None, Some(mode)).await?;
+        }
+        Ok(())
+    }
+
+    /// Rename, but not over a file or directory that is there (Exists).
+    pub async fn rename_noreplace(&self, from: &DavPath, to: &DavPath) -> FsResult<()> {
+        self.check(from, FsError::Forbidden).await?;
+        self.check(to, FsError::Forbidden).await?;
+        let rel = to.as_rel_ospath().to_path_buf();
+        if self.confine {
+            return self.confined(from, move |root, from| confine::rename_noreplace(root, from, &rel)).await;
+        }
+        let (from, to) = (self.basedir.join(from.as_rel_ospath()), self.basedir.join(rel));
+        let res = self.blocking(move || confine::renameat2_noreplace(&from, &to)).await;
+        res.map_err(|e| e.into())
+    }
+
+    /// Clear the write bits of a file, or give the owner write access
+    /// back. Symlinks are left alone.
+    pub async fn set_readonly(&self, path: &DavPath, readonly: bool) -> FsResult<()> {
+        self.xattr(path, move |cpath| {
+            use std::os::unix::fs::PermissionsExt;
+            let path = Path::new(OsStr::from_bytes(cpath.to_bytes()));
+            let meta = std::fs::symlink_metadata(path)?;
+            if meta.file_type().is_symlink() {
+                return Ok(());
+            }
+            let mut perms = meta.permissions();
+            let mode = perms.mode();
+            perms.set_mode(if readonly { mode & !0o222 } else { mode | 0o200 });
+            std::fs::set_permissions(path, perms)
+        })
+        .await
     }
 }
 
 impl DavFileSystem for UserFs {
-    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
-        self.fs.metadata(path)
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let _permit = METADATA.acquire().await;
+            self.check(path, FsError::NotFound).await?;
+            self.stat(path, true).await
+        }
+        .boxed()
     }
 
-    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<Box<dyn DavMetaData>> {
-        self.fs.symlink_metadata(path)
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            let _permit = METADATA.acquire().await;
+            self.check(path, FsError::NotFound).await?;
+            self.stat(path, false).await
+        }
+        .boxed()
     }
 
     fn read_dir<'a>(
         &'a self,
         path: &'a DavPath,
         meta: ReadDirMeta,
-    ) -> FsFuture<FsStream<Box<dyn DavDirEntry>>>
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
     {
-        self.fs.read_dir(path, meta)
+        async move {
+            let _permit = METADATA.acquire().await;
+            let fanout = LISTING_FANOUT.load(Ordering::Relaxed);
+            if fanout > 1 && !self.confine && meta != ReadDirMeta::None {
+                return self.read_dir_fanout(path, meta, fanout).await;
+            }
+            if self.symlinks == Symlinks::Follow || meta == ReadDirMeta::None {
+                return self.read_dir_pooled(path, meta).await;
+            }
+            // the symlinks in it are looked at before they are followed.
+            self.check(path, FsError::NotFound).await?;
+            let entries = self.read_dir_pooled(path, ReadDirMeta::DataSymlink).await?;
+            let (fs, path) = (self.clone(), path.clone());
+            let entries = entries.filter_map(move |entry| {
+                let (fs, path) = (fs.clone(), path.clone());
+                async move {
+                    if !entry.is_symlink().await.unwrap_or(false) {
+                        return Some(entry);
+                    }
+                    let dir = path.as_url_string();
+                    let name = percent_encode(&entry.name(), NON_ALPHANUMERIC).to_string();
+                    let path = DavPath::new(&format!("{}/{}", dir.trim_end_matches('/'), name)).ok()?;
+                    if fs.symlinks == Symlinks::Deny || fs.check(&path, FsError::NotFound).await.is_err() {
+                        return None;
+                    }
+                    if meta == ReadDirMeta::DataSymlink {
+                        return Some(entry);
+                    }
+                    let meta = fs.stat(&path, true).await.ok()?;
+                    Some(Box::new(LinkEntry { name: entry.name(), meta }) as Box<dyn DavDirEntry>)
+                }
+            });
+            Ok(Box::pin(entries) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
     }
 
-    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<Box<dyn DavFile>> {
-        self.fs.open(path, options)
+    #[cfg(not(feature = "io-uring"))]
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let err = if options.write || options.append { FsError::Forbidden } else { FsError::NotFound };
+            self.check(path, err).await?;
+            let new = self.creates(path, &options).await;
+            let file = match self.confine {
+                true => self.open_confined(path, options).await?,
+                false => {
+                    let dpath = path.clone();
+                    self.pooled(move |fs| async move { fs.open(&dpath, options).await }).await?
+                },
+            };
+            if new {
+                self.set_new_mode(path, false).await;
+            }
+            self.open_sendfile(path, &options).await;
+            Ok(file)
+        }
+        .boxed()
     }
 
-    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
-        self.fs.create_dir(path)
+    // The file is opened by LocalFs, which checks and creates it, and
+    // then once more for the ring, as the user.
+    #[cfg(feature = "io-uring")]
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            let err = if options.write || options.append { FsError::Forbidden } else { FsError::NotFound };
+            self.check(path, err).await?;
+            let new = self.creates(path, &options).await;
+            let dpath = path.clone();
+            let file = match self.confine {
+                true => self.open_confined(path, options).await?,
+                false => self.pooled(move |fs| async move { fs.open(&dpath, options).await }).await?,
+            };
+            if new {
+                self.set_new_mode(path, false).await;
+            }
+            self.open_sendfile(path, &options).await;
+            let ring = match crate::uring::get() {
+                Some(ring) if self.uring => ring,
+                _ => return Ok(file),
+            };
+            let write = options.write || options.append;
+            let oo = OpenOptions {
+                read: !write || options.read,
+                write,
+                append: options.append,
+                ..OpenOptions::default()
+            };
+            let fd = match self.reopen(path, oo).await {
+                Ok(fd) => fd,
+                Err(e) => {
+                    debug!("open: io_uring: {}", e);
+                    return Ok(file);
+                },
+            };
+            Ok(Box::new(crate::uring::UringFile::new(file, fd, ring)) as Box<dyn DavFile>)
+        }
+        .boxed()
     }
 
-    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
-        self.fs.remove_dir(path)
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check(path, FsError::Forbidden).await?;
+            if self.confine {
+                let mode = if self.public { 0o755 } else { 0o700 };
+                self.confined(path, move |root, rel| confine::create_dir(root, rel, mode)).await?;
+            } else {
+                let dpath = path.clone();
+                self.pooled(move |fs| async move { fs.create_dir(&dpath).await }).await?;
+            }
+            if self.new_files.is_set() {
+                self.set_new_mode(path, true).await;
+            }
+            Ok(())
+        }
+        .boxed()
     }
 
-    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<()> {
-        self.fs.remove_file(path)
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check(path, FsError::Forbidden).await?;
+            if self.confine {
+                return self.confined(path, confine::remove_dir).await;
+            }
+            let path = path.clone();
+            self.pooled(move |fs| async move { fs.remove_dir(&path).await }).await
+        }
+        .boxed()
     }
 
-    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<()> {
-        self.fs.rename(from, to)
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check(path, FsError::Forbidden).await?;
+            if self.confine {
+                return self.confined(path, confine::remove_file).await;
+            }
+            let path = path.clone();
+            self.pooled(move |fs| async move { fs.remove_file(&path).await }).await
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check(from, FsError::Forbidden).await?;
+            self.check(to, FsError::Forbidden).await?;
+            if self.confine {
+                let to = to.as_rel_ospath().to_path_buf();
+                return self.confined(from, move |root, from| confine::rename(root, from, &to)).await;
+            }
+            let (from, to) = (from.clone(), to.clone());
+            self.pooled(move |fs| async move { fs.rename(&from, &to).await }).await
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.set_times(path, Some(tm), None).boxed()
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.set_times(path, None, Some(tm)).boxed()
     }
 
-    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<()> {
-        self.fs.copy(from, to)
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check(from, FsError::Forbidden).await?;
+            self.check(to, FsError::Forbidden).await?;
+            if self.confine {
+                let to = to.as_rel_ospath().to_path_buf();
+                let copy = move |root: &Path, from: &Path| confine::copy(root, from, &to).map(|_| ());
+                return self.confined(from, copy).await;
+            }
+            let (from, to) = (from.clone(), to.clone());
+            self.pooled(move |fs| async move { fs.copy(&from, &to).await }).await
+        }
+        .boxed()
     }
 
     #[cfg(feature = "quota")]
-    fn get_quota<'a>(&'a self) -> FsFuture<(u64, Option<u64>)> {
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
         use crate::cache;
         use fs_quota::*;
-        use futures::future::FutureExt;
         use std::time::Duration;
 
         lazy_static::lazy_static! {
@@ -99,7 +878,12 @@ impl DavFileSystem for UserFs {
 
         async move {
             let mut key = self.basedir.clone();
-            key.push(&self.uid.to_string());
+            match self.quota {
+                Quota::User => key.push(self.uid.to_string()),
+                Quota::Project => key.push("project"),
+                Quota::Filesystem => key.push("filesystem"),
+                Quota::None | Quota::Limit(_) => return Err(FsError::NotImplemented),
+            }
             let r = match QCACHE.get(&key) {
                 Some(r) => {
                     debug!("get_quota for {:?}: from cache", key);
@@ -108,12 +892,19 @@ impl DavFileSystem for UserFs {
                 None => {
                     let path = self.basedir.clone();
                     let uid = self.uid;
-                    let r = self
-                        .fs
-                        .blocking(move || {
-                            FsQuota::check(&path, Some(uid)).map_err(|_| FsError::GeneralFailure)
-                        })
-                        .await?;
+                    let r = match self.quota {
+                        Quota::User => {
+                            self.blocking(move || FsQuota::check(&path, Some(uid))).await
+                        },
+                        // project quotas can only be read with privileges,
+                        // so do not switch to the user for this one.
+                        Quota::Project => tokio::task::block_in_place(|| FsQuota::check_project(&path)),
+                        _ => self.blocking(move || FsQuota::system(&path)).await,
+                    };
+                    let r = r.map_err(|e| {
+                        debug!("get_quota for {:?}: {:?}", key, e);
+                        FsError::GeneralFailure
+                    })?;
                     debug!("get_quota for {:?}: insert to cache", key);
                     QCACHE.insert(key, r)
                 },
@@ -123,3 +914,21 @@ impl DavFileSystem for UserFs {
         .boxed()
     }
 }
+
+// A directory entry with its metadata: a followed symlink, or an entry
+// of a confined listing.
+#[derive(Debug)]
+struct LinkEntry {
+    name: Vec<u8>,
+    meta: Box<dyn DavMetaData>,
+}
+
+impl DavDirEntry for LinkEntry {
+    fn name(&self) -> Vec<u8> {
+        self.name.clone()
+    }
+
+    fn metadata(&self) -> FsFuture<'_, Box<dyn DavMetaData>> {
+        future::ok(self.meta.clone()).boxed()
+    }
+}
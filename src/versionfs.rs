@@ -0,0 +1,380 @@
+//
+// File versions: before a file is overwritten by a PUT, or replaced by a
+// MOVE or COPY, the old contents are kept in a versions directory in the
+// location.
+//
+// The versions of "/docs/a.txt" are "/.versions/docs/a.txt/<time>", with
+// the time like in the trash ("20210601-120000.123"). When a version is
+// added, the oldest versions of that file above the maximum number, and
+// those older than the maximum age, are removed; the janitor does that
+// for all files.
+//
+// The versions directory is hidden, or, with versions-visible, a read-only
+// collection: a version is restored by copying it over the file, which
+// keeps the current contents as a version again.
+//
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, SystemTime};
+
+use futures::future::{self, FutureExt};
+use futures::StreamExt;
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+use crate::trashfs::{create_dirs, encode, in_dir, join, stamp, stamp_time};
+
+const MAX_VERSIONS: usize = 10;
+
+#[derive(Clone)]
+pub struct VersionFs {
+    fs:       Box<dyn DavFileSystem>,
+    name:     String,
+    visible:  bool,
+    // the versions can be read by their path, even if not visible.
+    readable: bool,
+    max:      Option<usize>,
+    max_age:  Option<Duration>,
+    // the name of the versions of this request.
+    stamp:    String,
+}
+
+/// A version of a file.
+pub struct Version {
+    pub path: DavPath,
+    pub name: String,
+    pub time: i64,
+    pub meta: Box<dyn DavMetaData>,
+}
+
+impl VersionFs {
+    /// "name" is the versions directory, in the root of the location.
+    /// Without "max" and "days" there are at most 10 versions of a file.
+    pub fn new(
+        fs: Box<dyn DavFileSystem>,
+        name: &str,
+        visible: bool,
+        readable: bool,
+        max: Option<usize>,
+        days: Option<u64>,
+    ) -> Box<VersionFs>
+    {
+        let (max, days) = match (max, days) {
+            (None, None) => (Some(MAX_VERSIONS), None),
+            other => other,
+        };
+        Box::new(VersionFs {
+            fs,
+            name: name.trim_matches('/').to_string(),
+            visible,
+            readable,
+            max,
+            max_age: days.map(|d| Duration::from_secs(d * 86400)),
+            stamp: stamp(&time::now_utc()),
+        })
+    }
+
+    fn in_versions(&self, path: &DavPath) -> bool {
+        in_dir(path, &self.name)
+    }
+
+    // Access to the versions directory: hidden, or read-only.
+    fn check_read(&self, path: &DavPath) -> FsResult<()> {
+        match self.in_versions(path) && !self.visible && !self.readable {
+            true => Err(FsError::NotFound),
+            false => Ok(()),
+        }
+    }
+
+    fn check_write(&self, path: &DavPath) -> FsResult<()> {
+        self.check_read(path)?;
+        match self.in_versions(path) {
+            true => Err(FsError::Forbidden),
+            false => Ok(()),
+        }
+    }
+
+    // The directory with the versions of "path".
+    fn versions_dir(&self, path: &DavPath) -> FsResult<DavPath> {
+        let url = path.as_url_string();
+        let url = format!("/{}{}/", encode(&self.name), url.trim_end_matches('/'));
+        DavPath::new(&url).map_err(|_| FsError::GeneralFailure)
+    }
+
+    // Keep the contents of "path" as a version, if it is a file that is not
+    // empty. With "keep" it is copied, otherwise moved. Returns the
+    // directory of the versions, to prune after the file was replaced.
+    async fn save(&self, path: &DavPath, keep: bool) -> FsResult<Option<DavPath>> {
+        match self.fs.symlink_metadata(path).await {
+            Ok(meta) if meta.is_file() && meta.len() > 0 => {},
+            _ => return Ok(None),
+        }
+        let dir = self.versions_dir(path)?;
+        create_dirs(&*self.fs, &dir).await?;
+        let dest = join(&dir, self.stamp.as_bytes())?;
+        match keep {
+            true => self.fs.copy(path, &dest).await?,
+            false => self.fs.rename(path, &dest).await?,
+        }
+        Ok(Some(dir))
+    }
+
+    // Remove the versions above the maximum number, and the old ones.
+    async fn prune(&self, dir: Option<DavPath>) {
+        let dir = match dir {
+            Some(ref dir) => dir,
+            None => return,
+        };
+        let mut names = match self.list(dir).await {
+            Ok(names) => names,
+            Err(_) => return,
+        };
+        names.reverse();
+
+        let cutoff = self.max_age.map(|a| time::now_utc().to_timespec().sec - a.as_secs() as i64);
+        for (idx, version) in names.iter().enumerate() {
+            let too_many = self.max.map(|m| idx >= m).unwrap_or(false);
+            let too_old = cutoff.map(|c| version.time < c).unwrap_or(false);
+            if !too_many && !too_old {
+                continue;
+            }
+            if let Err(e) = self.fs.remove_file(&version.path).await {
+                warn!("versions: remove {}: {:?}", version.path.as_url_string(), e);
+            }
+        }
+    }
+
+    /// Prune the versions of all files, like when a version is added.
+    pub async fn prune_all(&self) -> FsResult<()> {
+        let root = DavPath::new(&format!("/{}/", encode(&self.name))).map_err(|_| FsError::GeneralFailure)?;
+        self.prune_tree(root).await
+    }
+
+    // A directory has versions in it, directories with more, or both.
+    fn prune_tree(&self, dir: DavPath) -> future::BoxFuture<'_, FsResult<()>> {
+        async move {
+            let mut entries = self.fs.read_dir(&dir, ReadDirMeta::DataSymlink).await?;
+            let mut versions = false;
+            let mut dirs = Vec::new();
+            while let Some(entry) = entries.next().await {
+                match entry.metadata().await {
+                    Ok(meta) if meta.is_dir() => dirs.push(entry.name()),
+                    Ok(_) => versions |= stamp_time(&String::from_utf8_lossy(&entry.name())).is_some(),
+                    Err(_) => {},
+                }
+            }
+            if versions {
+                self.prune(Some(dir.clone())).await;
+            }
+            for name in dirs {
+                self.prune_tree(join(&dir, &name)?).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // The versions in a directory, oldest first.
+    async fn list(&self, dir: &DavPath) -> FsResult<Vec<Version>> {
+        let mut entries = self.fs.read_dir(dir, ReadDirMeta::Data).await?;
+        let mut versions = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let name = String::from_utf8_lossy(&entry.name()).into_owned();
+            let time = match stamp_time(&name) {
+                Some(t) => t,
+                None => continue,
+            };
+            if let Ok(meta) = entry.metadata().await {
+                let path = join(dir, name.as_bytes())?;
+                versions.push(Version { path, name, time, meta });
+            }
+        }
+        versions.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(versions)
+    }
+
+    /// The versions of a file, oldest first.
+    pub async fn versions(&self, path: &DavPath) -> FsResult<Vec<Version>> {
+        match self.list(&self.versions_dir(path)?).await {
+            Err(FsError::NotFound) => Ok(Vec::new()),
+            other => other,
+        }
+    }
+
+    /// Is this a file (and not a directory).
+    pub async fn is_file(&self, path: &DavPath) -> FsResult<bool> {
+        self.check_read(path)?;
+        Ok(self.fs.metadata(path).await?.is_file())
+    }
+
+    /// Keep the current contents of a file as a version. Returns the path
+    /// of the version.
+    pub async fn checkin(&self, path: &DavPath) -> FsResult<DavPath> {
+        self.check_write(path)?;
+        if !self.fs.metadata(path).await?.is_file() {
+            return Err(FsError::Forbidden);
+        }
+        let dir = self.versions_dir(path)?;
+        create_dirs(&*self.fs, &dir).await?;
+        let dest = join(&dir, self.stamp.as_bytes())?;
+        self.fs.copy(path, &dest).await?;
+        self.prune(Some(dir)).await;
+        Ok(dest)
+    }
+}
+
+impl DavFileSystem for VersionFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            if options.write || options.append || options.create || options.create_new {
+                self.check_write(path)?;
+                if !options.create_new {
+                    let dir = self.save(path, true).await?;
+                    self.prune(dir).await;
+                }
+            } else {
+                self.check_read(path)?;
+            }
+            self.fs.open(path, options).await
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        async move {
+            self.check_read(path)?;
+            let strm = self.fs.read_dir(path, meta).await?;
+            if self.visible || path.as_bytes() != b"/" {
+                return Ok(strm);
+            }
+            let name = self.name.as_bytes().to_vec();
+            let strm = strm.filter(move |e| future::ready(e.name() != name));
+            Ok(Box::pin(strm) as FsStream<Box<dyn DavDirEntry>>)
+        }
+        .boxed()
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            self.check_read(path)?;
+            self.fs.metadata(path).await
+        }
+        .boxed()
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        async move {
+            self.check_read(path)?;
+            self.fs.symlink_metadata(path).await
+        }
+        .boxed()
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check_write(path)?;
+            self.fs.create_dir(path).await
+        }
+        .boxed()
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check_write(path)?;
+            self.fs.remove_dir(path).await
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check_write(path)?;
+            self.fs.remove_file(path).await
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check_write(from)?;
+            self.check_write(to)?;
+            let dir = self.save(to, false).await?;
+            self.fs.rename(from, to).await?;
+            self.prune(dir).await;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    // Copying a version out of the versions directory restores it.
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.check_read(from)?;
+            self.check_write(to)?;
+            let dir = self.save(to, true).await?;
+            self.fs.copy(from, to).await?;
+            self.prune(dir).await;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            self.check_write(path)?;
+            self.fs.set_accessed(path, tm).await
+        }
+        .boxed()
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        async move {
+            self.check_write(path)?;
+            self.fs.set_modified(path, tm).await
+        }
+        .boxed()
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        async move {
+            self.check_write(path)?;
+            self.fs.patch_props(path, patch).await
+        }
+        .boxed()
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        async move {
+            self.check_read(path)?;
+            self.fs.get_props(path, do_content).await
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        async move {
+            self.check_read(path)?;
+            self.fs.get_prop(path, prop).await
+        }
+        .boxed()
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
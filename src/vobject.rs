@@ -0,0 +1,412 @@
+//
+// iCalendar (RFC 5545) and vCard (RFC 6350) objects, as far as needed for
+// the filters of calendar-query and addressbook-query reports.
+//
+// An object is parsed into its components and their properties. Times are
+// in seconds since the epoch. Floating times and times with a TZID are
+// taken as UTC, which is good enough to find the objects in a time range.
+//
+use xmltree::{Element, XMLNode};
+
+/// A component, like VCALENDAR, VEVENT or VCARD. Names are upper case.
+#[derive(Debug, Default)]
+pub struct Component {
+    pub name:  String,
+    pub props: Vec<Property>,
+    pub comps: Vec<Component>,
+}
+
+/// A property, with its parameters.
+#[derive(Debug)]
+pub struct Property {
+    pub name:   String,
+    pub params: Vec<(String, String)>,
+    pub value:  String,
+}
+
+impl Component {
+    pub fn prop(&self, name: &str) -> Option<&Property> {
+        self.props.iter().find(|p| p.name == name)
+    }
+
+    fn time(&self, name: &str) -> Option<(i64, bool)> {
+        self.prop(name).and_then(|p| parse_time(&p.value))
+    }
+}
+
+// A time range of a filter. A missing start or end is open.
+struct Range {
+    start: i64,
+    end:   i64,
+}
+
+/// Parse an object. Text after its END is ignored.
+pub fn parse(data: &str) -> Option<Component> {
+    let data = data.replace("\r\n ", "").replace("\r\n\t", "").replace("\n ", "").replace("\n\t", "");
+    let mut stack: Vec<Component> = Vec::new();
+    for line in data.lines().filter(|l| !l.is_empty()) {
+        let prop = parse_line(line)?;
+        match prop.name.as_str() {
+            "BEGIN" => {
+                stack.push(Component {
+                    name: prop.value.to_ascii_uppercase(),
+                    ..Component::default()
+                })
+            },
+            "END" => {
+                let comp = stack.pop()?;
+                if !comp.name.eq_ignore_ascii_case(&prop.value) {
+                    return None;
+                }
+                match stack.last_mut() {
+                    Some(parent) => parent.comps.push(comp),
+                    None => return Some(comp),
+                }
+            },
+            _ => stack.last_mut()?.props.push(prop),
+        }
+    }
+    None
+}
+
+// Split at "sep", but not inside quotes.
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (idx, c) in s.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(&s[start..idx]);
+            start = idx + 1;
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+// A content line: name *(";" param) ":" value.
+fn parse_line(line: &str) -> Option<Property> {
+    let mut quoted = false;
+    let colon = line.char_indices().find(|&(_, c)| {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        c == ':' && !quoted
+    })?;
+    let (head, value) = (&line[..colon.0], &line[colon.0 + 1..]);
+    let mut parts = split_unquoted(head, ';').into_iter();
+    // a vCard property can have a group, like "item1.EMAIL".
+    let name = parts.next()?.to_ascii_uppercase();
+    let name = match name.rfind('.') {
+        Some(idx) => name[idx + 1..].to_string(),
+        None => name,
+    };
+    let params = parts
+        .filter_map(|p| p.split_once('='))
+        .map(|(n, v)| (n.to_ascii_uppercase(), v.trim_matches('"').to_string()))
+        .collect();
+    Some(Property {
+        name,
+        params,
+        value: value.to_string(),
+    })
+}
+
+// Days since the epoch of a date in the Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// A DATE or DATE-TIME value, as seconds since the epoch, and whether
+/// it is a DATE.
+pub fn parse_time(value: &str) -> Option<(i64, bool)> {
+    let value = value.trim().trim_end_matches('Z');
+    let (date, time) = match value.split_once('T') {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+    let num = |s: &str, from: usize, to: usize| -> Option<i64> { s.get(from..to)?.parse().ok() };
+    if date.len() != 8 || !date.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let days = days_from_civil(num(date, 0, 4)?, num(date, 4, 6)?, num(date, 6, 8)?);
+    let secs = match time {
+        Some(t) if t.len() == 6 && t.bytes().all(|c| c.is_ascii_digit()) => {
+            num(t, 0, 2)? * 3600 + num(t, 2, 4)? * 60 + num(t, 4, 6)?
+        },
+        Some(_) => return None,
+        None => 0,
+    };
+    Some((days * 86400 + secs, time.is_none()))
+}
+
+/// A DURATION value, in seconds.
+pub fn parse_duration(value: &str) -> Option<i64> {
+    let value = value.trim();
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(v) => (true, v),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let mut total = 0;
+    let mut num = String::new();
+    for c in value.strip_prefix('P')?.chars() {
+        let unit = match c {
+            '0'..='9' => {
+                num.push(c);
+                continue;
+            },
+            'T' => continue,
+            'W' => 7 * 86400,
+            'D' => 86400,
+            'H' => 3600,
+            'M' => 60,
+            'S' => 1,
+            _ => return None,
+        };
+        total += num.parse::<i64>().ok()? * unit;
+        num.clear();
+    }
+    match num.is_empty() {
+        true => Some(if negative { -total } else { total }),
+        false => None,
+    }
+}
+
+fn children(elem: &Element) -> impl Iterator<Item = &Element> {
+    elem.children.iter().filter_map(XMLNode::as_element)
+}
+
+fn attr<'a>(elem: &'a Element, name: &str) -> Option<&'a str> {
+    elem.attributes.get(name).map(String::as_str)
+}
+
+fn range(elem: &Element) -> Option<Range> {
+    let time = |name| {
+        match attr(elem, name) {
+            Some(t) => parse_time(t).map(|t| Some(t.0)),
+            None => Some(None),
+        }
+    };
+    Some(Range {
+        start: time("start")?.unwrap_or(i64::MIN),
+        end:   time("end")?.unwrap_or(i64::MAX),
+    })
+}
+
+// Does a component overlap the range (RFC 4791, 9.9). A recurring
+// component is taken to overlap anything after its start.
+fn overlaps(comp: &Component, range: &Range) -> bool {
+    let duration = comp.prop("DURATION").and_then(|p| parse_duration(&p.value));
+    let (start, end) = match comp.name.as_str() {
+        "VEVENT" | "VJOURNAL" => {
+            let (start, date) = match comp.time("DTSTART") {
+                Some(t) => t,
+                None => return false,
+            };
+            let end = match (comp.time("DTEND"), duration) {
+                (Some((end, _)), _) => end,
+                (None, Some(d)) => start + d,
+                (None, None) if date => start + 86400,
+                (None, None) => start,
+            };
+            (start, end)
+        },
+        "VTODO" => {
+            match (comp.time("DTSTART"), comp.time("DUE"), duration) {
+                (Some((start, _)), Some((due, _)), _) => (start, due),
+                (Some((start, _)), None, Some(d)) => (start, start + d),
+                (Some((start, _)), None, None) => (start, start),
+                (None, Some((due, _)), _) => (due, due),
+                (None, None, _) => return true,
+            }
+        },
+        _ => return true,
+    };
+    if comp.prop("RRULE").is_some() || comp.prop("RDATE").is_some() {
+        return start < range.end;
+    }
+    match start == end {
+        true => start >= range.start && start < range.end,
+        false => start < range.end && end > range.start,
+    }
+}
+
+/// Does a calendar object match the C:filter of a calendar-query.
+pub fn calendar_matches(filter: &Element, calendar: &Component) -> bool {
+    let comps = std::slice::from_ref(calendar);
+    children(filter).all(|f| f.name != "comp-filter" || comp_filter(f, comps))
+}
+
+/// Does a vCard match the CR:filter of an addressbook-query. Its
+/// prop-filters must match any of them, unless it has test="allof".
+pub fn card_matches(filter: &Element, card: &Component) -> bool {
+    if !card.name.eq_ignore_ascii_case("VCARD") {
+        return false;
+    }
+    let tests: Vec<_> = children(filter).filter(|f| f.name == "prop-filter").collect();
+    let matches = |f: &&Element| prop_filter(f, &card.props, true);
+    match attr(filter, "test") {
+        Some("allof") => tests.iter().all(matches),
+        _ => tests.is_empty() || tests.iter().any(matches),
+    }
+}
+
+// Is there a component in "comps" that matches the comp-filter.
+fn comp_filter(filter: &Element, comps: &[Component]) -> bool {
+    let name = attr(filter, "name").unwrap_or_default();
+    let mut found = comps.iter().filter(|c| c.name.eq_ignore_ascii_case(name));
+    if filter.get_child("is-not-defined").is_some() {
+        return found.next().is_none();
+    }
+    found.any(|comp| {
+        children(filter).all(|f| {
+            match f.name.as_str() {
+                "time-range" => range(f).map(|r| overlaps(comp, &r)).unwrap_or(false),
+                "comp-filter" => comp_filter(f, &comp.comps),
+                "prop-filter" => prop_filter(f, &comp.props, false),
+                _ => true,
+            }
+        })
+    })
+}
+
+// Is there a property in "props" that matches the prop-filter. The tests
+// in it must all match, or any of them, with "anyof" as the default.
+fn prop_filter(filter: &Element, props: &[Property], anyof: bool) -> bool {
+    let name = attr(filter, "name").unwrap_or_default();
+    let mut found = props.iter().filter(|p| p.name.eq_ignore_ascii_case(name));
+    if filter.get_child("is-not-defined").is_some() {
+        return found.next().is_none();
+    }
+    let anyof = match attr(filter, "test") {
+        Some(test) => test == "anyof",
+        None => anyof,
+    };
+    found.any(|prop| {
+        let tests: Vec<bool> = children(filter).filter_map(|f| {
+            match f.name.as_str() {
+                "text-match" => Some(text_match(f, &prop.value)),
+                "param-filter" => Some(param_filter(f, prop)),
+                "time-range" => {
+                    let range = range(f)?;
+                    parse_time(&prop.value).map(|(t, _)| t >= range.start && t < range.end)
+                },
+                _ => None,
+            }
+        })
+        .collect();
+        match anyof {
+            true => tests.is_empty() || tests.contains(&true),
+            false => !tests.contains(&false),
+        }
+    })
+}
+
+fn param_filter(filter: &Element, prop: &Property) -> bool {
+    let name = attr(filter, "name").unwrap_or_default();
+    let mut found = prop.params.iter().filter(|(n, _)| n.eq_ignore_ascii_case(name));
+    if filter.get_child("is-not-defined").is_some() {
+        return found.next().is_none();
+    }
+    match filter.get_child("text-match") {
+        Some(f) => found.any(|(_, value)| text_match(f, value)),
+        None => found.next().is_some(),
+    }
+}
+
+// A text-match, with the i;ascii-casemap collation unless it is i;octet.
+fn text_match(filter: &Element, value: &str) -> bool {
+    let text = filter.get_text().unwrap_or_default();
+    let (value, text) = match attr(filter, "collation") {
+        Some("i;octet") => (value.to_string(), text.to_string()),
+        _ => (value.to_ascii_lowercase(), text.to_ascii_lowercase()),
+    };
+    let found = match attr(filter, "match-type") {
+        Some("equals") => value == text,
+        Some("starts-with") => value.starts_with(&text),
+        Some("ends-with") => value.ends_with(&text),
+        _ => value.contains(&text),
+    };
+    found != (attr(filter, "negate-condition") == Some("yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVENT: &str = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\nUID:1\r\n\
+                         SUMMARY;LANGUAGE=en:Team\r\n  meeting\r\nDTSTART:20240105T100000Z\r\n\
+                         DURATION:PT1H\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n";
+
+    fn filter(xml: &str) -> Element {
+        Element::parse(xml.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let cal = parse(EVENT).unwrap();
+        assert_eq!(cal.name, "VCALENDAR");
+        let event = &cal.comps[0];
+        assert_eq!(event.name, "VEVENT");
+        let summary = event.prop("SUMMARY").unwrap();
+        assert_eq!(summary.value, "Team meeting");
+        assert_eq!(summary.params, vec![("LANGUAGE".to_string(), "en".to_string())]);
+        assert_eq!(parse_time("19700102").unwrap(), (86400, true));
+        assert_eq!(parse_time("20240105T100000Z").unwrap().0, 1704448800);
+        assert_eq!(parse_duration("-P1DT2H").unwrap(), -93600);
+        assert!(parse("BEGIN:VCALENDAR\r\nEND:VEVENT\r\n").is_none());
+    }
+
+    #[test]
+    fn test_filter() {
+        let cal = parse(EVENT).unwrap();
+        let query = |inner: &str| {
+            let xml = format!(
+                "<C:filter xmlns:C=\"urn:ietf:params:xml:ns:caldav\">\
+                 <C:comp-filter name=\"VCALENDAR\"><C:comp-filter name=\"VEVENT\">{}\
+                 </C:comp-filter></C:comp-filter></C:filter>",
+                inner
+            );
+            calendar_matches(&filter(&xml), &cal)
+        };
+        assert!(query(""));
+        assert!(query("<C:time-range start=\"20240105T103000Z\" end=\"20240106T000000Z\"/>"));
+        assert!(!query("<C:time-range start=\"20240105T110000Z\"/>"));
+        let summary = "<C:prop-filter name=\"SUMMARY\"><C:text-match>MEETING</C:text-match></C:prop-filter>";
+        assert!(query(summary));
+        assert!(!query("<C:prop-filter name=\"LOCATION\"><C:text-match>x</C:text-match></C:prop-filter>"));
+        assert!(query("<C:prop-filter name=\"LOCATION\"><C:is-not-defined/></C:prop-filter>"));
+        assert!(!query("<C:comp-filter name=\"VALARM\"/>"));
+    }
+
+    #[test]
+    fn test_card_filter() {
+        let card = "BEGIN:VCARD\nVERSION:3.0\nFN:Jane Doe\n\
+                    item1.EMAIL;TYPE=work:jane@example.com\nEND:VCARD\n";
+        let card = parse(card).unwrap();
+        let query = |test: &str, inner: &str| {
+            let xml = format!(
+                "<CR:filter xmlns:CR=\"urn:ietf:params:xml:ns:carddav\" {}>{}</CR:filter>",
+                test, inner
+            );
+            card_matches(&filter(&xml), &card)
+        };
+        let fn_doe = "<CR:prop-filter name=\"FN\"><CR:text-match match-type=\"ends-with\">doe</CR:text-match>\
+                      </CR:prop-filter>";
+        let email = "<CR:prop-filter name=\"EMAIL\"><CR:param-filter name=\"TYPE\">\
+                     <CR:text-match match-type=\"equals\">home</CR:text-match></CR:param-filter>\
+                     </CR:prop-filter>";
+        assert!(query("", ""));
+        assert!(query("", fn_doe));
+        assert!(query("", &format!("{}{}", fn_doe, email)));
+        assert!(!query("test=\"allof\"", &format!("{}{}", fn_doe, email)));
+        assert!(!query("", email));
+    }
+}
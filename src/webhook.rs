@@ -0,0 +1,171 @@
+//
+// Webhooks ([webhook.NAME]): a POST to a url after a file was changed.
+//
+// Locations list the webhooks they use with "webhooks = [ NAME ]". After
+// a successful PUT, DELETE, MOVE, COPY or MKCOL, every webhook that wants
+// that event gets a JSON body:
+//
+// {"event":"put","user":"bob","path":"/files/a.txt","destination":null,
+//  "size":1234,"time":"2021-06-01T12:00:00Z"}
+//
+// With a secret, the X-Webhook-Signature header is "sha256=" and the hex
+// HMAC-SHA256 of the body. Deliveries run in the background; on an error,
+// a timeout, 429 or 5xx they are retried with an increasing delay.
+//
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnector;
+use ring::hmac;
+use serde_json::json;
+use webdav_handler::DavMethod;
+
+use crate::config::{self, Config, WebhookEvent};
+
+// Deliveries beyond this that are still running, are dropped.
+const MAX_PENDING: usize = 1024;
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    static ref CLIENT: hyper::Client<HttpsConnector<HttpConnector>> =
+        hyper::Client::builder().build(HttpsConnector::with_native_roots());
+}
+
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// A file event.
+pub struct Event {
+    pub event:       WebhookEvent,
+    pub user:        Option<String>,
+    pub path:        String,
+    pub destination: Option<String>,
+    pub size:        Option<u64>,
+}
+
+/// The event of a method, if there is one.
+pub fn event(method: DavMethod) -> Option<WebhookEvent> {
+    match method {
+        DavMethod::Put | DavMethod::Patch => Some(WebhookEvent::Put),
+        DavMethod::Delete => Some(WebhookEvent::Delete),
+        DavMethod::Move => Some(WebhookEvent::Move),
+        DavMethod::Copy => Some(WebhookEvent::Copy),
+        DavMethod::MkCol => Some(WebhookEvent::Mkcol),
+        _ => None,
+    }
+}
+
+// Without "events", a webhook gets all of them.
+fn wants(webhook: &config::Webhook, event: WebhookEvent) -> bool {
+    webhook.events.as_ref().map(|e| e.contains(&event)).unwrap_or(true)
+}
+
+/// Does any of these webhooks want this event.
+pub fn wanted(config: &Config, names: &[String], event: WebhookEvent) -> bool {
+    names
+        .iter()
+        .filter_map(|name| config.webhook.get(name))
+        .any(|w| wants(w, event))
+}
+
+fn event_name(event: WebhookEvent) -> &'static str {
+    match event {
+        WebhookEvent::Put => "put",
+        WebhookEvent::Delete => "delete",
+        WebhookEvent::Move => "move",
+        WebhookEvent::Copy => "copy",
+        WebhookEvent::Mkcol => "mkcol",
+    }
+}
+
+fn signature(secret: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, body);
+    let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
+
+/// Send the event to the webhooks that want it.
+pub fn send(config: &Config, names: &[String], event: Event) {
+    let body = json!({
+        "event": event_name(event.event),
+        "user": event.user,
+        "path": event.path,
+        "destination": event.destination,
+        "size": event.size,
+        "time": time::now_utc().rfc3339().to_string(),
+    })
+    .to_string();
+    for name in names {
+        let webhook = match config.webhook.get(name) {
+            Some(w) if wants(w, event.event) => w,
+            _ => continue,
+        };
+        if PENDING.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING {
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+            warn!("webhook {}: too many pending deliveries, dropped {}", name, event.path);
+            continue;
+        }
+        tokio::spawn(deliver(
+            name.clone(),
+            webhook.clone(),
+            event_name(event.event),
+            body.clone(),
+        ));
+    }
+}
+
+async fn deliver(name: String, webhook: config::Webhook, event: &'static str, body: String) {
+    let signature = webhook.secret.as_ref().map(|s| signature(s, body.as_bytes()));
+    let timeout = Duration::from_secs(webhook.timeout.unwrap_or(10));
+    let retries = webhook.retries.unwrap_or(3);
+    let mut delay = Duration::from_secs(1);
+
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_DELAY);
+        }
+        let mut req = http::Request::post(&webhook.url)
+            .header("Content-Type", "application/json")
+            .header("User-Agent", "webdav-server-rs")
+            .header("X-Webhook-Event", event);
+        if let Some(ref signature) = signature {
+            req = req.header("X-Webhook-Signature", signature.as_str());
+        }
+        let req = req.body(hyper::Body::from(body.clone())).unwrap();
+        let error = match tokio::time::timeout(timeout, CLIENT.request(req)).await {
+            Ok(Ok(resp)) if resp.status().is_success() => break,
+            Ok(Ok(resp)) => {
+                let status = resp.status();
+                if !status.is_server_error() && status != http::StatusCode::TOO_MANY_REQUESTS {
+                    warn!("webhook {}: {}: {}, not retried", name, webhook.url, status);
+                    break;
+                }
+                status.to_string()
+            },
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "timeout".to_string(),
+        };
+        if attempt == retries {
+            warn!("webhook {}: {}: {}, giving up", name, webhook.url, error);
+        } else {
+            debug!("webhook {}: {}: {}, retrying", name, webhook.url, error);
+        }
+    }
+    PENDING.fetch_sub(1, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2.
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
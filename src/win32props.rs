@@ -0,0 +1,240 @@
+//
+// The Win32 properties that the Windows WebClient and Office set with
+// PROPPATCH: Win32CreationTime, Win32LastAccessTime, Win32LastModifiedTime
+// and Win32FileAttributes, in urn:schemas-microsoft-com:.
+//
+// The handler answers 200 to those, and forgets them. Here the access and
+// modification times are set on the file, and the readonly attribute
+// clears the write bits of a local file. In a filesystem location the
+// creation time and the attributes (hidden, system, ...) are also kept in
+// extended attributes, and a PROPFIND gives them back.
+//
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::stream;
+use http::StatusCode;
+use hyper::body::{Bytes, HttpBody};
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::DavFileSystem;
+use xmltree::Element;
+
+use crate::report;
+use crate::userfs::UserFs;
+
+const NS_MS: &str = "urn:schemas-microsoft-com:";
+const ATTRIBUTES: &[u8] = b"user.webdav.win32attributes\0";
+const CREATION_TIME: &[u8] = b"user.webdav.win32creationtime\0";
+const READONLY: u32 = 0x0001;
+
+fn attr(name: &'static [u8]) -> &'static CStr {
+    CStr::from_bytes_with_nul(name).unwrap()
+}
+
+fn parse_time(s: &str) -> Option<SystemTime> {
+    let tm = time::strptime(s.trim(), "%a, %d %b %Y %H:%M:%S GMT").ok()?;
+    let secs = u64::try_from(tm.to_timespec().sec).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// The Win32 properties that a PROPPATCH sets, as (name, value).
+pub type Win32Props = Vec<(String, String)>;
+
+fn sets(root: &Element) -> Win32Props {
+    let elems = |e: &Element| e.children.iter().filter_map(|n| n.as_element()).cloned().collect::<Vec<_>>();
+    let mut props = Vec::new();
+    for set in elems(root).iter().filter(|e| report::is_dav(e, "set")) {
+        for prop in elems(set).iter().filter(|e| report::is_dav(e, "prop")) {
+            for e in elems(prop).into_iter().filter(|e| e.namespace.as_deref() == Some(NS_MS)) {
+                let value = e.get_text().map(|t| t.into_owned()).unwrap_or_default();
+                props.push((e.name, value));
+            }
+        }
+    }
+    props
+}
+
+/// Read the body of a PROPPATCH for the Win32 properties in it. The
+/// request is given back, for the handler.
+pub async fn proppatch(
+    req: http::Request<hyper::Body>,
+) -> Result<(http::Request<hyper::Body>, Win32Props), http::Response<String>>
+{
+    let (parts, body) = req.into_parts();
+    let body = report::body(http::Request::new(body)).await?;
+    let props = Element::parse(&body[..]).map(|root| sets(&root)).unwrap_or_default();
+    Ok((http::Request::from_parts(parts, body.into()), props))
+}
+
+/// After the handler said yes, set them. A failure is only logged; the
+/// WebClient does not care.
+pub async fn apply(props: Win32Props, fs: &dyn DavFileSystem, local: Option<&UserFs>, path: &DavPath) {
+    for (name, value) in props {
+        let res = match (name.as_str(), local) {
+            ("Win32LastModifiedTime", _) => {
+                match parse_time(&value) {
+                    Some(tm) => fs.set_modified(path, tm).await,
+                    None => continue,
+                }
+            },
+            ("Win32LastAccessTime", _) => {
+                match parse_time(&value) {
+                    Some(tm) => fs.set_accessed(path, tm).await,
+                    None => continue,
+                }
+            },
+            ("Win32CreationTime", Some(local)) => {
+                local.set_xattr(path, attr(CREATION_TIME), value.into_bytes()).await
+            },
+            ("Win32FileAttributes", Some(local)) => {
+                let bits = match u32::from_str_radix(value.trim(), 16) {
+                    Ok(bits) => bits,
+                    Err(_) => continue,
+                };
+                // before a readonly file can not be changed anymore.
+                let value = format!("{:08x}", bits).into_bytes();
+                match local.set_xattr(path, attr(ATTRIBUTES), value).await {
+                    Ok(()) => local.set_readonly(path, bits & READONLY != 0).await,
+                    Err(e) => Err(e),
+                }
+            },
+            _ => continue,
+        };
+        if let Err(e) = res {
+            debug!("win32props: {}: {}: {:?}", path.as_url_string(), name, e);
+        }
+    }
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Replace the value of <Z:name> in a D:response.
+fn replace_value(xml: &mut String, name: &str, value: &str) {
+    let (open, close) = (format!("<Z:{}>", name), format!("</Z:{}>", name));
+    if let Some(start) = xml.find(&open).map(|s| s + open.len()) {
+        if let Some(end) = xml[start..].find(&close).map(|e| start + e) {
+            xml.replace_range(start..end, &report::escape(value));
+        }
+    }
+}
+
+// One D:response, with what was kept for its href.
+async fn response(xml: &str, local: &UserFs, path: &DavPath, prefix: &str) -> String {
+    let mut xml = xml.to_string();
+    let href = xml.split("<D:href>").nth(1).and_then(|s| s.split("</D:href>").next());
+    let davpath = match href.and_then(|href| report::href_path(&unescape(href), path, prefix)) {
+        Some(p) => p,
+        None => return xml,
+    };
+    for (name, xattr) in [("Win32FileAttributes", ATTRIBUTES), ("Win32CreationTime", CREATION_TIME)].iter() {
+        if !xml.contains(&format!("<Z:{}>", name)) {
+            continue;
+        }
+        if let Ok(Some(value)) = local.get_xattr(&davpath, attr(xattr)).await {
+            replace_value(&mut xml, name, &String::from_utf8_lossy(&value));
+        }
+    }
+    xml
+}
+
+fn find(data: &[u8], what: &[u8]) -> Option<usize> {
+    data.windows(what.len()).position(|w| w == what)
+}
+
+// The complete D:responses at the start of the data, with what was kept.
+async fn responses(data: &mut Vec<u8>, local: &UserFs, path: &DavPath, prefix: &str) -> Vec<u8> {
+    const START: &[u8] = b"<D:response>";
+    const END: &[u8] = b"</D:response>";
+    let mut out = Vec::new();
+    let mut done = 0;
+    while let Some(start) = find(&data[done..], START).map(|s| done + s) {
+        let end = match find(&data[start..], END) {
+            Some(end) => start + end,
+            None => break,
+        };
+        out.extend_from_slice(&data[done..start]);
+        match std::str::from_utf8(&data[start..end]) {
+            Ok(xml) if xml.contains("<Z:Win32") => {
+                out.extend_from_slice(response(xml, local, path, prefix).await.as_bytes());
+            },
+            _ => out.extend_from_slice(&data[start..end]),
+        }
+        done = end;
+    }
+    data.drain(..done);
+    out
+}
+
+/// Put the creation times and attributes that were kept in a PROPFIND
+/// response. It is streamed, one D:response at a time.
+pub fn propfind(
+    resp: http::Response<hyper::Body>,
+    local: &UserFs,
+    path: &DavPath,
+    prefix: &str,
+) -> http::Response<hyper::Body>
+{
+    if resp.status() != StatusCode::MULTI_STATUS {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    parts.headers.remove(http::header::CONTENT_LENGTH);
+    let state = Some((body, Vec::new(), local.clone(), path.clone(), prefix.to_string()));
+    let strm = stream::unfold(state, |state| {
+        async move {
+            let (mut body, mut data, local, path, prefix) = state?;
+            loop {
+                match body.data().await {
+                    Some(Ok(chunk)) => {
+                        data.extend_from_slice(&chunk);
+                        let out = responses(&mut data, &local, &path, &prefix).await;
+                        if !out.is_empty() {
+                            return Some((Ok(Bytes::from(out)), Some((body, data, local, path, prefix))));
+                        }
+                    },
+                    Some(Err(e)) => {
+                        debug!("win32props: propfind: {}", e);
+                        return Some((Err(e), None));
+                    },
+                    None => return Some((Ok(Bytes::from(data)), None)),
+                }
+            }
+        }
+    });
+    http::Response::from_parts(parts, hyper::Body::wrap_stream(strm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_win32props() {
+        let xml = concat!(
+            r#"<?xml version="1.0"?><D:propertyupdate xmlns:D="DAV:" xmlns:Z="urn:schemas-microsoft-com:">"#,
+            "<D:set><D:prop><Z:Win32FileAttributes>00000021</Z:Win32FileAttributes>",
+            "<Z:Win32LastModifiedTime>Wed, 14 Oct 2026 09:09:29 GMT</Z:Win32LastModifiedTime>",
+            "<D:displayname>x</D:displayname></D:prop></D:set></D:propertyupdate>"
+        );
+        let props = sets(&Element::parse(xml.as_bytes()).unwrap());
+        assert_eq!(props.len(), 2);
+        assert_eq!(props[0], ("Win32FileAttributes".to_string(), "00000021".to_string()));
+        let tm = parse_time(&props[1].1).unwrap();
+        assert_eq!(tm.duration_since(UNIX_EPOCH).unwrap().as_secs(), 1791968969);
+
+        let xml = "<D:href>/a</D:href><Z:Win32FileAttributes>00000020</Z:Win32FileAttributes>";
+        let mut xml = xml.to_string();
+        replace_value(&mut xml, "Win32FileAttributes", "00000022");
+        assert!(xml.ends_with("<Z:Win32FileAttributes>00000022</Z:Win32FileAttributes>"));
+
+        assert_eq!(find(b"abcabc", b"ca"), Some(2));
+        assert_eq!(find(b"abc", b"abcd"), None);
+    }
+}
@@ -0,0 +1,174 @@
+//
+// Quirks for the Windows WebClient, the Mini-Redirector behind "net use"
+// and mapped network drives.
+//
+// Before it uses \\host\dav\share it asks the root of the server:
+// OPTIONS / and PROPFIND /. With no location at / those would get a 404,
+// and the mapping fails. So the directories above a location with
+// windows = true are answered here, as collections with the next part of
+// the route in them. The paths that it sends with backslashes (%5C) in
+// them get slashes instead.
+//
+use http::{HeaderMap, StatusCode};
+use percent_encoding::{percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use webdav_handler::DavMethod;
+
+use crate::config::Location;
+use crate::report::escape;
+
+const PATH: &AsciiSet = &NON_ALPHANUMERIC.remove(b'.').remove(b'-').remove(b'_').remove(b'~').remove(b'/');
+
+/// The request is from the Windows WebClient.
+pub fn is_webclient(headers: &HeaderMap) -> bool {
+    let ua = headers.get("user-agent").and_then(|h| h.to_str().ok()).unwrap_or("");
+    ua.contains("Microsoft-WebDAV-MiniRedir")
+}
+
+fn slashes(path: &str) -> String {
+    let path = path.replace("%5C", "/").replace("%5c", "/");
+    let mut out = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c != '/' || !out.ends_with('/') {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// A path with backslashes in it, with slashes instead.
+pub fn translate<B>(mut req: http::Request<B>) -> http::Request<B> {
+    let path = req.uri().path();
+    if !path.contains("%5C") && !path.contains("%5c") {
+        return req;
+    }
+    let path = match req.uri().query() {
+        Some(query) => format!("{}?{}", slashes(path), query),
+        None => slashes(path),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = match path.parse() {
+        Ok(pq) => Some(pq),
+        Err(_) => return req,
+    };
+    if let Ok(uri) = http::Uri::from_parts(parts) {
+        debug!("winclient: {} -> {}", req.uri(), uri);
+        *req.uri_mut() = uri;
+    }
+    req
+}
+
+/// Add MS-Author-Via to an OPTIONS response.
+pub fn options(headers: &mut HeaderMap) {
+    headers.insert("MS-Author-Via", "DAV".parse().unwrap());
+}
+
+// What is in a directory above the windows locations, if it is one.
+// "/dav/:user/*path" is below "/" and "/dav/"; in "/" is "dav".
+fn children(path: &[u8], locations: &[Location]) -> Option<Vec<String>> {
+    let path = String::from_utf8_lossy(path);
+    let path = match path.ends_with('/') {
+        true => path.into_owned(),
+        false => format!("{}/", path),
+    };
+    let mut found = false;
+    let mut children = Vec::new();
+    let routes = locations.iter().filter(|l| l.windows).flat_map(|l| l.route.iter());
+    for route in routes {
+        let fixed = &route[..route.find([':', '*']).unwrap_or(route.len())];
+        let rest = match fixed.strip_prefix(path.as_str()) {
+            Some(rest) => rest,
+            None => continue,
+        };
+        found = true;
+        let child = rest.split('/').next().unwrap_or_default();
+        if !child.is_empty() && !children.iter().any(|c| c == child) {
+            children.push(child.to_string());
+        }
+    }
+    match found {
+        true => Some(children),
+        false => None,
+    }
+}
+
+fn response(href: &str) -> String {
+    format!(
+        concat!(
+            "<D:response><D:href>{}</D:href><D:propstat><D:prop>",
+            "<D:resourcetype><D:collection/></D:resourcetype>",
+            "</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n"
+        ),
+        escape(href)
+    )
+}
+
+/// The answer to OPTIONS or PROPFIND on a directory above a windows
+/// location, which has no location of its own.
+pub fn root(
+    builder: http::response::Builder,
+    method: DavMethod,
+    path: &[u8],
+    headers: &HeaderMap,
+    locations: &[Location],
+) -> Option<http::Response<hyper::Body>>
+{
+    let children = children(path, locations)?;
+    match method {
+        DavMethod::Options => {
+            let resp = builder
+                .header("DAV", "1,2")
+                .header("MS-Author-Via", "DAV")
+                .header("Allow", "OPTIONS,PROPFIND")
+                .header("Content-Length", "0")
+                .body(hyper::Body::empty())
+                .unwrap();
+            Some(resp)
+        },
+        DavMethod::PropFind => {
+            let href = String::from_utf8_lossy(path);
+            let href = href.trim_end_matches('/');
+            let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+            body.push_str("<D:multistatus xmlns:D=\"DAV:\">\n");
+            body.push_str(&response(&format!("{}/", href)));
+            let depth = headers.get("Depth").and_then(|d| d.to_str().ok()).unwrap_or("infinity");
+            if depth.trim() != "0" {
+                for child in &children {
+                    let name = percent_encode(child.as_bytes(), PATH);
+                    body.push_str(&response(&format!("{}/{}/", href, name)));
+                }
+            }
+            body.push_str("</D:multistatus>\n");
+            let resp = builder
+                .status(StatusCode::MULTI_STATUS)
+                .header("Content-Type", "application/xml; charset=utf-8")
+                .body(body.into())
+                .unwrap();
+            Some(resp)
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_winclient() {
+        assert_eq!(slashes("/share%5Cdir%5cfile"), "/share/dir/file");
+        assert_eq!(slashes("/share/%5Cdir"), "/share/dir");
+
+        let location = |route: &str| {
+            let toml = format!(
+                "route = [ \"{}\" ]\ndirectory = \"/\"\nhandler = \"filesystem\"\nwindows = true",
+                route
+            );
+            toml::from_str::<Location>(&toml).unwrap()
+        };
+        let locations = [location("/dav/share/*path"), location("/dav/:user/*path"), location("/x")];
+        assert_eq!(children(b"/", &locations), Some(vec!["dav".to_string(), "x".to_string()]));
+        assert_eq!(children(b"/dav", &locations), Some(vec!["share".to_string()]));
+        assert_eq!(children(b"/dav/share/", &locations), Some(vec![]));
+        assert_eq!(children(b"/other/", &locations), None);
+    }
+}
@@ -0,0 +1,179 @@
+//
+// Write once, read many (worm = true): files can be added, but not
+// changed or taken away. A PUT over a file that exists, a DELETE, and
+// a MOVE get "403 Forbidden", and so do a COPY or MOVE that would
+// replace something. Empty files and directories are the exception,
+// so that clients that create a file first and then upload it, or
+// make a "New Folder" and then rename it, still work.
+//
+// With worm-retention, a file can be deleted (or moved) when it has not
+// changed for that many days. That is counted from the inode change
+// time, which, unlike the modification time, a client cannot set back.
+// A directory with something in it can never be moved; a DELETE of it
+// takes out what it can.
+//
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, SystemTime};
+
+use futures::future::FutureExt;
+use futures::stream::StreamExt;
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+
+#[derive(Clone)]
+pub struct WormFs {
+    fs:        Box<dyn DavFileSystem>,
+    retention: Option<Duration>,
+}
+
+impl WormFs {
+    pub fn new(fs: Box<dyn DavFileSystem>, retention_days: Option<u64>) -> Box<WormFs> {
+        Box::new(WormFs {
+            fs,
+            retention: retention_days.map(|days| Duration::from_secs(days * 86400)),
+        })
+    }
+
+    // Is the retention over for this file.
+    fn expired(&self, meta: &dyn DavMetaData) -> bool {
+        let retention = match self.retention {
+            Some(retention) => retention,
+            None => return false,
+        };
+        let changed = meta.status_changed().or_else(|_| meta.modified());
+        changed
+            .ok()
+            .and_then(|t| SystemTime::now().duration_since(t).ok())
+            .map(|age| age >= retention)
+            .unwrap_or(false)
+    }
+
+    // Can what is at "path" go away.
+    async fn removable(&self, path: &DavPath) -> FsResult<()> {
+        let meta = match self.fs.symlink_metadata(path).await {
+            Ok(meta) => meta,
+            Err(FsError::NotFound) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if meta.is_dir() {
+            let mut entries = self.fs.read_dir(path, ReadDirMeta::None).await?;
+            if entries.next().await.is_none() {
+                return Ok(());
+            }
+            debug!("wormfs: {}: not empty", path);
+            return Err(FsError::Forbidden);
+        }
+        if meta.len() > 0 && !self.expired(&*meta) {
+            debug!("wormfs: {}: write once", path);
+            return Err(FsError::Forbidden);
+        }
+        Ok(())
+    }
+}
+
+impl DavFileSystem for WormFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        async move {
+            if options.write || options.append || options.truncate {
+                match self.fs.metadata(path).await {
+                    Ok(meta) if meta.is_file() && meta.len() > 0 => {
+                        debug!("wormfs: {}: exists, not overwritten", path);
+                        return Err(FsError::Forbidden);
+                    },
+                    _ => {},
+                }
+            }
+            self.fs.open(path, options).await
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.removable(path).await?;
+            self.fs.remove_dir(path).await
+        }
+        .boxed()
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.removable(path).await?;
+            self.fs.remove_file(path).await
+        }
+        .boxed()
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.removable(from).await?;
+            self.removable(to).await?;
+            self.fs.rename(from, to).await
+        }
+        .boxed()
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.removable(to).await?;
+            self.fs.copy(from, to).await
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        self.fs.have_props(path)
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        self.fs.patch_props(path, patch)
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        self.fs.get_props(path, do_content)
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        self.fs.get_prop(path, prop)
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
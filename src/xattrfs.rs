@@ -0,0 +1,269 @@
+//
+// Extended attributes as dead properties.
+//
+// The user.* attributes of a file, those that desktops and tagging tools
+// write (user.xdg.tags, user.xdg.comment, ...), are properties in the
+// urn:webdav-server-rs:xattr namespace, without the "user.": a PROPFIND
+// shows them, and a PROPPATCH sets or removes them. Only values that are
+// text are shown. Properties in other namespaces are for the filesystem
+// below, if it has them (dead-props).
+//
+// A MOVE keeps the attributes of what it moves; a COPY of a file copies
+// them too. Those of the server itself (user.webdav.*) are not shown,
+// but are copied.
+//
+use std::ffi::CString;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use futures::future::{self, FutureExt};
+use http::StatusCode;
+use webdav_handler::davpath::DavPath;
+use webdav_handler::fs::*;
+use xmltree::Element;
+
+use crate::report;
+use crate::userfs::UserFs;
+
+pub const NS: &str = "urn:webdav-server-rs:xattr";
+
+// The name of the property of an attribute, if it has one.
+fn prop_name(xattr: &[u8]) -> Option<&str> {
+    let name = std::str::from_utf8(xattr.strip_prefix(b"user.")?).ok()?;
+    let valid = name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c));
+    let first = name.chars().next().map(|c| c.is_ascii_alphabetic() || c == '_').unwrap_or(false);
+    match valid && first && !name.starts_with("webdav.") {
+        true => Some(name),
+        false => None,
+    }
+}
+
+// The attribute of a property.
+fn xattr_name(prop: &str) -> Option<CString> {
+    let xattr = format!("user.{}", prop);
+    prop_name(xattr.as_bytes())?;
+    CString::new(xattr).ok()
+}
+
+fn is_ours(prop: &DavProp) -> bool {
+    prop.namespace.as_deref() == Some(NS)
+}
+
+fn davprop(name: &str, value: Option<&str>) -> DavProp {
+    DavProp {
+        name:      name.to_string(),
+        prefix:    None,
+        namespace: Some(NS.to_string()),
+        xml:       value.map(|v| report::prop_xml(NS, name, &report::escape(v)).into_bytes()),
+    }
+}
+
+#[derive(Clone)]
+pub struct XattrFs {
+    fs:    Box<dyn DavFileSystem>,
+    local: Box<UserFs>,
+}
+
+impl XattrFs {
+    pub fn new(fs: Box<dyn DavFileSystem>, local: Box<UserFs>) -> Box<XattrFs> {
+        Box::new(XattrFs { fs, local })
+    }
+
+    // The attributes that are properties, with their value.
+    async fn load(&self, path: &DavPath) -> FsResult<Vec<(String, String)>> {
+        let mut props = Vec::new();
+        for xattr in self.local.list_xattrs(path).await? {
+            let name = match prop_name(xattr.as_bytes()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            if let Ok(Some(value)) = self.local.get_xattr(path, &xattr).await {
+                if let Ok(value) = String::from_utf8(value) {
+                    props.push((name, value));
+                }
+            }
+        }
+        Ok(props)
+    }
+
+    async fn patch(&self, path: &DavPath, set: bool, prop: &DavProp) -> StatusCode {
+        let xattr = match xattr_name(&prop.name) {
+            Some(xattr) => xattr,
+            None => return StatusCode::FORBIDDEN,
+        };
+        let res = match set {
+            true => {
+                let elem = prop.xml.as_ref().and_then(|xml| Element::parse(&xml[..]).ok());
+                let elem = match elem {
+                    Some(elem) if elem.children.iter().all(|n| n.as_element().is_none()) => elem,
+                    _ => return StatusCode::CONFLICT,
+                };
+                let value = elem.get_text().map(|t| t.into_owned()).unwrap_or_default();
+                self.local.set_xattr(path, &xattr, value.into_bytes()).await
+            },
+            false => self.local.remove_xattr(path, &xattr).await.map(|_| ()),
+        };
+        match res {
+            Ok(()) => StatusCode::OK,
+            Err(e) => report::status(e),
+        }
+    }
+
+    // After a COPY of a file: all user.* attributes, ours as well.
+    async fn copy_xattrs(&self, from: &DavPath, to: &DavPath) {
+        let xattrs = match self.local.list_xattrs(from).await {
+            Ok(xattrs) => xattrs,
+            Err(_) => return,
+        };
+        for xattr in xattrs.iter().filter(|x| x.as_bytes().starts_with(b"user.")) {
+            if let Ok(Some(value)) = self.local.get_xattr(from, xattr).await {
+                if let Err(e) = self.local.set_xattr(to, xattr, value).await {
+                    debug!("xattrfs: copy {:?} to {}: {:?}", xattr, to.as_url_string(), e);
+                }
+            }
+        }
+    }
+}
+
+impl DavFileSystem for XattrFs {
+    fn open<'a>(&'a self, path: &'a DavPath, options: OpenOptions) -> FsFuture<'a, Box<dyn DavFile>> {
+        self.fs.open(path, options)
+    }
+
+    fn read_dir<'a>(
+        &'a self,
+        path: &'a DavPath,
+        meta: ReadDirMeta,
+    ) -> FsFuture<'a, FsStream<Box<dyn DavDirEntry>>>
+    {
+        self.fs.read_dir(path, meta)
+    }
+
+    fn metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, Box<dyn DavMetaData>> {
+        self.fs.symlink_metadata(path)
+    }
+
+    fn create_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.create_dir(path)
+    }
+
+    fn remove_dir<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_dir(path)
+    }
+
+    fn remove_file<'a>(&'a self, path: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.remove_file(path)
+    }
+
+    fn rename<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        self.fs.rename(from, to)
+    }
+
+    fn copy<'a>(&'a self, from: &'a DavPath, to: &'a DavPath) -> FsFuture<'a, ()> {
+        async move {
+            self.fs.copy(from, to).await?;
+            self.copy_xattrs(from, to).await;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn set_accessed<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_accessed(path, tm)
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a DavPath, tm: SystemTime) -> FsFuture<'a, ()> {
+        self.fs.set_modified(path, tm)
+    }
+
+    fn have_props<'a>(&'a self, _path: &'a DavPath) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>> {
+        Box::pin(future::ready(true))
+    }
+
+    fn patch_props<'a>(
+        &'a self,
+        path: &'a DavPath,
+        patch: Vec<(bool, DavProp)>,
+    ) -> FsFuture<'a, Vec<(StatusCode, DavProp)>>
+    {
+        async move {
+            let (ours, others): (Vec<_>, Vec<_>) = patch.into_iter().partition(|(_, p)| is_ours(p));
+            let mut result = Vec::new();
+            for (set, prop) in ours {
+                let status = self.patch(path, set, &prop).await;
+                result.push((status, DavProp { xml: None, ..prop }));
+            }
+            if !others.is_empty() {
+                match self.fs.have_props(path).await {
+                    true => result.extend(self.fs.patch_props(path, others).await?),
+                    false => {
+                        for (_, prop) in others {
+                            result.push((StatusCode::FORBIDDEN, DavProp { xml: None, ..prop }));
+                        }
+                    },
+                }
+            }
+            Ok(result)
+        }
+        .boxed()
+    }
+
+    fn get_props<'a>(&'a self, path: &'a DavPath, do_content: bool) -> FsFuture<'a, Vec<DavProp>> {
+        async move {
+            let mut props = match self.fs.have_props(path).await {
+                true => self.fs.get_props(path, do_content).await?,
+                false => Vec::new(),
+            };
+            for (name, value) in self.load(path).await? {
+                props.push(davprop(&name, Some(&value).filter(|_| do_content).map(|v| v.as_str())));
+            }
+            Ok(props)
+        }
+        .boxed()
+    }
+
+    fn get_prop<'a>(&'a self, path: &'a DavPath, prop: DavProp) -> FsFuture<'a, Vec<u8>> {
+        async move {
+            if !is_ours(&prop) {
+                return self.fs.get_prop(path, prop).await;
+            }
+            let xattr = xattr_name(&prop.name).ok_or(FsError::NotFound)?;
+            let value = self.local.get_xattr(path, &xattr).await?.ok_or(FsError::NotFound)?;
+            let value = String::from_utf8(value).map_err(|_| FsError::NotFound)?;
+            Ok(davprop(&prop.name, Some(&value)).xml.unwrap_or_default())
+        }
+        .boxed()
+    }
+
+    fn get_quota(&self) -> FsFuture<'_, (u64, Option<u64>)> {
+        self.fs.get_quota()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xattrfs() {
+        assert_eq!(prop_name(b"user.xdg.tags"), Some("xdg.tags"));
+        assert_eq!(prop_name(b"user.baloo.rating"), Some("baloo.rating"));
+        assert_eq!(prop_name(b"user.webdav.appledouble"), None);
+        assert_eq!(prop_name(b"security.selinux"), None);
+        assert_eq!(prop_name(b"user.1st"), None);
+        assert_eq!(prop_name(b"user.a b"), None);
+        assert_eq!(xattr_name("xdg.comment").unwrap().as_bytes(), b"user.xdg.comment");
+        assert!(xattr_name("webdav.win32attributes").is_none());
+
+        let prop = davprop("xdg.tags", Some("a&b"));
+        let xml = String::from_utf8(prop.xml.unwrap()).unwrap();
+        let elem = Element::parse(xml.as_bytes()).unwrap();
+        assert_eq!(elem.namespace.as_deref(), Some(NS));
+        assert_eq!(elem.get_text().unwrap(), "a&b");
+    }
+}